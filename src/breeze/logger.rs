@@ -0,0 +1,58 @@
+//! Logger setup
+//!
+//! `env_logger` decides based on `RUST_LOG` which messages are shown. On top of that, the levels of
+//! the emulated components can be changed while running (see `breeze_core::log_util`), so
+//! `env_logger` alone isn't enough: It's set up to let everything logged by a component through,
+//! and the component's level is applied before passing a message on.
+
+use breeze_core::log_util::{Component, COMPONENTS, component_level};
+use env_logger::{LogBuilder, Logger};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+use std::env;
+
+/// Applies the per-component log levels, falling back to the `RUST_LOG` configuration.
+struct ComponentLogger {
+    /// Logger configured with `RUST_LOG` only, used to decide what to log
+    filter: Logger,
+    /// Like `filter`, but lets all messages of the components through. Used for writing.
+    output: Logger,
+}
+
+impl Log for ComponentLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        match Component::of_target(metadata.target()).and_then(component_level) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            self.output.log(record);
+        }
+    }
+}
+
+/// Installs the logger. `default_filter` is used if `RUST_LOG` isn't set.
+pub fn init(default_filter: &str) -> Result<(), SetLoggerError> {
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string());
+
+    let mut output = LogBuilder::new();
+    output.parse(&filter);
+    for &component in &COMPONENTS {
+        for &target in component.targets() {
+            output.filter(Some(target), LogLevelFilter::Trace);
+        }
+    }
+
+    let logger = ComponentLogger {
+        filter: LogBuilder::new().parse(&filter).build(),
+        output: output.build(),
+    };
+    log::set_logger(|max_level| {
+        // Component levels can be raised at any time, so nothing may be filtered out early
+        max_level.set(LogLevelFilter::Trace);
+        Box::new(logger)
+    })
+}
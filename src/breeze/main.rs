@@ -8,23 +8,55 @@ extern crate env_logger;
 extern crate breeze_core;
 extern crate breeze_backends;
 extern crate breeze_backend;
+extern crate spc700;
+extern crate wdc65816;
 
 mod input;
+mod logger;
 
 use input::attach_default_input;
 
-use breeze_core::rom::Rom;
-use breeze_core::snes::Emulator;
+use breeze_core::benchmark;
+use breeze_core::chrome_trace::ChromeTrace;
+use breeze_core::config::Config;
+use breeze_core::crash_report;
+use breeze_core::debug_server::DebugServer;
+use breeze_core::debugger::Debugger;
+use breeze_core::input::InputMacro;
+use breeze_core::log_util::Component;
+use breeze_core::ppu::{ColorCorrection, MAX_WIDESCREEN_MARGIN};
+use breeze_core::ppu::viewer::DebugView;
+use breeze_core::profiler::Profiler;
+use breeze_core::ram_init::RamInit;
+use breeze_core::rom::{Region, Rom};
+use breeze_core::snes::{Emulator, Snes};
+use breeze_core::scenario::Scenario;
+use breeze_core::stack_check::StackPolicy;
+use breeze_core::state_diff::StateSnapshot;
+use breeze_core::storage::GameStorage;
+use breeze_core::test_rom::{Report, TestResult, TestSuite};
+use breeze_core::symbols::SymbolTable;
+use breeze_core::trace::{TraceFilter, Tracer};
+use breeze_core::trace_diff::TraceDiff;
+use breeze_core::triggers::{TriggerSet, load_triggers};
 use breeze_core::save::SaveStateFormat;
-use breeze_core::record::{RecordingFormat, create_recorder, create_replayer};
+use breeze_core::record::RecordingFormat;
 use breeze_backend::Renderer;
+use breeze_backend::av::{AvRecorder, RecordingRenderer, RecordingSink};
+use breeze_backend::filter::parse_filter;
+use breeze_backend::hotkey::HotkeyMap;
+use breeze_backend::viewport::{AspectRatio, DisplayOptions};
+use breeze_backend::wav::{AudioDump, DumpingSink};
+use spc700::port_log::PortLog;
+use wdc65816::opstats::OpcodeStats;
 
 use clap::ArgMatches;
 
-use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::process;
 
 
@@ -33,7 +65,114 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
         return Err("`record` and `replay` may not be specified together!".into());
     }
 
-    let renderer_name = args.value_of("renderer").unwrap_or(&breeze_backends::DEFAULT_RENDERER);
+    if let Some(manifest) = args.value_of("test-roms") {
+        let suite = try!(TestSuite::load(manifest));
+        println!("running {} test ROMs", suite.tests.len());
+        let report = suite.run(|result| println!("{}", result));
+        println!("{}", report.summary());
+        if !report.success() {
+            return Err(format!("{} test ROMs failed", report.failed()).into());
+        }
+        return Ok(());
+    }
+
+    if let Some(paths) = args.values_of("scenario") {
+        let mut results = Vec::new();
+        for path in paths {
+            let scenario = try!(Scenario::load(path));
+            let result = TestResult {
+                path: path.into(),
+                outcome: scenario.run(),
+            };
+            println!("{}", result);
+            results.push(result);
+        }
+        let report = Report { results: results };
+        println!("{}", report.summary());
+        if !report.success() {
+            return Err(format!("{} scenarios failed", report.failed()).into());
+        }
+        return Ok(());
+    }
+
+    if let Some(mut paths) = args.values_of("state-diff") {
+        let rom = try!(Rom::load_file(args.value_of("rom").unwrap()));
+
+        let mut load = |path: &str| -> Result<StateSnapshot, Box<Error>> {
+            let mut file = BufReader::new(try!(File::open(path)));
+            Ok(try!(StateSnapshot::from_save_state(rom.clone(), SaveStateFormat::default(),
+                                                   &mut file)))
+        };
+        let left = try!(load(paths.next().unwrap()));
+        let right = try!(load(paths.next().unwrap()));
+        print!("{}", left.diff(&right));
+        return Ok(());
+    }
+
+    // Settings are taken from the command line, the game's config file and the global config file,
+    // in that order of precedence
+    let config_path = match args.value_of("config") {
+        Some(path) => path.into(),
+        None => Config::default_path(),
+    };
+    let mut config = if args.is_present("config") && !args.is_present("save-config") {
+        try!(Config::load(&config_path))
+    } else {
+        try!(Config::load_if_exists(&config_path))
+    };
+    let cli_config = try!(config_from_args(args));
+    let mut global_config = config.clone();
+    global_config.merge(&cli_config);
+    if args.is_present("save-config") {
+        try!(global_config.save(&config_path));
+        info!("saved the settings to '{}'", config_path.display());
+    }
+
+    // Load the ROM into memory
+    let filename = args.value_of("rom").unwrap();
+    let mut rom = try!(Rom::load_file(&filename));
+
+    // Restore the game's SRAM from its storage directory, and apply its config
+    let base_dir = match global_config.paths.data_dir {
+        Some(ref dir) => dir.clone(),
+        None => GameStorage::default_base_dir(),
+    };
+    let storage = GameStorage::new(base_dir, &rom);
+    info!("storing game data in '{}'", storage.dir().display());
+    if try!(storage.load_sram(&mut rom)) {
+        info!("restored SRAM from '{}'", storage.sram_path().display());
+    }
+    config.merge(&try!(Config::load_if_exists(storage.config_path())));
+    config.merge(&cli_config);
+    try!(config.log.apply());
+
+    let ram_init = match config.emulator.ram_init {
+        Some(ref pattern) => try!(pattern.parse::<RamInit>()),
+        None => RamInit::default(),
+    };
+    let region = match config.emulator.region {
+        Some(ref region) => Some(try!(region.parse::<Region>())),
+        None => None,
+    };
+    let accuracy = try!(config.accuracy.to_accuracy());
+    let clocks = try!(config.clock.to_clock_rates());
+
+    if let Some(frames) = args.value_of("benchmark") {
+        let frames = try!(frames.parse::<u64>()
+            .map_err(|_| format!("invalid number of frames: {}", frames)));
+        let mut snes = Snes::with_ram_init(rom, ram_init);
+        snes.set_accuracy(accuracy);
+        snes.set_clock_rates(clocks);
+        if let Some(region) = region {
+            snes.set_region(region);
+        }
+        println!("{}", try!(benchmark::run(&mut snes, frames)));
+        return Ok(());
+    }
+
+    let renderer_name = config.emulator.renderer.clone()
+        .unwrap_or_else(|| breeze_backends::DEFAULT_RENDERER.to_string());
+    let renderer_name = &*renderer_name;
 
     let renderer_fn = match breeze_backends::RENDERER_MAP.get(renderer_name) {
         None => {
@@ -63,7 +202,9 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
         }
     };
 
-    let audio_name = args.value_of("audio").unwrap_or(&breeze_backends::DEFAULT_AUDIO);
+    let audio_name = config.emulator.audio.clone()
+        .unwrap_or_else(|| breeze_backends::DEFAULT_AUDIO.to_string());
+    let audio_name = &*audio_name;
     let audio_fn = match breeze_backends::AUDIO_MAP.get(audio_name) {
         None => {
             let mut message = format!("unknown audio sink: {}\n", audio_name);
@@ -91,45 +232,243 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
         }
     };
 
-    // Load the ROM into memory
-    let filename = args.value_of("rom").unwrap();
-    let mut file = try!(File::open(&filename));
-    let mut buf = Vec::new();
-    try!(file.read_to_end(&mut buf));
-
-    let rom = try!(Rom::from_bytes(&buf));
-
     // Create the backend parts
     info!("using {} renderer", renderer_name);
     let mut renderer = try!(renderer_fn());
     if let Some(title) = rom.get_title() {
         renderer.set_rom_title(title);
     }
+    if let Some(ref filter) = config.video.filter {
+        try!(renderer.set_filter(try!(parse_filter(filter))));
+    }
+    if let Some(ref shader) = config.video.shader {
+        // Either a path to a shader file, or the name of a built-in shader
+        let mut source = String::new();
+        let shader = match File::open(shader) {
+            Ok(mut file) => {
+                try!(file.read_to_string(&mut source));
+                &source
+            }
+            Err(_) => shader,
+        };
+        try!(renderer.set_shader(shader));
+    }
+    if config.video.aspect.is_some() || config.video.integer_scaling.is_some() {
+        let mut opts = DisplayOptions::default();
+        if let Some(ref aspect) = config.video.aspect {
+            opts.aspect = try!(aspect.parse::<AspectRatio>());
+        }
+        opts.integer_scaling = config.video.integer_scaling.unwrap_or(false);
+        try!(renderer.set_display_options(opts));
+    }
+    if let Some(scale) = config.video.scale {
+        if scale == 0 {
+            return Err("the window scale must be at least 1".into());
+        }
+        try!(renderer.set_window_scale(scale));
+    }
+    if !config.hotkeys.is_empty() {
+        let mut hotkeys = HotkeyMap::default();
+        for &(ref key, ref action) in &config.hotkeys {
+            try!(hotkeys.apply_binding(&format!("{}={}", key, action)));
+        }
+        try!(renderer.set_hotkeys(hotkeys));
+    }
 
     info!("using {} audio sink", audio_name);
     let audio = try!(audio_fn());
 
+    // Route everything through an A/V recorder, so that recording can be started at any time
+    let recorder = AvRecorder::new();
+    if let Some(path) = args.value_of("record-video") {
+        try!(start_video_recording(&recorder, path));
+    }
+    let renderer = RecordingRenderer::new(renderer, recorder.clone());
+    let audio = RecordingSink::new(audio, recorder.clone());
+
+    // The audio dump gets exactly the same samples, independent of A/V recording
+    let audio_dump = AudioDump::new();
+    if let Some(path) = args.value_of("dump-audio") {
+        try!(audio_dump.start(path));
+    }
+    let audio = DumpingSink::new(audio, audio_dump.clone());
+
     // Put everything together in the emulator
-    let mut emu = Emulator::new(rom, renderer, audio);
+    let mut emu = Emulator::with_ram_init(rom, renderer, audio, ram_init);
+    if let Some(margin) = config.video.widescreen {
+        if margin > MAX_WIDESCREEN_MARGIN as u32 {
+            return Err(format!("the widescreen margin must be at most {} pixels",
+                               MAX_WIDESCREEN_MARGIN).into());
+        }
+        if config.video.widescreen_blacklisted == Some(true) {
+            info!("widescreen rendering is blacklisted for this game");
+        } else if margin > 0 {
+            match emu.set_widescreen(margin as u16) {
+                Ok(()) => {
+                    // The frames got wider, so the window should as well (renderers without a
+                    // window can't be scaled, which is fine)
+                    let _ = emu.renderer.set_window_scale(config.video.scale.unwrap_or(3));
+                }
+                Err(e) => warn!("couldn't enable widescreen rendering: {}", e),
+            }
+        }
+    }
+    if config.audio.buffer_size.is_some() || config.audio.periods.is_some() {
+        let buffer = try!(config.audio.to_buffer_config());
+        match emu.set_audio_buffer(buffer) {
+            Ok(()) => info!("audio buffer: {} periods of {} frames", buffer.periods,
+                            buffer.period_size),
+            Err(e) => warn!("couldn't configure the audio buffer: {}", e),
+        }
+    }
+    for slot in 0..emu.macros.len() {
+        let path = storage.macro_path(slot as u8);
+        if path.exists() {
+            let mut text = String::new();
+            try!(File::open(&path).and_then(|mut file| file.read_to_string(&mut text)));
+            emu.macros[slot] = Some(try!(text.parse::<InputMacro>()));
+        }
+    }
+    for &(slot, ref path) in &config.macros {
+        if slot as usize > emu.macros.len() {
+            return Err(format!("invalid macro slot {} (expected 1 to {})", slot,
+                               emu.macros.len()).into());
+        }
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)));
+        emu.macros[slot as usize - 1] = Some(try!(text.parse::<InputMacro>()));
+    }
+    let exit_state_path = storage.exit_state_path();
+    emu.storage = Some(storage);
+    emu.rom_path = Some(filename.into());
+    emu.reload_keeps_sram = !args.is_present("reload-clears-sram");
+
+    emu.snes.set_accuracy(accuracy);
+    emu.set_clock_rates(clocks);
+    if let Some(region) = region {
+        emu.set_region(region);
+    }
+    let symbols = match args.value_of("symbols") {
+        Some(path) => {
+            let symbols = try!(SymbolTable::load(path)
+                .map_err(|e| format!("couldn't load symbols from '{}': {}", path, e)));
+            info!("loaded {} symbols from '{}'", symbols.len(), path);
+            symbols
+        }
+        None => SymbolTable::new(),
+    };
+    if let Some(path) = args.value_of("trace") {
+        let mut tracer = try!(Tracer::create(path));
+        if let Some(opts) = args.values_of("trace-filter") {
+            let mut filter = TraceFilter::default();
+            for opt in opts {
+                try!(filter.apply_option(opt));
+            }
+            tracer.set_filter(filter);
+        }
+        if let Some(size) = args.value_of("trace-rotate") {
+            let mib = try!(size.parse::<u64>()
+                .map_err(|_| format!("invalid trace file size: {}", size)));
+            tracer.set_rotation(mib * 1024 * 1024, 3);
+        }
+        tracer.set_symbols(symbols.clone());
+        emu.snes.set_tracer(Some(tracer));
+    }
+    if let Some(path) = args.value_of("trace-diff") {
+        emu.snes.set_trace_diff(Some(try!(TraceDiff::open(path))));
+    }
+    let profile_entries = match args.value_of("profile") {
+        Some(n) => {
+            emu.snes.set_profiler(Some(Profiler::new()));
+            Some(try!(n.parse::<usize>().map_err(|_| format!("invalid number: {}", n))))
+        }
+        None => None,
+    };
+    if args.is_present("opcode-stats") {
+        emu.snes.cpu_mut().set_opcode_stats(Some(OpcodeStats::new()));
+    }
+    if let Some(path) = args.value_of("triggers") {
+        let mut triggers = TriggerSet::new();
+        for trigger in try!(load_triggers(path, &symbols)) {
+            triggers.add(trigger, |fired| {
+                println!("trigger '{}' fired at frame {}", fired.trigger.name(), fired.frame);
+            });
+        }
+        emu.triggers = Some(triggers);
+    }
+    if args.is_present("chrome-trace") {
+        emu.snes.set_chrome_trace(Some(ChromeTrace::new()));
+    }
+    if args.is_present("apu-port-log") {
+        emu.peripherals_mut().apu.set_port_log(Some(PortLog::new()));
+    }
+    if let Some(ref policy) = config.emulator.stack_policy {
+        emu.snes.set_stack_policy(try!(policy.parse::<StackPolicy>()));
+    }
+    if let Some(ref color) = config.video.color {
+        let correction = try!(color.parse::<ColorCorrection>());
+        emu.peripherals_mut().ppu.set_color_correction(correction);
+    }
     attach_default_input(&mut emu.peripherals_mut().input, renderer_name);
 
+    let save_state_on_exit = config.emulator.save_state_on_exit.unwrap_or(false);
+    let savestate = match args.value_of("savestate") {
+        Some(filename) => Some(PathBuf::from(filename)),
+        None if save_state_on_exit && exit_state_path.exists() => Some(exit_state_path),
+        None => None,
+    };
+    if let Some(path) = savestate {
+        let mut file = BufReader::new(try!(File::open(&path)));
+        try!(emu.snes.restore_save_state(SaveStateFormat::default(), &mut file));
+        info!("restored state from '{}'", path.display());
+    }
+
+    // Movies start from the current state, so this has to happen after restoring a save state
+    let movie_format = match args.value_of("movie-format") {
+        Some(format) => try!(format.parse::<RecordingFormat>()),
+        None => RecordingFormat::default(),
+    };
     if let Some(record_file) = args.value_of("record") {
-        let writer = Box::new(File::create(record_file).unwrap());
-        let recorder = create_recorder(RecordingFormat::default(), writer, &emu.snes).unwrap();
-        emu.peripherals_mut().input.start_recording(recorder);
+        let writer = Box::new(try!(File::create(record_file)));
+        try!(emu.snes.start_recording(movie_format, writer));
     }
     if let Some(replay_file) = args.value_of("replay") {
-        let reader = Box::new(BufReader::new(File::open(replay_file).unwrap()));
-        let replayer = create_replayer(RecordingFormat::default(), reader, &emu.snes).unwrap();
-        emu.peripherals_mut().input.start_replay(replayer);
+        let reader = Box::new(BufReader::new(try!(File::open(replay_file))));
+        try!(emu.snes.start_replay(movie_format, reader));
     }
-    if let Some(filename) = args.value_of("savestate") {
-        let file = File::open(filename).unwrap();
-        let mut bufrd = BufReader::new(file);
-        emu.snes.restore_save_state(SaveStateFormat::default(), &mut bufrd).unwrap()
+    emu.osd.set_enabled(config.video.osd.unwrap_or(true));
+    emu.osd.set_show_fps(config.video.show_fps.unwrap_or(false));
+    if let Some(speed) = config.emulator.slow_motion {
+        if speed <= 0.0 {
+            return Err(format!("invalid slow motion speed: {}", speed).into());
+        }
+        emu.set_slow_motion_speed(speed);
+    }
+    if let Some(views) = args.values_of("debug-view") {
+        for view in views {
+            try!(emu.open_debug_view(try!(view.parse::<DebugView>())));
+        }
     }
 
-    if cfg!(debug_assertions) && args.is_present("oneframe") {
+    if args.is_present("debugger") {
+        // Run the debugger REPL on the terminal. Frames are still displayed by the renderer.
+        let stdin = io::stdin();
+        let renderer = &mut emu.renderer;
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols.clone());
+        debugger.set_rom_path(filename);
+        try!(debugger.run_repl(&mut emu.snes, stdin.lock(), io::stdout(), |frame| {
+            renderer.render(frame)
+        }));
+    } else if let Some(addr) = args.value_of("debug-server") {
+        // Let remote clients control emulation. Frames are still displayed by the renderer.
+        let mut server = try!(DebugServer::bind(addr));
+        let renderer = &mut emu.renderer;
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols.clone());
+        debugger.set_rom_path(filename);
+        try!(server.run(&mut debugger, &mut emu.snes, |frame| renderer.render(frame)));
+    } else if cfg!(debug_assertions) && args.is_present("oneframe") {
         debug!("PPU H={}, V={}",
             emu.peripherals().ppu.h_counter(),
             emu.peripherals().ppu.v_counter());
@@ -139,35 +478,180 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
 
         // Keep rendering, but don't run emulation
         // Copy out the frame buffer because the damn borrow checker doesn't like it otherwise
-        let framebuf = emu.peripherals().ppu.framebuf.clone();
+        let frame = emu.peripherals().ppu.output_frame().to_vec();
         loop {
-            let actions = try!(emu.renderer.render(&*framebuf));
+            let actions = try!(emu.renderer.render(&frame));
             for a in actions {
                 if emu.handle_action(a) { break }
             }
         }
     } else {
-        // Run normally
-        try!(emu.run());
+        // Run normally. If emulation panics, leave a crash report before going down.
+        match panic::catch_unwind(AssertUnwindSafe(|| emu.run())) {
+            Ok(result) => try!(result),
+            Err(payload) => {
+                match emu.write_crash_report(crash_report::panic_message(&*payload)) {
+                    Ok(path) => error!("wrote a crash report to '{}'", path.display()),
+                    Err(e) => error!("couldn't write a crash report: {}", e),
+                }
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    emu.log_audio_stats();
+
+    if let (Some(n), Some(profiler)) = (profile_entries, emu.snes.profiler()) {
+        println!("{}", profiler.report(n));
+        println!("\n{}", profiler.function_report(n, |addr| symbols.describe(addr)));
+    }
+    let op_stats = emu.snes.cpu().opcode_stats();
+    if let (Some(path), Some(stats)) = (args.value_of("opcode-stats"), op_stats) {
+        let mut file = try!(File::create(path));
+        try!(write!(file, "{}", stats));
+    }
+    if let (Some(path), Some(trace)) = (args.value_of("chrome-trace"), emu.snes.chrome_trace()) {
+        try!(trace.write_json(&mut BufWriter::new(try!(File::create(path)))));
+    }
+    let port_log = emu.peripherals().apu.port_log();
+    if let (Some(path), Some(log)) = (args.value_of("apu-port-log"), port_log) {
+        try!(log.write_csv(&mut try!(File::create(path))));
+    }
+
+    if let Some(ref storage) = emu.storage {
+        try!(storage.save_sram(&emu.peripherals().rom));
+        if save_state_on_exit {
+            let path = storage.exit_state_path();
+            let mut file = BufWriter::new(try!(storage.create_file(&path)));
+            try!(emu.snes.create_save_state(SaveStateFormat::default(), &mut file));
+            info!("saved state to '{}'", path.display());
+        }
     }
 
+    try!(recorder.stop());
+    try!(audio_dump.stop());
     Ok(())
 }
 
-fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        env::set_var("RUST_LOG", "breeze=INFO");
+/// Collects the settings given on the command line into a `Config`, which takes precedence over
+/// the config files.
+fn config_from_args(args: &ArgMatches) -> Result<Config, Box<Error>> {
+    let mut config = Config::default();
+    let string = |name| args.value_of(name).map(str::to_string);
+    let flag = |name| if args.is_present(name) { Some(true) } else { None };
+
+    config.emulator.renderer = string("renderer");
+    config.emulator.audio = string("audio");
+    config.emulator.ram_init = string("ram-init");
+    config.emulator.region = string("region");
+    config.emulator.save_state_on_exit = flag("save-state-on-exit");
+    config.emulator.stack_policy = string("stack-policy");
+    if let Some(size) = args.value_of("audio-buffer") {
+        config.audio.buffer_size = Some(try!(size.parse::<u32>()
+            .map_err(|_| format!("invalid audio buffer size: {}", size))));
+    }
+    if let Some(periods) = args.value_of("audio-periods") {
+        config.audio.periods = Some(try!(periods.parse::<u32>()
+            .map_err(|_| format!("invalid number of audio periods: {}", periods))));
+    }
+    if let Some(scale) = args.value_of("scale") {
+        config.video.scale = Some(try!(scale.parse::<u32>()
+            .map_err(|_| format!("invalid window scale: {}", scale))));
+    }
+    if let Some(speed) = args.value_of("slow-motion") {
+        config.emulator.slow_motion = Some(try!(speed.parse::<f64>()
+            .map_err(|_| format!("invalid slow motion speed: {}", speed))));
+    }
+    config.video.filter = string("filter");
+    config.video.shader = string("shader");
+    config.video.aspect = string("aspect");
+    config.video.integer_scaling = flag("integer-scaling");
+    config.video.color = string("color");
+    config.video.show_fps = flag("show-fps");
+    config.video.osd = flag("no-osd").map(|_| false);
+    if let Some(margin) = args.value_of("widescreen") {
+        config.video.widescreen = Some(try!(margin.parse::<u32>()
+            .map_err(|_| format!("invalid widescreen margin: {}", margin))));
     }
-    env_logger::init().unwrap();
+    config.accuracy.profile = string("accuracy");
+    if let Some(levels) = args.values_of("log") {
+        for setting in levels {
+            let mut parts = setting.splitn(2, '=');
+            let (name, level) = match (parts.next(), parts.next()) {
+                (Some(name), Some(level)) => (name, level),
+                _ => return Err(format!("expected COMPONENT=LEVEL, got `{}`", setting).into()),
+            };
+            let component = try!(name.parse::<Component>());
+            *config.log.level_mut(component) = Some(level.to_string());
+        }
+    }
+    if let Some(overrides) = args.values_of("accuracy-opt") {
+        for opt in overrides {
+            try!(config.accuracy.apply_override(opt));
+        }
+    }
+    config.clock.preset = string("clock");
+    let rate = |name| -> Result<Option<u32>, Box<Error>> {
+        match args.value_of(name) {
+            Some(hz) => Ok(Some(try!(hz.parse::<u32>()
+                .map_err(|_| format!("invalid clock rate: {}", hz))))),
+            None => Ok(None),
+        }
+    };
+    config.clock.master_hz = try!(rate("master-clock"));
+    config.clock.apu_hz = try!(rate("apu-clock"));
+    config.paths.data_dir = args.value_of("data-dir").map(Into::into);
+    if let Some(bindings) = args.values_of("hotkey") {
+        for binding in bindings {
+            let mut split = binding.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(key), Some(action)) => {
+                    config.hotkeys.push((key.trim().to_string(), action.trim().to_string()));
+                }
+                _ => return Err(format!("invalid hotkey binding (expected `KEY=ACTION`): {}",
+                                        binding).into()),
+            }
+        }
+    }
+    if let Some(macros) = args.values_of("macro") {
+        for binding in macros {
+            let mut split = binding.splitn(2, '=');
+            match (split.next().map(str::parse::<u8>), split.next()) {
+                (Some(Ok(slot)), Some(path)) if slot >= 1 => {
+                    config.macros.push((slot, path.into()));
+                }
+                _ => return Err(format!("invalid macro (expected `SLOT=FILE`): {}",
+                                        binding).into()),
+            }
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(feature = "ffmpeg")]
+fn start_video_recording(recorder: &AvRecorder, path: &str) -> Result<(), Box<Error>> {
+    let encoder = try!(breeze_backend::ffmpeg::FfmpegEncoder::new(path));
+    try!(recorder.start(Box::new(encoder)));
+    Ok(())
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+fn start_video_recording(_recorder: &AvRecorder, _path: &str) -> Result<(), Box<Error>> {
+    Err("video recording requires the `ffmpeg` feature".into())
+}
+
+fn main() {
+    logger::init("breeze=INFO").unwrap();
 
     let mut app = clap::App::new("breeze")
         .version(env!("CARGO_PKG_VERSION"))
         .about("SNES emulator")
         .arg(clap::Arg::with_name("rom")
-            .required(true)
+            .required_unless_one(&["test-roms", "scenario"])
             .value_name("ROM_PATH")
             .takes_value(true)
-            .help("The ROM file to execute"))
+            .help("The ROM file to execute (the first file of dumps split into `NAME.1`, \
+                   `NAME.2`, ...)"))
         .arg(clap::Arg::with_name("renderer")
             .short("R")
             .long("renderer")
@@ -178,10 +662,44 @@ fn main() {
             .long("audio")
             .takes_value(true)
             .help("The audio backend to use"))
+        .arg(clap::Arg::with_name("audio-buffer")
+            .long("audio-buffer")
+            .takes_value(true)
+            .value_name("FRAMES")
+            .help("Size of the audio device's buffer in sample frames (default: 1024). Smaller \
+                   buffers reduce latency, but may crackle on slow machines"))
+        .arg(clap::Arg::with_name("audio-periods")
+            .long("audio-periods")
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of audio buffers kept filled ahead of the device (default: 2)"))
+        .arg(clap::Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Read settings from FILE instead of the default config file \
+                   ($BREEZE_CONFIG or breeze/config.toml in the platform's config directory)"))
+        .arg(clap::Arg::with_name("save-config")
+            .long("save-config")
+            .help("Store the settings given on the command line in the config file"))
         .arg(clap::Arg::with_name("savestate")
             .long("savestate")
             .takes_value(true)
             .help("The save state file to load"))
+        .arg(clap::Arg::with_name("reload-clears-sram")
+            .long("reload-clears-sram")
+            .help("Start with empty cartridge RAM when the ROM is reloaded with the `reload-rom` \
+                   hotkey (by default, it's kept)"))
+        .arg(clap::Arg::with_name("save-state-on-exit")
+            .long("save-state-on-exit")
+            .help("Save the state to the game's data directory on exit, and restore it on the next \
+                   start (unless --savestate is given)"))
+        .arg(clap::Arg::with_name("data-dir")
+            .long("data-dir")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Base directory for per-game data (SRAM, save states, macros, screenshots). \
+                   Defaults to $BREEZE_DATA_DIR or the platform's data directory"))
         .arg(clap::Arg::with_name("record")
             .long("record")
             .takes_value(true)
@@ -189,7 +707,260 @@ fn main() {
         .arg(clap::Arg::with_name("replay")
             .long("replay")
             .takes_value(true)
-            .help("Replay a recording from a text file"));
+            .help("Replay a recording from a text file"))
+        .arg(clap::Arg::with_name("movie-format")
+            .long("movie-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["movie", "smv", "custom"])
+            .help("File format used by --record and --replay (default: movie)"))
+        .arg(clap::Arg::with_name("region")
+            .long("region")
+            .takes_value(true)
+            .value_name("REGION")
+            .possible_values(&["ntsc", "pal"])
+            .help("Emulate an NTSC or PAL console instead of the one the ROM header asks for"))
+        .arg(clap::Arg::with_name("benchmark")
+            .long("benchmark")
+            .takes_value(true)
+            .value_name("FRAMES")
+            .help("Emulate FRAMES frames as fast as possible without any output and print the \
+                   achieved frame rate"))
+        .arg(clap::Arg::with_name("ram-init")
+            .long("ram-init")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .help("Power-on RAM contents: `zero` (default), `55`, `alternating` or \
+                   `random[:SEED]`"))
+        .arg(clap::Arg::with_name("accuracy")
+            .long("accuracy")
+            .takes_value(true)
+            .value_name("PROFILE")
+            .possible_values(&["fast", "balanced", "accurate"])
+            .help("Accuracy profile to use (default: accurate)"))
+        .arg(clap::Arg::with_name("log")
+            .long("log")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("COMPONENT=LEVEL")
+            .help("Set the log level of an emulated component (`cpu`, `ppu`, `apu`, `dma` or \
+                   `input`), overriding RUST_LOG"))
+        .arg(clap::Arg::with_name("accuracy-opt")
+            .long("accuracy-opt")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME=VALUE")
+            .help("Override a single accuracy option of the selected profile (`dot_ppu`, \
+                   `cycle_cpu`, `dram_refresh`, `dma_timing`, `apu_sync`, `ppu_sync`, \
+                   `bg_raster` or `alu_latency`)"))
+        .arg(clap::Arg::with_name("clock")
+            .long("clock")
+            .takes_value(true)
+            .value_name("PRESET")
+            .possible_values(&["nominal", "typical", "fast-apu"])
+            .help("Clock rates to emulate: `nominal` (default), `typical` (APU resonator speed of \
+                   most real consoles) or `fast-apu` (a console with a fast APU resonator)"))
+        .arg(clap::Arg::with_name("master-clock")
+            .long("master-clock")
+            .takes_value(true)
+            .value_name("HZ")
+            .help("Override the master clock rate (default: 21477272)"))
+        .arg(clap::Arg::with_name("apu-clock")
+            .long("apu-clock")
+            .takes_value(true)
+            .value_name("HZ")
+            .help("Override the SPC700 clock rate (default: 1024000)"))
+        .arg(clap::Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .value_name("FILTER")
+            .help("Post-processing filter to apply to the output: `none` (default), \
+                   `nearest[:SCALE]`, `scanlines` or `xbrz`"))
+        .arg(clap::Arg::with_name("shader")
+            .long("shader")
+            .takes_value(true)
+            .value_name("SHADER")
+            .help("GPU shader to draw the output with: A built-in shader name or a path to a \
+                   shader file (only supported by some renderers)"))
+        .arg(clap::Arg::with_name("aspect")
+            .long("aspect")
+            .takes_value(true)
+            .value_name("ASPECT")
+            .possible_values(&["square", "ntsc", "stretch"])
+            .help("Pixel aspect ratio: `square` (default), `ntsc` (8:7) or `stretch` to fill the \
+                   window"))
+        .arg(clap::Arg::with_name("scale")
+            .long("scale")
+            .takes_value(true)
+            .value_name("N")
+            .help("Initial window size as a multiple of the SNES resolution (default: 3)"))
+        .arg(clap::Arg::with_name("widescreen")
+            .long("widescreen")
+            .takes_value(true)
+            .value_name("PIXELS")
+            .help("Render the BG layers this many pixels past both edges of the screen \
+                   (a hack that glitches in some games)"))
+        .arg(clap::Arg::with_name("integer-scaling")
+            .long("integer-scaling")
+            .help("Only scale the output by whole multiples of its native size"))
+        .arg(clap::Arg::with_name("hotkey")
+            .long("hotkey")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=ACTION")
+            .help("Bind a key to an action (`save-state`, `load-state`, `undo-load-state`, \
+                   `undo-save-state`, `rewind`, `fast-forward`, `slow-motion`, `screenshot`, \
+                   `reset`, `reload-rom`, `pause`, `frame-advance`, `play-macro-N`, \
+                   `record-macro-N`, `hold-BUTTON` or `exit`). `=ACTION` unbinds the action's \
+                   default key"))
+        .arg(clap::Arg::with_name("macro")
+            .long("macro")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("SLOT=FILE")
+            .help("Load an input macro into a slot (1-9), to be played with the `play-macro-N` \
+                   hotkey. Recorded macros are saved in the game's data directory"))
+        .arg(clap::Arg::with_name("slow-motion")
+            .long("slow-motion")
+            .takes_value(true)
+            .value_name("SPEED")
+            .help("Emulation speed while the slow motion hotkey is held (default: 0.25)"))
+        .arg(clap::Arg::with_name("debug-view")
+            .long("debug-view")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("VIEW")
+            .help("Open a debug window: `palette`, `tiles[:BPP]`, `bg1` - `bg4` or `oam` (only \
+                   supported by some renderers)"))
+        .arg(clap::Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .value_name("CORRECTION")
+            .help("Color correction: `expand` (default), `shift`, `gamma[:VALUE]` (emulates a CRT \
+                   with the given gamma, default 2.5) or `brighten`"))
+        .arg(clap::Arg::with_name("show-fps")
+            .long("show-fps")
+            .help("Display the frame rate on screen"))
+        .arg(clap::Arg::with_name("no-osd")
+            .long("no-osd")
+            .help("Don't display any messages on top of the emulated screen"))
+        .arg(clap::Arg::with_name("trace")
+            .long("trace")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write all executed CPU instructions to a file"))
+        .arg(clap::Arg::with_name("trace-filter")
+            .long("trace-filter")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME=VALUE")
+            .requires("trace")
+            .help("Only trace some instructions: `range=BBAAAA-BBAAAA`, `start_frame=N` or \
+                   `opcode=XX` (ranges and opcodes can be given multiple times)"))
+        .arg(clap::Arg::with_name("trace-rotate")
+            .long("trace-rotate")
+            .takes_value(true)
+            .value_name("MIB")
+            .requires("trace")
+            .help("Start a new trace file after this many MiB were written, keeping 3 old files"))
+        .arg(clap::Arg::with_name("symbols")
+            .long("symbols")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Load labels from a WLA-DX `.sym` or ca65 (`ld65 -Ln`) symbol file, used by the \
+                   trace and the debugger"))
+        .arg(clap::Arg::with_name("trace-diff")
+            .long("trace-diff")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Compare execution against a CPU trace produced by bsnes and stop at the first \
+                   difference"))
+        .arg(clap::Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .value_name("N")
+            .help("Record the cycles spent per instruction and print the N hottest addresses on \
+                   exit"))
+        .arg(clap::Arg::with_name("opcode-stats")
+            .long("opcode-stats")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Count how often each opcode and addressing mode is executed and write the \
+                   histogram (and the opcodes that were never executed) to FILE on exit"))
+        .arg(clap::Arg::with_name("triggers")
+            .long("triggers")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Load memory triggers (`NAME: CONDITION` lines, using the debugger's expression \
+                   syntax) from FILE and print a message whenever one fires"))
+        .arg(clap::Arg::with_name("chrome-trace")
+            .long("chrome-trace")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Record frames, interrupts, DMA transfers and APU syncs and write them to FILE \
+                   on exit (in the Chrome tracing JSON format, viewable in chrome://tracing)"))
+        .arg(clap::Arg::with_name("apu-port-log")
+            .long("apu-port-log")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Log the accesses to the CPU/APU communication ports and write the most recent \
+                   ones to FILE (as CSV) on exit"))
+        .arg(clap::Arg::with_name("dump-audio")
+            .long("dump-audio")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the emulated audio output (32 kHz stereo, before resampling) to a WAV \
+                   file"))
+        .arg(clap::Arg::with_name("stack-policy")
+            .long("stack-policy")
+            .takes_value(true)
+            .value_name("POLICY")
+            .help("What to do when the stack pointer wraps around: `ignore`, `log` (the first \
+                   time, default) or `break` (pauses, or stops the debugger)"))
+        .arg(clap::Arg::with_name("debugger")
+            .long("debugger")
+            .help("Start an interactive debugger on the terminal instead of running the game"))
+        .arg(clap::Arg::with_name("debug-server")
+            .long("debug-server")
+            .takes_value(true)
+            .value_name("ADDR")
+            .conflicts_with("debugger")
+            .help("Listen for debug clients (JSON over WebSocket or TCP) on ADDR, eg. \
+                   `127.0.0.1:6502`, and start paused"))
+        .arg(clap::Arg::with_name("test-roms")
+            .long("test-roms")
+            .takes_value(true)
+            .value_name("MANIFEST")
+            .help("Run the test ROMs listed in MANIFEST headless and report which ones pass"))
+        .arg(clap::Arg::with_name("scenario")
+            .long("scenario")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Run a scripted test scenario headless (inputs, frame counts and expected \
+                   memory or frame hashes) and report whether it passes"))
+        .arg(clap::Arg::with_name("state-diff")
+            .long("state-diff")
+            .takes_value(true)
+            .number_of_values(2)
+            .value_names(&["STATE_A", "STATE_B"])
+            .help("Compare two save states of the ROM and print which registers and memory \
+                   ranges differ (for tracking down desyncs)"));
+
+    if cfg!(feature = "ffmpeg") {
+        app = app.arg(clap::Arg::with_name("record-video")
+            .long("record-video")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Record video and audio to a file (requires ffmpeg to be installed)"));
+    }
 
     // Add debugging options
     if cfg!(debug_assertions) {
@@ -0,0 +1,235 @@
+//! Synchronized audio/video dumping.
+//!
+//! `RecordingRenderer` and `RecordingSink` wrap another renderer or audio sink and pass everything
+//! they are given on to an `AvRecorder`, which forwards it to an `AvSink` (for example a video
+//! encoder) while recording is active. Frontends can start and stop recording at any time, for
+//! example when a hotkey is pressed.
+
+use {AudioBufferConfig, AudioSink, BackendAction, BackendResult, DebugSurfaceId, Renderer};
+use filter::Filter;
+use hotkey::HotkeyMap;
+use pacing::FrameTiming;
+use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use viewport::DisplayOptions;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Consumer of the A/V stream.
+///
+/// Video frames are `RGB24` (see `Renderer::render`), audio samples are 32 kHz stereo. Both are
+/// passed in the order the emulator produces them, so the audio belonging to a frame is the audio
+/// passed between that frame and the next one.
+pub trait AvSink: Send {
+    /// Called for every frame rendered while recording.
+    fn video_frame(&mut self, frame: &[u8], width: u32, height: u32) -> io::Result<()>;
+
+    /// Called for every chunk of audio produced while recording.
+    fn audio_samples(&mut self, samples: &[(i16, i16)]) -> io::Result<()>;
+
+    /// Called when recording is stopped. Should flush and close all output.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Shared handle controlling A/V recording. Cloning it yields another handle to the same recorder.
+#[derive(Clone)]
+pub struct AvRecorder {
+    sink: Arc<Mutex<Option<Box<AvSink>>>>,
+}
+
+impl AvRecorder {
+    /// Creates a recorder that isn't recording.
+    pub fn new() -> Self {
+        AvRecorder {
+            sink: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts recording to `sink`. If a recording is already in progress, it is stopped first.
+    pub fn start(&self, sink: Box<AvSink>) -> io::Result<()> {
+        try!(self.stop());
+        *self.sink.lock().unwrap() = Some(sink);
+        info!("started A/V recording");
+        Ok(())
+    }
+
+    /// Stops the current recording (if any) and finishes the output.
+    pub fn stop(&self) -> io::Result<()> {
+        let sink = self.sink.lock().unwrap().take();
+        match sink {
+            Some(mut sink) => {
+                info!("stopped A/V recording");
+                sink.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.sink.lock().unwrap().is_some()
+    }
+
+    /// Passes a frame to the sink. If that fails, recording is stopped.
+    pub fn video_frame(&self, frame: &[u8], width: u32, height: u32) {
+        let mut guard = self.sink.lock().unwrap();
+        let failed = match *guard {
+            Some(ref mut sink) => sink.video_frame(frame, width, height).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            error!("A/V recording failed, stopping: {}", e);
+            if let Some(mut sink) = guard.take() {
+                let _ = sink.finish();
+            }
+        }
+    }
+
+    /// Passes audio samples to the sink. If that fails, recording is stopped.
+    pub fn audio_samples(&self, samples: &[(i16, i16)]) {
+        let mut guard = self.sink.lock().unwrap();
+        let failed = match *guard {
+            Some(ref mut sink) => sink.audio_samples(samples).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            error!("A/V recording failed, stopping: {}", e);
+            if let Some(mut sink) = guard.take() {
+                let _ = sink.finish();
+            }
+        }
+    }
+}
+
+/// A renderer that passes all frames to an `AvRecorder` before rendering them.
+pub struct RecordingRenderer<R: Renderer> {
+    inner: R,
+    recorder: AvRecorder,
+    /// Size of the frames passed to `render`
+    frame_size: (u32, u32),
+}
+
+impl<R: Renderer> RecordingRenderer<R> {
+    pub fn new(inner: R, recorder: AvRecorder) -> Self {
+        RecordingRenderer {
+            inner: inner,
+            recorder: recorder,
+            frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    pub fn recorder(&self) -> &AvRecorder { &self.recorder }
+    pub fn inner(&self) -> &R { &self.inner }
+    pub fn inner_mut(&mut self) -> &mut R { &mut self.inner }
+}
+
+impl<R: Renderer> Renderer for RecordingRenderer<R> {
+    fn create() -> BackendResult<Self> where Self: Sized {
+        Ok(RecordingRenderer::new(try!(R::create()), AvRecorder::new()))
+    }
+
+    fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>> {
+        let (width, height) = self.frame_size;
+        self.recorder.video_frame(frame_data, width, height);
+        self.inner.render(frame_data)
+    }
+
+    fn set_rom_title(&mut self, title: &str) {
+        self.inner.set_rom_title(title)
+    }
+
+    fn set_filter(&mut self, filter: Option<Box<Filter>>) -> BackendResult<()> {
+        self.inner.set_filter(filter)
+    }
+
+    fn set_shader(&mut self, shader: &str) -> BackendResult<()> {
+        self.inner.set_shader(shader)
+    }
+
+    fn set_display_options(&mut self, opts: DisplayOptions) -> BackendResult<()> {
+        self.inner.set_display_options(opts)
+    }
+
+    fn set_window_scale(&mut self, scale: u32) -> BackendResult<()> {
+        self.inner.set_window_scale(scale)
+    }
+
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        try!(self.inner.set_frame_size(width, height));
+        self.frame_size = (width, height);
+        Ok(())
+    }
+
+    fn set_hotkeys(&mut self, hotkeys: HotkeyMap) -> BackendResult<()> {
+        self.inner.set_hotkeys(hotkeys)
+    }
+
+    fn frame_timing(&self) -> FrameTiming {
+        self.inner.frame_timing()
+    }
+
+    fn open_debug_surface(&mut self, title: &str, width: u32, height: u32)
+    -> BackendResult<DebugSurfaceId> {
+        self.inner.open_debug_surface(title, width, height)
+    }
+
+    fn update_debug_surface(&mut self,
+                            id: DebugSurfaceId,
+                            data: &[u8],
+                            width: u32,
+                            height: u32) -> BackendResult<()> {
+        self.inner.update_debug_surface(id, data, width, height)
+    }
+
+    fn close_debug_surface(&mut self, id: DebugSurfaceId) {
+        self.inner.close_debug_surface(id)
+    }
+}
+
+/// An audio sink that passes all samples to an `AvRecorder` before playing them.
+pub struct RecordingSink<A: AudioSink> {
+    inner: A,
+    recorder: AvRecorder,
+}
+
+impl<A: AudioSink> RecordingSink<A> {
+    pub fn new(inner: A, recorder: AvRecorder) -> Self {
+        RecordingSink {
+            inner: inner,
+            recorder: recorder,
+        }
+    }
+
+    pub fn recorder(&self) -> &AvRecorder { &self.recorder }
+}
+
+impl<A: AudioSink> AudioSink for RecordingSink<A> {
+    fn create() -> BackendResult<Self> where Self: Sized {
+        Ok(RecordingSink::new(try!(A::create()), AvRecorder::new()))
+    }
+
+    fn write(&mut self, data: &[(i16, i16)]) {
+        self.recorder.audio_samples(data);
+        self.inner.write(data);
+    }
+
+    fn output_rate(&self) -> u32 {
+        self.inner.output_rate()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.inner.latency()
+    }
+
+    fn set_rate_adjust(&mut self, adjust: f64) {
+        self.inner.set_rate_adjust(adjust)
+    }
+
+    fn set_buffer_config(&mut self, config: AudioBufferConfig) -> BackendResult<()> {
+        self.inner.set_buffer_config(config)
+    }
+
+    fn underruns(&self) -> Option<u64> {
+        self.inner.underruns()
+    }
+}
@@ -0,0 +1,357 @@
+//! Headless backend that records everything in memory.
+//!
+//! This is meant for automated tests and for running test ROMs without a window or audio device:
+//! `CaptureRenderer` keeps every rendered frame (and, with the `png` feature, can dump them as PNG
+//! files), `CaptureSink` keeps all audio, and `ScriptedJoypad` plays back a prepared sequence of
+//! button presses.
+//!
+//! There's no single `HeadlessBackend` type: These 3 parts together form the headless backend. Use
+//! `CaptureRenderer` and `CaptureSink` as the emulator's renderer and audio sink, and plug a
+//! `ScriptedJoypad` driven by `CaptureRenderer::frame_counter` into a controller port.
+
+use {BackendAction, BackendResult, DebugSurfaceId, Renderer, AudioSink};
+use hash::hash_bytes;
+use input::joypad::{JoypadImpl, JoypadState};
+#[cfg(feature = "png")]
+use png::write_png;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+#[cfg(feature = "png")]
+use std::fs::File;
+#[cfg(feature = "png")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "png")]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Renderer that stores every frame it is given.
+///
+/// Since this can consume a lot of memory (every frame is ~170 KB), only the last `max_frames`
+/// frames are kept (all of them by default). The hashes of all frames are kept regardless, so
+/// regression tests can compare them against known-good values.
+pub struct CaptureRenderer {
+    frames: Vec<Vec<u8>>,
+    max_frames: Option<usize>,
+    /// Hash of every frame rendered so far, in whatever size it had (see `hash_bytes`). For
+    /// native-size frames, this is the same as `Snes::frame_hash`.
+    frame_hashes: Vec<u64>,
+    /// Size of the frames passed to `render`
+    frame_size: (u32, u32),
+    /// Directory every frame is written to as a PNG file
+    #[cfg(feature = "png")]
+    dump_dir: Option<PathBuf>,
+    /// Number of frames rendered so far (including the ones that were dropped)
+    frame_count: Arc<AtomicUsize>,
+    /// Frame after which `BackendAction::Exit` is returned
+    exit_after: Option<usize>,
+    /// Open debug surfaces, indexed by their ID (closed surfaces are `None`)
+    debug_surfaces: Vec<Option<CapturedSurface>>,
+}
+
+/// The last image displayed on a debug surface of a `CaptureRenderer`.
+pub struct CapturedSurface {
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+    /// `RGB24` image data
+    pub data: Vec<u8>,
+}
+
+impl CaptureRenderer {
+    /// Only keep the last `max` frames (`None` keeps all frames).
+    pub fn set_max_frames(&mut self, max: Option<usize>) {
+        self.max_frames = max;
+        self.drop_old_frames();
+    }
+
+    /// Makes the renderer request an exit after the given number of frames were rendered. This
+    /// allows using `Emulator::run` to run a ROM for a fixed number of frames.
+    pub fn exit_after(&mut self, frames: Option<usize>) {
+        self.exit_after = frames;
+    }
+
+    /// Writes every frame rendered from now on to `dir` as a PNG file named after the frame
+    /// number (`frame-000001.png` is the first frame), or stops if `None` is passed.
+    #[cfg(feature = "png")]
+    pub fn dump_frames(&mut self, dir: Option<PathBuf>) {
+        self.dump_dir = dir;
+    }
+
+    /// Returns the size (width, height) of the frames rendered.
+    pub fn frame_size(&self) -> (u32, u32) {
+        self.frame_size
+    }
+
+    /// Returns the hashes of all frames rendered so far, oldest first (even of the frames dropped
+    /// because of `set_max_frames`).
+    pub fn frame_hashes(&self) -> &[u64] {
+        &self.frame_hashes
+    }
+
+    /// Returns the number of frames rendered so far.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count.load(Ordering::SeqCst)
+    }
+
+    /// Returns a shared frame counter that is incremented after every rendered frame. Used to
+    /// drive a `ScriptedJoypad`.
+    pub fn frame_counter(&self) -> Arc<AtomicUsize> {
+        self.frame_count.clone()
+    }
+
+    /// Returns the recorded frames, oldest first. Frames are stored as `RGB24` data (see
+    /// `Renderer::render`).
+    pub fn frames(&self) -> &[Vec<u8>] {
+        &self.frames
+    }
+
+    /// Returns the most recently rendered frame, if any.
+    pub fn last_frame(&self) -> Option<&[u8]> {
+        self.frames.last().map(|frame| &frame[..])
+    }
+
+    /// Removes all recorded frames and returns them.
+    pub fn take_frames(&mut self) -> Vec<Vec<u8>> {
+        ::std::mem::replace(&mut self.frames, Vec::new())
+    }
+
+    /// Returns the debug surface with the given ID, if it's open.
+    pub fn debug_surface(&self, id: DebugSurfaceId) -> Option<&CapturedSurface> {
+        self.debug_surfaces.get(id.0 as usize).and_then(|surface| surface.as_ref())
+    }
+
+    #[cfg(feature = "png")]
+    fn dump_frame(&self, dir: &Path, number: usize, frame: &[u8]) -> BackendResult<()> {
+        let path = dir.join(format!("frame-{:06}.png", number));
+        let mut file = BufWriter::new(try!(File::create(&path)));
+        let (width, height) = self.frame_size;
+        try!(write_png(&mut file, width, height, frame));
+        try!(file.flush());
+        Ok(())
+    }
+
+    fn drop_old_frames(&mut self) {
+        if let Some(max) = self.max_frames {
+            if self.frames.len() > max {
+                let excess = self.frames.len() - max;
+                self.frames.drain(..excess);
+            }
+        }
+    }
+}
+
+impl Renderer for CaptureRenderer {
+    fn create() -> BackendResult<Self> where Self: Sized {
+        Ok(CaptureRenderer {
+            frames: Vec::new(),
+            max_frames: None,
+            frame_hashes: Vec::new(),
+            frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            #[cfg(feature = "png")]
+            dump_dir: None,
+            frame_count: Arc::new(AtomicUsize::new(0)),
+            exit_after: None,
+            debug_surfaces: Vec::new(),
+        })
+    }
+
+    fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>> {
+        self.frames.push(frame_data.to_vec());
+        self.frame_hashes.push(hash_bytes(frame_data));
+        self.drop_old_frames();
+        let count = self.frame_count.fetch_add(1, Ordering::SeqCst) + 1;
+        #[cfg(feature = "png")]
+        {
+            if let Some(ref dir) = self.dump_dir {
+                try!(self.dump_frame(dir, count, frame_data));
+            }
+        }
+
+        match self.exit_after {
+            Some(limit) if count >= limit => Ok(vec![BackendAction::Exit]),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn set_rom_title(&mut self, _title: &str) {}
+
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        self.frame_size = (width, height);
+        Ok(())
+    }
+
+    fn open_debug_surface(&mut self, title: &str, width: u32, height: u32)
+    -> BackendResult<DebugSurfaceId> {
+        self.debug_surfaces.push(Some(CapturedSurface {
+            title: title.to_owned(),
+            width: width,
+            height: height,
+            data: vec![0; width as usize * height as usize * 3],
+        }));
+        Ok(DebugSurfaceId(self.debug_surfaces.len() as u32 - 1))
+    }
+
+    fn update_debug_surface(&mut self,
+                            id: DebugSurfaceId,
+                            data: &[u8],
+                            width: u32,
+                            height: u32) -> BackendResult<()> {
+        match self.debug_surfaces.get_mut(id.0 as usize) {
+            Some(&mut Some(ref mut surface)) => {
+                surface.width = width;
+                surface.height = height;
+                surface.data.clear();
+                surface.data.extend_from_slice(data);
+                Ok(())
+            }
+            _ => Err(format!("invalid debug surface: {:?}", id).into()),
+        }
+    }
+
+    fn close_debug_surface(&mut self, id: DebugSurfaceId) {
+        if let Some(surface) = self.debug_surfaces.get_mut(id.0 as usize) {
+            *surface = None;
+        }
+    }
+}
+
+/// Audio sink that stores all samples written to it.
+pub struct CaptureSink {
+    samples: Vec<(i16, i16)>,
+    /// Number of samples written by each `write` call
+    buffer_lens: Vec<usize>,
+}
+
+impl CaptureSink {
+    /// Returns all samples written so far (32 kHz, stereo).
+    pub fn samples(&self) -> &[(i16, i16)] {
+        &self.samples
+    }
+
+    /// Returns the length of every buffer passed to `write`, in order. Together with `samples`,
+    /// this allows splitting the recorded audio back into the original buffers.
+    pub fn buffer_lens(&self) -> &[usize] {
+        &self.buffer_lens
+    }
+
+    /// Removes all recorded samples and returns them.
+    pub fn take_samples(&mut self) -> Vec<(i16, i16)> {
+        self.buffer_lens.clear();
+        ::std::mem::replace(&mut self.samples, Vec::new())
+    }
+}
+
+impl AudioSink for CaptureSink {
+    fn create() -> BackendResult<Self> {
+        Ok(CaptureSink {
+            samples: Vec::new(),
+            buffer_lens: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, data: &[(i16, i16)]) {
+        self.samples.extend_from_slice(data);
+        self.buffer_lens.push(data.len());
+    }
+}
+
+/// A joypad that replays a scripted sequence of inputs.
+///
+/// The script is a list of `(frame, state)` pairs, sorted by frame: Starting at `frame`, the joypad
+/// reports `state` until the next entry takes over. Before the first entry, no buttons are pressed.
+/// The current frame is read from a shared counter, usually `CaptureRenderer::frame_counter`.
+pub struct ScriptedJoypad {
+    frame: Arc<AtomicUsize>,
+    script: Vec<(usize, JoypadState)>,
+}
+
+impl ScriptedJoypad {
+    /// Creates a scripted joypad. `script` will be sorted by frame number.
+    pub fn new(frame: Arc<AtomicUsize>, mut script: Vec<(usize, JoypadState)>) -> Self {
+        script.sort_by_key(|&(frame, _)| frame);
+        ScriptedJoypad {
+            frame: frame,
+            script: script,
+        }
+    }
+}
+
+impl JoypadImpl for ScriptedJoypad {
+    fn update_state(&mut self) -> JoypadState {
+        let frame = self.frame.load(Ordering::SeqCst);
+        self.script.iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map(|&(_, state)| state)
+            .unwrap_or_else(JoypadState::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaptureRenderer, CaptureSink, ScriptedJoypad};
+    use {AudioSink, BackendAction, Renderer};
+    use hash::hash_bytes;
+    use input::joypad::{JoypadButton, JoypadImpl, JoypadState};
+    use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    fn pressed(button: JoypadButton) -> JoypadState {
+        *JoypadState::new().set(button, true)
+    }
+
+    #[test]
+    fn capture_frames() {
+        let mut renderer = CaptureRenderer::create().unwrap();
+        renderer.set_max_frames(Some(2));
+        renderer.exit_after(Some(4));
+        assert_eq!(renderer.frame_size(), (SCREEN_WIDTH, SCREEN_HEIGHT));
+
+        let frames: Vec<Vec<u8>> = (0..4).map(|i| vec![i; 6]).collect();
+        for frame in &frames[..3] {
+            assert_eq!(renderer.render(frame).unwrap(), vec![]);
+        }
+        renderer.set_frame_size(512, 448).unwrap();
+        assert_eq!(renderer.frame_size(), (512, 448));
+        assert_eq!(renderer.render(&frames[3]).unwrap(), vec![BackendAction::Exit]);
+
+        assert_eq!(renderer.frame_count(), 4);
+        // Hashes are kept for the dropped frames as well
+        let hashes: Vec<u64> = frames.iter().map(|frame| hash_bytes(frame)).collect();
+        assert_eq!(renderer.frame_hashes(), &hashes[..]);
+        assert_eq!(renderer.frames(), &frames[2..]);
+        assert_eq!(renderer.last_frame(), Some(&frames[3][..]));
+        assert_eq!(renderer.take_frames(), &frames[2..]);
+        assert_eq!(renderer.last_frame(), None);
+    }
+
+    #[test]
+    fn capture_audio() {
+        let mut sink = CaptureSink::create().unwrap();
+        sink.write(&[(1, -1), (2, -2)]);
+        sink.write(&[]);
+        sink.write(&[(3, -3)]);
+        assert_eq!(sink.samples(), &[(1, -1), (2, -2), (3, -3)]);
+        assert_eq!(sink.buffer_lens(), &[2, 0, 1]);
+        assert_eq!(sink.take_samples().len(), 3);
+        assert!(sink.samples().is_empty() && sink.buffer_lens().is_empty());
+    }
+
+    #[test]
+    fn scripted_joypad() {
+        let mut renderer = CaptureRenderer::create().unwrap();
+        let a = pressed(JoypadButton::A);
+        let start = pressed(JoypadButton::Start);
+        // Out of order on purpose
+        let mut joypad = ScriptedJoypad::new(renderer.frame_counter(),
+                                             vec![(3, JoypadState::new()), (1, a), (2, start)]);
+
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            states.push(joypad.update_state());
+            renderer.render(&[0; 3]).unwrap();
+        }
+        assert_eq!(states, vec![JoypadState::new(), a, start, JoypadState::new(),
+                                JoypadState::new()]);
+    }
+}
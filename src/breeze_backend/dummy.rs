@@ -28,6 +28,10 @@ impl Renderer for DummyRenderer {
     }
 
     fn set_rom_title(&mut self, _title: &str) {}
+
+    fn set_frame_size(&mut self, _width: u32, _height: u32) -> BackendResult<()> {
+        Ok(())
+    }
 }
 
 /// Dummy audio sink with no output
@@ -0,0 +1,273 @@
+//! Video encoding by piping the A/V stream into an `ffmpeg` process.
+//!
+//! Requires the `ffmpeg` binary to be installed and in the `PATH`. Video is piped into ffmpeg
+//! directly, while audio is buffered in a temporary raw PCM file and muxed in when the recording is
+//! finished (ffmpeg can't easily read 2 streams from pipes in a portable way).
+//!
+//! The size of the emulator's frames changes whenever a game switches to or from hi-res, interlace
+//! or overscan mode, but a video stream has a fixed size. Videos are therefore always encoded at
+//! the size of the largest possible frame: Twice the native width and twice the overscan height.
+//! Smaller frames are scaled up by a whole factor on each axis and centered vertically.
+
+use APU_SAMPLE_RATE;
+use av::AvSink;
+use pacing::NTSC_FRAME_RATE;
+use ppu::{OVERSCAN_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// An `AvSink` encoding to a video file using ffmpeg.
+pub struct FfmpegEncoder {
+    output: PathBuf,
+    frame_rate: f64,
+    /// The ffmpeg process encoding the video (started when the first frame arrives, since the
+    /// output size depends on the width of the picture)
+    video: Option<(Child, ChildStdin)>,
+    video_path: PathBuf,
+    canvas: Canvas,
+    audio: BufWriter<File>,
+    audio_path: PathBuf,
+    /// Whether any audio was written (if not, the video is stored without an audio stream)
+    has_audio: bool,
+}
+
+impl FfmpegEncoder {
+    /// Prepares encoding to `output`. The container format is chosen by ffmpeg based on the file
+    /// extension.
+    pub fn new<P: AsRef<Path>>(output: P) -> io::Result<Self> {
+        Self::with_frame_rate(output, NTSC_FRAME_RATE)
+    }
+
+    /// Like `new`, but allows specifying the frame rate of the video (eg. for PAL games).
+    pub fn with_frame_rate<P: AsRef<Path>>(output: P, frame_rate: f64) -> io::Result<Self> {
+        let output = output.as_ref().to_path_buf();
+        let video_path = temp_path(&output, "video.mkv");
+        let audio_path = temp_path(&output, "audio.pcm");
+
+        Ok(FfmpegEncoder {
+            output: output,
+            frame_rate: frame_rate,
+            video: None,
+            video_path: video_path,
+            canvas: Canvas::new(0, 0),
+            audio: BufWriter::new(try!(File::create(&audio_path))),
+            audio_path: audio_path,
+            has_audio: false,
+        })
+    }
+
+    fn start_video(&mut self, frame_w: u32, frame_h: u32) -> io::Result<()> {
+        self.canvas = Canvas::for_frame(frame_w, frame_h);
+        let (width, height) = (self.canvas.width, self.canvas.height);
+        let mut child = try!(Command::new("ffmpeg")
+            .args(&["-loglevel", "error", "-y",
+                    "-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .arg("-s").arg(format!("{}x{}", width, height))
+            .arg("-r").arg(self.frame_rate.to_string())
+            .args(&["-i", "-", "-c:v", "libx264", "-pix_fmt", "yuv420p", "-crf", "18"])
+            .arg(&self.video_path)
+            .stdin(Stdio::piped())
+            .spawn());
+        let stdin = child.stdin.take().unwrap();
+        self.video = Some((child, stdin));
+        Ok(())
+    }
+}
+
+/// Number of frame pixels covering one native pixel on each axis (1 or 2 for the PPU's frames).
+fn density(frame_w: u32, frame_h: u32) -> (u32, u32) {
+    ((frame_w / SCREEN_WIDTH).max(1), (frame_h / SCREEN_HEIGHT).max(1))
+}
+
+/// The fixed-size frame that's passed to ffmpeg.
+struct Canvas {
+    width: u32,
+    height: u32,
+    /// `RGB24` pixels
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Self {
+        Canvas {
+            width: width,
+            height: height,
+            data: vec![0; width as usize * height as usize * 3],
+        }
+    }
+
+    /// Creates a canvas large enough for all frames of a recording starting with a frame of the
+    /// given size. Only the width of the picture matters, since it's widened by the widescreen
+    /// hack.
+    fn for_frame(frame_w: u32, frame_h: u32) -> Self {
+        let native_w = frame_w / density(frame_w, frame_h).0;
+        Canvas::new(native_w * 2, OVERSCAN_HEIGHT * 2)
+    }
+
+    /// Scales `frame` to the density of the canvas and draws it centered, with black bars around
+    /// it.
+    fn draw(&mut self, frame: &[u8], frame_w: u32, frame_h: u32) -> io::Result<&[u8]> {
+        let (density_x, density_y) = density(frame_w, frame_h);
+        let (scale_x, scale_y) = (2 / density_x, 2 / density_y);
+        if scale_x == 0 || scale_y == 0 || frame_w * scale_x > self.width ||
+           frame_h * scale_y > self.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{}x{} frame doesn't fit into the {}x{} video", frame_w, frame_h,
+                        self.width, self.height)));
+        }
+
+        for b in &mut self.data {
+            *b = 0;
+        }
+        let (frame_w, scale_x) = (frame_w as usize, scale_x as usize);
+        let width = self.width as usize;
+        let left = (width - frame_w * scale_x) / 2;
+        let top = (self.height - frame_h * scale_y) as usize / 2;
+        for (y, line) in frame.chunks(frame_w * 3).take(frame_h as usize).enumerate() {
+            for sub_y in 0..scale_y as usize {
+                let row = top + y * scale_y as usize + sub_y;
+                let start = (row * width + left) * 3;
+                let dest = &mut self.data[start..start + frame_w * scale_x * 3];
+                for (x, pixel) in line.chunks(3).enumerate() {
+                    for sub_x in 0..scale_x {
+                        let i = (x * scale_x + sub_x) * 3;
+                        dest[i..i + 3].copy_from_slice(pixel);
+                    }
+                }
+            }
+        }
+        Ok(&self.data)
+    }
+}
+
+/// Builds the path of a temporary file stored next to `output`.
+fn temp_path(output: &Path, suffix: &str) -> PathBuf {
+    let mut name = output.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    output.with_file_name(name)
+}
+
+fn check_status(child: &mut Child) -> io::Result<()> {
+    let status = try!(child.wait());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("ffmpeg failed: {}", status)))
+    }
+}
+
+impl AvSink for FfmpegEncoder {
+    fn video_frame(&mut self, frame: &[u8], width: u32, height: u32) -> io::Result<()> {
+        if self.video.is_none() {
+            try!(self.start_video(width, height));
+        }
+
+        let data = try!(self.canvas.draw(frame, width, height));
+        let &mut (_, ref mut stdin) = self.video.as_mut().unwrap();
+        stdin.write_all(data)
+    }
+
+    fn audio_samples(&mut self, samples: &[(i16, i16)]) -> io::Result<()> {
+        self.has_audio |= !samples.is_empty();
+        for &(l, r) in samples {
+            try!(self.audio.write_all(&[l as u8, (l >> 8) as u8, r as u8, (r >> 8) as u8]));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        try!(self.audio.flush());
+
+        let (mut child, stdin) = match self.video.take() {
+            Some(video) => video,
+            None => {
+                // Nothing was recorded
+                let _ = fs::remove_file(&self.audio_path);
+                return Ok(());
+            }
+        };
+        // Closing stdin makes ffmpeg finish the file
+        drop(stdin);
+        try!(check_status(&mut child));
+
+        // Mux video and audio into the final file
+        let mut mux = Command::new("ffmpeg");
+        mux.args(&["-loglevel", "error", "-y"]).arg("-i").arg(&self.video_path);
+        if self.has_audio {
+            mux.args(&["-f", "s16le", "-ac", "2"])
+               .arg("-ar").arg(APU_SAMPLE_RATE.to_string())
+               .arg("-i").arg(&self.audio_path)
+               .args(&["-c:a", "aac", "-shortest"]);
+        }
+        mux.args(&["-c:v", "copy"]).arg(&self.output);
+        let result = mux.spawn().and_then(|mut child| check_status(&mut child));
+
+        let _ = fs::remove_file(&self.video_path);
+        let _ = fs::remove_file(&self.audio_path);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Canvas;
+
+    /// Creates a frame whose pixels all have a different color.
+    fn frame(w: u32, h: u32) -> Vec<u8> {
+        (0..w * h).flat_map(|i| vec![i as u8, (i >> 8) as u8, (i >> 16) as u8]).collect()
+    }
+
+    fn pixel(canvas: &Canvas, x: u32, y: u32) -> &[u8] {
+        let start = (y * canvas.width + x) as usize * 3;
+        &canvas.data[start..start + 3]
+    }
+
+    #[test]
+    fn hires_switch() {
+        let mut canvas = Canvas::for_frame(256, 224);
+        assert_eq!((canvas.width, canvas.height), (512, 478));
+
+        // Normal frame: Doubled on both axes, 15 black lines above and below
+        let normal = frame(256, 224);
+        assert_eq!(canvas.draw(&normal, 256, 224).unwrap().len(), 512 * 478 * 3);
+        assert_eq!(pixel(&canvas, 0, 14), [0, 0, 0]);
+        assert_eq!(pixel(&canvas, 511, 463), [0, 0, 0]);
+        for &(x, y) in &[(0, 15), (1, 16), (3, 15), (511, 462)] {
+            let native = (y - 15) / 2 * 256 + x / 2;
+            assert_eq!(pixel(&canvas, x, y), &normal[native as usize * 3..native as usize * 3 + 3]);
+        }
+
+        // The game switches to hi-res: Only doubled vertically
+        let hires = frame(512, 224);
+        assert_eq!(canvas.draw(&hires, 512, 224).unwrap().len(), 512 * 478 * 3);
+        assert_eq!(pixel(&canvas, 0, 14), [0, 0, 0]);
+        for &(x, y) in &[(0, 15), (1, 16), (3, 15), (511, 462)] {
+            let native = (y - 15) / 2 * 512 + x;
+            assert_eq!(pixel(&canvas, x, y), &hires[native as usize * 3..native as usize * 3 + 3]);
+        }
+
+        // Hi-res, interlaced and overscan frames fill the whole canvas
+        let full = frame(512, 478);
+        assert!(canvas.draw(&full, 512, 478).unwrap() == &full[..]);
+        let overscan = frame(256, 239);
+        canvas.draw(&overscan, 256, 239).unwrap();
+        assert_eq!(pixel(&canvas, 511, 477), &overscan[(239 * 256 - 1) * 3..]);
+    }
+
+    #[test]
+    fn widescreen() {
+        let mut canvas = Canvas::for_frame(384, 224);
+        assert_eq!((canvas.width, canvas.height), (768, 478));
+
+        // Hi-res frames don't have widescreen margins
+        canvas.draw(&frame(512, 448), 512, 448).unwrap();
+        assert_eq!(pixel(&canvas, 127, 100), [0, 0, 0]);
+        assert_eq!(pixel(&canvas, 129, 15), [1, 0, 0]);
+
+        assert!(canvas.draw(&frame(1024, 896), 1024, 896).is_err());
+    }
+}
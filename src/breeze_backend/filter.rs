@@ -0,0 +1,315 @@
+//! CPU-side post-processing filters.
+//!
+//! Filters take the `RGB24` frame produced by the PPU and transform it into another (usually
+//! larger) `RGB24` image, which is then displayed by the renderer instead of the original frame.
+//! Renderers that support filters use a `FilterStage` to apply them.
+
+/// A post-processing filter operating on `RGB24` images.
+pub trait Filter {
+    /// Returns the size of the image produced when filtering an image of the given size.
+    fn output_size(&self, width: u32, height: u32) -> (u32, u32);
+
+    /// Filters `input` (`width * height` pixels) and writes the result to `output`, which has the
+    /// size returned by `output_size`.
+    fn apply(&mut self, input: &[u8], width: u32, height: u32, output: &mut [u8]);
+}
+
+/// Parses a filter description. Known filters are `none`, `nearest[:SCALE]`, `scanlines` and
+/// `xbrz`. `none` results in `Ok(None)`.
+pub fn parse_filter(s: &str) -> Result<Option<Box<Filter>>, String> {
+    let mut split = s.splitn(2, ':');
+    let name = split.next().unwrap();
+    let arg = split.next();
+
+    let filter: Box<Filter> = match (name, arg) {
+        ("none", None) => return Ok(None),
+        ("nearest", None) => Box::new(Nearest { scale: 2 }),
+        ("nearest", Some(scale)) => match scale.parse() {
+            Ok(scale) if scale > 0 => Box::new(Nearest { scale: scale }),
+            _ => return Err(format!("invalid scale factor: {}", scale)),
+        },
+        ("scanlines", None) => Box::new(Scanlines::default()),
+        ("xbrz", None) => Box::new(Xbrz),
+        _ => return Err(format!("unknown filter: {} (expected `none`, `nearest[:SCALE]`, \
+                                 `scanlines` or `xbrz`)", s)),
+    };
+    Ok(Some(filter))
+}
+
+/// Scales the image up by an integer factor without any interpolation.
+pub struct Nearest {
+    pub scale: u32,
+}
+
+impl Filter for Nearest {
+    fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (width * self.scale, height * self.scale)
+    }
+
+    fn apply(&mut self, input: &[u8], width: u32, height: u32, output: &mut [u8]) {
+        let scale = self.scale as usize;
+        let (w, h) = (width as usize, height as usize);
+        let out_w = w * scale;
+        for y in 0..h * scale {
+            for x in 0..out_w {
+                let src = ((y / scale) * w + x / scale) * 3;
+                let dst = (y * out_w + x) * 3;
+                output[dst..dst + 3].copy_from_slice(&input[src..src + 3]);
+            }
+        }
+    }
+}
+
+/// Doubles the image size and darkens every second line, imitating the gaps between the scanlines
+/// of a CRT.
+pub struct Scanlines {
+    /// Brightness of the inserted lines (`0` is black, `255` duplicates the line above)
+    pub brightness: u8,
+}
+
+impl Default for Scanlines {
+    fn default() -> Self {
+        Scanlines { brightness: 160 }
+    }
+}
+
+impl Filter for Scanlines {
+    fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (width * 2, height * 2)
+    }
+
+    fn apply(&mut self, input: &[u8], width: u32, height: u32, output: &mut [u8]) {
+        let (w, h) = (width as usize, height as usize);
+        let out_w = w * 2;
+        for y in 0..h * 2 {
+            let dark = y % 2 == 1;
+            for x in 0..out_w {
+                let src = ((y / 2) * w + x / 2) * 3;
+                let dst = (y * out_w + x) * 3;
+                for c in 0..3 {
+                    output[dst + c] = if dark {
+                        (input[src + c] as u16 * self.brightness as u16 / 255) as u8
+                    } else {
+                        input[src + c]
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// An edge-directed 2x scaler in the spirit of xBRZ.
+///
+/// This is a simplified version that only detects diagonal edges (using the xBR level 1 rules) and
+/// smooths them by blending the affected corner pixels. It does not do xBRZ's shallow and steep
+/// line detection, but still gets rid of most of the staircase effect.
+pub struct Xbrz;
+
+type Rgb = [u8; 3];
+
+/// Perceptual distance between two colors, computed in YUV space (luma is weighted the most).
+fn color_dist(x: Rgb, y: Rgb) -> u32 {
+    let diff = |c: usize| x[c] as f32 - y[c] as f32;
+    let (r, g, b) = (diff(0), diff(1), diff(2));
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = -0.169 * r - 0.331 * g + 0.5 * b;
+    let v = 0.5 * r - 0.419 * g - 0.081 * b;
+    (48.0 * y.abs() + 7.0 * u.abs() + 6.0 * v.abs()) as u32
+}
+
+fn blend(a: Rgb, b: Rgb) -> Rgb {
+    [
+        ((a[0] as u16 + b[0] as u16) / 2) as u8,
+        ((a[1] as u16 + b[1] as u16) / 2) as u8,
+        ((a[2] as u16 + b[2] as u16) / 2) as u8,
+    ]
+}
+
+impl Filter for Xbrz {
+    fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (width * 2, height * 2)
+    }
+
+    fn apply(&mut self, input: &[u8], width: u32, height: u32, output: &mut [u8]) {
+        let (w, h) = (width as i32, height as i32);
+        // Fetches a pixel, clamping the coordinates to the image
+        let px = |x: i32, y: i32| -> Rgb {
+            let x = if x < 0 { 0 } else if x >= w { w - 1 } else { x };
+            let y = if y < 0 { 0 } else if y >= h { h - 1 } else { y };
+            let i = (y * w + x) as usize * 3;
+            [input[i], input[i + 1], input[i + 2]]
+        };
+
+        for y in 0..h {
+            for x in 0..w {
+                let e = px(x, y);
+
+                // Handle each of the 4 output pixels (corners). The offsets are mirrored so that
+                // the same rules work for all corners (they're written for the bottom-right one).
+                for &(dx, dy) in &[(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+                    let p = |ox: i32, oy: i32| px(x + ox * dx, y + oy * dy);
+                    let (b, c, d, f, g, hh, i) =
+                        (p(0, -1), p(1, -1), p(-1, 0), p(1, 0), p(-1, 1), p(0, 1), p(1, 1));
+                    let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+                    // Weight of an edge along F-H vs. one along E-I
+                    let wd1 = color_dist(e, c) + color_dist(e, g) + color_dist(i, f4) +
+                              color_dist(i, h5) + 4 * color_dist(hh, f);
+                    let wd2 = color_dist(hh, d) + color_dist(hh, i5) + color_dist(f, i4) +
+                              color_dist(f, b) + 4 * color_dist(e, i);
+
+                    let color = if wd1 < wd2 && e != f && e != hh {
+                        let new = if color_dist(e, f) <= color_dist(e, hh) { f } else { hh };
+                        blend(e, new)
+                    } else {
+                        e
+                    };
+
+                    let out_x = (x * 2 + (dx + 1) / 2) as usize;
+                    let out_y = (y * 2 + (dy + 1) / 2) as usize;
+                    let dst = (out_y * w as usize * 2 + out_x) * 3;
+                    output[dst..dst + 3].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+}
+
+/// Applies an optional filter to frames. Used by renderers to implement `Renderer::set_filter`.
+pub struct FilterStage {
+    filter: Option<Box<Filter>>,
+    buf: Vec<u8>,
+}
+
+impl FilterStage {
+    /// Creates a filter stage that doesn't filter.
+    pub fn new() -> Self {
+        FilterStage {
+            filter: None,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: Option<Box<Filter>>) {
+        self.filter = filter;
+    }
+
+    /// Returns the size of the filtered image for an input of the given size.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.filter {
+            Some(ref filter) => filter.output_size(width, height),
+            None => (width, height),
+        }
+    }
+
+    /// Filters a frame of the given size and returns the result. If no filter is set, `frame` is
+    /// returned unchanged.
+    pub fn process<'a>(&'a mut self, frame: &'a [u8], width: u32, height: u32) -> &'a [u8] {
+        match self.filter {
+            Some(ref mut filter) => {
+                let (out_w, out_h) = filter.output_size(width, height);
+                self.buf.resize(out_w as usize * out_h as usize * 3, 0);
+                filter.apply(frame, width, height, &mut self.buf);
+                &self.buf
+            }
+            None => frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_filter, Filter, FilterStage, Nearest, Scanlines, Xbrz};
+
+    const W: [u8; 3] = [255, 255, 255];
+    const K: [u8; 3] = [0, 0, 0];
+
+    fn image(pixels: &[[u8; 3]]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.iter().cloned()).collect()
+    }
+
+    fn apply<F: Filter>(mut filter: F, input: &[u8], width: u32, height: u32) -> Vec<u8> {
+        let (out_w, out_h) = filter.output_size(width, height);
+        let mut output = vec![0; out_w as usize * out_h as usize * 3];
+        filter.apply(input, width, height, &mut output);
+        output
+    }
+
+    #[test]
+    fn parse() {
+        assert!(parse_filter("none").unwrap().is_none());
+        for &(s, size) in &[("nearest", (512, 448)), ("nearest:3", (768, 672)),
+                            ("scanlines", (512, 448)), ("xbrz", (512, 448))] {
+            assert_eq!(parse_filter(s).unwrap().unwrap().output_size(256, 224), size);
+        }
+
+        for s in &["", "bilinear", "Nearest", "nearest:0", "nearest:x", "scanlines:2", "none:1"] {
+            assert!(parse_filter(s).is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn nearest() {
+        let input = image(&[[1, 2, 3], [4, 5, 6]]);
+        let output = apply(Nearest { scale: 2 }, &input, 2, 1);
+        assert_eq!(output, image(&[[1, 2, 3], [1, 2, 3], [4, 5, 6], [4, 5, 6],
+                                   [1, 2, 3], [1, 2, 3], [4, 5, 6], [4, 5, 6]]));
+        assert_eq!(apply(Nearest { scale: 1 }, &input, 2, 1), input);
+    }
+
+    #[test]
+    fn scanlines() {
+        let input = image(&[[255, 100, 0], W]);
+        let filter = Scanlines::default();
+        assert_eq!(filter.output_size(256, 239), (512, 478));
+        assert_eq!(apply(filter, &input, 2, 1),
+                   image(&[[255, 100, 0], [255, 100, 0], W, W,
+                           [160, 62, 0], [160, 62, 0], [160, 160, 160], [160, 160, 160]]));
+
+        let black = apply(Scanlines { brightness: 0 }, &input, 2, 1);
+        assert_eq!(&black[12..], &[0; 12][..]);
+    }
+
+    #[test]
+    fn xbrz() {
+        assert_eq!(Xbrz.output_size(512, 448), (1024, 896));
+
+        // Flat areas are just doubled
+        let flat = image(&[W; 9]);
+        assert_eq!(apply(Xbrz, &flat, 3, 3), image(&[W; 36]));
+
+        // A diagonal edge gets its corners smoothed
+        let diagonal = image(&[W, W, W, W,
+                               W, W, W, K,
+                               W, W, K, K,
+                               W, K, K, K]);
+        let output = apply(Xbrz, &diagonal, 4, 4);
+        let pixel = |x: usize, y: usize| &output[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), W);
+        assert_eq!(pixel(7, 7), K);
+        // The inner corners of the staircase are blended
+        assert_eq!(pixel(5, 3), [127, 127, 127]);
+        assert_eq!(pixel(4, 4), [127, 127, 127]);
+        assert_eq!(pixel(5, 4), K);
+        assert_eq!(pixel(4, 3), W);
+        assert!(output.chunks(3).all(|p| p == W || p == K || p == [127, 127, 127]));
+    }
+
+    #[test]
+    fn stage() {
+        let input = image(&[[1, 2, 3], [4, 5, 6]]);
+        let mut stage = FilterStage::new();
+        assert_eq!(stage.output_size(2, 1), (2, 1));
+        assert_eq!(stage.process(&input, 2, 1), &input[..]);
+
+        stage.set_filter(parse_filter("nearest:3").unwrap());
+        assert_eq!(stage.output_size(2, 1), (6, 3));
+        let expected = apply(Nearest { scale: 3 }, &input, 2, 1);
+        assert_eq!(stage.process(&input, 2, 1), &expected[..]);
+        // Frame size changes resize the buffer
+        assert_eq!(stage.process(&input, 1, 2).len(), 3 * 6 * 3);
+
+        stage.set_filter(None);
+        assert_eq!(stage.process(&input, 2, 1), &input[..]);
+    }
+}
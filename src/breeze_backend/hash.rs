@@ -0,0 +1,53 @@
+//! Stable hashing of emulator output
+//!
+//! Test harnesses can use this to check that a ROM still produces the same frames (or audio)
+//! without storing reference images. We use 64-bit FNV-1a, since it's trivial and (unlike the
+//! hasher in `std`) guaranteed to produce the same result on every platform and Rust version.
+
+use std::hash::Hasher;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// An FNV-1a hasher.
+///
+/// Only feed it raw bytes (`write`) if the result must be portable: The `write_*` methods for
+/// wider integers use native endianness.
+#[derive(Copy, Clone, Debug)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Hashes a byte slice.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Hashes a buffer of audio samples. Samples are hashed in little-endian byte order, so the result
+/// is the same on every platform.
+pub fn hash_samples(samples: &[(i16, i16)]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    for &(l, r) in samples {
+        hasher.write(&[l as u8, (l >> 8) as u8, r as u8, (r >> 8) as u8]);
+    }
+    hasher.finish()
+}
@@ -0,0 +1,210 @@
+//! Configurable hotkeys
+//!
+//! Backends translate their key events into key names and look them up in a `HotkeyMap`, which
+//! tells them which `BackendAction` to return to the emulator.
+//!
+//! Key names are case-insensitive and follow SDL's naming: Letters and digits are named after the
+//! character on the key (`P`, `1`), other keys have names like `F5`, `Tab`, `Backspace`, `Space`,
+//! `Return` or `Escape`.
+
+use BackendAction;
+use input::joypad::JoypadButton;
+
+use std::str::FromStr;
+
+/// The actions that can be bound to a key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    /// Return to the state from before the last `LoadState`
+    UndoLoadState,
+    /// Restore the save state overwritten by the last `SaveState`
+    UndoSaveState,
+    /// Rewind while held
+    Rewind,
+    /// Run as fast as possible while held
+    FastForward,
+    /// Run in slow motion while held
+    SlowMotion,
+    Screenshot,
+    Reset,
+    /// Reload the ROM file and reset
+    ReloadRom,
+    /// Pause or resume emulation
+    Pause,
+    /// Emulate a single frame while paused
+    FrameAdvance,
+    /// Play the input macro in a slot (`0-8`, named `play-macro-1` to `play-macro-9`)
+    PlayMacro(u8),
+    /// Start or stop recording a macro into a slot (`record-macro-1` to `record-macro-9`)
+    RecordMacro(u8),
+    /// Hold a joypad button until this is pressed again (`hold-a`, `hold-start`, ...)
+    ToggleHold(JoypadButton),
+    Exit,
+}
+
+/// Number of input macro slots
+pub const MACRO_SLOTS: u8 = 9;
+
+impl Hotkey {
+    /// Returns whether this hotkey is active while the key is held (instead of firing once when
+    /// it's pressed).
+    pub fn is_held(&self) -> bool {
+        match *self {
+            Hotkey::Rewind | Hotkey::FastForward | Hotkey::SlowMotion => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the action to perform when the key is pressed (`pressed = true`) or released.
+    pub fn action(&self, pressed: bool) -> Option<BackendAction> {
+        if !pressed && !self.is_held() {
+            return None;
+        }
+
+        Some(match *self {
+            Hotkey::SaveState => BackendAction::SaveState,
+            Hotkey::LoadState => BackendAction::LoadState,
+            Hotkey::UndoLoadState => BackendAction::UndoLoadState,
+            Hotkey::UndoSaveState => BackendAction::UndoSaveState,
+            Hotkey::Rewind => BackendAction::Rewind(pressed),
+            Hotkey::FastForward => BackendAction::FastForward(pressed),
+            Hotkey::SlowMotion => BackendAction::SlowMotion(pressed),
+            Hotkey::Screenshot => BackendAction::Screenshot,
+            Hotkey::Reset => BackendAction::Reset,
+            Hotkey::ReloadRom => BackendAction::ReloadRom,
+            Hotkey::Pause => BackendAction::TogglePause,
+            Hotkey::FrameAdvance => BackendAction::FrameAdvance,
+            Hotkey::PlayMacro(slot) => BackendAction::PlayMacro(slot),
+            Hotkey::RecordMacro(slot) => BackendAction::RecordMacro(slot),
+            Hotkey::ToggleHold(button) => BackendAction::ToggleHold(button),
+            Hotkey::Exit => BackendAction::Exit,
+        })
+    }
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        fn macro_slot(s: &str) -> Option<u8> {
+            match s.parse::<u8>() {
+                Ok(n) if n >= 1 && n <= MACRO_SLOTS => Some(n - 1),
+                _ => None,
+            }
+        }
+
+        if s.starts_with("play-macro-") {
+            if let Some(slot) = macro_slot(&s["play-macro-".len()..]) {
+                return Ok(Hotkey::PlayMacro(slot));
+            }
+        }
+        if s.starts_with("record-macro-") {
+            if let Some(slot) = macro_slot(&s["record-macro-".len()..]) {
+                return Ok(Hotkey::RecordMacro(slot));
+            }
+        }
+        if s.starts_with("hold-") {
+            if let Ok(button) = s["hold-".len()..].parse() {
+                return Ok(Hotkey::ToggleHold(button));
+            }
+        }
+
+        Ok(match s {
+            "save-state" => Hotkey::SaveState,
+            "load-state" => Hotkey::LoadState,
+            "undo-load-state" => Hotkey::UndoLoadState,
+            "undo-save-state" => Hotkey::UndoSaveState,
+            "rewind" => Hotkey::Rewind,
+            "fast-forward" => Hotkey::FastForward,
+            "slow-motion" => Hotkey::SlowMotion,
+            "screenshot" => Hotkey::Screenshot,
+            "reset" => Hotkey::Reset,
+            "reload-rom" => Hotkey::ReloadRom,
+            "pause" => Hotkey::Pause,
+            "frame-advance" => Hotkey::FrameAdvance,
+            "exit" => Hotkey::Exit,
+            _ => return Err(format!("unknown hotkey action: {} (expected one of `save-state`, \
+                                     `load-state`, `undo-load-state`, `undo-save-state`, \
+                                     `rewind`, `fast-forward`, `slow-motion`, `screenshot`, \
+                                     `reset`, `reload-rom`, `pause`, `frame-advance`, \
+                                     `play-macro-N`, `record-macro-N`, `hold-BUTTON` or \
+                                     `exit`)", s)),
+        })
+    }
+}
+
+/// Maps key names to hotkeys.
+#[derive(Clone, Debug)]
+pub struct HotkeyMap {
+    /// (key name, hotkey) pairs. Key names are stored in lowercase.
+    bindings: Vec<(String, Hotkey)>,
+}
+
+impl HotkeyMap {
+    /// Creates a map without any bindings.
+    pub fn empty() -> Self {
+        HotkeyMap { bindings: Vec::new() }
+    }
+
+    /// Binds `key` to `hotkey`, replacing the previous binding of that key.
+    pub fn bind(&mut self, key: &str, hotkey: Hotkey) {
+        let key = key.to_ascii_lowercase();
+        self.bindings.retain(|&(ref k, _)| *k != key);
+        self.bindings.push((key, hotkey));
+    }
+
+    /// Removes all bindings of `hotkey`.
+    pub fn unbind(&mut self, hotkey: Hotkey) {
+        self.bindings.retain(|&(_, h)| h != hotkey);
+    }
+
+    /// Parses and applies a binding of the form `KEY=ACTION` (eg. `F5=save-state`). An empty key
+    /// (`=ACTION`) removes all bindings of the action.
+    pub fn apply_binding(&mut self, binding: &str) -> Result<(), String> {
+        let mut split = binding.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(action)) => {
+                let hotkey = try!(action.trim().parse());
+                let key = key.trim();
+                if key.is_empty() {
+                    self.unbind(hotkey);
+                } else {
+                    self.bind(key, hotkey);
+                }
+                Ok(())
+            }
+            _ => Err(format!("invalid hotkey binding (expected `KEY=ACTION`): {}", binding)),
+        }
+    }
+
+    /// Looks up the hotkey bound to `key`.
+    pub fn get(&self, key: &str) -> Option<Hotkey> {
+        self.bindings.iter()
+            .find(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, hotkey)| hotkey)
+    }
+
+    /// Handles a key press or release. Returns the action to perform, if any.
+    pub fn handle_key(&self, key: &str, pressed: bool) -> Option<BackendAction> {
+        self.get(key).and_then(|hotkey| hotkey.action(pressed))
+    }
+}
+
+impl Default for HotkeyMap {
+    fn default() -> Self {
+        let mut map = HotkeyMap::empty();
+        map.bind("F5", Hotkey::SaveState);
+        map.bind("F9", Hotkey::LoadState);
+        map.bind("F10", Hotkey::UndoLoadState);
+        map.bind("Backspace", Hotkey::Rewind);
+        map.bind("Tab", Hotkey::FastForward);
+        map.bind("Backslash", Hotkey::SlowMotion);
+        map.bind("F12", Hotkey::Screenshot);
+        map.bind("F8", Hotkey::Reset);
+        map.bind("F7", Hotkey::Pause);
+        map.bind("F6", Hotkey::FrameAdvance);
+        map
+    }
+}
@@ -3,10 +3,14 @@
 
 // FIXME Allow configuring left+right/up+down behaviour
 
+use std::ops::BitOr;
+use std::str::FromStr;
+
 /// (C-like) Enum of all Joypad buttons.
 ///
 /// Discriminants are the button's bit numbers in `JoypadState` (the highest number will be read
 /// first).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum JoypadButton {
     A = 7,
     B = 15,
@@ -22,11 +26,66 @@ pub enum JoypadButton {
     Right = 8,
 }
 
+/// All joypad buttons, in the order they're usually listed
+pub const BUTTONS: [JoypadButton; 12] = [
+    JoypadButton::A, JoypadButton::B, JoypadButton::X, JoypadButton::Y,
+    JoypadButton::L, JoypadButton::R, JoypadButton::Start, JoypadButton::Select,
+    JoypadButton::Up, JoypadButton::Down, JoypadButton::Left, JoypadButton::Right,
+];
+
+impl JoypadButton {
+    /// Returns the button's name, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        use self::JoypadButton::*;
+
+        match *self {
+            A => "a",
+            B => "b",
+            X => "x",
+            Y => "y",
+            L => "l",
+            R => "r",
+            Start => "start",
+            Select => "select",
+            Up => "up",
+            Down => "down",
+            Left => "left",
+            Right => "right",
+        }
+    }
+}
+
+impl FromStr for JoypadButton {
+    type Err = String;
+
+    /// Parses a button name (`a`, `b`, `x`, `y`, `l`, `r`, `start`, `select`, `up`, `down`,
+    /// `left` or `right`, case-insensitive).
+    fn from_str(s: &str) -> Result<Self, String> {
+        use self::JoypadButton::*;
+
+        Ok(match &*s.to_lowercase() {
+            "a" => A,
+            "b" => B,
+            "x" => X,
+            "y" => Y,
+            "l" => L,
+            "r" => R,
+            "start" => Start,
+            "select" => Select,
+            "up" => Up,
+            "down" => Down,
+            "left" => Left,
+            "right" => Right,
+            _ => return Err(format!("unknown joypad button: {}", s)),
+        })
+    }
+}
+
 /// State of a SNES joypad.
 ///
 /// Bits (`HIGH | LOW`, returned on Data1 from high to low, or left to right):
 /// `B Y Select Start Up Down Left Right | A X L R 0 0 0 0`
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
 pub struct JoypadState(u16);
 
 impl JoypadState {
@@ -44,6 +103,20 @@ impl JoypadState {
         self
     }
 
+    /// Returns whether a button is pressed.
+    pub fn is_pressed(&self, button: JoypadButton) -> bool {
+        self.0 & 1 << button as u8 != 0
+    }
+
+    /// Returns whether no button is pressed.
+    pub fn is_empty(&self) -> bool { self.0 == 0 }
+
+    /// Returns the raw button bits, in the order described above.
+    pub fn bits(&self) -> u16 { self.0 }
+
+    /// Creates a state from raw button bits, as returned by `bits`.
+    pub fn from_bits(bits: u16) -> Self { JoypadState(bits) }
+
     /// Reads a bit from the state, as if the state would be stored inside the joypads shift
     /// register. This shifts the state to the left and inserts a 1-bit at the right side.
     pub fn read_bit(&mut self) -> bool {
@@ -57,11 +130,23 @@ impl JoypadState {
     }
 }
 
+/// Combines the pressed buttons of two states.
+impl BitOr for JoypadState {
+    type Output = JoypadState;
+
+    fn bitor(self, rhs: JoypadState) -> JoypadState {
+        JoypadState(self.0 | rhs.0)
+    }
+}
+
 /// Trait for joypad implementations.
 ///
 /// This should be implemented by the backend, and is a simple abstraction from the bit-level
 /// trickery at the controller port made for joysticks.
-pub trait JoypadImpl {
+///
+/// Implementations must be `Send`, since the emulated system (including everything plugged into
+/// it) can be moved to another thread.
+pub trait JoypadImpl: Send {
     /// Called to "latch" the current joypad state.
     ///
     /// This should check and return the current state of the joypad.
@@ -1,15 +1,42 @@
 #![deny(warnings)]
 #![deny(unused_import_braces, unused_qualifications, unused_extern_crates)]
 
+#[macro_use] extern crate log;
+
 pub mod input;
+pub mod av;
+pub mod capture;
 pub mod dummy;
+#[cfg(feature = "ffmpeg")]
+pub mod ffmpeg;
+pub mod filter;
+pub mod hash;
+pub mod hotkey;
+pub mod osd;
+pub mod pacing;
+#[cfg(feature = "png")]
+pub mod png;
 pub mod ppu;
+pub mod rate_control;
+pub mod resample;
 pub mod viewport;
+pub mod wav;
+
+use filter::Filter;
+use hotkey::HotkeyMap;
+use input::joypad::JoypadButton;
+use pacing::FrameTiming;
+use viewport::DisplayOptions;
 
 use std::error::Error;
+use std::time::Duration;
+
+/// Sample rate of the audio produced by the APU (in Hz).
+pub const APU_SAMPLE_RATE: u32 = 32000;
 
 /// An action that can be performed by the user, is detected by the backend and executed by the
 /// emulator core.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BackendAction {
     /// Exit the emulator
     Exit,
@@ -17,8 +44,40 @@ pub enum BackendAction {
     SaveState,
     /// Restore the last save state
     LoadState,
+    /// Go back to the state the console was in before the last save state was loaded
+    UndoLoadState,
+    /// Put back the save state that was overwritten by the last `SaveState`
+    UndoSaveState,
+    /// Start (`true`) or stop (`false`) rewinding
+    Rewind(bool),
+    /// Start (`true`) or stop (`false`) running as fast as possible
+    FastForward(bool),
+    /// Start (`true`) or stop (`false`) running in slow motion
+    SlowMotion(bool),
+    /// Save the current frame to a file
+    Screenshot,
+    /// Reset the console
+    Reset,
+    /// Load the ROM file again (after it was rebuilt) and reset the console
+    ReloadRom,
+    /// Pause or resume emulation
+    TogglePause,
+    /// Emulate a single frame, then pause
+    FrameAdvance,
+    /// Play the input macro in a slot (`0-8`)
+    PlayMacro(u8),
+    /// Start or stop recording an input macro into a slot (`0-8`)
+    RecordMacro(u8),
+    /// Toggle holding a joypad button on port 0
+    ToggleHold(JoypadButton),
+    /// The user closed a debug surface. Its ID is no longer valid.
+    DebugSurfaceClosed(DebugSurfaceId),
 }
 
+/// Identifies a debug surface opened with `Renderer::open_debug_surface`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DebugSurfaceId(pub u32);
+
 /// Result with an erased error type.
 pub type BackendResult<T> = Result<T, Box<dyn Error>>;
 
@@ -35,15 +94,90 @@ pub trait Renderer {
     /// in order, one byte per channel, then for the next pixel (left to right, top line to bottom
     /// line).
     ///
-    /// The renderer is also responsible for timing: It should only return to the caller when
-    /// another frame should be rendered. This also affects input latency: As soon as the `render`
-    /// method returns, the input devices can be queried by the running program. This allows
-    /// intricate timing mechanisms for better input latency and makes support for dynamic refresh
-    /// easier. If the renderer returns immediately, the emulator will run at maximum speed.
+    /// If the renderer waits for vsync, it should report that via `frame_timing`. The emulator
+    /// will then only add its own delays if the display's refresh rate doesn't match the emulated
+    /// console. This also affects input latency: As soon as the `render` method returns, the input
+    /// devices can be queried by the running program.
     fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>>;
 
     /// Set the ROM title. This usually sets the window title.
     fn set_rom_title(&mut self, title: &str);
+
+    /// Sets the post-processing filter applied to every frame before displaying it (`None`
+    /// disables filtering).
+    ///
+    /// Renderers that don't support filters return an error.
+    fn set_filter(&mut self, _filter: Option<Box<Filter>>) -> BackendResult<()> {
+        Err("this renderer doesn't support filters".into())
+    }
+
+    /// Sets a GPU shader used to draw frames. `shader` is either the name of a shader built into
+    /// the renderer, or the source code of a custom shader (the language depends on the renderer).
+    ///
+    /// Renderers that don't support shaders return an error.
+    fn set_shader(&mut self, _shader: &str) -> BackendResult<()> {
+        Err("this renderer doesn't support shaders".into())
+    }
+
+    /// Changes how the output is scaled to fit the window.
+    ///
+    /// Renderers that don't display anything return an error.
+    fn set_display_options(&mut self, _opts: DisplayOptions) -> BackendResult<()> {
+        Err("this renderer doesn't support display options".into())
+    }
+
+    /// Resizes the window to `scale` times the native resolution of the console. The user may
+    /// resize it afterwards.
+    ///
+    /// Renderers without a window return an error.
+    fn set_window_scale(&mut self, _scale: u32) -> BackendResult<()> {
+        Err("this renderer doesn't support window scaling".into())
+    }
+
+    /// Changes the size (in pixels) of the frames passed to `render`. Frames have the native size
+    /// of `ppu::SCREEN_WIDTH * ppu::SCREEN_HEIGHT` pixels by default, enhancements like widescreen
+    /// rendering produce larger ones.
+    ///
+    /// Renderers that only support the native size return an error.
+    fn set_frame_size(&mut self, _width: u32, _height: u32) -> BackendResult<()> {
+        Err("this renderer only supports the native frame size".into())
+    }
+
+    /// Sets the hotkeys the renderer should react to. Renderers without keyboard handling return an
+    /// error.
+    fn set_hotkeys(&mut self, _hotkeys: HotkeyMap) -> BackendResult<()> {
+        Err("this renderer doesn't support hotkeys".into())
+    }
+
+    /// Describes how `render` limits the frame rate. The default is `FrameTiming::Unlimited`,
+    /// which is only appropriate for renderers that don't display anything.
+    fn frame_timing(&self) -> FrameTiming { FrameTiming::Unlimited }
+
+    /// Opens an additional surface (usually a separate window) that debug viewers (tilemap, OAM,
+    /// etc.) can draw to. `width` and `height` are the size of the images that will be displayed
+    /// initially.
+    ///
+    /// Renderers that can only display the main screen return an error.
+    fn open_debug_surface(&mut self, _title: &str, _width: u32, _height: u32)
+    -> BackendResult<DebugSurfaceId> {
+        Err("this renderer doesn't support debug surfaces".into())
+    }
+
+    /// Displays an `RGB24` image (see `render`) of size `width * height` on a debug surface. The
+    /// size may change between calls.
+    ///
+    /// The image should become visible with the next call to `render`. When the user closes a
+    /// debug surface, `render` returns `BackendAction::DebugSurfaceClosed`.
+    fn update_debug_surface(&mut self,
+                            _id: DebugSurfaceId,
+                            _data: &[u8],
+                            _width: u32,
+                            _height: u32) -> BackendResult<()> {
+        Err("this renderer doesn't support debug surfaces".into())
+    }
+
+    /// Closes a debug surface. Does nothing if the ID is invalid.
+    fn close_debug_surface(&mut self, _id: DebugSurfaceId) {}
 }
 
 // XXX https://github.com/rust-lang/rust/issues/22194
@@ -59,17 +193,120 @@ impl<T: Renderer + ?Sized> Renderer for Box<T> {
     fn set_rom_title(&mut self, title: &str) {
         (**self).set_rom_title(title)
     }
+
+    fn set_filter(&mut self, filter: Option<Box<Filter>>) -> BackendResult<()> {
+        (**self).set_filter(filter)
+    }
+
+    fn set_shader(&mut self, shader: &str) -> BackendResult<()> {
+        (**self).set_shader(shader)
+    }
+
+    fn set_display_options(&mut self, opts: DisplayOptions) -> BackendResult<()> {
+        (**self).set_display_options(opts)
+    }
+
+    fn set_window_scale(&mut self, scale: u32) -> BackendResult<()> {
+        (**self).set_window_scale(scale)
+    }
+
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        (**self).set_frame_size(width, height)
+    }
+
+    fn set_hotkeys(&mut self, hotkeys: HotkeyMap) -> BackendResult<()> {
+        (**self).set_hotkeys(hotkeys)
+    }
+
+    fn frame_timing(&self) -> FrameTiming {
+        (**self).frame_timing()
+    }
+
+    fn open_debug_surface(&mut self, title: &str, width: u32, height: u32)
+    -> BackendResult<DebugSurfaceId> {
+        (**self).open_debug_surface(title, width, height)
+    }
+
+    fn update_debug_surface(&mut self,
+                            id: DebugSurfaceId,
+                            data: &[u8],
+                            width: u32,
+                            height: u32) -> BackendResult<()> {
+        (**self).update_debug_surface(id, data, width, height)
+    }
+
+    fn close_debug_surface(&mut self, id: DebugSurfaceId) {
+        (**self).close_debug_surface(id)
+    }
+}
+
+/// How much audio an `AudioSink` keeps buffered. Bigger buffers survive longer hiccups of the
+/// emulator without underruns, smaller ones reduce the delay until a sound is heard.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AudioBufferConfig {
+    /// Number of sample frames the device requests at a time
+    pub period_size: u32,
+    /// Number of periods kept buffered ahead of the device
+    pub periods: u32,
+}
+
+impl AudioBufferConfig {
+    /// Returns the amount of audio this config keeps buffered at a sample rate of `rate` Hz.
+    pub fn target_latency(&self, rate: u32) -> Duration {
+        let frames = self.period_size as u64 * self.periods as u64;
+        let nanos = frames * 1_000_000_000 / rate as u64;
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+impl Default for AudioBufferConfig {
+    /// 2 periods of 1024 frames, or 64 ms at the APU's sample rate.
+    fn default() -> Self {
+        AudioBufferConfig {
+            period_size: 1024,
+            periods: 2,
+        }
+    }
 }
 
 /// Trait for audio backends. Provides methods for writing to a stereo audio channel.
+///
+/// Audio sinks use a push model: The emulator calls `write` whenever the APU has produced new
+/// samples, and the sink has to buffer them until the device wants them.
 pub trait AudioSink {
     /// Creates a new audio sink.
     fn create() -> BackendResult<Self> where Self: Sized;
 
     /// Write 32 kHz 16-bit data to the device.
     ///
-    /// The data contains 16-bit samples for the left and right channel.
+    /// The data contains 16-bit samples for the left and right channel. If the device doesn't
+    /// support 32 kHz output, the sink must resample the data.
     fn write(&mut self, data: &[(i16, i16)]);
+
+    /// Returns the sample rate the device is running at (in Hz).
+    ///
+    /// This is informational only: `write` always takes `APU_SAMPLE_RATE` data.
+    fn output_rate(&self) -> u32 { APU_SAMPLE_RATE }
+
+    /// Returns the time it takes until a sample passed to `write` is audible, if known.
+    fn latency(&self) -> Option<Duration> { None }
+
+    /// Makes the sink play `adjust` times as many samples as it was given (by resampling), to keep
+    /// its buffer level stable. `adjust` stays close to 1.0 (see `rate_control`).
+    ///
+    /// The default implementation ignores the adjustment.
+    fn set_rate_adjust(&mut self, _adjust: f64) {}
+
+    /// Changes how much audio the sink buffers. The sink may round the values to something the
+    /// device supports, `latency` reports what is actually buffered.
+    ///
+    /// Sinks whose buffer size can't be changed return an error.
+    fn set_buffer_config(&mut self, _config: AudioBufferConfig) -> BackendResult<()> {
+        Err("this audio sink doesn't support configuring its buffer".into())
+    }
+
+    /// Returns how often the device ran out of samples so far, if the sink can tell.
+    fn underruns(&self) -> Option<u64> { None }
 }
 
 impl<T: AudioSink + ?Sized> AudioSink for Box<T> {
@@ -80,4 +317,24 @@ impl<T: AudioSink + ?Sized> AudioSink for Box<T> {
     fn write(&mut self, data: &[(i16, i16)]) {
         (**self).write(data);
     }
+
+    fn output_rate(&self) -> u32 {
+        (**self).output_rate()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        (**self).latency()
+    }
+
+    fn set_rate_adjust(&mut self, adjust: f64) {
+        (**self).set_rate_adjust(adjust)
+    }
+
+    fn set_buffer_config(&mut self, config: AudioBufferConfig) -> BackendResult<()> {
+        (**self).set_buffer_config(config)
+    }
+
+    fn underruns(&self) -> Option<u64> {
+        (**self).underruns()
+    }
 }
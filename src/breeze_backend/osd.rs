@@ -0,0 +1,288 @@
+//! On-screen display
+//!
+//! The `Osd` keeps short text messages ("State saved"), indicators that stay visible while some
+//! mode is active ("PAUSED") and an optional FPS counter, and draws them over a frame using a
+//! built-in bitmap font. Since it works on the `RGB24` frame data, every renderer can display it
+//! without any special support.
+
+use std::time::Instant;
+
+/// Number of frames a message stays visible by default (about 2 seconds).
+pub const DEFAULT_MESSAGE_FRAMES: u32 = 120;
+
+/// Maximum number of messages displayed at once. When more are shown, the oldest ones are removed.
+const MAX_MESSAGES: usize = 4;
+
+/// Width of a glyph in pixels
+pub const GLYPH_WIDTH: u32 = 5;
+/// Height of a glyph in pixels
+pub const GLYPH_HEIGHT: u32 = 7;
+/// Horizontal distance between the start of two characters
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+/// Vertical distance between the start of two lines of text
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 3;
+/// Distance of the text from the edges of the frame
+const MARGIN: u32 = 4;
+
+/// A 5x7 font covering ASCII `0x20` to `0x5F`. Each glyph is stored as 7 rows, bit 4 is the
+/// leftmost pixel. Lowercase letters are displayed as uppercase.
+const FONT: [[u8; 7]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // '!'
+    [0x0A, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A], // '#'
+    [0x04, 0x0F, 0x14, 0x0E, 0x05, 0x1E, 0x04], // '$'
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // '%'
+    [0x0C, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0D], // '&'
+    [0x04, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // '('
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // ')'
+    [0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00], // '*'
+    [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08], // ','
+    [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C], // '.'
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // '/'
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E], // '0'
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E], // '1'
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F], // '2'
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E], // '3'
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02], // '4'
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E], // '5'
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E], // '6'
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // '7'
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E], // '8'
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x04, 0x08], // ';'
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // '<'
+    [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00], // '='
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // '>'
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // '?'
+    [0x0E, 0x11, 0x01, 0x0D, 0x15, 0x15, 0x0E], // '@'
+    [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11], // 'A'
+    [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E], // 'B'
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E], // 'C'
+    [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C], // 'D'
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F], // 'E'
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10], // 'F'
+    [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F], // 'G'
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // 'H'
+    [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E], // 'I'
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C], // 'J'
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // 'K'
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F], // 'L'
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11], // 'M'
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // 'N'
+    [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // 'O'
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10], // 'P'
+    [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D], // 'Q'
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11], // 'R'
+    [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E], // 'S'
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // 'T'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // 'U'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04], // 'V'
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A], // 'W'
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11], // 'X'
+    [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04], // 'Y'
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F], // 'Z'
+    [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E], // '['
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // '\'
+    [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E], // ']'
+    [0x04, 0x0A, 0x11, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F], // '_'
+];
+
+/// Returns the glyph to display for `c`. Characters not in the font are displayed as `?`.
+fn glyph(c: char) -> &'static [u8; 7] {
+    let c = c.to_ascii_uppercase();
+    match c {
+        ' ' ... '_' => &FONT[c as usize - 0x20],
+        _ => &FONT['?' as usize - 0x20],
+    }
+}
+
+/// Returns the width of `text` in pixels when drawn with `draw_text`.
+pub fn text_width(text: &str) -> u32 {
+    match text.chars().count() as u32 {
+        0 => 0,
+        n => n * ADVANCE - 1,
+    }
+}
+
+/// Draws `text` onto an `RGB24` frame of size `width * height`, with its top left corner at
+/// `(x, y)`. The text is drawn in `color` with a black outline, so it's readable on any
+/// background. Pixels outside of the frame are skipped.
+pub fn draw_text(frame: &mut [u8], width: u32, height: u32, x: u32, y: u32, text: &str,
+                 color: (u8, u8, u8)) {
+    // Draw the outline first, then the text on top of it
+    for &(outline, color) in &[(true, (0, 0, 0)), (false, color)] {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = glyph(c);
+            let x0 = x + i as u32 * ADVANCE;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> col) == 0 { continue }
+
+                    let (px, py) = ((x0 + col) as i32, (y + row as u32) as i32);
+                    if outline {
+                        for &(dx, dy) in &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0),
+                                           (-1, 1), (0, 1), (1, 1)] {
+                            set_pixel(frame, width, height, px + dx, py + dy, color);
+                        }
+                    } else {
+                        set_pixel(frame, width, height, px, py, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn set_pixel(frame: &mut [u8], width: u32, height: u32, x: i32, y: i32, color: (u8, u8, u8)) {
+    if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+        let start = (y as usize * width as usize + x as usize) * 3;
+        frame[start] = color.0;
+        frame[start + 1] = color.1;
+        frame[start + 2] = color.2;
+    }
+}
+
+/// A message that disappears after some time
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+/// Measures the frame rate for the FPS counter
+struct FpsCounter {
+    /// Start of the current measurement
+    start: Instant,
+    frames: u32,
+    /// Text displayed for the last measurement
+    text: String,
+}
+
+/// On-screen display state. Call `tick` once per frame and `draw` to composite it onto the frame.
+pub struct Osd {
+    enabled: bool,
+    /// Transient messages, oldest first. Displayed in the bottom left corner.
+    messages: Vec<Message>,
+    /// Indicators of active modes. Displayed in the top left corner.
+    indicators: Vec<String>,
+    /// FPS counter (`None` if disabled). Displayed in the top right corner.
+    fps: Option<FpsCounter>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            enabled: true,
+            messages: Vec::new(),
+            indicators: Vec::new(),
+            fps: None,
+        }
+    }
+
+    /// Enables or disables the OSD. While disabled, `draw` doesn't draw anything.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Displays a message for `DEFAULT_MESSAGE_FRAMES` frames.
+    pub fn show<S: Into<String>>(&mut self, text: S) {
+        self.show_for(text, DEFAULT_MESSAGE_FRAMES);
+    }
+
+    /// Displays a message for the given number of frames.
+    pub fn show_for<S: Into<String>>(&mut self, text: S, frames: u32) {
+        let text = text.into();
+        // Don't stack identical messages, just display the existing one longer
+        self.messages.retain(|msg| msg.text != text);
+        self.messages.push(Message {
+            text: text,
+            frames_left: frames,
+        });
+        if self.messages.len() > MAX_MESSAGES {
+            let excess = self.messages.len() - MAX_MESSAGES;
+            self.messages.drain(..excess);
+        }
+    }
+
+    /// Shows or hides an indicator. Indicators stay visible until they are hidden.
+    pub fn set_indicator(&mut self, text: &str, shown: bool) {
+        self.indicators.retain(|ind| ind != text);
+        if shown {
+            self.indicators.push(text.to_owned());
+        }
+    }
+
+    /// Shows or hides the FPS counter.
+    pub fn set_show_fps(&mut self, show: bool) {
+        self.fps = if show {
+            Some(FpsCounter {
+                start: Instant::now(),
+                frames: 0,
+                text: String::new(),
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Advances the OSD by a frame: Removes messages that have expired and updates the FPS
+    /// counter.
+    pub fn tick(&mut self) {
+        for msg in &mut self.messages {
+            msg.frames_left = msg.frames_left.saturating_sub(1);
+        }
+        self.messages.retain(|msg| msg.frames_left > 0);
+
+        if let Some(ref mut fps) = self.fps {
+            fps.frames += 1;
+            let elapsed = fps.start.elapsed();
+            if elapsed.as_secs() >= 1 {
+                let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                fps.text = format!("{:.1} FPS", fps.frames as f64 / secs);
+                fps.start = Instant::now();
+                fps.frames = 0;
+            }
+        }
+    }
+
+    /// Returns whether `draw` would draw anything.
+    pub fn is_visible(&self) -> bool {
+        self.enabled && (!self.messages.is_empty() || !self.indicators.is_empty() ||
+                         self.fps.as_ref().map_or(false, |fps| !fps.text.is_empty()))
+    }
+
+    /// Draws the OSD onto an `RGB24` frame of size `width * height`.
+    pub fn draw(&self, frame: &mut [u8], width: u32, height: u32) {
+        const WHITE: (u8, u8, u8) = (0xff, 0xff, 0xff);
+        const YELLOW: (u8, u8, u8) = (0xff, 0xe0, 0x40);
+
+        if !self.enabled { return }
+
+        for (i, ind) in self.indicators.iter().enumerate() {
+            draw_text(frame, width, height, MARGIN, MARGIN + i as u32 * LINE_HEIGHT, ind, YELLOW);
+        }
+
+        if let Some(ref fps) = self.fps {
+            let x = width.saturating_sub(MARGIN + text_width(&fps.text));
+            draw_text(frame, width, height, x, MARGIN, &fps.text, WHITE);
+        }
+
+        // Newest message at the bottom
+        let mut y = height.saturating_sub(MARGIN + GLYPH_HEIGHT);
+        for msg in self.messages.iter().rev() {
+            draw_text(frame, width, height, MARGIN, y, &msg.text, WHITE);
+            y = y.saturating_sub(LINE_HEIGHT);
+        }
+    }
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Osd::new()
+    }
+}
@@ -0,0 +1,136 @@
+//! Frame pacing
+//!
+//! The emulator has to produce frames at the rate of the emulated console (about 60 Hz for NTSC,
+//! 50 Hz for PAL). If the renderer waits for vsync and the display refreshes at (almost) that rate,
+//! the display is the best clock we can get, since every frame is shown for exactly one refresh.
+//! Otherwise, a `FramePacer` sleeps between frames.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frame rate of the NTSC SNES (in Hz): The master clock runs at 21.477272 MHz and a frame takes
+/// 357366 master cycles.
+pub const NTSC_FRAME_RATE: f64 = 21_477_272.0 / 357_366.0;
+
+/// Frame rate of the PAL SNES (in Hz): The master clock runs at 21.28137 MHz and a frame takes
+/// 425568 master cycles.
+pub const PAL_FRAME_RATE: f64 = 21_281_370.0 / 425_568.0;
+
+/// If the display's refresh rate is within this fraction of the emulated frame rate, we sync to
+/// the display.
+const VSYNC_TOLERANCE: f64 = 0.01;
+
+/// Describes how a renderer limits the frame rate.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameTiming {
+    /// `Renderer::render` blocks until the next refresh of a display running at the given rate (in
+    /// Hz)
+    Vsync(f64),
+    /// The renderer doesn't wait, frames have to be timed by the emulator
+    Timer,
+    /// Frames should be emulated as fast as possible (for example, because nothing is displayed)
+    Unlimited,
+}
+
+/// Schedules frames to match the emulated console's frame rate.
+pub struct FramePacer {
+    frame_rate: f64,
+    /// When the next frame should be finished (`None` if we aren't pacing with a timer)
+    deadline: Option<Instant>,
+    /// Don't wait at all
+    fast_forward: bool,
+    /// Emulation speed relative to the console (`0.5` runs at half speed)
+    speed: f64,
+}
+
+impl FramePacer {
+    /// Creates a pacer for a console running at `frame_rate` Hz.
+    pub fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            frame_rate: frame_rate,
+            deadline: None,
+            fast_forward: false,
+            speed: 1.0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 { self.frame_rate }
+
+    /// Changes the frame rate (eg. when switching between NTSC and PAL).
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+        self.deadline = None;
+    }
+
+    /// Enables or disables fast-forwarding. While fast-forwarding, `wait` returns immediately, so
+    /// the emulator runs as fast as it can (unless the renderer waits for vsync).
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+        self.deadline = None;
+    }
+
+    pub fn is_fast_forwarding(&self) -> bool { self.fast_forward }
+
+    /// Sets the emulation speed relative to the console. Factors below 1.0 slow emulation down
+    /// (slow motion), factors above speed it up. Fast-forwarding takes precedence.
+    pub fn set_speed(&mut self, speed: f64) {
+        assert!(speed > 0.0, "invalid emulation speed: {}", speed);
+        self.speed = speed;
+        self.deadline = None;
+    }
+
+    pub fn speed(&self) -> f64 { self.speed }
+
+    /// Returns the rate at which frames are produced (the console's frame rate scaled by the
+    /// speed factor).
+    fn effective_rate(&self) -> f64 { self.frame_rate * self.speed }
+
+    /// Returns whether frames with the given timing need to be paced by a timer.
+    pub fn needs_timer(&self, timing: FrameTiming) -> bool {
+        match timing {
+            FrameTiming::Vsync(hz) => {
+                (hz - self.effective_rate()).abs() / self.effective_rate() > VSYNC_TOLERANCE
+            }
+            FrameTiming::Timer => true,
+            FrameTiming::Unlimited => false,
+        }
+    }
+
+    /// Called after a frame was rendered. Sleeps until the next frame should be emulated, unless
+    /// the renderer already takes care of that.
+    pub fn wait(&mut self, timing: FrameTiming) {
+        if self.fast_forward || !self.needs_timer(timing) {
+            self.deadline = None;
+            return;
+        }
+
+        let nanos = (1_000_000_000.0 / self.effective_rate()) as u64;
+        let period = Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32);
+        let now = Instant::now();
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => {
+                // First paced frame, just start the clock
+                self.deadline = Some(now + period);
+                return;
+            }
+        };
+
+        if now < deadline {
+            thread::sleep(deadline - now);
+            self.deadline = Some(deadline + period);
+        } else if now - deadline > period {
+            // We're more than a frame behind (the emulator is too slow, or was paused). Don't try
+            // to catch up, that would just run the following frames too fast.
+            self.deadline = Some(now + period);
+        } else {
+            self.deadline = Some(deadline + period);
+        }
+    }
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        FramePacer::new(NTSC_FRAME_RATE)
+    }
+}
@@ -0,0 +1,148 @@
+//! Minimal PNG encoder for screenshots and frame dumps
+//!
+//! Writes 8-bit RGB images. The image data isn't compressed (it's stored in uncompressed deflate
+//! blocks), which keeps this free of dependencies. The files are larger than necessary, but every
+//! PNG decoder can read them.
+
+use std::io::{self, Write};
+
+const SIGNATURE: &'static [u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Maximum length of an uncompressed deflate block
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+/// Writes an `RGB24` image of the given size (see `Renderer::render`) as a PNG file.
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let row_len = width as usize * 3;
+    assert_eq!(rgb.len(), row_len * height as usize, "image data doesn't match the image size");
+
+    try!(out.write_all(SIGNATURE));
+
+    let mut ihdr = Vec::with_capacity(13);
+    push_u32(&mut ihdr, width);
+    push_u32(&mut ihdr, height);
+    ihdr.extend_from_slice(&[
+        8,  // Bit depth
+        2,  // Color type: RGB
+        0,  // Compression method: deflate
+        0,  // Filter method
+        0,  // No interlacing
+    ]);
+    try!(write_chunk(out, b"IHDR", &ihdr));
+
+    // Every row starts with its filter type (0 = none)
+    let mut raw = Vec::with_capacity((row_len + 1) * height as usize);
+    for row in rgb.chunks(row_len) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    try!(write_chunk(out, b"IDAT", &zlib_stored(&raw)));
+    write_chunk(out, b"IEND", &[])
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                            value as u8]);
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(8);
+    push_u32(&mut header, data.len() as u32);
+    header.extend_from_slice(kind);
+    try!(out.write_all(&header));
+    try!(out.write_all(data));
+
+    // The CRC covers the chunk type and data
+    let crc = crc32(crc32(!0, kind), data);
+    let mut trailer = Vec::with_capacity(4);
+    push_u32(&mut trailer, !crc);
+    out.write_all(&trailer)
+}
+
+/// Wraps `data` in a zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 16);
+    out.extend_from_slice(&[0x78, 0x01]);   // Deflate, 32K window, no preset dictionary
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = if blocks.peek().is_none() { 1 } else { 0 };
+        let len = block.len() as u16;
+        out.extend_from_slice(&[last, len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32, write_png, zlib_stored, MAX_STORED_BLOCK};
+
+    #[test]
+    fn checksums() {
+        // Check values of the CRC-32 used by PNG and of Adler-32
+        assert_eq!(!crc32(!0, b""), 0);
+        assert_eq!(!crc32(!0, b"123456789"), 0xcbf43926);
+        assert_eq!(!crc32(!0, b"IEND"), 0xae426082);
+        // Computing the CRC in parts gives the same result
+        assert_eq!(!crc32(crc32(!0, b"1234"), b"56789"), 0xcbf43926);
+
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // Long enough to need the modulo reduction
+        assert_eq!(adler32(&[0xff; 10000]), 0xb623eb2b);
+    }
+
+    #[test]
+    fn stored_blocks() {
+        assert_eq!(zlib_stored(b""), [0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+        assert_eq!(zlib_stored(b"ab"), [0x78, 0x01, 1, 2, 0, 0xfd, 0xff, b'a', b'b',
+                                        0x01, 0x26, 0x00, 0xc4]);
+
+        let data = vec![7; MAX_STORED_BLOCK + 1];
+        let zlib = zlib_stored(&data);
+        assert_eq!(zlib.len(), 2 + 5 + MAX_STORED_BLOCK + 5 + 1 + 4);
+        assert_eq!(&zlib[2..7], &[0, 0xff, 0xff, 0, 0]);
+        assert_eq!(&zlib[7 + MAX_STORED_BLOCK..12 + MAX_STORED_BLOCK], &[1, 1, 0, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn image() {
+        let mut out = Vec::new();
+        write_png(&mut out, 1, 1, &[255, 0, 0]).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR: 13 bytes of data, 1x1 pixels, 8-bit RGB
+        assert_eq!(&out[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&out[16..29], &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(&out[29..33], &[0x90, 0x77, 0x53, 0xde]);
+        assert_eq!(&out[out.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
@@ -6,3 +6,5 @@ pub const SCREEN_WIDTH: u32 = 256;
 /// Physical screen height
 /// (this is the height of a field, or a half-frame)
 pub const SCREEN_HEIGHT: u32 = 224;     // 224px for 60 Hz NTSC, 264 for 50 Hz PAL
+/// Number of scanlines displayed in overscan mode
+pub const OVERSCAN_HEIGHT: u32 = 239;
@@ -0,0 +1,98 @@
+//! Dynamic audio rate control
+//!
+//! The emulator is paced by the video output (either by waiting for vsync or by a `FramePacer`),
+//! while the audio device consumes samples according to its own clock. Since the two never run at
+//! exactly the same speed (and vsync may even run at a slightly different rate than the emulated
+//! console), the audio buffer slowly fills up or runs dry, resulting in dropped samples or gaps.
+//!
+//! To prevent that, the `RateController` looks at how much audio is buffered after every frame and
+//! tells the audio sink to play slightly faster or slower (`AudioSink::set_rate_adjust`). The
+//! adjustment is limited to a fraction of a percent, so the change in pitch isn't audible.
+
+use std::time::Duration;
+
+/// Default maximum deviation of the playback rate (0.5%).
+pub const DEFAULT_MAX_ADJUST: f64 = 0.005;
+
+/// Amount of audio we try to keep buffered by default.
+pub const DEFAULT_TARGET_LATENCY_MS: u64 = 64;
+
+/// Weight of a new measurement in the smoothed buffer level. Audio devices consume samples in
+/// blocks, so the measured latency jumps around a lot between frames.
+const SMOOTHING: f64 = 0.05;
+
+/// Computes the rate adjustment to apply to the audio output.
+#[derive(Clone, Debug)]
+pub struct RateController {
+    /// Target buffer level in seconds
+    target: f64,
+    max_adjust: f64,
+    /// Smoothed buffer level in seconds (`None` until the first measurement)
+    level: Option<f64>,
+    adjust: f64,
+}
+
+impl RateController {
+    /// Creates a controller that tries to keep `target_latency` worth of audio buffered, and
+    /// changes the playback rate by at most `max_adjust` (eg. `0.005` for 0.5%) to do so.
+    pub fn new(target_latency: Duration, max_adjust: f64) -> Self {
+        RateController {
+            target: duration_secs(target_latency),
+            max_adjust: max_adjust,
+            level: None,
+            adjust: 1.0,
+        }
+    }
+
+    /// Returns the current rate adjustment (1.0 means no adjustment).
+    pub fn adjust(&self) -> f64 { self.adjust }
+
+    /// Changes the buffer level the controller tries to keep (eg. after the audio sink's buffer
+    /// was resized with `AudioSink::set_buffer_config`).
+    pub fn set_target_latency(&mut self, target_latency: Duration) {
+        self.target = duration_secs(target_latency);
+    }
+
+    /// Returns the smoothed buffer level, which is the latency actually achieved (`None` until
+    /// the first measurement).
+    pub fn level(&self) -> Option<Duration> {
+        self.level.map(|level| {
+            let nanos = (level.max(0.0) * 1_000_000_000.0) as u64;
+            Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+        })
+    }
+
+    /// Updates the controller with the current latency of the audio sink (see
+    /// `AudioSink::latency`). Returns the new rate adjustment to pass to
+    /// `AudioSink::set_rate_adjust`.
+    pub fn update(&mut self, latency: Duration) -> f64 {
+        let measured = duration_secs(latency);
+        let level = match self.level {
+            Some(level) => level + (measured - level) * SMOOTHING,
+            None => measured,
+        };
+        self.level = Some(level);
+
+        // 0.0 = empty, 0.5 = at the target, 1.0 = twice the target. An emptier buffer means we
+        // should produce more samples, so the adjustment goes above 1.0.
+        let fill = (level / (self.target * 2.0)).min(1.0);
+        self.adjust = 1.0 + self.max_adjust * (1.0 - 2.0 * fill);
+        self.adjust
+    }
+
+    /// Forgets all measurements (eg. after emulation was paused and the buffer ran dry).
+    pub fn reset(&mut self) {
+        self.level = None;
+        self.adjust = 1.0;
+    }
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        RateController::new(Duration::from_millis(DEFAULT_TARGET_LATENCY_MS), DEFAULT_MAX_ADJUST)
+    }
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
@@ -0,0 +1,53 @@
+//! Sample rate conversion for audio sinks.
+
+/// Converts a stream of stereo samples from one sample rate to another using linear
+/// interpolation.
+///
+/// The conversion ratio can be nudged while running (see `set_rate_adjust`), which allows
+/// compensating for the audio device's clock drifting against the emulator's.
+pub struct Resampler {
+    /// Input samples per output sample, without adjustment
+    base_step: f64,
+    /// Input samples per output sample, including the rate adjustment
+    step: f64,
+    /// Position between the last sample processed and the next one, in units of input samples
+    pos: f64,
+    /// Last sample processed. Used to interpolate across `process` calls.
+    last: (i16, i16),
+}
+
+impl Resampler {
+    /// Creates a resampler converting from `in_rate` to `out_rate` (both in Hz).
+    pub fn new(in_rate: u32, out_rate: u32) -> Self {
+        let step = in_rate as f64 / out_rate as f64;
+        Resampler {
+            base_step: step,
+            step: step,
+            pos: 0.0,
+            last: (0, 0),
+        }
+    }
+
+    /// Makes the resampler produce `adjust` times as many output samples as the plain conversion
+    /// would. Values above 1.0 make the output play slower (and fill the device's buffer faster).
+    pub fn set_rate_adjust(&mut self, adjust: f64) {
+        self.step = self.base_step / adjust;
+    }
+
+    /// Converts `data` and appends the result to `out`.
+    pub fn process(&mut self, data: &[(i16, i16)], out: &mut Vec<(i16, i16)>) {
+        let lerp = |a: i16, b: i16, t: f64| (a as f64 + (b as f64 - a as f64) * t) as i16;
+        while (self.pos as usize) < data.len() {
+            let i = self.pos as usize;
+            let t = self.pos.fract();
+            let prev = if i == 0 { self.last } else { data[i - 1] };
+            let next = data[i];
+            out.push((lerp(prev.0, next.0, t), lerp(prev.1, next.1, t)));
+            self.pos += self.step;
+        }
+        self.pos -= data.len() as f64;
+        if let Some(&last) = data.last() {
+            self.last = last;
+        }
+    }
+}
@@ -2,6 +2,8 @@
 
 use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
 
+use std::str::FromStr;
+
 /// A simple rectangle
 pub struct Viewport {
     pub x: u32,
@@ -10,6 +12,49 @@ pub struct Viewport {
     pub h: u32,
 }
 
+/// Controls the shape of the displayed pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AspectRatio {
+    /// Square pixels, as in the frame buffer (the default)
+    Square,
+    /// Pixels are 8:7 (wider than high), like on an NTSC TV
+    Ntsc,
+    /// Fill the whole window, no matter how distorted the image gets
+    Stretch,
+}
+
+impl FromStr for AspectRatio {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "square" => Ok(AspectRatio::Square),
+            "ntsc" | "8:7" => Ok(AspectRatio::Ntsc),
+            "stretch" => Ok(AspectRatio::Stretch),
+            _ => Err(format!("unknown aspect ratio: {} (expected `square`, `ntsc` or `stretch`)",
+                             s)),
+        }
+    }
+}
+
+/// User preferences for mapping the emulator's output to the window.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DisplayOptions {
+    pub aspect: AspectRatio,
+    /// Only scale the output by whole multiples of its native size. The remaining space is filled
+    /// with black bars. With `AspectRatio::Ntsc`, this only applies to the vertical axis.
+    pub integer_scaling: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        DisplayOptions {
+            aspect: AspectRatio::Square,
+            integer_scaling: false,
+        }
+    }
+}
+
 impl Viewport {
     /// Calculates a viewport to use for a window of the given size.
     ///
@@ -17,28 +62,48 @@ impl Viewport {
     /// least one axis. Basically, this calculates the black bars to apply to the window to make the
     /// center have the native SNES ratio.
     pub fn for_window_size(w: u32, h: u32) -> Self {
+        Self::for_frame(w, h, SCREEN_WIDTH, SCREEN_HEIGHT, &DisplayOptions::default())
+    }
+
+    /// Calculates a viewport displaying a frame of size `frame_w * frame_h` in a window of size
+    /// `win_w * win_h`, according to the given options.
+    ///
+    /// Frames larger than the native resolution (hi-res and interlaced modes, or upscaled by a
+    /// filter) are assumed to cover the same area as a native frame, just with smaller pixels.
+    pub fn for_frame(win_w: u32, win_h: u32, frame_w: u32, frame_h: u32, opts: &DisplayOptions)
+    -> Self {
         // FIXME Not sure if floats are a good idea here
-        let w = w as f32;
-        let h = h as f32;
-
-        const NATIVE_RATIO: f32 = SCREEN_WIDTH as f32 / SCREEN_HEIGHT as f32;
-        let ratio = w / h;
-
-        let view_w;
-        let view_h;
-
-        if ratio > NATIVE_RATIO {
-            // Too wide
-            view_h = h;
-            view_w = h * NATIVE_RATIO;
-        } else {
-            // Too high
-            view_w = w;
-            view_h = w / NATIVE_RATIO;
-        }
+        let (win_w, win_h) = (win_w as f32, win_h as f32);
+
+        // Size of the frame in native pixels
+        let native_w = (frame_w / (frame_w / SCREEN_WIDTH).max(1)) as f32;
+        let native_h = (frame_h / (frame_h / SCREEN_HEIGHT).max(1)) as f32;
+
+        let (view_w, view_h) = match opts.aspect {
+            AspectRatio::Stretch => {
+                if opts.integer_scaling {
+                    (integer_scale(win_w / native_w) * native_w,
+                     integer_scale(win_h / native_h) * native_h)
+                } else {
+                    (win_w, win_h)
+                }
+            }
+            AspectRatio::Square | AspectRatio::Ntsc => {
+                let pixel_aspect = if opts.aspect == AspectRatio::Ntsc { 8.0 / 7.0 } else { 1.0 };
+                let display_w = native_w * pixel_aspect;
 
-        let border_x = (w - view_w).round() as u32 / 2;
-        let border_y = (h - view_h).round() as u32 / 2;
+                // Scale factor that makes the frame fill the window on one axis
+                let mut scale = (win_w / display_w).min(win_h / native_h);
+                if opts.integer_scaling {
+                    scale = integer_scale(scale);
+                }
+
+                (display_w * scale, native_h * scale)
+            }
+        };
+
+        let border_x = (win_w - view_w).round() as u32 / 2;
+        let border_y = (win_h - view_h).round() as u32 / 2;
         let view_w = view_w.round() as u32;
         let view_h = view_h.round() as u32;
 
@@ -50,3 +115,66 @@ impl Viewport {
         }
     }
 }
+
+/// Rounds a scale factor down to a whole number. If the window is too small to fit the frame at
+/// all, the (fractional) factor is kept.
+fn integer_scale(scale: f32) -> f32 {
+    if scale < 1.0 { scale } else { scale.floor() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{integer_scale, AspectRatio, DisplayOptions, Viewport};
+
+    fn view(win: (u32, u32), frame: (u32, u32), aspect: AspectRatio, integer_scaling: bool)
+    -> (u32, u32, u32, u32) {
+        let opts = DisplayOptions { aspect: aspect, integer_scaling: integer_scaling };
+        let v = Viewport::for_frame(win.0, win.1, frame.0, frame.1, &opts);
+        (v.x, v.y, v.w, v.h)
+    }
+
+    #[test]
+    fn square() {
+        assert_eq!(view((700, 500), (256, 224), AspectRatio::Square, false), (64, 0, 571, 500));
+        assert_eq!(view((700, 500), (256, 224), AspectRatio::Square, true), (94, 26, 512, 448));
+        // Overscan frames are taller
+        assert_eq!(view((700, 500), (256, 239), AspectRatio::Square, true), (94, 11, 512, 478));
+        assert_eq!(view((333, 777), (256, 239), AspectRatio::Square, false), (0, 233, 333, 311));
+        assert_eq!(view((333, 777), (256, 239), AspectRatio::Square, true), (38, 269, 256, 239));
+
+        let v = Viewport::for_window_size(700, 500);
+        assert_eq!((v.x, v.y, v.w, v.h), (64, 0, 571, 500));
+    }
+
+    #[test]
+    fn hires_and_interlace() {
+        // 512x448 frames cover the same area as native ones
+        for &(win, integer) in &[((700, 500), false), ((700, 500), true), ((1001, 999), true)] {
+            let native = view(win, (256, 224), AspectRatio::Square, integer);
+            assert_eq!(view(win, (512, 448), AspectRatio::Square, integer), native);
+            assert_eq!(view(win, (512, 224), AspectRatio::Square, integer), native);
+            assert_eq!(view(win, (256, 448), AspectRatio::Ntsc, integer),
+                       view(win, (256, 224), AspectRatio::Ntsc, integer));
+        }
+    }
+
+    #[test]
+    fn ntsc_and_stretch() {
+        assert_eq!(view((700, 500), (256, 224), AspectRatio::Ntsc, false), (23, 0, 653, 500));
+        assert_eq!(view((700, 500), (256, 224), AspectRatio::Ntsc, true), (57, 26, 585, 448));
+        assert_eq!(view((701, 499), (256, 239), AspectRatio::Stretch, false), (0, 0, 701, 499));
+        assert_eq!(view((701, 499), (512, 478), AspectRatio::Stretch, true), (94, 10, 512, 478));
+    }
+
+    #[test]
+    fn small_window() {
+        // The frame is scaled down even with integer scaling
+        let expected = (14, 0, 171, 150);
+        assert_eq!(view((200, 150), (256, 224), AspectRatio::Square, false), expected);
+        assert_eq!(view((200, 150), (256, 224), AspectRatio::Square, true), expected);
+
+        assert_eq!(integer_scale(0.5), 0.5);
+        assert_eq!(integer_scale(1.0), 1.0);
+        assert_eq!(integer_scale(2.99), 2.0);
+    }
+}
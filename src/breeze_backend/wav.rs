@@ -0,0 +1,256 @@
+//! WAV file output for audio dumps
+//!
+//! Writes uncompressed 16-bit PCM. The size fields in the header are patched when the writer is
+//! finalized (or dropped), so a dump that's cut short by a crash can still be opened by most
+//! players.
+
+use {AudioBufferConfig, AudioSink, BackendResult, APU_SAMPLE_RATE};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Size of the RIFF/WAVE header written by `WavWriter`
+const HEADER_SIZE: u32 = 44;
+
+/// Writes 16-bit PCM samples to a WAV file.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    /// Number of bytes of sample data written so far
+    data_len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Creates a WAV file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(try!(File::create(path))), channels, rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes a WAV header to `out`. Samples are written with `write_mono` or `write_stereo`.
+    pub fn new(mut out: W, channels: u16, rate: u32) -> io::Result<Self> {
+        let block_align = channels * 2;
+        try!(out.write_all(b"RIFF"));
+        try!(write_u32(&mut out, HEADER_SIZE - 8));
+        try!(out.write_all(b"WAVEfmt "));
+        try!(write_u32(&mut out, 16));                          // fmt chunk size
+        try!(write_u16(&mut out, 1));                           // PCM
+        try!(write_u16(&mut out, channels));
+        try!(write_u32(&mut out, rate));
+        try!(write_u32(&mut out, rate * block_align as u32));   // bytes per second
+        try!(write_u16(&mut out, block_align));
+        try!(write_u16(&mut out, 16));                          // bits per sample
+        try!(out.write_all(b"data"));
+        try!(write_u32(&mut out, 0));
+
+        Ok(WavWriter {
+            out: out,
+            channels: channels,
+            data_len: 0,
+        })
+    }
+
+    /// Writes samples to a mono file.
+    pub fn write_mono(&mut self, samples: &[i16]) -> io::Result<()> {
+        debug_assert_eq!(self.channels, 1);
+        for &sample in samples {
+            try!(write_u16(&mut self.out, sample as u16));
+        }
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    /// Writes `(left, right)` samples to a stereo file.
+    pub fn write_stereo(&mut self, samples: &[(i16, i16)]) -> io::Result<()> {
+        debug_assert_eq!(self.channels, 2);
+        for &(l, r) in samples {
+            try!(write_u16(&mut self.out, l as u16));
+            try!(write_u16(&mut self.out, r as u16));
+        }
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 4);
+        Ok(())
+    }
+
+    /// Updates the size fields in the header and flushes the output. More samples can be written
+    /// afterwards (and the writer finalized again).
+    pub fn finalize(&mut self) -> io::Result<()> {
+        let end = try!(self.out.seek(SeekFrom::Current(0)));
+        try!(self.out.seek(SeekFrom::Start(4)));
+        try!(write_u32(&mut self.out, HEADER_SIZE - 8 + self.data_len));
+        try!(self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4)));
+        try!(write_u32(&mut self.out, self.data_len));
+        try!(self.out.seek(SeekFrom::Start(end)));
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            error!("couldn't finalize WAV file: {}", e);
+        }
+    }
+}
+
+/// Dumps the output of each of the 8 DSP voices (before mixing) to a separate mono WAV file.
+///
+/// The files are named `PREFIX-voiceN.wav`, where `N` is the voice number (0-7).
+pub struct VoiceWavDump {
+    writers: Vec<WavWriter<BufWriter<File>>>,
+}
+
+impl VoiceWavDump {
+    /// Creates the 8 WAV files.
+    pub fn create<P: AsRef<Path>>(prefix: P) -> io::Result<Self> {
+        let mut writers = Vec::with_capacity(8);
+        for voice in 0..8 {
+            writers.push(try!(WavWriter::create(Self::voice_path(prefix.as_ref(), voice), 1,
+                APU_SAMPLE_RATE)));
+        }
+        Ok(VoiceWavDump { writers: writers })
+    }
+
+    /// Returns the path of the file voice `voice` is written to.
+    pub fn voice_path(prefix: &Path, voice: usize) -> PathBuf {
+        let mut name = prefix.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(format!("-voice{}.wav", voice));
+        prefix.with_file_name(name)
+    }
+
+    /// Writes one output sample per voice.
+    pub fn write(&mut self, samples: &[i16; 8]) -> io::Result<()> {
+        for (writer, &sample) in self.writers.iter_mut().zip(samples) {
+            try!(writer.write_mono(&[sample]));
+        }
+        Ok(())
+    }
+
+    /// Updates the headers of all files (see `WavWriter::finalize`).
+    pub fn finalize(&mut self) -> io::Result<()> {
+        for writer in &mut self.writers {
+            try!(writer.finalize());
+        }
+        Ok(())
+    }
+}
+
+/// Shared handle controlling a dump of the full audio mix to a stereo WAV file. Cloning it yields
+/// another handle to the same dump.
+///
+/// The samples are written exactly as the emulator produces them (32 kHz, before any resampling
+/// done by the audio device), which makes dumps usable for soundtrack capture and for comparing
+/// the audio output of different emulator versions. Unlike `av::AvRecorder`, this doesn't involve
+/// the video output at all.
+#[derive(Clone)]
+pub struct AudioDump {
+    writer: Arc<Mutex<Option<WavWriter<BufWriter<File>>>>>,
+}
+
+impl AudioDump {
+    /// Creates a handle that isn't dumping anything.
+    pub fn new() -> Self {
+        AudioDump {
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts dumping to a WAV file at `path`. If a dump is already in progress, it is stopped
+    /// first.
+    pub fn start<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        try!(self.stop());
+        let writer = try!(WavWriter::create(path.as_ref(), 2, APU_SAMPLE_RATE));
+        *self.writer.lock().unwrap() = Some(writer);
+        info!("dumping audio to '{}'", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Stops the current dump (if any) and finalizes the file.
+    pub fn stop(&self) -> io::Result<()> {
+        let writer = self.writer.lock().unwrap().take();
+        match writer {
+            Some(mut writer) => {
+                info!("stopped audio dump");
+                writer.finalize()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_dumping(&self) -> bool {
+        self.writer.lock().unwrap().is_some()
+    }
+
+    /// Writes samples to the file. If that fails, the dump is stopped.
+    pub fn write(&self, samples: &[(i16, i16)]) {
+        let mut guard = self.writer.lock().unwrap();
+        let failed = match *guard {
+            Some(ref mut writer) => writer.write_stereo(samples).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            error!("audio dump failed, stopping: {}", e);
+            // Dropping the writer finalizes what was written so far
+            guard.take();
+        }
+    }
+}
+
+/// An audio sink that passes all samples to an `AudioDump` before playing them.
+pub struct DumpingSink<A: AudioSink> {
+    inner: A,
+    dump: AudioDump,
+}
+
+impl<A: AudioSink> DumpingSink<A> {
+    pub fn new(inner: A, dump: AudioDump) -> Self {
+        DumpingSink {
+            inner: inner,
+            dump: dump,
+        }
+    }
+
+    pub fn dump(&self) -> &AudioDump { &self.dump }
+}
+
+impl<A: AudioSink> AudioSink for DumpingSink<A> {
+    fn create() -> BackendResult<Self> where Self: Sized {
+        Ok(DumpingSink::new(try!(A::create()), AudioDump::new()))
+    }
+
+    fn write(&mut self, data: &[(i16, i16)]) {
+        self.dump.write(data);
+        self.inner.write(data);
+    }
+
+    fn output_rate(&self) -> u32 {
+        self.inner.output_rate()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.inner.latency()
+    }
+
+    fn set_rate_adjust(&mut self, adjust: f64) {
+        self.inner.set_rate_adjust(adjust)
+    }
+
+    fn set_buffer_config(&mut self, config: AudioBufferConfig) -> BackendResult<()> {
+        self.inner.set_buffer_config(config)
+    }
+
+    fn underruns(&self) -> Option<u64> {
+        self.inner.underruns()
+    }
+}
+
+fn write_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
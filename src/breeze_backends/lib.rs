@@ -21,6 +21,9 @@ pub extern crate breeze_sdl;    // FIXME pub because of the input hack
 #[cfg(feature = "cpal")]
 extern crate breeze_cpal;
 
+#[cfg(feature = "wgpu")]
+extern crate breeze_wgpu;
+
 use breeze_backend::{AudioSink, Renderer};
 use breeze_backend::dummy::{DummyRenderer, DummySink};
 pub use breeze_backend::viewport::{self, Viewport};
@@ -50,9 +53,15 @@ lazy_static! {
         #[cfg(not(feature = "sdl"))]
         const BUILD_SDL: MapEntry = None;
 
+        #[cfg(feature = "wgpu")]
+        const BUILD_WGPU: MapEntry = Some(make::<breeze_wgpu::WgpuRenderer>);
+        #[cfg(not(feature = "wgpu"))]
+        const BUILD_WGPU: MapEntry = None;
+
         let mut map = RendererMap::new();
         map.insert("glium", BUILD_GLIUM);
         map.insert("sdl", BUILD_SDL);
+        map.insert("wgpu", BUILD_WGPU);
         map.insert("dummy", Some(make::<DummyRenderer>));
         map
     };
@@ -60,8 +69,10 @@ lazy_static! {
     pub static ref DEFAULT_RENDERER: &'static str = {
         if cfg!(feature = "glium") {
             "glium"
-        } else if cfg!(feature = "sdl2") {
+        } else if cfg!(feature = "sdl") {
             "sdl"
+        } else if cfg!(feature = "wgpu") {
+            "wgpu"
         } else {
             "dummy" // let's hope nobody does this by accident
         }
@@ -79,8 +90,14 @@ lazy_static! {
         #[cfg(not(feature = "cpal"))]
         const BUILD_CPAL: MapEntry = None;
 
+        #[cfg(feature = "sdl")]
+        const BUILD_SDL: MapEntry = Some(make::<breeze_sdl::SdlAudio>);
+        #[cfg(not(feature = "sdl"))]
+        const BUILD_SDL: MapEntry = None;
+
         let mut map = AudioMap::new();
         map.insert("cpal", BUILD_CPAL);
+        map.insert("sdl", BUILD_SDL);
         map.insert("dummy", Some(make::<DummySink>));
         map
     };
@@ -88,6 +105,8 @@ lazy_static! {
     pub static ref DEFAULT_AUDIO: &'static str = {
         if cfg!(feature = "cpal") {
             "cpal"
+        } else if cfg!(feature = "sdl") {
+            "sdl"
         } else {
             "dummy"
         }
@@ -0,0 +1,166 @@
+//! Accuracy vs. speed settings
+//!
+//! Several parts of the emulator can trade accuracy for speed. Instead of making the user tune
+//! each of them, we provide a few named profiles, which can then be tweaked by overriding single
+//! options.
+//!
+//! Besides the individual toggles, 2 grouped options can be overridden:
+//!
+//! * `dot_ppu`: Dot-accurate PPU. Catches the PPU up after every CPU instruction (`ppu_sync = 0`)
+//!   and applies mid-scanline register writes (`bg_raster`).
+//! * `cycle_cpu`: Cycle-stepped CPU timing. Stalls the CPU for WRAM refresh (`dram_refresh`) and
+//!   models the latency of its multiplication and division unit (`alu_latency`).
+
+use std::str::FromStr;
+
+/// Named bundles of accuracy settings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccuracyProfile {
+    /// Skips timing details most games don't need and lets components drift further apart
+    Fast,
+    /// Models all timing details, but syncs the APU less often
+    Balanced,
+    /// Syncs all components as often as possible (the default)
+    Accurate,
+}
+
+impl Default for AccuracyProfile {
+    fn default() -> Self {
+        AccuracyProfile::Accurate
+    }
+}
+
+impl FromStr for AccuracyProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "fast" => Ok(AccuracyProfile::Fast),
+            "balanced" => Ok(AccuracyProfile::Balanced),
+            "accurate" => Ok(AccuracyProfile::Accurate),
+            _ => Err(format!("unknown accuracy profile: {} (expected `fast`, `balanced` or \
+                              `accurate`)", s)),
+        }
+    }
+}
+
+/// The individual accuracy toggles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Accuracy {
+    /// Stall the CPU for WRAM refresh once per scanline
+    pub dram_refresh: bool,
+    /// Charge the CPU for the time spent doing DMA and HDMA transfers
+    pub dma_timing: bool,
+    /// Number of master cycles the APU may lag behind the CPU before it is caught up. `0` catches
+    /// it up after every CPU instruction.
+    pub apu_sync: u32,
+    /// Number of master cycles the PPU may lag behind the CPU before it is caught up. `0` catches
+    /// it up after every CPU instruction. Larger values delay IRQs and register reads that depend
+    /// on the beam position.
+    pub ppu_sync: u32,
+    /// Apply BG register writes in the middle of a scanline to the rest of that scanline. If
+    /// `false`, the BG settings are only read once at the start of each scanline.
+    pub bg_raster: bool,
+    /// Make multiplication and division results available only after the 8 or 16 CPU cycles the
+    /// hardware needs. If `false`, they are available immediately.
+    pub alu_latency: bool,
+}
+
+impl Default for Accuracy {
+    fn default() -> Self {
+        Accuracy::from_profile(AccuracyProfile::default())
+    }
+}
+
+impl Accuracy {
+    /// Creates the settings bundled in a profile.
+    pub fn from_profile(profile: AccuracyProfile) -> Self {
+        match profile {
+            AccuracyProfile::Fast => Accuracy {
+                dram_refresh: false,
+                dma_timing: false,
+                apu_sync: 1024,
+                ppu_sync: 128,
+                bg_raster: false,
+                alu_latency: false,
+            },
+            AccuracyProfile::Balanced => Accuracy {
+                dram_refresh: true,
+                dma_timing: true,
+                apu_sync: 256,
+                ppu_sync: 0,
+                bg_raster: true,
+                alu_latency: true,
+            },
+            AccuracyProfile::Accurate => Accuracy {
+                dram_refresh: true,
+                dma_timing: true,
+                apu_sync: 0,
+                ppu_sync: 0,
+                bg_raster: true,
+                alu_latency: true,
+            },
+        }
+    }
+
+    /// Returns `true` if the PPU is dot-accurate (see the module docs).
+    pub fn dot_ppu(&self) -> bool {
+        self.ppu_sync == 0 && self.bg_raster
+    }
+
+    /// Enables or disables the dot-accurate PPU. Disabling it uses the settings of the `Fast`
+    /// profile.
+    pub fn set_dot_ppu(&mut self, enabled: bool) {
+        let fast = Accuracy::from_profile(AccuracyProfile::Fast);
+        self.ppu_sync = if enabled { 0 } else { fast.ppu_sync };
+        self.bg_raster = enabled;
+    }
+
+    /// Returns `true` if the CPU is cycle-stepped (see the module docs).
+    pub fn cycle_cpu(&self) -> bool {
+        self.dram_refresh && self.alu_latency
+    }
+
+    /// Enables or disables cycle-stepped CPU timing.
+    pub fn set_cycle_cpu(&mut self, enabled: bool) {
+        self.dram_refresh = enabled;
+        self.alu_latency = enabled;
+    }
+
+    /// Overrides a single option by name. Boolean options accept `on`/`off` and `true`/`false`,
+    /// the others take a number.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn parse_bool(value: &str) -> Result<bool, String> {
+            match value {
+                "on" | "true" => Ok(true),
+                "off" | "false" => Ok(false),
+                _ => Err(format!("invalid boolean value: {}", value)),
+            }
+        }
+        fn parse_num(value: &str) -> Result<u32, String> {
+            value.parse().map_err(|_| format!("invalid number: {}", value))
+        }
+
+        match name {
+            "dram_refresh" => self.dram_refresh = try!(parse_bool(value)),
+            "dma_timing" => self.dma_timing = try!(parse_bool(value)),
+            "apu_sync" => self.apu_sync = try!(parse_num(value)),
+            "ppu_sync" => self.ppu_sync = try!(parse_num(value)),
+            "bg_raster" => self.bg_raster = try!(parse_bool(value)),
+            "alu_latency" => self.alu_latency = try!(parse_bool(value)),
+            "dot_ppu" => self.set_dot_ppu(try!(parse_bool(value))),
+            "cycle_cpu" => self.set_cycle_cpu(try!(parse_bool(value))),
+            _ => return Err(format!("unknown accuracy option: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Parses and applies an override of the form `name=value`.
+    pub fn apply_override(&mut self, opt: &str) -> Result<(), String> {
+        let mut split = opt.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(name), Some(value)) => self.set_option(name.trim(), value.trim()),
+            _ => Err(format!("invalid accuracy override (expected `name=value`): {}", opt)),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Headless benchmarking
+//!
+//! Runs the emulator as fast as possible without rendering or audio output and measures how many
+//! frames it manages per second. Useful for comparing accuracy settings and for catching
+//! performance regressions.
+
+use snes::Snes;
+use stats::Counters;
+
+use breeze_backend::BackendResult;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The outcome of a benchmark run
+#[derive(Clone, Debug)]
+pub struct BenchmarkResult {
+    /// Number of frames emulated
+    pub frames: u64,
+    /// Wall-clock time the frames took
+    pub elapsed: Duration,
+    /// Cycles emulated during the run
+    pub counters: Counters,
+    /// Frame rate of the emulated console, to compare against
+    pub native_frame_rate: f64,
+}
+
+impl BenchmarkResult {
+    /// Returns the number of frames emulated per second.
+    pub fn frame_rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 * 1e-9;
+        if secs == 0.0 { 0.0 } else { self.frames as f64 / secs }
+    }
+
+    /// Returns the emulation speed relative to the real console (`1.0` is full speed).
+    pub fn speed(&self) -> f64 {
+        self.frame_rate() / self.native_frame_rate
+    }
+}
+
+impl fmt::Display for BenchmarkResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = self.elapsed.as_secs() * 1000 + self.elapsed.subsec_nanos() as u64 / 1_000_000;
+        try!(writeln!(f, "{} frames in {} ms: {:.1} FPS ({:.0}% of full speed)", self.frames, ms,
+            self.frame_rate(), self.speed() * 100.0));
+        write!(f, "{} master cycles ({} CPU, {} APU, {} DMA)", self.counters.master_cy,
+            self.counters.cpu_cy, self.counters.apu_cy, self.counters.dma_cy)
+    }
+}
+
+/// Emulates `frames` frames as fast as possible and measures the time it took.
+pub fn run(snes: &mut Snes, frames: u64) -> BackendResult<BenchmarkResult> {
+    let start_counters = snes.stats().total;
+    let start = Instant::now();
+    for _ in 0..frames {
+        try!(snes.advance_frame());
+    }
+    let elapsed = start.elapsed();
+
+    Ok(BenchmarkResult {
+        frames: frames,
+        elapsed: elapsed,
+        counters: snes.stats().total - start_counters,
+        native_frame_rate: snes.frame_rate(),
+    })
+}
@@ -0,0 +1,184 @@
+//! Timeline export in the Chrome tracing format
+//!
+//! A `ChromeTrace` records what the scheduler does (frames, V-Blank, NMIs, IRQs, DMA and HDMA
+//! transfers and the bursts in which the APU catches up with the CPU) along with the master cycle
+//! it happened at. `write_json` produces a file that can be loaded into `chrome://tracing` or
+//! other viewers of the Trace Event Format (like Perfetto), which show each component on its own
+//! row.
+//!
+//! Timestamps are derived from the master clock, so they show emulated time, not how long it took
+//! to emulate.
+
+use std::io::{self, Write};
+
+/// Master clock frequency of an NTSC SNES in Hz, used to convert cycles to microseconds
+const MASTER_CLOCK_HZ: f64 = 21_477_272.0;
+
+/// Default maximum number of events recorded
+const DEFAULT_MAX_EVENTS: usize = 1_000_000;
+
+/// The row an event is displayed on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Track {
+    Cpu,
+    Ppu,
+    Dma,
+    Apu,
+}
+
+impl Track {
+    fn name(&self) -> &'static str {
+        match *self {
+            Track::Cpu => "CPU",
+            Track::Ppu => "PPU",
+            Track::Dma => "DMA",
+            Track::Apu => "APU",
+        }
+    }
+
+    /// Thread ID used in the trace file
+    fn tid(&self) -> u32 { *self as u32 }
+}
+
+/// A recorded event
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub track: Track,
+    pub name: String,
+    /// Master cycle at which the event started
+    pub start: u64,
+    /// Length in master cycles. Instant events (like interrupts) have none.
+    pub duration: Option<u64>,
+}
+
+/// Records scheduler events. Enable with `Snes::set_chrome_trace`.
+#[derive(Clone, Debug)]
+pub struct ChromeTrace {
+    events: Vec<Event>,
+    max_events: usize,
+    /// Master cycle at which the current CPU instruction started
+    now: u64,
+    /// Start of the current frame and V-Blank period, if they started while recording
+    frame_start: Option<u64>,
+    vblank_start: Option<u64>,
+    frames: u64,
+}
+
+impl Default for ChromeTrace {
+    fn default() -> Self { Self::with_max_events(DEFAULT_MAX_EVENTS) }
+}
+
+impl ChromeTrace {
+    /// Creates a trace that records up to 1 million events.
+    pub fn new() -> Self { Self::default() }
+
+    /// Creates a trace that records up to `max_events` events. Further events are dropped.
+    pub fn with_max_events(max_events: usize) -> Self {
+        ChromeTrace {
+            events: Vec::new(),
+            max_events: max_events,
+            now: 0,
+            frame_start: None,
+            vblank_start: None,
+            frames: 0,
+        }
+    }
+
+    /// Returns the recorded events, in the order they were recorded.
+    pub fn events(&self) -> &[Event] { &self.events }
+
+    /// Returns whether the event limit was reached.
+    pub fn is_full(&self) -> bool { self.events.len() >= self.max_events }
+
+    /// Sets the master cycle the current CPU instruction started at. Events recorded by the CPU's
+    /// address bus (like DMA transfers) are timestamped with it.
+    pub fn set_time(&mut self, master_cy: u64) { self.now = master_cy }
+
+    /// Returns the time set by `set_time`.
+    pub fn now(&self) -> u64 { self.now }
+
+    /// Records an event that happens at a single point in time.
+    pub fn instant<S: Into<String>>(&mut self, track: Track, name: S, time: u64) {
+        self.push(Event {
+            track: track,
+            name: name.into(),
+            start: time,
+            duration: None,
+        });
+    }
+
+    /// Records an event that takes `duration` master cycles.
+    pub fn span<S: Into<String>>(&mut self, track: Track, name: S, start: u64, duration: u64) {
+        self.push(Event {
+            track: track,
+            name: name.into(),
+            start: start,
+            duration: Some(duration),
+        });
+    }
+
+    /// Called when the PPU starts a new frame (V=0, H=0).
+    pub fn start_frame(&mut self, time: u64) {
+        if let Some(start) = self.frame_start {
+            let name = format!("frame {}", self.frames);
+            self.span(Track::Ppu, name, start, time - start);
+        }
+        if let Some(start) = self.vblank_start.take() {
+            self.span(Track::Ppu, "V-Blank", start, time - start);
+        }
+        self.frame_start = Some(time);
+        self.frames += 1;
+    }
+
+    /// Called when V-Blank starts.
+    pub fn start_vblank(&mut self, time: u64) {
+        self.vblank_start = Some(time);
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.events.len() < self.max_events {
+            self.events.push(event);
+            if self.is_full() {
+                once!(warn!("Chrome trace is full, dropping further events"));
+            }
+        }
+    }
+
+    /// Writes the events as a JSON trace file.
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let micros = |cy: u64| cy as f64 * 1_000_000.0 / MASTER_CLOCK_HZ;
+
+        try!(write!(out, "{{\"traceEvents\":["));
+        // Name the rows
+        for (i, track) in [Track::Cpu, Track::Ppu, Track::Dma, Track::Apu].iter().enumerate() {
+            if i > 0 { try!(write!(out, ",")); }
+            try!(write!(out, "\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\
+                \"args\":{{\"name\":\"{}\"}}}}", track.tid(), track.name()));
+        }
+        for event in &self.events {
+            try!(write!(out, ",\n{{\"name\":\"{}\",\"pid\":1,\"tid\":{},\"ts\":{:.3}",
+                escape(&event.name), event.track.tid(), micros(event.start)));
+            match event.duration {
+                Some(duration) => try!(write!(out, ",\"ph\":\"X\",\"dur\":{:.3}}}",
+                    micros(duration))),
+                None => try!(write!(out, ",\"ph\":\"i\",\"s\":\"t\"}}")),
+            }
+        }
+        try!(writeln!(out, "\n],\"displayTimeUnit\":\"ns\"}}"));
+        Ok(())
+    }
+}
+
+/// Escapes a string for use in a JSON string literal.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,159 @@
+//! Clock rates
+//!
+//! The CPU and PPU are driven by the master clock crystal, while the APU has its own ceramic
+//! resonator. Ceramic resonators are much less precise than crystals, so the APU runs at a slightly
+//! different speed on every console (almost always a bit faster than the nominal 24.576 MHz).
+//! Some games are sensitive to the resulting CPU/APU clock ratio, so it can be changed here.
+//!
+//! PAL consoles have a slightly slower master clock and longer frames (312 instead of 262
+//! scanlines), so they run at about 50 instead of 60 frames per second.
+
+use rom::Region;
+
+use std::str::FromStr;
+
+/// Nominal master clock rate of an NTSC console in Hz
+pub const NTSC_MASTER_CLOCK: u32 = 21_477_272;
+
+/// Nominal master clock rate of a PAL console in Hz
+pub const PAL_MASTER_CLOCK: u32 = 21_281_370;
+
+/// Nominal SPC700 clock rate in Hz (the 24.576 MHz resonator divided by 24)
+pub const NOMINAL_APU_CLOCK: u32 = 1_024_000;
+
+/// Master clock cycles per NTSC frame (on average: every other non-interlaced frame is 4 cycles
+/// shorter)
+const NTSC_MASTER_CY_PER_FRAME: f64 = 357_366.0;
+
+/// Master clock cycles per PAL frame (312 scanlines of 1364 cycles)
+const PAL_MASTER_CY_PER_FRAME: f64 = 425_568.0;
+
+/// Allowed master clock rates (a few percent around the nominal rate)
+const MASTER_CLOCK_RANGE: (u32, u32) = (21_000_000, 22_000_000);
+
+/// Allowed APU clock rates (resonators deviate far less than this)
+const APU_CLOCK_RANGE: (u32, u32) = (1_000_000, 1_050_000);
+
+/// Named clock rate settings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockPreset {
+    /// The rates from the data sheets (the default)
+    Nominal,
+    /// An APU resonator running at the speed measured on most real consoles (the DSP produces
+    /// about 32040 samples per second instead of 32000)
+    Typical,
+    /// An APU resonator on the fast end of the measured range (about 32100 samples per second)
+    FastApu,
+}
+
+impl Default for ClockPreset {
+    fn default() -> Self {
+        ClockPreset::Nominal
+    }
+}
+
+impl FromStr for ClockPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "nominal" => Ok(ClockPreset::Nominal),
+            "typical" => Ok(ClockPreset::Typical),
+            "fast-apu" => Ok(ClockPreset::FastApu),
+            _ => Err(format!("unknown clock preset: {} (expected `nominal`, `typical` or \
+                              `fast-apu`)", s)),
+        }
+    }
+}
+
+/// The clock rates of the emulated console.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClockRates {
+    /// Master clock rate in Hz
+    master: u32,
+    /// SPC700 clock rate in Hz
+    apu: u32,
+    /// Region of the console, which determines the length of a frame
+    region: Region,
+}
+
+impl Default for ClockRates {
+    fn default() -> Self {
+        ClockRates::from_preset(ClockPreset::default())
+    }
+}
+
+impl ClockRates {
+    /// Creates the rates of a preset (for an NTSC console, see `set_region`).
+    pub fn from_preset(preset: ClockPreset) -> Self {
+        let apu = match preset {
+            ClockPreset::Nominal => NOMINAL_APU_CLOCK,
+            ClockPreset::Typical => 32_040 * 32,
+            ClockPreset::FastApu => 32_100 * 32,
+        };
+        ClockRates {
+            master: NTSC_MASTER_CLOCK,
+            apu: apu,
+            region: Region::Ntsc,
+        }
+    }
+
+    /// Creates custom rates (in Hz) for an NTSC console. Fails if a rate is outside of the range
+    /// real consoles could conceivably run at.
+    pub fn new(master: u32, apu: u32) -> Result<Self, String> {
+        let check = |name, rate, (min, max)| if rate < min || rate > max {
+            Err(format!("{} clock rate {} Hz is out of range (expected {} to {} Hz)", name, rate,
+                        min, max))
+        } else {
+            Ok(())
+        };
+        try!(check("master", master, MASTER_CLOCK_RANGE));
+        try!(check("APU", apu, APU_CLOCK_RANGE));
+
+        Ok(ClockRates {
+            master: master,
+            apu: apu,
+            region: Region::Ntsc,
+        })
+    }
+
+    /// Changes the region of the console. If the master clock runs at the nominal rate of the
+    /// previous region, it is switched to the nominal rate of the new one. Custom rates are kept.
+    pub fn set_region(&mut self, region: Region) {
+        if self.master == nominal_master_clock(self.region) {
+            self.master = nominal_master_clock(region);
+        }
+        self.region = region;
+    }
+
+    /// Returns the region the rates are used for.
+    pub fn region(&self) -> Region { self.region }
+
+    /// Returns the master clock rate in Hz.
+    pub fn master(&self) -> u32 { self.master }
+
+    /// Returns the SPC700 clock rate in Hz.
+    pub fn apu(&self) -> u32 { self.apu }
+
+    /// Returns the number of frames rendered per second at these rates.
+    pub fn frame_rate(&self) -> f64 {
+        let cy_per_frame = match self.region {
+            Region::Ntsc => NTSC_MASTER_CY_PER_FRAME,
+            Region::Pal => PAL_MASTER_CY_PER_FRAME,
+        };
+        self.master as f64 / cy_per_frame
+    }
+
+    /// Returns the length of an APU cycle in master cycles, as a 16.16 fixed-point number.
+    pub fn apu_divider(&self) -> u32 {
+        (((self.master as u64) << 16) / self.apu as u64) as u32
+    }
+}
+
+/// Returns the nominal master clock rate of a console of the given region.
+fn nominal_master_clock(region: Region) -> u32 {
+    match region {
+        Region::Ntsc => NTSC_MASTER_CLOCK,
+        Region::Pal => PAL_MASTER_CLOCK,
+    }
+}
@@ -0,0 +1,739 @@
+//! Persistent configuration
+//!
+//! Settings that would otherwise have to be passed on the command line every time are read from a
+//! TOML file. There is a global config file (see `Config::default_path`), and every game can have
+//! its own (`GameStorage::config_path`) whose settings take precedence:
+//!
+//! ```toml
+//! [emulator]
+//! renderer = "glium"
+//! audio = "cpal"
+//! ram_init = "random"
+//! slow_motion = 0.5
+//! region = "pal"
+//! save_state_on_exit = true
+//! stack_policy = "break"
+//!
+//! [video]
+//! scale = 4
+//! filter = "scanlines"
+//! aspect = "ntsc"
+//! integer_scaling = true
+//! show_fps = true
+//! widescreen = 32
+//!
+//! [audio]
+//! buffer_size = 512
+//! periods = 3
+//!
+//! [accuracy]
+//! profile = "balanced"
+//! dot_ppu = false
+//! ppu_sync = 0
+//!
+//! [clock]
+//! preset = "typical"
+//! apu_hz = 1026000
+//!
+//! [paths]
+//! data_dir = "/home/me/snes"
+//!
+//! [log]
+//! dma = "trace"
+//! cpu = "warn"
+//!
+//! [hotkeys]
+//! F1 = "play-macro-1"
+//! F2 = "record-macro-1"
+//!
+//! [macros]
+//! 3 = "/home/me/snes/combos/hadoken.txt"
+//! ```
+//!
+//! All settings are optional. String settings accept the same values as the corresponding command
+//! line options. The config types implement `Serialize` and `Deserialize` when the `serialize`
+//! feature is enabled, so frontends can embed them in their own settings.
+
+mod toml;
+
+use self::toml::{Entry, Value};
+use accuracy::{Accuracy, AccuracyProfile};
+use clock::{ClockPreset, ClockRates};
+use log_util::{self, Component, COMPONENTS};
+
+use breeze_backend::AudioBufferConfig;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// General emulator settings (`[emulator]`)
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct EmulatorConfig {
+    /// Renderer backend to use
+    pub renderer: Option<String>,
+    /// Audio backend to use
+    pub audio: Option<String>,
+    /// RAM initialization pattern (see `RamInit`)
+    pub ram_init: Option<String>,
+    /// Emulation speed while the slow motion hotkey is held
+    pub slow_motion: Option<f64>,
+    /// Region of the emulated console (see `Region`), overriding the one in the ROM header
+    pub region: Option<String>,
+    /// Save the state on exit and restore it on the next start
+    pub save_state_on_exit: Option<bool>,
+    /// What to do when the CPU's stack pointer wraps around (see `StackPolicy`)
+    pub stack_policy: Option<String>,
+}
+
+/// Display settings (`[video]`)
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct VideoConfig {
+    /// Initial window size as a multiple of the native resolution
+    pub scale: Option<u32>,
+    /// Scaling filter
+    pub filter: Option<String>,
+    /// Post-processing shader (a file name or the name of a built-in shader)
+    pub shader: Option<String>,
+    /// Aspect ratio of the displayed image
+    pub aspect: Option<String>,
+    /// Only scale by whole numbers
+    pub integer_scaling: Option<bool>,
+    /// Color correction (see `ColorCorrection`)
+    pub color: Option<String>,
+    /// Show the FPS counter
+    pub show_fps: Option<bool>,
+    /// Show on-screen messages
+    pub osd: Option<bool>,
+    /// Width of the widescreen margins on each side of the picture (see `Ppu::set_widescreen`)
+    pub widescreen: Option<u32>,
+    /// Never use widescreen rendering (for games that glitch with it, in their per-game config)
+    pub widescreen_blacklisted: Option<bool>,
+}
+
+/// Audio output settings (`[audio]`)
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct AudioConfig {
+    /// Number of sample frames the device requests at a time
+    pub buffer_size: Option<u32>,
+    /// Number of buffers kept filled ahead of the device
+    pub periods: Option<u32>,
+}
+
+impl AudioConfig {
+    /// Creates the buffer config described by this config. Missing settings keep their defaults.
+    pub fn to_buffer_config(&self) -> Result<AudioBufferConfig, String> {
+        let default = AudioBufferConfig::default();
+        let config = AudioBufferConfig {
+            period_size: self.buffer_size.unwrap_or(default.period_size),
+            periods: self.periods.unwrap_or(default.periods),
+        };
+        if config.period_size == 0 {
+            return Err("the audio buffer size must be at least 1".to_string());
+        }
+        if config.periods == 0 {
+            return Err("the number of audio periods must be at least 1".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// Accuracy settings (`[accuracy]`). The options override those of the profile. The grouped
+/// options `dot_ppu` and `cycle_cpu` are applied first, so the individual toggles override them.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct AccuracyConfig {
+    /// Name of the profile to start with
+    pub profile: Option<String>,
+    pub dram_refresh: Option<bool>,
+    pub dma_timing: Option<bool>,
+    pub apu_sync: Option<u32>,
+    pub ppu_sync: Option<u32>,
+    pub bg_raster: Option<bool>,
+    pub alu_latency: Option<bool>,
+    pub dot_ppu: Option<bool>,
+    pub cycle_cpu: Option<bool>,
+}
+
+impl AccuracyConfig {
+    /// Creates the accuracy settings described by this config.
+    pub fn to_accuracy(&self) -> Result<Accuracy, String> {
+        let profile = match self.profile {
+            Some(ref name) => try!(name.parse::<AccuracyProfile>()),
+            None => AccuracyProfile::default(),
+        };
+
+        let mut accuracy = Accuracy::from_profile(profile);
+        if let Some(value) = self.dot_ppu { accuracy.set_dot_ppu(value) }
+        if let Some(value) = self.cycle_cpu { accuracy.set_cycle_cpu(value) }
+        if let Some(value) = self.dram_refresh { accuracy.dram_refresh = value }
+        if let Some(value) = self.dma_timing { accuracy.dma_timing = value }
+        if let Some(value) = self.apu_sync { accuracy.apu_sync = value }
+        if let Some(value) = self.ppu_sync { accuracy.ppu_sync = value }
+        if let Some(value) = self.bg_raster { accuracy.bg_raster = value }
+        if let Some(value) = self.alu_latency { accuracy.alu_latency = value }
+        Ok(accuracy)
+    }
+
+    /// Parses and applies an override of the form `name=value` (see `Accuracy::apply_override`).
+    pub fn apply_override(&mut self, opt: &str) -> Result<(), String> {
+        // Let `Accuracy` validate the override, then take the value it set
+        let mut accuracy = Accuracy::default();
+        try!(accuracy.apply_override(opt));
+        match opt.splitn(2, '=').next().unwrap().trim() {
+            "dram_refresh" => self.dram_refresh = Some(accuracy.dram_refresh),
+            "dma_timing" => self.dma_timing = Some(accuracy.dma_timing),
+            "apu_sync" => self.apu_sync = Some(accuracy.apu_sync),
+            "ppu_sync" => self.ppu_sync = Some(accuracy.ppu_sync),
+            "bg_raster" => self.bg_raster = Some(accuracy.bg_raster),
+            "alu_latency" => self.alu_latency = Some(accuracy.alu_latency),
+            "dot_ppu" => self.dot_ppu = Some(accuracy.dot_ppu()),
+            "cycle_cpu" => self.cycle_cpu = Some(accuracy.cycle_cpu()),
+            name => unreachable!("unhandled accuracy option {}", name),
+        }
+        Ok(())
+    }
+}
+
+/// Clock rates (`[clock]`). The rates override those of the preset.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ClockConfig {
+    /// Name of the preset to start with (see `ClockPreset`)
+    pub preset: Option<String>,
+    /// Master clock rate in Hz
+    pub master_hz: Option<u32>,
+    /// SPC700 clock rate in Hz
+    pub apu_hz: Option<u32>,
+}
+
+impl ClockConfig {
+    /// Creates the clock rates described by this config.
+    pub fn to_clock_rates(&self) -> Result<ClockRates, String> {
+        let preset = match self.preset {
+            Some(ref name) => try!(name.parse::<ClockPreset>()),
+            None => ClockPreset::default(),
+        };
+
+        let rates = ClockRates::from_preset(preset);
+        ClockRates::new(self.master_hz.unwrap_or(rates.master()),
+                        self.apu_hz.unwrap_or(rates.apu()))
+    }
+}
+
+/// File locations (`[paths]`)
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct PathsConfig {
+    /// Base directory for per-game data (see `GameStorage`)
+    pub data_dir: Option<PathBuf>,
+}
+
+/// Log levels of the emulated components (`[log]`), overriding `RUST_LOG` (see `log_util`)
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct LogConfig {
+    pub cpu: Option<String>,
+    pub ppu: Option<String>,
+    pub apu: Option<String>,
+    pub dma: Option<String>,
+    pub input: Option<String>,
+}
+
+impl LogConfig {
+    /// Returns the configured level of a component.
+    pub fn level(&self, component: Component) -> &Option<String> {
+        match component {
+            Component::Cpu => &self.cpu,
+            Component::Ppu => &self.ppu,
+            Component::Apu => &self.apu,
+            Component::Dma => &self.dma,
+            Component::Input => &self.input,
+        }
+    }
+
+    pub fn level_mut(&mut self, component: Component) -> &mut Option<String> {
+        match component {
+            Component::Cpu => &mut self.cpu,
+            Component::Ppu => &mut self.ppu,
+            Component::Apu => &mut self.apu,
+            Component::Dma => &mut self.dma,
+            Component::Input => &mut self.input,
+        }
+    }
+
+    /// Sets the log level of every component that has one configured (see
+    /// `log_util::set_component_level`). Nothing is changed if a level is invalid.
+    pub fn apply(&self) -> Result<(), String> {
+        let mut levels = Vec::new();
+        for &component in &COMPONENTS {
+            if let Some(ref level) = *self.level(component) {
+                levels.push((component, try!(log_util::parse_level(level))));
+            }
+        }
+        for (component, level) in levels {
+            log_util::set_component_level(component, Some(level));
+        }
+        Ok(())
+    }
+}
+
+/// All settings stored in a config file
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Config {
+    pub emulator: EmulatorConfig,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub accuracy: AccuracyConfig,
+    pub clock: ClockConfig,
+    pub paths: PathsConfig,
+    pub log: LogConfig,
+    /// `(key, action)` hotkey bindings (`[hotkeys]`), applied on top of the default bindings. An
+    /// empty key removes the default binding of the action.
+    pub hotkeys: Vec<(String, String)>,
+    /// `(slot, path)` input macros to load (`[macros]`), with slots counted from 1
+    pub macros: Vec<(u8, PathBuf)>,
+}
+
+impl Config {
+    /// Returns the path of the global config file: `$BREEZE_CONFIG` if set, otherwise
+    /// `breeze/config.toml` in the platform's config directory (`$XDG_CONFIG_HOME`, `~/.config`
+    /// or `%APPDATA%`), falling back to `breeze.toml` in the working directory.
+    pub fn default_path() -> PathBuf {
+        if let Some(path) = env::var_os("BREEZE_CONFIG") {
+            return path.into();
+        }
+
+        let mut dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+        if dir.is_none() && cfg!(windows) {
+            dir = env::var_os("APPDATA").map(PathBuf::from);
+        }
+        if dir.is_none() {
+            dir = env::var_os("HOME").map(|home| Path::new(&home).join(".config"));
+        }
+        match dir {
+            Some(dir) => dir.join("breeze").join("config.toml"),
+            None => PathBuf::from("breeze.toml"),
+        }
+    }
+
+    /// Reads a config file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)));
+        text.parse::<Config>()
+            .map_err(|e| format!("invalid config file '{}': {}", path.display(), e).into())
+    }
+
+    /// Reads a config file if it exists. Returns an empty config if it doesn't.
+    pub fn load_if_exists<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>> {
+        if path.as_ref().exists() {
+            Config::load(path)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    /// Writes the config to a file, creating the directories containing it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<Error>> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        let mut file = try!(File::create(path));
+        try!(write!(file, "{}", self));
+        Ok(())
+    }
+
+    /// Applies all settings of `other` on top of `self`. Settings missing in `other` are kept.
+    pub fn merge(&mut self, other: &Config) {
+        fn merge<T: Clone>(this: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *this = other.clone();
+            }
+        }
+
+        merge(&mut self.emulator.renderer, &other.emulator.renderer);
+        merge(&mut self.emulator.audio, &other.emulator.audio);
+        merge(&mut self.emulator.ram_init, &other.emulator.ram_init);
+        merge(&mut self.emulator.slow_motion, &other.emulator.slow_motion);
+        merge(&mut self.emulator.region, &other.emulator.region);
+        merge(&mut self.emulator.save_state_on_exit, &other.emulator.save_state_on_exit);
+        merge(&mut self.emulator.stack_policy, &other.emulator.stack_policy);
+        merge(&mut self.video.scale, &other.video.scale);
+        merge(&mut self.video.filter, &other.video.filter);
+        merge(&mut self.video.shader, &other.video.shader);
+        merge(&mut self.video.aspect, &other.video.aspect);
+        merge(&mut self.video.integer_scaling, &other.video.integer_scaling);
+        merge(&mut self.video.color, &other.video.color);
+        merge(&mut self.video.show_fps, &other.video.show_fps);
+        merge(&mut self.video.osd, &other.video.osd);
+        merge(&mut self.video.widescreen, &other.video.widescreen);
+        merge(&mut self.video.widescreen_blacklisted, &other.video.widescreen_blacklisted);
+        merge(&mut self.audio.buffer_size, &other.audio.buffer_size);
+        merge(&mut self.audio.periods, &other.audio.periods);
+        merge(&mut self.accuracy.profile, &other.accuracy.profile);
+        merge(&mut self.accuracy.dram_refresh, &other.accuracy.dram_refresh);
+        merge(&mut self.accuracy.dma_timing, &other.accuracy.dma_timing);
+        merge(&mut self.accuracy.apu_sync, &other.accuracy.apu_sync);
+        merge(&mut self.accuracy.ppu_sync, &other.accuracy.ppu_sync);
+        merge(&mut self.accuracy.bg_raster, &other.accuracy.bg_raster);
+        merge(&mut self.accuracy.alu_latency, &other.accuracy.alu_latency);
+        merge(&mut self.accuracy.dot_ppu, &other.accuracy.dot_ppu);
+        merge(&mut self.accuracy.cycle_cpu, &other.accuracy.cycle_cpu);
+        merge(&mut self.clock.preset, &other.clock.preset);
+        merge(&mut self.clock.master_hz, &other.clock.master_hz);
+        merge(&mut self.clock.apu_hz, &other.clock.apu_hz);
+        merge(&mut self.paths.data_dir, &other.paths.data_dir);
+        for &component in &COMPONENTS {
+            merge(self.log.level_mut(component), other.log.level(component));
+        }
+
+        for &(ref key, ref action) in &other.hotkeys {
+            // An empty key unbinds the action, there can be several of those
+            if !key.is_empty() {
+                self.hotkeys.retain(|&(ref k, _)| !k.eq_ignore_ascii_case(key));
+            }
+            self.hotkeys.push((key.clone(), action.clone()));
+        }
+        for &(slot, ref path) in &other.macros {
+            self.macros.retain(|&(s, _)| s != slot);
+            self.macros.push((slot, path.clone()));
+        }
+    }
+
+    /// Stores a single setting.
+    fn set(&mut self, entry: Entry) -> Result<(), String> {
+        fn string(value: Value) -> Result<String, String> {
+            match value {
+                Value::String(s) => Ok(s),
+                v => Err(format!("expected a string, found {}", v.type_name())),
+            }
+        }
+        fn boolean(value: Value) -> Result<bool, String> {
+            match value {
+                Value::Boolean(b) => Ok(b),
+                v => Err(format!("expected a boolean, found {}", v.type_name())),
+            }
+        }
+        fn number(value: Value) -> Result<f64, String> {
+            match value {
+                Value::Float(x) => Ok(x),
+                Value::Integer(i) => Ok(i as f64),
+                v => Err(format!("expected a number, found {}", v.type_name())),
+            }
+        }
+        fn uint(value: Value) -> Result<u32, String> {
+            match value {
+                Value::Integer(i) if i >= 0 && i <= u32::max_value() as i64 => Ok(i as u32),
+                Value::Integer(i) => Err(format!("{} is out of range", i)),
+                v => Err(format!("expected an integer, found {}", v.type_name())),
+            }
+        }
+
+        let Entry { table, key, value, line } = entry;
+        let result = match (&*table, &*key) {
+            ("emulator", "renderer") => string(value).map(|v| self.emulator.renderer = Some(v)),
+            ("emulator", "audio") => string(value).map(|v| self.emulator.audio = Some(v)),
+            ("emulator", "ram_init") => string(value).map(|v| self.emulator.ram_init = Some(v)),
+            ("emulator", "slow_motion") => {
+                number(value).map(|v| self.emulator.slow_motion = Some(v))
+            }
+            ("emulator", "region") => string(value).map(|v| self.emulator.region = Some(v)),
+            ("emulator", "save_state_on_exit") => {
+                boolean(value).map(|v| self.emulator.save_state_on_exit = Some(v))
+            }
+            ("emulator", "stack_policy") => {
+                string(value).map(|v| self.emulator.stack_policy = Some(v))
+            }
+            ("video", "scale") => uint(value).map(|v| self.video.scale = Some(v)),
+            ("video", "filter") => string(value).map(|v| self.video.filter = Some(v)),
+            ("video", "shader") => string(value).map(|v| self.video.shader = Some(v)),
+            ("video", "aspect") => string(value).map(|v| self.video.aspect = Some(v)),
+            ("video", "integer_scaling") => {
+                boolean(value).map(|v| self.video.integer_scaling = Some(v))
+            }
+            ("video", "color") => string(value).map(|v| self.video.color = Some(v)),
+            ("video", "show_fps") => boolean(value).map(|v| self.video.show_fps = Some(v)),
+            ("video", "osd") => boolean(value).map(|v| self.video.osd = Some(v)),
+            ("video", "widescreen") => uint(value).map(|v| self.video.widescreen = Some(v)),
+            ("video", "widescreen_blacklisted") => {
+                boolean(value).map(|v| self.video.widescreen_blacklisted = Some(v))
+            }
+            ("audio", "buffer_size") => uint(value).map(|v| self.audio.buffer_size = Some(v)),
+            ("audio", "periods") => uint(value).map(|v| self.audio.periods = Some(v)),
+            ("accuracy", "profile") => string(value).map(|v| self.accuracy.profile = Some(v)),
+            ("accuracy", "dram_refresh") => {
+                boolean(value).map(|v| self.accuracy.dram_refresh = Some(v))
+            }
+            ("accuracy", "dma_timing") => {
+                boolean(value).map(|v| self.accuracy.dma_timing = Some(v))
+            }
+            ("accuracy", "apu_sync") => uint(value).map(|v| self.accuracy.apu_sync = Some(v)),
+            ("accuracy", "ppu_sync") => uint(value).map(|v| self.accuracy.ppu_sync = Some(v)),
+            ("accuracy", "bg_raster") => {
+                boolean(value).map(|v| self.accuracy.bg_raster = Some(v))
+            }
+            ("accuracy", "alu_latency") => {
+                boolean(value).map(|v| self.accuracy.alu_latency = Some(v))
+            }
+            ("accuracy", "dot_ppu") => boolean(value).map(|v| self.accuracy.dot_ppu = Some(v)),
+            ("accuracy", "cycle_cpu") => {
+                boolean(value).map(|v| self.accuracy.cycle_cpu = Some(v))
+            }
+            ("clock", "preset") => string(value).map(|v| self.clock.preset = Some(v)),
+            ("clock", "master_hz") => uint(value).map(|v| self.clock.master_hz = Some(v)),
+            ("clock", "apu_hz") => uint(value).map(|v| self.clock.apu_hz = Some(v)),
+            ("paths", "data_dir") => string(value).map(|v| self.paths.data_dir = Some(v.into())),
+            ("log", _) => match key.parse::<Component>() {
+                Ok(component) => string(value).map(|v| *self.log.level_mut(component) = Some(v)),
+                Err(_) => Err(format!("unknown setting `{}` in `[log]`", key)),
+            },
+            ("hotkeys", _) => string(value).map(|v| self.hotkeys.push((key.clone(), v))),
+            ("macros", _) => match key.parse::<u8>() {
+                Ok(slot) if slot >= 1 => {
+                    string(value).map(|v| self.macros.push((slot, v.into())))
+                }
+                _ => Err(format!("invalid macro slot `{}`", key)),
+            },
+            ("", _) => Err(format!("unknown setting `{}` (settings must be in a table)", key)),
+            (_, _) => Err(format!("unknown setting `{}` in `[{}]`", key, table)),
+        };
+        result.map_err(|e| format!("line {}: {}", line, e))
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut config = Config::default();
+        for entry in try!(toml::parse(s)) {
+            try!(config.set(entry));
+        }
+        Ok(config)
+    }
+}
+
+/// Writes the config in TOML format. Settings that aren't set are left out.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn s(value: &Option<String>) -> Option<Value> { value.clone().map(Value::String) }
+        fn b(value: &Option<bool>) -> Option<Value> { value.map(Value::Boolean) }
+        fn u(value: &Option<u32>) -> Option<Value> { value.map(|v| Value::Integer(v as i64)) }
+        fn p(value: &Option<PathBuf>) -> Option<Value> {
+            value.as_ref().map(|path| Value::String(path.to_string_lossy().into_owned()))
+        }
+
+        let tables = vec![
+            ("emulator", vec![
+                ("renderer".to_string(), s(&self.emulator.renderer)),
+                ("audio".to_string(), s(&self.emulator.audio)),
+                ("ram_init".to_string(), s(&self.emulator.ram_init)),
+                ("slow_motion".to_string(), self.emulator.slow_motion.map(Value::Float)),
+                ("region".to_string(), s(&self.emulator.region)),
+                ("save_state_on_exit".to_string(), b(&self.emulator.save_state_on_exit)),
+                ("stack_policy".to_string(), s(&self.emulator.stack_policy)),
+            ]),
+            ("video", vec![
+                ("scale".to_string(), u(&self.video.scale)),
+                ("filter".to_string(), s(&self.video.filter)),
+                ("shader".to_string(), s(&self.video.shader)),
+                ("aspect".to_string(), s(&self.video.aspect)),
+                ("integer_scaling".to_string(), b(&self.video.integer_scaling)),
+                ("color".to_string(), s(&self.video.color)),
+                ("show_fps".to_string(), b(&self.video.show_fps)),
+                ("osd".to_string(), b(&self.video.osd)),
+                ("widescreen".to_string(), u(&self.video.widescreen)),
+                ("widescreen_blacklisted".to_string(), b(&self.video.widescreen_blacklisted)),
+            ]),
+            ("audio", vec![
+                ("buffer_size".to_string(), u(&self.audio.buffer_size)),
+                ("periods".to_string(), u(&self.audio.periods)),
+            ]),
+            ("accuracy", vec![
+                ("profile".to_string(), s(&self.accuracy.profile)),
+                ("dram_refresh".to_string(), b(&self.accuracy.dram_refresh)),
+                ("dma_timing".to_string(), b(&self.accuracy.dma_timing)),
+                ("apu_sync".to_string(), u(&self.accuracy.apu_sync)),
+                ("ppu_sync".to_string(), u(&self.accuracy.ppu_sync)),
+                ("bg_raster".to_string(), b(&self.accuracy.bg_raster)),
+                ("alu_latency".to_string(), b(&self.accuracy.alu_latency)),
+                ("dot_ppu".to_string(), b(&self.accuracy.dot_ppu)),
+                ("cycle_cpu".to_string(), b(&self.accuracy.cycle_cpu)),
+            ]),
+            ("clock", vec![
+                ("preset".to_string(), s(&self.clock.preset)),
+                ("master_hz".to_string(), u(&self.clock.master_hz)),
+                ("apu_hz".to_string(), u(&self.clock.apu_hz)),
+            ]),
+            ("paths", vec![
+                ("data_dir".to_string(), p(&self.paths.data_dir)),
+            ]),
+            ("log", COMPONENTS.iter()
+                .map(|&component| (component.name().to_string(), s(self.log.level(component))))
+                .collect()),
+            ("hotkeys", self.hotkeys.iter()
+                .map(|&(ref key, ref action)| (key.clone(), Some(Value::String(action.clone()))))
+                .collect()),
+            ("macros", self.macros.iter()
+                .map(|&(slot, ref path)| (slot.to_string(), p(&Some(path.clone()))))
+                .collect()),
+        ];
+
+        let mut first = true;
+        for (table, entries) in tables {
+            let entries: Vec<_> = entries.into_iter()
+                .filter_map(|(key, value)| value.map(|value| (key, value)))
+                .collect();
+            if entries.is_empty() { continue }
+
+            if !first {
+                try!(writeln!(f));
+            }
+            first = false;
+            try!(writeln!(f, "[{}]", table));
+            for (key, value) in entries {
+                try!(toml::write_key(f, &key));
+                try!(writeln!(f, " = {}", value));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use accuracy::Accuracy;
+    use clock::ClockRates;
+
+    use breeze_backend::AudioBufferConfig;
+
+    use std::path::PathBuf;
+
+    const EXAMPLE: &'static str = r#"
+[emulator]
+renderer = "glium"
+ram_init = "random"
+slow_motion = 0.5
+save_state_on_exit = true
+
+[video]
+scale = 4
+filter = "scanlines"
+integer_scaling = false
+
+[audio]
+buffer_size = 512
+
+[accuracy]
+profile = "balanced"
+dot_ppu = false
+ppu_sync = 0
+
+[clock]
+apu_hz = 1026000
+
+[paths]
+data_dir = "/home/me/snes"
+
+[log]
+dma = "trace"
+
+[hotkeys]
+F1 = "play-macro-1"
+"Shift+F1" = "record-macro-1"
+
+[macros]
+3 = "/home/me/snes/combos/hadoken.txt"
+"#;
+
+    #[test]
+    fn roundtrip() {
+        let config = EXAMPLE.parse::<Config>().unwrap();
+        assert_eq!(config.emulator.renderer, Some("glium".to_string()));
+        assert_eq!(config.emulator.slow_motion, Some(0.5));
+        assert_eq!(config.video.scale, Some(4));
+        assert_eq!(config.video.integer_scaling, Some(false));
+        assert_eq!(config.accuracy.ppu_sync, Some(0));
+        assert_eq!(config.accuracy.dot_ppu, Some(false));
+        // The individual `ppu_sync` overrides the grouped `dot_ppu`
+        let accuracy = config.accuracy.to_accuracy().unwrap();
+        assert_eq!(accuracy.ppu_sync, 0);
+        assert!(!accuracy.bg_raster && accuracy.cycle_cpu());
+        assert_eq!(config.clock.apu_hz, Some(1026000));
+        assert_eq!(config.paths.data_dir, Some(PathBuf::from("/home/me/snes")));
+        assert_eq!(config.log.dma, Some("trace".to_string()));
+        assert_eq!(config.hotkeys, vec![
+            ("F1".to_string(), "play-macro-1".to_string()),
+            ("Shift+F1".to_string(), "record-macro-1".to_string()),
+        ]);
+        assert_eq!(config.macros, vec![(3, PathBuf::from("/home/me/snes/combos/hadoken.txt"))]);
+
+        let written = config.to_string();
+        assert_eq!(written.parse::<Config>(), Ok(config));
+        assert_eq!(Config::default().to_string(), "");
+    }
+
+    #[test]
+    fn missing_keys() {
+        let config = "[video]\nscale = 2\n".parse::<Config>().unwrap();
+        assert_eq!(config.video.scale, Some(2));
+        assert_eq!(config.video.filter, None);
+        assert_eq!(config.emulator, Default::default());
+        assert_eq!(config.accuracy.to_accuracy(), Ok(Accuracy::default()));
+        assert_eq!(config.audio.to_buffer_config(), Ok(AudioBufferConfig::default()));
+        assert_eq!(config.clock.to_clock_rates(), Ok(ClockRates::default()));
+        assert_eq!("".parse::<Config>(), Ok(Config::default()));
+    }
+
+    #[test]
+    fn grouped_accuracy_options() {
+        let mut config = Config::default();
+        config.accuracy.profile = Some("fast".to_string());
+        config.accuracy.apply_override("dot_ppu=on").unwrap();
+        config.accuracy.apply_override("cycle_cpu = true").unwrap();
+        assert_eq!((config.accuracy.dot_ppu, config.accuracy.cycle_cpu), (Some(true), Some(true)));
+        let accuracy = config.accuracy.to_accuracy().unwrap();
+        assert_eq!((accuracy.ppu_sync, accuracy.bg_raster), (0, true));
+        assert!(accuracy.dram_refresh && accuracy.alu_latency);
+        // Not part of either group
+        assert!(!accuracy.dma_timing);
+        assert_eq!(accuracy.apu_sync, 1024);
+
+        config.accuracy.apply_override("dot_ppu=off").unwrap();
+        assert!(!config.accuracy.to_accuracy().unwrap().dot_ppu());
+        assert!(config.accuracy.apply_override("dot_ppu=2").is_err());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!("[video]\nscael = 2\n".parse::<Config>().is_err());
+        assert!("scale = 2\n".parse::<Config>().is_err());
+        assert!("[video]\nscale = \"2\"\n".parse::<Config>().is_err());
+        assert!("[video]\nscale = -1\n".parse::<Config>().is_err());
+        assert!("[macros]\n0 = \"combo.txt\"\n".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let mut global = "[video]\nscale = 2\nfilter = \"nearest\"\n[hotkeys]\nF1 = \"pause\"\n"
+            .parse::<Config>().unwrap();
+        let game = "[video]\nscale = 3\n[hotkeys]\nf1 = \"reset\"\n".parse::<Config>().unwrap();
+        global.merge(&game);
+        assert_eq!(global.video.scale, Some(3));
+        assert_eq!(global.video.filter, Some("nearest".to_string()));
+        // Keys are matched case-insensitively
+        assert_eq!(global.hotkeys, vec![("f1".to_string(), "reset".to_string())]);
+    }
+}
@@ -0,0 +1,222 @@
+//! A reader and writer for the subset of TOML used by config files
+//!
+//! Supported are `[table]` headers, `key = value` pairs with bare (`a-z`, `A-Z`, `0-9`, `_`, `-`)
+//! or quoted keys, and string, integer, float and boolean values. Arrays, inline tables, dates
+//! and multi-line strings are not supported.
+
+use std::char;
+use std::fmt;
+
+/// A value in a TOML document
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    /// Describes the type of the value for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::String(ref s) => write_string(f, s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(x) if x.fract() == 0.0 && x.is_finite() => write!(f, "{:.1}", x),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Writes a key, quoting it if it can't be written as a bare key.
+pub fn write_key(f: &mut fmt::Formatter, key: &str) -> fmt::Result {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        write!(f, "{}", key)
+    } else {
+        write_string(f, key)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\t' => try!(write!(f, "\\t")),
+            '\r' => try!(write!(f, "\\r")),
+            c if c.is_control() => try!(write!(f, "\\u{:04X}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    write!(f, "\"")
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// A key-value pair and the table it's in (`""` for the top-level table)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+    /// Line number (starting at 1), for error messages
+    pub line: usize,
+}
+
+/// Parses a document into its entries, in order.
+pub fn parse(s: &str) -> Result<Vec<Entry>, String> {
+    let mut table = String::new();
+    let mut entries = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let lineno = i + 1;
+        let err = |msg: String| format!("line {}: {}", lineno, msg);
+
+        let mut p = Parser { s: line };
+        p.skip_ws();
+        if p.at_end() { continue }
+
+        if p.eat('[') {
+            p.skip_ws();
+            table = try!(p.key().map_err(&err));
+            p.skip_ws();
+            if !p.eat(']') {
+                return Err(err("expected `]`".to_string()));
+            }
+        } else {
+            let key = try!(p.key().map_err(&err));
+            p.skip_ws();
+            if !p.eat('=') {
+                return Err(err(format!("expected `=` after `{}`", key)));
+            }
+            p.skip_ws();
+            let value = try!(p.value().map_err(&err));
+            if entries.iter().any(|e: &Entry| e.table == table && e.key == key) {
+                return Err(err(format!("duplicate key `{}`", key)));
+            }
+            entries.push(Entry {
+                table: table.clone(),
+                key: key,
+                value: value,
+                line: lineno,
+            });
+        }
+
+        p.skip_ws();
+        if !p.at_end() {
+            return Err(err(format!("unexpected `{}`", p.s)));
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses a single line
+struct Parser<'a> {
+    /// The rest of the line
+    s: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// Skips whitespace and comments.
+    fn skip_ws(&mut self) {
+        self.s = self.s.trim_left();
+        if self.s.starts_with('#') {
+            self.s = "";
+        }
+    }
+
+    fn at_end(&self) -> bool { self.s.is_empty() }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.s.starts_with(c) {
+            self.s = &self.s[c.len_utf8()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if self.s.starts_with('"') {
+            return self.string();
+        }
+
+        let len = self.s.find(|c| !is_bare_key_char(c)).unwrap_or(self.s.len());
+        if len == 0 {
+            return Err("expected a key".to_string());
+        }
+        let key = &self.s[..len];
+        self.s = &self.s[len..];
+        Ok(key.to_string())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.s.starts_with('"') {
+            return self.string().map(Value::String);
+        }
+
+        let len = self.s.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(self.s.len());
+        let word = &self.s[..len];
+        self.s = &self.s[len..];
+        let digits = word.replace('_', "");
+        match word {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => if let Ok(i) = digits.parse() {
+                Ok(Value::Integer(i))
+            } else if let Ok(x) = digits.parse() {
+                Ok(Value::Float(x))
+            } else {
+                Err(format!("invalid value: `{}`", word))
+            },
+        }
+    }
+
+    /// Parses a basic (`"..."`) string.
+    fn string(&mut self) -> Result<String, String> {
+        assert!(self.eat('"'));
+        let mut result = String::new();
+        let mut chars = self.s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.s = &self.s[i + 1..];
+                    return Ok(result);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, 't')) => result.push('\t'),
+                    Some((_, 'r')) => result.push('\r'),
+                    Some((_, '"')) => result.push('"'),
+                    Some((_, '\\')) => result.push('\\'),
+                    Some((_, 'u')) => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => result.push(c),
+                            None => return Err(format!("invalid escape: \\u{}", hex)),
+                        }
+                    }
+                    Some((_, c)) => return Err(format!("invalid escape: \\{}", c)),
+                    None => break,
+                },
+                c => result.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
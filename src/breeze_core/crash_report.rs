@@ -0,0 +1,83 @@
+//! Crash reports
+//!
+//! When emulation panics (an illegal opcode, an unimplemented feature or a plain bug), frontends
+//! can catch the panic and write a crash report: The panic message, the CPU and PPU registers and
+//! everything the `CrashLog` recorded (the last instructions, memory accesses and I/O register
+//! writes). A save state of the machine is written next to it, so the crash can be reproduced
+//! from shortly before it happened.
+//!
+//! The state is taken *after* the panic, so the component that panicked may have been left in
+//! the middle of an update. It's usually still good enough to get close to the crash.
+
+use save::SaveStateFormat;
+use snes::Snes;
+
+use std::any::Any;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Extracts the message from a panic payload (as returned by `panic::catch_unwind`).
+pub fn panic_message(payload: &(Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "(no message)"
+    }
+}
+
+/// Writes a crash report describing the state of `snes`.
+pub fn write_report<W: Write>(snes: &Snes, message: &str, w: &mut W) -> io::Result<()> {
+    try!(writeln!(w, "breeze crash report"));
+    try!(writeln!(w, "panic: {}", message));
+    try!(writeln!(w, "ROM: {}", snes.peripherals().rom.get_title().unwrap_or("(untitled)")));
+    try!(writeln!(w, "frame: {}, master cycle: {}", snes.frame_count(), snes.master_cy()));
+    try!(writeln!(w));
+
+    let cpu = snes.cpu();
+    try!(writeln!(w, "CPU registers:"));
+    try!(writeln!(w, "pc:{:02X}:{:04X} a:{:04X} x:{:04X} y:{:04X} s:{:04X} d:{:04X} dbr:{:02X} \
+                      p:{:02X} emu:{}{}",
+        cpu.pbr, cpu.pc, cpu.a, cpu.x, cpu.y, cpu.s, cpu.d, cpu.dbr, cpu.status().0,
+        cpu.emulation() as u8, if cpu.waiting() { " (waiting for interrupt)" } else { "" }));
+    try!(writeln!(w));
+
+    let ppu = &snes.peripherals().ppu;
+    try!(writeln!(w, "PPU registers (V:{} H:{}):", ppu.v_counter(), ppu.h_counter()));
+    let registers = ppu.registers();
+    for line in registers.values().chunks(6) {
+        for (i, &(name, value)) in line.iter().enumerate() {
+            if i > 0 { try!(write!(w, " ")) }
+            try!(write!(w, "{:>9}:{:04X}", name, value));
+        }
+        try!(writeln!(w));
+    }
+    try!(writeln!(w));
+
+    match snes.crash_log() {
+        Some(crash_log) => try!(crash_log.dump(w)),
+        None => try!(writeln!(w, "(no crash log recorded)")),
+    }
+    Ok(())
+}
+
+/// Creates a save state of a machine that panicked. Returns `None` if that failed (or panicked
+/// again, since the machine may have been left in an inconsistent state).
+pub fn save_state(snes: &Snes) -> Option<Vec<u8>> {
+    let mut state = Vec::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        snes.create_save_state(SaveStateFormat::default(), &mut state)
+    }));
+    match result {
+        Ok(Ok(())) => Some(state),
+        Ok(Err(e)) => {
+            warn!("couldn't save the crashed machine's state: {}", e);
+            None
+        }
+        Err(_) => {
+            warn!("saving the crashed machine's state panicked");
+            None
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! A minimal JSON reader and writer for the debug protocol
+//!
+//! Numbers are stored as `f64`, which represents every value the protocol uses (addresses, bytes
+//! and counters well below 2^53) exactly. Objects keep the order of their members.
+
+use std::char;
+use std::fmt;
+
+/// A JSON value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Creates an empty object.
+    pub fn object() -> Self { Value::Object(Vec::new()) }
+
+    /// Adds a member to an object (builder-style). Does nothing if `self` isn't an object.
+    pub fn with<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        if let Value::Object(ref mut members) = self {
+            members.push((key.to_string(), value.into()));
+        }
+        self
+    }
+
+    /// Looks up a member of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => {
+                members.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an unsigned integer if it is a non-negative whole number.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < 9007199254740992.0 => {
+                Some(n as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Parses a JSON document.
+    pub fn parse(s: &str) -> Result<Value, String> {
+        let mut parser = Parser { s: s, pos: 0 };
+        let value = try!(parser.value());
+        parser.skip_whitespace();
+        if parser.pos != s.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self { Value::Bool(b) }
+}
+
+macro_rules! number_from {
+    ( $($t:ty),* ) => {
+        $( impl From<$t> for Value {
+            fn from(n: $t) -> Self { Value::Number(n as f64) }
+        } )*
+    };
+}
+
+number_from!(u8, u16, u32, u64, usize, i32, f64);
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Self { Value::String(s.to_string()) }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self { Value::String(s) }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(opt: Option<T>) -> Self {
+        match opt {
+            Some(value) => value.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Writes the value as compact JSON (without any newlines, so it can be sent as a single line).
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if !n.is_finite() => f.write_str("null"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(ref s) => write_string(f, s),
+            Value::Array(ref values) => {
+                try!(f.write_str("["));
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { try!(f.write_str(",")) }
+                    try!(write!(f, "{}", value));
+                }
+                f.write_str("]")
+            }
+            Value::Object(ref members) => {
+                try!(f.write_str("{"));
+                for (i, &(ref key, ref value)) in members.iter().enumerate() {
+                    if i > 0 { try!(f.write_str(",")) }
+                    try!(write_string(f, key));
+                    try!(write!(f, ":{}", value));
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(f.write_str("\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(f.write_str("\\\"")),
+            '\\' => try!(f.write_str("\\\\")),
+            '\n' => try!(f.write_str("\\n")),
+            '\r' => try!(f.write_str("\\r")),
+            '\t' => try!(f.write_str("\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at offset {}", msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> { self.s[self.pos..].chars().next() }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() { break }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if self.s[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("invalid value"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(try!(self.value()));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => { self.pos += 1; return Ok(Value::Array(values)) }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some('"') { return Err(self.error("expected key")) }
+                    let key = try!(self.string());
+                    try!(self.expect(':'));
+                    members.push((key, try!(self.value())));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => { self.pos += 1; return Ok(Value::Object(members)) }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_digit(10) => self.number(),
+            Some(_) => Err(self.error("invalid value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            match c {
+                '0' ... '9' | '-' | '+' | '.' | 'e' | 'E' => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.s[start..self.pos].parse().map(Value::Number).map_err(|_| {
+            format!("invalid number at offset {}", start)
+        })
+    }
+
+    /// Parses a string, starting at the opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = match self.peek() {
+                        Some(c) => c,
+                        None => return Err(self.error("unterminated string")),
+                    };
+                    self.pos += escape.len_utf8();
+                    match escape {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        '/' => s.push('/'),
+                        'b' => s.push('\x08'),
+                        'f' => s.push('\x0c'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => s.push(try!(self.unicode_escape())),
+                        _ => return Err(self.error("invalid escape sequence")),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// Parses the hex digits of a `\u` escape, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = try!(self.hex4());
+        let code = if high >= 0xd800 && high < 0xdc00 {
+            if !self.s[self.pos..].starts_with("\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let low = try!(self.hex4());
+            if low < 0xdc00 || low >= 0xe000 { return Err(self.error("unpaired surrogate")) }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid character"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = match self.s.get(self.pos..self.pos + 4) {
+            Some(digits) => digits,
+            None => return Err(self.error("invalid \\u escape")),
+        };
+        // `from_str_radix` alone would also accept a leading sign
+        if !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(self.error("invalid \\u escape"));
+        }
+        let code = u32::from_str_radix(digits, 16).unwrap();
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    fn parse_err(s: &str) -> String {
+        Value::parse(s).unwrap_err()
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(Value::parse(" null "), Ok(Value::Null));
+        assert_eq!(Value::parse("true"), Ok(Value::Bool(true)));
+        assert_eq!(Value::parse("[]"), Ok(Value::Array(vec![])));
+        assert_eq!(Value::parse("{ }"), Ok(Value::object()));
+        let value = Value::parse(r#"{"id": 1, "cmd": "read", "data": [0, false, null, {}]}"#)
+            .unwrap();
+        assert_eq!(value.get("id").and_then(Value::as_u64), Some(1));
+        assert_eq!(value.get("cmd").and_then(Value::as_str), Some("read"));
+        assert_eq!(value.get("data").and_then(Value::as_array).map(|data| data.len()), Some(4));
+        assert_eq!(value.get("len"), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Value::parse("0"), Ok(Value::Number(0.0)));
+        assert_eq!(Value::parse("-12.5"), Ok(Value::Number(-12.5)));
+        assert_eq!(Value::parse("1e3"), Ok(Value::Number(1000.0)));
+        assert_eq!(Value::parse("2.5E-1"), Ok(Value::Number(0.25)));
+        assert_eq!(Value::parse("16777215").unwrap().as_u64(), Some(0xffffff));
+        assert_eq!(Value::parse("1.5").unwrap().as_u64(), None);
+        assert_eq!(Value::parse("-1").unwrap().as_u64(), None);
+
+        assert_eq!(Value::Number(255.0).to_string(), "255");
+        assert_eq!(Value::Number(-0.5).to_string(), "-0.5");
+        assert_eq!(Value::Number(::std::f64::NAN).to_string(), "null");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(Value::parse(r#""a\"b\\c\/d\b\f\n\r\t""#),
+                   Ok(Value::String("a\"b\\c/d\x08\x0c\n\r\t".to_string())));
+        assert_eq!(Value::parse(r#""\u0041\u00e9\u20AC""#), Ok(Value::from("Aé€")));
+        // Surrogate pair
+        assert_eq!(Value::parse(r#""\ud83d\ude00""#), Ok(Value::from("\u{1f600}")));
+        assert_eq!(Value::parse("\"ü\""), Ok(Value::String("ü".to_string())));
+
+        let s = Value::from("quote \" backslash \\ newline \n tab \t bell \x07 é");
+        assert_eq!(s.to_string(), r#""quote \" backslash \\ newline \n tab \t bell \u0007 é""#);
+        assert_eq!(Value::parse(&s.to_string()), Ok(s));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_err(""), "unexpected end of input at offset 0");
+        assert_eq!(parse_err("nul"), "invalid value at offset 0");
+        assert_eq!(parse_err("[1 2]"), "expected ',' or ']' at offset 3");
+        assert_eq!(parse_err("{\"a\" 1}"), "expected ':' at offset 5");
+        assert_eq!(parse_err("{1: 2}"), "expected key at offset 1");
+        assert_eq!(parse_err("{\"a\": 1,}"), "expected key at offset 8");
+        assert_eq!(parse_err("[1] x"), "trailing characters at offset 4");
+        assert_eq!(parse_err("\"abc"), "unterminated string at offset 4");
+        assert_eq!(parse_err(r#""\x""#), "invalid escape sequence at offset 3");
+        assert_eq!(parse_err(r#""\u12""#), "invalid \\u escape at offset 3");
+        assert_eq!(parse_err(r#""\u+041""#), "invalid \\u escape at offset 3");
+        assert_eq!(parse_err(r#""\ud83d""#), "unpaired surrogate at offset 7");
+        assert_eq!(parse_err(r#""\ud83d\u0041""#), "unpaired surrogate at offset 13");
+        assert_eq!(parse_err("-"), "invalid number at offset 0");
+        assert_eq!(parse_err("[1-2]"), "invalid number at offset 1");
+    }
+
+    #[test]
+    fn write() {
+        let value = Value::object()
+            .with("id", 7u32)
+            .with("result", Value::object().with("data", vec![0u8, 18, 255]))
+            .with("error", None::<String>)
+            .with("ok", true);
+        let json = value.to_string();
+        assert_eq!(json, r#"{"id":7,"result":{"data":[0,18,255]},"error":null,"ok":true}"#);
+        assert_eq!(Value::parse(&json), Ok(value));
+    }
+}
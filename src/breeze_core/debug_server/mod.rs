@@ -0,0 +1,567 @@
+//! Remote debug server
+//!
+//! The `DebugServer` exposes the `Debugger` over TCP, so debug UIs (in a browser, or integrated
+//! into an editor) can control the emulator without linking against this crate. Clients can
+//! either speak WebSocket (browsers) or send plain newline-delimited messages (the first bytes of
+//! a connection decide). Every message is a JSON object.
+//!
+//! Requests have a `cmd` member and an optional `id`, which is copied into the response:
+//!
+//! ```text
+//! -> {"id": 1, "cmd": "read", "addr": "7E:0010", "len": 4}
+//! <- {"id": 1, "result": {"addr": 8257552, "data": [0, 18, 52, 255]}}
+//! -> {"id": 2, "cmd": "break", "addr": "NmiHandler", "condition": "A == 0x42"}
+//! <- {"id": 2, "error": "unknown label: NmiHandler"}
+//! ```
+//!
+//! Commands:
+//!
+//! * `regs`: CPU registers, PPU counters, master cycle and frame count
+//! * `read` (`addr`, `len` = 1): memory contents (`null` for bytes with read side effects)
+//! * `write` (`addr`, `data`): writes bytes to RAM or ROM
+//! * `break` (`addr`, `condition`), `delete` (`addr`), `breakpoints`
+//! * `step` (`count` = 1): executes instructions, returns why execution stopped
+//! * `continue`, `pause`: run or stop emulation (a `stopped` event is sent when it stops)
+//! * `subscribe`, `unsubscribe` (`stream`: `frames` or `events`)
+//! * `command` (`line`): runs a debugger REPL command and returns its output as text
+//! * `quit`: exits the emulator
+//!
+//! Addresses can be numbers or strings in any format the debugger accepts. Events are sent as
+//! objects with an `event` member: `frame` (frame number and hash), `events` (the register writes
+//! of the last frame, see `EventLog`) and `stopped`.
+
+pub mod json;
+pub mod websocket;
+
+use self::json::Value;
+use debugger::{Debugger, StopReason};
+use events::{EventKind, EventLog};
+use expr::Expr;
+use snes::Snes;
+
+use breeze_backend::{BackendAction, BackendResult};
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::thread;
+use std::time::Duration;
+
+/// Clients sending more than this without completing a message are disconnected.
+const MAX_PENDING_INPUT: usize = 1024 * 1024;
+
+/// Time to sleep between polls while the emulator is paused
+const IDLE_POLL_MS: u64 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Protocol {
+    /// Not enough data received yet to tell
+    Unknown,
+    /// Newline-delimited JSON
+    Lines,
+    WebSocket,
+}
+
+struct Client {
+    stream: TcpStream,
+    protocol: Protocol,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    frames: bool,
+    events: bool,
+    closed: bool,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> Self {
+        Client {
+            stream: stream,
+            protocol: Protocol::Unknown,
+            input: Vec::new(),
+            output: Vec::new(),
+            frames: false,
+            events: false,
+            closed: false,
+        }
+    }
+
+    /// Reads everything available and returns the complete messages received.
+    fn receive(&mut self) -> Vec<String> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => { self.closed = true; break }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("debug client disconnected: {}", e);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+
+        if self.protocol == Protocol::Unknown {
+            if self.input.len() < 4 && !self.closed { return Vec::new() }
+            if self.input.starts_with(b"GET ") {
+                let end = match self.input.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(end) => end + 4,
+                    None => return Vec::new(),
+                };
+                let response = str::from_utf8(&self.input[..end]).ok()
+                    .and_then(websocket::handshake_response);
+                match response {
+                    Some(response) => {
+                        self.output.extend_from_slice(response.as_bytes());
+                        self.input.drain(..end);
+                        self.protocol = Protocol::WebSocket;
+                    }
+                    None => {
+                        self.output.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                        self.closed = true;
+                        return Vec::new();
+                    }
+                }
+            } else {
+                self.protocol = Protocol::Lines;
+            }
+        }
+
+        let mut messages = Vec::new();
+        match self.protocol {
+            Protocol::Unknown => {}
+            Protocol::Lines => {
+                while let Some(newline) = self.input.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = self.input.drain(..newline + 1).collect();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    if !line.is_empty() { messages.push(line) }
+                }
+            }
+            Protocol::WebSocket => {
+                while let Some((frame, len)) = websocket::decode_frame(&self.input) {
+                    self.input.drain(..len);
+                    match frame.opcode {
+                        websocket::OP_TEXT | websocket::OP_BINARY if frame.fin => {
+                            messages.push(String::from_utf8_lossy(&frame.payload).into_owned());
+                        }
+                        websocket::OP_PING => {
+                            let pong = websocket::encode_frame(websocket::OP_PONG, &frame.payload);
+                            self.output.extend_from_slice(&pong);
+                        }
+                        websocket::OP_PONG => {}
+                        websocket::OP_CLOSE => {
+                            let close = websocket::encode_frame(websocket::OP_CLOSE, &[]);
+                            self.output.extend_from_slice(&close);
+                            self.closed = true;
+                        }
+                        _ => {
+                            // Fragmented messages aren't supported
+                            debug!("debug client sent unsupported frame (opcode {}, fin {})",
+                                frame.opcode, frame.fin);
+                            self.closed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.input.len() > MAX_PENDING_INPUT {
+            debug!("debug client sent an oversized message");
+            self.closed = true;
+        }
+        messages
+    }
+
+    /// Queues a message for sending.
+    fn send(&mut self, message: &Value) {
+        let text = message.to_string();
+        match self.protocol {
+            Protocol::WebSocket => {
+                let frame = websocket::encode_frame(websocket::OP_TEXT, text.as_bytes());
+                self.output.extend_from_slice(&frame);
+            }
+            _ => {
+                self.output.extend_from_slice(text.as_bytes());
+                self.output.push(b'\n');
+            }
+        }
+    }
+
+    /// Sends as much queued output as possible without blocking.
+    fn flush(&mut self) {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => { self.closed = true; break }
+                Ok(n) => { self.output.drain(..n); }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("debug client disconnected: {}", e);
+                    self.closed = true;
+                    self.output.clear();
+                }
+            }
+        }
+    }
+}
+
+/// What the server should do after handling a request
+enum Control {
+    Continue,
+    Quit,
+}
+
+/// Serves the debug protocol to any number of clients.
+pub struct DebugServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    /// Whether emulation is running (as opposed to being stopped in the debugger)
+    running: bool,
+}
+
+impl DebugServer {
+    /// Starts listening on the given address (eg. `127.0.0.1:6502`).
+    ///
+    /// Note that the protocol has no authentication: Anyone who can connect can read and write
+    /// emulator memory, so don't listen on public interfaces.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = try!(TcpListener::bind(addr));
+        try!(listener.set_nonblocking(true));
+        Ok(DebugServer {
+            listener: listener,
+            clients: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves clients until one of them sends `quit` or the renderer requests an exit. Emulation
+    /// starts out paused.
+    ///
+    /// `render` is called for every completed frame, just like in `Snes::render_frame`.
+    pub fn run<F>(&mut self, debugger: &mut Debugger, snes: &mut Snes, mut render: F)
+                  -> BackendResult<()>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        if snes.cpu().call_stack().is_none() {
+            // Needed for the `bt` command
+            snes.cpu_mut().set_call_stack_tracking(true);
+        }
+        info!("debug server listening on {}", try!(self.listener.local_addr()));
+
+        loop {
+            self.accept();
+
+            for i in 0..self.clients.len() {
+                for message in self.clients[i].receive() {
+                    let (response, control) = self.handle(i, &message, debugger, snes,
+                                                          &mut render);
+                    self.clients[i].send(&response);
+                    if let Control::Quit = control {
+                        self.flush_all();
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.running {
+                match try!(debugger.cont(snes, Some(1), &mut render)) {
+                    StopReason::FrameLimit => self.publish_frame(snes),
+                    StopReason::Exit => {
+                        self.flush_all();
+                        return Ok(());
+                    }
+                    reason => {
+                        self.running = false;
+                        let (name, addr) = describe_stop(reason);
+                        let event = stopped_event(snes, name, addr);
+                        self.broadcast(&event);
+                    }
+                }
+            } else {
+                thread::sleep(Duration::from_millis(IDLE_POLL_MS));
+            }
+
+            self.flush_all();
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("couldn't set up debug connection from {}: {}", addr, e);
+                        continue;
+                    }
+                    // Responses are small, don't let Nagle's algorithm delay them
+                    let _ = stream.set_nodelay(true);
+                    info!("debug client connected from {}", addr);
+                    self.clients.push(Client::new(stream));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to accept debug connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn flush_all(&mut self) {
+        for client in &mut self.clients {
+            client.flush();
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+
+    fn broadcast(&mut self, message: &Value) {
+        for client in &mut self.clients {
+            client.send(message);
+        }
+    }
+
+    /// Sends the frame and event streams to subscribed clients after a frame was completed.
+    fn publish_frame(&mut self, snes: &Snes) {
+        let frame = Value::object()
+            .with("event", "frame")
+            .with("frame", snes.frame_count())
+            .with("hash", format!("{:016x}", snes.frame_hash()));
+        let events = snes.event_log().map(|log| {
+            let events: Vec<Value> = log.last_frame().iter().map(|event| {
+                Value::object()
+                    .with("v", event.v)
+                    .with("h", event.h)
+                    .with("addr", event.addr)
+                    .with("value", event.value)
+                    .with("kind", kind_name(event.kind))
+            }).collect();
+            Value::object()
+                .with("event", "events")
+                .with("frame", snes.frame_count())
+                .with("events", events)
+        });
+
+        for client in &mut self.clients {
+            if client.frames { client.send(&frame) }
+            if client.events {
+                if let Some(ref events) = events { client.send(events) }
+            }
+        }
+    }
+
+    /// Handles a request from client `index` and builds the response.
+    fn handle<F>(&mut self, index: usize, message: &str, debugger: &mut Debugger, snes: &mut Snes,
+                 render: &mut F) -> (Value, Control)
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        let request = match Value::parse(message) {
+            Ok(request) => request,
+            Err(e) => {
+                return (Value::object().with("error", format!("invalid JSON: {}", e)),
+                        Control::Continue);
+            }
+        };
+        let mut response = Value::object();
+        if let Some(id) = request.get("id") {
+            response = response.with("id", id.clone());
+        }
+
+        let mut control = Control::Continue;
+        let result = self.execute(index, &request, debugger, snes, render, &mut control);
+        let response = match result {
+            Ok(result) => response.with("result", result),
+            Err(msg) => response.with("error", msg),
+        };
+        (response, control)
+    }
+
+    fn execute<F>(&mut self, index: usize, request: &Value, debugger: &mut Debugger,
+                  snes: &mut Snes, render: &mut F, control: &mut Control)
+                  -> Result<Value, String>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        let cmd = try!(request.get("cmd").and_then(Value::as_str)
+            .ok_or_else(|| "missing `cmd`".to_string()));
+
+        match cmd {
+            "regs" => Ok(registers(snes)),
+            "read" => {
+                let dbr = snes.cpu().dbr;
+                let addr = try!(address(request, debugger, dbr));
+                let len = try!(optional_uint(request, "len", 1));
+                if len > 0x10000 { return Err("`len` must be at most 65536".to_string()) }
+                let data = debugger.read_memory(snes, addr, len as usize);
+                Ok(Value::object().with("addr", addr).with("data", data))
+            }
+            "write" => {
+                let dbr = snes.cpu().dbr;
+                let addr = try!(address(request, debugger, dbr));
+                let data = try!(request.get("data").and_then(Value::as_array)
+                    .ok_or_else(|| "missing `data` array".to_string()));
+                let mut bytes = Vec::with_capacity(data.len());
+                for value in data {
+                    match value.as_u64() {
+                        Some(byte) if byte <= 0xff => bytes.push(byte as u8),
+                        _ => return Err(format!("invalid byte: {}", value)),
+                    }
+                }
+                Ok(Value::object().with("ok", debugger.write_memory(snes, addr, &bytes)))
+            }
+            "break" => {
+                let pbr = snes.cpu().pbr;
+                let addr = try!(address(request, debugger, pbr));
+                let condition = match request.get("condition").and_then(Value::as_str) {
+                    Some(condition) => Some(try!(Expr::parse(condition, debugger.symbols()))),
+                    None => None,
+                };
+                let added = debugger.add_breakpoint(addr);
+                debugger.set_condition(addr, condition);
+                Ok(Value::object().with("addr", addr).with("added", added))
+            }
+            "delete" => {
+                let pbr = snes.cpu().pbr;
+                let addr = try!(address(request, debugger, pbr));
+                Ok(Value::object().with("ok", debugger.remove_breakpoint(addr)))
+            }
+            "breakpoints" => {
+                let breakpoints: Vec<Value> = debugger.breakpoints().iter().map(|&addr| {
+                    Value::object()
+                        .with("addr", addr)
+                        .with("condition", debugger.condition(addr).map(|c| c.to_string()))
+                }).collect();
+                Ok(Value::from(breakpoints))
+            }
+            "step" => {
+                let count = try!(optional_uint(request, "count", 1));
+                self.running = false;
+                let reason = try!(debugger.step(snes, count as u32, render)
+                    .map_err(|e| e.to_string()));
+                if reason == StopReason::Exit { *control = Control::Quit }
+                Ok(stop_info(snes, reason))
+            }
+            "continue" => {
+                self.running = true;
+                Ok(Value::object())
+            }
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    let event = stopped_event(snes, "pause", None);
+                    self.broadcast(&event);
+                }
+                Ok(Value::object())
+            }
+            "subscribe" | "unsubscribe" => {
+                let enable = cmd == "subscribe";
+                match request.get("stream").and_then(Value::as_str) {
+                    Some("frames") => self.clients[index].frames = enable,
+                    Some("events") => {
+                        self.clients[index].events = enable;
+                        if enable && snes.event_log().is_none() {
+                            snes.set_event_log(Some(EventLog::new()));
+                        }
+                    }
+                    _ => return Err("`stream` must be `frames` or `events`".to_string()),
+                }
+                Ok(Value::object())
+            }
+            "command" => {
+                let line = try!(request.get("line").and_then(Value::as_str)
+                    .ok_or_else(|| "missing `line`".to_string()));
+                let mut output = Vec::new();
+                let quit = try!(debugger.execute(snes, line, render, &mut output)
+                    .map_err(|e| e.to_string()));
+                if quit { *control = Control::Quit }
+                Ok(Value::object().with("output", String::from_utf8_lossy(&output).into_owned()))
+            }
+            "quit" => {
+                *control = Control::Quit;
+                Ok(Value::object())
+            }
+            _ => Err(format!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+fn registers(snes: &Snes) -> Value {
+    let cpu = snes.cpu();
+    let ppu = &snes.peripherals().ppu;
+    Value::object()
+        .with("pc", cpu.pc)
+        .with("pbr", cpu.pbr)
+        .with("a", cpu.a)
+        .with("x", cpu.x)
+        .with("y", cpu.y)
+        .with("s", cpu.s)
+        .with("d", cpu.d)
+        .with("dbr", cpu.dbr)
+        .with("p", cpu.status().0)
+        .with("emulation", cpu.emulation())
+        .with("waiting", cpu.waiting())
+        .with("v", ppu.v_counter())
+        .with("h", ppu.h_counter())
+        .with("master_cycle", snes.master_cy())
+        .with("frame", snes.frame_count())
+}
+
+/// Returns the protocol name of a stop reason and the address it refers to.
+fn describe_stop(reason: StopReason) -> (&'static str, Option<u32>) {
+    match reason {
+        StopReason::Done => ("done", None),
+        StopReason::Breakpoint(addr) => ("breakpoint", Some(addr)),
+        StopReason::FrameLimit => ("frame_limit", None),
+        StopReason::StackWrap(addr) => ("stack_wrap", Some(addr)),
+        StopReason::Exit => ("exit", None),
+    }
+}
+
+/// Describes why execution stopped, and where.
+fn stop_info(snes: &Snes, reason: StopReason) -> Value {
+    let (name, addr) = describe_stop(reason);
+    let cpu = snes.cpu();
+    Value::object()
+        .with("reason", name)
+        .with("addr", addr)
+        .with("pc", (cpu.pbr as u32) << 16 | cpu.pc as u32)
+}
+
+fn stopped_event(snes: &Snes, reason: &str, addr: Option<u32>) -> Value {
+    let cpu = snes.cpu();
+    Value::object()
+        .with("event", "stopped")
+        .with("reason", reason)
+        .with("addr", addr)
+        .with("pc", (cpu.pbr as u32) << 16 | cpu.pc as u32)
+}
+
+fn kind_name(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Ppu => "ppu",
+        EventKind::Apu => "apu",
+        EventKind::CpuIo => "cpu_io",
+        EventKind::Dma => "dma",
+    }
+}
+
+/// Reads the `addr` member of a request, which is either a 24-bit number or a string in any
+/// format the debugger accepts.
+fn address(request: &Value, debugger: &Debugger, default_bank: u8) -> Result<u32, String> {
+    match request.get("addr") {
+        Some(&Value::String(ref s)) => debugger.parse_address(s, default_bank),
+        Some(value) => match value.as_u64() {
+            Some(addr) if addr <= 0xffffff => Ok(addr as u32),
+            _ => Err(format!("invalid address: {}", value)),
+        },
+        None => Err("missing `addr`".to_string()),
+    }
+}
+
+fn optional_uint(request: &Value, key: &str, default: u64) -> Result<u64, String> {
+    match request.get(key) {
+        None | Some(&Value::Null) => Ok(default),
+        Some(value) => value.as_u64().ok_or_else(|| format!("invalid `{}`: {}", key, value)),
+    }
+}
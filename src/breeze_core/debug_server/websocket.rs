@@ -0,0 +1,306 @@
+//! The server side of the WebSocket protocol (RFC 6455), as far as the debug server needs it
+//!
+//! Only the opening handshake and unfragmented frames are handled. Fragmented messages and
+//! extensions (like compression) are never negotiated by browsers unless the server agrees to
+//! them, so clients won't send them.
+
+/// Appended to the client's key before hashing it, as specified by RFC 6455
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xA;
+
+/// Builds the response to an HTTP upgrade request. Returns `None` if `request` (the complete
+/// request header) isn't a WebSocket handshake.
+pub fn handshake_response(request: &str) -> Option<String> {
+    let key = request.lines().skip(1).filter_map(|line| {
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => return None,
+        };
+        if line[..colon].trim().eq_ignore_ascii_case("sec-websocket-key") {
+            Some(line[colon + 1..].trim())
+        } else {
+            None
+        }
+    }).next();
+
+    key.map(|key| format!("HTTP/1.1 101 Switching Protocols\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key)))
+}
+
+/// Computes the `Sec-WebSocket-Accept` header value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    base64(&sha1(&input))
+}
+
+/// A received frame
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    /// The unmasked payload
+    pub payload: Vec<u8>,
+}
+
+/// Decodes a frame from the start of `buf`. Returns the frame and the number of bytes it
+/// occupied, or `None` if `buf` doesn't contain a complete frame yet.
+pub fn decode_frame(buf: &[u8]) -> Option<(Frame, usize)> {
+    if buf.len() < 2 { return None }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 { return None }
+            ((buf[2] as u64) << 8 | buf[3] as u64, 4)
+        }
+        127 => {
+            if buf.len() < 10 { return None }
+            (buf[2..10].iter().fold(0, |len, &b| len << 8 | b as u64), 10)
+        }
+        len => (len as u64, 2),
+    };
+    let mask = if masked {
+        if buf.len() < pos + 4 { return None }
+        let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+        pos += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    if ((buf.len() - pos) as u64) < len { return None }
+    let end = pos + len as usize;
+
+    let mut payload = buf[pos..end].to_vec();
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    Some((Frame { fin: fin, opcode: opcode, payload: payload }, end))
+}
+
+/// Encodes an unmasked, unfragmented frame (servers must not mask their frames).
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0 ... 125 => frame.push(len as u8),
+        len @ 126 ... 0xffff => {
+            frame.push(126);
+            frame.push((len >> 8) as u8);
+            frame.push(len as u8);
+        }
+        len => {
+            frame.push(127);
+            for shift in (0..8).rev() {
+                frame.push((len as u64 >> (shift * 8)) as u8);
+            }
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// SHA-1, which the handshake needs. It's not used for anything security-related.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    let bits = data.len() as u64 * 8;
+    for shift in (0..8).rev() {
+        msg.push((bits >> (shift * 8)) as u8);
+    }
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (chunk[i * 4] as u32) << 24 | (chunk[i * 4 + 1] as u32) << 16 |
+                   (chunk[i * 4 + 2] as u32) << 8 | chunk[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0 ... 19 => ((b & c) | (!b & d), 0x5A827999),
+                20 ... 39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40 ... 59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &'static [u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 |
+                (*chunk.get(1).unwrap_or(&0) as u32) << 8 |
+                *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, base64, decode_frame, encode_frame, handshake_response, sha1, Frame,
+                OP_BINARY, OP_TEXT};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // 56 bytes: The length no longer fits into the first block
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn handshake() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = "GET /debug HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nsec-websocket-key:  dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        let response = handshake_response(request).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert_eq!(handshake_response("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn decode_masked() {
+        // Examples from RFC 6455, section 5.7
+        let unmasked = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let hello = Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() };
+        assert_eq!(decode_frame(&unmasked), Some((hello, 7)));
+        let hello = Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() };
+        assert_eq!(decode_frame(&masked), Some((hello, 11)));
+
+        // The first fragment of a message
+        let (frame, len) = decode_frame(&[0x01, 0x03, 0x48, 0x65, 0x6c, 0x00]).unwrap();
+        assert_eq!((frame.fin, frame.opcode, len), (false, OP_TEXT, 5));
+        assert_eq!(frame.payload, b"Hel");
+    }
+
+    #[test]
+    fn decode_extended_length() {
+        let payload: Vec<u8> = (0..70000).map(|i| i as u8).collect();
+
+        // 16-bit length
+        let mut buf = vec![0x82, 0xfe, 0x01, 0x00, 1, 2, 3, 4];
+        buf.extend(payload[..256].iter().enumerate().map(|(i, &b)| b ^ [1, 2, 3, 4][i % 4]));
+        let (frame, len) = decode_frame(&buf).unwrap();
+        assert_eq!((frame.opcode, len), (OP_BINARY, buf.len()));
+        assert!(frame.payload == &payload[..256]);
+
+        // 64-bit length
+        let mut buf = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70];
+        buf.extend_from_slice(&payload);
+        let (frame, len) = decode_frame(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert!(frame.payload == payload);
+    }
+
+    #[test]
+    fn decode_partial() {
+        let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let mut extended = vec![0x82, 0x7e, 0x01, 0x00];
+        extended.extend_from_slice(&[0; 256]);
+        let mut long = vec![0x82, 0x7f, 0, 0, 0, 0, 0, 0, 0x01, 0x00];
+        long.extend_from_slice(&[0; 256]);
+        for buf in &[&masked[..], &extended, &long] {
+            for len in 0..buf.len() {
+                assert_eq!(decode_frame(&buf[..len]), None);
+            }
+            assert!(decode_frame(buf).is_some());
+        }
+
+        // Data after the frame belongs to the next one
+        let mut two = masked.to_vec();
+        two.extend_from_slice(&masked[..3]);
+        assert_eq!(decode_frame(&two).unwrap().1, masked.len());
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        for &len in &[0, 1, 125, 126, 0xffff, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let encoded = encode_frame(OP_BINARY, &payload);
+            let header_len = match len {
+                0 ... 125 => 2,
+                126 ... 0xffff => 4,
+                _ => 10,
+            };
+            assert_eq!(encoded.len(), header_len + len);
+            let (frame, used) = decode_frame(&encoded).unwrap();
+            assert_eq!(used, encoded.len());
+            assert!(frame == Frame { fin: true, opcode: OP_BINARY, payload: payload });
+        }
+    }
+}
@@ -0,0 +1,1141 @@
+//! Interactive debugger
+//!
+//! The `Debugger` provides command-style operations (stepping, breakpoints, disassembly, register
+//! and memory access) on top of `Snes::step`. `Debugger::run_repl` wraps them in a line-oriented
+//! command interface for terminal users.
+//!
+//! If a symbol file is loaded (see `Debugger::set_symbols`), addresses can also be given as labels
+//! and the disassembly shows labels and jump targets.
+
+use events::{EventKind, EventLog};
+use expr::Expr;
+use ppu::{PpuRegisters, RegisterChange, RegisterHistory};
+use ram_search::{RamSearch, SearchFilter};
+use rom::Rom;
+use snes::{Peripherals, Snes};
+use symbols::SymbolTable;
+use watch::{Watch, WatchMode};
+
+use spc700::{Envelope, VOICES};
+use spc700::port_log::{Accessor, PortLog};
+use wdc65816::callstack::CallKind;
+use wdc65816::disasm::Instruction;
+use wdc65816::opstats::OpcodeStats;
+use breeze_backend::{BackendAction, BackendResult};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Why the debugger stopped executing code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// All requested instructions were executed
+    Done,
+    /// A breakpoint at the contained (24-bit) address was hit
+    Breakpoint(u32),
+    /// The requested number of frames was emulated without hitting a breakpoint
+    FrameLimit,
+    /// The instruction at the contained address made the stack pointer wrap around (only with
+    /// `StackPolicy::Break`)
+    StackWrap(u32),
+    /// The renderer requested an exit
+    Exit,
+}
+
+/// Debugger state: Breakpoints, the RAM search, symbols and the last command entered into the
+/// REPL.
+#[derive(Default)]
+pub struct Debugger {
+    /// Breakpoint addresses in the form `$BBAAAA`, sorted
+    breakpoints: Vec<u32>,
+    /// Conditions of conditional breakpoints
+    conditions: HashMap<u32, Expr>,
+    /// Running cheat search, if any
+    ram_search: Option<RamSearch>,
+    /// Labels used for parsing and displaying addresses
+    symbols: SymbolTable,
+    /// Expressions sampled while executing code
+    watches: Vec<Watch>,
+    /// Repeated when an empty line is entered
+    last_command: String,
+    /// ROM file reloaded by the `reload` command
+    rom_path: Option<PathBuf>,
+}
+
+impl Debugger {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn symbols(&self) -> &SymbolTable { &self.symbols }
+
+    /// Replaces the symbol table used to parse and display addresses.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Sets the ROM file the `reload` command loads if no file is given.
+    pub fn set_rom_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.rom_path = Some(path.into());
+    }
+
+    /// Adds a breakpoint at a 24-bit address. Returns `false` if there already is one.
+    pub fn add_breakpoint(&mut self, addr: u32) -> bool {
+        match self.breakpoints.binary_search(&addr) {
+            Ok(_) => false,
+            Err(i) => {
+                self.breakpoints.insert(i, addr);
+                true
+            }
+        }
+    }
+
+    /// Removes the breakpoint at the given address. Returns `false` if there was none.
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        match self.breakpoints.binary_search(&addr) {
+            Ok(i) => {
+                self.breakpoints.remove(i);
+                self.conditions.remove(&addr);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns all breakpoint addresses in ascending order.
+    pub fn breakpoints(&self) -> &[u32] { &self.breakpoints }
+
+    /// Makes the breakpoint at `addr` conditional: It only triggers if `condition` evaluates to a
+    /// non-zero value. Passing `None` makes it unconditional again. Returns `false` if there is no
+    /// breakpoint at `addr`.
+    pub fn set_condition(&mut self, addr: u32, condition: Option<Expr>) -> bool {
+        if self.breakpoints.binary_search(&addr).is_err() { return false }
+        match condition {
+            Some(condition) => { self.conditions.insert(addr, condition); }
+            None => { self.conditions.remove(&addr); }
+        }
+        true
+    }
+
+    /// Returns the condition of the breakpoint at `addr`, if it has one.
+    pub fn condition(&self, addr: u32) -> Option<&Expr> { self.conditions.get(&addr) }
+
+    /// Parses an address the same way commands do (`BB:AAAA`, `BBAAAA`, `AAAA` or a label).
+    /// Addresses without a bank use `default_bank`.
+    pub fn parse_address(&self, s: &str, default_bank: u8) -> Result<u32, String> {
+        parse_addr(s, default_bank, &self.symbols).map_err(|e| match e {
+            CommandError::Usage(msg) => msg,
+            CommandError::Backend(e) => e.to_string(),
+        })
+    }
+
+    /// Adds a watch expression, sampling its current value. Returns its index.
+    pub fn add_watch(&mut self, snes: &mut Snes, expr: Expr, mode: WatchMode) -> usize {
+        let mut watch = Watch::new(expr, mode);
+        watch.sample(snes);
+        self.watches.push(watch);
+        self.watches.len() - 1
+    }
+
+    /// Removes the watch with the given index. Returns `false` if there is none.
+    pub fn remove_watch(&mut self, index: usize) -> bool {
+        if index < self.watches.len() {
+            self.watches.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns all watches in the order they were added.
+    pub fn watches(&self) -> &[Watch] { &self.watches }
+
+    /// Samples the watches after an instruction was executed. `frame_done` is `true` if the
+    /// instruction completed a frame.
+    fn sample_watches(&mut self, snes: &mut Snes, frame_done: bool) {
+        for watch in &mut self.watches {
+            if watch.mode() == WatchMode::Step || frame_done {
+                watch.sample(snes);
+            }
+        }
+    }
+
+    /// Returns the breakpoint the CPU is currently sitting on, if any (and if its condition
+    /// holds).
+    fn breakpoint_hit(&self, snes: &mut Snes) -> Option<u32> {
+        let pc = {
+            let cpu = snes.cpu();
+            if cpu.waiting() { return None }
+            full_addr(cpu.pbr, cpu.pc)
+        };
+        if self.breakpoints.binary_search(&pc).is_err() { return None }
+        match self.conditions.get(&pc) {
+            Some(condition) if !condition.is_true(snes) => None,
+            _ => Some(pc),
+        }
+    }
+
+    /// Executes up to `count` instructions, stopping early when a breakpoint is reached.
+    ///
+    /// `render` is called for every completed frame, just like in `Snes::render_frame`.
+    pub fn step<F>(&mut self, snes: &mut Snes, count: u32, render: &mut F)
+                   -> BackendResult<StopReason>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        for _ in 0..count {
+            let actions = try!(snes.step(render));
+            self.sample_watches(snes, actions.is_some());
+            if let Some(actions) = actions {
+                if actions.contains(&BackendAction::Exit) { return Ok(StopReason::Exit) }
+            }
+            if let Some(addr) = self.breakpoint_hit(snes) {
+                return Ok(StopReason::Breakpoint(addr));
+            }
+            if let Some(addr) = snes.take_stack_break() {
+                return Ok(StopReason::StackWrap(addr));
+            }
+        }
+
+        Ok(StopReason::Done)
+    }
+
+    /// Resumes execution until a breakpoint is reached or, if `max_frames` is given, the given
+    /// number of frames was emulated.
+    ///
+    /// At least one instruction is executed, so this can be used to continue from a breakpoint.
+    pub fn cont<F>(&mut self, snes: &mut Snes, max_frames: Option<u32>, render: &mut F)
+                   -> BackendResult<StopReason>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        let mut frames = 0;
+        loop {
+            let actions = try!(snes.step(render));
+            self.sample_watches(snes, actions.is_some());
+            if let Some(actions) = actions {
+                if actions.contains(&BackendAction::Exit) { return Ok(StopReason::Exit) }
+                frames += 1;
+                if max_frames.map_or(false, |max| frames >= max) {
+                    return Ok(StopReason::FrameLimit);
+                }
+            }
+            if let Some(addr) = self.breakpoint_hit(snes) {
+                return Ok(StopReason::Breakpoint(addr));
+            }
+            if let Some(addr) = snes.take_stack_break() {
+                return Ok(StopReason::StackWrap(addr));
+            }
+        }
+    }
+
+    /// Formats the CPU registers in the same style as the instruction trace.
+    pub fn registers(&self, snes: &Snes) -> String {
+        let cpu = snes.cpu();
+        let ppu = &snes.peripherals().ppu;
+        format!("pc:{:02X}:{:04X} a:{:04X} x:{:04X} y:{:04X} s:{:04X} d:{:04X} dbr:{:02X} emu:{} \
+                 {}{}\nV:{} H:{} master cycle:{}",
+            cpu.pbr, cpu.pc, cpu.a, cpu.x, cpu.y, cpu.s, cpu.d, cpu.dbr, cpu.emulation() as u8,
+            cpu.status(), if cpu.waiting() { " (waiting for interrupt)" } else { "" },
+            ppu.v_counter(), ppu.h_counter(), snes.master_cy())
+    }
+
+    /// Disassembles `count` instructions starting at `addr`.
+    ///
+    /// Immediate operand sizes are decoded using the current M and X flags, updated by any `rep`
+    /// and `sep` instructions encountered on the way. I/O registers are not read (they're decoded
+    /// as `$00`).
+    pub fn disassemble(&self, snes: &mut Snes, addr: u32, count: usize) -> Vec<Instruction> {
+        let mut flags = Flags::new(snes);
+        let mem = snes.peripherals_mut();
+        let bank = (addr >> 16) as u8;
+        let mut addr = addr as u16;
+        let mut instrs = Vec::with_capacity(count);
+        for _ in 0..count {
+            let instr = flags.disassemble(mem, bank, addr);
+            addr = instr.next_addr();
+            instrs.push(instr);
+        }
+        instrs
+    }
+
+    /// Disassembles up to `before` instructions preceding the program counter, the instruction at
+    /// the program counter, and `after` instructions following it.
+    ///
+    /// Since 65816 instructions have different lengths, the preceding instructions are guessed by
+    /// looking for a starting point that decodes into an instruction ending right at the PC.
+    pub fn disassemble_around(&self, snes: &mut Snes, before: usize, after: usize)
+                              -> Vec<Instruction> {
+        let (pbr, pc) = (snes.cpu().pbr, snes.cpu().pc);
+        let start_flags = Flags::new(snes);
+        let mut instrs = Vec::new();
+        {
+            let mem = snes.peripherals_mut();
+            // Instructions are at most 4 bytes long. Try the earliest starting point first, since
+            // that gives the longest chain (and a higher chance of having synced up).
+            for distance in (1..before as u16 * 4 + 1).rev() {
+                if distance > pc { continue }
+                let mut addr = pc - distance;
+                let mut chain = Vec::new();
+                let mut flags = start_flags;
+                while addr < pc {
+                    let instr = flags.disassemble(mem, pbr, addr);
+                    addr = instr.next_addr();
+                    chain.push(instr);
+                }
+                if addr == pc {
+                    let skip = chain.len().saturating_sub(before);
+                    instrs.extend(chain.drain(skip..));
+                    break;
+                }
+            }
+        }
+
+        instrs.extend(self.disassemble(snes, full_addr(pbr, pc), after + 1));
+        instrs
+    }
+
+    /// Reads `len` bytes starting at `addr`. Bytes that can't be read without side effects (eg.
+    /// I/O registers) are returned as `None`. The address wraps inside the bank.
+    pub fn read_memory(&self, snes: &mut Snes, addr: u32, len: usize) -> Vec<Option<u8>> {
+        let bank = (addr >> 16) as u8;
+        (0..len).map(|i| {
+            snes.peripherals_mut().peek(bank, (addr as u16).wrapping_add(i as u16))
+        }).collect()
+    }
+
+    /// Writes bytes to WRAM, ROM or cartridge RAM, starting at `addr`. Returns `false` if any of
+    /// the bytes couldn't be written.
+    pub fn write_memory(&self, snes: &mut Snes, addr: u32, data: &[u8]) -> bool {
+        let bank = (addr >> 16) as u8;
+        let mut ok = true;
+        for (i, &byte) in data.iter().enumerate() {
+            ok &= snes.peripherals_mut().poke(bank, (addr as u16).wrapping_add(i as u16), byte);
+        }
+        ok
+    }
+
+    /// Executes a single debugger command and writes its output to `out`. An empty line repeats
+    /// the last command.
+    ///
+    /// Returns `true` if the debugger should be exited.
+    pub fn execute<F, W>(&mut self, snes: &mut Snes, line: &str, render: &mut F, out: &mut W)
+                         -> BackendResult<bool>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>>, W: Write {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.trim().to_string();
+            self.last_command.clone()
+        };
+        let mut args = line.split_whitespace();
+        let cmd = match args.next() {
+            Some(cmd) => cmd,
+            None => return Ok(false),
+        };
+        let args: Vec<&str> = args.collect();
+
+        let output = match self.run_command(snes, cmd, &args, render) {
+            Ok(Some(output)) => output,
+            Ok(None) => return Ok(true),
+            Err(CommandError::Backend(e)) => return Err(e),
+            Err(CommandError::Usage(msg)) => format!("error: {}", msg),
+        };
+        if !output.is_empty() {
+            try!(writeln!(out, "{}", output));
+        }
+        Ok(false)
+    }
+
+    /// Runs a command. Returns the text to print, or `None` if the debugger should be exited.
+    fn run_command<F>(&mut self, snes: &mut Snes, cmd: &str, args: &[&str], render: &mut F)
+                      -> Result<Option<String>, CommandError>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        let pbr = snes.cpu().pbr;
+        let dbr = snes.cpu().dbr;
+
+        let output = match cmd {
+            "s" | "step" => {
+                let count = try!(parse_optional_number(args.get(0), 1));
+                let reason = try!(self.step(snes, count, render));
+                try!(self.stop_message(snes, reason))
+            }
+            "c" | "continue" => {
+                let max_frames = match args.get(0) {
+                    Some(arg) => Some(try!(parse_number(arg))),
+                    None => None,
+                };
+                let reason = try!(self.cont(snes, max_frames, render));
+                try!(self.stop_message(snes, reason))
+            }
+            "d" | "disasm" => {
+                let instrs = match args.get(0) {
+                    Some(addr) => {
+                        let addr = try!(parse_addr(addr, pbr, &self.symbols));
+                        let count = try!(parse_optional_number(args.get(1), 16));
+                        self.disassemble(snes, addr, count as usize)
+                    }
+                    None => self.disassemble_around(snes, 4, 8),
+                };
+                format_instructions(snes, &instrs, &self.symbols)
+            }
+            "r" | "regs" => self.registers(snes),
+            "m" | "read" => {
+                let addr = try!(parse_addr(try!(arg(args, 0, "read ADDR [LEN]")), dbr,
+                    &self.symbols));
+                let len = try!(parse_optional_number(args.get(1), 64));
+                let data = self.read_memory(snes, addr, len as usize);
+                format_hex_dump(addr, &data)
+            }
+            "w" | "write" => {
+                let addr = try!(parse_addr(try!(arg(args, 0, "write ADDR BYTE...")), dbr,
+                    &self.symbols));
+                let mut data = Vec::new();
+                for byte in &args[1..] {
+                    data.push(try!(parse_byte(byte)));
+                }
+                if data.is_empty() {
+                    return Err(CommandError::Usage("usage: write ADDR BYTE...".to_string()));
+                }
+                if self.write_memory(snes, addr, &data) {
+                    String::new()
+                } else {
+                    return Err(CommandError::Usage(
+                        "some bytes are not in RAM or ROM and were not written".to_string()));
+                }
+            }
+            "b" | "break" => match args.get(0) {
+                Some(addr) => {
+                    let addr = try!(parse_addr(addr, pbr, &self.symbols));
+                    let condition = match args.get(1) {
+                        Some(&"if") => Some(try!(self.parse_condition(&args[2..]))),
+                        Some(_) => return Err(CommandError::Usage(
+                            "usage: break [ADDR [if CONDITION]]".to_string())),
+                        None => None,
+                    };
+                    let desc = self.describe_addr(addr);
+                    let mut s = if self.add_breakpoint(addr) {
+                        format!("breakpoint set at {}", desc)
+                    } else {
+                        format!("there already is a breakpoint at {}", desc)
+                    };
+                    if condition.is_some() {
+                        self.set_condition(addr, condition);
+                        s.push_str(" (condition updated)");
+                    }
+                    s
+                }
+                None => self.list_breakpoints(),
+            },
+            "watch" => {
+                if args.is_empty() {
+                    self.list_watches()
+                } else {
+                    let (mode, expr) = match args[0].parse::<WatchMode>() {
+                        Ok(mode) => (mode, &args[1..]),
+                        Err(_) => (WatchMode::Frame, args),
+                    };
+                    let expr = try!(self.parse_expr(expr));
+                    let index = self.add_watch(snes, expr, mode);
+                    format!("watch {} added", index)
+                }
+            }
+            "unwatch" => match try!(arg(args, 0, "unwatch N|all")) {
+                "all" => {
+                    self.watches.clear();
+                    "all watches removed".to_string()
+                }
+                index => {
+                    let index = try!(parse_number(index)) as usize;
+                    if self.remove_watch(index) {
+                        format!("watch {} removed", index)
+                    } else {
+                        return Err(CommandError::Usage(format!("no watch {}", index)));
+                    }
+                }
+            },
+            "history" => {
+                let index = try!(parse_number(try!(arg(args, 0, "history N [COUNT]")))) as usize;
+                let count = try!(parse_optional_number(args.get(1), 20)) as usize;
+                match self.watches.get(index) {
+                    Some(watch) => format_history(watch, count),
+                    None => return Err(CommandError::Usage(format!("no watch {}", index))),
+                }
+            }
+            "cond" | "condition" => {
+                let usage = "condition ADDR [CONDITION]";
+                let addr = try!(parse_addr(try!(arg(args, 0, usage)), pbr, &self.symbols));
+                let condition = if args.len() > 1 {
+                    Some(try!(self.parse_condition(&args[1..])))
+                } else {
+                    None
+                };
+                let removed = condition.is_none();
+                if !self.set_condition(addr, condition) {
+                    return Err(CommandError::Usage(
+                        format!("no breakpoint at {}", self.describe_addr(addr))));
+                }
+                if removed {
+                    format!("breakpoint at {} is now unconditional", self.describe_addr(addr))
+                } else {
+                    format!("condition of breakpoint at {} updated", self.describe_addr(addr))
+                }
+            }
+            "delete" => {
+                let addr = try!(parse_addr(try!(arg(args, 0, "delete ADDR")), pbr,
+                    &self.symbols));
+                let desc = self.describe_addr(addr);
+                if self.remove_breakpoint(addr) {
+                    format!("breakpoint at {} deleted", desc)
+                } else {
+                    format!("no breakpoint at {}", desc)
+                }
+            }
+            "breakpoints" => self.list_breakpoints(),
+            "trace" => match snes.tracer_mut() {
+                Some(tracer) => {
+                    let enabled = match args.get(0) {
+                        Some(&"on") => true,
+                        Some(&"off") => false,
+                        None => !tracer.is_enabled(),
+                        Some(_) => {
+                            return Err(CommandError::Usage("usage: trace [on|off]".to_string()))
+                        }
+                    };
+                    tracer.set_enabled(enabled);
+                    try!(tracer.flush().map_err(|e| CommandError::Backend(e.into())));
+                    format!("tracing {}", if enabled { "enabled" } else { "disabled" })
+                }
+                None => return Err(CommandError::Usage(
+                    "no trace file is open".to_string())),
+            },
+            "profile" => {
+                let functions = args.get(0) == Some(&"functions");
+                let n = try!(parse_optional_number(args.get(functions as usize), 20)) as usize;
+                let symbols = &self.symbols;
+                match snes.profiler() {
+                    Some(profiler) if functions => {
+                        profiler.function_report(n, |addr| symbols.describe(addr))
+                    }
+                    Some(profiler) => profiler.report(n),
+                    None => return Err(CommandError::Usage("profiling is disabled".to_string())),
+                }
+            }
+            "opstats" => match args.get(0) {
+                Some(&"on") => {
+                    snes.cpu_mut().set_opcode_stats(Some(OpcodeStats::new()));
+                    "counting executed opcodes".to_string()
+                }
+                Some(&"off") => {
+                    snes.cpu_mut().set_opcode_stats(None);
+                    "stopped counting executed opcodes".to_string()
+                }
+                Some(&"clear") => match snes.cpu_mut().opcode_stats_mut() {
+                    Some(stats) => {
+                        stats.clear();
+                        "opcode counts cleared".to_string()
+                    }
+                    None => return Err(CommandError::Usage(
+                        "opcode counting is disabled (use `opstats on`)".to_string())),
+                },
+                Some(_) => {
+                    return Err(CommandError::Usage("usage: opstats [on|off|clear]".to_string()))
+                }
+                None => match snes.cpu().opcode_stats() {
+                    Some(stats) => stats.to_string(),
+                    None => return Err(CommandError::Usage(
+                        "opcode counting is disabled (use `opstats on`)".to_string())),
+                },
+            },
+            "bt" | "backtrace" => match args.get(0) {
+                Some(&"on") => {
+                    snes.cpu_mut().set_call_stack_tracking(true);
+                    "tracking calls (the call stack starts out empty)".to_string()
+                }
+                Some(&"off") => {
+                    snes.cpu_mut().set_call_stack_tracking(false);
+                    "stopped tracking calls".to_string()
+                }
+                Some(_) => return Err(CommandError::Usage("usage: bt [on|off]".to_string())),
+                None => try!(self.backtrace(snes)),
+            },
+            "search" => try!(self.search(snes, args.get(0).cloned())),
+            "events" => match args.get(0) {
+                Some(&"on") => {
+                    snes.set_event_log(Some(EventLog::new()));
+                    "logging register writes (use `events` after the next frame)".to_string()
+                }
+                Some(&"off") => {
+                    snes.set_event_log(None);
+                    "stopped logging register writes".to_string()
+                }
+                Some(_) => return Err(CommandError::Usage("usage: events [on|off]".to_string())),
+                None => match snes.event_log() {
+                    Some(events) => format_events(events),
+                    None => return Err(CommandError::Usage(
+                        "register write logging is disabled (use `events on`)".to_string())),
+                },
+            },
+            "ppu" => match args.get(0) {
+                Some(&"on") => {
+                    snes.set_ppu_history(Some(RegisterHistory::new()));
+                    "taking PPU register snapshots at the start of every frame".to_string()
+                }
+                Some(&"off") => {
+                    snes.set_ppu_history(None);
+                    "stopped taking PPU register snapshots".to_string()
+                }
+                Some(&"diff") => match snes.ppu_history().map(|history| history.changes()) {
+                    Some(Some(changes)) => format_register_changes(&changes),
+                    Some(None) => return Err(CommandError::Usage(
+                        "no snapshots of 2 frames yet (run for another frame)".to_string())),
+                    None => return Err(CommandError::Usage(
+                        "PPU register snapshots are disabled (use `ppu on`)".to_string())),
+                },
+                Some(_) => return Err(CommandError::Usage("usage: ppu [on|off|diff]".to_string())),
+                None => format_ppu_registers(&snes.peripherals().ppu.registers()),
+            },
+            "ports" => match args.get(0) {
+                Some(&"on") => {
+                    snes.peripherals_mut().apu.set_port_log(Some(PortLog::new()));
+                    "logging APU port accesses".to_string()
+                }
+                Some(&"off") => {
+                    snes.peripherals_mut().apu.set_port_log(None);
+                    "stopped logging APU port accesses".to_string()
+                }
+                Some(&"clear") => match snes.peripherals_mut().apu.port_log_mut() {
+                    Some(log) => {
+                        log.clear();
+                        "cleared the APU port log".to_string()
+                    }
+                    None => return Err(CommandError::Usage(
+                        "APU port logging is disabled (use `ports on`)".to_string())),
+                },
+                arg => {
+                    let usage = || CommandError::Usage("usage: ports [on|off|clear|N]".to_string());
+                    let count = match arg {
+                        Some(n) => try!(n.parse::<usize>().map_err(|_| usage())),
+                        None => 20,
+                    };
+                    match snes.peripherals().apu.port_log() {
+                        Some(log) => format_port_log(log, count),
+                        None => return Err(CommandError::Usage(
+                            "APU port logging is disabled (use `ports on`)".to_string())),
+                    }
+                }
+            },
+            "voices" => format_voices(snes),
+            "mute" | "unmute" => {
+                let usage = format!("usage: {} VOICE...", cmd);
+                if args.is_empty() { return Err(CommandError::Usage(usage)) }
+                for voice in args {
+                    let voice = try!(parse_voice(voice));
+                    snes.peripherals_mut().apu.set_voice_muted(voice, cmd == "mute");
+                }
+                format_voices(snes)
+            }
+            "solo" => {
+                let voice = match try!(arg(args, 0, "solo VOICE|off")) {
+                    "off" => None,
+                    voice => Some(try!(parse_voice(voice))),
+                };
+                snes.peripherals_mut().apu.solo_voice(voice);
+                format_voices(snes)
+            }
+            "reload" => {
+                let keep_sram = !args.contains(&"clear-sram");
+                let path = match args.iter().find(|&&arg| arg != "clear-sram") {
+                    Some(path) => PathBuf::from(path),
+                    None => match self.rom_path {
+                        Some(ref path) => path.clone(),
+                        None => return Err(CommandError::Usage(
+                            "usage: reload FILE [clear-sram]".to_string())),
+                    },
+                };
+                let rom = try!(Rom::load_file(&path).map_err(|e| CommandError::Usage(
+                    format!("couldn't load '{}': {}", path.display(), e))));
+                snes.replace_rom(rom, keep_sram);
+                self.rom_path = Some(path);
+                format!("reloaded the ROM and reset ({} breakpoints kept)", self.breakpoints.len())
+            }
+            "h" | "help" => HELP.to_string(),
+            "q" | "quit" => return Ok(None),
+            _ => return Err(CommandError::Usage(
+                format!("unknown command '{}' (try 'help')", cmd))),
+        };
+
+        Ok(Some(output))
+    }
+
+    /// Implements the `search` command.
+    fn search(&mut self, snes: &Snes, arg: Option<&str>) -> Result<String, CommandError> {
+        /// Number of candidates listed
+        const MAX_LISTED: usize = 20;
+
+        let wram = &*snes.peripherals().wram;
+        match (arg, self.ram_search.as_mut()) {
+            (Some("start"), _) => {
+                let search = RamSearch::new(wram);
+                let s = format!("started a new search with {} candidates", search.len());
+                self.ram_search = Some(search);
+                Ok(s)
+            }
+            (Some(filter), Some(search)) => {
+                search.filter(wram, try!(filter.parse::<SearchFilter>()
+                    .map_err(CommandError::Usage)));
+                Ok(format!("{} candidates left", search.len()))
+            }
+            (None, Some(search)) => {
+                let mut s = format!("{} candidates", search.len());
+                for candidate in search.candidates().take(MAX_LISTED) {
+                    write!(s, "\n{}  ${:02X}  {}", format_addr(candidate.addr), candidate.value,
+                        candidate.cheat_code(candidate.value)).unwrap();
+                }
+                Ok(s)
+            }
+            (_, None) => Err(CommandError::Usage("no search running (use `search start`)".into())),
+        }
+    }
+
+    /// Implements the `bt` command: Lists the current location, followed by the location of each
+    /// call on the call stack (innermost first).
+    fn backtrace(&self, snes: &Snes) -> Result<String, CommandError> {
+        let call_stack = match snes.cpu().call_stack() {
+            Some(call_stack) => call_stack,
+            None => return Err(CommandError::Usage(
+                "call stack tracking is disabled (use `bt on`)".to_string())),
+        };
+
+        let pc = full_addr(snes.cpu().pbr, snes.cpu().pc);
+        let mut s = format!("#0  {}", self.describe_addr(pc));
+        for (i, frame) in call_stack.frames().iter().rev().enumerate() {
+            let kind = match frame.kind {
+                CallKind::Jsr => "jsr",
+                CallKind::Jsl => "jsl",
+                CallKind::Nmi => "nmi",
+                CallKind::Irq => "irq",
+            };
+            write!(s, "\n#{:<2} {}  ({} to {})", i + 1, self.describe_addr(frame.caller), kind,
+                self.describe_addr(frame.target)).unwrap();
+        }
+        Ok(s)
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints set".to_string();
+        }
+
+        let mut s = String::new();
+        for (i, &addr) in self.breakpoints.iter().enumerate() {
+            if i != 0 { s.push('\n'); }
+            s.push_str(&self.describe_addr(addr));
+            if let Some(condition) = self.conditions.get(&addr) {
+                write!(s, " if {}", condition).unwrap();
+            }
+        }
+        s
+    }
+
+    /// Parses a breakpoint condition split into whitespace-separated arguments.
+    fn parse_condition(&self, args: &[&str]) -> Result<Expr, CommandError> {
+        if args.is_empty() {
+            return Err(CommandError::Usage("missing condition".to_string()));
+        }
+        Expr::parse(&args.join(" "), &self.symbols)
+            .map_err(|e| CommandError::Usage(format!("invalid condition: {}", e)))
+    }
+
+    /// Parses an expression split into whitespace-separated arguments.
+    fn parse_expr(&self, args: &[&str]) -> Result<Expr, CommandError> {
+        if args.is_empty() {
+            return Err(CommandError::Usage("missing expression".to_string()));
+        }
+        Expr::parse(&args.join(" "), &self.symbols)
+            .map_err(|e| CommandError::Usage(format!("invalid expression: {}", e)))
+    }
+
+    fn list_watches(&self) -> String {
+        if self.watches.is_empty() {
+            return "no watches set".to_string();
+        }
+
+        let mut s = format!("{:<3} {:5} {:>10}  {}", "#", "mode", "value", "expression");
+        for (i, watch) in self.watches.iter().enumerate() {
+            let mode = match watch.mode() {
+                WatchMode::Step => "step",
+                WatchMode::Frame => "frame",
+            };
+            let value = match watch.last_value() {
+                Some(value) => format!("${:X}", value),
+                None => "-".to_string(),
+            };
+            write!(s, "\n{:<3} {:5} {:>10}  {}", i, mode, value, watch.expr()).unwrap();
+        }
+        s
+    }
+
+    /// Formats an address, followed by its label (if any).
+    fn describe_addr(&self, addr: u32) -> String {
+        match self.symbols.describe(addr) {
+            Some(label) => format!("{} ({})", format_addr(addr), label),
+            None => format_addr(addr),
+        }
+    }
+
+    /// Describes why execution stopped, followed by the instruction at the program counter.
+    fn stop_message(&self, snes: &mut Snes, reason: StopReason) -> Result<String, CommandError> {
+        let mut s = match reason {
+            StopReason::Done => String::new(),
+            StopReason::Breakpoint(addr) => {
+                format!("breakpoint hit at {}\n", self.describe_addr(addr))
+            }
+            StopReason::FrameLimit => "frame limit reached\n".to_string(),
+            StopReason::StackWrap(addr) => {
+                format!("stack pointer wrapped around at {}\n", self.describe_addr(addr))
+            }
+            StopReason::Exit => return Ok(String::new()),
+        };
+        let pc = full_addr(snes.cpu().pbr, snes.cpu().pc);
+        let instrs = self.disassemble(snes, pc, 1);
+        s.push_str(&format_instructions(snes, &instrs, &self.symbols));
+        Ok(s)
+    }
+
+    /// Runs a line-oriented debugger REPL, reading commands from `input` until it's exhausted or
+    /// `quit` is entered.
+    ///
+    /// `render` is called for every completed frame, just like in `Snes::render_frame`.
+    pub fn run_repl<F, R, W>(&mut self, snes: &mut Snes, mut input: R, mut output: W,
+                             mut render: F) -> BackendResult<()>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>>, R: BufRead, W: Write {
+        try!(writeln!(output, "breeze debugger. Type 'help' for a list of commands."));
+        if snes.cpu().call_stack().is_none() {
+            // Needed for `bt`
+            snes.cpu_mut().set_call_stack_tracking(true);
+        }
+        let mut line = String::new();
+        loop {
+            try!(write!(output, "(breeze) "));
+            try!(output.flush());
+
+            line.clear();
+            if try!(input.read_line(&mut line)) == 0 {
+                // EOF
+                try!(writeln!(output, ""));
+                return Ok(());
+            }
+            if try!(self.execute(snes, &line, &mut render, &mut output)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+const HELP: &'static str = "\
+Addresses are hexadecimal, optionally prefixed with `$` or `0x`. A bank can be given as `BB:AAAA`
+or `BBAAAA`, otherwise PBR (for code) or DBR (for data) is used. If symbols are loaded, addresses
+can also be given as `Label` or `Label+N` (`N` is decimal, or hexadecimal with `$`).
+
+s, step [N]             execute N instructions (default: 1)
+c, continue [FRAMES]    run until a breakpoint is hit (or FRAMES frames were emulated)
+d, disasm [ADDR] [N]    disassemble N instructions at ADDR (default: around PC)
+r, regs                 show the CPU registers
+m, read ADDR [LEN]      dump LEN bytes of memory (default: 64)
+w, write ADDR BYTE...   write bytes to RAM or ROM
+b, break [ADDR]         set a breakpoint at ADDR (without ADDR: list breakpoints)
+b, break ADDR if COND   set a breakpoint that only triggers if COND is true, eg.
+                        `b NmiHandler if A == 0x42 && [7E:0010] != 0`
+cond ADDR [COND]        change the condition of a breakpoint (without COND: remove it)
+watch [step|frame] EXPR sample EXPR after every instruction (recording changes) or every frame
+                        (default) while executing code
+watch                   list watches with their last value
+unwatch N|all           remove watch N (or all watches)
+history N [COUNT]       show the last COUNT values of watch N (default: 20)
+breakpoints             list breakpoints
+delete ADDR             delete the breakpoint at ADDR
+trace [on|off]          enable or disable the instruction trace (toggles without argument)
+profile [N]             show the N instructions the most cycles were spent in (default: 20)
+profile functions [N]   show the N subroutines the most cycles were spent in (default: 20)
+opstats on|off|clear    enable or disable counting executed opcodes, or reset the counts
+opstats                 show how often each opcode and addressing mode was executed
+bt, backtrace [on|off]  show the call stack, or enable/disable call tracking
+search start            start a cheat search, taking a snapshot of WRAM
+search FILTER           keep addresses passing FILTER and take a new snapshot. Without a value,
+                        `=`, `!=`, `>` and `<` compare to the snapshot (`>5` compares to 5),
+                        `+N` and `-N` keep values that changed by N
+search                  list the remaining addresses
+events [on|off]         enable or disable the register write log (without argument: show the
+                        writes of the last frame)
+ppu                     show the PPU registers
+ppu on|off              enable or disable PPU register snapshots at the start of every frame
+ppu diff                show the PPU registers that changed between the starts of the last two
+                        frames
+ports on|off            enable or disable the log of CPU/APU port accesses
+ports [N]               show the last N APU port accesses (default: 20)
+ports clear             clear the APU port log
+voices                  show the state of the 8 DSP voices
+mute VOICE...           mute DSP voices (0-7)
+unmute VOICE...         unmute DSP voices
+solo VOICE|off          mute all voices except VOICE (`off` unmutes all voices)
+reload [FILE] [clear-sram]
+                        load the ROM file again (or FILE instead) and reset, keeping breakpoints,
+                        watches and the cartridge RAM (unless `clear-sram` is given)
+h, help                 show this text
+q, quit                 exit the debugger
+
+An empty line repeats the last command.
+
+Conditions and watches can use the registers (A, X, Y, S, D, DBR, PBR, PC, P), memory bytes
+([ADDR], with hexadecimal addresses), labels, numbers (decimal unless prefixed with `$` or `0x`)
+and the operators || && == != < <= > >= | ^ & + - !";
+
+enum CommandError {
+    /// The command was used incorrectly, the message will be displayed
+    Usage(String),
+    /// The renderer failed
+    Backend(Box<Error>),
+}
+
+impl From<Box<Error>> for CommandError {
+    fn from(e: Box<Error>) -> Self { CommandError::Backend(e) }
+}
+
+fn full_addr(bank: u8, addr: u16) -> u32 { (bank as u32) << 16 | addr as u32 }
+
+fn format_addr(addr: u32) -> String { format!("${:02X}:{:04X}", addr >> 16, addr & 0xffff) }
+
+/// Tracks the M and X flags while disassembling a linear sequence of instructions.
+#[derive(Copy, Clone)]
+struct Flags {
+    small_acc: bool,
+    small_index: bool,
+    /// In emulation mode, `rep` can't make the registers 16-bit
+    emulation: bool,
+}
+
+impl Flags {
+    fn new(snes: &Snes) -> Self {
+        let cpu = snes.cpu();
+        Flags {
+            small_acc: cpu.status().small_acc(),
+            small_index: cpu.status().small_index(),
+            emulation: cpu.emulation(),
+        }
+    }
+
+    fn disassemble(&mut self, mem: &mut Peripherals, bank: u8, addr: u16) -> Instruction {
+        const REP: u8 = 0xc2;
+        const SEP: u8 = 0xe2;
+
+        let instr = mem.disassemble(bank, addr, self.small_acc, self.small_index);
+        let bytes = instr.bytes();
+        match bytes[0] {
+            REP if !self.emulation => {
+                self.small_acc &= bytes[1] & 0x20 == 0;
+                self.small_index &= bytes[1] & 0x10 == 0;
+            }
+            SEP => {
+                self.small_acc |= bytes[1] & 0x20 != 0;
+                self.small_index |= bytes[1] & 0x10 != 0;
+            }
+            _ => {}
+        }
+        instr
+    }
+}
+
+/// Formats a disassembly listing. Labels are printed on their own line, and jump targets are
+/// annotated with the closest label.
+fn format_instructions(snes: &Snes, instrs: &[Instruction], symbols: &SymbolTable) -> String {
+    let pc = full_addr(snes.cpu().pbr, snes.cpu().pc);
+    let mut s = String::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        if i != 0 { s.push('\n'); }
+        let addr = full_addr(instr.bank, instr.addr);
+        if let Some(label) = symbols.label(addr) {
+            write!(s, "{}:\n", label).unwrap();
+        }
+        let marker = if addr == pc { '>' } else { ' ' };
+        let mut bytes = String::new();
+        for byte in instr.bytes() {
+            write!(bytes, "{:02X} ", byte).unwrap();
+        }
+        write!(s, "{} {}  {:12} {}", marker, format_addr(addr), bytes, instr).unwrap();
+        if let Some(target) = instr.target().and_then(|target| symbols.describe(target)) {
+            write!(s, "  ; {}", target).unwrap();
+        }
+    }
+    s
+}
+
+/// Formats the last `count` samples of a watch.
+fn format_history(watch: &Watch, count: usize) -> String {
+    let samples = watch.samples();
+    let mut s = format!("{} ({} samples)\n{:>8}  {:9}  {:>10}", watch.expr(), samples.len(),
+        "frame", "pc", "value");
+    for sample in samples.iter().skip(samples.len().saturating_sub(count)) {
+        write!(s, "\n{:8}  {}  {:>10}", sample.frame, format_addr(sample.pc),
+            format!("${:X}", sample.value)).unwrap();
+    }
+    s
+}
+
+fn format_port_log(log: &PortLog, count: usize) -> String {
+    let entries = log.entries();
+    let mut s = format!("{} APU port accesses logged", entries.len());
+    for entry in entries.iter().skip(entries.len().saturating_sub(count)) {
+        let accessor = match entry.accessor {
+            Accessor::Cpu => "CPU",
+            Accessor::Apu => "APU",
+        };
+        write!(s, "\n{:12}  {} {:5} port {} = ${:02X}  APU PC ${:04X}", entry.cycle, accessor,
+            if entry.write { "write" } else { "read" }, entry.port, entry.value, entry.apu_pc)
+            .unwrap();
+        if entry.count > 1 {
+            write!(s, "  (x{})", entry.count).unwrap();
+        }
+    }
+    s
+}
+
+fn format_events(events: &EventLog) -> String {
+    let mut s = format!("{} register writes in the last frame", events.last_frame().len());
+    for event in events.last_frame() {
+        let kind = match event.kind {
+            EventKind::Ppu => "PPU",
+            EventKind::Apu => "APU",
+            EventKind::CpuIo => "CPU",
+            EventKind::Dma => "DMA",
+        };
+        write!(s, "\nV:{:3} H:{:3}  {}  ${:04X} = ${:02X}", event.v, event.h, kind, event.addr,
+            event.value).unwrap();
+    }
+    s
+}
+
+fn format_ppu_registers(regs: &PpuRegisters) -> String {
+    let mut s = String::new();
+    for (i, &(name, value)) in regs.values().iter().enumerate() {
+        if i > 0 {
+            s.push_str(if i % 4 == 0 { "\n" } else { "  " });
+        }
+        write!(s, "{:9} {:>5}", name.to_uppercase(), format!("${:X}", value)).unwrap();
+    }
+    s
+}
+
+fn format_register_changes(changes: &[RegisterChange]) -> String {
+    let mut s = format!("{} PPU registers changed since the previous frame", changes.len());
+    for change in changes {
+        write!(s, "\n{:9} ${:X} -> ${:X}", change.name.to_uppercase(), change.old, change.new)
+            .unwrap();
+    }
+    s
+}
+
+fn format_voices(snes: &Snes) -> String {
+    let apu = &snes.peripherals().apu;
+    let mut s = "#  src  start  loop  pitch  vol L/R   envelope        env  out  flags".to_string();
+    for voice in 0..VOICES {
+        let state = apu.voice_state(voice);
+        let envelope = match state.envelope {
+            Envelope::Adsr { attack, decay, sustain_level, sustain_rate } => {
+                format!("ADSR {:X} {} {} {:02X}", attack, decay, sustain_level, sustain_rate)
+            }
+            Envelope::Gain(gain) => format!("GAIN ${:02X}", gain),
+            Envelope::Release => "release".to_string(),
+        };
+        let mut flags = String::new();
+        if state.key_on { flags.push_str("kon ") }
+        if state.ended { flags.push_str("end ") }
+        if state.muted { flags.push_str("muted") }
+        write!(s, "\n{}  ${:02X}  ${:04X} ${:04X} ${:04X}  {:4}/{:<4}  {:15} ${:02X}  {:4}  {}",
+            voice, state.source, state.start_addr, state.loop_addr, state.pitch, state.lvol,
+            state.rvol, envelope, state.env, state.out, flags.trim_right()).unwrap();
+    }
+    s
+}
+
+fn format_hex_dump(addr: u32, data: &[Option<u8>]) -> String {
+    let bank = (addr >> 16) as u8;
+    let mut s = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        if i != 0 { s.push('\n'); }
+        let line_addr = (addr as u16).wrapping_add(i as u16 * 16);
+        write!(s, "{}:", format_addr(full_addr(bank, line_addr))).unwrap();
+        for byte in line {
+            match *byte {
+                Some(byte) => write!(s, " {:02X}", byte).unwrap(),
+                None => s.push_str(" --"),
+            }
+        }
+    }
+    s
+}
+
+fn arg<'a>(args: &[&'a str], index: usize, usage: &str) -> Result<&'a str, CommandError> {
+    args.get(index).cloned().ok_or_else(|| CommandError::Usage(format!("usage: {}", usage)))
+}
+
+fn strip_hex_prefix(s: &str) -> &str {
+    if s.starts_with('$') {
+        &s[1..]
+    } else if s.starts_with("0x") || s.starts_with("0X") {
+        &s[2..]
+    } else {
+        s
+    }
+}
+
+/// Parses an address as `BB:AAAA`, `BBAAAA` or `AAAA` (in which case `default_bank` is used), or as
+/// a label from `symbols`. Labels take precedence over unprefixed hexadecimal numbers.
+fn parse_addr(s: &str, default_bank: u8, symbols: &SymbolTable) -> Result<u32, CommandError> {
+    let invalid = || CommandError::Usage(format!("invalid address: {}", s));
+    if strip_hex_prefix(s).len() == s.len() {
+        if let Some(addr) = symbols.resolve(s) {
+            return Ok(addr);
+        }
+    }
+    let hex = strip_hex_prefix(s);
+    let (bank, addr) = match hex.find(':') {
+        Some(colon) => {
+            let bank = try!(u8::from_str_radix(&hex[..colon], 16).map_err(|_| invalid()));
+            let addr = try!(u16::from_str_radix(&hex[colon + 1..], 16).map_err(|_| invalid()));
+            (bank, addr)
+        }
+        None => {
+            let value = try!(u32::from_str_radix(hex, 16).map_err(|_| invalid()));
+            if hex.len() > 6 || value > 0xffffff { return Err(invalid()) }
+            if hex.len() > 4 {
+                ((value >> 16) as u8, value as u16)
+            } else {
+                (default_bank, value as u16)
+            }
+        }
+    };
+    Ok(full_addr(bank, addr))
+}
+
+fn parse_byte(s: &str) -> Result<u8, CommandError> {
+    u8::from_str_radix(strip_hex_prefix(s), 16)
+        .map_err(|_| CommandError::Usage(format!("invalid byte: {}", s)))
+}
+
+/// Parses a DSP voice number (0-7).
+fn parse_voice(s: &str) -> Result<usize, CommandError> {
+    match s.parse() {
+        Ok(voice) if voice < VOICES => Ok(voice),
+        _ => Err(CommandError::Usage(format!("invalid voice (expected 0-{}): {}", VOICES - 1, s))),
+    }
+}
+
+/// Parses a decimal number.
+fn parse_number(s: &str) -> Result<u32, CommandError> {
+    s.parse().map_err(|_| CommandError::Usage(format!("invalid number: {}", s)))
+}
+
+fn parse_optional_number(s: Option<&&str>, default: u32) -> Result<u32, CommandError> {
+    match s {
+        Some(s) => parse_number(s),
+        None => Ok(default),
+    }
+}
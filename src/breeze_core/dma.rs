@@ -13,6 +13,7 @@
 
 use std::cell::Cell;
 
+use log_util::target;
 use snes::Peripherals;
 
 use wdc65816::Mem;
@@ -51,10 +52,13 @@ pub struct DmaChannel {
     /// * `l`: Line counter
     hdma_flags: u8,
     hdma_do_transfer: bool,
+    /// `$43xB/$43xF`: Unused register (readable and writable, but has no effect). Some games use
+    /// it as scratch RAM.
+    unused: u8,
 }
 
 impl_save_state!(DmaChannel { params, a_addr, a_addr_bank, b_addr, dma_size, hdma_indirect_bank,
-                              hdma_addr, hdma_flags, hdma_do_transfer } ignore {});
+                              hdma_addr, hdma_flags, hdma_do_transfer, unused } ignore {});
 
 impl Default for DmaChannel {
     fn default() -> Self {
@@ -68,6 +72,7 @@ impl Default for DmaChannel {
             hdma_addr: 0,
             hdma_flags: 0,
             hdma_do_transfer: false,
+            unused: 0xff,
         }
     }
 }
@@ -117,6 +122,12 @@ impl DmaChannel {
             0x5 => self.dma_size as u8,
             0x6 => (self.dma_size >> 8) as u8,
             0x7 => self.hdma_indirect_bank,
+            0x8 => self.hdma_addr as u8,
+            0x9 => (self.hdma_addr >> 8) as u8,
+            0xA => self.hdma_flags,
+            0xB | 0xF => self.unused,
+            // FIXME Open bus
+            0xC ... 0xE => 0,
             _ => panic!("invalid DMA channel register ${:02X}", reg),
         }
     }
@@ -131,6 +142,11 @@ impl DmaChannel {
             0x5 => self.dma_size = (self.dma_size & 0xff00) | val as u16,
             0x6 => self.dma_size = (self.dma_size & 0x00ff) | ((val as u16) << 8),
             0x7 => self.hdma_indirect_bank = val,
+            0x8 => self.hdma_addr = (self.hdma_addr & 0xff00) | val as u16,
+            0x9 => self.hdma_addr = (self.hdma_addr & 0x00ff) | ((val as u16) << 8),
+            0xA => self.hdma_flags = val,
+            0xB | 0xF => self.unused = val,
+            0xC ... 0xE => {}
             _ => panic!("invalid DMA channel register ${:02X}", reg),
         }
     }
@@ -151,7 +167,7 @@ impl DmaChannel {
     /// Returns the transfer size in bytes. Note that this limits the number of bytes, even if the
     /// transfer mode would transfer more bytes.
     fn transfer_size(&self) -> u32 {
-        if self.dma_size == 0xffff { 65536 } else { self.dma_size as u32 }
+        if self.dma_size == 0 { 65536 } else { self.dma_size as u32 }
     }
 
     fn transfer_mode(&self) -> TransferMode {
@@ -167,63 +183,36 @@ impl DmaChannel {
     }
 }
 
+impl TransferMode {
+    /// Returns the offsets added to the B-Bus address for each byte of a unit.
+    fn b_addr_offsets(&self) -> &'static [u8] {
+        match *self {
+            Single => &[0],
+            TwoInc => &[0, 1],
+            TwoNoInc => &[0, 0],
+            FourIncOnce => &[0, 0, 1, 1],
+            FourIncAlways => &[0, 1, 2, 3],
+            FourToggle => &[0, 1, 0, 1],
+        }
+    }
+}
+
 /// Perform a single DMA transfer according to `mode`. Reads and writes up to 4 bytes using the
 /// given read/write functions.
+///
+/// `b_addr` is the low byte of the B-Bus address (`$21xx`). Offsets added to it wrap around
+/// within the B-Bus.
 fn dma_transfer<R, W>(p: &mut Peripherals,
                       mode: TransferMode,
-                      b_addr: u16,
+                      b_addr: u8,
                       read_byte: &mut R,
                       write_byte: &mut W)
     where R: FnMut(&mut Peripherals, u16) -> u8,
           W: FnMut(&mut Peripherals, u8, u16) {
-    match mode {
-        Single => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-        }
-        TwoInc => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-        }
-        TwoNoInc => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-        }
-        FourIncOnce => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-        }
-        FourIncAlways => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-            let b = read_byte(p, b_addr + 2);
-            write_byte(p, b, b_addr + 2);
-            let b = read_byte(p, b_addr + 3);
-            write_byte(p, b, b_addr + 3);
-        }
-        FourToggle => {
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-            let b = read_byte(p, b_addr);
-            write_byte(p, b, b_addr);
-            let b = read_byte(p, b_addr + 1);
-            write_byte(p, b, b_addr + 1);
-        }
+    for &offset in mode.b_addr_offsets() {
+        let addr = 0x2100 | b_addr.wrapping_add(offset) as u16;
+        let b = read_byte(p, addr);
+        write_byte(p, b, addr);
     }
 }
 
@@ -235,15 +224,14 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
     // FIXME: "Now, after the pause, wait 2-8 master cycles to reach a whole multiple of 8 master
     // cycles since reset."
     // (Since this is pretty unpredictable behaviour, nothing should rely on it - I hope)
-    // FIXME: do_io_cycle is interfering badly with (H)DMA, we should save and restore p.cy
-    // (I think?). also do this in init_hdma.
 
+    // DMA accesses always take 8 master cycles, regardless of the memory region, so the cycles
+    // added by the memory accesses are dropped again afterwards
+    let io_cy = p.cy();
     let mut dma_cy = 8; // 8 cycles overhead for any DMA transaction
 
     for i in 0..8 {
         if channels & (1 << i) != 0 {
-            dma_cy += 8;    // 8 cycles per active channel
-
             let chan = p.dma[i];
             let write_to_a = chan.write_to_a();
             let mode = chan.transfer_mode();
@@ -252,14 +240,18 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
             
             let a_addr = Cell::new(chan.a_addr);
             let a_addr_inc = chan.a_addr_increment();
-            let b_addr = 0x2100 + chan.b_addr as u16;
+            let b_addr = chan.b_addr;
+
+            // 8 master cycles per channel, plus 8 per byte
+            let chan_cy = 8 + bytes.get() * 8;
+            dma_cy += chan_cy;
 
-            trace!("DMA on channel {} with {} bytes in mode {:?}, inc {} ({}), \
-                    A-Bus ${:02X}:{:04X}, B-Bus $00:{:04X}",
+            trace!(target: target::DMA,
+                   "DMA on channel {} with {} bytes in mode {:?}, inc {} ({}), \
+                    A-Bus ${:02X}:{:04X}, B-Bus $00:21{:02X}, {} master cycles",
                    i, bytes.get(), mode, a_addr_inc, if write_to_a {"B->A"} else {"A->B"}, a_bank,
-                   a_addr.get(), b_addr);
+                   a_addr.get(), b_addr, chan_cy);
 
-            // FIXME Decrement the channel's `dma_size` field
             let mut read_byte = |p: &mut Peripherals, b_addr| -> u8 {
                 if bytes.get() == 0 { return 0; }
                 let (src_bank, src_addr) = if write_to_a {
@@ -289,16 +281,19 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
                 }
             };
 
-            dma_cy += bytes.get() * 8;  // 8 master cycles per byte
             while bytes.get() > 0 {
                 dma_transfer(p, mode, b_addr, &mut read_byte, &mut write_byte);
             }
 
+            // The size counter ends up at 0 and the A-Bus address points after the transferred
+            // data
             p.dma[i].dma_size = 0;
+            p.dma[i].a_addr = a_addr.get();
         }
     }
+    p.set_cy(io_cy);
 
-    trace!("DMA completed after {} master clock cycles", dma_cy);
+    trace!(target: target::DMA, "DMA completed after {} master clock cycles", dma_cy);
 
     dma_cy
 }
@@ -313,8 +308,9 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
     // "Overhead is ~18 master cycles, plus 8 master cycles for each channel set for direct HDMA and
     // 24 master cycles for each channel set for indirect HDMA."
 
-    // FIXME: See do_dma about do_io_cycle affecting master cy
-    let mut cy = 0; 
+    // Like in `do_dma`, the memory access time is included in the overhead
+    let io_cy = p.cy();
+    let mut cy = 0;
 
     for i in 0..8 {
         if channel_mask & (1 << i) != 0 {
@@ -326,7 +322,7 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
             let repeat_and_count = p.load(i_bank, i_addr);
 
             // and bump table address to first entry
-            p.dma[i].hdma_addr += 1;
+            p.dma[i].hdma_addr = p.dma[i].hdma_addr.wrapping_add(1);
 
             // HDMA should terminate if .hdma_flags is 0 here or below.
             // Assuming the mechanism is just .hdma_flags itself, checked per scanline.
@@ -336,10 +332,10 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
             // If indirect, load first value address and bump table address to next line count.
             if p.dma[i].params & 0x40 != 0 {
-                let addr_low = p.load(i_bank, i_addr + 1);
-                let addr_high = p.load(i_bank, i_addr + 2);
+                let addr_low = p.load(i_bank, i_addr.wrapping_add(1));
+                let addr_high = p.load(i_bank, i_addr.wrapping_add(2));
 
-                p.dma[i].hdma_addr += 2;
+                p.dma[i].hdma_addr = p.dma[i].hdma_addr.wrapping_add(2);
                 
                 p.dma[i].dma_size = ((addr_high as u16) << 8) | (addr_low as u16);
 
@@ -350,6 +346,7 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
         }
     }
 
+    p.set_cy(io_cy);
     cy
 }
 
@@ -358,7 +355,8 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
     if channel_mask == 0 { return 0 }
 
-    // FIXME: See do_dma about do_io_cycle affecting master cy
+    // Like in `do_dma`, the memory access time is included in the 8 cycles per byte
+    let io_cy = p.cy();
     let mut cy = 18;
 
     for i in 0..8 {
@@ -378,7 +376,7 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
             let a_bank = if indirect { chan.hdma_indirect_bank } else { chan.a_addr_bank };
             let a_addr = Cell::new(if indirect { chan.dma_size } else { chan.hdma_addr });
 
-            let b_addr = 0x2100 + chan.b_addr as u16;
+            let b_addr = chan.b_addr;
 
             // Each round is a full round, so no counting. A->B only.
             let mut read_byte = |p: &mut Peripherals, _| -> u8 {
@@ -423,15 +421,15 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
                 if indirect {
                     // Apparently, we do this even if hdma_flags == 0 and we're about to stop HDMA.
-                    let low_byte = p.load(bank, addr + 1);
-                    let high_byte = p.load(bank, addr + 2);
+                    let low_byte = p.load(bank, addr.wrapping_add(1));
+                    let high_byte = p.load(bank, addr.wrapping_add(2));
                     
                     p.dma[i].dma_size = ((high_byte as u16) << 8) | (low_byte as u16);
 
-                    p.dma[i].hdma_addr += 3;
+                    p.dma[i].hdma_addr = p.dma[i].hdma_addr.wrapping_add(3);
                 }
                 else {
-                    p.dma[i].hdma_addr += 1;
+                    p.dma[i].hdma_addr = p.dma[i].hdma_addr.wrapping_add(1);
                 }
 
                 p.dma[i].hdma_do_transfer = true;
@@ -439,5 +437,6 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
         }
     }
 
+    p.set_cy(io_cy);
     cy
 }
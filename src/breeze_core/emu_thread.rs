@@ -0,0 +1,257 @@
+//! Running the emulator on a worker thread
+//!
+//! GUI frontends usually can't run emulation on their UI thread. An `EmuThread` moves a `Snes` to
+//! a worker thread and controls it through messages: The UI thread sends `Command`s and receives
+//! completed frames (along with their audio) and replies as `Output`s, so neither thread ever
+//! waits for the other.
+//!
+//! The worker installs its own joypads in both controller ports, which are controlled with
+//! `Command::SetInput`.
+
+use input::Peripheral;
+use save::SaveStateFormat;
+use snes::Snes;
+
+use breeze_backend::input::joypad::{JoypadImpl, JoypadState};
+use breeze_backend::pacing::{FramePacer, FrameTiming};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+/// Stack size of the emulator thread. `Snes` contains all emulated memory inline, and unoptimized
+/// builds keep several copies of it on the stack.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Messages sent to the emulator thread
+pub enum Command {
+    /// Starts (`true`) or stops (`false`) emulating frames in real time. The thread starts
+    /// stopped.
+    SetRunning(bool),
+    /// Emulates a single frame (useful while stopped)
+    Step,
+    /// Emulates as fast as possible instead of in real time while running
+    SetFastForward(bool),
+    /// Sets the speed relative to the console while running (`0.5` runs at half speed)
+    SetSpeed(f64),
+    /// Sets the buttons held on the joypad in a controller port (0 or 1)
+    SetInput(u8, JoypadState),
+    /// Resets the system
+    Reset,
+    /// Requests a save state, which is sent back as `Output::SaveState`
+    SaveState,
+    /// Restores a save state
+    LoadState(Vec<u8>),
+    /// Runs a function on the emulated system (to peek or poke memory, change settings, etc.)
+    Exec(Box<FnMut(&mut Snes) + Send>),
+    /// Stops the thread
+    Quit,
+}
+
+/// Messages sent by the emulator thread
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// A frame was completed
+    Frame {
+        /// Number of frames emulated so far (see `Snes::frame_count`)
+        number: u64,
+        /// The frame as RGB24 data (see `Renderer::render`)
+        data: Vec<u8>,
+        /// The audio produced while emulating the frame (see `AudioSink::write`)
+        audio: Vec<(i16, i16)>,
+    },
+    /// Reply to `Command::SaveState`
+    SaveState(Vec<u8>),
+    /// A command failed or emulation was stopped because of an error
+    Error(String),
+}
+
+/// A joypad controlled by `Command::SetInput`
+struct ThreadJoypad(Arc<Mutex<JoypadState>>);
+
+impl JoypadImpl for ThreadJoypad {
+    fn update_state(&mut self) -> JoypadState {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Handle of an emulator running on a worker thread. Dropping it stops the thread.
+pub struct EmuThread {
+    commands: Sender<Command>,
+    output: Receiver<Output>,
+    thread: Option<JoinHandle<Snes>>,
+}
+
+impl EmuThread {
+    /// Moves `snes` to a new thread. Its controller ports are replaced with joypads controlled by
+    /// `Command::SetInput`.
+    pub fn spawn(mut snes: Snes) -> io::Result<Self> {
+        let input = [Arc::new(Mutex::new(JoypadState::new())),
+                     Arc::new(Mutex::new(JoypadState::new()))];
+        for port in 0..2 {
+            let joypad = ThreadJoypad(input[port as usize].clone());
+            snes.peripherals_mut().input.ports[port] =
+                Some(Peripheral::new_joypad(Box::new(joypad)));
+        }
+
+        let (command_tx, command_rx) = mpsc::channel();
+        let (output_tx, output_rx) = mpsc::channel();
+        let thread = try!(thread::Builder::new()
+            .name("emulator".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let pacer = FramePacer::new(snes.frame_rate());
+                Worker {
+                    snes: snes,
+                    input: input,
+                    commands: command_rx,
+                    output: output_tx,
+                    running: false,
+                    pacer: pacer,
+                }.run()
+            }));
+
+        Ok(EmuThread {
+            commands: command_tx,
+            output: output_rx,
+            thread: Some(thread),
+        })
+    }
+
+    /// Sends a command to the thread. Returns `false` if the thread has stopped.
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// Returns the receiving end of the thread's output.
+    pub fn output(&self) -> &Receiver<Output> { &self.output }
+
+    /// Stops the thread and returns the emulated system, or the panic payload if the thread
+    /// panicked.
+    pub fn stop(mut self) -> thread::Result<Snes> {
+        self.send(Command::Quit);
+        self.thread.take().unwrap().join()
+    }
+}
+
+impl Drop for EmuThread {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.send(Command::Quit);
+            if thread.join().is_err() {
+                error!("emulator thread panicked");
+            }
+        }
+    }
+}
+
+/// State of the worker thread
+struct Worker {
+    snes: Snes,
+    input: [Arc<Mutex<JoypadState>>; 2],
+    commands: Receiver<Command>,
+    output: Sender<Output>,
+    running: bool,
+    pacer: FramePacer,
+}
+
+impl Worker {
+    fn run(mut self) -> Snes {
+        loop {
+            // Handle all pending commands before emulating the next frame. While stopped, wait
+            // for the next one.
+            let command = if self.running {
+                match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => break,
+                }
+            };
+
+            let keep_going = match command {
+                Some(command) => self.handle(command),
+                None => {
+                    let ok = self.frame();
+                    self.pacer.wait(FrameTiming::Timer);
+                    ok
+                }
+            };
+            if !keep_going { break }
+        }
+
+        self.snes
+    }
+
+    /// Executes a command. Returns `false` if the thread should stop.
+    fn handle(&mut self, command: Command) -> bool {
+        match command {
+            Command::SetRunning(running) => self.running = running,
+            Command::Step => return self.frame(),
+            Command::SetFastForward(fast_forward) => self.pacer.set_fast_forward(fast_forward),
+            Command::SetSpeed(speed) => self.pacer.set_speed(speed),
+            Command::SetInput(port, state) => match self.input.get(port as usize) {
+                Some(input) => *input.lock().unwrap() = state,
+                None => return self.send(Output::Error(format!("invalid port: {}", port))),
+            },
+            Command::Reset => self.snes.reset(),
+            Command::SaveState => {
+                let mut state = Vec::new();
+                let output = match self.snes.create_save_state(SaveStateFormat::Custom,
+                                                               &mut state) {
+                    Ok(()) => Output::SaveState(state),
+                    Err(e) => Output::Error(format!("couldn't create save state: {}", e)),
+                };
+                return self.send(output);
+            }
+            Command::LoadState(state) => {
+                if let Err(e) = self.snes.restore_save_state(SaveStateFormat::Custom,
+                                                             &mut &state[..]) {
+                    return self.send(Output::Error(format!("couldn't load save state: {}", e)));
+                }
+            }
+            Command::Exec(mut f) => {
+                f(&mut self.snes);
+                // The function might have changed the clock rates
+                let frame_rate = self.snes.frame_rate();
+                if frame_rate != self.pacer.frame_rate() {
+                    self.pacer.set_frame_rate(frame_rate);
+                }
+            }
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    /// Emulates a frame and sends it. Returns `false` if the UI thread is gone.
+    fn frame(&mut self) -> bool {
+        let mut data = None;
+        let result = self.snes.render_frame(|framebuf| {
+            data = Some(framebuf.to_vec());
+            Ok(Vec::new())
+        });
+
+        match (result, data) {
+            (Ok(_), Some(data)) => {
+                let number = self.snes.frame_count();
+                let audio = self.snes.peripherals().apu.audio().to_vec();
+                self.send(Output::Frame { number: number, data: data, audio: audio })
+            }
+            (Ok(_), None) => true,
+            (Err(e), _) => {
+                self.running = false;
+                self.send(Output::Error(format!("emulation stopped: {}", e)))
+            }
+        }
+    }
+
+    /// Sends output to the UI thread. Returns `false` if it's gone.
+    fn send(&self, output: Output) -> bool {
+        self.output.send(output).is_ok()
+    }
+}
@@ -0,0 +1,89 @@
+//! Register write log ("event viewer")
+//!
+//! An `EventLog` records every write to a PPU, APU, CPU IO or DMA register along with the
+//! scanline and dot at which it happened. Frontends can plot the events of a frame on a grid to
+//! see when raster effects are set up (and why they glitch).
+//!
+//! Note that the PPU may lag behind the CPU by a few cycles, depending on the accuracy settings
+//! (see `Accuracy::ppu_sync`). Set `ppu_sync` to 0 for exact positions.
+
+use std::mem;
+
+/// The kind of register written
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// `$2100-$213f`, `$2180-$2183`
+    Ppu,
+    /// `$2140-$217f`
+    Apu,
+    /// `$4016`, `$4200-$421f`
+    CpuIo,
+    /// `$4300-$437f`
+    Dma,
+}
+
+impl EventKind {
+    /// Returns the kind of register at `addr` (in bank `$00`), or `None` if writes to it aren't
+    /// logged.
+    pub fn from_addr(addr: u16) -> Option<Self> {
+        match addr {
+            0x2100 ... 0x213f | 0x2180 ... 0x2183 => Some(EventKind::Ppu),
+            0x2140 ... 0x217f => Some(EventKind::Apu),
+            0x4016 | 0x4200 ... 0x421f => Some(EventKind::CpuIo),
+            0x4300 ... 0x437f => Some(EventKind::Dma),
+            _ => None,
+        }
+    }
+}
+
+/// A register write
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Scanline (V-Counter) at the time of the write
+    pub v: u16,
+    /// Dot (H-Counter) at the time of the write
+    pub h: u16,
+    /// Address of the register
+    pub addr: u16,
+    pub value: u8,
+    pub kind: EventKind,
+}
+
+/// Records register writes, one frame at a time.
+#[derive(Clone, Debug, Default)]
+pub struct EventLog {
+    /// Events of the frame currently being emulated
+    current: Vec<Event>,
+    /// Events of the last completed frame
+    last: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self { Self::default() }
+
+    /// Records a write to `addr`. Writes to registers that aren't logged are ignored.
+    pub fn record(&mut self, v: u16, h: u16, addr: u16, value: u8) {
+        if let Some(kind) = EventKind::from_addr(addr) {
+            self.current.push(Event {
+                v: v,
+                h: h,
+                addr: addr,
+                value: value,
+                kind: kind,
+            });
+        }
+    }
+
+    /// Called at the start of a frame (V=0, H=0). The events recorded so far become the last
+    /// frame's events.
+    pub fn start_frame(&mut self) {
+        mem::swap(&mut self.current, &mut self.last);
+        self.current.clear();
+    }
+
+    /// Returns the events of the last completed frame, in the order they happened.
+    pub fn last_frame(&self) -> &[Event] { &self.last }
+
+    /// Returns the events recorded so far in the current frame.
+    pub fn current_frame(&self) -> &[Event] { &self.current }
+}
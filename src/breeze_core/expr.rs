@@ -0,0 +1,400 @@
+//! Debugger expressions
+//!
+//! A small expression language evaluated against the CPU registers and memory, used for
+//! breakpoint conditions like `A == 0x42 && [7E:0010] != 0`.
+//!
+//! * Numbers are decimal unless prefixed with `$` or `0x`. Inside `[...]`, they are hexadecimal
+//!   and can be given as `BB:AAAA`, just like debugger addresses.
+//! * Registers: `A`, `X`, `Y`, `S`, `D`, `DBR`, `PBR`, `PC` and `P` (case-insensitive).
+//! * `[ADDR]` reads the byte at the 24-bit address `ADDR` (which can be an expression, like
+//!   `[7E:0010 + X]`). I/O registers and unmapped addresses read as 0, since they can't be read
+//!   without side effects.
+//! * Labels from the symbol table evaluate to their address.
+//! * Operators, by increasing precedence: `||`, `&&`, `==` `!=` `<` `<=` `>` `>=`, `|`, `^`, `&`,
+//!   `+` `-`, and the unary `!` and `-`. Comparisons and logical operators evaluate to 0 or 1.
+//!
+//! All arithmetic is done on wrapping 32-bit unsigned integers.
+
+use snes::Snes;
+use symbols::SymbolTable;
+
+use std::fmt;
+
+/// A CPU register that can be used in expressions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register { A, X, Y, S, D, Dbr, Pbr, Pc, P }
+
+impl Register {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match &*name.to_uppercase() {
+            "A" => Register::A,
+            "X" => Register::X,
+            "Y" => Register::Y,
+            "S" => Register::S,
+            "D" => Register::D,
+            "DBR" => Register::Dbr,
+            "PBR" => Register::Pbr,
+            "PC" => Register::Pc,
+            "P" => Register::P,
+            _ => return None,
+        })
+    }
+
+    fn read(&self, snes: &Snes) -> u32 {
+        let cpu = snes.cpu();
+        (match *self {
+            Register::A => cpu.a,
+            Register::X => cpu.x,
+            Register::Y => cpu.y,
+            Register::S => cpu.s,
+            Register::D => cpu.d,
+            Register::Dbr => cpu.dbr as u16,
+            Register::Pbr => cpu.pbr as u16,
+            Register::Pc => cpu.pc,
+            Register::P => cpu.status().0 as u16,
+        }) as u32
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum UnOp { Not, Neg }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BinOp { Or, And, Eq, Ne, Lt, Le, Gt, Ge, BitOr, BitXor, BitAnd, Add, Sub }
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Num(u32),
+    Reg(Register),
+    /// Memory byte at the address
+    Mem(Box<Node>),
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, snes: &mut Snes) -> u32 {
+        match *self {
+            Node::Num(n) => n,
+            Node::Reg(reg) => reg.read(snes),
+            Node::Mem(ref addr) => {
+                let addr = addr.eval(snes);
+                snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16).unwrap_or(0) as u32
+            }
+            Node::Unary(op, ref e) => {
+                let value = e.eval(snes);
+                match op {
+                    UnOp::Not => (value == 0) as u32,
+                    UnOp::Neg => value.wrapping_neg(),
+                }
+            }
+            // Short-circuiting operators
+            Node::Binary(BinOp::Or, ref l, ref r) => {
+                (l.eval(snes) != 0 || r.eval(snes) != 0) as u32
+            }
+            Node::Binary(BinOp::And, ref l, ref r) => {
+                (l.eval(snes) != 0 && r.eval(snes) != 0) as u32
+            }
+            Node::Binary(op, ref l, ref r) => {
+                let (l, r) = (l.eval(snes), r.eval(snes));
+                match op {
+                    BinOp::Or | BinOp::And => unreachable!(),
+                    BinOp::Eq => (l == r) as u32,
+                    BinOp::Ne => (l != r) as u32,
+                    BinOp::Lt => (l < r) as u32,
+                    BinOp::Le => (l <= r) as u32,
+                    BinOp::Gt => (l > r) as u32,
+                    BinOp::Ge => (l >= r) as u32,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                }
+            }
+        }
+    }
+}
+
+/// A parsed expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr {
+    /// The source text, used for display
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Parses an expression. Labels are looked up in `symbols`.
+    pub fn parse(s: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let mut parser = Parser {
+            s: s,
+            pos: 0,
+            brackets: 0,
+            symbols: symbols,
+        };
+        let root = try!(parser.or());
+        parser.skip_whitespace();
+        if parser.pos != s.len() {
+            return Err(format!("unexpected `{}`", &s[parser.pos..]));
+        }
+        Ok(Expr {
+            source: s.trim().to_string(),
+            root: root,
+        })
+    }
+
+    /// Evaluates the expression. Needs mutable access to peek at memory.
+    pub fn eval(&self, snes: &mut Snes) -> u32 { self.root.eval(snes) }
+
+    /// Evaluates the expression as a condition (non-zero values are true).
+    pub fn is_true(&self, snes: &mut Snes) -> bool { self.eval(snes) != 0 }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Recursive descent parser, one method per precedence level
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    /// Nesting depth of `[...]`. Numbers are hexadecimal inside brackets.
+    brackets: u32,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str { &self.s[self.pos..] }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_left().len();
+    }
+
+    /// Consumes `token` if the input continues with it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes the first of `ops` the input continues with. Longer operators must come first.
+    fn eat_op<T: Copy>(&mut self, ops: &[(&str, T)]) -> Option<T> {
+        for &(token, op) in ops {
+            if self.eat(token) { return Some(op) }
+        }
+        None
+    }
+
+    /// Parses a left-associative binary operator level.
+    fn binary<F>(&mut self, ops: &[(&str, BinOp)], mut operand: F) -> Result<Node, String>
+    where F: FnMut(&mut Self) -> Result<Node, String> {
+        let mut node = try!(operand(self));
+        while let Some(op) = self.eat_op(ops) {
+            let rhs = try!(operand(self));
+            node = Node::Binary(op, Box::new(node), Box::new(rhs));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&[("||", BinOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&&", BinOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        let lhs = try!(self.bit_or());
+        let ops = [("==", BinOp::Eq), ("!=", BinOp::Ne), ("<=", BinOp::Le), ("<", BinOp::Lt),
+                   (">=", BinOp::Ge), (">", BinOp::Gt)];
+        match self.eat_op(&ops) {
+            Some(op) => Ok(Node::Binary(op, Box::new(lhs), Box::new(try!(self.bit_or())))),
+            None => Ok(lhs),
+        }
+    }
+
+    fn bit_or(&mut self) -> Result<Node, String> {
+        // Don't mistake `||` for two `|`
+        let mut node = try!(self.bit_xor());
+        while !self.rest().trim_left().starts_with("||") && self.eat("|") {
+            let rhs = try!(self.bit_xor());
+            node = Node::Binary(BinOp::BitOr, Box::new(node), Box::new(rhs));
+        }
+        Ok(node)
+    }
+
+    fn bit_xor(&mut self) -> Result<Node, String> {
+        self.binary(&[("^", BinOp::BitXor)], Self::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Node, String> {
+        let mut node = try!(self.sum());
+        while !self.rest().trim_left().starts_with("&&") && self.eat("&") {
+            let rhs = try!(self.sum());
+            node = Node::Binary(BinOp::BitAnd, Box::new(node), Box::new(rhs));
+        }
+        Ok(node)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        // `!=` is handled by `comparison`, but it can't start an operand anyway
+        match self.eat_op(&[("!", UnOp::Not), ("-", UnOp::Neg)]) {
+            Some(op) => Ok(Node::Unary(op, Box::new(try!(self.unary())))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        if self.eat("(") {
+            let node = try!(self.or());
+            if !self.eat(")") { return Err("expected `)`".to_string()) }
+            return Ok(node);
+        }
+        if self.eat("[") {
+            self.brackets += 1;
+            let node = try!(self.or());
+            self.brackets -= 1;
+            if !self.eat("]") { return Err("expected `]`".to_string()) }
+            return Ok(Node::Mem(Box::new(node)));
+        }
+
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '$'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(match rest.chars().next() {
+                Some(c) => format!("unexpected `{}`", c),
+                None => "unexpected end of expression".to_string(),
+            });
+        }
+        let token = &rest[..len];
+        self.pos += len;
+
+        // `BB:AAAA`
+        if self.rest().starts_with(':') {
+            let rest = self.rest();
+            let addr_len = rest[1..].find(|c: char| !c.is_digit(16)).unwrap_or(rest.len() - 1);
+            let addr = &rest[1..1 + addr_len];
+            let bank = token.trim_left_matches('$');
+            return match (u8::from_str_radix(bank, 16), u16::from_str_radix(addr, 16)) {
+                (Ok(bank), Ok(addr)) => {
+                    self.pos += 1 + addr_len;
+                    Ok(Node::Num((bank as u32) << 16 | addr as u32))
+                }
+                _ => Err(format!("invalid address: {}:{}", token, addr)),
+            };
+        }
+
+        if let Some(reg) = Register::from_name(token) {
+            return Ok(Node::Reg(reg));
+        }
+        if let Some(addr) = self.symbols.resolve(token) {
+            return Ok(Node::Num(addr));
+        }
+        let number = if token.starts_with('$') {
+            u32::from_str_radix(&token[1..], 16)
+        } else if token.starts_with("0x") || token.starts_with("0X") {
+            u32::from_str_radix(&token[2..], 16)
+        } else if self.brackets > 0 {
+            u32::from_str_radix(token, 16)
+        } else {
+            token.parse()
+        };
+        number.map(Node::Num).map_err(|_| format!("unknown register, label or number: {}", token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinOp, Expr, Node, Register, UnOp};
+    use symbols::SymbolTable;
+
+    fn parse(s: &str) -> Result<Node, String> {
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x7e0010, "player_x".to_string());
+        Expr::parse(s, &symbols).map(|expr| expr.root)
+    }
+
+    fn num(n: u32) -> Box<Node> { Box::new(Node::Num(n)) }
+
+    fn bin(op: BinOp, l: Box<Node>, r: Box<Node>) -> Box<Node> { Box::new(Node::Binary(op, l, r)) }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse("16"), Ok(Node::Num(16)));
+        assert_eq!(parse("$10"), Ok(Node::Num(16)));
+        assert_eq!(parse("0x10"), Ok(Node::Num(16)));
+        assert_eq!(parse("7E:0010"), Ok(Node::Num(0x7e0010)));
+        // Hexadecimal inside brackets
+        assert_eq!(parse("[10]"), Ok(Node::Mem(num(16))));
+        assert_eq!(parse("player_x"), Ok(Node::Num(0x7e0010)));
+        assert_eq!(parse("player_x+2"), Ok(*bin(BinOp::Add, num(0x7e0010), num(2))));
+        assert_eq!(parse("pc"), Ok(Node::Reg(Register::Pc)));
+        assert_eq!(parse(" DBR "), Ok(Node::Reg(Register::Dbr)));
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parse("1 + 2 & 3"), Ok(*bin(BinOp::BitAnd, bin(BinOp::Add, num(1), num(2)),
+                                                num(3))));
+        assert_eq!(parse("1 | 2 ^ 3 & 4"),
+                   Ok(*bin(BinOp::BitOr, num(1),
+                           bin(BinOp::BitXor, num(2), bin(BinOp::BitAnd, num(3), num(4))))));
+        assert_eq!(parse("1 == 2 && 3 || 4"),
+                   Ok(*bin(BinOp::Or, bin(BinOp::And, bin(BinOp::Eq, num(1), num(2)), num(3)),
+                           num(4))));
+        assert_eq!(parse("1 || 2 && 3"),
+                   Ok(*bin(BinOp::Or, num(1), bin(BinOp::And, num(2), num(3)))));
+        assert_eq!(parse("1 | 2 < 3"), Ok(*bin(BinOp::Lt, bin(BinOp::BitOr, num(1), num(2)),
+                                                num(3))));
+        assert_eq!(parse("-1 + !2"), Ok(*bin(BinOp::Add, Box::new(Node::Unary(UnOp::Neg, num(1))),
+                                              Box::new(Node::Unary(UnOp::Not, num(2))))));
+        // Left-associative
+        assert_eq!(parse("5 - 2 - 1"), Ok(*bin(BinOp::Sub, bin(BinOp::Sub, num(5), num(2)),
+                                                num(1))));
+        // `!=` and `<=` aren't split up
+        assert_eq!(parse("1 != 2"), Ok(*bin(BinOp::Ne, num(1), num(2))));
+        assert_eq!(parse("1 <= 2"), Ok(*bin(BinOp::Le, num(1), num(2))));
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(parse("(1 | 2) & 3"), Ok(*bin(BinOp::BitAnd, bin(BinOp::BitOr, num(1), num(2)),
+                                                  num(3))));
+        assert_eq!(parse("5 - (2 - 1)"), Ok(*bin(BinOp::Sub, num(5),
+                                                  bin(BinOp::Sub, num(2), num(1)))));
+        assert_eq!(parse("((1))"), Ok(Node::Num(1)));
+        assert_eq!(parse("[7E:0010 + X]"),
+                   Ok(Node::Mem(bin(BinOp::Add, num(0x7e0010), Box::new(Node::Reg(Register::X))))));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(""), Err("unexpected end of expression".to_string()));
+        assert_eq!(parse("1 +"), Err("unexpected end of expression".to_string()));
+        assert_eq!(parse("(1 + 2"), Err("expected `)`".to_string()));
+        assert_eq!(parse("[10"), Err("expected `]`".to_string()));
+        assert_eq!(parse("1 2"), Err("unexpected `2`".to_string()));
+        assert_eq!(parse("1 )"), Err("unexpected `)`".to_string()));
+        assert_eq!(parse("* 2"), Err("unexpected `*`".to_string()));
+        // Comparisons don't chain
+        assert_eq!(parse("1 == 2 == 3"), Err("unexpected `== 3`".to_string()));
+        assert_eq!(parse("enemy_x"),
+                   Err("unknown register, label or number: enemy_x".to_string()));
+        assert_eq!(parse("ZZ:0010"), Err("invalid address: ZZ:0010".to_string()));
+    }
+}
@@ -0,0 +1,138 @@
+//! Save state history for TAS workflows
+//!
+//! While a movie is recorded, the `Greenzone` keeps save states of past frames around, so that
+//! seeking backwards (eg. to change the input of an earlier frame) restores a nearby state and
+//! only has to emulate a few frames, instead of replaying the whole movie from power-on.
+//!
+//! The history is limited to a memory budget. When it's exceeded, states are thinned out so that
+//! recent frames stay densely covered while older frames are covered more sparsely.
+
+use snes::Snes;
+
+use breeze_backend::{BackendAction, BackendResult};
+
+use std::collections::BTreeMap;
+use std::io;
+
+/// Save state history, keyed by frame number.
+pub struct Greenzone {
+    /// Frame number -> save state taken after that many frames were emulated
+    states: BTreeMap<u64, Vec<u8>>,
+    /// A state is captured every `interval` frames
+    interval: u64,
+    /// Memory budget in bytes
+    max_bytes: usize,
+    /// Total size of all states
+    bytes: usize,
+}
+
+impl Greenzone {
+    /// Creates an empty greenzone that captures a state every `interval` frames and uses at most
+    /// `max_bytes` bytes of memory for them.
+    pub fn new(interval: u64, max_bytes: usize) -> Self {
+        assert!(interval > 0, "greenzone interval must not be 0");
+        Greenzone {
+            states: BTreeMap::new(),
+            interval: interval,
+            max_bytes: max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Returns the number of states stored.
+    pub fn len(&self) -> usize { self.states.len() }
+
+    pub fn is_empty(&self) -> bool { self.states.is_empty() }
+
+    /// Returns the memory used by the stored states (in bytes).
+    pub fn memory_usage(&self) -> usize { self.bytes }
+
+    /// Returns the frame numbers states are stored for, in ascending order.
+    pub fn frames<'a>(&'a self) -> Box<Iterator<Item=u64> + 'a> {
+        Box::new(self.states.keys().cloned())
+    }
+
+    /// Called once per frame (after it was emulated). Captures the state of `snes` if its frame
+    /// number is a multiple of the interval.
+    pub fn capture(&mut self, snes: &Snes) -> io::Result<()> {
+        let frame = snes.frame_count();
+        if frame % self.interval != 0 || self.states.contains_key(&frame) {
+            return Ok(());
+        }
+
+        let mut state = Vec::new();
+        try!(snes.save_state(&mut state));
+        self.bytes += state.len();
+        self.states.insert(frame, state);
+        self.evict();
+        Ok(())
+    }
+
+    /// Removes states until the memory budget is met. The first and the last state are always
+    /// kept. Otherwise, the state with the smallest gap to its predecessor relative to its age is
+    /// removed, which thins out old parts of the history first.
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes && self.states.len() > 2 {
+            let newest = *self.states.keys().next_back().unwrap();
+            // (frame, gap, age) of the best candidate so far
+            let mut victim: Option<(u64, u64, u64)> = None;
+            {
+                let mut frames = self.states.keys().cloned();
+                let mut prev = frames.next().unwrap();
+                for frame in frames.take_while(|&frame| frame != newest) {
+                    let (gap, age) = (frame - prev, newest - frame);
+                    // Compare `gap / age` without dividing
+                    let better = match victim {
+                        Some((_, best_gap, best_age)) => gap * best_age < best_gap * age,
+                        None => true,
+                    };
+                    if better {
+                        victim = Some((frame, gap, age));
+                    }
+                    prev = frame;
+                }
+            }
+
+            let state = self.states.remove(&victim.unwrap().0).unwrap();
+            self.bytes -= state.len();
+        }
+    }
+
+    /// Discards all states captured after `frame`. Call this when the input of `frame` is changed,
+    /// since those states don't match the new input anymore.
+    pub fn invalidate_after(&mut self, frame: u64) {
+        let stale: Vec<u64> = self.states.keys().cloned().filter(|&f| f > frame).collect();
+        for frame in stale {
+            let state = self.states.remove(&frame).unwrap();
+            self.bytes -= state.len();
+        }
+    }
+
+    /// Restores the latest state at or before `frame`. Returns the frame number of the restored
+    /// state, or `None` if there is no such state.
+    pub fn restore(&self, snes: &mut Snes, frame: u64) -> io::Result<Option<u64>> {
+        let (&state_frame, state) = match self.states.range(..frame + 1).next_back() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        try!(snes.load_state(&mut &state[..]));
+        snes.set_frame_count(state_frame);
+        Ok(Some(state_frame))
+    }
+
+    /// Seeks to the start of `frame` by restoring the closest state before it and emulating the
+    /// remaining frames. `render` is called for every emulated frame.
+    ///
+    /// Returns `false` if there is no state at or before `frame`.
+    pub fn seek<F>(&self, snes: &mut Snes, frame: u64, mut render: F) -> BackendResult<bool>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        if try!(self.restore(snes, frame)).is_none() {
+            return Ok(false);
+        }
+        while snes.frame_count() < frame {
+            try!(snes.render_frame(&mut render));
+        }
+        Ok(true)
+    }
+}
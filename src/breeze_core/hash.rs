@@ -0,0 +1,6 @@
+//! Stable hashing of emulator output
+//!
+//! The hash functions live in `breeze_backend::hash`, so that backends (eg. the capture renderer)
+//! produce the same hashes as the core.
+
+pub use breeze_backend::hash::{FnvHasher, hash_bytes, hash_samples};
@@ -0,0 +1,164 @@
+//! Input macros
+//!
+//! A macro is a short sequence of joypad states (one per frame), like a fighting game combo or a
+//! series of menu inputs. Unlike movies, macros aren't tied to a specific point in the game: They
+//! can be recorded at any time and played back whenever the user wants, on any port.
+//!
+//! While a macro plays, it replaces the input of the joypad it's played on. Playback and recording
+//! happen when input is latched, so they are exact to the frame.
+
+use breeze_backend::input::joypad::{BUTTONS, JoypadState};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A sequence of joypad states, one per frame.
+///
+/// The text format has one line per frame, listing the names of the held buttons separated by
+/// spaces (`-` if no button is held). A line can end in `*N` to repeat it `N` times, and `#`
+/// starts a comment:
+///
+/// ```text
+/// # Hadoken
+/// down *2
+/// down right
+/// right a
+/// - *10
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputMacro {
+    frames: Vec<JoypadState>,
+}
+
+impl InputMacro {
+    /// Creates an empty macro.
+    pub fn new() -> Self { InputMacro::default() }
+
+    /// Returns the joypad state of each frame.
+    pub fn frames(&self) -> &[JoypadState] { &self.frames }
+
+    /// Returns the length of the macro in frames.
+    pub fn len(&self) -> usize { self.frames.len() }
+
+    pub fn is_empty(&self) -> bool { self.frames.is_empty() }
+
+    /// Appends a frame to the macro.
+    pub fn push(&mut self, state: JoypadState) {
+        self.frames.push(state);
+    }
+}
+
+impl fmt::Display for InputMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut i = 0;
+        while i < self.frames.len() {
+            let state = self.frames[i];
+            let repeat = self.frames[i..].iter().take_while(|&&s| s == state).count();
+
+            let mut buttons = BUTTONS.iter().filter(|&&b| state.is_pressed(b)).map(|b| b.name());
+            match buttons.next() {
+                Some(first) => {
+                    try!(write!(f, "{}", first));
+                    for name in buttons {
+                        try!(write!(f, " {}", name));
+                    }
+                }
+                None => try!(write!(f, "-")),
+            }
+            if repeat > 1 {
+                try!(write!(f, " *{}", repeat));
+            }
+            try!(writeln!(f));
+
+            i += repeat;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InputMacro {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut input_macro = InputMacro::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            if line.trim().is_empty() { continue }
+
+            let mut state = JoypadState::new();
+            let mut repeat = 1;
+            for word in line.split_whitespace() {
+                if word.starts_with('*') {
+                    repeat = try!(word[1..].parse()
+                        .map_err(|_| format!("line {}: invalid repeat count: {}", i + 1, word)));
+                } else if word != "-" {
+                    let button = try!(word.parse().map_err(|e| format!("line {}: {}", i + 1, e)));
+                    state.set(button, true);
+                }
+            }
+
+            for _ in 0..repeat {
+                input_macro.push(state);
+            }
+        }
+        Ok(input_macro)
+    }
+}
+
+/// Macro playback and recording state of a controller port
+#[derive(Default)]
+pub struct MacroState {
+    /// The macro being played and the index of the next frame to play
+    playing: Option<(InputMacro, usize)>,
+    /// The macro being recorded
+    recording: Option<InputMacro>,
+}
+
+impl MacroState {
+    pub fn is_playing(&self) -> bool { self.playing.is_some() }
+
+    pub fn is_recording(&self) -> bool { self.recording.is_some() }
+
+    pub fn play(&mut self, input_macro: InputMacro) {
+        self.playing = if input_macro.is_empty() { None } else { Some((input_macro, 0)) };
+    }
+
+    pub fn stop_playing(&mut self) {
+        self.playing = None;
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputMacro::new());
+    }
+
+    pub fn stop_recording(&mut self) -> Option<InputMacro> {
+        self.recording.take()
+    }
+
+    /// Returns the state the macro being played wants to have latched this frame.
+    pub fn current(&self) -> Option<JoypadState> {
+        self.playing.as_ref().map(|&(ref input_macro, pos)| input_macro.frames[pos])
+    }
+
+    /// Called at the end of each frame with the state that was latched in that frame. Records the
+    /// state and advances playback.
+    pub fn end_frame(&mut self, latched: JoypadState) {
+        if let Some(ref mut input_macro) = self.recording {
+            input_macro.push(latched);
+        }
+
+        let done = match self.playing {
+            Some((ref input_macro, ref mut pos)) => {
+                *pos += 1;
+                *pos == input_macro.len()
+            }
+            None => false,
+        };
+        if done {
+            self.playing = None;
+        }
+    }
+}
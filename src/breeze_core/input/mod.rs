@@ -12,12 +12,19 @@
 //! light gun into port 1 (this doesn't work because the `IOBit` line of port 1 isn't connected to
 //! the PPUs counter latch line). The backend should warn on these.
 
+mod macros;
 mod port;
 
+pub use self::macros::InputMacro;
 pub use self::port::Peripheral;
 
+use self::macros::MacroState;
+use log_util::target;
 use record::{Recorder, Replayer};
+use breeze_backend::input::joypad::{JoypadButton, JoypadState};
 
+use std::io;
+use std::mem;
 use std::ops::{Index, IndexMut};
 
 /// Represents the 2 controller ports on the SNES
@@ -80,11 +87,30 @@ pub struct Input {
     /// Current latch state. Peripherals will have `set_latch` called when this changes.
     latch: bool,
     latched_this_frame: bool,
+    /// Macro playback and recording of both ports
+    macros: [MacroState; 2],
+    /// Buttons held on each port regardless of the joypad's input
+    held: [JoypadState; 2],
+    /// The joypad state last latched on each port, as seen by the game
+    latched: [Option<JoypadState>; 2],
 }
 
-impl_save_state!(Input { auto_read_data, latch, latched_this_frame } ignore { ports, mode });
+impl_save_state!(Input { auto_read_data, latch, latched_this_frame }
+                 ignore { ports, mode, macros, held, latched });
 
 impl Input {
+    /// Plugs `peripheral` into `port` (or unplugs it if `None`) and returns the peripheral that
+    /// was plugged in before.
+    pub fn plug(&mut self, port: u8, peripheral: Option<Peripheral>) -> Option<Peripheral> {
+        if port == 0 {
+            if let Some(Peripheral::Multitap { .. }) = peripheral {
+                warn!(target: target::INPUT, "multitap plugged into port 1, most games only \
+                                              support it in port 2");
+            }
+        }
+        mem::replace(&mut self.ports[port], peripheral)
+    }
+
     /// Start recording input to a `Write` implementor, often a file.
     ///
     /// When reading data from a controller port, the recorder will write that data to the given
@@ -119,9 +145,71 @@ impl Input {
         }
     }
 
+    /// Plays a macro on the joypad in `port`. Its input is replaced by the macro, starting with
+    /// the next time input is latched, until the macro ends.
+    pub fn play_macro(&mut self, port: u8, input_macro: InputMacro) {
+        self.macros[port as usize].play(input_macro);
+    }
+
+    /// Stops playing a macro on `port`.
+    pub fn stop_macro(&mut self, port: u8) {
+        self.macros[port as usize].stop_playing();
+    }
+
+    pub fn is_playing_macro(&self, port: u8) -> bool {
+        self.macros[port as usize].is_playing()
+    }
+
+    /// Starts recording the input latched on `port` (including the input of macros played on it)
+    /// into a new macro.
+    pub fn start_macro_recording(&mut self, port: u8) {
+        self.macros[port as usize].start_recording();
+    }
+
+    /// Stops recording a macro on `port` and returns it. Returns `None` if no macro was being
+    /// recorded.
+    pub fn stop_macro_recording(&mut self, port: u8) -> Option<InputMacro> {
+        self.macros[port as usize].stop_recording()
+    }
+
+    pub fn is_recording_macro(&self, port: u8) -> bool {
+        self.macros[port as usize].is_recording()
+    }
+
+    /// Holds (`true`) or releases a button on the joypad in `port`. Held buttons are pressed in
+    /// addition to the joypad's input (and macros) until they're released.
+    pub fn set_held(&mut self, port: u8, button: JoypadButton, held: bool) {
+        self.held[port as usize].set(button, held);
+    }
+
+    /// Toggles holding a button on `port`. Returns whether the button is held now.
+    pub fn toggle_held(&mut self, port: u8, button: JoypadButton) -> bool {
+        let held = !self.held[port as usize].is_pressed(button);
+        self.set_held(port, button, held);
+        held
+    }
+
+    /// Releases all buttons held on `port`.
+    pub fn release_held(&mut self, port: u8) {
+        self.held[port as usize] = JoypadState::new();
+    }
+
+    /// Returns the buttons held on `port`.
+    pub fn held(&self, port: u8) -> JoypadState {
+        self.held[port as usize]
+    }
+
+    /// Returns the joypad state the game saw when it last latched input on `port`, after applying
+    /// macros, held buttons and movie input. This is updated once per frame and is meant for
+    /// input displays. Returns `None` if no joypad is plugged into the port.
+    pub fn latched_state(&self, port: u8) -> Option<JoypadState> {
+        self.latched[port as usize]
+    }
+
     pub fn new_frame(&mut self) {
         if self.latch {
-            once!(warn!("latch still active from older frame (might interfere with \
+            once!(warn!(target: target::INPUT,
+                        "latch still active from older frame (might interfere with \
                          recording); latch might be changed by emulator!"));
         }
 
@@ -131,6 +219,11 @@ impl Input {
         }
 
         self.latched_this_frame = false;
+        for port in 0..2 {
+            if let Some(state) = self.latched[port] {
+                self.macros[port].end_frame(state);
+            }
+        }
         match self.mode {
             InputMode::Normal
             | InputMode::Recorded(_) => {
@@ -145,8 +238,9 @@ impl Input {
         match self.ports[port] {
             Some(ref mut cpa) => {
                 if !self.latched_this_frame {
-                    once!(warn!("reading data lines without prior latching (this can interfere \
-                                 with input recording)"));
+                    once!(warn!(target: target::INPUT,
+                                "reading data lines without prior latching (this can \
+                                 interfere with input recording)"));
                 }
 
                 cpa.read_bit()
@@ -189,25 +283,53 @@ impl Input {
                 // Latch changed state
                 if new_latch {
                     if self.latched_this_frame {
-                        once!(warn!("already latched input in this frame! (this might interfere \
-                                     with recording)"));
+                        once!(warn!(target: target::INPUT,
+                                    "already latched input in this frame! (this might \
+                                     interfere with recording)"));
                     }
                     self.latched_this_frame = true;
                 }
 
+                let mut replay_ended = false;
                 match self.mode {
                     InputMode::Normal | InputMode::Recorded(..) => {
-                        self.ports.for_each_peripheral(|p| p.set_latch(new_latch))
+                        self.ports.for_each_peripheral(|p| p.set_latch(new_latch));
+                        if new_latch {
+                            self.process_latched();
+                        }
+                    }
+                    InputMode::Replayed(ref mut replayer) => {
+                        // The peripherals still latch (and poll their input source), but their
+                        // state is then replaced with the recorded one
+                        self.ports.for_each_peripheral(|p| p.set_latch(new_latch));
+                        if new_latch {
+                            if let Err(e) = replayer.replay_frame(&mut self.ports) {
+                                if e.kind() == io::ErrorKind::UnexpectedEof {
+                                    info!(target: target::INPUT, "replay finished");
+                                } else {
+                                    error!(target: target::INPUT, "error when replaying input: {}",
+                                           e);
+                                }
+                                replay_ended = true;
+                            }
+                        }
                     }
-                    InputMode::Replayed(_) => {}
+                }
+                if replay_ended {
+                    self.mode = InputMode::Normal;
+                }
+
+                if new_latch {
+                    self.latched[0] = self.ports.0.as_ref().and_then(Peripheral::joypad_state);
+                    self.latched[1] = self.ports.1.as_ref().and_then(Peripheral::joypad_state);
                 }
 
                 if new_latch {
                     // Input state was updated. Record it if necessary.
                     if let InputMode::Recorded(ref mut recorder) = self.mode {
                         if let Err(e) = recorder.record_frame(&self.ports) {
-                            error!("error when recording input: {}", e);
-                            error!("recording will be aborted!");
+                            error!(target: target::INPUT, "error when recording input: {}", e);
+                            error!(target: target::INPUT, "recording will be aborted!");
                             // TODO Actually do that
                         }
                     }
@@ -220,6 +342,53 @@ impl Input {
         }
     }
 
+    /// Called right after the peripherals latched their input. Replaces the latched state with the
+    /// input of the macros being played and adds held buttons.
+    fn process_latched(&mut self) {
+        for port in 0..2 {
+            if let Some(ref mut peripheral) = self.ports[port] {
+                if let Some(state) = peripheral.joypad_state() {
+                    let state = self.macros[port as usize].current().unwrap_or(state);
+                    peripheral.set_joypad_state(state | self.held[port as usize]);
+                }
+            }
+        }
+    }
+
+    /// Called on writes to `$4201`. Bit 6 drives the `IOBit` line of port 0, bit 7 the one of
+    /// port 1.
+    pub fn set_io_port(&mut self, value: u8) {
+        if let Some(ref mut p) = self.ports.0 {
+            p.set_io_bit(value & 0x40 != 0);
+        }
+        if let Some(ref mut p) = self.ports.1 {
+            p.set_io_bit(value & 0x80 != 0);
+        }
+    }
+
+    /// Reads the state of the `IOBit` lines for `$4213`. Bits that aren't connected to a
+    /// peripheral read as 1 (the caller has to mask this with the value written to `$4201`).
+    pub fn read_io_port(&mut self) -> u8 {
+        let port0 = match self.ports.0 {
+            Some(ref mut p) => p.read_io_bit(),
+            None => true,
+        };
+        let port1 = match self.ports.1 {
+            Some(ref mut p) => p.read_io_bit(),
+            None => true,
+        };
+        0x3f | (port0 as u8) << 6 | (port1 as u8) << 7
+    }
+
+    /// Called on every pixel. Returns `true` if the peripheral in port 1 wants to latch the PPU's
+    /// H/V counters (only port 1's `IOBit` is connected to the latch).
+    pub fn update_hv_latch(&mut self) -> bool {
+        match self.ports.1 {
+            Some(ref mut p) => p.update_hv_latch(),
+            None => false,
+        }
+    }
+
     /// Called when auto joypad read is enabled and it's time to do one.
     ///
     /// On the real console, auto joypad read takes place in the first few scanline in V-Blank. We
@@ -24,6 +24,22 @@ pub enum Peripheral {
         state: JoypadState,
     },
 
+    /// The MP5 multitap, which connects up to 4 joypads to a single port (usually port 2).
+    ///
+    /// The port's `IOBit` line selects which pair of joypads is read: The first two are read on
+    /// `Data1`/`Data2` while it's 1, the last two while it's 0. While the latch is active, `Data2`
+    /// reads as 1, which is how games detect the multitap.
+    Multitap {
+        /// The joypad implementations, `None` for empty slots (which read as 0s)
+        imps: [Option<Box<JoypadImpl>>; 4],
+        /// Current state of each joypad
+        states: [JoypadState; 4],
+        /// State of the port's `IOBit` line
+        iobit: bool,
+        /// State of the latch line
+        latch: bool,
+    },
+
     // TODO: Mouse, Light Guns, etc.
 }
 
@@ -38,6 +54,67 @@ impl Peripheral {
             state: JoypadState::new(),
         }
     }
+
+    /// Creates a multitap with the given joypads plugged into it. Only the first 4 joypads are
+    /// used, remaining slots are left empty.
+    pub fn new_multitap(joypads: Vec<Box<JoypadImpl>>) -> Self {
+        let mut imps = [None, None, None, None];
+        for (slot, imp) in imps.iter_mut().zip(joypads) {
+            *slot = Some(imp);
+        }
+
+        Multitap {
+            imps: imps,
+            states: [JoypadState::new(); 4],
+            iobit: true,
+            latch: false,
+        }
+    }
+}
+
+/// State access
+impl Peripheral {
+    /// Returns the state latched by a joypad, or `None` if this isn't a joypad.
+    pub fn joypad_state(&self) -> Option<JoypadState> {
+        match *self {
+            Joypad { state, .. } => Some(state),
+            Multitap { .. } => None,
+        }
+    }
+
+    /// Replaces the state latched by a joypad. Does nothing if this isn't a joypad.
+    pub fn set_joypad_state(&mut self, new_state: JoypadState) {
+        match *self {
+            Joypad { ref mut state, .. } => *state = new_state,
+            Multitap { .. } => {}
+        }
+    }
+
+    /// Returns the states latched by the joypads plugged into a multitap, or `None` if this isn't
+    /// a multitap. Empty slots are `None`.
+    pub fn multitap_states(&self) -> Option<[Option<JoypadState>; 4]> {
+        match *self {
+            Multitap { ref imps, ref states, .. } => {
+                let mut result = [None; 4];
+                for i in 0..4 {
+                    if imps[i].is_some() {
+                        result[i] = Some(states[i]);
+                    }
+                }
+                Some(result)
+            }
+            Joypad { .. } => None,
+        }
+    }
+
+    /// Replaces the states latched by the joypads plugged into a multitap. Does nothing if this
+    /// isn't a multitap.
+    pub fn set_multitap_states(&mut self, new_states: [JoypadState; 4]) {
+        match *self {
+            Multitap { ref mut states, .. } => *states = new_states,
+            Joypad { .. } => {}
+        }
+    }
 }
 
 /// CPU interface
@@ -47,12 +124,23 @@ impl Peripheral {
     ///
     /// Auto-joypad mode writes 1 and then 0 to the latch before reading data.
     pub fn set_latch(&mut self, latch: bool) {
-        if latch {
-            match *self {
-                Joypad { ref mut imp, ref mut state } => {
+        match *self {
+            Joypad { ref mut imp, ref mut state } => {
+                if latch {
                     *state = imp.update_state();
                 }
             }
+            Multitap { ref mut imps, ref mut states, latch: ref mut tap_latch, .. } => {
+                if latch {
+                    for (imp, state) in imps.iter_mut().zip(states.iter_mut()) {
+                        *state = match *imp {
+                            Some(ref mut imp) => imp.update_state(),
+                            None => JoypadState::new(),
+                        };
+                    }
+                }
+                *tap_latch = latch;
+            }
         }
     }
 
@@ -69,22 +157,32 @@ impl Peripheral {
                 // The Data2 line is always 0 (it's not used by single joypads)
                 (bit, false)
             }
+            Multitap { ref imps, ref mut states, iobit, latch } => {
+                if latch {
+                    return (false, true);
+                }
+
+                let first = if iobit { 0 } else { 2 };
+                let mut read = |i: usize| imps[i].is_some() && states[i].read_bit();
+                (read(first), read(first + 1))
+            }
         }
     }
 
     /// Sets the bit written out to the `IOBit` line.
     ///
-    /// This is called when the SNES writes to the highest 2 bits of `$4213`. (If these are set to
-    /// 0, reads from `$4201` will always return 0. If these are set to 1, then reads from `$4201`
+    /// This is called when the SNES writes to the highest 2 bits of `$4201`. (If these are set to
+    /// 0, reads from `$4213` will always return 0. If these are set to 1, then reads from `$4213`
     /// will return whatever value is written to the respective `IOBit` lines.)
-    pub fn set_io_bit(&mut self, _iobit: bool) {
+    pub fn set_io_bit(&mut self, new_iobit: bool) {
         match *self {
             Joypad { .. } => {}
+            Multitap { ref mut iobit, .. } => *iobit = new_iobit,
         }
     }
 
-    /// Called on reads from `$4201` when the respective bit in `$4213` is set to 1 (if the bit in
-    /// `$4213` is set to 0, all reads will return 0 and this method is not called).
+    /// Called on reads from `$4213`. If the respective bit in `$4201` is set to 0, the value
+    /// returned here is ignored and the bit reads as 0.
     ///
     /// This should return the current status of the `IOBit` line.
     ///
@@ -95,24 +193,26 @@ impl Peripheral {
         match *self {
             // FIXME: `IOBit` isn't connected. Does it read as true or false then?
             Joypad { .. } => true,
+            // The multitap doesn't drive the line, so it reads back what the console outputs
+            Multitap { iobit, .. } => iobit,
         }
     }
 
     /// This will be called on every pixel. When this method returns `true`, the PPU's H/V Counters
     /// will be latched.
     ///
-    /// Note that the returned value is not returned on read from the I/O Port (`$4201`). You have
+    /// Note that the returned value is not returned on read from the I/O Port (`$4213`). You have
     /// to make sure that this method and `read_io_bit` return correct values.
     pub fn update_hv_latch(&mut self) -> bool {
         match *self {
-            Joypad { .. } => false,
+            Joypad { .. } | Multitap { .. } => false,
         }
     }
 
     /// Called once after every frame
     pub fn next_frame(&mut self) {
         match *self {
-            Joypad { .. } => {},
+            Joypad { .. } | Multitap { .. } => {},
         }
     }
 }
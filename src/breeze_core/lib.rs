@@ -3,19 +3,49 @@
 
 #[macro_use] extern crate log;
 extern crate byteorder;
-extern crate slicevec;
 
 #[macro_use] #[no_link] extern crate byte_array;
 #[macro_use] extern crate libsavestate;
 extern crate wdc65816;
 extern crate spc700;
 extern crate breeze_backend;
+#[cfg(feature = "serialize")] #[macro_use] extern crate serde_derive;
 
-#[macro_use] mod log_util;
+#[macro_use] pub mod log_util;
+pub mod accuracy;
+pub mod benchmark;
+pub mod chrome_trace;
+pub mod clock;
+pub mod config;
+pub mod crash_report;
+pub mod debug_server;
+pub mod debugger;
 pub mod dma;
+pub mod emu_thread;
+pub mod events;
+pub mod expr;
+pub mod greenzone;
+pub mod hash;
 pub mod record;
+pub mod rewind;
 pub mod ppu;
+pub mod profiler;
 pub mod input;
+pub mod ram_init;
+pub mod ram_search;
 pub mod rom;
+pub mod run;
 pub mod save;
+pub mod scenario;
+pub mod screenshot;
 pub mod snes;
+pub mod stack_check;
+pub mod state_diff;
+pub mod storage;
+pub mod stats;
+pub mod symbols;
+pub mod test_rom;
+pub mod trace;
+pub mod triggers;
+pub mod trace_diff;
+pub mod watch;
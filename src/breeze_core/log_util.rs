@@ -1,8 +1,26 @@
-//! Logging utility macros
+//! Logging utility macros, per-component log levels and crash diagnostics
+//!
+//! Messages about the emulated hardware are logged to one target per component (see `target`),
+//! so their verbosity can be controlled separately: `RUST_LOG=breeze_core::dma=trace` shows every
+//! DMA transfer without the CPU's instruction trace. The CPU and APU cores are separate crates and
+//! log to their crate names (`wdc65816` and `spc700`), which count as part of the component.
+//!
+//! On top of that, frontends can change the level of a component at runtime (for example from the
+//! `[log]` section of the config) with `set_component_level`. The installed logger has to consult
+//! `component_level` for that to have an effect.
+
+use events::EventKind;
+
+use wdc65816::{Cpu, Mem};
+use log::LogLevelFilter;
 
 use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::thread;
 
 /// Evaluates the given expression once (when first reached).
@@ -17,6 +35,115 @@ macro_rules! once {
     }}
 }
 
+/// Log targets of the emulated components (use them with the `target:` form of the log macros)
+pub mod target {
+    pub const CPU: &'static str = "breeze_core::cpu";
+    pub const PPU: &'static str = "breeze_core::ppu";
+    pub const APU: &'static str = "breeze_core::apu";
+    pub const DMA: &'static str = "breeze_core::dma";
+    pub const INPUT: &'static str = "breeze_core::input";
+}
+
+/// An emulated component with its own log target
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Component {
+    Cpu,
+    Ppu,
+    Apu,
+    Dma,
+    Input,
+}
+
+/// All components, in the order they appear in the config
+pub const COMPONENTS: [Component; 5] = [
+    Component::Cpu, Component::Ppu, Component::Apu, Component::Dma, Component::Input,
+];
+
+impl Component {
+    /// Returns the log targets of this component. Messages are logged to these targets or to
+    /// targets below them (eg. `spc700::dsp` or `breeze_core::ppu::bg`).
+    pub fn targets(self) -> &'static [&'static str] {
+        match self {
+            Component::Cpu => &[target::CPU, "wdc65816"],
+            Component::Ppu => &[target::PPU],
+            Component::Apu => &[target::APU, "spc700"],
+            Component::Dma => &[target::DMA],
+            Component::Input => &[target::INPUT],
+        }
+    }
+
+    /// Returns the component a message logged to `target` belongs to.
+    pub fn of_target(target: &str) -> Option<Component> {
+        // `spc700` and `spc700::dsp` match, `spc700foo` doesn't
+        let matches = |t: &str| {
+            target.starts_with(t) &&
+                (target.len() == t.len() || target[t.len()..].starts_with("::"))
+        };
+        COMPONENTS.iter().cloned().find(|c| c.targets().iter().any(|&t| matches(t)))
+    }
+
+    /// Returns the name used in the config and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Component::Cpu => "cpu",
+            Component::Ppu => "ppu",
+            Component::Apu => "apu",
+            Component::Dma => "dma",
+            Component::Input => "input",
+        }
+    }
+}
+
+impl FromStr for Component {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        COMPONENTS.iter().cloned().find(|c| c.name() == s).ok_or_else(|| {
+            format!("unknown component: {} (expected `cpu`, `ppu`, `apu`, `dma` or `input`)", s)
+        })
+    }
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Level overrides of the components, indexed like `COMPONENTS`. `0` means no override, otherwise
+/// the value is the `LogLevelFilter` plus 1.
+static COMPONENT_LEVELS: [AtomicUsize; 5] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Sets the log level of a component, overriding the logger's own configuration. `None` removes
+/// the override.
+pub fn set_component_level(component: Component, level: Option<LogLevelFilter>) {
+    let value = level.map_or(0, |level| level as usize + 1);
+    COMPONENT_LEVELS[component as usize].store(value, Ordering::Relaxed);
+}
+
+/// Returns the level set with `set_component_level`, if any.
+pub fn component_level(component: Component) -> Option<LogLevelFilter> {
+    match COMPONENT_LEVELS[component as usize].load(Ordering::Relaxed) {
+        0 => None,
+        1 => Some(LogLevelFilter::Off),
+        2 => Some(LogLevelFilter::Error),
+        3 => Some(LogLevelFilter::Warn),
+        4 => Some(LogLevelFilter::Info),
+        5 => Some(LogLevelFilter::Debug),
+        _ => Some(LogLevelFilter::Trace),
+    }
+}
+
+/// Parses a log level (`off`, `error`, `warn`, `info`, `debug` or `trace`).
+pub fn parse_level(s: &str) -> Result<LogLevelFilter, String> {
+    s.parse::<LogLevelFilter>().map_err(|_| {
+        format!("invalid log level: {} (expected `off`, `error`, `warn`, `info`, `debug` or \
+                 `trace`)", s)
+    })
+}
+
 /// Wraps a `Cell<T>` and writes its contents to stdout if dropped while panicking.
 pub struct LogOnPanic<T: Copy + Debug> {
     name: &'static str,
@@ -46,3 +173,184 @@ impl<T: Copy + Debug> Drop for LogOnPanic<T> {
         }
     }
 }
+
+/// Fixed-size buffer that overwrites its oldest element when full.
+#[derive(Clone, Debug)]
+struct Ring<T: Copy> {
+    buf: Vec<T>,
+    capacity: usize,
+    /// Index of the oldest element (once the buffer is full)
+    next: usize,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(capacity: usize) -> Self {
+        Ring {
+            buf: Vec::with_capacity(capacity),
+            capacity: capacity,
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, t: T) {
+        if self.buf.len() < self.capacity {
+            self.buf.push(t);
+        } else if self.capacity != 0 {
+            self.buf[self.next] = t;
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+
+    /// Iterates over the elements, oldest first.
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=&'a T> + 'a> {
+        Box::new(self.buf[self.next..].iter().chain(self.buf[..self.next].iter()))
+    }
+
+    fn len(&self) -> usize { self.buf.len() }
+}
+
+/// CPU state before an instruction was executed
+#[derive(Copy, Clone, Debug)]
+struct InstrEntry {
+    /// `$OOBBAAAA`: Opcode, PBR and PC
+    op_pc: u32,
+    a: u16,
+    x: u16,
+    y: u16,
+    s: u16,
+    d: u16,
+    dbr: u8,
+    /// Processor status, the emulation flag is stored in bit 8
+    p: u16,
+}
+
+/// A CPU memory access
+#[derive(Copy, Clone, Debug)]
+struct MemAccess {
+    /// `$WWBBAAAA`: Bank and address, `WW` is 1 for writes
+    addr: u32,
+    value: u8,
+}
+
+/// A write to an I/O register (see `EventKind`)
+#[derive(Copy, Clone, Debug)]
+struct IoWrite {
+    /// Scanline and dot at the time of the write
+    v: u16,
+    h: u16,
+    addr: u16,
+    value: u8,
+}
+
+/// Default number of instructions kept by a `CrashLog`
+pub const CRASH_LOG_INSTRUCTIONS: usize = 4096;
+/// Default number of memory accesses kept by a `CrashLog`
+pub const CRASH_LOG_ACCESSES: usize = 256;
+/// Number of I/O register writes kept by a `CrashLog`
+pub const CRASH_LOG_IO_WRITES: usize = 256;
+
+/// Remembers the last few executed instructions (with the CPU registers), memory accesses and I/O
+/// register writes and writes them to stderr if dropped while panicking (just like
+/// `LogOnPanic`). This includes panics caused by illegal opcodes and unimplemented features, so
+/// bug reports come with the code that led up to the crash. `crash_report` includes it in crash
+/// report files.
+#[derive(Clone, Debug)]
+pub struct CrashLog {
+    instrs: Ring<InstrEntry>,
+    accesses: Ring<MemAccess>,
+    io_writes: Ring<IoWrite>,
+}
+
+impl Default for CrashLog {
+    fn default() -> Self { CrashLog::new(CRASH_LOG_INSTRUCTIONS, CRASH_LOG_ACCESSES) }
+}
+
+impl CrashLog {
+    /// Creates a log that keeps the given number of instructions and memory accesses.
+    pub fn new(instructions: usize, accesses: usize) -> Self {
+        CrashLog {
+            instrs: Ring::new(instructions),
+            accesses: Ring::new(accesses),
+            io_writes: Ring::new(CRASH_LOG_IO_WRITES),
+        }
+    }
+
+    /// Records an instruction that is about to be executed, along with the CPU's registers.
+    pub fn record_instruction<M: Mem>(&mut self, opcode: u8, cpu: &Cpu<M>) {
+        self.instrs.push(InstrEntry {
+            op_pc: (opcode as u32) << 24 | (cpu.pbr as u32) << 16 | cpu.pc as u32,
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            s: cpu.s,
+            d: cpu.d,
+            dbr: cpu.dbr,
+            p: (cpu.emulation() as u16) << 8 | cpu.status().0 as u16,
+        });
+    }
+
+    /// Records a load or store.
+    pub fn record_access(&mut self, bank: u8, addr: u16, value: u8, write: bool) {
+        self.accesses.push(MemAccess {
+            addr: (write as u32) << 24 | (bank as u32) << 16 | addr as u32,
+            value: value,
+        });
+    }
+
+    /// Records a write to an I/O register at the given PPU position. Writes to addresses that
+    /// aren't I/O registers are ignored.
+    pub fn record_io_write(&mut self, v: u16, h: u16, addr: u16, value: u8) {
+        if EventKind::from_addr(addr).is_some() {
+            self.io_writes.push(IoWrite {
+                v: v,
+                h: h,
+                addr: addr,
+                value: value,
+            });
+        }
+    }
+
+    /// Writes the recorded instructions, memory accesses and I/O register writes, oldest first.
+    /// The registers of the last instruction are the state before the crashing instruction was
+    /// executed.
+    pub fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(writeln!(w, "last {} instructions:", self.instrs.len()));
+        try!(self.dump_instructions(w, self.instrs.len()));
+        try!(writeln!(w, "last {} memory accesses:", self.accesses.len()));
+        for access in self.accesses.iter() {
+            let kind = if access.addr >> 24 != 0 { "store" } else { "load " };
+            try!(writeln!(w, "{} ${:02X}:{:04X} = ${:02X}", kind, (access.addr >> 16) as u8,
+                access.addr as u16, access.value));
+        }
+        try!(writeln!(w, "last {} I/O register writes:", self.io_writes.len()));
+        for write in self.io_writes.iter() {
+            try!(writeln!(w, "V:{:3} H:{:3} ${:04X} = ${:02X}", write.v, write.h, write.addr,
+                write.value));
+        }
+        Ok(())
+    }
+
+    /// Writes the last `count` recorded instructions (or all of them, if fewer were recorded),
+    /// oldest first.
+    pub fn dump_instructions<W: Write>(&self, w: &mut W, count: usize) -> io::Result<()> {
+        let skip = self.instrs.len().saturating_sub(count);
+        for e in self.instrs.iter().skip(skip) {
+            try!(writeln!(w, "${:02X}:{:04X} {:02X}  a:{:04X} x:{:04X} y:{:04X} s:{:04X} \
+                              d:{:04X} dbr:{:02X} p:{:02X} emu:{}",
+                (e.op_pc >> 16) as u8, e.op_pc as u16, e.op_pc >> 24, e.a, e.x, e.y, e.s, e.d,
+                e.dbr, e.p as u8, e.p >> 8));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CrashLog {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = writeln!(stderr, "[panic log] crash trace follows")
+                .and_then(|_| self.dump(&mut stderr));
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! Background layer rendering
 
 use super::{Ppu, SnesRgb};
+use super::viewer::DebugImage;
 
 /// BG layer scanline cache.
 ///
@@ -9,6 +10,9 @@ use super::{Ppu, SnesRgb};
 #[derive(Default)]
 pub struct BgCache {
     layers: [BgLayerCache; 4],
+    /// Settings of all BG layers, collected once at the start of the scanline (`None` if they
+    /// need to be collected again)
+    settings: Option<[BgSettings; 4]>,
 }
 
 /// Data that's stored in the BG layer caches for a single pixel
@@ -29,6 +33,10 @@ struct BgLayerCache {
     valid: bool,
     /// Stores the prerendered scanline
     scanline: [CachedPixel; super::SCREEN_WIDTH as usize],
+    /// In the hi-res modes 5 and 6, `scanline` stores the right (odd) half of each pixel, which
+    /// is displayed on the main screen. This stores the left (even) half, which is displayed on
+    /// the subscreen.
+    hires_scanline: [CachedPixel; super::SCREEN_WIDTH as usize],
 }
 
 impl Default for BgLayerCache {
@@ -36,29 +44,24 @@ impl Default for BgLayerCache {
         BgLayerCache {
             valid: false,
             scanline: [CachedPixel::default(); super::SCREEN_WIDTH as usize],
+            hires_scanline: [CachedPixel::default(); super::SCREEN_WIDTH as usize],
         }
     }
 }
 
-impl BgLayerCache {
-    /// Invalidates the cache of this layer, causing it to be rebuilt on next access.
-    #[allow(dead_code)] // FIXME Use in the right locations
-    fn invalidate(&mut self) {
-        self.valid = false;
-    }
-}
-
 impl BgCache {
-    /// Invalidates the BG cache of all layers
-    fn invalidate_all(&mut self) {
+    /// Invalidates the BG cache of all layers and the collected settings
+    pub fn invalidate_all(&mut self) {
         self.layers[0].valid = false;
         self.layers[1].valid = false;
         self.layers[2].valid = false;
         self.layers[3].valid = false;
+        self.settings = None;
     }
 }
 
 /// Collected background settings
+#[derive(Copy, Clone, Default)]
 struct BgSettings {
     /// Mosaic pixel size (1-16). 1 = Normal pixels.
     /// FIXME: I think there's a difference between disabled and enabled with 1x1 mosaic size in
@@ -73,8 +76,10 @@ struct BgSettings {
     tilemap_mirror_h: bool,
     /// When `true`, this BGs tilemaps are repeated downwards
     tilemap_mirror_v: bool,
-    /// If `true`, BG tiles are 16x16 pixels. If `false`, they are 8x8 pixels.
-    tile_size_16: bool,
+    /// If `true`, BG tiles are 16 pixels wide. If `false`, they are 8 pixels wide.
+    tile_width_16: bool,
+    /// If `true`, BG tiles are 16 pixels high. If `false`, they are 8 pixels high.
+    tile_height_16: bool,
     /// Character Data start address in VRAM
     chr_addr: u16,
     /// Horizontal scroll offset. Moves the BG layer to the left by some number of pixels.
@@ -83,6 +88,23 @@ struct BgSettings {
     vofs: u16,
 }
 
+impl BgSettings {
+    /// Returns the width and height of this BG's tiles in pixels.
+    fn tile_size(&self) -> (u16, u16) {
+        (if self.tile_width_16 { 16 } else { 8 }, if self.tile_height_16 { 16 } else { 8 })
+    }
+
+    /// Calculates the VRAM word address of the tilemap entry for the given tile coordinates.
+    fn tilemap_entry_addr(&self, tile_x: u16, tile_y: u16) -> u16 {
+        let (sx, sy) = (!self.tilemap_mirror_h, !self.tilemap_mirror_v);
+        self.tilemap_word_addr |
+            ((tile_y & 0x1f) << 5) |
+            (tile_x & 0x1f) |
+            if sy {(tile_y & 0x20) << if sx {6} else {5}} else {0} |
+            if sx {(tile_x & 0x20) << 5} else {0}
+    }
+}
+
 /// Unpacked tilemap entry for internal (rendering) use.
 ///
 /// A tilemap entry is 2 bytes large and contains informations about a single background layer tile.
@@ -102,7 +124,7 @@ struct TilemapEntry {
 
 impl Ppu {
     /// Determines whether the given BG layer (1-4) is enabled
-    fn bg_enabled(&self, bg: u8, subscreen: bool) -> bool {
+    pub fn bg_enabled(&self, bg: u8, subscreen: bool) -> bool {
         let reg = if subscreen { self.ts } else { self.tm };
         reg & (1 << (bg - 1)) != 0
     }
@@ -127,6 +149,56 @@ impl Ppu {
         }
     }
 
+    /// Reads the raw 16-bit tilemap word at the given VRAM word address.
+    fn tilemap_word(&self, word_address: u16) -> u16 {
+        let byte_address = word_address << 1;
+        (self.vram[byte_address + 1] as u16) << 8 | self.vram[byte_address] as u16
+    }
+
+    /// Returns `true` if the BG layers are rendered at twice the horizontal resolution (modes 5
+    /// and 6).
+    pub fn hires_bg(&self) -> bool {
+        match self.bg_mode() {
+            5 | 6 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the current BG mode uses offset-per-tile (modes 2, 4 and 6).
+    fn offset_per_tile(&self) -> bool {
+        match self.bg_mode() {
+            2 | 4 | 6 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the scroll offsets of BG1 or BG2 for the tile column at screen position `x` in an
+    /// offset-per-tile mode.
+    ///
+    /// BG3's tilemap stores a horizontal offset (first row) and a vertical offset (second row) for
+    /// each column, which replace the layer's scroll registers if bit 13 (BG1) or 14 (BG2) is set.
+    /// In mode 4, there's only one row, and bit 15 selects the offset an entry replaces. The
+    /// leftmost column can't be changed. Replaced horizontal offsets keep the layer's fine scroll
+    /// (the low 3 bits).
+    fn opt_scroll(&self, bg_num: u8, bg: &BgSettings, bg3: &BgSettings, x: u16) -> (u16, u16) {
+        let opt_x = x + (bg.hofs & 7);
+        if opt_x < 8 { return (bg.hofs, bg.vofs) }
+
+        let valid_bit = if bg_num == 1 { 0x2000 } else { 0x4000 };
+        let tile_x = (opt_x - 8).wrapping_add(bg3.hofs & !7) / 8;
+        let tile_y = bg3.vofs / 8;
+        let entry = self.tilemap_word(bg3.tilemap_entry_addr(tile_x, tile_y));
+        let (h, v) = if self.bg_mode() == 4 {
+            if entry & 0x8000 != 0 { (0, entry) } else { (entry, 0) }
+        } else {
+            (entry, self.tilemap_word(bg3.tilemap_entry_addr(tile_x, tile_y.wrapping_add(1))))
+        };
+
+        let hofs = if h & valid_bit != 0 { (h & 0x3f8) | (bg.hofs & 7) } else { bg.hofs };
+        let vofs = if v & valid_bit != 0 { v & 0x3ff } else { bg.vofs };
+        (hofs, vofs)
+    }
+
     /// Collects properties of a background layer
     fn bg_settings(&self, bg: u8) -> BgSettings {
         // The BGxSC register for our background layer
@@ -162,23 +234,27 @@ impl Ppu {
             tilemap_word_addr: ((bgsc as u16 & 0xfc) >> 2) << 10,
             tilemap_mirror_h: bgsc & 0b01 == 0, // inverted bit value
             tilemap_mirror_v: bgsc & 0b10 == 0, // inverted bit value
-            tile_size_16: match self.bg_mode() {
-                // "If the BG character size for BG1/BG2/BG3/BG4 bit is set, then the BG is made of
-                // 16x16 tiles. Otherwise, 8x8 tiles are used. However, note that Modes 5 and 6
-                // always use 16-pixel wide tiles, and Mode 7 always uses 8x8 tiles."
+            // "If the BG character size for BG1/BG2/BG3/BG4 bit is set, then the BG is made of
+            // 16x16 tiles. Otherwise, 8x8 tiles are used. However, note that Modes 5 and 6
+            // always use 16-pixel wide tiles, and Mode 7 always uses 8x8 tiles."
+            // BGMODE: `4321----` (`-` = not relevant here) - Use 16x16 tiles?
+            tile_width_16: match self.bg_mode() {
                 5 | 6 => true,
                 7 => false,
-                _ => {
-                    // BGMODE: `4321----` (`-` = not relevant here) - Use 16x16 tiles?
-                    self.bgmode & (1 << (bg + 3)) != 0
-                }
+                _ => self.bgmode & (1 << (bg + 3)) != 0,
             },
+            tile_height_16: self.bg_mode() != 7 && self.bgmode & (1 << (bg + 3)) != 0,
             chr_addr: (chr as u16) << 12,
             hofs: hofs,
             vofs: vofs,
         }
     }
 
+    /// Collects the properties of all 4 background layers
+    fn all_bg_settings(&self) -> [BgSettings; 4] {
+        [self.bg_settings(1), self.bg_settings(2), self.bg_settings(3), self.bg_settings(4)]
+    }
+
     /// Returns the number of color bits in the given BG layer in the current BG mode (2, 4, 7 or
     /// 8). To get the number of colors, use `1 << color_bits_for_bg`.
     ///
@@ -236,6 +312,20 @@ impl Ppu {
         }
     }
 
+    /// Returns whether 256-color BGs use direct color instead of the palette (`$2130` bit 0).
+    fn direct_color(&self) -> bool { self.cgwsel & 0x01 != 0 }
+
+    /// Looks up the color of a non-transparent pixel of a BG layer (1-4, not in mode 7), given the
+    /// tile's palette number and the palette index read from the character data.
+    fn bg_tile_color(&self, bg_num: u8, palette_num: u8, palette_index: u8) -> SnesRgb {
+        if self.direct_color() && self.color_bits_for_bg(bg_num) == 8 {
+            SnesRgb::from_direct_color(palette_index, palette_num)
+        } else {
+            let palette_base = self.palette_base_for_bg_tile(bg_num, palette_num);
+            self.cgram.get_color(palette_base + palette_index)
+        }
+    }
+
     fn render_mode7_scanline(&mut self) {
         // TODO Figure out how to integrate EXTBG
         assert!(self.setini & 0x40 == 0, "NYI: Mode 7 EXTBG");
@@ -300,6 +390,7 @@ impl Ppu {
 
             let rgb = match palette_index {
                 0 => None,
+                _ if self.direct_color() => Some(SnesRgb::from_direct_color(palette_index, 0)),
                 _ => Some(self.cgram.get_color(palette_index)),
             };
 
@@ -334,18 +425,65 @@ impl Ppu {
         }
 
         let mut x = self.x;
-        let y = self.scanline;
-        let bg = self.bg_settings(bg_num);
-        let tile_size = if bg.tile_size_16 { 16 } else { 8 };
+        // Interlaced hi-res modes have twice the vertical resolution
+        let y = if self.hires_bg() && self.interlace() {
+            self.scanline * 2 + self.interlace_field as u16
+        } else {
+            self.scanline
+        };
+        let settings = match self.bg_cache.settings {
+            Some(settings) => settings,
+            None => {
+                let settings = self.all_bg_settings();
+                self.bg_cache.settings = Some(settings);
+                settings
+            }
+        };
+        let bg = settings[bg_num as usize - 1];
         let (hofs, vofs) = (bg.hofs, bg.vofs);
-        let (sx, sy) = (!bg.tilemap_mirror_h, !bg.tilemap_mirror_v);
 
         let color_bits = self.color_bits_for_bg(bg_num);
-        if color_bits == 8 {
-            // can use direct color mode
-            debug_assert!(self.cgwsel & 0x01 == 0, "NYI: direct color mode");
+
+        let opt = bg_num <= 2 && self.offset_per_tile();
+        let (tile_w, tile_h) = bg.tile_size();
+        if opt || self.hires_bg() || tile_w != tile_h {
+            // The scroll offsets can change every column, the layer has twice the resolution or
+            // the tiles aren't square, so look up each pixel separately
+            let bg3 = settings[2];
+            let hires = self.hires_bg();
+            let cache = |pixel: Option<(u8, SnesRgb)>| match pixel {
+                Some((priority, color)) => CachedPixel {
+                    priority: priority,
+                    color: Some(color),
+                },
+                None => CachedPixel::default(),
+            };
+            while x < super::SCREEN_WIDTH as u16 {
+                let (hofs, vofs) = if opt {
+                    self.opt_scroll(bg_num, &bg, &bg3, x)
+                } else {
+                    (hofs, vofs)
+                };
+                let bg_y = y.wrapping_add(vofs);
+                if hires {
+                    // The scroll offset is counted in normal pixels, which are 2 layer pixels wide
+                    let bg_x = x.wrapping_add(hofs) << 1;
+                    let left = cache(self.bg_pixel(bg_num, &bg, bg_x, bg_y));
+                    let right = cache(self.bg_pixel(bg_num, &bg, bg_x | 1, bg_y));
+                    let layer = &mut self.bg_cache.layers[bg_num as usize - 1];
+                    layer.hires_scanline[x as usize] = left;
+                    layer.scanline[x as usize] = right;
+                } else {
+                    let pixel = cache(self.bg_pixel(bg_num, &bg, x.wrapping_add(hofs), bg_y));
+                    self.bg_cache.layers[bg_num as usize - 1].scanline[x as usize] = pixel;
+                }
+                x += 1;
+            }
+            return;
         }
 
+        // 8x8 and 16x16 tiles: Render a tile at a time
+        let tile_size = tile_w as u8;
         let mut tile_x = x.wrapping_add(hofs) / tile_size as u16;
         let tile_y = y.wrapping_add(vofs) / tile_size as u16;
         let mut off_x = (x.wrapping_add(hofs) % tile_size as u16) as u8;
@@ -355,21 +493,12 @@ impl Ppu {
             // Render current tile (`tile_x`) starting at `off_x` until the end of the tile,
             // then go to next tile and set `off_x = 0`
 
-            // Calculate the VRAM word address, where the tilemap entry for our tile is stored
-            let tilemap_entry_word_address =
-                bg.tilemap_word_addr |
-                ((tile_y & 0x1f) << 5) |
-                (tile_x & 0x1f) |
-                if sy {(tile_y & 0x20) << if sx {6} else {5}} else {0} |
-                if sx {(tile_x & 0x20) << 5} else {0};
-            let tilemap_entry = self.tilemap_entry(tilemap_entry_word_address);
+            let tilemap_entry = self.tilemap_entry(bg.tilemap_entry_addr(tile_x, tile_y));
 
             let bitplane_start_addr =
                 (bg.chr_addr << 1) +
                 (tilemap_entry.tile_number * 8 * color_bits as u16);   // 8 bytes per bitplane
 
-            let palette_base = self.palette_base_for_bg_tile(bg_num, tilemap_entry.palette);
-
             while off_x < tile_size && x < super::SCREEN_WIDTH as u16 {
                 let palette_index = self.read_chr_entry(color_bits,
                                                         bitplane_start_addr,
@@ -379,7 +508,7 @@ impl Ppu {
 
                 let rgb = match palette_index {
                     0 => None,
-                    _ => Some(self.cgram.get_color(palette_base + palette_index)),
+                    _ => Some(self.bg_tile_color(bg_num, tilemap_entry.palette, palette_index)),
                 };
 
                 self.bg_cache.layers[bg_num as usize - 1].scanline[x as usize] = CachedPixel {
@@ -395,6 +524,54 @@ impl Ppu {
         }
     }
 
+    /// Looks up the pixel of a BG layer (1-4, not in mode 7) at screen position `x` of the current
+    /// scanline, without using the cache. `x` may be outside of the visible area.
+    ///
+    /// Returns the tile priority and the color of the pixel, or `None` if it is transparent.
+    pub fn bg_pixel_at(&self, bg_num: u8, x: i16) -> Option<(u8, SnesRgb)> {
+        let bg = self.bg_settings(bg_num);
+        let bg_x = (x as u16).wrapping_add(bg.hofs);
+        let bg_y = self.scanline.wrapping_add(bg.vofs);
+        if self.hires_bg() {
+            // Use the right half of the pixel, like the main screen
+            self.bg_pixel(bg_num, &bg, bg_x << 1 | 1, bg_y)
+        } else {
+            self.bg_pixel(bg_num, &bg, bg_x, bg_y)
+        }
+    }
+
+    /// Looks up the pixel at the (scrolled) position `bg_x`, `bg_y` on a BG layer.
+    ///
+    /// Returns the tile priority and the color of the pixel, or `None` if it is transparent.
+    fn bg_pixel(&self, bg_num: u8, bg: &BgSettings, bg_x: u16, bg_y: u16)
+                -> Option<(u8, SnesRgb)> {
+        let (tile_w, tile_h) = bg.tile_size();
+        let tilemap_entry = self.tilemap_entry(bg.tilemap_entry_addr(bg_x / tile_w,
+                                                                     bg_y / tile_h));
+        let color_bits = self.color_bits_for_bg(bg_num);
+
+        // Larger tiles consist of several 8x8 tiles: `TILE`, `TILE+1`, `TILE+16` and `TILE+17`.
+        // Flipping also swaps the 8x8 tiles.
+        let off_x = if tilemap_entry.hflip { tile_w - 1 - bg_x % tile_w } else { bg_x % tile_w };
+        let off_y = if tilemap_entry.vflip { tile_h - 1 - bg_y % tile_h } else { bg_y % tile_h };
+        let tile_number = (tilemap_entry.tile_number + off_x / 8 + off_y / 8 * 16) & 0x3ff;
+        let bitplane_start_addr = (bg.chr_addr << 1)
+            .wrapping_add(tile_number.wrapping_mul(8 * color_bits as u16));
+        let palette_index = self.read_chr_entry(color_bits,
+                                                bitplane_start_addr,
+                                                8,
+                                                ((off_x % 8) as u8, (off_y % 8) as u8),
+                                                (false, false));
+
+        match palette_index {
+            0 => None,
+            _ => {
+                let rgb = self.bg_tile_color(bg_num, tilemap_entry.palette, palette_index);
+                Some((tilemap_entry.priority, rgb))
+            }
+        }
+    }
+
     /// Main entry point into the BG layer renderer.
     ///
     /// Lookup the color of the given background layer (1-4) at the current pixel, using the given
@@ -425,12 +602,116 @@ impl Ppu {
             self.bg_cache.layers[bg_num as usize - 1].valid = true;
         }
 
-        // Cache must be valid now, so we can access the pixel we need:
-        let pixel = &self.bg_cache.layers[bg_num as usize - 1].scanline[self.x as usize];
+        // Cache must be valid now, so we can access the pixel we need. In the hi-res modes, the
+        // subscreen shows the left half of each pixel.
+        let layer = &self.bg_cache.layers[bg_num as usize - 1];
+        let pixel = if subscreen && self.hires_bg() {
+            &layer.hires_scanline[self.x as usize]
+        } else {
+            &layer.scanline[self.x as usize]
+        };
         if pixel.priority == prio {
             pixel.color
         } else {
             None
         }
     }
+
+    /// Renders the complete tilemap of a BG layer (1-4) into `image`, ignoring scrolling.
+    /// Transparent pixels show the backdrop color. If the layer doesn't exist in the current BG
+    /// mode, a single tile filled with the backdrop color is rendered.
+    pub fn render_tilemap_view(&self, bg_num: u8, image: &mut DebugImage) {
+        debug_assert!(bg_num >= 1 && bg_num <= 4);
+        let backdrop = self.cgram.get_color(0).to_rgb(&self.color_lut);
+
+        // Number of BG layers with tilemaps in each mode (offset-per-tile data isn't a tilemap)
+        let layers = match self.bg_mode() {
+            0 => 4,
+            1 => 3,
+            2 ... 5 => 2,
+            _ => 1,
+        };
+        if bg_num > layers {
+            image.clear(8, 8, backdrop);
+            return;
+        }
+
+        if self.bg_mode() == 7 {
+            self.render_mode7_tilemap_view(image);
+            return;
+        }
+
+        let bg = self.bg_settings(bg_num);
+        let (tile_w, tile_h) = bg.tile_size();
+        let (tile_w, tile_h) = (tile_w as u32, tile_h as u32);
+        let (sx, sy) = (!bg.tilemap_mirror_h, !bg.tilemap_mirror_v);
+        let width_tiles: u16 = if sx { 64 } else { 32 };
+        let height_tiles: u16 = if sy { 64 } else { 32 };
+        let color_bits = self.color_bits_for_bg(bg_num);
+
+        image.clear(width_tiles as u32 * tile_w, height_tiles as u32 * tile_h, backdrop);
+        for tile_y in 0..height_tiles {
+            for tile_x in 0..width_tiles {
+                // Same addressing as in `render_bg_scanline`
+                let tilemap_entry_word_address =
+                    bg.tilemap_word_addr |
+                    ((tile_y & 0x1f) << 5) |
+                    (tile_x & 0x1f) |
+                    if sy {(tile_y & 0x20) << if sx {6} else {5}} else {0} |
+                    if sx {(tile_x & 0x20) << 5} else {0};
+                let entry = self.tilemap_entry(tilemap_entry_word_address);
+
+                for y in 0..tile_h {
+                    for x in 0..tile_w {
+                        // 16x16 tiles consist of 4 8x8 tiles: `TILE`, `TILE+1`, `TILE+16` and
+                        // `TILE+17`. Flipping also swaps the 8x8 tiles.
+                        let sub_x = (x / 8) as u16 ^ if entry.hflip && tile_w == 16 {1} else {0};
+                        let sub_y = (y / 8) as u16 ^ if entry.vflip && tile_h == 16 {1} else {0};
+                        let tile_number = entry.tile_number + sub_x + sub_y * 16;
+                        let chr_addr = (bg.chr_addr << 1)
+                            .wrapping_add(tile_number.wrapping_mul(8 * color_bits as u16));
+
+                        let palette_index = self.read_chr_entry(color_bits,
+                                                                chr_addr,
+                                                                8,
+                                                                ((x % 8) as u8, (y % 8) as u8),
+                                                                (entry.vflip, entry.hflip));
+                        if palette_index != 0 {
+                            let rgb = self.bg_tile_color(bg_num, entry.palette, palette_index);
+                            image.set_pixel(tile_x as u32 * tile_w + x,
+                                            tile_y as u32 * tile_h + y,
+                                            rgb.to_rgb(&self.color_lut));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Renders the 128x128 tile mode 7 tilemap (1024x1024 pixels).
+    fn render_mode7_tilemap_view(&self, image: &mut DebugImage) {
+        let backdrop = self.cgram.get_color(0).to_rgb(&self.color_lut);
+        image.clear(1024, 1024, backdrop);
+        for tile_y in 0..128u16 {
+            for tile_x in 0..128u16 {
+                // See `render_mode7_scanline` for the address calculations
+                let tile_number = self.vram[(tile_y << 8) | (tile_x << 1)] as u16;
+                for y in 0..8u16 {
+                    for x in 0..8u16 {
+                        let palette_index = self.vram[(tile_number << 7) | (y << 4) | (x << 1) | 1];
+                        if palette_index != 0 {
+                            let rgb = if self.direct_color() {
+                                SnesRgb::from_direct_color(palette_index, 0)
+                            } else {
+                                self.cgram.get_color(palette_index)
+                            };
+                            image.set_pixel((tile_x * 8 + x) as u32,
+                                            (tile_y * 8 + y) as u32,
+                                            rgb.to_rgb(&self.color_lut));
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
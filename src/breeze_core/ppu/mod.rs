@@ -9,19 +9,27 @@
 pub mod cgram;
 mod bg;
 pub mod oam;
+mod output;
 mod rendering;
 mod regs;
 mod rgb;
 mod sprites;
+pub mod viewer;
+mod widescreen;
 
-pub use self::rgb::{Rgb, SnesRgb};
+pub use self::regs::{PpuRegisters, RegisterChange, RegisterHistory};
+pub use self::rgb::{ColorCorrection, ColorLut, Rgb, SnesRgb};
+pub use self::output::HIRES_WIDTH;
+pub use self::widescreen::MAX_WIDESCREEN_MARGIN;
 
 use self::sprites::SpriteRenderState;
 use self::bg::BgCache;
 use self::oam::Oam;
 use self::cgram::Cgram;
+use self::widescreen::Widescreen;
+use log_util::target;
 
-pub use breeze_backend::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use breeze_backend::ppu::{OVERSCAN_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
 
 /// VRAM size in Bytes
 pub const VRAM_SIZE: usize = 64 * 1024;
@@ -36,8 +44,9 @@ pub struct Ppu {
     /// component and the third byte is the blue component. The fourth byte is then the red
     /// component of the second pixel (at coordinate `(1,0)`), and so on.
     ///
+    /// In hi-res scanlines, each pixel is the average of its 2 halves. Hi-res, overscan and
+    /// interlaced frames are stored in full in `ext_framebuf`.
     // FIXME The size can change depending on the PPU config, make sure all frames fit in
-    // FIXME How would this work in high resolution modes?
     pub framebuf: FrameBuf,
 
     /// Opaque state object used by the render code. This value may change between frames/scanlines
@@ -47,6 +56,24 @@ pub struct Ppu {
     /// Cache for faster background rendering
     bg_cache: BgCache,
 
+    /// Widescreen renderer (`None` if disabled). Not part of the emulated state.
+    widescreen: Option<Widescreen>,
+
+    /// Frame buffer for frames larger than the native size: Hi-res, overscan and interlaced
+    /// frames (`RGB24`, like `framebuf`, but `ext_size` pixels large). Only written while
+    /// `ext_output` is enabled. Not part of the emulated state.
+    ext_framebuf: Vec<u8>,
+    /// Size of the current frame if it's stored in `ext_framebuf` (`None` if it has the native
+    /// size). Not part of the emulated state.
+    ext_size: Option<(u32, u32)>,
+    /// Whether frames larger than the native size are output (see `set_extended_output`). Not
+    /// part of the emulated state.
+    ext_output: bool,
+
+    /// Converts the 15-bit colors to the frame buffer's 24-bit colors. Not part of the emulated
+    /// state.
+    color_lut: ColorLut,
+
     /// Object Attribute Memory
     ///
     /// The first 512 Bytes contain 4 Bytes per sprite (for a maximum of 128 simultaneous on-screen
@@ -91,6 +118,8 @@ pub struct Ppu {
     /// $f0 of every other frame (those with $213f.7=1) is only 1360 cycles. Frames are 262
     /// scanlines in non-interlace mode, while in interlace mode frames with $213f.7=0 are 263
     /// scanlines. V-Blank runs from either scanline $e1 or $f0 until the end of the frame."
+    ///
+    /// PAL frames are 312 scanlines (313 in interlace mode), so V-Blank is 50 scanlines longer.
     scanline: u16,
 
     /// Horizontal pixel counter
@@ -125,7 +154,8 @@ pub struct Ppu {
     oamaddl: u8,
     /// `$2103` High bit (bit 9) of OAM word address and priority rotation bit
     /// `p------b`
-    /// * `p`: If set, give priority to sprite `(OAMAddr&0xFE)>>1` (internal OAM address)
+    /// * `p`: If set, give priority to sprite `(OAMAddr&0xFE)>>1` (internal OAM word address, see
+    ///   `first_sprite`)
     /// * `b`: High bit of OAM word address ("reload value")
     oamaddh: u8,
     /// Internal OAM address register (10 bit)
@@ -269,6 +299,8 @@ pub struct Ppu {
     /// Store the low byte to write to the current CGRAM position after the high byte is written by
     /// the CPU (writes are always done in pairs - like the low 512 bytes of OAM).
     cg_low_buf: Option<u8>,
+    /// Whether the next read of `$213b` returns the high byte of the current color
+    cg_read_high: bool,
 
     /// `$2123` Window Mask Settings for BG1 and BG2
     /// `ABCDabcd`
@@ -414,72 +446,169 @@ pub struct Ppu {
     ///
     /// Reset on read if `$4201` bit 7 is set.
     ext_latch: bool,
+    /// `p` flag of STAT78 / `$213f` (see `interlace_field`). A property of the console, not part
+    /// of the emulated state.
+    pal: bool,
+    /// Last value read from a PPU1 (5C77) register. Returned by reads of its write-only
+    /// registers and unused bits of `$213e`.
+    ppu1_mdr: u8,
+    /// Last value read from a PPU2 (5C78) register. Returned by unused bits of `$213b`-`$213f`.
+    ppu2_mdr: u8,
+
+    /// Whether BG register writes take effect in the middle of a scanline (see
+    /// `Accuracy::bg_raster`). Not part of the emulated state.
+    bg_raster: bool,
 }
 
 impl_save_state!(Ppu {
     oam, cgram, vram, inidisp, obsel, oamaddl, oamaddh, oamaddr, oam_lsb, bgmode, mosaic, bg1sc,
     bg2sc, bg3sc, bg4sc, bg12nba, bg34nba, bg1hofs, m7hofs, bg1vofs, m7vofs, bg2hofs, bg2vofs,
     bg3hofs, bg3vofs, bg4hofs, bg4vofs, bg_old, m7_old, vmain, vmaddr, vram_prefetch, m7sel, m7a,
-    m7b, m7b_last, m7c, m7d, m7x, m7y, cgadd, cg_low_buf, w12sel, w34sel, wobjsel, wh0, wh1, wh2,
-    wh3, wbglog, wobjlog, tm, ts, tmw, tsw, cgwsel, cgadsub, coldata_r, coldata_g, coldata_b,
-    setini, ophct, ophct_high, opvct, opvct_high, can_latch_counters, scanline, x, time_over,
-    range_over, interlace_field, ext_latch
+    m7b, m7b_last, m7c, m7d, m7x, m7y, cgadd, cg_low_buf, cg_read_high, w12sel, w34sel, wobjsel,
+    wh0, wh1, wh2, wh3, wbglog, wobjlog, tm, ts, tmw, tsw, cgwsel, cgadsub, coldata_r, coldata_g,
+    coldata_b, setini, ophct, ophct_high, opvct, opvct_high, can_latch_counters, scanline, x,
+    time_over, range_over, interlace_field, ext_latch, ppu1_mdr, ppu2_mdr
 } ignore {
-    framebuf, sprite_render_state, bg_cache
+    framebuf, sprite_render_state, bg_cache, widescreen, ext_framebuf, ext_size, ext_output,
+    color_lut, pal, bg_raster
 });
 
 impl Ppu {
-    /// Load a PPU register (addresses `$2134` to `$213f`)
-    pub fn load(&mut self, addr: u16) -> u8 {
+    /// Changes how colors are converted for the frame buffer. Takes effect with the next pixel.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_lut = ColorLut::new(correction);
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection { self.color_lut.correction() }
+
+    /// Sets whether this is a PAL PPU, which is reported to games via `$213f` and makes frames 312
+    /// scanlines long.
+    pub fn set_pal(&mut self, pal: bool) {
+        self.pal = pal;
+    }
+
+    pub fn is_pal(&self) -> bool { self.pal }
+
+    /// Sets whether BG register writes in the middle of a scanline affect the rest of it.
+    pub fn set_bg_raster(&mut self, bg_raster: bool) {
+        self.bg_raster = bg_raster;
+    }
+
+    /// Load a PPU register (addresses `$2100` to `$213f`)
+    ///
+    /// `bus` is the CPU's open-bus value, which is what reading `$2137` and most write-only
+    /// registers returns. Reading the other write-only registers returns the PPU1 open bus
+    /// instead.
+    pub fn load(&mut self, addr: u16, bus: u8) -> u8 {
         match addr {
+            // Write-only registers of the PPU1 (5C77) chip
+            0x2104 ... 0x2106 | 0x2108 ... 0x210a | 0x2114 ... 0x2116 | 0x2118 ... 0x211a |
+            0x2124 ... 0x2126 | 0x2128 ... 0x212a => self.ppu1_mdr,
             // `$2134` - `$2136`: Multiplication Result of `self.m7a * self.m7b_last`
             // MPYL - Low Byte
-            0x2134 => (self.m7a as u32 * self.m7b_last as u32) as u8,
+            0x2134 => {
+                self.ppu1_mdr = (self.m7a as u32 * self.m7b_last as u32) as u8;
+                self.ppu1_mdr
+            }
             // MPYM - Middle Byte
-            0x2135 => ((self.m7a as u32 * self.m7b_last as u32) >> 8) as u8,
+            0x2135 => {
+                self.ppu1_mdr = ((self.m7a as u32 * self.m7b_last as u32) >> 8) as u8;
+                self.ppu1_mdr
+            }
             // MPYH - High Byte
-            0x2136 => ((self.m7a as u32 * self.m7b_last as u32) >> 16) as u8,
+            0x2136 => {
+                self.ppu1_mdr = ((self.m7a as u32 * self.m7b_last as u32) >> 16) as u8;
+                self.ppu1_mdr
+            }
+            // SLHV - Latches the H/V counters, the data read is CPU open bus
             0x2137 => {
                 self.latch_counters();
-                0   // FIXME The data read is open bus, which isn't yet emulated
+                bus
             }
             // RDOAM
-            0x2138 => self.oam_load(),
-            0x2139 => self.vram_load_low(),
-            0x213a => self.vram_load_high(),
-            // OPHCT
+            0x2138 => {
+                self.ppu1_mdr = self.oam_load();
+                self.ppu1_mdr
+            }
+            0x2139 => {
+                self.ppu1_mdr = self.vram_load_low();
+                self.ppu1_mdr
+            }
+            0x213a => {
+                self.ppu1_mdr = self.vram_load_high();
+                self.ppu1_mdr
+            }
+            // RDCGRAM - Bit 7 of the high byte is PPU2 open bus
+            0x213b => {
+                let addr = self.cgadd as u16 * 2;
+                if self.cg_read_high {
+                    self.ppu2_mdr = (self.ppu2_mdr & 0x80) | (self.cgram[addr + 1] & 0x7f);
+                    self.cgadd = self.cgadd.wrapping_add(1);
+                } else {
+                    self.ppu2_mdr = self.cgram[addr];
+                }
+                self.cg_read_high = !self.cg_read_high;
+                self.ppu2_mdr
+            }
+            // OPHCT - The high byte has a single valid bit, the rest is PPU2 open bus
             0x213c => {
-                let value = if self.ophct_high { (self.ophct >> 8) as u8 } else { self.ophct as u8};
+                self.ppu2_mdr = if self.ophct_high {
+                    (self.ppu2_mdr & 0xfe) | (self.ophct >> 8) as u8 & 0x01
+                } else {
+                    self.ophct as u8
+                };
                 self.ophct_high = !self.ophct_high;
-                value
+                self.ppu2_mdr
             }
             // OPVCT
             0x213d => {
-                let value = if self.opvct_high { (self.opvct >> 8) as u8 } else { self.opvct as u8};
+                self.ppu2_mdr = if self.opvct_high {
+                    (self.ppu2_mdr & 0xfe) | (self.opvct >> 8) as u8 & 0x01
+                } else {
+                    self.opvct as u8
+                };
                 self.opvct_high = !self.opvct_high;
-                value
+                self.ppu2_mdr
             }
+            // STAT77
             0x213e => {
-                (if self.time_over { 0x80 } else { 0x00 })
-                | (if self.range_over { 0x40 } else { 0x00 })
-                | 0x01
+                self.ppu1_mdr = (self.ppu1_mdr & 0x10)
+                    | (if self.time_over { 0x80 } else { 0x00 })
+                    | (if self.range_over { 0x40 } else { 0x00 })
+                    | 0x01;
+                self.ppu1_mdr
             }
+            // STAT78
             0x213f => {
                 let interlace = if self.interlace_field { 0x80 } else { 0x00 };
                 let latch = if self.ext_latch { 0x40 } else { 0x00 };
+                let pal = if self.pal { 0x10 } else { 0x00 };
 
                 self.ophct_high = false;
                 self.opvct_high = false;
+                if self.can_latch_counters {
+                    self.ext_latch = false;
+                }
 
-                // FIXME Does PAL/NTSC have significance? Or the version we return?
-                interlace | latch | 0x02
+                // FIXME Does the version we return have significance?
+                self.ppu2_mdr = (self.ppu2_mdr & 0x20) | interlace | latch | pal | 0x02;
+                self.ppu2_mdr
             }
-            _ => panic!("invalid/unimplemented PPU load from ${:04X}", addr),
+            // Other write-only registers aren't connected to a PPU data bus
+            _ => bus,
         }
     }
 
     /// Store a byte in a PPU register (addresses `$2100` - `$2133`)
     pub fn store(&mut self, addr: u16, value: u8) {
+        if self.bg_raster && self.x > 0 {
+            if let 0x2105 ... 0x2114 | 0x211a ... 0x2120 | 0x2133 = addr {
+                // The part of the scanline that's already cached was rendered with the old
+                // settings, so rerender the rest
+                self.bg_cache.invalidate_all();
+            }
+        }
+
         match addr {
             0x2100 => self.inidisp = value,
             0x2101 => self.obsel = value,
@@ -515,6 +644,7 @@ impl Ppu {
             0x2121 => {
                 self.cgadd = value;
                 self.cg_low_buf = None;
+                self.cg_read_high = false;
             }
             0x2122 => match self.cg_low_buf {
                 None => self.cg_low_buf = Some(value),
@@ -534,23 +664,33 @@ impl Ppu {
             0x2129 => self.wh3 = value,
             0x212a => self.wbglog = value,
             0x212b => {
-                if value & 0xf0 != 0 { once!(warn!("invalid value for $212b: ${:02X}", value)); }
+                if value & 0xf0 != 0 {
+                    once!(warn!(target: target::PPU, "invalid value for $212b: ${:02X}", value));
+                }
                 self.wobjlog = value;
             }
             0x212c => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212c: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    once!(warn!(target: target::PPU, "invalid value for $212c: ${:02X}", value));
+                }
                 self.tm = value;
             }
             0x212d => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212d: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    once!(warn!(target: target::PPU, "invalid value for $212d: ${:02X}", value));
+                }
                 self.ts = value;
             }
             0x212e => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212e: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    once!(warn!(target: target::PPU, "invalid value for $212e: ${:02X}", value));
+                }
                 self.tmw = value;
             }
             0x212f => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212f: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    once!(warn!(target: target::PPU, "invalid value for $212f: ${:02X}", value));
+                }
                 self.tsw = value;
             }
             0x2130 => self.cgwsel = value,
@@ -564,9 +704,9 @@ impl Ppu {
             0x2133 => {
                 assert!(value & 0x80 == 0, "ext. sync not yet implemented");
                 assert!(value & 0x40 == 0, "Mode 7 EXTBG not yet implemented");
-                if value & 0x08 != 0 { once!(warn!("pseudo-hires mode not yet implemented")); }
-                if value & 0x04 != 0 { once!(warn!("overscan not yet implemented")); }
-                if value & 0x03 != 0 { once!(warn!("interlace not yet implemented")); }
+                if value & 0x02 != 0 {
+                    once!(warn!(target: target::PPU, "OBJ interlace not yet implemented"));
+                }
                 self.setini = value;
             }
             _ => panic!("invalid or unimplemented PPU store: ${:02X} to ${:04X}", value, addr),
@@ -575,15 +715,20 @@ impl Ppu {
 
     /// Latches the H/V counters if `$4201` bit 7 is set (otherwise, no latching can occur)
     pub fn latch_counters(&mut self) {
-        // FIXME Call this when the port 2 peripheral wants to latch
         if self.can_latch_counters {
-            // Note that this does not change the high/low byte flags of OP[HV]CT
-            self.ophct = self.x;
-            self.opvct = self.scanline;
-            self.ext_latch = true;
+            self.force_latch_counters();
         }
     }
 
+    /// Latches the H/V counters regardless of `$4201` bit 7. Used when the latch line itself is
+    /// pulled low (by a write to `$4201` or a peripheral in port 2).
+    pub fn force_latch_counters(&mut self) {
+        // Note that this does not change the high/low byte flags of OP[HV]CT
+        self.ophct = self.x;
+        self.opvct = self.scanline;
+        self.ext_latch = true;
+    }
+
     /// Runs the PPU for a bit.
     ///
     /// This will render exactly one pixel (when in H/V-Blank, the pixel counter will be
@@ -591,10 +736,19 @@ impl Ppu {
     pub fn update(&mut self) -> u8 {
         if !self.in_h_blank() && !self.in_v_blank() {
             // This pixel is visible
-            let pixel = self.render_pixel();
             let x = self.x;
             let y = self.scanline;
-            self.set_pixel(x, y, pixel);
+            if x == 0 && y == 1 {
+                // First visible pixel of a new frame
+                self.start_output_frame();
+            }
+            let (left, right) = if self.hires_line() {
+                self.render_hires_pixels()
+            } else {
+                let pixel = self.render_pixel();
+                (pixel, pixel)
+            };
+            self.output_pixel(x, y, left, right);
         }
 
         self.x += 1;
@@ -602,7 +756,20 @@ impl Ppu {
             // End of H-Blank
             self.x = 0;
             self.scanline += 1;
-            if self.scanline == 262 {
+            // In interlace mode, frames with the interlace field flag cleared have an additional
+            // scanline
+            let base_scanlines = if self.pal { 312 } else { 262 };
+            let scanlines = if self.interlace() && !self.interlace_field {
+                base_scanlines + 1
+            } else {
+                base_scanlines
+            };
+            if self.scanline == self.frame_height() + 1 && !self.forced_blank() {
+                // V-Blank starts, the OAM address is reset to the value last written to
+                // `$2102/$2103`. Games rely on this when using priority rotation.
+                self.update_oam_addr();
+            }
+            if self.scanline >= scanlines {
                 // V-Blank ends now. The next `update` call will render the first visible pixel of
                 // a new frame.
                 self.scanline = 0;
@@ -619,10 +786,26 @@ impl Ppu {
 
     pub fn in_h_blank(&self) -> bool { self.x >= 256 }
     // Scanline 0 is displayed, but not rendered (usually cut off by TVs)
-    pub fn in_v_blank(&self) -> bool { self.scanline == 0 || self.scanline as u32 >= SCREEN_HEIGHT }
+    pub fn in_v_blank(&self) -> bool { self.scanline == 0 || self.scanline >= self.frame_height() }
     pub fn forced_blank(&self) -> bool { self.inidisp & 0x80 != 0 }
     fn brightness(&self) -> u8 { self.inidisp & 0xf }
 
+    /// Applies the screen brightness (set in `INIDISP`) to a color.
+    fn apply_brightness(&self, color: SnesRgb) -> SnesRgb {
+        let brightness = self.brightness() as u16;
+        if brightness == 0 {
+            // This isn't actually correct: The image is still (barely) visible. So barely that this
+            // makes basically no difference.
+            SnesRgb::new(0, 0, 0)
+        } else {
+            SnesRgb::new(
+                (color.r() as u16 * (brightness + 1) / 16) as u8,
+                (color.g() as u16 * (brightness + 1) / 16) as u8,
+                (color.b() as u16 * (brightness + 1) / 16) as u8,
+            )
+        }
+    }
+
     /// Returns the current X position
     pub fn h_counter(&self) -> u16 { self.x }
     /// Returns the current Y position (scanline)
@@ -681,7 +864,8 @@ impl Ppu {
         self.m7_old = val;
     }
 
-    /// Update the internal OAM address register after a write to `$2102` or `$2103`
+    /// Update the internal OAM address register after a write to `$2102` or `$2103` (or at the
+    /// start of V-Blank)
     fn update_oam_addr(&mut self) {
         self.oamaddr = (((self.oamaddh as u16 & 0x01) << 8) | self.oamaddl as u16) << 1;
     }
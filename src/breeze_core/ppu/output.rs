@@ -0,0 +1,170 @@
+//! Output frame geometry
+//!
+//! The native picture is `SCREEN_WIDTH * SCREEN_HEIGHT` pixels large, but some modes produce
+//! larger frames:
+//!
+//! * **Hi-res**: BG modes 5 and 6 and the pseudo-hires mode (`SETINI` bit 3) output 512 pixels
+//!   per scanline: Every pixel is split into a left half showing the subscreen and a right half
+//!   showing the main screen. In modes 5 and 6, the BG layers also have twice the horizontal
+//!   resolution (see `Ppu::hires_bg`), while pseudo-hires only interleaves the 2 screens.
+//! * **Overscan** (`SETINI` bit 2): 239 scanlines are displayed instead of 224.
+//! * **Interlace** (`SETINI` bit 0): Every frame only draws one field (the even or the odd lines)
+//!   of a picture that's twice as high. The other field is kept from the previous frame.
+//!
+//! These frames are stored in a separate frame buffer whose size can change from frame to frame.
+//! A frame containing at least one hi-res scanline is twice as wide, normal scanlines of such a
+//! frame are stretched to fill the whole width. `Ppu::framebuf` always holds a native-size
+//! version of the picture.
+
+use super::{Ppu, Rgb, OVERSCAN_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Width of hi-res frames in pixels
+pub const HIRES_WIDTH: u32 = SCREEN_WIDTH * 2;
+
+impl Ppu {
+    /// Sets whether hi-res, overscan and interlaced frames are output in their full size. If
+    /// disabled, `output_frame` always returns native-size frames: Both halves of hi-res pixels are
+    /// averaged, the overscan area is cut off and only the current field is shown.
+    ///
+    /// Takes effect with the next frame.
+    pub fn set_extended_output(&mut self, enabled: bool) {
+        self.ext_output = enabled;
+        if !enabled {
+            self.ext_size = None;
+            self.ext_framebuf = Vec::new();
+        }
+    }
+
+    /// Returns `true` if the current scanline is a hi-res scanline.
+    pub fn hires_line(&self) -> bool {
+        self.hires_bg() || self.setini & 0x08 != 0
+    }
+
+    /// Returns `true` if interlace mode is enabled.
+    pub fn interlace(&self) -> bool { self.setini & 0x01 != 0 }
+
+    /// Returns the number of scanlines in the picture (224, or 239 in overscan mode). V-Blank
+    /// starts on the scanline after that.
+    pub fn frame_height(&self) -> u16 {
+        if self.setini & 0x04 != 0 { OVERSCAN_HEIGHT as u16 } else { SCREEN_HEIGHT as u16 }
+    }
+
+    /// Returns `true` if the current frame (or, during V-Blank, the last completed frame) doesn't
+    /// have the native size.
+    pub fn extended_frame(&self) -> bool { self.ext_size.is_some() }
+
+    /// Returns the size of the frames returned by `output_frame` in pixels. This can change from
+    /// frame to frame.
+    pub fn output_size(&self) -> (u32, u32) {
+        if let Some(size) = self.ext_size {
+            return size;
+        }
+        match self.widescreen {
+            Some(ref ws) => (ws.width(), SCREEN_HEIGHT),
+            None => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    /// Returns the picture that should be displayed: The full-size frame if it's larger than the
+    /// native size (and extended output is enabled), the widescreen frame if widescreen rendering
+    /// is enabled, `framebuf` otherwise.
+    ///
+    /// The widescreen margins aren't shown in frames larger than the native size.
+    pub fn output_frame(&self) -> &[u8] {
+        if self.ext_size.is_some() {
+            return &self.ext_framebuf;
+        }
+        match self.widescreen {
+            Some(ref ws) => &ws.framebuf,
+            None => &*self.framebuf,
+        }
+    }
+
+    /// Decides the size of a new frame. Called before its first pixel is rendered.
+    pub fn start_output_frame(&mut self) {
+        if !self.ext_output { return }
+
+        let height = self.frame_height() as u32 * if self.interlace() { 2 } else { 1 };
+        // Interlaced frames keep the other field of the previous frame, so they also keep its width
+        let width = match self.ext_size {
+            Some((width, h)) if self.interlace() && h == height => width,
+            _ => SCREEN_WIDTH,
+        };
+        if (width, height) == (SCREEN_WIDTH, SCREEN_HEIGHT) {
+            self.ext_size = None;
+        } else {
+            self.resize_ext_frame(width, height);
+        }
+    }
+
+    /// Writes a rendered pixel to the frame buffers. Outside of hi-res scanlines, `left` and
+    /// `right` are the same color.
+    pub fn output_pixel(&mut self, x: u16, y: u16, left: Rgb, right: Rgb) {
+        if (y as u32) < SCREEN_HEIGHT {
+            let pixel = left.average(&right);
+            self.set_pixel(x, y, pixel);
+            if self.widescreen.is_some() {
+                self.set_widescreen_pixel(x, y, pixel);
+                if x == SCREEN_WIDTH as u16 - 1 {
+                    self.render_widescreen_margins();
+                }
+            }
+        }
+
+        if !self.ext_output { return }
+
+        // The first hi-res scanline makes the frame twice as wide
+        if self.hires_line() && self.ext_size.map_or(true, |(width, _)| width != HIRES_WIDTH) {
+            let height = self.ext_size.map_or(SCREEN_HEIGHT, |(_, height)| height);
+            self.resize_ext_frame(HIRES_WIDTH, height);
+        }
+
+        let (width, height) = match self.ext_size {
+            Some(size) => size,
+            None => return,
+        };
+        let row = if self.interlace() { y * 2 + self.interlace_field as u16 } else { y };
+        if row as u32 >= height { return }
+
+        let start = (row as usize * width as usize + x as usize) * 3;
+        if width == HIRES_WIDTH {
+            let start = start + x as usize * 3;
+            let dest = &mut self.ext_framebuf[start..start + 6];
+            dest[0] = left.r;
+            dest[1] = left.g;
+            dest[2] = left.b;
+            dest[3] = right.r;
+            dest[4] = right.g;
+            dest[5] = right.b;
+        } else {
+            let dest = &mut self.ext_framebuf[start..start + 3];
+            dest[0] = left.r;
+            dest[1] = left.g;
+            dest[2] = left.b;
+        }
+    }
+
+    /// Changes the size of the current frame, scaling the picture rendered so far.
+    fn resize_ext_frame(&mut self, width: u32, height: u32) {
+        if self.ext_size == Some((width, height)) { return }
+
+        let mut buf = vec![0; width as usize * height as usize * 3];
+        {
+            let (src, (src_width, src_height)) = match self.ext_size {
+                Some(size) => (&self.ext_framebuf[..], size),
+                None => (&self.framebuf[..], (SCREEN_WIDTH, SCREEN_HEIGHT)),
+            };
+            for y in 0..height {
+                let src_y = y * src_height / height;
+                for x in 0..width {
+                    let src_x = x * src_width / width;
+                    let from = ((src_y * src_width + src_x) * 3) as usize;
+                    let to = ((y * width + x) * 3) as usize;
+                    buf[to..to + 3].copy_from_slice(&src[from..from + 3]);
+                }
+            }
+        }
+        self.ext_framebuf = buf;
+        self.ext_size = Some((width, height));
+    }
+}
@@ -7,28 +7,103 @@
 
 use super::Ppu;
 
-macro_rules! fields_u8 {
-    ( $($fld:ident)+ ) => {
+macro_rules! registers {
+    ( u8: $($fld8:ident)+ ; u16: $($fld16:ident)+ ) => {
         impl Ppu {
             $(
-                pub fn $fld(&self) -> u8 { self.$fld }
+                pub fn $fld8(&self) -> u8 { self.$fld8 }
             )+
-        }
-    };
-}
-
-macro_rules! fields_u16 {
-    ( $($fld:ident)+ ) => {
-        impl Ppu {
             $(
-                pub fn $fld(&self) -> u16 { self.$fld }
+                pub fn $fld16(&self) -> u16 { self.$fld16 }
             )+
+
+            /// Takes a snapshot of all registers that have an accessor method.
+            pub fn registers(&self) -> PpuRegisters {
+                PpuRegisters {
+                    values: vec![
+                        $( (stringify!($fld8), self.$fld8 as u16), )+
+                        $( (stringify!($fld16), self.$fld16), )+
+                    ],
+                }
+            }
         }
     };
 }
 
-fields_u8!(inidisp obsel bgmode mosaic bg1sc bg2sc bg3sc bg4sc bg12nba bg34nba vmain m7sel w12sel
-    w34sel wobjsel wh0 wh1 wh2 wh3 wbglog wobjlog tm ts tmw tsw cgwsel cgadsub coldata_r coldata_g
-    coldata_b setini);
-fields_u16!(bg1hofs m7hofs bg1vofs m7vofs bg2hofs bg2vofs bg3hofs bg3vofs bg4hofs bg4vofs m7a m7b
-    m7c m7d m7x m7y);
+registers!(u8: inidisp obsel oamaddl oamaddh bgmode mosaic bg1sc bg2sc bg3sc bg4sc bg12nba
+    bg34nba vmain m7sel cgadd w12sel w34sel wobjsel wh0 wh1 wh2 wh3 wbglog wobjlog tm ts tmw tsw
+    cgwsel cgadsub coldata_r coldata_g coldata_b setini;
+    u16: bg1hofs m7hofs bg1vofs m7vofs bg2hofs bg2vofs bg3hofs bg3vofs bg4hofs bg4vofs vmaddr m7a
+    m7b m7c m7d m7x m7y);
+
+/// The values of all PPU registers at some point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PpuRegisters {
+    /// `(name, value)` pairs, always in the same order. Names are the accessor method names.
+    values: Vec<(&'static str, u16)>,
+}
+
+impl PpuRegisters {
+    /// Returns all registers as `(name, value)` pairs.
+    pub fn values(&self) -> &[(&'static str, u16)] { &self.values }
+
+    /// Returns the value of the register with the given (accessor method) name.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.values.iter().find(|&&(reg, _)| reg == name).map(|&(_, value)| value)
+    }
+
+    /// Returns the registers that differ between `older` and `self`.
+    pub fn changes_since(&self, older: &PpuRegisters) -> Vec<RegisterChange> {
+        self.values.iter().zip(&older.values)
+            .filter(|&(&(_, new), &(_, old))| new != old)
+            .map(|(&(name, new), &(_, old))| RegisterChange {
+                name: name,
+                old: old,
+                new: new,
+            })
+            .collect()
+    }
+}
+
+/// A register whose value differs between two snapshots
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub name: &'static str,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Keeps the PPU registers at the start of the last two frames, so graphics glitches can be
+/// narrowed down to the registers that changed when they appeared.
+#[derive(Clone, Debug, Default)]
+pub struct RegisterHistory {
+    /// Registers at the start of the current frame
+    current: Option<PpuRegisters>,
+    /// Registers at the start of the previous frame
+    previous: Option<PpuRegisters>,
+}
+
+impl RegisterHistory {
+    pub fn new() -> Self { Self::default() }
+
+    /// Called at the start of a frame (V=0, H=0) to take a new snapshot.
+    pub fn start_frame(&mut self, ppu: &Ppu) {
+        self.previous = self.current.take();
+        self.current = Some(ppu.registers());
+    }
+
+    /// Returns the registers at the start of the current frame.
+    pub fn current(&self) -> Option<&PpuRegisters> { self.current.as_ref() }
+
+    /// Returns the registers at the start of the previous frame.
+    pub fn previous(&self) -> Option<&PpuRegisters> { self.previous.as_ref() }
+
+    /// Returns the registers that changed between the starts of the previous and the current
+    /// frame, or `None` if fewer than 2 frames were started since the history was created.
+    pub fn changes(&self) -> Option<Vec<RegisterChange>> {
+        match (self.current.as_ref(), self.previous.as_ref()) {
+            (Some(current), Some(previous)) => Some(current.changes_since(previous)),
+            _ => None,
+        }
+    }
+}
@@ -24,8 +24,10 @@
 //!   `TILE+17`, where `TILE` is the stored tile number.
 
 use super::{Ppu, Rgb, SnesRgb};
+use log_util::target;
 
 /// An enum of all layers a pixel can come from
+#[derive(Copy, Clone)]
 enum Layer {
     Bg1,
     Bg2,
@@ -139,17 +141,15 @@ impl Ppu {
         let enable_bg_4_mask = (enable_mask_reg & 0b01000) != 0;
         let enable_sprite_mask = (enable_mask_reg & 0b10000) != 0;
 
-        // Set up all six masks from mask configurations
+        // Set up the layer masks from mask configurations (the color window is handled in
+        // `render_pixel`)
         let mask_bg_1 = Mask::new(self.w12sel, 0, self.wbglog, 0);
         let mask_bg_2 = Mask::new(self.w12sel, 4, self.wbglog, 2);
         let mask_bg_3 = Mask::new(self.w34sel, 0, self.wbglog, 4);
         let mask_bg_4 = Mask::new(self.w34sel, 4, self.wbglog, 6);
         let mask_sprites = Mask::new(self.wobjsel, 0, self.wobjlog, 0);
-        let mask_color = Mask::new(self.wobjsel, 4, self.wobjlog, 2);
 
-        // Check current pixel to get W1 and W2
-        let in_w1 = self.x >= (self.wh0 as u16) && self.x < (self.wh1 as u16);
-        let in_w2 = self.x >= (self.wh2 as u16) && self.x < (self.wh3 as u16);
+        let (in_w1, in_w2) = self.in_windows();
 
         macro_rules! mask_layer {
             ( 1 ) => { enable_bg_1_mask && mask_bg_1.check(in_w1, in_w2) };
@@ -159,41 +159,22 @@ impl Ppu {
             ( sprites ) => { enable_sprite_mask && mask_sprites.check(in_w1, in_w2) };
         }
 
-        // Enable/disable color clipping using mask settings, masks and cgwsel.
-        let clip_color = {
-            match (self.cgwsel >> 6, mask_color.check(in_w1, in_w2)) {
-                (0b11, _) => true,     // Always clip
-                (0b01, false) => true, // Clip outside window
-                (0b10, true) => true,  // Clip inside window
-                _ => false
-            }
-        };
-
         // This macro gets the current pixel from a tile with given priority in the given layer.
-        // If the pixel is non-transparent and non-masked, it will return its RGB value (after
-        // color clipping). If it is transparent or masked, it will do nothing (ie. the code
-        // following this macro is executed).
-
-        // FIXME: It's not clear whether colors should clip to black or color 0. I've seen both indicated.
-        // Clip to color 0 for now.
-        let clip_to_color = self.backdrop_color();
-        
+        // If the pixel is non-transparent and non-masked, it will return its RGB value. If it is
+        // transparent or masked, it will do nothing (ie. the code following this macro is
+        // executed).
         macro_rules! try_layer {
             ( Sprites with priority $prio:tt ) => {
                 if let Some((rgb, opaque)) = self.maybe_draw_sprite_pixel(e!($prio), subscreen) {
                     if !mask_layer!(sprites) {
-                        let rgb_post_clip = if !clip_color { rgb } else { clip_to_color };
-                        
-                        return (rgb_post_clip, Layer::Obj { opaque: opaque });
+                        return (rgb, Layer::Obj { opaque: opaque });
                     }
                 }
             };
             ( BG $bg:tt tiles with priority $prio:tt ) => {
                 if let Some(rgb) = self.lookup_bg_color(e!($bg), e!($prio), subscreen) {
                     if !mask_layer!($bg) {
-                        let rgb_post_clip = if !clip_color { rgb } else { clip_to_color };
-                        
-                        return (rgb_post_clip, bglayer!($bg));
+                        return (rgb, bglayer!($bg));
                     }
                 }
             };
@@ -263,6 +244,33 @@ impl Ppu {
         (self.backdrop_color(), Layer::Backdrop)
     }
 
+    /// Returns whether the current pixel is inside window 1 and window 2.
+    ///
+    /// Both window edges are inclusive. A window whose left edge is right of its right edge is
+    /// empty.
+    fn in_windows(&self) -> (bool, bool) {
+        let in_w1 = self.x >= self.wh0 as u16 && self.x <= self.wh1 as u16;
+        let in_w2 = self.x >= self.wh2 as u16 && self.x <= self.wh3 as u16;
+        (in_w1, in_w2)
+    }
+
+    /// Returns whether the current pixel is inside the color window.
+    fn in_color_window(&self) -> bool {
+        let (in_w1, in_w2) = self.in_windows();
+        Mask::new(self.wobjsel, 4, self.wobjlog, 2).check(in_w1, in_w2)
+    }
+
+    /// Returns whether the main screen is forced to black at the current pixel ("color clipping",
+    /// CGWSEL bits 6-7). Color math is still applied to the black pixel.
+    fn main_screen_clipped(&self) -> bool {
+        match (self.cgwsel >> 6, self.in_color_window()) {
+            (0b11, _) => true,     // Always clip
+            (0b01, false) => true, // Clip outside window
+            (0b10, true) => true,  // Clip inside window
+            _ => false
+        }
+    }
+
     fn color_math_enabled(&self, layer: Layer) -> bool {
         let bit = match layer {
             Layer::Bg1 => 0,
@@ -278,13 +286,8 @@ impl Ppu {
             return false;
         }
 
-        let in_w1 = self.x >= (self.wh0 as u16) && self.x < (self.wh1 as u16);
-        let in_w2 = self.x >= (self.wh2 as u16) && self.x < (self.wh3 as u16);
-
-        let mask = Mask::new(self.wobjsel, 4, self.wobjlog, 2);
-        
         // Apply color mask & settings in cgwsel
-        match ((self.cgwsel >> 4) & 0b11, mask.check(in_w1, in_w2)) {
+        match ((self.cgwsel >> 4) & 0b11, self.in_color_window()) {
             (0b11, _) => false,     // Always
             (0b01, false) => false, // Outside window
             (0b10, true) => false,  // Inside window
@@ -295,11 +298,43 @@ impl Ppu {
     /// Main rendering entry point. Renders the current pixel and returns its color. Assumes that
     /// the current pixel is on the screen.
     pub fn render_pixel(&mut self) -> Rgb {
+        if !self.prepare_pixel() {
+            return Rgb {r: 0, g: 0, b: 0};
+        }
+
+        let main = self.get_raw_pixel(false);
+        let color = self.screen_pixel(false, main, None);
+        self.apply_brightness(color).to_rgb(&self.color_lut)
+    }
+
+    /// Renders the current pixel of a hi-res scanline (see `hires_line`) and returns the colors of
+    /// its left and right half.
+    ///
+    /// The left half shows the subscreen and the right half shows the main screen. Each half uses
+    /// the other screen for color math, so both screens are evaluated once and used for both
+    /// halves.
+    pub fn render_hires_pixels(&mut self) -> (Rgb, Rgb) {
+        if !self.prepare_pixel() {
+            let black = Rgb {r: 0, g: 0, b: 0};
+            return (black, black);
+        }
+
+        let main = self.get_raw_pixel(false);
+        let sub = self.get_raw_pixel(true);
+        let left = self.screen_pixel(true, sub, Some(main));
+        let right = self.screen_pixel(false, main, Some(sub));
+        (self.apply_brightness(left).to_rgb(&self.color_lut),
+         self.apply_brightness(right).to_rgb(&self.color_lut))
+    }
+
+    /// Does the per-frame and per-scanline work before the current pixel can be rendered. Returns
+    /// `false` if the screen is blanked, in which case the pixel is black.
+    fn prepare_pixel(&mut self) -> bool {
         assert!(self.x < super::SCREEN_WIDTH as u16);
-        assert!(self.scanline < super::SCREEN_HEIGHT as u16);
+        assert!(self.scanline < self.frame_height());
 
         if self.forced_blank() {
-            return Rgb {r: 0, g: 0, b: 0};
+            return false;
         }
 
         if self.x == 0 && self.scanline == 0 {
@@ -308,7 +343,8 @@ impl Ppu {
             self.range_over = false;
             self.time_over = false;
 
-            trace!("New frame. BG mode {}, layers enabled: {:05b}, sprites are {:?} or {:?}",
+            trace!(target: target::PPU,
+                   "New frame. BG mode {}, layers enabled: {:05b}, sprites are {:?} or {:?}",
                    self.bg_mode(),
                    self.tm & 0x1f,
                    self.obj_size(false),
@@ -320,51 +356,54 @@ impl Ppu {
             self.collect_sprite_data_for_scanline();
         }
 
-        let (main_pix_color, main_pix_layer) = self.get_raw_pixel(false);
-        let post_math_color = if self.color_math_enabled(main_pix_layer) {
-            let math_color = if self.cgwsel & 0x02 == 0 {
-                // Fixed color. Note that the fixed color is also used as the subscreen's backdrop
-                // color.
-                SnesRgb::new(self.coldata_r, self.coldata_g, self.coldata_b)
-            } else {
-                // Subscreen
-                let (sub_color, sub_layer) = self.get_raw_pixel(true);
-                match sub_layer {
-                    Layer::Backdrop => {
-                        // Use COLDATA color as backdrop (FIXME a bit hacky, but is it too bad?)
-                        SnesRgb::new(self.coldata_r, self.coldata_g, self.coldata_b)
-                    }
-                    _ => sub_color,
-                }
-            };
+        true
+    }
 
-            // FIXME: Disable half-math when color is clipped.
-            if self.cgadsub & 0x80 == 0 {
-                // Add
-                main_pix_color.saturating_add(&math_color)
-            } else {
-                // Subtract
-                main_pix_color.saturating_sub(&math_color)
-            }
-        } else {
-            // No color math
-            main_pix_color
+    /// Applies color math to the current pixel of the main screen (or of the subscreen, if
+    /// `subscreen` is set), using the pixel of the other screen (`other`, which is rendered here
+    /// if needed and not passed). Brightness is not applied.
+    ///
+    /// The pixels are the raw pixels returned by `get_raw_pixel`.
+    fn screen_pixel(&mut self,
+                    subscreen: bool,
+                    pixel: (SnesRgb, Layer),
+                    other: Option<(SnesRgb, Layer)>) -> SnesRgb {
+        let fixed_color = SnesRgb::new(self.coldata_r, self.coldata_g, self.coldata_b);
+        let (pix_color, pix_layer) = match pixel {
+            // The subscreen's backdrop is the fixed color
+            (_, Layer::Backdrop) if subscreen => (fixed_color, Layer::Backdrop),
+            pixel => pixel,
         };
+        let clipped = self.main_screen_clipped();
+        let pix_color = if clipped { SnesRgb::new(0, 0, 0) } else { pix_color };
+        if !self.color_math_enabled(pix_layer) {
+            return pix_color;
+        }
 
-        let brightness = self.brightness() as u16;
-        let final_color = if brightness == 0 {
-            // This isn't actually correct: The image is still (barely) visible. So barely that this
-            // makes basically no difference.
-            SnesRgb::new(0, 0, 0)
+        // Half-color math is disabled when the pixel was clipped, or when the subscreen is
+        // transparent at this pixel
+        let (math_color, half) = if self.cgwsel & 0x02 == 0 {
+            (fixed_color, !clipped)
         } else {
-            SnesRgb::new(
-                (post_math_color.r() as u16 * (brightness + 1) / 16) as u8,
-                (post_math_color.g() as u16 * (brightness + 1) / 16) as u8,
-                (post_math_color.b() as u16 * (brightness + 1) / 16) as u8,
-            )
+            // The other screen. Note that the fixed color is also used as the subscreen's backdrop
+            // color.
+            let other = match other {
+                Some(other) => other,
+                None => self.get_raw_pixel(!subscreen),
+            };
+            match other {
+                (_, Layer::Backdrop) if !subscreen => (fixed_color, false),
+                (other_color, _) => (other_color, !clipped),
+            }
         };
+        let half = half && self.cgadsub & 0x40 != 0;
 
-        final_color.to_adjusted_rgb()
+        match (self.cgadsub & 0x80 == 0, half) {
+            (true, false) => pix_color.saturating_add(&math_color),
+            (true, true) => pix_color.half_add(&math_color),
+            (false, false) => pix_color.saturating_sub(&math_color),
+            (false, true) => pix_color.half_sub(&math_color),
+        }
     }
 
     /// Reads character data for a pixel and returns the palette index stored in the bitplanes.
@@ -372,7 +411,8 @@ impl Ppu {
     /// # Parameters
     /// * `bitplane_count`: Number of bitplanes (must be even)
     /// * `start_addr`: Address of the first bitplane (or the first 2)
-    /// * `tile_size`: 8 or 16
+    /// * `tile_size`: 8 or 16. 16x16 tiles consist of 4 8x8 tiles: The one at `start_addr`, the
+    ///   next one, and the 2 tiles 16 tiles after them (the next row in a 16-tile wide sheet).
     /// * `(x, y)`: Offset inside the tile (`0-7` or `0-15`, depending on the tile size)
     /// * `(vflip, hflip)`: Flip this tile vertically (top and down are flipped) or horizontally
     ///   (left and right are flipped)
@@ -386,17 +426,22 @@ impl Ppu {
         debug_assert!(bitplane_count & 1 == 0, "odd bitplane count");
         debug_assert!(x <= 7 || (x <= 15 && tile_size == 16), "invalid x value: {}", x);
         debug_assert!(y <= 7 || (y <= 15 && tile_size == 16), "invalid y value: {}", y);
-        debug_assert!(tile_size == 8, "non-8x8 tiles unsupported"); // FIXME support 16x16 tiles
+        debug_assert!(tile_size == 8 || tile_size == 16, "invalid tile size: {}", tile_size);
         let bitplane_pairs = bitplane_count >> 1;
 
-        // Flip coordinates, if necessary
+        // Flip coordinates, if necessary. This also swaps the 8x8 tiles of a 16x16 tile.
         let x = if hflip { tile_size - x - 1 } else { x };
         let y = if vflip { tile_size - y - 1 } else { y };
 
+        // Select the 8x8 tile containing the pixel
+        let sub_tile = (x / 8) as u16 + (y / 8) as u16 * 16;
+        let start_addr = start_addr.wrapping_add(sub_tile * 8 * bitplane_count as u16);
+        let (x, y) = (x % 8, y % 8);
+
         let mut palette_index = 0u8;
         for i in 0..bitplane_pairs {
             let bitplane_bits = self.read_2_bitplanes(
-                start_addr + i as u16 * 16, // 16 Bytes per pair of bitplanes
+                start_addr.wrapping_add(i as u16 * 16), // 16 Bytes per pair of bitplanes
                 (x, y));
             palette_index |= bitplane_bits << (2 * i);
         }
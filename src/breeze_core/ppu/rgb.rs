@@ -1,6 +1,7 @@
 //! Defines RGB color types for use in the renderer
 
 use std::cmp;
+use std::str::FromStr;
 
 /// 5-bit per channel RGB value used by the SNES
 #[derive(Debug, Copy, Clone)]
@@ -27,6 +28,18 @@ impl SnesRgb {
         }
     }
 
+    /// Decodes a color in direct color mode (used by 256-color BGs if enabled in `$2130`).
+    ///
+    /// The 8-bit palette index is `BBGGGRRR`, the palette number of the tile (`bgr`, 0 in mode 7)
+    /// supplies one additional bit per channel.
+    pub fn from_direct_color(index: u8, palette: u8) -> SnesRgb {
+        SnesRgb::new(
+            (index & 0x07) << 2 | (palette & 0x01) << 1,
+            (index >> 3 & 0x07) << 2 | (palette & 0x02),
+            (index >> 6) << 3 | (palette & 0x04),
+        )
+    }
+
     pub fn r(&self) -> u8 { self.r }
     pub fn g(&self) -> u8 { self.g }
     pub fn b(&self) -> u8 { self.b }
@@ -49,6 +62,18 @@ impl SnesRgb {
         SnesRgb::new(r, g, b)
     }
 
+    /// Adds `self` and `other` per color and halves the result (half-color math).
+    pub fn half_add(&self, other: &Self) -> Self {
+        SnesRgb::new((self.r + other.r) >> 1, (self.g + other.g) >> 1, (self.b + other.b) >> 1)
+    }
+
+    /// Subtracts `other` from `self` per color (saturating) and halves the result (half-color
+    /// math).
+    pub fn half_sub(&self, other: &Self) -> Self {
+        let diff = self.saturating_sub(other);
+        SnesRgb::new(diff.r >> 1, diff.g >> 1, diff.b >> 1)
+    }
+
     /// Converts 5-bit RGB to 8-bit RGB, adjusting the color space
     ///
     /// The colors are adjusted as follows (http://wiki.superfamicom.org/snes/show/Palettes):
@@ -75,6 +100,103 @@ impl SnesRgb {
 
         rgb
     }
+
+    /// Converts 5-bit RGB to 8-bit RGB using a color correction lookup table.
+    pub fn to_rgb(&self, lut: &ColorLut) -> Rgb {
+        Rgb {
+            r: lut.table[self.r as usize],
+            g: lut.table[self.g as usize],
+            b: lut.table[self.b as usize],
+        }
+    }
+}
+
+/// Controls how the SNES' 5-bit color channels are converted to the 8-bit channels of the frame
+/// buffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorCorrection {
+    /// Shift every channel left by 3 bits. Maximum intensity (31) becomes 248, so white is
+    /// slightly gray.
+    Shift,
+    /// Scale to the full 8-bit range by repeating the high bits in the low bits (the default).
+    Expand,
+    /// Apply a gamma curve on top of `Expand` to emulate how a CRT displays the signal: The value
+    /// is the gamma of the emulated CRT, assuming the display has a gamma of 2.2. Values above 2.2
+    /// darken the mid-tones.
+    Gamma(f32),
+    /// Brighten dark and mid-tones, approximating the look of a real console on a TV.
+    Brighten,
+}
+
+/// Gamma assumed for the user's display
+const DISPLAY_GAMMA: f32 = 2.2;
+/// CRT gamma used by `gamma` when no value is given
+const DEFAULT_CRT_GAMMA: f32 = 2.5;
+
+impl Default for ColorCorrection {
+    fn default() -> Self { ColorCorrection::Expand }
+}
+
+impl FromStr for ColorCorrection {
+    type Err = String;
+
+    /// Parses `shift`, `expand`, `gamma[:VALUE]` or `brighten`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut split = s.splitn(2, ':');
+        match (split.next().unwrap(), split.next()) {
+            ("shift", None) => Ok(ColorCorrection::Shift),
+            ("expand", None) => Ok(ColorCorrection::Expand),
+            ("gamma", None) => Ok(ColorCorrection::Gamma(DEFAULT_CRT_GAMMA)),
+            ("gamma", Some(value)) => match value.parse::<f32>() {
+                Ok(gamma) if gamma > 0.0 => Ok(ColorCorrection::Gamma(gamma)),
+                _ => Err(format!("invalid gamma value: {}", value)),
+            },
+            ("brighten", None) => Ok(ColorCorrection::Brighten),
+            _ => Err(format!("unknown color correction: {} (expected `shift`, `expand`, \
+                              `gamma[:VALUE]` or `brighten`)", s)),
+        }
+    }
+}
+
+/// Lookup table mapping 5-bit channel intensities to 8-bit intensities, built from a
+/// `ColorCorrection`. The same table is used for all 3 channels.
+#[derive(Copy, Clone)]
+pub struct ColorLut {
+    table: [u8; 32],
+    correction: ColorCorrection,
+}
+
+impl ColorLut {
+    pub fn new(correction: ColorCorrection) -> Self {
+        let mut table = [0; 32];
+        for (c, out) in table.iter_mut().enumerate() {
+            let c = c as u8;
+            let expanded = (c << 3) | (c >> 2);
+            let curve = |exponent: f32| {
+                (255.0 * (expanded as f32 / 255.0).powf(exponent)).round() as u8
+            };
+
+            *out = match correction {
+                ColorCorrection::Shift => c << 3,
+                ColorCorrection::Expand => expanded,
+                ColorCorrection::Gamma(gamma) => curve(gamma / DISPLAY_GAMMA),
+                ColorCorrection::Brighten => curve(0.8),
+            };
+        }
+
+        ColorLut {
+            table: table,
+            correction: correction,
+        }
+    }
+
+    pub fn correction(&self) -> ColorCorrection { self.correction }
+}
+
+impl Default for ColorLut {
+    fn default() -> Self {
+        ColorLut::new(ColorCorrection::default())
+    }
 }
 
 /// Standard 24-bit RGB color rendered to the frame buffer
@@ -84,3 +206,14 @@ pub struct Rgb {
     pub g: u8,
     pub b: u8,
 }
+
+impl Rgb {
+    /// Returns the average of two colors. Used to shrink hi-res pixel pairs to a single pixel.
+    pub fn average(&self, other: &Rgb) -> Rgb {
+        Rgb {
+            r: ((self.r as u16 + other.r as u16) / 2) as u8,
+            g: ((self.g as u16 + other.g as u16) / 2) as u8,
+            b: ((self.b as u16 + other.b as u16) / 2) as u8,
+        }
+    }
+}
@@ -2,8 +2,7 @@
 
 use super::{Ppu, SnesRgb};
 use super::oam::OamEntry;
-
-use slicevec::SliceVec;
+use super::viewer::{DebugImage, TRANSPARENT};
 
 /// Information saved about individual sprite layer pixels. Prerendered into the scanline cache.
 #[derive(Copy, Clone)]
@@ -14,6 +13,12 @@ struct SpritePixel {
     opaque: bool,
 }
 
+/// Maximum number of sprites on a scanline. More sprites set the `range_over` flag.
+const MAX_SPRITES: usize = 32;
+
+/// Maximum number of 8x8 sprite tiles on a scanline. More tiles set the `time_over` flag.
+const MAX_TILES: usize = 34;
+
 /// Render state stored inside the `Ppu`. We use this to cache the visible sprites for each
 /// scanline, just like the real PPU would.
 pub struct SpriteRenderState {
@@ -21,37 +26,35 @@ pub struct SpriteRenderState {
     /// scanline and contains, for each pixel on the scanline, the color of the OBJ (= sprite) layer
     /// and the priority of that pixel (`None` in case there's no opaque sprite pixel there).
     sprite_scanline: [(Option<SpritePixel>); super::SCREEN_WIDTH as usize],
+    /// Sprites in Range on the current scanline. Only valid while collecting sprite data, and only
+    /// up to the number of sprites found.
+    visible_sprites: [OamEntry; MAX_SPRITES],
+    /// Sprite tiles loaded for the current scanline (same restrictions as `visible_sprites`)
+    visible_tiles: [SpriteTile; MAX_TILES],
 }
 
 impl Default for SpriteRenderState {
     fn default() -> Self {
         SpriteRenderState {
             sprite_scanline: [None; super::SCREEN_WIDTH as usize],
+            visible_sprites: [OamEntry::default(); MAX_SPRITES],
+            visible_tiles: [SpriteTile::default(); MAX_TILES],
         }
     }
 }
 
 /// Informations about a single tile of a sprite, needed for drawing.
 #[derive(Copy, Clone, Default)]
-struct SpriteTile<'a> {
+struct SpriteTile {
     /// Address of character data for this tile
     chr_addr: u16,
     /// X position of the tile on the screen. Can be negative if the tile starts outside the screen.
     x: i16,
     /// Y position of the scanline inside the tile (0-7)
     y_off: u8,
-    /// Reference to the sprite this tile is a part of. Note that tiles can be shared, but that
-    /// doesn't matter for this.
-    ///
-    /// This is only an `Option` so it can implement `Default`. Integer generics should make this go
-    /// away (since they'd make `arrayvec` more usable).
-    sprite: Option<&'a OamEntry>,
-}
-
-impl<'a> SpriteTile<'a> {
-    fn sprite(&self) -> &OamEntry {
-        self.sprite.unwrap()
-    }
+    /// Index of the sprite this tile is a part of in `SpriteRenderState::visible_sprites`. Note
+    /// that tiles can be shared, but that doesn't matter for this.
+    sprite: u8,
 }
 
 impl Ppu {
@@ -59,24 +62,22 @@ impl Ppu {
     ///
     /// Called when rendering the first pixel on a scanline.
     pub fn collect_sprite_data_for_scanline(&mut self) {
-        let first_sprite = if self.oamaddh & 0x80 == 0 {
-            0
-        } else {
-            // Priority rotation enabled
-            (self.oamaddl as u16 & 0xfe) >> 1
-        };
+        let first_sprite = self.first_sprite();
 
         // Find the first 32 sprites on the current scanline (RANGE)
         // NB Priority is ignored for this step, it's only used for drawing, which isn't done here
-        let mut visible_sprites = [OamEntry::default(); 32];
-        let mut visible_sprites = SliceVec::new(&mut visible_sprites);
+        let mut sprite_count = 0;
         for i in first_sprite..first_sprite+128 {
             let index = (i & 0x7f) as u8;   // limit to 127 and wrap back around
             let entry = self.oam.get_sprite(index);
 
-            if self.sprite_on_scanline(&entry) && visible_sprites.push(entry).is_err() {
-                self.range_over = true;
-                break;
+            if self.sprite_on_scanline(&entry) {
+                if sprite_count == MAX_SPRITES {
+                    self.range_over = true;
+                    break;
+                }
+                self.sprite_render_state.visible_sprites[sprite_count] = entry;
+                sprite_count += 1;
             }
         }
 
@@ -90,17 +91,13 @@ impl Ppu {
         // * Tiles are loaded iff they are on the current scanline (and have `-8 < X < 256`)
         // FIXME Is this ^^ correct?
 
-        let mut visible_tiles = [SpriteTile::default(); 34];
-        let mut visible_tiles = SliceVec::new(&mut visible_tiles);
-
-        // Word address of first sprite character table
-        let name_base: u16 = (self.obsel as u16 & 0b111) << 13;
-        let name_select: u16 = (self.obsel as u16 >> 3) & 0b11;
+        let mut tile_count = 0;
 
         // TIME: Start at the last sprite found, load up to 34 8x8 tiles (for each sprite from left
-        // to right, after taking flip bits of the sprite into account [FIXME Flip bits are ignored
-        // I think])
-        'collect_tiles: for sprite in visible_sprites.iter().rev() {
+        // to right, after taking flip bits of the sprite into account). Flipping a sprite flips
+        // the order of its tiles, `read_chr_entry` then flips each tile.
+        'collect_tiles: for sprite_index in (0..sprite_count).rev() {
+            let sprite = self.sprite_render_state.visible_sprites[sprite_index];
             // How many tiles are there?
             let (sprite_w, sprite_h) = self.obj_size(sprite.size_toggle);
             let sprite_w_tiles = sprite_w / 8;
@@ -116,43 +113,39 @@ impl Ppu {
             // Y offset into the tile row
             let tile_y_off = (sprite_y_off % 8) as u8;
 
-            // Calculate VRAM word address of first tile. Depends on base/name bits in `$2101`.
-            let tile_start_word_addr =
-                (name_base |
-                ((sprite.tile as u16) << 4) |
-                (sprite.name_table as u16 * ((name_select + 1) << 12))) & 0x7fff;
-            let tile_start_addr = tile_start_word_addr * 2;
-
-            // The character data for the first tile is stored at `tile_start_addr`, in the same
-            // format as BG character data (bitplanes, etc.). Keep in mind that sprites do not have
-            // tilemaps.
+            // The character data for the first tile is stored at the sprite's tile number, in the
+            // same format as BG character data (bitplanes, etc.). Keep in mind that sprites do not
+            // have tilemaps.
             // One 8x8 tile is 32 Bytes large (4 bits per pixel).
             // Tiles in a single (1 tile or 8 pixel high) row of the sprite are stored sequentially:
-            // Tile coord (1,0) is stored directly behind (0,0), which is stored at
-            // `tile_start_addr`.
+            // Tile coord (1,0) is stored directly behind (0,0).
             // Rows of tiles, however, are always stored 512 Bytes (or 16 tiles/128 pixels) apart:
             // If tile (0,0) is at address $0000, tile (0,1) is at $0200. This is independent of
             // the sprite size, which means that there are "holes" in the sprite character data,
-            // which are used to store the data of other sprites.
-
-            // Start address of the row of tiles on the scanline
-            let y_row_start_addr = tile_start_addr.wrapping_add(512 * y_tile);
+            // which are used to store the data of other sprites. See `sprite_tile_addr`.
 
-            // FIXME "Only those tiles with -8 < X < 256 are counted."
-            // Add all tiles in this row to our tile list (left to right)
+            // Add all tiles in this row to our tile list (left to right). Tiles that are entirely
+            // off-screen don't count towards the limit.
             for i in 0..sprite_w_tiles as i16 {
                 let flip_i = if sprite.hflip { sprite_w_tiles as i16 - i - 1 } else { i };
-                let tile = SpriteTile {
-                    chr_addr: y_row_start_addr + 32 * i as u16,
-                    x: sprite.x + 8 * flip_i,
-                    y_off: tile_y_off,
-                    sprite: Some(sprite),
-                };
+                let x = sprite.x + 8 * flip_i;
+                if x <= -8 || x >= 256 {
+                    continue;
+                }
 
-                if visible_tiles.push(tile).is_err() {
+                if tile_count == MAX_TILES {
                     self.time_over = true;
                     break 'collect_tiles
                 }
+
+                let chr_addr = self.sprite_tile_addr(&sprite, i as u16, y_tile);
+                self.sprite_render_state.visible_tiles[tile_count] = SpriteTile {
+                    chr_addr: chr_addr,
+                    x: x,
+                    y_off: tile_y_off,
+                    sprite: sprite_index as u8,
+                };
+                tile_count += 1;
             }
         }
 
@@ -166,21 +159,23 @@ impl Ppu {
         // them in order (overwriting what's already there).
         self.sprite_render_state.sprite_scanline = [None; super::SCREEN_WIDTH as usize];
 
-        for tile in visible_tiles.iter() {
+        for tile_index in 0..tile_count {
+            let tile = self.sprite_render_state.visible_tiles[tile_index];
+            let sprite = self.sprite_render_state.visible_sprites[tile.sprite as usize];
             for x_off in 0u8..8 {
                 let screen_x = tile.x + x_off as i16;
                 if screen_x >= 0 && screen_x < super::SCREEN_WIDTH as i16 {
                     // on-screen pixel (can write to buffer)
-                    let color = self.read_sprite_tile_pixel(tile, x_off);
+                    let color = self.read_sprite_tile_pixel(&tile, &sprite, x_off);
                     let buffer = &mut self.sprite_render_state.sprite_scanline;
 
                     if let Some(rgb) = color {
                         // Write non-transparent pixel
                         buffer[screen_x as usize] = Some(SpritePixel {
                             color: rgb,
-                            prio: tile.sprite().priority,
+                            prio: sprite.priority,
                             // Sprites with palettes 0-3 are opaque
-                            opaque: tile.sprite().palette <= 3,
+                            opaque: sprite.palette <= 3,
                         });
                     }
                 }
@@ -188,6 +183,39 @@ impl Ppu {
         }
     }
 
+    /// Returns the index of the sprite with the highest priority.
+    ///
+    /// This is sprite 0, unless priority rotation is enabled in `$2103`. Then it's selected by the
+    /// internal OAM address, which changes when OAM is accessed and is reloaded from `$2102/$2103`
+    /// at the start of V-Blank.
+    fn first_sprite(&self) -> u16 {
+        if self.oamaddh & 0x80 == 0 {
+            0
+        } else {
+            // `oamaddr` is a byte address, and every sprite occupies 2 words
+            (self.oamaddr >> 2) & 0x7f
+        }
+    }
+
+    /// Returns the VRAM byte address of the 8x8 tile in column `col` and row `row` of a sprite
+    /// (before flipping). Depends on base/name bits in `$2101`.
+    ///
+    /// The sprite character data is a grid of 16x16 tiles, and both the column and the row wrap
+    /// around within it (eg. a 16x16 sprite using tile `$0F` continues with tile `$00`).
+    fn sprite_tile_addr(&self, sprite: &OamEntry, col: u16, row: u16) -> u16 {
+        // Word address of first sprite character table
+        let name_base: u16 = (self.obsel as u16 & 0b111) << 13;
+        let name_select: u16 = (self.obsel as u16 >> 3) & 0b11;
+
+        let tile = sprite.tile as u16;
+        let tile = (tile + row * 16) & 0xf0 | (tile + col) & 0x0f;
+        let word_addr =
+            (name_base |
+            (tile << 4) |
+            (sprite.name_table as u16 * ((name_select + 1) << 12))) & 0x7fff;
+        word_addr * 2
+    }
+
     /// Determines if the given sprite has any tiles on the current scanline
     fn sprite_on_scanline(&self, sprite: &OamEntry) -> bool {
         let (w, h) = self.obj_size(sprite.size_toggle);
@@ -215,19 +243,20 @@ impl Ppu {
         }
     }
 
-    fn read_sprite_tile_pixel(&self, tile: &SpriteTile, x_offset: u8) -> Option<SnesRgb> {
+    fn read_sprite_tile_pixel(&self, tile: &SpriteTile, sprite: &OamEntry, x_offset: u8)
+                              -> Option<SnesRgb> {
         debug_assert!(x_offset < 8);
         let rel_color = self.read_chr_entry(4,  // 16 colors
                                             tile.chr_addr,
                                             8,  // 8x8 tiles
                                             (x_offset as u8, tile.y_off),
-                                            (tile.sprite().vflip, tile.sprite().hflip));
+                                            (sprite.vflip, sprite.hflip));
         debug_assert!(rel_color < 16, "rel_color = {} (but is 4-bit!)", rel_color);
 
         // color index 0 is always transparent
         if rel_color == 0 { return None }
 
-        let abs_color = 128 + tile.sprite().palette * 16 + rel_color;
+        let abs_color = 128 + sprite.palette * 16 + rel_color;
         let rgb = self.cgram.get_color(abs_color);
 
         Some(rgb)
@@ -252,4 +281,35 @@ impl Ppu {
             _ => None,
         }
     }
+
+    /// Renders all 128 sprites into `image`, in a grid of 16x8 cells of 64x64 pixels (the largest
+    /// possible sprite size). Sprite positions and priorities are ignored, flipping is applied.
+    pub fn render_oam_view(&self, image: &mut DebugImage) {
+        const CELL: u32 = 64;
+
+        image.clear(16 * CELL, 8 * CELL, TRANSPARENT);
+        for index in 0..128u8 {
+            let sprite = self.oam.get_sprite(index);
+            let (w, h) = self.obj_size(sprite.size_toggle);
+
+            let (x0, y0) = (index as u32 % 16 * CELL, index as u32 / 16 * CELL);
+            for y in 0..h as u16 {
+                for x in 0..w as u16 {
+                    // Position inside the unflipped sprite
+                    let src_x = if sprite.hflip { w as u16 - x - 1 } else { x };
+                    let src_y = if sprite.vflip { h as u16 - y - 1 } else { y };
+                    let chr_addr = self.sprite_tile_addr(&sprite, src_x / 8, src_y / 8);
+                    let rel_color = self.read_chr_entry(4,
+                                                        chr_addr,
+                                                        8,
+                                                        ((src_x % 8) as u8, (src_y % 8) as u8),
+                                                        (false, false));
+                    if rel_color != 0 {
+                        let rgb = self.cgram.get_color(128 + sprite.palette * 16 + rel_color);
+                        image.set_pixel(x0 + x as u32, y0 + y as u32, rgb.to_rgb(&self.color_lut));
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,155 @@
+//! Debug views of the PPU's memories
+//!
+//! These render the contents of CGRAM, VRAM and OAM into images, independent of what's currently
+//! displayed on the screen. Frontends can show them on debug surfaces (see
+//! `Renderer::open_debug_surface`) to help with debugging graphical glitches.
+
+use super::{Ppu, Rgb};
+
+use std::str::FromStr;
+
+/// Color of transparent pixels in views that don't have a backdrop
+pub const TRANSPARENT: Rgb = Rgb { r: 0x40, g: 0x40, b: 0x40 };
+
+/// The available debug views.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    /// All 256 CGRAM colors, 16 per row
+    Palette,
+    /// All of VRAM, decoded as 8x8 tiles with the given number of bits per pixel (2, 4 or 8)
+    Tiles { bpp: u8 },
+    /// The complete tilemap of a BG layer (1-4), ignoring scrolling
+    Tilemap(u8),
+    /// All 128 sprites, in order
+    Oam,
+}
+
+impl DebugView {
+    /// Returns a human-readable name of this view, suitable as a window title.
+    pub fn title(&self) -> String {
+        match *self {
+            DebugView::Palette => "Palette".to_owned(),
+            DebugView::Tiles { bpp } => format!("Tiles ({} bpp)", bpp),
+            DebugView::Tilemap(bg) => format!("BG{} Tilemap", bg),
+            DebugView::Oam => "Sprites".to_owned(),
+        }
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = String;
+
+    /// Parses `palette`, `tiles[:BPP]`, `bg1` - `bg4` or `oam`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut split = s.splitn(2, ':');
+        let view = match (split.next().unwrap(), split.next()) {
+            ("palette", None) => DebugView::Palette,
+            ("tiles", None) => DebugView::Tiles { bpp: 4 },
+            ("tiles", Some(bpp)) => match bpp {
+                "2" => DebugView::Tiles { bpp: 2 },
+                "4" => DebugView::Tiles { bpp: 4 },
+                "8" => DebugView::Tiles { bpp: 8 },
+                _ => return Err(format!("invalid bits per pixel: {} (expected 2, 4 or 8)", bpp)),
+            },
+            ("bg1", None) => DebugView::Tilemap(1),
+            ("bg2", None) => DebugView::Tilemap(2),
+            ("bg3", None) => DebugView::Tilemap(3),
+            ("bg4", None) => DebugView::Tilemap(4),
+            ("oam", None) | ("sprites", None) => DebugView::Oam,
+            _ => return Err(format!("unknown debug view: {} (expected `palette`, `tiles[:BPP]`, \
+                                     `bg1` - `bg4` or `oam`)", s)),
+        };
+        Ok(view)
+    }
+}
+
+/// An `RGB24` image a debug view is rendered into. Can be reused between frames.
+#[derive(Default)]
+pub struct DebugImage {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl DebugImage {
+    pub fn new() -> Self {
+        DebugImage::default()
+    }
+
+    pub fn width(&self) -> u32 { self.width }
+    pub fn height(&self) -> u32 { self.height }
+
+    /// Returns the image data in `RGB24` format (like the PPU's frame buffer).
+    pub fn data(&self) -> &[u8] { &self.data }
+
+    /// Resizes the image and fills it with the given color.
+    pub fn clear(&mut self, width: u32, height: u32, color: Rgb) {
+        self.width = width;
+        self.height = height;
+        self.data.clear();
+        for _ in 0..width * height {
+            self.data.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+    }
+
+    /// Sets a pixel. Does nothing if the coordinates are out of bounds.
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Rgb) {
+        if x < self.width && y < self.height {
+            let start = (y * self.width + x) as usize * 3;
+            self.data[start] = color.r;
+            self.data[start + 1] = color.g;
+            self.data[start + 2] = color.b;
+        }
+    }
+}
+
+/// Debug views
+impl Ppu {
+    /// Renders a debug view into `image`.
+    pub fn render_debug_view(&self, view: DebugView, image: &mut DebugImage) {
+        match view {
+            DebugView::Palette => self.render_palette_view(image),
+            DebugView::Tiles { bpp } => self.render_tile_view(bpp, image),
+            DebugView::Tilemap(bg) => self.render_tilemap_view(bg, image),
+            DebugView::Oam => self.render_oam_view(image),
+        }
+    }
+
+    /// Renders all colors in CGRAM as 8x8 swatches, 16 colors per row.
+    fn render_palette_view(&self, image: &mut DebugImage) {
+        const SWATCH: u32 = 8;
+
+        image.clear(16 * SWATCH, 16 * SWATCH, TRANSPARENT);
+        for index in 0..256u32 {
+            let color = self.cgram.get_color(index as u8).to_rgb(&self.color_lut);
+            let (x0, y0) = (index % 16 * SWATCH, index / 16 * SWATCH);
+            for y in y0..y0 + SWATCH {
+                for x in x0..x0 + SWATCH {
+                    image.set_pixel(x, y, color);
+                }
+            }
+        }
+    }
+
+    /// Renders all of VRAM as 8x8 tiles with `bpp` bits per pixel, 16 tiles per row. Since tiles
+    /// don't know their palette, colors are displayed as shades of gray.
+    fn render_tile_view(&self, bpp: u8, image: &mut DebugImage) {
+        let tile_bytes = 8 * bpp as u32;
+        let tile_count = super::VRAM_SIZE as u32 / tile_bytes;
+        let max_index = (1u32 << bpp) - 1;
+
+        image.clear(16 * 8, tile_count / 16 * 8, TRANSPARENT);
+        for tile in 0..tile_count {
+            let addr = (tile * tile_bytes) as u16;
+            let (x0, y0) = (tile % 16 * 8, tile / 16 * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let index = self.read_chr_entry(bpp, addr, 8, (x, y), (false, false)) as u32;
+                    let level = (index * 255 / max_index) as u8;
+                    let gray = Rgb { r: level, g: level, b: level };
+                    image.set_pixel(x0 + x as u32, y0 + y as u32, gray);
+                }
+            }
+        }
+    }
+}
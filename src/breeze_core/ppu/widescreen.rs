@@ -0,0 +1,121 @@
+//! Widescreen rendering hack
+//!
+//! Many games keep valid tilemap data to the left and right of the visible area (because they
+//! scroll in that direction, for example). When enabled, the PPU additionally renders the BG
+//! layers into a margin on both sides of the picture, producing a wider frame.
+//!
+//! Only BG layers are drawn into the margins. Sprites, windows and color math are ignored, which
+//! causes visible glitches in some games (which can be blacklisted in their per-game config).
+
+use super::{Ppu, Rgb, SnesRgb, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Largest supported margin (in pixels on each side)
+pub const MAX_WIDESCREEN_MARGIN: u16 = 64;
+
+/// State of the widescreen renderer
+pub struct Widescreen {
+    /// Width of the margin on each side in pixels
+    margin: u16,
+    /// Frame buffer for the whole picture (`RGB24`, like `Ppu::framebuf`, but
+    /// `SCREEN_WIDTH + 2 * margin` pixels wide)
+    pub framebuf: Vec<u8>,
+}
+
+impl Widescreen {
+    pub fn width(&self) -> u32 { SCREEN_WIDTH + 2 * self.margin as u32 }
+
+    /// Sets the pixel at screen position `x` (which is negative in the left margin).
+    fn set_pixel(&mut self, x: i16, y: u16, rgb: Rgb) {
+        let col = (x + self.margin as i16) as usize;
+        let start = (y as usize * self.width() as usize + col) * 3;
+        self.framebuf[start] = rgb.r;
+        self.framebuf[start+1] = rgb.g;
+        self.framebuf[start+2] = rgb.b;
+    }
+}
+
+impl Ppu {
+    /// Enables widescreen rendering with a margin of `margin` pixels on each side of the picture.
+    /// `0` disables it.
+    pub fn set_widescreen(&mut self, margin: u16) {
+        assert!(margin <= MAX_WIDESCREEN_MARGIN, "widescreen margin {} is too large", margin);
+        self.widescreen = if margin == 0 {
+            None
+        } else {
+            let width = SCREEN_WIDTH + 2 * margin as u32;
+            Some(Widescreen {
+                margin: margin,
+                framebuf: vec![0; width as usize * SCREEN_HEIGHT as usize * 3],
+            })
+        };
+    }
+
+    /// Returns the width of the widescreen margins (`0` if widescreen rendering is disabled).
+    pub fn widescreen_margin(&self) -> u16 {
+        self.widescreen.as_ref().map(|ws| ws.margin).unwrap_or(0)
+    }
+
+    /// Copies a pixel of the visible area into the widescreen frame.
+    pub fn set_widescreen_pixel(&mut self, x: u16, y: u16, rgb: Rgb) {
+        if let Some(ref mut ws) = self.widescreen {
+            ws.set_pixel(x as i16, y, rgb);
+        }
+    }
+
+    /// Renders the widescreen margins of the current scanline. Called after the last visible pixel
+    /// of the scanline was rendered.
+    pub fn render_widescreen_margins(&mut self) {
+        let margin = self.widescreen_margin() as i16;
+        let y = self.scanline;
+        for i in 0..margin {
+            for &x in &[i - margin, SCREEN_WIDTH as i16 + i] {
+                let rgb = self.render_margin_pixel(x);
+                if let Some(ref mut ws) = self.widescreen {
+                    ws.set_pixel(x, y, rgb);
+                }
+            }
+        }
+    }
+
+    /// Renders a pixel outside of the visible area, using only the BG layers.
+    fn render_margin_pixel(&self, x: i16) -> Rgb {
+        if self.forced_blank() {
+            return Rgb {r: 0, g: 0, b: 0};
+        }
+
+        // BG layers and priorities in the order they are drawn in by `get_raw_pixel` (front to
+        // back, without the sprites). Mode 7 has no tilemap to extend, so only the backdrop is
+        // drawn.
+        let order: &[(u8, u8)] = match self.bg_mode() {
+            0 => &[(1, 1), (2, 1), (1, 0), (2, 0), (3, 1), (4, 1), (3, 0), (4, 0)],
+            1 if self.bgmode & 0x08 != 0 => &[(3, 1), (1, 1), (2, 1), (1, 0), (2, 0), (3, 0)],
+            1 => &[(1, 1), (2, 1), (1, 0), (2, 0), (3, 1), (3, 0)],
+            2 ... 5 => &[(1, 1), (2, 1), (1, 0), (2, 0)],
+            6 => &[(1, 1), (1, 0)],
+            _ => &[],
+        };
+
+        // Look up each layer only once
+        let mut pixels: [Option<Option<(u8, SnesRgb)>>; 4] = [None; 4];
+        let mut color = self.cgram.get_color(0);
+        for &(bg, prio) in order {
+            if !self.bg_enabled(bg, false) { continue }
+            let pixel = match pixels[bg as usize - 1] {
+                Some(pixel) => pixel,
+                None => {
+                    let pixel = self.bg_pixel_at(bg, x);
+                    pixels[bg as usize - 1] = Some(pixel);
+                    pixel
+                }
+            };
+            if let Some((pixel_prio, rgb)) = pixel {
+                if pixel_prio == prio {
+                    color = rgb;
+                    break;
+                }
+            }
+        }
+
+        self.apply_brightness(color).to_rgb(&self.color_lut)
+    }
+}
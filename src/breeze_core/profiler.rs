@@ -0,0 +1,130 @@
+//! Cycle profiler
+//!
+//! Records how many master clock cycles are spent executing the instruction at each `bank:PC`
+//! address. The hottest addresses tell homebrew developers where their game spends its time, and
+//! help us find out what a slow game is doing.
+//!
+//! If the CPU tracks its call stack, cycles are also attributed to the subroutine (or interrupt
+//! handler) that was executing, which is easier to digest than a list of single instructions.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Profiling data of a single instruction address
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hotspot {
+    /// Address of the instruction (`$BBAAAA`)
+    pub addr: u32,
+    /// Master cycles spent executing the instruction (including wait states and DMA it triggered)
+    pub cycles: u64,
+    /// Number of times the instruction was executed
+    pub count: u64,
+}
+
+/// Profiling data of a subroutine
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FunctionCost {
+    /// Entry point of the subroutine or interrupt handler (`$BBAAAA`), or `None` for code that
+    /// wasn't called by anything (the main loop, usually)
+    pub entry: Option<u32>,
+    /// Master cycles spent in the subroutine itself (not including subroutines it called)
+    pub cycles: u64,
+}
+
+/// Collects the cycles spent per instruction address.
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    /// Maps addresses to (cycles, count)
+    entries: HashMap<u32, (u64, u64)>,
+    /// Maps subroutine entry points to cycles
+    functions: HashMap<Option<u32>, u64>,
+    total_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self { Self::default() }
+
+    /// Records an execution of the instruction at `addr` that took `cycles` master cycles.
+    pub fn record(&mut self, addr: u32, cycles: u64) {
+        let entry = self.entries.entry(addr).or_insert((0, 0));
+        entry.0 += cycles;
+        entry.1 += 1;
+        self.total_cycles += cycles;
+    }
+
+    /// Attributes `cycles` master cycles to the subroutine starting at `entry` (`None` for
+    /// top-level code). This is recorded in addition to `record`.
+    pub fn record_function(&mut self, entry: Option<u32>, cycles: u64) {
+        *self.functions.entry(entry).or_insert(0) += cycles;
+    }
+
+    /// Discards all collected data.
+    pub fn reset(&mut self) {
+        self.entries.clear();
+        self.functions.clear();
+        self.total_cycles = 0;
+    }
+
+    /// Returns the number of master cycles recorded in total.
+    pub fn total_cycles(&self) -> u64 { self.total_cycles }
+
+    /// Returns the `n` addresses where the most cycles were spent, hottest first.
+    pub fn top(&self, n: usize) -> Vec<Hotspot> {
+        let mut hotspots: Vec<_> = self.entries.iter().map(|(&addr, &(cycles, count))| Hotspot {
+            addr: addr,
+            cycles: cycles,
+            count: count,
+        }).collect();
+        // Sort by address as well, so the order is deterministic
+        hotspots.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.addr.cmp(&b.addr)));
+        hotspots.truncate(n);
+        hotspots
+    }
+
+    /// Returns the `n` subroutines where the most cycles were spent, hottest first.
+    pub fn top_functions(&self, n: usize) -> Vec<FunctionCost> {
+        let mut functions: Vec<_> = self.functions.iter().map(|(&entry, &cycles)| FunctionCost {
+            entry: entry,
+            cycles: cycles,
+        }).collect();
+        functions.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.entry.cmp(&b.entry)));
+        functions.truncate(n);
+        functions
+    }
+
+    /// Formats the `n` hottest subroutines as a table. `describe` can name entry points (eg. using
+    /// a symbol table).
+    pub fn function_report<F>(&self, n: usize, describe: F) -> String
+    where F: Fn(u32) -> Option<String> {
+        let mut s = format!("{:>8}  {:>14} {:>6}  {}", "entry", "cycles", "%", "name");
+        for function in self.top_functions(n) {
+            let (addr, name) = match function.entry {
+                Some(entry) => (format!("${:02X}:{:04X}", entry >> 16, entry & 0xffff),
+                    describe(entry).unwrap_or_default()),
+                None => ("-".to_string(), "(top level)".to_string()),
+            };
+            write!(s, "\n{:>8}  {:>14} {:>6.2}  {}", addr, function.cycles,
+                self.percent(function.cycles), name).unwrap();
+        }
+        s
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total_cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.total_cycles as f64
+        }
+    }
+
+    /// Formats the `n` hottest addresses as a table.
+    pub fn report(&self, n: usize) -> String {
+        let mut s = format!("{:>8}  {:>14} {:>6}  {:>10}", "address", "cycles", "%", "count");
+        for hotspot in self.top(n) {
+            write!(s, "\n${:02X}:{:04X}  {:>14} {:>6.2}  {:>10}", hotspot.addr >> 16,
+                hotspot.addr & 0xffff, hotspot.cycles, self.percent(hotspot.cycles),
+                hotspot.count).unwrap();
+        }
+        s
+    }
+}
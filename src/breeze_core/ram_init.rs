@@ -0,0 +1,106 @@
+//! Power-on RAM initialization patterns
+//!
+//! The SNES doesn't clear any of its RAMs on power-on, so their initial contents depend on the
+//! console (and on luck). Most games don't care, but some rely on specific garbage being there, and
+//! others are broken by it. We let the user pick how WRAM, VRAM and the APU's RAM are filled.
+
+use libsavestate::SaveState;
+
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Describes how RAM is filled when the emulated console is powered on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RamInit {
+    /// Fill everything with `$00` (the default, and what most emulators do)
+    Zero,
+    /// Fill everything with `$55`
+    Pattern55,
+    /// Alternate between pages (256 Bytes) filled with `$00` and `$FF`. This is roughly what many
+    /// real consoles end up with.
+    Alternating,
+    /// Fill with pseudo-random data generated from the given seed. The same seed will always
+    /// produce the same RAM contents.
+    Random(u32),
+}
+
+impl Default for RamInit {
+    fn default() -> Self {
+        RamInit::Zero
+    }
+}
+
+impl RamInit {
+    /// Fills `ram` according to this pattern.
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamInit::Zero => for b in ram.iter_mut() { *b = 0x00 },
+            RamInit::Pattern55 => for b in ram.iter_mut() { *b = 0x55 },
+            RamInit::Alternating => {
+                for (i, b) in ram.iter_mut().enumerate() {
+                    *b = if (i >> 8) & 1 == 0 { 0x00 } else { 0xff };
+                }
+            }
+            RamInit::Random(seed) => {
+                // Xorshift32. The state must never be 0, so we map a 0 seed to something else.
+                let mut state = if seed == 0 { 0x2545f491 } else { seed };
+                for b in ram.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *b = (state >> 24) as u8;
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for RamInit {
+    type Err = String;
+
+    /// Parses `zero`, `55`, `alternating` or `random[:SEED]`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "zero" => Ok(RamInit::Zero),
+            "55" => Ok(RamInit::Pattern55),
+            "alternating" => Ok(RamInit::Alternating),
+            "random" => Ok(RamInit::Random(0)),
+            _ if s.starts_with("random:") => match s["random:".len()..].parse() {
+                Ok(seed) => Ok(RamInit::Random(seed)),
+                Err(_) => Err(format!("invalid RAM init seed: {}", &s["random:".len()..])),
+            },
+            _ => Err(format!("unknown RAM init pattern: {} (expected one of `zero`, `55`, \
+                              `alternating` or `random[:SEED]`)", s)),
+        }
+    }
+}
+
+/// Saved as a kind byte followed by the 32-bit seed (which is 0 for all non-random kinds).
+impl SaveState for RamInit {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let (kind, seed): (u8, u32) = match *self {
+            RamInit::Zero => (0, 0),
+            RamInit::Pattern55 => (1, 0),
+            RamInit::Alternating => (2, 0),
+            RamInit::Random(seed) => (3, seed),
+        };
+        try!(kind.save_state(w));
+        seed.save_state(w)
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        let mut kind = 0u8;
+        let mut seed = 0u32;
+        try!(kind.restore_state(r));
+        try!(seed.restore_state(r));
+        *self = match kind {
+            0 => RamInit::Zero,
+            1 => RamInit::Pattern55,
+            2 => RamInit::Alternating,
+            3 => RamInit::Random(seed),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                           "invalid RAM init pattern in save state")),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! RAM search for finding cheats
+//!
+//! Implements the classic cheat search workflow: Take a snapshot of WRAM, play a bit, then narrow
+//! down the addresses by comparing their current values to the snapshot (or to a constant). After
+//! a few iterations, only the address holding the value you're looking for (lives, health, ...)
+//! should be left.
+
+use snes::WRAM_SIZE;
+
+use std::str::FromStr;
+
+/// Decides which addresses are kept by `RamSearch::filter`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchFilter {
+    /// The value is equal to the one in the snapshot
+    Unchanged,
+    /// The value differs from the one in the snapshot
+    Changed,
+    /// The value is greater than the one in the snapshot
+    Increased,
+    /// The value is less than the one in the snapshot
+    Decreased,
+    /// The value changed by exactly this amount since the snapshot (wrapping around)
+    ChangedBy(i16),
+    /// The value is equal to a constant
+    Equal(u8),
+    /// The value is not equal to a constant
+    NotEqual(u8),
+    /// The value is greater than a constant
+    Greater(u8),
+    /// The value is less than a constant
+    Less(u8),
+}
+
+impl SearchFilter {
+    /// Returns whether an address with the given previous and current value passes the filter.
+    pub fn matches(&self, old: u8, new: u8) -> bool {
+        match *self {
+            SearchFilter::Unchanged => new == old,
+            SearchFilter::Changed => new != old,
+            SearchFilter::Increased => new > old,
+            SearchFilter::Decreased => new < old,
+            SearchFilter::ChangedBy(n) => new == old.wrapping_add(n as u8),
+            SearchFilter::Equal(n) => new == n,
+            SearchFilter::NotEqual(n) => new != n,
+            SearchFilter::Greater(n) => new > n,
+            SearchFilter::Less(n) => new < n,
+        }
+    }
+}
+
+impl FromStr for SearchFilter {
+    type Err = String;
+
+    /// Parses a filter. Without a value, `=`, `!=`, `>` and `<` compare to the snapshot, with a
+    /// value (`=5`, `>$10`) they compare to a constant. `+N` and `-N` keep values that changed by
+    /// exactly `N`. Values are decimal or, prefixed with `$`, hexadecimal.
+    fn from_str(s: &str) -> Result<Self, String> {
+        fn parse_value(s: &str, max: u32) -> Result<u32, String> {
+            let value = if s.starts_with('$') {
+                u32::from_str_radix(&s[1..], 16)
+            } else {
+                s.parse()
+            };
+            match value {
+                Ok(value) if value <= max => Ok(value),
+                _ => Err(format!("invalid value: {}", s)),
+            }
+        }
+
+        let s = s.trim();
+        let (op, value) = match s.find(|c: char| c != '=' && c != '!' && c != '>' && c != '<' &&
+                                                 c != '+' && c != '-') {
+            Some(i) => (&s[..i], Some(s[i..].trim())),
+            None => (s, None),
+        };
+
+        Ok(match (op, value) {
+            ("=", None) => SearchFilter::Unchanged,
+            ("!=", None) => SearchFilter::Changed,
+            (">", None) => SearchFilter::Increased,
+            ("<", None) => SearchFilter::Decreased,
+            ("=", Some(v)) => SearchFilter::Equal(try!(parse_value(v, 0xff)) as u8),
+            ("!=", Some(v)) => SearchFilter::NotEqual(try!(parse_value(v, 0xff)) as u8),
+            (">", Some(v)) => SearchFilter::Greater(try!(parse_value(v, 0xff)) as u8),
+            ("<", Some(v)) => SearchFilter::Less(try!(parse_value(v, 0xff)) as u8),
+            ("+", Some(v)) => SearchFilter::ChangedBy(try!(parse_value(v, 0xff)) as i16),
+            ("-", Some(v)) => SearchFilter::ChangedBy(-(try!(parse_value(v, 0xff)) as i16)),
+            _ => return Err(format!("invalid search filter: {}", s)),
+        })
+    }
+}
+
+/// An address that is still in the running
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    /// Full address (`$7E0000` - `$7FFFFF`)
+    pub addr: u32,
+    /// Value at the time of the last snapshot
+    pub value: u8,
+}
+
+impl Candidate {
+    /// Returns a Pro Action Replay code that forces this address to `value`.
+    pub fn cheat_code(&self, value: u8) -> String {
+        format!("{:06X}{:02X}", self.addr, value)
+    }
+}
+
+/// State of a RAM search.
+pub struct RamSearch {
+    /// WRAM contents at the time of the last snapshot
+    snapshot: Vec<u8>,
+    /// WRAM offsets still in the running, in ascending order
+    candidates: Vec<u32>,
+}
+
+impl RamSearch {
+    /// Starts a new search with all of WRAM as candidates.
+    pub fn new(wram: &[u8]) -> Self {
+        assert_eq!(wram.len(), WRAM_SIZE);
+        RamSearch {
+            snapshot: wram.to_vec(),
+            candidates: (0..WRAM_SIZE as u32).collect(),
+        }
+    }
+
+    /// Removes all candidates whose current value doesn't pass `filter`, then takes a new
+    /// snapshot.
+    pub fn filter(&mut self, wram: &[u8], filter: SearchFilter) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&i| filter.matches(snapshot[i as usize], wram[i as usize]));
+        self.snapshot.copy_from_slice(wram);
+    }
+
+    /// Takes a new snapshot without removing any candidates.
+    pub fn snapshot(&mut self, wram: &[u8]) {
+        self.snapshot.copy_from_slice(wram);
+    }
+
+    /// Returns the number of remaining candidates.
+    pub fn len(&self) -> usize { self.candidates.len() }
+
+    pub fn is_empty(&self) -> bool { self.candidates.is_empty() }
+
+    /// Returns the remaining candidates along with their value in the last snapshot.
+    pub fn candidates<'a>(&'a self) -> Box<Iterator<Item=Candidate> + 'a> {
+        Box::new(self.candidates.iter().map(move |&i| Candidate {
+            addr: 0x7e0000 + i,
+            value: self.snapshot[i as usize],
+        }))
+    }
+}
@@ -38,11 +38,11 @@ impl super::Recorder for Recorder {
 }
 
 pub struct Replayer {
-    reader: Box<BufRead>,
+    reader: Box<BufRead + Send>,
 }
 
 impl super::Replayer for Replayer {
-    fn new(reader: Box<BufRead>, _snes: &Snes) -> io::Result<Self> {
+    fn new(reader: Box<BufRead + Send>, _snes: &Snes) -> io::Result<Self> {
         Ok(Replayer {
             reader: reader,
         })
@@ -3,14 +3,16 @@
 //! Contains submodules that implement specific recording formats.
 
 mod custom;
+mod movie;
 mod smv;
 
 use input::Ports;
 use snes::Snes;
 
 use std::io::{self, Write, BufRead, Seek};
+use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RecordingFormat {
     /// Custom RLE compressed format
     ///
@@ -21,26 +23,45 @@ pub enum RecordingFormat {
     ///
     /// This implements SMV version 4, used by Snes9x 1.51
     Smv,
+
+    /// Breeze's own movie format, which stores the state the recording started from
+    ///
+    /// See the `movie` module for a description of the format.
+    Movie,
 }
 
 impl Default for RecordingFormat {
     fn default() -> Self {
-        RecordingFormat::Smv
+        RecordingFormat::Movie
+    }
+}
+
+impl FromStr for RecordingFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "smv" => Ok(RecordingFormat::Smv),
+            "custom" => Ok(RecordingFormat::Custom),
+            "movie" => Ok(RecordingFormat::Movie),
+            _ => Err(format!("unknown recording format: {} (expected `movie`, `smv` or `custom`)",
+                             s)),
+        }
     }
 }
 
 /// Trait for recording sources
 ///
 /// This shouldn't be implemented manually
-pub trait WriteSeek : Write + Seek {}
+pub trait WriteSeek : Write + Seek + Send {}
 
-impl<T: Write + Seek> WriteSeek for T {}
+impl<T: Write + Seek + Send> WriteSeek for T {}
 
 // TODO: Implement methods that detect the `RecordingFormat` from a file extension or a `Read`
 // instance (based on the header)
 
 /// Trait for input recorders
-pub trait Recorder {
+pub trait Recorder: Send {
     /// Create a new recorder, writing to the given writer
     fn new(writer: Box<WriteSeek>, snes: &Snes) -> io::Result<Self> where Self: Sized;
 
@@ -52,15 +73,19 @@ pub trait Recorder {
 }
 
 /// Trait for record replayers
-pub trait Replayer {
+pub trait Replayer: Send {
     /// Create a new replayer, reading from the given buffered reader.
-    fn new(reader: Box<BufRead>, snes: &Snes) -> io::Result<Self> where Self: Sized;
+    fn new(reader: Box<BufRead + Send>, snes: &Snes) -> io::Result<Self> where Self: Sized;
 
     /// Replay the next frame, updating the state of `ports`.
     ///
     /// Called when input is latched. If the game doesn't latch input, we guarantee that this will
     /// still be called once per frame.
     fn replay_frame(&mut self, ports: &mut Ports) -> io::Result<()>;
+
+    /// Returns the save state the recording was started from, if the format stores one. It's
+    /// restored before the replay starts.
+    fn take_start_state(&mut self) -> Option<Vec<u8>> { None }
 }
 
 /// Create a recorder for a specified format.
@@ -72,16 +97,41 @@ pub fn create_recorder(format: RecordingFormat,
     Ok(match format {
         RecordingFormat::Custom => Box::new(try!(custom::Recorder::new(writer, snes))),
         RecordingFormat::Smv => Box::new(try!(smv::Recorder::new(writer, snes))),
+        RecordingFormat::Movie => Box::new(try!(movie::Recorder::new(writer, snes))),
     })
 }
 
 pub fn create_replayer(format: RecordingFormat,
-                       reader: Box<BufRead>,
+                       reader: Box<BufRead + Send>,
                        snes: &Snes)
                        -> io::Result<Box<Replayer>> {
     debug!("creating replayer for {:?} format", format);
     Ok(match format {
         RecordingFormat::Custom => Box::new(try!(custom::Replayer::new(reader, snes))),
         RecordingFormat::Smv => Box::new(try!(smv::Replayer::new(reader, snes))),
+        RecordingFormat::Movie => Box::new(try!(movie::Replayer::new(reader, snes))),
     })
 }
+
+impl Snes {
+    /// Starts recording the input into `writer`.
+    pub fn start_recording(&mut self, format: RecordingFormat, writer: Box<WriteSeek>)
+                           -> io::Result<()> {
+        let recorder = try!(create_recorder(format, writer, self));
+        self.peripherals_mut().input.start_recording(recorder);
+        Ok(())
+    }
+
+    /// Starts replaying a recording. If the recording stores the state it was started from, that
+    /// state is restored first, so the replay is deterministic. User input is ignored until the
+    /// recording ends.
+    pub fn start_replay(&mut self, format: RecordingFormat, reader: Box<BufRead + Send>)
+                        -> io::Result<()> {
+        let mut replayer = try!(create_replayer(format, reader, self));
+        if let Some(state) = replayer.take_start_state() {
+            try!(self.load_state(&mut &state[..]));
+        }
+        self.peripherals_mut().input.start_replay(replayer);
+        Ok(())
+    }
+}
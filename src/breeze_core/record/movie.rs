@@ -0,0 +1,333 @@
+//! Breeze movie format
+//!
+//! A movie stores the state of the system when the recording was started and the input latched
+//! by the game afterwards, which is enough to reproduce the run exactly. All numbers are stored
+//! in little endian.
+//!
+//! The header:
+//!
+//! * 8 Bytes: Magic bytes `BRZMOVIE`
+//! * `u32`: Format version (currently 2)
+//! * `u64`: Hash of the ROM the movie was recorded with (see `Rom::hash`). Movies can only be
+//!   replayed with the same ROM.
+//! * 5 Bytes: Power-on RAM pattern, as written by the `SaveState` impl of `RamInit`. It isn't
+//!   needed to replay from the start state, but a reset during the movie fills RAM with it again.
+//! * `u32`: Length of the start state, followed by the start state itself (a save state in the
+//!   custom format, see `Snes::save_state`)
+//!
+//! The header is followed by one entry per input latch (at least one per frame), until the end of
+//! the file. Each entry contains, for both controller ports:
+//!
+//! * `u8`: Type of the peripheral plugged into the port (0 = none, 1 = joypad, 5 = multitap)
+//! * Joypad: `u16` button state (see `JoypadState::bits`)
+//! * Multitap: 4 `u16` button states, one for each slot
+
+use super::WriteSeek;
+use input::{Peripheral, Ports};
+use ram_init::RamInit;
+use snes::Snes;
+
+use breeze_backend::input::joypad::JoypadState;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use libsavestate::SaveState;
+
+use std::io::{self, BufRead, Read, Write};
+
+const MAGIC: &'static [u8; 8] = b"BRZMOVIE";
+const VERSION: u32 = 2;
+
+const NONE: u8 = 0;
+const JOYPAD: u8 = 1;
+const MULTITAP: u8 = 5;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Recorder for the movie format
+pub struct Recorder {
+    writer: Box<WriteSeek>,
+}
+
+impl super::Recorder for Recorder {
+    fn new(mut writer: Box<WriteSeek>, snes: &Snes) -> io::Result<Self> {
+        let mut state = Vec::new();
+        try!(snes.save_state(&mut state));
+
+        try!(writer.write_all(MAGIC));
+        try!(writer.write_u32::<LittleEndian>(VERSION));
+        try!(writer.write_u64::<LittleEndian>(snes.peripherals().rom.hash()));
+        try!(snes.ram_init().save_state(&mut writer));
+        try!(writer.write_u32::<LittleEndian>(state.len() as u32));
+        try!(writer.write_all(&state));
+
+        Ok(Recorder {
+            writer: writer,
+        })
+    }
+
+    fn record_frame(&mut self, ports: &Ports) -> io::Result<()> {
+        for port in &[&ports.0, &ports.1] {
+            match **port {
+                None => try!(self.writer.write_u8(NONE)),
+                Some(ref peripheral @ Peripheral::Joypad { .. }) => {
+                    let state = peripheral.joypad_state().unwrap();
+                    try!(self.writer.write_u8(JOYPAD));
+                    try!(self.writer.write_u16::<LittleEndian>(state.bits()));
+                }
+                Some(ref peripheral @ Peripheral::Multitap { .. }) => {
+                    try!(self.writer.write_u8(MULTITAP));
+                    for state in &peripheral.multitap_states().unwrap() {
+                        let bits = state.map_or(0, |state| state.bits());
+                        try!(self.writer.write_u16::<LittleEndian>(bits));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replayer for the movie format
+pub struct Replayer {
+    reader: Box<BufRead + Send>,
+    /// The state to restore before replaying, until it's taken by `take_start_state`
+    start_state: Option<Vec<u8>>,
+}
+
+impl super::Replayer for Replayer {
+    fn new(mut reader: Box<BufRead + Send>, snes: &Snes) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        try!(reader.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(invalid_data("not a Breeze movie".to_string()));
+        }
+        let version = try!(reader.read_u32::<LittleEndian>());
+        if version != VERSION {
+            return Err(invalid_data(format!("movie has version {}, expected {}",
+                version, VERSION)));
+        }
+
+        let rom_hash = try!(reader.read_u64::<LittleEndian>());
+        if rom_hash != snes.peripherals().rom.hash() {
+            // The start state would be restored on top of the wrong ROM
+            return Err(invalid_data(format!("movie was recorded with a different ROM (hash \
+                {:016x}, the loaded ROM has {:016x})", rom_hash, snes.peripherals().rom.hash())));
+        }
+
+        let mut ram_init = RamInit::default();
+        try!(ram_init.restore_state(&mut reader));
+        if ram_init != snes.ram_init() {
+            warn!("movie was recorded with RAM init pattern {:?}, but the emulator was started \
+                   with {:?} (resets during the replay will likely desync)", ram_init,
+                   snes.ram_init());
+        }
+
+        let len = try!(reader.read_u32::<LittleEndian>());
+        let mut state = Vec::new();
+        try!((&mut reader).take(len as u64).read_to_end(&mut state));
+        if state.len() != len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated start state"));
+        }
+
+        Ok(Replayer {
+            reader: reader,
+            start_state: Some(state),
+        })
+    }
+
+    fn replay_frame(&mut self, ports: &mut Ports) -> io::Result<()> {
+        for port in 0..2 {
+            let kind = try!(self.reader.read_u8());
+            let expected = match ports[port] {
+                None => NONE,
+                Some(Peripheral::Joypad { .. }) => JOYPAD,
+                Some(Peripheral::Multitap { .. }) => MULTITAP,
+            };
+            if kind != expected {
+                return Err(invalid_data(format!("movie has peripheral type {} in port {}, but \
+                    type {} is plugged in", kind, port + 1, expected)));
+            }
+
+            match ports[port] {
+                None => {}
+                Some(ref mut peripheral @ Peripheral::Joypad { .. }) => {
+                    let bits = try!(self.reader.read_u16::<LittleEndian>());
+                    peripheral.set_joypad_state(JoypadState::from_bits(bits));
+                }
+                Some(ref mut peripheral @ Peripheral::Multitap { .. }) => {
+                    let mut states = [JoypadState::new(); 4];
+                    for state in &mut states {
+                        *state = JoypadState::from_bits(
+                            try!(self.reader.read_u16::<LittleEndian>()));
+                    }
+                    peripheral.set_multitap_states(states);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn take_start_state(&mut self) -> Option<Vec<u8>> {
+        self.start_state.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Replayer, MAGIC, VERSION};
+    use input::{Peripheral, Ports};
+    use record::Recorder as RecorderImpl;
+    use record::Replayer as ReplayerImpl;
+    use record::RecordingFormat;
+    use rom::Rom;
+    use snes::Snes;
+
+    use breeze_backend::input::joypad::{JoypadImpl, JoypadState};
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Runs a test on a thread with a large stack. `Snes` contains all emulated memory inline,
+    /// which doesn't fit on the default test thread stack in unoptimized builds.
+    fn run<F: FnOnce() + Send + 'static>(test: F) {
+        let thread = thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(test).unwrap();
+        if let Err(payload) = thread.join() {
+            panic!(payload);
+        }
+    }
+
+    /// A writer whose contents can still be read after it was moved into a recorder
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> Vec<u8> { self.0.lock().unwrap().get_ref().clone() }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Seek for SharedBuf {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> { self.0.lock().unwrap().seek(pos) }
+    }
+
+    struct NoInput;
+
+    impl JoypadImpl for NoInput {
+        fn update_state(&mut self) -> JoypadState { JoypadState::new() }
+    }
+
+    fn snes_with_rom(fill: u8) -> Snes {
+        Snes::new(Rom::from_bytes(&vec![fill; 0x8000]).unwrap())
+    }
+
+    /// A joypad in port 1 and a multitap with 4 joypads in port 2
+    fn ports() -> Ports {
+        let joypads = (0..4).map(|_| Box::new(NoInput) as Box<JoypadImpl>).collect();
+        Ports(Some(Peripheral::new_joypad(Box::new(NoInput))),
+              Some(Peripheral::new_multitap(joypads)))
+    }
+
+    /// The joypad states of `ports()`, varied by `frame`
+    fn states(frame: u16) -> (JoypadState, [JoypadState; 4]) {
+        let pad = JoypadState::from_bits(0x8000 >> frame);
+        let mut tap = [JoypadState::new(); 4];
+        for (i, state) in tap.iter_mut().enumerate() {
+            *state = JoypadState::from_bits((frame << 4) | i as u16);
+        }
+        (pad, tap)
+    }
+
+    /// Records `frames` frames of input on `snes` and returns the movie.
+    fn record(snes: &Snes, frames: u16) -> Vec<u8> {
+        let buf = SharedBuf::default();
+        let mut recorder = Recorder::new(Box::new(buf.clone()), snes).unwrap();
+        let mut ports = ports();
+        for frame in 0..frames {
+            let (pad, tap) = states(frame);
+            ports.0.as_mut().unwrap().set_joypad_state(pad);
+            ports.1.as_mut().unwrap().set_multitap_states(tap);
+            recorder.record_frame(&ports).unwrap();
+        }
+        buf.contents()
+    }
+
+    #[test]
+    fn roundtrip() {
+        run(|| {
+            let snes = snes_with_rom(0);
+            let movie = record(&snes, 3);
+
+            let mut replayer = Replayer::new(Box::new(Cursor::new(movie)), &snes).unwrap();
+            let mut state = Vec::new();
+            snes.save_state(&mut state).unwrap();
+            assert!(replayer.take_start_state() == Some(state));
+
+            let mut ports = ports();
+            for frame in 0..3 {
+                replayer.replay_frame(&mut ports).unwrap();
+                let (pad, tap) = states(frame);
+                assert_eq!(ports.0.as_ref().unwrap().joypad_state(), Some(pad));
+                let replayed = ports.1.as_ref().unwrap().multitap_states().unwrap();
+                for (replayed, &expected) in replayed.iter().zip(&tap) {
+                    assert_eq!(*replayed, Some(expected));
+                }
+            }
+            // The movie ends here
+            assert!(replayer.replay_frame(&mut ports).is_err());
+        });
+    }
+
+    #[test]
+    fn peripheral_mismatch() {
+        run(|| {
+            let snes = snes_with_rom(0);
+            let movie = record(&snes, 1);
+
+            let mut replayer = Replayer::new(Box::new(Cursor::new(movie)), &snes).unwrap();
+            let mut ports = Ports(None, None);
+            assert!(replayer.replay_frame(&mut ports).is_err());
+        });
+    }
+
+    #[test]
+    fn rejects_other_rom() {
+        run(|| {
+            let movie = record(&snes_with_rom(0), 1);
+
+            let mut snes = snes_with_rom(0xea);
+            let err = snes.start_replay(RecordingFormat::Movie, Box::new(Cursor::new(movie)))
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!snes.peripherals().input.is_replaying());
+        });
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        run(|| {
+            let mut snes = snes_with_rom(0);
+            let mut movie = record(&snes, 1);
+            (&mut movie[MAGIC.len()..]).write_u32::<LittleEndian>(VERSION + 1).unwrap();
+
+            let err = snes.start_replay(RecordingFormat::Movie, Box::new(Cursor::new(movie)))
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!snes.peripherals().input.is_replaying());
+        });
+    }
+
+    #[test]
+    fn rejects_other_format() {
+        run(|| {
+            let mut snes = snes_with_rom(0);
+            let movie = Box::new(Cursor::new(b"BREEZEST not a movie".to_vec()));
+            assert!(snes.start_replay(RecordingFormat::Movie, movie).is_err());
+        });
+    }
+}
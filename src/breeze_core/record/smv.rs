@@ -43,6 +43,7 @@ impl super::Recorder for Recorder {
             match *port {
                 None => 0,
                 Some(Peripheral::Joypad {..}) => 1,
+                Some(Peripheral::Multitap {..}) => 5,
             }
         }
 
@@ -90,11 +91,11 @@ impl Drop for Recorder {
 }
 
 pub struct Replayer {
-    reader: Box<BufRead>,
+    reader: Box<BufRead + Send>,
 }
 
 impl super::Replayer for Replayer {
-    fn new(reader: Box<BufRead>, _snes: &Snes) -> io::Result<Self> {
+    fn new(reader: Box<BufRead + Send>, _snes: &Snes) -> io::Result<Self> {
         Ok(Replayer {
             reader: reader,
         })
@@ -0,0 +1,272 @@
+//! Rewind buffer
+//!
+//! Captures a save state every few frames and keeps the most recent ones in a ring buffer, so that
+//! frontends can instantly go back in time (see `Snes::rewind`).
+//!
+//! Only the newest state is stored in full. Consecutive states differ in few bytes, so every older
+//! state is stored as the XOR of itself and the next newer state, with runs of zeros compressed.
+//! Going back applies these deltas one after another, starting at the newest state.
+
+use snes::Snes;
+
+use std::collections::VecDeque;
+use std::io;
+
+/// An older state, stored as the difference to the next newer one
+struct Delta {
+    /// Frame number of the state
+    frame: u64,
+    /// Length of the state in bytes
+    len: usize,
+    /// The compressed XOR of the state and the next newer state (see `encode_delta`)
+    data: Vec<u8>,
+}
+
+/// Ring buffer of save states of the most recent frames.
+pub struct Rewind {
+    /// A state is captured every `interval` frames
+    interval: u64,
+    /// Maximum number of states kept (including the newest one)
+    capacity: usize,
+    /// Frame number and full state of the newest state
+    newest: Option<(u64, Vec<u8>)>,
+    /// Older states, newest first
+    older: VecDeque<Delta>,
+}
+
+impl Rewind {
+    /// Creates an empty rewind buffer that captures a state every `interval` frames and keeps
+    /// the last `capacity` of them.
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        assert!(interval > 0, "rewind interval must not be 0");
+        assert!(capacity > 0, "rewind capacity must not be 0");
+        Rewind {
+            interval: interval,
+            capacity: capacity,
+            newest: None,
+            older: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of states stored.
+    pub fn len(&self) -> usize {
+        self.older.len() + if self.newest.is_some() { 1 } else { 0 }
+    }
+
+    pub fn is_empty(&self) -> bool { self.newest.is_none() }
+
+    /// Returns the memory used by the stored states (in bytes).
+    pub fn memory_usage(&self) -> usize {
+        let newest = self.newest.as_ref().map_or(0, |&(_, ref state)| state.len());
+        self.older.iter().fold(newest, |sum, delta| sum + delta.data.len())
+    }
+
+    /// Returns the frame number of the oldest state stored.
+    pub fn oldest_frame(&self) -> Option<u64> {
+        match self.older.back() {
+            Some(delta) => Some(delta.frame),
+            None => self.newest.as_ref().map(|&(frame, _)| frame),
+        }
+    }
+
+    /// Called once per frame (after it was emulated). Captures the state of `snes` if its frame
+    /// number is a multiple of the interval.
+    pub fn capture(&mut self, snes: &Snes) -> io::Result<()> {
+        let frame = snes.frame_count();
+        if frame % self.interval != 0 {
+            return Ok(());
+        }
+        if let Some((newest_frame, _)) = self.newest {
+            if newest_frame == frame {
+                return Ok(());
+            }
+        }
+
+        let mut state = Vec::new();
+        try!(snes.save_state(&mut state));
+        if let Some((old_frame, old_state)) = self.newest.take() {
+            self.older.push_front(Delta {
+                frame: old_frame,
+                len: old_state.len(),
+                data: encode_delta(&old_state, &state),
+            });
+            if self.older.len() >= self.capacity {
+                self.older.pop_back();
+            }
+        }
+        self.newest = Some((frame, state));
+        Ok(())
+    }
+
+    /// Restores the newest state that's at least `frames` frames older than the current frame of
+    /// `snes`. The states newer than that are discarded. Returns the frame number of the restored
+    /// state, or `None` (without changing anything) if no state is old enough.
+    pub fn rewind(&mut self, snes: &mut Snes, frames: u64) -> io::Result<Option<u64>> {
+        let target = match snes.frame_count().checked_sub(frames) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let deltas = match self.newest {
+            None => return Ok(None),
+            Some((frame, _)) if frame <= target => 0,
+            Some(_) => match self.older.iter().position(|delta| delta.frame <= target) {
+                Some(index) => index + 1,
+                None => return Ok(None),
+            },
+        };
+
+        let (mut frame, mut state) = self.newest.take().unwrap();
+        for _ in 0..deltas {
+            let delta = self.older.pop_front().unwrap();
+            apply_delta(&mut state, &delta.data, delta.len);
+            frame = delta.frame;
+        }
+
+        try!(snes.load_state(&mut &state[..]));
+        snes.set_frame_count(frame);
+        self.newest = Some((frame, state));
+        Ok(Some(frame))
+    }
+
+    /// Discards all states.
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let (mut value, mut shift) = (0, 0);
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= (byte as usize & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Computes the XOR of `old` and `new` (padding the shorter one with zeros) and compresses it.
+///
+/// The result is a sequence of a zero run length and a literal length (as varints), followed by
+/// that many literal bytes.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let len = old.len().max(new.len());
+    let xor = |i: usize| old.get(i).cloned().unwrap_or(0) ^ new.get(i).cloned().unwrap_or(0);
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 { i += 1; }
+        let literals_start = i;
+        while i < len && xor(i) != 0 { i += 1; }
+
+        write_varint(&mut out, literals_start - zeros_start);
+        write_varint(&mut out, i - literals_start);
+        out.extend((literals_start..i).map(&xor));
+    }
+    out
+}
+
+/// Turns `state` into the older state a delta was computed from. `len` is the older state's
+/// length.
+fn apply_delta(state: &mut Vec<u8>, delta: &[u8], len: usize) {
+    if state.len() < len {
+        state.resize(len, 0);
+    }
+
+    let (mut pos, mut i) = (0, 0);
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for byte in &mut state[i..i + literals] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += literals;
+    }
+
+    state.truncate(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, encode_delta, read_varint, write_varint};
+
+    #[test]
+    fn varint_roundtrip() {
+        let values = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 123_456_789];
+        let mut buf = Vec::new();
+        for &value in &values {
+            write_varint(&mut buf, value);
+        }
+        assert_eq!(buf[0], 0);
+        assert_eq!(&buf[3..5], &[0x80, 0x01]);
+
+        let mut pos = 0;
+        for &value in &values {
+            assert_eq!(read_varint(&buf, &mut pos), value);
+        }
+        assert_eq!(pos, buf.len());
+    }
+
+    /// Encodes the delta from `new` back to `old` and checks that applying it restores `old`.
+    fn roundtrip(old: &[u8], new: &[u8]) {
+        let delta = encode_delta(old, new);
+        let mut state = new.to_vec();
+        apply_delta(&mut state, &delta, old.len());
+        assert_eq!(state, old);
+    }
+
+    #[test]
+    fn delta_identical() {
+        let state = vec![0x55; 1000];
+        roundtrip(&state, &state);
+        // Only the zero run is stored
+        assert_eq!(encode_delta(&state, &state).len(), 3);
+    }
+
+    #[test]
+    fn delta_scattered_changes() {
+        let old: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 1;
+        new[1] ^= 0xff;
+        new[200] = 0;
+        new[4999] ^= 0x80;
+        roundtrip(&old, &new);
+    }
+
+    #[test]
+    fn delta_long_runs() {
+        // Zero and literal runs longer than a single varint byte can describe
+        let old = vec![0; 70_000];
+        let mut new = old.clone();
+        for byte in &mut new[300..20_300] {
+            *byte = 0xaa;
+        }
+        roundtrip(&old, &new);
+        roundtrip(&new, &old);
+    }
+
+    #[test]
+    fn delta_different_lengths() {
+        let short: Vec<u8> = (0..100).map(|i| (i * 7) as u8).collect();
+        let long: Vec<u8> = (0..300).map(|i| (i * 3) as u8).collect();
+        roundtrip(&short, &long);
+        roundtrip(&long, &short);
+        roundtrip(&[], &long);
+        roundtrip(&long, &[]);
+    }
+}
@@ -1,70 +1,262 @@
 //! ROM image loading code
 
+use hash::hash_bytes;
+
+use breeze_backend::pacing::{NTSC_FRAME_RATE, PAL_FRAME_RATE};
+
 use std::cmp;
+use std::fmt;
+use std::fs::File;
 use std::str;
+use std::str::FromStr;
 use std::i16;
-use std::io;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Largest supported cartridge RAM size. Bigger header values are clamped to this.
+const MAX_RAM_SIZE: u32 = 512 * 1024;
 
 fn invalid_data(err: String) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, err)
 }
 
 /// The (decoded) SNES header
+///
+/// Whether the image started with a copier header is recorded in the `DumpInfo` instead, since
+/// that's a property of the dump and not of the cartridge.
 #[derive(Clone)]
 pub struct RomHeader {
     /// ASCII title, filled with spaces to 21 Bytes
     title: [u8; 21],
+    map_mode: MapMode,
+    fast_rom: bool,
+    cartridge_type: u8,
     rom_size: u32,
     ram_size: u32,
+    region: Region,
     checksum: u16,
-    rom_type: RomType,
+    checksum_complement: u16,
 }
 
+/// How the ROM is mapped into the address space
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum RomType {
+pub enum MapMode {
+    /// 32 KB of ROM in the upper half of every bank
     LoRom,
+    /// 64 KB of ROM in banks `$40-$7D` and `$C0-$FF`, mirrored to the upper halves of the other
+    /// banks
     HiRom,
+    /// HiROM extended to 8 MB: Banks `$C0-$FF` hold the first 4 MB, banks `$40-$7D` the rest
+    ExHiRom,
+}
+
+/// The video standard of a console (and the games made for it)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Region {
+    /// 60 Hz (Japan, North and South America, Korea)
+    Ntsc,
+    /// 50 Hz (Europe, Australia)
+    Pal,
+}
+
+impl Region {
+    /// Decodes the destination code in the ROM header. Unknown codes are assumed to be NTSC.
+    fn from_destination_code(code: u8) -> Region {
+        match code {
+            0x02 ... 0x0c | 0x11 => Region::Pal,
+            _ => Region::Ntsc,
+        }
+    }
+
+    /// Returns the number of frames the console renders per second.
+    pub fn frame_rate(&self) -> f64 {
+        match *self {
+            Region::Ntsc => NTSC_FRAME_RATE,
+            Region::Pal => PAL_FRAME_RATE,
+        }
+    }
+}
+
+impl Default for Region {
+    fn default() -> Self { Region::Ntsc }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            _ => Err(format!("unknown region: {} (expected `ntsc` or `pal`)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+        })
+    }
+}
+
+/// Chunk orders some copier devices stored HiROM games in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Interleave {
+    /// The 32 KB halves of every 64 KB bank are separated: The upper halves of all banks come
+    /// first, followed by the lower halves. Used by the Super Wild Card and similar copiers.
+    Swc,
+    /// Game Doctor's layout of 24 Mbit games: Like `Swc`, but the last 1.5 MB are rotated by
+    /// 512 KB.
+    GameDoctor,
+}
+
+impl Interleave {
+    /// Restores the original order of an image stored in this format. Returns `None` if the
+    /// image doesn't have a size this format can be used with.
+    fn deinterleave(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Interleave::Swc => {
+                if bytes.is_empty() || bytes.len() % 0x10000 != 0 {
+                    return None;
+                }
+                let half = bytes.len() / 2;
+                let mut out = Vec::with_capacity(bytes.len());
+                let halves = bytes[..half].chunks(0x8000).zip(bytes[half..].chunks(0x8000));
+                for (upper, lower) in halves {
+                    out.extend_from_slice(lower);
+                    out.extend_from_slice(upper);
+                }
+                Some(out)
+            }
+            Interleave::GameDoctor => {
+                if bytes.len() != 0x300000 {
+                    return None;
+                }
+                let mut rotated = Vec::with_capacity(bytes.len());
+                rotated.extend_from_slice(&bytes[..0x180000]);
+                rotated.extend_from_slice(&bytes[0x200000..]);
+                rotated.extend_from_slice(&bytes[0x180000..0x200000]);
+                Interleave::Swc.deinterleave(&rotated)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Interleave {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Interleave::Swc => "SWC",
+            Interleave::GameDoctor => "Game Doctor",
+        })
+    }
+}
+
+/// How a ROM image was dumped, and what the loader did to turn it into a plain image
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DumpInfo {
+    /// Number of files (or byte slices) the image was put together from
+    pub parts: usize,
+    /// A 512 byte copier header was stripped (from at least one of the parts)
+    pub copier_header: bool,
+    /// The image was stored in an interleaved format and has been deinterleaved
+    pub interleave: Option<Interleave>,
 }
 
 impl RomHeader {
+    /// Returns the game's title, with the padding removed.
+    pub fn title(&self) -> &str {
+        // Non-ASCII bytes were replaced with spaces when loading the header
+        str::from_utf8(&self.title).unwrap().trim_right()
+    }
+
+    /// Returns the map mode the ROM is loaded with.
+    pub fn map_mode(&self) -> MapMode { self.map_mode }
+
+    /// Returns whether the game runs from FastROM (accessed at 3.58 MHz in banks `$80-$FF`).
+    pub fn fast_rom(&self) -> bool { self.fast_rom }
+
+    /// Returns the raw cartridge type byte, which describes the chips on the cartridge (RAM,
+    /// battery, coprocessors).
+    pub fn cartridge_type(&self) -> u8 { self.cartridge_type }
+
+    /// Returns whether the cartridge RAM is battery-backed (and should be saved to disk).
+    pub fn has_battery(&self) -> bool {
+        self.ram_size > 0 && match self.cartridge_type & 0x0f {
+            0x02 | 0x05 | 0x06 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the ROM size according to the header, in Bytes.
+    pub fn rom_size(&self) -> u32 { self.rom_size }
+
+    /// Returns the size of the cartridge RAM, in Bytes.
+    pub fn ram_size(&self) -> u32 { self.ram_size }
+
+    /// Returns the region the game was made for.
+    pub fn region(&self) -> Region { self.region }
+
+    /// Returns the checksum stored in the header.
+    pub fn checksum(&self) -> u16 { self.checksum }
+
+    /// Returns the checksum complement stored in the header. In a valid header, this is the
+    /// checksum with all bits inverted.
+    pub fn checksum_complement(&self) -> u16 { self.checksum_complement }
+
     fn dump(&self) {
-        info!("ROM name: '{}'", str::from_utf8(&self.title).unwrap_or("").trim_right());
+        info!("ROM name: '{}'", self.title());
         info!("{} KB ROM / {} KB Cartridge RAM", self.rom_size / 1024, self.ram_size / 1024);
+        info!("mapper: {:?}", self.map_mode);
+        info!("region: {}", self.region);
     }
 
     /// Loads the ROM header from the given ROM byte slice.
     ///
-    /// `rom_type` is the expected type of the ROM header, based on its location. This method
+    /// `map_mode` is the expected map mode of the ROM, based on the header's location. This method
     /// decides where to look for the header based on this value.
     ///
     /// Returns the decoded `RomHeader` and a scoring value. The higher the score, the more likely
-    /// the header matches the `RomType`.
+    /// the header matches the `MapMode`.
     ///
     /// In case `bytes` is too small for the expected ROM type, a dummy header and the maximum
     /// negative score will be returned.
-    fn load(bytes: &[u8], rom_type: RomType) -> (RomHeader, i16) {
+    fn load(bytes: &[u8], map_mode: MapMode) -> (RomHeader, i16) {
         fn dummy_result() -> (RomHeader, i16) {
             (RomHeader {
-                title: [0; 21],
+                title: [b' '; 21],
+                map_mode: MapMode::LoRom,
+                fast_rom: false,
+                cartridge_type: 0,
                 rom_size: 0,
                 ram_size: 0,
+                region: Region::Ntsc,
                 checksum: 0,
-                rom_type: RomType::LoRom,
+                checksum_complement: 0,
             }, i16::MIN)
         }
 
         // Extract header slice
-        let bytes = match rom_type {
-            RomType::LoRom => if bytes.len() < 0x8000 {
+        let bytes = match map_mode {
+            MapMode::LoRom => if bytes.len() < 0x8000 {
                 return dummy_result();
             } else {
                 &bytes[0x7FFF - 63..0x7FFF + 1]
             },
-            RomType::HiRom => if bytes.len() < 0x10000 {
+            MapMode::HiRom => if bytes.len() < 0x10000 {
                 return dummy_result();
             } else {
                 &bytes[0xFFFF - 63..0xFFFF + 1]
-            }
+            },
+            // The header is mapped to the same address as in HiROM, but that's in the second half
+            // of the ROM
+            MapMode::ExHiRom => if bytes.len() < 0x410000 {
+                return dummy_result();
+            } else {
+                &bytes[0x40FFFF - 63..0x40FFFF + 1]
+            },
         };
 
         // The header size must be correct (the ROM loader won't pass a wrong size)
@@ -73,7 +265,7 @@ impl RomHeader {
         // Score value. Decremented whenever something isn't right. Subject to tweaking.
         let mut score = 0;
 
-        debug!("loading {:?} header", rom_type);
+        debug!("loading {:?} header", map_mode);
         debug!("raw rom header: {:?}", bytes);
 
         // Byte 28/29 is the checksum's complement followed by the checksum itself in Byte 30/31.
@@ -118,46 +310,67 @@ impl RomHeader {
         //  * `0011`: LoROM + SA-1
         //  * `0101`: ExHiROM
         //  * `1010`: HiROM + SPC7110
-        // (TODO)
+        // (TODO: The coprocessors)
 
-        let header_rom_type = match bytes[21] & 0x0f {
-            0 => RomType::LoRom,
-            1 => RomType::HiRom,
+        let header_map_mode = match bytes[21] & 0x0f {
+            0 => MapMode::LoRom,
+            1 => MapMode::HiRom,
+            5 => MapMode::ExHiRom,
             t => {
                 debug!("unknown / unimplemented ROM type {}", t);
                 score -= 10;    // until we actually implement this (FIXME Dirty hack)
-                RomType::LoRom
+                MapMode::LoRom
             }
         };
 
-        if header_rom_type == rom_type {
-            debug!("type: {:?}", rom_type);
+        if header_map_mode == map_mode {
+            debug!("type: {:?}", map_mode);
         } else {
-            debug!("expected rom type {:?}, got {:?}", rom_type, header_rom_type);
+            debug!("expected map mode {:?}, got {:?}", map_mode, header_map_mode);
             score -= 3;
         }
 
-        // bytes[22] is the chipset info. For now, we don't care about that.
+        let fast_rom = bytes[21] & 0x10 != 0;
+
+        // bytes[22] is the chipset info (cartridge type)
         debug!("chipset: 0x{:02X}", bytes[22]);
 
         debug!("ROM/RAM size values: {:02X} {:02X}", bytes[23], bytes[24]);
         // Size values are masked with 0x0F to prevent overlong bitshifts. The valid values are all
         // in range 0x00 to 0x0F anyway.
         let rom_size = 0x400 << (bytes[23] as u32 & 0x0f);
-        let ram_size = 0x400 << (bytes[24] as u32 & 0x0f);
+        // A RAM size value of 0 means that there's no cartridge RAM
+        let ram_size = match bytes[24] & 0x0f {
+            0 => 0,
+            n => 0x400 << n as u32,
+        };
+        let ram_size = if ram_size > MAX_RAM_SIZE {
+            warn!("header specifies {} KB of cartridge RAM, using {} KB", ram_size / 1024,
+                  MAX_RAM_SIZE / 1024);
+            MAX_RAM_SIZE
+        } else {
+            ram_size
+        };
         debug!("{} KB of ROM, {} KB of cartridge RAM", rom_size / 1024, ram_size / 1024);
 
-        // bytes[25-26] is a vendor code (doesn't matter)
-        debug!("vendor code: 0x{:02X}{:02X}", bytes[25], bytes[26]);
+        // bytes[25] is the destination code, which tells us whether this is a PAL game
+        debug!("destination code: 0x{:02X}", bytes[25]);
+        let region = Region::from_destination_code(bytes[25]);
+        // bytes[26] is a vendor code (doesn't matter)
+        debug!("vendor code: 0x{:02X}", bytes[26]);
         // 27 = version (also doesn't matter for us)
         debug!("version: 0x{:02X}", bytes[27]);
 
         (RomHeader {
             title: title,
+            map_mode: map_mode,
+            fast_rom: fast_rom,
+            cartridge_type: bytes[22],
             rom_size: rom_size,
             ram_size: ram_size,
+            region: region,
             checksum: rom_checksum,
-            rom_type: rom_type,
+            checksum_complement: check_inv,
         }, score)
     }
 }
@@ -166,48 +379,84 @@ impl RomHeader {
 #[derive(Clone)]
 pub struct Rom {
     header: RomHeader,
+    dump_info: DumpInfo,
     ram: Vec<u8>,
     rom: Vec<u8>,
 }
 
 // NB: If we want to support "realistic" saves, we'd just save the cartridge RAM and nothing else
-impl_save_state!(Rom { ram } ignore { header, rom });
+impl_save_state!(Rom { ram } ignore { header, dump_info, rom });
 
 impl Rom {
     /// Loads a ROM from raw data.
-    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Rom> {
-        // Would it be useful if we returned the warnings somehow?
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Rom> {
+        Rom::from_parts(&[bytes])
+    }
 
-        debug!("raw size: {} bytes (${:X})", bytes.len(), bytes.len());
+    /// Loads a ROM that was dumped into several parts, given in order. Every part may start with
+    /// a copier header.
+    pub fn from_parts(parts: &[&[u8]]) -> io::Result<Rom> {
+        // Would it be useful if we returned the warnings somehow?
 
-        // ROMs may begin with a 512 Bytes SMC header. It needs to go.
-        match bytes.len() % 1024 {
-            512 => {
-                info!("stripping SMC header");
-                bytes = &bytes[512..];
-            }
-            0 => {},
-            n => {
-                let fmt = format!("len() % 1024 == {} (expected 512 or 0)", n);
-                error!("{}", fmt);
-                return Err(invalid_data(fmt));
+        let mut info = DumpInfo { parts: parts.len(), ..DumpInfo::default() };
+        let mut bytes = Vec::new();
+        for (i, &part) in parts.iter().enumerate() {
+            debug!("part {}: raw size: {} bytes (${:X})", i + 1, part.len(), part.len());
+
+            // ROMs may begin with a 512 Bytes SMC header. It needs to go.
+            match part.len() % 1024 {
+                512 => {
+                    info!("stripping SMC header");
+                    info.copier_header = true;
+                    bytes.extend_from_slice(&part[512..]);
+                }
+                0 => bytes.extend_from_slice(part),
+                n => {
+                    let fmt = format!("len() % 1024 == {} (expected 512 or 0)", n);
+                    error!("{}", fmt);
+                    return Err(invalid_data(fmt));
+                }
             }
         }
 
         // Try all header locations and pick the one that's probably right.
         // Oh how much I wish there was a real standard for this.
         // FIXME: We might want to... like... not play *literally every file* but warn instead :)
-        let (lo_header, lo_score) = RomHeader::load(bytes,
-                                                    RomType::LoRom);
-        let (hi_header, hi_score) = RomHeader::load(bytes,
-                                                    RomType::HiRom);
-
-        info!("LoROM/HiROM scores: {}, {}", lo_score, hi_score);
-        let header = if lo_score > hi_score {
-            lo_header
+        let (lo_header, lo_score) = RomHeader::load(&bytes,
+                                                    MapMode::LoRom);
+        let (hi_header, hi_score) = RomHeader::load(&bytes,
+                                                    MapMode::HiRom);
+        let (exhi_header, exhi_score) = RomHeader::load(&bytes,
+                                                        MapMode::ExHiRom);
+
+        info!("LoROM/HiROM/ExHiROM scores: {}, {}, {}", lo_score, hi_score, exhi_score);
+        let (mut header, mut score) = if lo_score > hi_score {
+            (lo_header, lo_score)
         } else {
-            hi_header
+            (hi_header, hi_score)
         };
+        if exhi_score > score {
+            header = exhi_header;
+            score = exhi_score;
+        }
+
+        // Interleaved dumps are HiROM games whose header ended up in the wrong place. Only use
+        // a deinterleaved layout if its header looks better than all the others.
+        for &interleave in &[Interleave::Swc, Interleave::GameDoctor] {
+            if let Some(deinterleaved) = interleave.deinterleave(&bytes) {
+                let (il_header, il_score) = RomHeader::load(&deinterleaved, MapMode::HiRom);
+                debug!("{} interleaved HiROM score: {}", interleave, il_score);
+                if il_score > score {
+                    header = il_header;
+                    score = il_score;
+                    info.interleave = Some(interleave);
+                }
+            }
+        }
+        if let Some(interleave) = info.interleave {
+            info!("deinterleaving {} dump", interleave);
+            bytes = interleave.deinterleave(&bytes).unwrap();
+        }
 
         header.dump();
 
@@ -237,106 +486,281 @@ impl Rom {
 
         Ok(Rom {
             header: header,
+            dump_info: info,
             ram: ram,
             rom: rom,
         })
     }
 
+    /// Loads a ROM file.
+    ///
+    /// Dumps split into numbered files (`game.1`, `game.2`, ...) are loaded completely when
+    /// passing the first one.
+    pub fn load_file<P: AsRef<Path>>(path: P) -> io::Result<Rom> {
+        let path = path.as_ref();
+        let mut paths = vec![path.to_path_buf()];
+        if path.extension().map_or(false, |ext| ext == "1") {
+            for n in 2.. {
+                let next = path.with_extension(n.to_string());
+                if !next.exists() { break }
+                paths.push(next);
+            }
+            if paths.len() > 1 {
+                info!("loading a ROM split into {} files", paths.len());
+            }
+        }
+        Rom::load_files(&paths)
+    }
+
+    /// Loads a ROM that was dumped into several files, given in order.
+    pub fn load_files(paths: &[PathBuf]) -> io::Result<Rom> {
+        let mut bufs = Vec::new();
+        for path in paths {
+            let mut buf = Vec::new();
+            try!(try!(File::open(path)).read_to_end(&mut buf));
+            bufs.push(buf);
+        }
+        let parts: Vec<&[u8]> = bufs.iter().map(|buf| &buf[..]).collect();
+        Rom::from_parts(&parts)
+    }
+
+    /// Returns the ROM's internal header.
+    pub fn header(&self) -> &RomHeader { &self.header }
+
+    /// Returns how the ROM was dumped and what the loader did to load it.
+    pub fn dump_info(&self) -> &DumpInfo { &self.dump_info }
+
     pub fn get_title(&self) -> Option<&str> {
-        str::from_utf8(&self.header.title).ok().map(|s| s.trim_right())
+        Some(self.header.title())
     }
 
-    fn resolve_lorom(&mut self, bank: u8, addr: u16) -> &mut u8 {
-        match addr {
-            0x0000 ... 0x7fff => {
-                // Cartridge RAM mapped to the low 32 KB
-                // (there's other stuff here, but that's handled much earlier than we are called)
-                match bank {
-                    0x70 ... 0x7d => {
-                        let a = (bank as u32 - 0x70) * 0x8000 + addr as u32;
-                        self.ram.get_mut(a as usize).unwrap_or_else(|| out_of_ram_bounds(bank, addr, a))
-                    }
-                    0xfe ... 0xff => {
-                        // last 64k of RAM
-                        let start = self.ram.len() as u32 - 64 * 1024;
-                        let a = start + (bank - 0xfe) as u32 * 0x8000 + addr as u32;
-                        self.ram.get_mut(a as usize).unwrap_or_else(|| out_of_ram_bounds(bank, addr, a))
-                    }
-                    _ => {
-                        // 0x40 ... 0x6f | 0x7e ... 0xfd
-                        panic!("attempted to access unmapped address: ${:02X}:{:04X}", bank, addr);
-                    }
+    /// Returns the region the game was made for, according to its header.
+    pub fn region(&self) -> Region { self.header.region }
+
+    /// Returns the cartridge RAM (SRAM). Empty if the cartridge has none.
+    pub fn ram(&self) -> &[u8] { &self.ram }
+
+    /// Returns mutable access to the cartridge RAM, eg. to restore its contents from a file.
+    pub fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+
+    /// Returns a hash of the ROM contents (without SMC header), which identifies the game.
+    pub fn hash(&self) -> u64 { hash_bytes(&self.rom) }
+
+    /// Returns the offset into the cartridge RAM that an address in the cartridge RAM area maps
+    /// to, or `None` if the address is outside of that area.
+    ///
+    /// The RAM is mirrored across the whole area, so the offset is always inside the RAM (unless
+    /// the cartridge has no RAM at all). Since RAM sizes are powers of 2, games can detect the
+    /// size by checking where the mirrors start.
+    fn sram_offset(&self, bank: u8, addr: u16) -> Option<usize> {
+        let offset = match self.header.map_mode {
+            // 32 KB in the low half of banks $70-$7D and $F0-$FF
+            MapMode::LoRom => match (bank, addr) {
+                (0x70 ... 0x7d, 0x0000 ... 0x7fff) |
+                (0xf0 ... 0xff, 0x0000 ... 0x7fff) => {
+                    (bank as usize & 0x0f) * 0x8000 + addr as usize
+                }
+                _ => return None,
+            },
+            // 8 KB at $6000-$7FFF of banks $20-$3F and $A0-$BF
+            MapMode::HiRom | MapMode::ExHiRom => match (bank, addr) {
+                (0x20 ... 0x3f, 0x6000 ... 0x7fff) |
+                (0xa0 ... 0xbf, 0x6000 ... 0x7fff) => {
+                    (bank as usize & 0x1f) * 0x2000 + (addr as usize & 0x1fff)
                 }
+                _ => return None,
             },
+        };
+        Some(offset & self.ram.len().wrapping_sub(1))
+    }
+
+    fn resolve_lorom(&mut self, bank: u8, addr: u16) -> Option<&mut u8> {
+        match addr {
+            // Cartridge RAM (handled by `sram_offset`) and other stuff that's handled much earlier
+            // than we are called
+            0x0000 ... 0x7fff => None,
             0x8000 ... 0xffff => match bank {
                 // LoROM is mapped to the higher 8 pages
                 0xfe => {
                     let a = 0x3f0000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
+                    self.rom.get_mut(a as usize)
                 }
                 0xff => {
                     let a = 0x3f8000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
+                    self.rom.get_mut(a as usize)
                 }
                 0x80 ... 0xfd | 0x00 ... 0x7d => {
                     // `& !0x80` because 0x80-0xFD mirrors 0x00-0x7D
                     let a = (bank as u32 & !0x80) * 0x8000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
+                    self.rom.get_mut(a as usize)
                 }
-                _ => panic!("attempted to access unmapped address: ${:02X}:{:04X}", bank, addr)
+                _ => None,
             },
             _ => unreachable!()
         }
     }
 
-    fn resolve_hirom(&mut self, bank: u8, addr: u16) -> &mut u8 {
+    fn resolve_hirom(&mut self, bank: u8, addr: u16) -> Option<&mut u8> {
         let addr = addr as usize;
         match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf if addr >= 0x8000 => {
-                &mut self.rom[(bank as usize & 0x3f) << 16 | addr]
-            }
-            0x20 ... 0x3f | 0xa0 ... 0xbf if addr >= 0x6000 && addr <= 0x7fff => {
-                // `addr` is masked with `0x1fff` since HiROM seems to have up to 8K mirrored RAM
-                &mut self.ram[addr & 0x1fff]
+                self.rom.get_mut((bank as usize & 0x3f) << 16 | addr)
             }
             0x40 ... 0x7d | 0xc0 ... 0xfd => {
-                &mut self.rom[((bank as usize & 0x7f) - 0x40) << 16 | addr]
+                self.rom.get_mut(((bank as usize & 0x7f) - 0x40) << 16 | addr)
             }
             0x7e ... 0x7f => unreachable!(),    // WRAM banks
             0xfe ... 0xff => {
-                &mut self.rom[(bank as usize - 0xfe + 0x3e) << 16 | addr]
+                self.rom.get_mut((bank as usize - 0xfe + 0x3e) << 16 | addr)
             }
-            _ => panic!("attempted to access unmapped address: ${:02}:{:04X}", bank, addr),
+            _ => None,
         }
     }
 
-    fn resolve_addr(&mut self, bank: u8, addr: u16) -> &mut u8 {
-        match self.header.rom_type {
-            RomType::LoRom => self.resolve_lorom(bank, addr),
-            RomType::HiRom => self.resolve_hirom(bank, addr),
+    fn resolve_exhirom(&mut self, bank: u8, addr: u16) -> Option<&mut u8> {
+        let addr = addr as usize;
+        // Like HiROM, banks $00-$3F and $80-$BF mirror the upper halves of the 64 KB banks
+        let base = match bank {
+            0x80 ... 0xbf if addr >= 0x8000 => 0,
+            0xc0 ... 0xff => 0,
+            0x00 ... 0x3f if addr >= 0x8000 => 0x400000,
+            0x40 ... 0x7d => 0x400000,
+            0x7e ... 0x7f => unreachable!(),    // WRAM banks
+            _ => return None,
+        };
+        self.rom.get_mut(base + ((bank as usize & 0x3f) << 16 | addr))
+    }
+
+    /// Resolves an address to the ROM or cartridge RAM byte it is mapped to. Returns `None` if
+    /// nothing is mapped there (or the address is outside the ROM/RAM).
+    fn resolve_addr(&mut self, bank: u8, addr: u16) -> Option<&mut u8> {
+        if let Some(offset) = self.sram_offset(bank, addr) {
+            return self.ram.get_mut(offset);
+        }
+
+        match self.header.map_mode {
+            MapMode::LoRom => self.resolve_lorom(bank, addr),
+            MapMode::HiRom => self.resolve_hirom(bank, addr),
+            MapMode::ExHiRom => self.resolve_exhirom(bank, addr),
         }
     }
 }
 
 impl Rom {
     pub fn load(&mut self, bank: u8, addr: u16) -> u8 {
-        *self.resolve_addr(bank, addr)
+        if let Some(byte) = self.resolve_addr(bank, addr) {
+            return *byte;
+        }
+        // Games probe for cartridge RAM, so this isn't an error if the cartridge has none.
+        // FIXME This should return open bus.
+        if self.sram_offset(bank, addr).is_some() { 0 } else { unmapped(bank, addr) }
     }
 
     pub fn store(&mut self, bank: u8, addr: u16, value: u8) {
         if addr >= 0x8000 {
             warn!("writing ${:02X} to ROM address ${:02X}:{:04X}", value, bank, addr);
         }
-        *self.resolve_addr(bank, addr) = value;
+        if let Some(byte) = self.resolve_addr(bank, addr) {
+            *byte = value;
+            return;
+        }
+        if self.sram_offset(bank, addr).is_none() {
+            unmapped(bank, addr);
+        }
+    }
+
+    /// Reads a byte without panicking if the address isn't mapped. Meant for debugging tools.
+    pub fn peek(&mut self, bank: u8, addr: u16) -> Option<u8> {
+        self.resolve_addr(bank, addr).map(|byte| *byte)
+    }
+
+    /// Writes a byte without panicking (or warning about writes to ROM). Returns `false` if the
+    /// address isn't mapped. Meant for debugging tools.
+    pub fn poke(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        match self.resolve_addr(bank, addr) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
     }
 }
 
-fn out_of_ram_bounds(bank: u8, addr: u16, abs: u32) -> ! {
-    panic!("RAM access out of bounds at {:02X}:{:04X} -> {:04X}",
-        bank, addr, abs)
+fn unmapped(bank: u8, addr: u16) -> ! {
+    panic!("attempted to access unmapped (or out of bounds) ROM address: ${:02X}:{:04X}",
+        bank, addr)
 }
 
-fn out_of_rom_bounds(bank: u8, addr: u16, abs: u32) -> ! {
-    panic!("ROM access out of bounds at {:02X}:{:04X} -> {:06X}",
-        bank, addr, abs)
+#[cfg(test)]
+mod tests {
+    use super::{Interleave, MapMode, Rom};
+
+    /// Builds a HiROM image with a valid header and distinct contents in every 32 KB chunk.
+    fn hirom(size: usize) -> Vec<u8> {
+        let mut bytes: Vec<u8> = (0..size).map(|i| (i / 0x8000 * 0x40 + i % 251) as u8).collect();
+        {
+            let header = &mut bytes[0xffc0..0x10000];
+            header[..21].copy_from_slice(b"INTERLEAVE TEST      ");
+            header[21] = 0x31;  // FastROM, HiROM
+            header[22] = 0;
+            header[23] = (size / 0x400).trailing_zeros() as u8;
+            header[24] = 0;
+            header[25] = 1;
+            // Placeholder checksum, the complement and checksum bytes always add up to $1FE
+            header[28..32].copy_from_slice(&[0xff, 0xff, 0, 0]);
+        }
+        let checksum = bytes.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        bytes[0xffdc..0xffe0].copy_from_slice(&[!checksum as u8, (!checksum >> 8) as u8,
+                                                checksum as u8, (checksum >> 8) as u8]);
+        bytes
+    }
+
+    /// Stores an image in the SWC layout: the upper halves of all banks, then the lower halves.
+    fn swc_interleave(bytes: &[u8]) -> Vec<u8> {
+        let banks = || bytes.chunks(0x10000);
+        banks().flat_map(|bank| bank[0x8000..].iter())
+            .chain(banks().flat_map(|bank| bank[..0x8000].iter()))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn plain_hirom() {
+        let bytes = hirom(0x20000);
+        let rom = Rom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.dump_info().interleave, None);
+        assert_eq!(rom.header.map_mode, MapMode::HiRom);
+        assert!(rom.rom == bytes);
+    }
+
+    #[test]
+    fn swc() {
+        let bytes = hirom(0x40000);
+        let interleaved = swc_interleave(&bytes);
+        assert!(interleaved != bytes);
+        assert!(Interleave::Swc.deinterleave(&interleaved) == Some(bytes.clone()));
+
+        let rom = Rom::from_bytes(&interleaved).unwrap();
+        assert_eq!(rom.dump_info().interleave, Some(Interleave::Swc));
+        assert_eq!(rom.header.map_mode, MapMode::HiRom);
+        assert_eq!(rom.header.title(), "INTERLEAVE TEST");
+        assert!(rom.rom == bytes);
+
+        // Sizes that aren't a multiple of 64 KB can't be interleaved
+        assert_eq!(Interleave::Swc.deinterleave(&interleaved[..0x18000]), None);
+    }
+
+    #[test]
+    fn game_doctor() {
+        let bytes = hirom(0x300000);
+        // SWC layout with the last 1.5 MB rotated by 512 KB
+        let swc = swc_interleave(&bytes);
+        let mut interleaved = swc[..0x180000].to_vec();
+        interleaved.extend_from_slice(&swc[0x280000..]);
+        interleaved.extend_from_slice(&swc[0x180000..0x280000]);
+
+        assert!(Interleave::GameDoctor.deinterleave(&interleaved) == Some(bytes));
+        assert_eq!(Interleave::GameDoctor.deinterleave(&interleaved[..0x200000]), None);
+    }
 }
@@ -0,0 +1,87 @@
+//! Running the emulator until a stop condition is met
+//!
+//! `Snes::run_until` is the building block for embedding the core in other programs: It emulates
+//! until any of the conditions in a `StopConditions` is met and reports which one it was.
+
+use snes::Snes;
+
+use breeze_backend::{BackendAction, BackendResult};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Conditions that make `Snes::run_until` return. With none of them set, it runs until the
+/// renderer requests an exit.
+#[derive(Clone, Debug, Default)]
+pub struct StopConditions {
+    /// Stop after this many frames were completed
+    pub frames: Option<u64>,
+    /// Stop once this many master clock cycles were emulated (checked after every instruction, so
+    /// the budget may be exceeded by a few cycles)
+    pub cycles: Option<u64>,
+    /// Stop when the CPU is about to execute an instruction at one of these addresses (in the
+    /// form `$BBAAAA`)
+    pub breakpoints: Vec<u32>,
+    /// Stop when this flag is set (eg. by another thread). It is not reset by `run_until`.
+    pub stop_flag: Option<Arc<AtomicBool>>,
+}
+
+/// Why `Snes::run_until` returned
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Break {
+    /// The requested number of frames was emulated
+    Frames,
+    /// The cycle budget was used up
+    Cycles,
+    /// The CPU reached the breakpoint at the contained address
+    Breakpoint(u32),
+    /// The stop flag was set
+    Stopped,
+    /// The renderer requested an exit
+    Exit,
+    /// Emulation is paused (see `Snes::pause`), so nothing was emulated
+    Paused,
+}
+
+impl Snes {
+    /// Emulates until one of the given stop conditions is met.
+    ///
+    /// `render` is called for every completed frame, just like in `render_frame`, and completed
+    /// frames are captured into the rewind buffer (see `step`). Unless emulation is paused, at
+    /// least one instruction is executed, so this can be used to continue from a breakpoint.
+    pub fn run_until<F>(&mut self, stop: &StopConditions, mut render: F) -> BackendResult<Break>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        // Like `run_frame`, don't emulate anything while paused
+        if self.is_paused() {
+            return Ok(Break::Paused);
+        }
+
+        let start_cy = self.master_cy();
+        let mut frames = 0;
+        self.peripherals_mut().apu.clear_audio();
+
+        loop {
+            if let Some(actions) = try!(self.step(&mut render)) {
+                if actions.contains(&BackendAction::Exit) { return Ok(Break::Exit) }
+                frames += 1;
+                if stop.frames.map_or(false, |max| frames >= max) {
+                    return Ok(Break::Frames);
+                }
+                // Keep the audio of the current frame only, like `render_frame`
+                self.peripherals_mut().apu.clear_audio();
+            }
+            if stop.cycles.map_or(false, |max| self.master_cy() - start_cy >= max) {
+                return Ok(Break::Cycles);
+            }
+            if !stop.breakpoints.is_empty() && !self.cpu().waiting() {
+                let pc = (self.cpu().pbr as u32) << 16 | self.cpu().pc as u32;
+                if stop.breakpoints.contains(&pc) {
+                    return Ok(Break::Breakpoint(pc));
+                }
+            }
+            if let Some(ref flag) = stop.stop_flag {
+                if flag.load(Ordering::Relaxed) { return Ok(Break::Stopped) }
+            }
+        }
+    }
+}
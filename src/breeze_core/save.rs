@@ -1,17 +1,30 @@
 //! Savestate writing and reading
+//!
+//! Save states in the custom format start with a header containing a version number. Since the
+//! rest of the state is a raw dump of the emulated components, a state saved by a different
+//! version of the emulator can't be restored, and is rejected when its version doesn't match.
 
 use snes::Snes;
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use libsavestate::SaveState;
 
 use std::io::prelude::*;
 use std::io::{self, BufWriter};
 
+/// Magic bytes at the start of a save state in the custom format
+const MAGIC: &'static [u8; 8] = b"BREEZEST";
+
+/// Version of the custom save state format. Must be bumped whenever the saved state changes (eg.
+/// when a field is added to an emulated component).
+pub const SAVE_STATE_VERSION: u32 = 6;
+
 /// Enum of supported save state formats
 pub enum SaveStateFormat {
     /// ZSNES V0.6 (WIP)
     Zsnes,
-    /// Custom binary format (unspecified format, subject to change)
+    /// Custom binary format (a versioned header, followed by an unspecified format that is subject
+    /// to change)
     Custom,
 }
 
@@ -32,12 +45,42 @@ impl Snes {
         }
     }
 
-    pub fn restore_save_state(&mut self, format: SaveStateFormat, r: &mut BufRead) -> io::Result<()> {
+    pub fn restore_save_state(&mut self, format: SaveStateFormat, mut r: &mut BufRead) -> io::Result<()> {
         // FIXME Remove `format` parameter when autodetection is implemented (and return the detected type instead)
         match format {
             SaveStateFormat::Zsnes => self.load_zsnes(r),
-            SaveStateFormat::Custom => self.restore_state(r),
+            SaveStateFormat::Custom => self.load_state(&mut r),
+        }
+    }
+
+    /// Saves the state of the whole system (CPU, PPU, APU, DMA channels, WRAM and cartridge RAM) in
+    /// the custom format.
+    pub fn save_state(&self, w: &mut Write) -> io::Result<()> {
+        try!(w.write_all(MAGIC));
+        try!(w.write_u32::<LittleEndian>(SAVE_STATE_VERSION));
+        SaveState::save_state(self, w)
+    }
+
+    /// Restores a state saved by `save_state`.
+    ///
+    /// Fails if the data isn't a save state or was saved with a different `SAVE_STATE_VERSION`.
+    /// In that case, the system is left untouched. If reading the state itself fails, the system
+    /// may be left in a partially restored state.
+    pub fn load_state(&mut self, r: &mut Read) -> io::Result<()> {
+        let mut magic = [0; 8];
+        try!(r.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a breeze save state"));
         }
+
+        let version = try!(r.read_u32::<LittleEndian>());
+        if version != SAVE_STATE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "save state has version {}, but only version {} is supported",
+                version, SAVE_STATE_VERSION)));
+        }
+
+        SaveState::restore_state(self, r)
     }
 
     fn save_zsnes(&self, w: &mut Write) -> io::Result<()> {
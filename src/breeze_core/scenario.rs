@@ -0,0 +1,294 @@
+//! Scripted end-to-end test scenarios
+//!
+//! A scenario runs a ROM headless with scripted controller input and checks the state of the
+//! system at given points. This allows writing regression tests for game compatibility without
+//! any test ROM support. Scenarios are described in a file with one command per line:
+//!
+//! ```text
+//! # Comments start with `#`. Paths are relative to the scenario file.
+//! rom game.sfc
+//! input 60-65 start           # hold Start on controller 1 while frames 60 to 65 are latched
+//! input p2 100 a right        # press A and Right on controller 2 for frame 100
+//! run 300                     # emulate 300 frames
+//! expect mem 7E0010 = 05      # bytes in the CPU address space
+//! expect sram 0000 = 01 02 03 # bytes in cartridge RAM
+//! expect hash 4f1a0c3e9b2d7785
+//! run 60
+//! expect mem 7E0010 = 06
+//! ```
+//!
+//! `input` lines are collected before the scenario starts and can appear anywhere. All other
+//! commands are executed in order. Frames are counted from 0: The input for frame `N` is what the
+//! controller reports when it is read after `N` frames were rendered. Overlapping `input` ranges
+//! press the buttons of all of them. All numbers except frame counts are hexadecimal.
+
+use input::Peripheral;
+use snes::Snes;
+use test_rom::{catch_panics, load_rom, Outcome};
+
+use breeze_backend::capture::ScriptedJoypad;
+use breeze_backend::input::joypad::{JoypadButton, JoypadState};
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Buttons held on a controller for a range of frames
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputRange {
+    /// Controller port (0 or 1)
+    pub port: u8,
+    /// First frame (inclusive)
+    pub start: usize,
+    /// Last frame (inclusive)
+    pub end: usize,
+    pub buttons: Vec<JoypadButton>,
+}
+
+/// A command executed while running a scenario
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Emulate the given number of frames
+    Run(u32),
+    /// Check bytes in the CPU's address space, starting at a `$BBAAAA` address
+    ExpectMemory { addr: u32, bytes: Vec<u8> },
+    /// Check bytes in cartridge RAM, starting at an offset
+    ExpectSram { offset: usize, bytes: Vec<u8> },
+    /// Check the hash of the last frame (see `Snes::frame_hash`)
+    ExpectHash(u64),
+}
+
+/// A parsed scenario file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Scenario {
+    pub rom: PathBuf,
+    pub inputs: Vec<InputRange>,
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// Reads a scenario file (see the module documentation for the format).
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>> {
+        let path = path.as_ref();
+        let reader = BufReader::new(try!(File::open(path)));
+        let mut lines = Vec::new();
+        for line in reader.lines() {
+            lines.push(try!(line));
+        }
+        let base = path.parent().unwrap_or(Path::new(""));
+        Ok(try!(Self::parse(&lines.join("\n"), base)
+            .map_err(|e| format!("{}: {}", path.display(), e))))
+    }
+
+    /// Parses a scenario. The ROM path is resolved relative to `base`.
+    pub fn parse(s: &str, base: &Path) -> Result<Self, String> {
+        let mut rom = None;
+        let mut inputs = Vec::new();
+        let mut steps = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() { continue }
+
+            let result = match tokens[0] {
+                "rom" if tokens.len() == 2 => {
+                    rom = Some(base.join(tokens[1]));
+                    Ok(())
+                }
+                "input" => parse_input(&tokens[1..]).map(|input| inputs.push(input)),
+                "run" if tokens.len() == 2 => tokens[1].parse()
+                    .map(|frames| steps.push(Step::Run(frames)))
+                    .map_err(|_| format!("invalid frame count: {}", tokens[1])),
+                "expect" => parse_expect(&tokens[1..]).map(|step| steps.push(step)),
+                _ => Err(format!("invalid command: {}", line.trim())),
+            };
+            try!(result.map_err(|e| format!("line {}: {}", i + 1, e)));
+        }
+
+        match rom {
+            Some(rom) => Ok(Scenario {
+                rom: rom,
+                inputs: inputs,
+                steps: steps,
+            }),
+            None => Err("no ROM given (use `rom PATH`)".to_string()),
+        }
+    }
+
+    /// Builds the `ScriptedJoypad` script for a controller port from the input ranges.
+    fn script(&self, port: u8) -> Vec<(usize, JoypadState)> {
+        let ranges: Vec<_> = self.inputs.iter().filter(|input| input.port == port).collect();
+        // The pressed buttons can only change where a range starts or ends
+        let mut points: Vec<usize> = ranges.iter()
+            .flat_map(|range| vec![range.start, range.end + 1])
+            .collect();
+        points.sort();
+        points.dedup();
+
+        points.into_iter().map(|frame| {
+            let mut state = JoypadState::new();
+            for range in ranges.iter().filter(|range| range.start <= frame && frame <= range.end) {
+                for &button in &range.buttons {
+                    state.set(button, true);
+                }
+            }
+            (frame, state)
+        }).collect()
+    }
+
+    /// Runs the scenario and returns its outcome. Panics are reported as errors.
+    pub fn run(&self) -> Outcome {
+        let rom = match load_rom(&self.rom) {
+            Ok(rom) => rom,
+            Err(e) => return Outcome::Error(format!("couldn't load ROM: {}", e)),
+        };
+
+        catch_panics(|| {
+            let mut snes = Snes::new(rom);
+            // Panics are reported as errors, like for test ROMs
+            snes.set_crash_log(None);
+            let frame = Arc::new(AtomicUsize::new(0));
+            for port in 0..2 {
+                if port == 0 || self.inputs.iter().any(|input| input.port == port) {
+                    let joypad = ScriptedJoypad::new(frame.clone(), self.script(port));
+                    snes.peripherals_mut().input.ports[port] =
+                        Some(Peripheral::new_joypad(Box::new(joypad)));
+                }
+            }
+
+            for step in &self.steps {
+                if let Err(msg) = self.run_step(&mut snes, &frame, step) {
+                    return Outcome::Fail(format!("after {} frames: {}", snes.frame_count(), msg));
+                }
+            }
+            Outcome::Pass
+        })
+    }
+
+    fn run_step(&self, snes: &mut Snes, frame: &AtomicUsize, step: &Step) -> Result<(), String> {
+        match *step {
+            Step::Run(frames) => {
+                for _ in 0..frames {
+                    try!(snes.render_frame(|_| {
+                        frame.fetch_add(1, Ordering::SeqCst);
+                        Ok(Vec::new())
+                    }).map_err(|e| format!("emulation failed: {}", e)));
+                }
+                Ok(())
+            }
+            Step::ExpectMemory { addr, ref bytes } => {
+                let actual: Vec<Option<u8>> = (0..bytes.len()).map(|i| {
+                    let addr = addr + i as u32;
+                    snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16)
+                }).collect();
+                if actual.iter().zip(bytes).all(|(&actual, &expected)| actual == Some(expected)) {
+                    Ok(())
+                } else {
+                    Err(format!("expected ${:06X} = {}, found {}", addr, format_bytes(bytes),
+                        format_optional_bytes(&actual)))
+                }
+            }
+            Step::ExpectSram { offset, ref bytes } => {
+                let sram = snes.peripherals().rom.ram();
+                match sram.get(offset..offset + bytes.len()) {
+                    Some(actual) if actual == &bytes[..] => Ok(()),
+                    Some(actual) => Err(format!("expected SRAM ${:04X} = {}, found {}", offset,
+                        format_bytes(bytes), format_bytes(actual))),
+                    None => Err(format!("SRAM ${:04X}-${:04X} is out of bounds (SRAM size: ${:X})",
+                        offset, offset + bytes.len() - 1, sram.len())),
+                }
+            }
+            Step::ExpectHash(expected) => {
+                let hash = snes.frame_hash();
+                if hash == expected {
+                    Ok(())
+                } else {
+                    Err(format!("expected frame hash {:016x}, got {:016x}", expected, hash))
+                }
+            }
+        }
+    }
+}
+
+/// Parses the arguments of `input`: `[p1|p2] FRAME[-FRAME] BUTTON...`
+fn parse_input(tokens: &[&str]) -> Result<InputRange, String> {
+    let (port, tokens) = match tokens.first() {
+        Some(&"p1") => (0, &tokens[1..]),
+        Some(&"p2") => (1, &tokens[1..]),
+        _ => (0, tokens),
+    };
+    let frames = match tokens.first() {
+        Some(frames) => *frames,
+        None => return Err("usage: input [p1|p2] FRAME[-FRAME] BUTTON...".to_string()),
+    };
+
+    let invalid = || format!("invalid frame range: {}", frames);
+    let mut split = frames.splitn(2, '-');
+    let start = try!(split.next().unwrap_or("").parse::<usize>().map_err(|_| invalid()));
+    let end = match split.next() {
+        Some(end) => try!(end.parse::<usize>().map_err(|_| invalid())),
+        None => start,
+    };
+    if end < start { return Err(invalid()) }
+
+    let mut buttons = Vec::new();
+    for button in &tokens[1..] {
+        buttons.push(try!(button.parse()));
+    }
+    if buttons.is_empty() { return Err("no buttons given".to_string()) }
+
+    Ok(InputRange {
+        port: port,
+        start: start,
+        end: end,
+        buttons: buttons,
+    })
+}
+
+/// Parses the arguments of `expect`: `mem ADDR = BYTES...`, `sram OFFSET = BYTES...` or
+/// `hash HASH`.
+fn parse_expect(tokens: &[&str]) -> Result<Step, String> {
+    fn hex(s: &str, max: u64) -> Result<u64, String> {
+        match u64::from_str_radix(s.trim_left_matches('$'), 16) {
+            Ok(n) if n <= max => Ok(n),
+            _ => Err(format!("invalid hexadecimal value: {}", s)),
+        }
+    }
+    fn bytes(tokens: &[&str]) -> Result<Vec<u8>, String> {
+        if tokens.len() < 2 || tokens[0] != "=" {
+            return Err("expected `= BYTE...`".to_string());
+        }
+        tokens[1..].iter().map(|byte| hex(byte, 0xff).map(|byte| byte as u8)).collect()
+    }
+
+    match tokens.first() {
+        Some(&"mem") if tokens.len() > 1 => Ok(Step::ExpectMemory {
+            addr: try!(hex(tokens[1], 0xffffff)) as u32,
+            bytes: try!(bytes(&tokens[2..])),
+        }),
+        Some(&"sram") if tokens.len() > 1 => Ok(Step::ExpectSram {
+            offset: try!(hex(tokens[1], 0xffffff)) as usize,
+            bytes: try!(bytes(&tokens[2..])),
+        }),
+        Some(&"hash") if tokens.len() == 2 => Ok(Step::ExpectHash(try!(hex(tokens[1], !0)))),
+        _ => Err("usage: expect mem ADDR = BYTE... | sram OFFSET = BYTE... | hash HASH"
+            .to_string()),
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+fn format_optional_bytes(bytes: &[Option<u8>]) -> String {
+    bytes.iter().map(|byte| match *byte {
+        Some(byte) => format!("{:02X}", byte),
+        None => "--".to_string(),
+    }).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,68 @@
+//! Screenshots of the emulated picture
+//!
+//! `Snes::screenshot` copies the last completed frame, which can then be saved as a PPM file or,
+//! with the `png` feature, as a PNG file.
+
+use snes::Snes;
+
+#[cfg(feature = "png")]
+use breeze_backend::png::write_png;
+
+use std::io::{self, Write};
+
+/// File extension of the screenshots written by `Screenshot::write`.
+#[cfg(feature = "png")]
+pub const EXTENSION: &'static str = "png";
+#[cfg(not(feature = "png"))]
+pub const EXTENSION: &'static str = "ppm";
+
+/// A copy of a frame output by the PPU.
+#[derive(Clone, Debug)]
+pub struct Screenshot {
+    /// Width in pixels (512 for hi-res frames, more in widescreen mode)
+    pub width: u32,
+    /// Height in pixels (more than 224 for overscan and interlaced frames)
+    pub height: u32,
+    /// `RGB24` image data, one row after the other
+    pub data: Vec<u8>,
+}
+
+impl Screenshot {
+    /// Writes the image as a binary PPM file.
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write!(out, "P6\n{} {}\n255\n", self.width, self.height));
+        out.write_all(&self.data)
+    }
+
+    /// Writes the image as a PNG file.
+    #[cfg(feature = "png")]
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_png(out, self.width, self.height, &self.data)
+    }
+
+    /// Writes the image in the format given by `EXTENSION`.
+    #[cfg(feature = "png")]
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.write_png(out)
+    }
+
+    /// Writes the image in the format given by `EXTENSION`.
+    #[cfg(not(feature = "png"))]
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.write_ppm(out)
+    }
+}
+
+impl Snes {
+    /// Returns a copy of the last completed frame, exactly as it was passed to the renderer (see
+    /// `Ppu::output_frame`).
+    pub fn screenshot(&self) -> Screenshot {
+        let ppu = &self.peripherals().ppu;
+        let (width, height) = ppu.output_size();
+        Screenshot {
+            width: width,
+            height: height,
+            data: ppu.output_frame().to_vec(),
+        }
+    }
+}
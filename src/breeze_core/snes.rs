@@ -1,24 +1,62 @@
 //! This module glues everything together and coordinates emulation.
 
+use accuracy::Accuracy;
+use chrome_trace::{ChromeTrace, Track};
+use clock::ClockRates;
+use crash_report;
 use dma::*;
-use input::Input;
-use log_util::LogOnPanic;
-use ppu::{FrameBuf, Ppu};
-use rom::Rom;
+use events::EventLog;
+use greenzone::Greenzone;
+use hash::{hash_bytes, hash_samples};
+use input::{Input, InputMacro};
+use log_util::{target, CrashLog, LogOnPanic};
+use profiler::Profiler;
+use ppu::{Ppu, RegisterHistory, MAX_WIDESCREEN_MARGIN, SCREEN_WIDTH, SCREEN_HEIGHT};
+use ppu::viewer::{DebugImage, DebugView};
+use ram_init::RamInit;
+use rewind::Rewind;
+use rom::{Region, Rom};
+use screenshot;
 use save::SaveStateFormat;
+use stack_check::{StackCheck, StackPolicy};
+use stats::Stats;
+use storage::{self, GameStorage};
+use trace::Tracer;
+use trace_diff::TraceDiff;
+use triggers::TriggerSet;
 
 use spc700::Spc700;
 use wdc65816::{Cpu, Mem};
-use breeze_backend::{BackendAction, BackendResult, Renderer, AudioSink};
+use wdc65816::disasm::{self, Instruction};
+use breeze_backend::{AudioBufferConfig, BackendAction, BackendResult, DebugSurfaceId, Renderer};
+use breeze_backend::AudioSink;
+use breeze_backend::hotkey::MACRO_SLOTS;
+use breeze_backend::osd::Osd;
+use breeze_backend::pacing::FramePacer;
+use breeze_backend::rate_control::RateController;
 
 use std::cmp;
 use std::env;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 
 const CPU_CYCLE: i32 = 6;
 
+/// H-Counter value (dot) at which the CPU is paused for WRAM refresh. Refresh starts at master
+/// cycle 538 of each scanline, which is roughly dot 134 (we don't model long dots yet).
+const DRAM_REFRESH_DOT: u16 = 538 / 4;
+/// Master cycles the CPU is stalled for every scanline while WRAM is refreshed.
+const DRAM_REFRESH_CYCLES: u32 = 40;
+
+/// The `Emulator` captures a rewind state every this many frames...
+const REWIND_INTERVAL: u64 = 4;
+/// ...and keeps this many of them (20 seconds at 60 Hz)
+const REWIND_CAPACITY: usize = 300;
+
 pub const WRAM_SIZE: usize = 128 * 1024;
 byte_array!(pub Wram[WRAM_SIZE] with save state please);
 
@@ -45,20 +83,22 @@ pub struct Peripherals {
     /// immediately and the register can't be read)
     hdmaen: u8,
     /// `$4200` - NMITIMEN: Interrupt enable flags
-    /// `n-xy---a`
+    /// `n-vh---a`
     /// * `n`: Enable NMI on V-Blank
-    /// * `x`: Enable IRQ on H-Counter match
-    /// * `y`: Enable IRQ on V-Counter match
+    /// * `v`: Enable IRQ on V-Counter match (`vtime`)
+    /// * `h`: Enable IRQ on H-Counter match (`htime`). With `v` set, the IRQ fires at that dot in
+    ///   line `vtime` only, without `v`, it fires in every line. With just `v` set, it fires at
+    ///   the start of line `vtime`.
     /// * `a`: Enable auto-joypad read
     nmien: u8,
     /// `$4201` - WRIO: Programmable I/O Port (out-port)
     /// `abxxxxxx`
-    /// * `a`: Connected to Port 0 `IOBit`
-    /// * `b`: Connected to Port 1 `IOBit`
+    /// * `a`: Connected to the `IOBit` of port 1 (the second port) and the PPU's counter latch
+    /// * `b`: Connected to the `IOBit` of port 0
     /// * `x`: Not connected
     ///
     /// Any bit set to 0 will be 0 when read from `$4213`. If `a` is 0, reading `$2137` will not
-    /// latch the H/V Counters.
+    /// latch the H/V Counters. Changing `a` from 1 to 0 latches the counters.
     wrio: u8,
     /// `$4202` - WRMPYA: Multiplicand 1
     wrmpya: u8,
@@ -72,6 +112,18 @@ pub struct Peripherals {
     rddiv: u16,
     /// `$4216`/`$4217` - RDMPYH/RDMPYL: Unsigned Division Remainder / Multiply Product
     rdmpy: u16,
+    /// Shift register of the multiplier/divider. The ALU works on a single bit per CPU cycle, so
+    /// `rddiv` and `rdmpy` contain intermediate values while it's running.
+    alu_shift: u32,
+    /// Remaining CPU cycles of the multiplication in progress (0-8)
+    alu_mpy_steps: u8,
+    /// Remaining CPU cycles of the division in progress (0-16)
+    alu_div_steps: u8,
+    /// Number of CPU cycles of the current instruction the ALU was already run for
+    alu_synced_cy: u16,
+    /// Memory accesses done by the current instruction, used as an approximation of the number of
+    /// CPU cycles elapsed in it
+    instr_accesses: u16,
     /// `$4207`/`$4208` - HTIMEL/HTIMEH: H Timer (9-bit value)
     htime: u16,
     /// `$4209`/`$420a` - VTIMEL/VTIMEH: V Timer (9-bit value)
@@ -82,23 +134,45 @@ pub struct Peripherals {
     memsel: bool,
     /// `$4210` NMI flag and 5A22 Version (the version is constant)
     /// `n---vvvv`
-    /// * `n`: `self.nmi`
+    /// * `n`: `self.nmi` (set at the start of V-Blank, cleared on read and at the end of V-Blank)
     /// * `v`: Version
     nmi: bool,
+    /// The CPU's NMI line (NMI flag AND NMI enable) went high, the CPU takes the NMI before the
+    /// next instruction. The line is edge-triggered, so this stays set even if the NMI is disabled
+    /// in the meantime.
+    nmi_pending: bool,
     /// `$4211` TIMEUP - IRQ flag
     /// `i-------`
-    /// * `i`: IRQ flag (cleared on read)
+    /// * `i`: IRQ flag (cleared on read, or by disabling the H/V IRQ). Drives the CPU's IRQ line.
     irq: bool,
 
     /// Additional cycles spent doing IO (in master clock cycles). This is added to the cycle count
     /// returned by the CPU and then reset to 0.
     cy: u32,
+
+    /// Accuracy settings in effect. Not part of the emulated state.
+    accuracy: Accuracy,
+    /// Timing statistics. Not part of the emulated state.
+    stats: Stats,
+    /// Register write log. Not part of the emulated state.
+    events: Option<EventLog>,
+    /// PPU registers at the start of the last frames. Not part of the emulated state.
+    ppu_history: Option<RegisterHistory>,
+    /// Recent instructions and memory accesses, dumped on panic. Not part of the emulated state.
+    crash_log: Option<CrashLog>,
+    /// Scheduler event timeline. Not part of the emulated state.
+    chrome_trace: Option<ChromeTrace>,
 }
 
 impl_save_state!(Peripherals {
-    apu, ppu, rom, wram, dma, hdmaen, nmien, wrio, wrmpya, wrmpyb, wrdiv, rddiv, rdmpy, htime,
-    vtime, memsel, nmi, irq, cy, input, wmaddl, wmaddm, wmaddh
-} ignore {});
+    apu, ppu, rom, wram, dma, hdmaen, nmien, wrio, wrmpya, wrmpyb, wrdiv, rddiv, rdmpy,
+    alu_shift, alu_mpy_steps, alu_div_steps, htime, vtime, memsel, nmi, nmi_pending, irq, cy,
+    input, wmaddl, wmaddm, wmaddh
+} ignore {
+    // Only used during an instruction
+    alu_synced_cy, instr_accesses,
+    accuracy, stats, events, ppu_history, crash_log, chrome_trace
+});
 
 impl Peripherals {
     pub fn new(rom: Rom, input: Input) -> Peripherals {
@@ -124,15 +198,36 @@ impl Peripherals {
             wrmpyb: 0,
             rddiv: 0,
             rdmpy: 0,
+            alu_shift: 0,
+            alu_mpy_steps: 0,
+            alu_div_steps: 0,
+            alu_synced_cy: 0,
+            instr_accesses: 0,
             nmi: false,
+            nmi_pending: false,
             irq: false,
             cy: 0,
+            accuracy: Accuracy::default(),
+            stats: Stats::default(),
+            events: None,
+            ppu_history: None,
+            crash_log: Some(CrashLog::default()),
+            chrome_trace: None,
         }
     }
 
     fn nmi_enabled(&self) -> bool { self.nmien & 0x80 != 0 }
-    fn v_irq_enabled(&self) -> bool { self.nmien & 0x10 != 0 }
-    fn h_irq_enabled(&self) -> bool { self.nmien & 0x20 != 0 }
+
+    /// Returns whether the H/V timer IRQ fires at the PPU's current position.
+    fn timer_irq_due(&self) -> bool {
+        let (v, h) = (self.ppu.v_counter(), self.ppu.h_counter());
+        match (self.nmien >> 4) & 0x03 {
+            0 => false,
+            1 => h == self.htime,
+            2 => v == self.vtime && h == 0,
+            _ => v == self.vtime && h == self.htime,
+        }
+    }
 
     /// Adds the time needed to access the given memory location to the cycle counter.
     fn do_io_cycle(&mut self, bank: u8, addr: u16) {
@@ -140,6 +235,8 @@ impl Peripherals {
         const SLOW: u32 = 2;
         const XSLOW: u32 = 6;
 
+        self.instr_accesses = self.instr_accesses.saturating_add(1);
+
         self.cy += match bank {
             0x00 ... 0x3f => match addr {
                 0x0000 ... 0x1fff | 0x6000 ... 0xffff => SLOW,
@@ -158,6 +255,104 @@ impl Peripherals {
         }
     }
 
+    /// Returns the master cycles spent on I/O since the CPU last reported its cycles.
+    pub fn cy(&self) -> u32 { self.cy }
+
+    /// Overwrites the I/O cycle count. DMA uses this to drop the cycles added by its memory
+    /// accesses, which take a fixed time.
+    pub fn set_cy(&mut self, cy: u32) {
+        self.cy = cy;
+    }
+
+    /// Reads a byte from the CPU's address space without any side effects (no I/O cycles are
+    /// counted and no registers are touched). Returns `None` for I/O registers and unmapped
+    /// addresses.
+    pub fn peek(&mut self, bank: u8, addr: u16) -> Option<u8> {
+        match bank {
+            0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
+                0x0000 ... 0x1fff => Some(self.wram[addr as usize]),
+                0x6000 ... 0xffff => self.rom.peek(bank, addr),
+                _ => None,
+            },
+            0x7e | 0x7f => Some(self.wram[(bank as usize - 0x7e) * 65536 + addr as usize]),
+            _ => self.rom.peek(bank, addr),
+        }
+    }
+
+    /// Writes a byte to WRAM, ROM or cartridge RAM without any side effects. Returns `false` if
+    /// the address doesn't refer to any of them.
+    pub fn poke(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        match bank {
+            0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
+                0x0000 ... 0x1fff => {
+                    self.wram[addr as usize] = value;
+                    true
+                }
+                0x6000 ... 0xffff => self.rom.poke(bank, addr, value),
+                _ => false,
+            },
+            0x7e | 0x7f => {
+                self.wram[(bank as usize - 0x7e) * 65536 + addr as usize] = value;
+                true
+            }
+            _ => self.rom.poke(bank, addr, value),
+        }
+    }
+
+    /// Decodes the instruction at `bank:addr` without any side effects (see `peek`). Bytes that
+    /// can't be peeked are decoded as `$00`.
+    pub fn disassemble(&mut self, bank: u8, addr: u16, small_acc: bool, small_index: bool)
+                       -> Instruction {
+        disasm::disassemble(&mut Peek(self), bank, addr, small_acc, small_index)
+    }
+
+    /// Runs the multiplier/divider for `steps` CPU cycles. Each cycle processes a single bit, like
+    /// the real hardware does.
+    fn step_alu(&mut self, steps: u16) {
+        for _ in 0..steps {
+            if self.alu_mpy_steps > 0 {
+                // Shift-and-add. `rddiv` starts out as `WRMPYB << 8 | WRMPYA`, so it contains
+                // `WRMPYB` when we're done.
+                self.alu_mpy_steps -= 1;
+                if self.rddiv & 1 != 0 {
+                    self.rdmpy = self.rdmpy.wrapping_add(self.alu_shift as u16);
+                }
+                self.rddiv >>= 1;
+                self.alu_shift <<= 1;
+            } else if self.alu_div_steps > 0 {
+                // Shift-and-subtract. Division by 0 results in a quotient of `$FFFF` and leaves
+                // the dividend as the remainder.
+                self.alu_div_steps -= 1;
+                self.rddiv <<= 1;
+                self.alu_shift >>= 1;
+                if self.rdmpy as u32 >= self.alu_shift {
+                    self.rdmpy -= self.alu_shift as u16;
+                    self.rddiv |= 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Runs the ALU until the given CPU cycle of the current instruction.
+    fn catch_up_alu(&mut self, instr_cy: u16) {
+        if instr_cy > self.alu_synced_cy {
+            let steps = instr_cy - self.alu_synced_cy;
+            self.step_alu(steps);
+            self.alu_synced_cy = instr_cy;
+        }
+    }
+
+    /// Starts a multiplication or division. Results are available immediately if ALU latency
+    /// isn't emulated.
+    fn start_alu(&mut self) {
+        self.alu_synced_cy = self.instr_accesses;
+        if !self.accuracy.alu_latency {
+            self.step_alu(16);
+        }
+    }
+
     fn get_and_inc_wram_addr(&mut self) -> usize {
         let addr = (self.wmaddh as usize) << 16 |
                    (self.wmaddm as usize) << 8 |
@@ -172,31 +367,32 @@ impl Peripherals {
 }
 
 impl Mem for Peripherals {
+    /// Loads without a known open-bus value (like DMA reads do) return 0 where the CPU's bus
+    /// value would be returned.
     fn load(&mut self, bank: u8, addr: u16) -> u8 {
+        self.load_with_bus(bank, addr, 0)
+    }
+
+    fn load_with_bus(&mut self, bank: u8, addr: u16, bus: u8) -> u8 {
         self.do_io_cycle(bank, addr);
-        match bank {
+        let value = match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
                 // Mirror of first 8k of WRAM
                 0x0000 ... 0x1fff => self.wram[addr as usize],
                 // PPU
-                0x2100 ... 0x2133 => {
-                    once!(warn!("read from write-only PPU register ${:04X}", addr));
-                    0
-                }
-                0x2134 ... 0x213f => self.ppu.load(addr),
+                0x2100 ... 0x213f => self.ppu.load(addr, bus),
                 // APU IO registers
                 0x2140 ... 0x217f => self.apu.read_port((addr & 0b11) as u8),
                 0x2180 => {
                     let addr = self.get_and_inc_wram_addr();
                     self.wram[addr]
                 }
-                0x2181 ... 0x2183 => {
-                    once!(warn!("open-bus load from WRAM register ${:02X}", addr));
-                    0   // FIXME Emulate open-bus
-                }
+                // WMADDL/WMADDM/WMADDH are write-only
+                0x2181 ... 0x2183 => bus,
                 0x4016 | 0x4017 => self.input.load(addr),
-                0x4202 => self.wrmpya,
-                0x4203 => self.wrmpyb,
+                // Write-only CPU registers (NMITIMEN, WRIO, multiplier and divider inputs, timers,
+                // DMA enables and MEMSEL) read as open bus
+                0x4200 ... 0x420d => bus,
                 0x4210 => {
                     const CPU_VERSION: u8 = 2;  // FIXME Is 2 okay in all cases? Does anyone care?
                     let nmi = if self.nmi { 0x80 } else { 0 };
@@ -216,21 +412,33 @@ impl Mem for Peripherals {
                     (if self.ppu.in_v_blank() { 0x80 } else { 0 }) +
                     (if self.ppu.in_h_blank() { 0x40 } else { 0 })
                 }
-                // RDDIVL - Unsigned Division Result (Quotient) (lower 8bit)
-                0x4214 => self.rddiv as u8,
-                // RDDIVH - Unsigned Division Result (Quotient) (upper 8bit)
-                0x4215 => (self.rddiv >> 8) as u8,
-                // RDMPYL
-                0x4216 => self.rdmpy as u8,
-                // RDMPYH
-                0x4217 => (self.rdmpy >> 8) as u8,
+                // RDIO - Programmable I/O Port (in-port)
+                // Lines set to 0 in WRIO are pulled low, the others report what's attached.
+                0x4213 => self.wrio & self.input.read_io_port(),
+                0x4214 ... 0x4217 => {
+                    // The read happens in the last of the accesses done so far
+                    let cy = self.instr_accesses - 1;
+                    self.catch_up_alu(cy);
+                    match addr {
+                        // RDDIVL - Unsigned Division Result (Quotient) (lower 8bit)
+                        0x4214 => self.rddiv as u8,
+                        // RDDIVH - Unsigned Division Result (Quotient) (upper 8bit)
+                        0x4215 => (self.rddiv >> 8) as u8,
+                        // RDMPYL
+                        0x4216 => self.rdmpy as u8,
+                        // RDMPYH
+                        0x4217 => (self.rdmpy >> 8) as u8,
+                        _ => unreachable!(),
+                    }
+                }
                 // Input ports
                 0x4218 ... 0x421f => self.input.load(addr),
                 // DMA channels (0x43xr, where x is the channel and r is the channel register)
                 0x4300 ... 0x43ff => self.dma[(addr as usize & 0x00f0) >> 4].load(addr as u8 & 0xf),
                 0x6000 ... 0xffff => self.rom.load(bank, addr),
                 _ => {
-                    once!(warn!("invalid/unimplemented load from ${:02X}:{:04X}", bank, addr));
+                    once!(warn!(target: target::CPU,
+                                "invalid/unimplemented load from ${:02X}:{:04X}", bank, addr));
                     0
                 }
             },
@@ -238,17 +446,33 @@ impl Mem for Peripherals {
             0x7e | 0x7f => self.wram[(bank as usize - 0x7e) * 65536 + addr as usize],
             0x40 ... 0x7d | 0xc0 ... 0xff => self.rom.load(bank, addr),
             _ => unreachable!(),    // Rust should know this!
+        };
+        if let Some(ref mut crash_log) = self.crash_log {
+            crash_log.record_access(bank, addr, value, false);
         }
+        value
     }
 
     fn store(&mut self, bank: u8, addr: u16, value: u8) {
         self.do_io_cycle(bank, addr);
+        if let Some(ref mut events) = self.events {
+            if bank & 0x40 == 0 {
+                events.record(self.ppu.v_counter(), self.ppu.h_counter(), addr, value);
+            }
+        }
+        if let Some(ref mut crash_log) = self.crash_log {
+            crash_log.record_access(bank, addr, value, true);
+            if bank & 0x40 == 0 {
+                crash_log.record_io_write(self.ppu.v_counter(), self.ppu.h_counter(), addr, value);
+            }
+        }
         match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
                 0x0000 ... 0x1fff => self.wram[addr as usize] = value,
                 // PPU registers. Let it deal with the access.
                 0x2100 ... 0x2133 => self.ppu.store(addr, value),
-                0x2134 ... 0x213f => once!(warn!("store to read-only PPU register ${:04X}", addr)),
+                0x2134 ... 0x213f => once!(warn!(target: target::PPU,
+                    "store to read-only PPU register ${:04X}", addr)),
                 // APU IO registers.
                 0x2140 ... 0x217f => self.apu.store_port((addr & 0b11) as u8, value),
                 0x2180 => {
@@ -258,51 +482,79 @@ impl Mem for Peripherals {
                 0x2181 => self.wmaddl = value,
                 0x2182 => self.wmaddm = value,
                 0x2183 => self.wmaddh = value & 1,
-                0x2184 ... 0x21ff => once!(warn!("invalid store: ${:02X} to ${:02X}:{:04X}", value,
-                    bank, addr)),
+                0x2184 ... 0x21ff => once!(warn!(target: target::CPU,
+                    "invalid store: ${:02X} to ${:02X}:{:04X}", value, bank, addr)),
                 0x4016 => self.input.store(addr, value),
                 0x4200 => {
                     // NMITIMEN - NMI/IRQ enable
-                    // E-HV---J
+                    // E-VH---J
                     // E: Enable NMI
-                    // H: Enable IRQ on H-Counter
                     // V: Enable IRQ on V-Counter
+                    // H: Enable IRQ on H-Counter
                     // J: Enable Auto-Joypad-Read
 
                     // Check useless bits
-                    if value & 0x4e != 0 { once!(warn!("Invalid value for NMIEN: ${:02X}", value)) }
+                    if value & 0x4e != 0 {
+                        once!(warn!(target: target::CPU, "Invalid value for NMIEN: ${:02X}", value))
+                    }
+                    if value & 0x30 == 0 {
+                        // Disabling the timer acknowledges a pending IRQ
+                        self.irq = false;
+                    }
+                    if value & 0x80 != 0 && !self.nmi_enabled() && self.nmi {
+                        // Enabling the NMI during V-Blank (before the flag was read) raises the
+                        // NMI line, so the NMI fires (again)
+                        self.nmi_pending = true;
+                    }
                     self.nmien = value;
                 }
                 0x4201 => {
-                    // FIXME: Propagate to controller ports and the I/O read port
+                    if self.wrio & 0x80 != 0 && value & 0x80 == 0 {
+                        // 1 -> 0 transition of the latch line
+                        self.ppu.force_latch_counters();
+                    }
                     self.wrio = value;
                     self.ppu.can_latch_counters = value & 0x80 != 0;
+                    self.input.set_io_port(value);
                 }
                 0x4202 => self.wrmpya = value,
-                // WRMPYB: Performs multiplication on write
+                // WRMPYB: Starts multiplication on write (ignored while the ALU is busy)
                 0x4203 => {
-                    self.wrmpyb = value;
-                    self.rdmpy = self.wrmpya as u16 * value as u16;
+                    self.rdmpy = 0;
+                    if self.alu_mpy_steps == 0 && self.alu_div_steps == 0 {
+                        self.wrmpyb = value;
+                        self.rddiv = (value as u16) << 8 | self.wrmpya as u16;
+                        self.alu_shift = value as u32;
+                        self.alu_mpy_steps = 8;
+                        self.start_alu();
+                    }
                 }
                 0x4204 => self.wrdiv = (self.wrdiv & 0xff00) | value as u16,
                 0x4205 => self.wrdiv = ((value as u16) << 8) | (self.wrdiv & 0xff),
-                // WRDIVB: Performs division on write
+                // WRDIVB: Starts division on write (ignored while the ALU is busy)
                 0x4206 => {
-                    self.rddiv = if value == 0 { 0xffff } else { self.wrdiv / value as u16 };
-                    self.rdmpy = if value == 0 { value as u16 } else { self.wrdiv % value as u16 };
+                    self.rdmpy = self.wrdiv;
+                    if self.alu_mpy_steps == 0 && self.alu_div_steps == 0 {
+                        self.alu_shift = (value as u32) << 16;
+                        self.alu_div_steps = 16;
+                        self.start_alu();
+                    }
                 }
                 0x4207 => self.htime = (self.htime & 0xff00) | value as u16,
-                0x4208 => {
-                    assert!(value & 0x01 == value, "invalid value for $4207: ${:02X}", value);
-                    self.htime = ((value as u16) << 8) | (self.htime & 0xff);
-                }
+                0x4208 => self.htime = ((value as u16 & 0x01) << 8) | (self.htime & 0xff),
                 0x4209 => self.vtime = (self.vtime & 0xff00) | value as u16,
-                0x420a => {
-                    assert!(value & 0x01 == value, "invalid value for $4209: ${:02X}", value);
-                    self.vtime = ((value as u16) << 8) | (self.vtime & 0xff);
-                }
+                0x420a => self.vtime = ((value as u16 & 0x01) << 8) | (self.vtime & 0xff),
                 // MDMAEN - Party enable
-                0x420b => self.cy += do_dma(self, value),
+                0x420b => {
+                    let cy = do_dma(self, value);
+                    self.stats.total.dma_cy += cy as u64;
+                    if let Some(ref mut trace) = self.chrome_trace {
+                        let now = trace.now();
+                        trace.span(Track::Dma, format!("DMA (channels ${:02X})", value), now,
+                            cy as u64);
+                    }
+                    if self.accuracy.dma_timing { self.cy += cy; }
+                }
                 // HDMAEN - HDMA enable
                 0x420c => self.hdmaen = value,
                 // MEMSEL - FastROM select
@@ -312,7 +564,7 @@ impl Mem for Peripherals {
                 0x4300 ... 0x43ff => {
                     self.dma[(addr as usize & 0x00f0) >> 4].store(addr as u8 & 0xf, value);
                 }
-                0x8000 ... 0xffff => self.rom.store(bank, addr, value),
+                0x6000 ... 0xffff => self.rom.store(bank, addr, value),
                 _ => panic!("invalid store: ${:02X} to ${:02X}:{:04X}", value, bank, addr)
             },
             // WRAM main banks
@@ -321,159 +573,633 @@ impl Mem for Peripherals {
             _ => unreachable!(),    // Rust should know this!
         }
     }
+
+    fn cycles(&mut self, cy: u16) {
+        // Run the ALU for the rest of the instruction
+        self.catch_up_alu(cy);
+        self.alu_synced_cy = 0;
+    }
+
+    fn irq(&mut self) -> bool { self.irq }
+
+    fn nmi(&mut self) -> bool { mem::replace(&mut self.nmi_pending, false) }
+}
+
+/// Reads memory through `Peripherals::peek`, so that disassembling doesn't have side effects.
+struct Peek<'a>(&'a mut Peripherals);
+
+impl<'a> Mem for Peek<'a> {
+    fn load(&mut self, bank: u8, addr: u16) -> u8 { self.0.peek(bank, addr).unwrap_or(0) }
+    fn store(&mut self, _bank: u8, _addr: u16, _value: u8) {}
 }
 
 /// SNES system state
 ///
 /// Contains all registers, RAMs, cartridge memory, timing information, latches, flip-flops, etc.
+///
+/// `Snes` is `Send`, so it can run on a worker thread (see `EmuThread`). Everything plugged into it
+/// (joypads, input recorders and replayers, trace readers) has to be `Send` as well. It isn't
+/// `Sync`, so other threads have to talk to the thread owning it instead of sharing it.
 pub struct Snes {
     cpu: Cpu<Peripherals>,
     master_cy: u64,
     /// Master clock cycles for the APU not yet accounted for (can be negative)
     apu_master_cy_debt: i32,
+    /// Fractional part of `apu_master_cy_debt` (in 1/65536 master cycles, to be subtracted)
+    apu_master_cy_frac: u32,
     /// Master clock cycles for the PPU not yet accounted for (can be negative)
     ppu_master_cy_debt: i32,
     /// Master cycle at which the emulator should enable CPU and APU tracing. This will print all
     /// opcodes as they are executed (as long as the `trace` log level is enabled).
     trace_start: u64,
+    /// Writes executed instructions to a file
+    tracer: Option<Tracer>,
+    /// Compares execution against a reference trace
+    trace_diff: Option<TraceDiff>,
+    /// Records the cycles spent per instruction address
+    profiler: Option<Profiler>,
+    /// Number of frames emulated since power-on. Not part of the emulated state.
+    frames: u64,
+    /// The pattern RAM was filled with on power-on, used again by `reset`. Saved, so that
+    /// resetting after loading a state fills RAM just like in the session the state came from.
+    ram_init: RamInit,
+    /// Clock rates of the emulated console. Not part of the emulated state.
+    clocks: ClockRates,
+    /// Reports stack overflows and underflows
+    stack_check: StackCheck,
+    /// Recent states for `rewind`. Not part of the emulated state.
+    rewind: Option<Rewind>,
+    /// `run_frame` doesn't emulate anything while paused
+    paused: bool,
 }
 
-impl_save_state!(Snes { cpu, master_cy, apu_master_cy_debt, ppu_master_cy_debt }
-    ignore { trace_start });
+impl_save_state!(Snes {
+    cpu, master_cy, apu_master_cy_debt, apu_master_cy_frac, ppu_master_cy_debt, ram_init
+} ignore {
+    trace_start, tracer, trace_diff, profiler, frames, clocks, stack_check, rewind, paused
+});
 
 impl Snes {
+    /// Creates a new SNES. The region is taken from the ROM header (see `Rom::region`).
     pub fn new(rom: Rom) -> Self {
+        Self::with_ram_init(rom, RamInit::default())
+    }
+
+    /// Creates a new SNES of the given region, regardless of the region in the ROM header.
+    pub fn with_region(rom: Rom, region: Region) -> Self {
+        let mut snes = Self::new(rom);
+        snes.set_region(region);
+        snes
+    }
+
+    /// Creates a new SNES and fills WRAM, VRAM and APU RAM with the given pattern.
+    pub fn with_ram_init(rom: Rom, ram_init: RamInit) -> Self {
+        let mut periph = Peripherals::new(rom, Input::default());
+        ram_init.fill(&mut *periph.wram);
+        ram_init.fill(&mut *periph.ppu.vram);
+        ram_init.fill(periph.apu.ram_mut());
+        let region = periph.rom.region();
+        periph.ppu.set_pal(region == Region::Pal);
+        periph.ppu.set_bg_raster(periph.accuracy.bg_raster);
+        let mut clocks = ClockRates::default();
+        clocks.set_region(region);
+
         Snes {
-            cpu: Cpu::new(Peripherals::new(rom, Input::default())),
+            cpu: Cpu::new(periph),
             master_cy: 0,
             apu_master_cy_debt: 0,
+            apu_master_cy_frac: 0,
             ppu_master_cy_debt: 0,
             trace_start: !0,
+            tracer: None,
+            trace_diff: None,
+            profiler: None,
+            frames: 0,
+            ram_init: ram_init,
+            clocks: clocks,
+            stack_check: StackCheck::default(),
+            rewind: None,
+            paused: false,
         }
     }
 
+    /// Power-cycles the system. The ROM, the connected input devices and the settings that aren't
+    /// part of the emulated state (accuracy, region, clock rates, statistics, tracing, color
+    /// correction) are kept.
+    pub fn reset(&mut self) {
+        let rom = self.cpu.mem.rom.clone();
+        let input = mem::replace(&mut self.cpu.mem.input, Input::default());
+        let accuracy = self.cpu.mem.accuracy;
+        let stats = mem::replace(&mut self.cpu.mem.stats, Stats::default());
+        let events = self.cpu.mem.events.take();
+        let ppu_history = self.cpu.mem.ppu_history.take();
+        let crash_log = self.cpu.mem.crash_log.take();
+        let port_log = self.cpu.mem.apu.port_log().cloned();
+        let chrome_trace = self.cpu.mem.chrome_trace.take();
+        let trace_start = self.trace_start;
+        let tracer = self.tracer.take();
+        let trace_diff = self.trace_diff.take();
+        let profiler = self.profiler.take();
+        let call_stack_tracking = self.cpu.call_stack().is_some();
+        let op_stats = self.cpu.opcode_stats().cloned();
+        let color_correction = self.cpu.mem.ppu.color_correction();
+        let widescreen_margin = self.cpu.mem.ppu.widescreen_margin();
+        let region = self.region();
+        let clocks = self.clocks;
+        let stack_policy = self.stack_check.policy();
+        let rewind = self.rewind.take();
+        let paused = self.paused;
+
+        *self = Snes::with_ram_init(rom, self.ram_init);
+        self.clocks = clocks;
+        self.stack_check = StackCheck::new(stack_policy);
+        self.cpu.mem.ppu.set_color_correction(color_correction);
+        self.cpu.mem.ppu.set_widescreen(widescreen_margin);
+        self.set_region(region);
+        self.cpu.mem.input = input;
+        self.cpu.mem.accuracy = accuracy;
+        self.cpu.mem.ppu.set_bg_raster(accuracy.bg_raster);
+        self.cpu.mem.stats = stats;
+        self.cpu.mem.events = events;
+        self.cpu.mem.ppu_history = ppu_history;
+        self.cpu.mem.crash_log = crash_log;
+        self.cpu.mem.apu.set_port_log(port_log);
+        self.cpu.mem.chrome_trace = chrome_trace;
+        self.trace_start = trace_start;
+        self.tracer = tracer;
+        self.trace_diff = trace_diff;
+        self.profiler = profiler;
+        self.rewind = rewind;
+        self.paused = paused;
+        if let Some(ref mut rewind) = self.rewind { rewind.clear(); }
+        self.cpu.set_call_stack_tracking(call_stack_tracking);
+        self.cpu.set_opcode_stats(op_stats);
+    }
+
+    /// Replaces the ROM image (eg. with a freshly rebuilt version of a homebrew game) and resets
+    /// the system. Everything `reset` keeps is kept as well. If `keep_sram` is set, the cartridge
+    /// RAM is copied over to the new ROM (as far as it fits), otherwise it starts out empty.
+    pub fn replace_rom(&mut self, mut rom: Rom, keep_sram: bool) {
+        if keep_sram {
+            let old = self.cpu.mem.rom.ram();
+            let new = rom.ram_mut();
+            let len = old.len().min(new.len());
+            new[..len].copy_from_slice(&old[..len]);
+        }
+        self.cpu.mem.rom = rom;
+        self.reset();
+    }
+
+    /// Returns the pattern RAM was initialized with on power-on.
+    pub fn ram_init(&self) -> RamInit { self.ram_init }
+
+    /// Returns the accuracy settings currently in use.
+    pub fn accuracy(&self) -> &Accuracy { &self.cpu.mem.accuracy }
+
+    /// Returns a stable hash of the last rendered frame.
+    ///
+    /// Call this after `render_frame` returns to get one hash per frame. The hash only depends on
+    /// the frame's contents and will be the same on every platform.
+    pub fn frame_hash(&self) -> u64 {
+        hash_bytes(&*self.cpu.mem.ppu.framebuf)
+    }
+
+    /// Returns a stable hash of the audio produced during the last rendered frame.
+    ///
+    /// Like `frame_hash`, call this after `render_frame` returns. The hash covers all samples the
+    /// APU generated during that frame (see `Spc700::audio`).
+    pub fn audio_hash(&self) -> u64 {
+        hash_samples(self.cpu.mem.apu.audio())
+    }
+
+    /// Returns timing statistics collected since the last call to `reset_stats`.
+    pub fn stats(&self) -> &Stats { &self.cpu.mem.stats }
+
+    /// Resets all timing statistics.
+    pub fn reset_stats(&mut self) {
+        self.cpu.mem.stats = Stats::default();
+    }
+
+    /// Returns the region of the emulated console.
+    pub fn region(&self) -> Region {
+        if self.cpu.mem.ppu.is_pal() { Region::Pal } else { Region::Ntsc }
+    }
+
+    /// Changes the region of the emulated console. It defaults to the region of the ROM.
+    ///
+    /// This changes the region games see when reading `$213f`, the number of scanlines per frame
+    /// and the master clock rate (unless custom clock rates are used, see
+    /// `ClockRates::set_region`). Takes effect with the next frame.
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.mem.ppu.set_pal(region == Region::Pal);
+        self.clocks.set_region(region);
+    }
+
+    /// Returns the clock rates of the emulated console.
+    pub fn clock_rates(&self) -> ClockRates { self.clocks }
+
+    /// Returns the number of frames the emulated console outputs per second. Frontends pace
+    /// emulation with this (see `FramePacer`).
+    pub fn frame_rate(&self) -> f64 { self.clocks.frame_rate() }
+
+    /// Changes the clock rates. They're adjusted to the current region (see
+    /// `ClockRates::set_region`). Takes effect immediately.
+    pub fn set_clock_rates(&mut self, mut clocks: ClockRates) {
+        clocks.set_region(self.region());
+        debug!("clock rates: {:?}", clocks);
+        self.clocks = clocks;
+    }
+
+    /// Changes the accuracy settings. Takes effect immediately.
+    pub fn set_accuracy(&mut self, accuracy: Accuracy) {
+        debug!("accuracy settings: {:?}", accuracy);
+        self.cpu.mem.accuracy = accuracy;
+        self.cpu.mem.ppu.set_bg_raster(accuracy.bg_raster);
+    }
+
     /// Get a reference to the `Peripherals` instance
     pub fn peripherals(&self) -> &Peripherals { &self.cpu.mem }
 
     /// Get a mutable reference to the `Peripherals` instance
     pub fn peripherals_mut(&mut self) -> &mut Peripherals { &mut self.cpu.mem }
 
+    /// Get a reference to the CPU (and its registers)
+    pub fn cpu(&self) -> &Cpu<Peripherals> { &self.cpu }
+
+    /// Get a mutable reference to the CPU
+    pub fn cpu_mut(&mut self) -> &mut Cpu<Peripherals> { &mut self.cpu }
+
+    /// Returns the number of master clock cycles emulated since power-on.
+    pub fn master_cy(&self) -> u64 { self.master_cy }
+
+    /// Returns the number of frames emulated since power-on (or since the emulator was started, if
+    /// a save state was loaded).
+    pub fn frame_count(&self) -> u64 { self.frames }
+
+    /// Sets the frame counter. Since it isn't part of save states, this is needed to keep it
+    /// meaningful when jumping around in time (eg. when seeking in a movie).
+    pub fn set_frame_count(&mut self, frames: u64) {
+        self.frames = frames;
+    }
+
+    /// Starts capturing states for `rewind` into the given buffer (or stops if `None` is passed).
+    pub fn set_rewind(&mut self, rewind: Option<Rewind>) {
+        self.rewind = rewind;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&Rewind> { self.rewind.as_ref() }
+
+    /// Goes back (at least) `frames` frames in time by restoring a state from the rewind buffer.
+    ///
+    /// Returns the number of frames actually rewound, or `None` if rewinding is disabled or the
+    /// buffer doesn't reach back far enough (nothing is changed in that case).
+    pub fn rewind(&mut self, frames: u64) -> io::Result<Option<u64>> {
+        let current = self.frames;
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return Ok(None),
+        };
+        let result = rewind.rewind(self, frames);
+        self.rewind = Some(rewind);
+        Ok(try!(result).map(|frame| current - frame))
+    }
+
+    /// Starts writing executed instructions to the given tracer (or stops if `None` is passed).
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Get a mutable reference to the tracer, to change its settings while running.
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> { self.tracer.as_mut() }
+
+    /// Sets what happens when the CPU's stack pointer wraps around (see `stack_check`).
+    pub fn set_stack_policy(&mut self, policy: StackPolicy) {
+        self.stack_check = StackCheck::new(policy);
+    }
+
+    pub fn stack_policy(&self) -> StackPolicy { self.stack_check.policy() }
+
+    /// Returns (and forgets) the address of the instruction that wrapped the stack pointer around,
+    /// if that happened since the last call and the stack policy is `StackPolicy::Break`.
+    pub fn take_stack_break(&mut self) -> Option<u32> { self.stack_check.take_break() }
+
+    /// Compares execution against a reference trace. At the first divergence, `step` (and thus
+    /// `render_frame`) returns a `Divergence` error describing it.
+    pub fn set_trace_diff(&mut self, trace_diff: Option<TraceDiff>) {
+        self.trace_diff = trace_diff;
+    }
+
+    /// Starts (or, when passing `None`, stops) recording the cycles spent per instruction.
+    ///
+    /// Starting the profiler also enables call stack tracking, so that cycles can be attributed to
+    /// subroutines.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        if profiler.is_some() && self.cpu.call_stack().is_none() {
+            self.cpu.set_call_stack_tracking(true);
+        }
+        self.profiler = profiler;
+    }
+
+    /// Returns the profiler, if profiling is enabled.
+    pub fn profiler(&self) -> Option<&Profiler> { self.profiler.as_ref() }
+
+    /// Get a mutable reference to the profiler (eg. to reset it).
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> { self.profiler.as_mut() }
+
+    /// Starts (or, when passing `None`, stops) logging register writes.
+    pub fn set_event_log(&mut self, events: Option<EventLog>) {
+        self.cpu.mem.events = events;
+    }
+
+    /// Returns the register write log, if enabled.
+    pub fn event_log(&self) -> Option<&EventLog> { self.cpu.mem.events.as_ref() }
+
+    /// Starts (or, when passing `None`, stops) recording scheduler events for export in the Chrome
+    /// tracing format.
+    pub fn set_chrome_trace(&mut self, trace: Option<ChromeTrace>) {
+        self.cpu.mem.chrome_trace = trace;
+    }
+
+    /// Returns the recorded scheduler events, if enabled.
+    pub fn chrome_trace(&self) -> Option<&ChromeTrace> { self.cpu.mem.chrome_trace.as_ref() }
+
+    /// Starts (or, when passing `None`, stops) taking a snapshot of the PPU registers at the start
+    /// of every frame.
+    pub fn set_ppu_history(&mut self, history: Option<RegisterHistory>) {
+        self.cpu.mem.ppu_history = history;
+    }
+
+    /// Returns the PPU register snapshots of the last frames, if enabled.
+    pub fn ppu_history(&self) -> Option<&RegisterHistory> { self.cpu.mem.ppu_history.as_ref() }
+
+    /// Replaces (or, when passing `None`, disables) the log of recent instructions and memory
+    /// accesses that is dumped when the emulator panics. It is enabled by default.
+    pub fn set_crash_log(&mut self, crash_log: Option<CrashLog>) {
+        self.cpu.mem.crash_log = crash_log;
+    }
+
+    /// Returns the crash log, if enabled (eg. to dump it manually).
+    pub fn crash_log(&self) -> Option<&CrashLog> { self.cpu.mem.crash_log.as_ref() }
+
+    /// Pauses emulation. `run_frame` does nothing until `resume` is called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    /// Emulates exactly one frame, unless emulation is paused. Returns whether a frame was
+    /// emulated.
+    ///
+    /// The frame can be fetched from the PPU afterwards (see `Ppu::output_frame`), and the audio
+    /// produced during it from the APU (see `Spc700::audio`).
+    pub fn run_frame(&mut self) -> BackendResult<bool> {
+        if self.paused {
+            return Ok(false);
+        }
+        try!(self.advance_frame());
+        Ok(true)
+    }
+
+    /// Emulates exactly one frame, even if emulation is paused (to step through a paused game
+    /// frame by frame).
+    pub fn advance_frame(&mut self) -> BackendResult<()> {
+        self.render_frame(|_| Ok(Vec::new())).map(|_| ())
+    }
+
     /// Runs emulation until the next frame is completed.
+    ///
+    /// `render` is passed the PPU's output frame (see `Ppu::output_frame`). The audio produced
+    /// during the frame can be fetched from the APU afterwards (see `Spc700::audio`).
     pub fn render_frame<F>(&mut self, mut render: F) -> BackendResult<Vec<BackendAction>>
-    where F: FnMut(&FrameBuf) -> BackendResult<Vec<BackendAction>> {
-        /// Approximated APU clock divider. It's actually somewhere around 20.9..., which is why we
-        /// can't directly use `MASTER_CLOCK_FREQ / APU_CLOCK_FREQ` (it would round down, which
-        /// might not be critical, but better safe than sorry).
-        const APU_DIVIDER: i32 = 21;
-
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
         let working_cy = LogOnPanic::new("cycle count", self.master_cy);
+        self.cpu.mem.apu.clear_audio();
 
         loop {
-            // Store an action we should perform.
-            let mut actions = vec![];
-            let mut frame_rendered = false;
+            if let Some(actions) = try!(self.step(&mut render)) {
+                return Ok(actions);
+            }
+
+            working_cy.set(self.master_cy);
+        }
+    }
 
-            if self.master_cy >= self.trace_start {
-                self.cpu.trace = true;
-                self.cpu.mem.apu.trace = true;
+    /// Adds the current state to the rewind buffer, if enabled.
+    fn capture_rewind(&mut self) {
+        if let Some(mut rewind) = self.rewind.take() {
+            match rewind.capture(self) {
+                Ok(()) => self.rewind = Some(rewind),
+                Err(e) => error!("couldn't capture rewind state, disabling rewind: {}", e),
             }
+        }
+    }
+
+    /// Executes a single CPU instruction and lets the rest of the system catch up.
+    ///
+    /// `render` is called when the PPU completes a frame. In that case, the actions it returned are
+    /// passed through and the state is captured into the rewind buffer (if enabled), otherwise
+    /// `None` is returned.
+    pub fn step<F>(&mut self, render: &mut F) -> BackendResult<Option<Vec<BackendAction>>>
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        self.cpu.mem.stats.start_frame();
+
+        // Store the actions returned by `render`, if a frame was completed
+        let mut actions = None;
 
-            // Run a CPU instruction and calculate the master cycles elapsed
-            let cpu_master_cy = self.cpu.dispatch() as i32 * CPU_CYCLE + self.cpu.mem.cy as i32;
-            self.cpu.mem.cy = 0;
-
-            // In case the CPU did no work, we pretend that it still took a few cycles. This happens
-            // if a WAI instruction was executed and the CPU is doing nothing while waiting for an
-            // interrupt. We need to emulate the rest of the SNES to some degree or everything
-            // freezes. This should probably be fixed in a better way.
-            let cpu_master_cy = cmp::max(3, cpu_master_cy); // HACK: Use at least 3 master cycles
-            self.master_cy += cpu_master_cy as u64;
-
-            // Now we "owe" the other components a few cycles:
-            self.apu_master_cy_debt += cpu_master_cy;
-            self.ppu_master_cy_debt += cpu_master_cy;
-
-            // Run all components until we no longer owe them:
-            while self.apu_master_cy_debt > APU_DIVIDER {
-                // (Since the APU uses lots of cycles to do stuff - lower clock rate and such - we
-                // only run it if we owe it `APU_DIVIDER` master cycles - or one SPC700 cycle)
-                let apu_master_cy = self.cpu.mem.apu.dispatch() as i32 * APU_DIVIDER;
-                self.apu_master_cy_debt -= apu_master_cy;
+        if self.master_cy >= self.trace_start {
+            self.cpu.trace = true;
+            self.cpu.mem.apu.trace = true;
+        }
+
+        if let Some(ref mut tracer) = self.tracer {
+            if let Err(e) = tracer.trace(&mut self.cpu, self.frames) {
+                error!("couldn't write instruction trace, disabling it: {}", e);
+                tracer.set_enabled(false);
             }
-            while self.ppu_master_cy_debt > 0 {
-                let cy = self.cpu.mem.ppu.update();
-                self.ppu_master_cy_debt -= cy as i32;
-
-                let (v, h) = (self.cpu.mem.ppu.v_counter(), self.cpu.mem.ppu.h_counter());
-                match (v, h) {
-                    (0, 0) => self.cpu.mem.nmi = false,
-                    (0, 6) => {
-                        let channels = self.cpu.mem.hdmaen;
-                        self.cpu.mem.cy += init_hdma(&mut self.cpu.mem, channels);
-                    }
-                    (0 ... 224, 278) => {
-                        // FIXME: 224 or 239, depending on overscan
-                        let channels = self.cpu.mem.hdmaen;
-                        self.cpu.mem.cy += do_hdma(&mut self.cpu.mem, channels);
+        }
+
+        if let Some(ref mut trace_diff) = self.trace_diff {
+            try!(trace_diff.check(&mut self.cpu));
+        }
+
+        if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+            trace.set_time(self.master_cy);
+        }
+
+        // (The log is moved out of the way to read the registers)
+        if let Some(mut crash_log) = self.cpu.mem.crash_log.take() {
+            let opcode = self.cpu.mem.peek(self.cpu.pbr, self.cpu.pc).unwrap_or(0);
+            crash_log.record_instruction(opcode, &self.cpu);
+            self.cpu.mem.crash_log = Some(crash_log);
+        }
+
+        // Run a CPU instruction and calculate the master cycles elapsed
+        let pc = (self.cpu.pbr as u32) << 16 | self.cpu.pc as u32;
+        // The subroutine the instruction belongs to, if we're profiling (a call belongs to the
+        // caller)
+        let function = match (&self.profiler, self.cpu.call_stack()) {
+            (&Some(_), Some(call_stack)) => Some(call_stack.current().map(|frame| frame.target)),
+            _ => None,
+        };
+        self.cpu.mem.instr_accesses = 0;
+        let cpu_cy = self.cpu.dispatch();
+        if let Some(wrap) = self.cpu.take_stack_wrap() {
+            self.stack_check.check(wrap, pc, &self.cpu, self.cpu.mem.crash_log.as_ref());
+        }
+        let cpu_master_cy = cpu_cy as i32 * CPU_CYCLE + self.cpu.mem.cy as i32;
+        self.cpu.mem.cy = 0;
+        self.cpu.mem.stats.total.cpu_cy += cpu_cy as u64;
+
+        // In case the CPU did no work, we pretend that it still took a few cycles. This happens
+        // if a WAI instruction was executed and the CPU is doing nothing while waiting for an
+        // interrupt. We need to emulate the rest of the SNES to some degree or everything
+        // freezes. This should probably be fixed in a better way.
+        let cpu_master_cy = cmp::max(3, cpu_master_cy); // HACK: Use at least 3 master cycles
+        self.master_cy += cpu_master_cy as u64;
+        self.cpu.mem.stats.total.master_cy += cpu_master_cy as u64;
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, cpu_master_cy as u64);
+            if let Some(function) = function {
+                profiler.record_function(function, cpu_master_cy as u64);
+            }
+        }
+
+        // Now we "owe" the other components a few cycles:
+        self.apu_master_cy_debt += cpu_master_cy;
+        self.ppu_master_cy_debt += cpu_master_cy;
+
+        // Run all components until we no longer owe them (depending on the accuracy settings,
+        // we let them lag behind for a bit):
+        let accuracy = self.cpu.mem.accuracy;
+        if self.apu_master_cy_debt > accuracy.apu_sync as i32 {
+            let apu_start = self.master_cy - self.apu_master_cy_debt as u64;
+            let apu_start_debt = self.apu_master_cy_debt;
+            // Master cycles per APU cycle (about 20.97 at the nominal rates), in 16.16 fixed point
+            let apu_divider = self.clocks.apu_divider();
+            while self.apu_master_cy_debt > (apu_divider >> 16) as i32 {
+                // (Since the APU uses lots of cycles to do stuff - lower clock rate and such -
+                // we only run it if we owe it at least one SPC700 cycle)
+                let apu_cy = self.cpu.mem.apu.dispatch();
+                self.cpu.mem.stats.total.apu_cy += apu_cy as u64;
+                let elapsed = apu_cy as u64 * apu_divider as u64 + self.apu_master_cy_frac as u64;
+                self.apu_master_cy_debt -= (elapsed >> 16) as i32;
+                self.apu_master_cy_frac = elapsed as u32 & 0xffff;
+            }
+            if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                let duration = apu_start_debt - self.apu_master_cy_debt;
+                if duration > 0 { trace.span(Track::Apu, "APU sync", apu_start, duration as u64) }
+            }
+        }
+        let catch_up_ppu = self.ppu_master_cy_debt > accuracy.ppu_sync as i32;
+        while catch_up_ppu && self.ppu_master_cy_debt > 0 {
+            let cy = self.cpu.mem.ppu.update();
+            self.ppu_master_cy_debt -= cy as i32;
+            self.cpu.mem.stats.total.ppu_cy += cy as u64;
+
+            if self.cpu.mem.input.update_hv_latch() {
+                self.cpu.mem.ppu.latch_counters();
+            }
+
+            let (v, h) = (self.cpu.mem.ppu.v_counter(), self.cpu.mem.ppu.h_counter());
+            // Scanline after the last visible one (depends on overscan)
+            let vblank_line = self.cpu.mem.ppu.frame_height() + 1;
+            // The point in time the PPU is at
+            let ppu_time = (self.master_cy as i64 - self.ppu_master_cy_debt as i64) as u64;
+            match (v, h) {
+                (0, 0) => {
+                    self.cpu.mem.nmi = false;
+                    if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                        trace.start_frame(ppu_time);
                     }
-                    (224, 256) => {
-                        // Last pixel in the current frame was rendered
-                        for action in try!(render(&self.cpu.mem.ppu.framebuf)) {
-                            actions.push(action);
-                        }
-                        frame_rendered = true;
+                    if let Some(ref mut events) = self.cpu.mem.events {
+                        events.start_frame();
                     }
-                    (225, 0) => {
-                        // First V-Blank pixel
-                        self.cpu.mem.input.new_frame();
-
-                        // FIXME This timing is wrong, the NMI flag is set later
-                        self.cpu.mem.nmi = true;
-                        if self.cpu.mem.nmi_enabled() {
-                            self.cpu.trigger_nmi();
-                            // XXX Break to handle the NMI immediately. Let's hope we don't owe the PPU
-                            // too many cycles.
-                            break;
-                        }
+                    if let Some(ref mut history) = self.cpu.mem.ppu_history {
+                        history.start_frame(&self.cpu.mem.ppu);
                     }
-                    (225, 50) => {
-                        // Auto-Joypad read
-                        // "This begins between dots 32.5 and 95.5 of the first V-Blank scanline,
-                        // and ends 4224 master cycles later."
-                        // FIXME start this at the right position
-                        // FIXME Set auto read status bit
-                        if self.cpu.mem.nmien & 1 != 0 {
-                            self.cpu.mem.input.perform_auto_read();
-                        }
+                }
+                (0, 6) => {
+                    let channels = self.cpu.mem.hdmaen;
+                    let cy = init_hdma(&mut self.cpu.mem, channels);
+                    self.cpu.mem.stats.total.dma_cy += cy as u64;
+                    if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                        if cy > 0 { trace.span(Track::Dma, "HDMA init", ppu_time, cy as u64) }
                     }
-                    (_, 180) => {
-                        // Approximate DRAM refresh (FIXME Probably incorrect, but does it matter?)
-                        self.cpu.mem.cy += 40;
+                    if accuracy.dma_timing { self.cpu.mem.cy += cy; }
+                }
+                (v, 278) if v < vblank_line => {
+                    let channels = self.cpu.mem.hdmaen;
+                    let cy = do_hdma(&mut self.cpu.mem, channels);
+                    self.cpu.mem.stats.total.dma_cy += cy as u64;
+                    if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                        if cy > 0 { trace.span(Track::Dma, "HDMA", ppu_time, cy as u64) }
                     }
-                    _ => {}
+                    if accuracy.dma_timing { self.cpu.mem.cy += cy; }
                 }
+                (v, 256) if v == vblank_line - 1 => {
+                    // Last pixel in the current frame was rendered
+                    actions = Some(try!(render(self.cpu.mem.ppu.output_frame())));
+                    self.cpu.mem.stats.end_frame();
+                    self.frames += 1;
+                }
+                (v, 0) if v == vblank_line => {
+                    // First V-Blank pixel
+                    self.cpu.mem.input.new_frame();
+
+                    if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                        trace.start_vblank(ppu_time);
+                    }
 
-                {
-                    let cpu = &mut self.cpu;
-                    if cpu.mem.ppu.v_counter() == cpu.mem.vtime && cpu.mem.v_irq_enabled() {
-                        //trace!("V-IRQ at V={}", cpu.mem.ppu.v_counter());
-                        cpu.mem.irq = true;
-                        cpu.trigger_irq();
-                        break;
+                    // The NMI is taken before the CPU's next instruction (see `Mem::nmi`)
+                    self.cpu.mem.nmi = true;
+                    if self.cpu.mem.nmi_enabled() {
+                        if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                            trace.instant(Track::Cpu, "NMI", ppu_time);
+                        }
+                        self.cpu.mem.nmi_pending = true;
                     }
-                    if cpu.mem.ppu.h_counter() == cpu.mem.htime && cpu.mem.h_irq_enabled() {
-                        //trace!("H-IRQ at H={}", cpu.mem.ppu.h_counter());
-                        cpu.mem.irq = true;
-                        cpu.trigger_irq();
-                        break;
+                }
+                (v, 50) if v == vblank_line => {
+                    // Auto-Joypad read
+                    // "This begins between dots 32.5 and 95.5 of the first V-Blank scanline,
+                    // and ends 4224 master cycles later."
+                    // FIXME start this at the right position
+                    // FIXME Set auto read status bit
+                    if self.cpu.mem.nmien & 1 != 0 {
+                        self.cpu.mem.input.perform_auto_read();
                     }
                 }
+                (_, DRAM_REFRESH_DOT) => {
+                    // DRAM refresh. This happens on every scanline (including V-Blank) and
+                    // halts the CPU, but not the PPU or APU. We charge the stall to the CPU's
+                    // next instruction, which makes the rest of the system catch up.
+                    if accuracy.dram_refresh {
+                        self.cpu.mem.cy += DRAM_REFRESH_CYCLES;
+                    }
+                }
+                _ => {}
             }
 
-            if frame_rendered { return Ok(actions); }
+            if self.cpu.mem.timer_irq_due() {
+                if let Some(ref mut trace) = self.cpu.mem.chrome_trace {
+                    trace.instant(Track::Cpu, "IRQ (H/V)", ppu_time);
+                }
+                // The flag stays set until it's acknowledged, so if the I flag is set now, the
+                // CPU takes the IRQ once it's cleared. The CPU polls the line before its next
+                // instruction (see `Mem::irq`).
+                self.cpu.mem.irq = true;
+            }
+        }
 
-            working_cy.set(self.master_cy);
+        if actions.is_some() {
+            self.capture_rewind();
         }
+        Ok(actions)
     }
 }
 
@@ -484,6 +1210,47 @@ pub struct Emulator<R: Renderer, A: AudioSink> {
     /// The audio sink to be used for APU output
     pub audio: A,
     pub snes: Snes,
+    /// Keeps the emulation running at the console's frame rate
+    pub pacer: FramePacer,
+    /// Keeps the audio sink's buffer level stable by adjusting its playback rate (`None` disables
+    /// dynamic rate control)
+    pub rate_control: Option<RateController>,
+    /// Messages displayed on top of the emulated frame
+    pub osd: Osd,
+    /// Save state history captured while input is recorded (`None` disables it)
+    pub greenzone: Option<Greenzone>,
+    /// Memory triggers checked after every frame (`None` disables them)
+    pub triggers: Option<TriggerSet>,
+    /// Input macro slots, played and recorded on port 0 with the macro hotkeys
+    pub macros: Vec<Option<InputMacro>>,
+    /// Where save states, screenshots and macros are stored (`None` puts them into the working
+    /// directory)
+    pub storage: Option<GameStorage>,
+    /// File the ROM was loaded from, read again by the reload hotkey (`None` disables reloading)
+    pub rom_path: Option<PathBuf>,
+    /// Keep the cartridge RAM when the ROM is reloaded (instead of starting with empty SRAM)
+    pub reload_keeps_sram: bool,
+    /// State of the console before the last save state was loaded
+    undo_load: Option<Vec<u8>>,
+    /// Contents of the save state file before it was last overwritten
+    undo_save: Option<Vec<u8>>,
+    /// Copy of the frame buffer the OSD is drawn onto
+    osd_frame: Vec<u8>,
+    /// Size of the frames the renderer currently expects
+    frame_size: (u32, u32),
+    /// Output hi-res, overscan and interlaced frames in full size. Disabled if the renderer can't
+    /// change its frame size.
+    extended_output: bool,
+    /// Emulate a single frame even though we're paused
+    frame_advance: bool,
+    /// The rewind hotkey is held: Go back in time instead of emulating new frames
+    rewinding: bool,
+    /// Speed factor used while the slow motion hotkey is held
+    slow_motion_speed: f64,
+    /// Open debug views and the surfaces they're displayed on
+    debug_views: Vec<(DebugView, DebugSurfaceId)>,
+    /// Scratch image debug views are rendered into
+    debug_image: DebugImage,
     #[allow(dead_code)]
     priv_: (),
 }
@@ -493,6 +1260,11 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
     ///
     /// This will also create a default `Input` instance without any attached peripherals.
     pub fn new(rom: Rom, renderer: R, audio: A) -> Self {
+        Self::with_ram_init(rom, renderer, audio, RamInit::default())
+    }
+
+    /// Like `new`, but fills the system's RAM with the given pattern on power-on.
+    pub fn with_ram_init(rom: Rom, renderer: R, audio: A, ram_init: RamInit) -> Self {
         // Start tracing at this master cycle (`!0` by default, which practically disables tracing)
         let trace_start: u64 = match env::var("BREEZE_TRACE") {
             Ok(string) => match string.parse() {
@@ -513,13 +1285,33 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
             }
         };
 
-        let mut snes = Snes::new(rom);
+        let mut snes = Snes::with_ram_init(rom, ram_init);
         snes.trace_start = trace_start;
+        snes.set_rewind(Some(Rewind::new(REWIND_INTERVAL, REWIND_CAPACITY)));
 
         Emulator {
             renderer: renderer,
             audio: audio,
+            pacer: FramePacer::new(snes.frame_rate()),
             snes: snes,
+            rate_control: Some(RateController::default()),
+            osd: Osd::new(),
+            greenzone: None,
+            triggers: None,
+            macros: vec![None; MACRO_SLOTS as usize],
+            storage: None,
+            rom_path: None,
+            reload_keeps_sram: true,
+            undo_load: None,
+            undo_save: None,
+            osd_frame: Vec::new(),
+            frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            extended_output: true,
+            frame_advance: false,
+            rewinding: false,
+            slow_motion_speed: 0.25,
+            debug_views: Vec::new(),
+            debug_image: DebugImage::new(),
             priv_: (),
         }
     }
@@ -530,26 +1322,370 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
     /// Get a mutable reference to the `Peripherals` instance
     pub fn peripherals_mut(&mut self) -> &mut Peripherals { &mut self.snes.cpu.mem }
 
+    pub fn is_paused(&self) -> bool { self.snes.is_paused() }
+
+    /// Pauses or resumes emulation. While paused, `render_frame` keeps displaying the last frame.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused { self.snes.pause() } else { self.snes.resume() }
+        self.frame_advance = false;
+        self.osd.set_indicator("PAUSED", paused);
+    }
+
+    /// Pauses emulation (if it isn't already paused), emulates exactly one frame and displays it.
+    /// Emulation stays paused afterwards.
+    ///
+    /// Returns `true` if the backend requested an exit, like `render_frame`.
+    pub fn frame_advance(&mut self) -> BackendResult<bool> {
+        if !self.is_paused() {
+            self.set_paused(true);
+        }
+        self.frame_advance = true;
+        self.render_frame()
+    }
+
+    /// Returns the emulation speed relative to the console.
+    pub fn speed(&self) -> f64 { self.pacer.speed() }
+
+    /// Changes the clock rates of the emulated console and adjusts the frame rate to them.
+    pub fn set_clock_rates(&mut self, clocks: ClockRates) {
+        self.snes.set_clock_rates(clocks);
+        self.pacer.set_frame_rate(self.snes.frame_rate());
+    }
+
+    /// Changes the region of the emulated console (see `Snes::set_region`) and adjusts the frame
+    /// rate to it.
+    pub fn set_region(&mut self, region: Region) {
+        self.snes.set_region(region);
+        self.pacer.set_frame_rate(self.snes.frame_rate());
+    }
+
+    /// Sets the emulation speed relative to the console (`0.5` runs at half speed). Frames are
+    /// emulated as usual, they're just paced slower (or faster).
+    pub fn set_speed(&mut self, speed: f64) {
+        self.pacer.set_speed(speed);
+        self.osd.set_indicator("SLOW MOTION", speed < 1.0);
+    }
+
+    /// Sets the speed used while the slow motion hotkey is held (0.25 by default).
+    pub fn set_slow_motion_speed(&mut self, speed: f64) {
+        assert!(speed > 0.0, "invalid slow motion speed: {}", speed);
+        self.slow_motion_speed = speed;
+    }
+
+    /// Resizes the audio sink's buffer and makes the rate control aim for the new latency.
+    pub fn set_audio_buffer(&mut self, config: AudioBufferConfig) -> BackendResult<()> {
+        try!(self.audio.set_buffer_config(config));
+        if let Some(ref mut controller) = self.rate_control {
+            controller.set_target_latency(config.target_latency(self.audio.output_rate()));
+        }
+        Ok(())
+    }
+
+    /// Logs the audio latency that was achieved and how often the audio device ran dry.
+    pub fn log_audio_stats(&self) {
+        let millis = |d: Duration| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000;
+        if let Some(level) = self.rate_control.as_ref().and_then(|c| c.level()) {
+            info!("achieved audio latency: {} ms", millis(level));
+        }
+        if let Some(underruns) = self.audio.underruns() {
+            info!("audio buffer underruns: {}", underruns);
+        }
+    }
+
+    /// Enables widescreen rendering with a margin of `margin` pixels on each side of the picture
+    /// (`0` disables it).
+    ///
+    /// Fails if the margin is too large or the renderer doesn't support the wider frames.
+    pub fn set_widescreen(&mut self, margin: u16) -> BackendResult<()> {
+        if margin > MAX_WIDESCREEN_MARGIN {
+            return Err(format!("widescreen margin must be at most {} pixels",
+                               MAX_WIDESCREEN_MARGIN).into());
+        }
+        if margin == self.snes.cpu.mem.ppu.widescreen_margin() {
+            return Ok(());
+        }
+
+        let size = (SCREEN_WIDTH + 2 * margin as u32, SCREEN_HEIGHT);
+        try!(self.renderer.set_frame_size(size.0, size.1));
+        self.frame_size = size;
+        self.snes.cpu.mem.ppu.set_widescreen(margin);
+        Ok(())
+    }
+
+    /// Opens a debug surface showing the given view. The view is updated after every frame.
+    ///
+    /// Fails if the renderer doesn't support debug surfaces.
+    pub fn open_debug_view(&mut self, view: DebugView) -> BackendResult<()> {
+        self.snes.cpu.mem.ppu.render_debug_view(view, &mut self.debug_image);
+        let id = try!(self.renderer.open_debug_surface(&view.title(),
+                                                       self.debug_image.width(),
+                                                       self.debug_image.height()));
+        self.debug_views.push((view, id));
+        self.update_debug_views()
+    }
+
+    /// Closes all debug surfaces showing the given view.
+    pub fn close_debug_view(&mut self, view: DebugView) {
+        let renderer = &mut self.renderer;
+        self.debug_views.retain(|&(v, id)| {
+            if v == view { renderer.close_debug_surface(id); }
+            v != view
+        });
+    }
+
+    /// Renders all open debug views and displays them.
+    fn update_debug_views(&mut self) -> BackendResult<()> {
+        for &(view, id) in &self.debug_views {
+            self.snes.cpu.mem.ppu.render_debug_view(view, &mut self.debug_image);
+            try!(self.renderer.update_debug_surface(id,
+                                                    self.debug_image.data(),
+                                                    self.debug_image.width(),
+                                                    self.debug_image.height()));
+        }
+        Ok(())
+    }
+
+    /// Loads the ROM from `rom_path` again and resets the system, keeping input devices, macros,
+    /// settings and (if `reload_keeps_sram` is set) the cartridge RAM.
+    pub fn reload_rom(&mut self) -> BackendResult<()> {
+        if self.snes.cpu.mem.input.is_recording() || self.snes.cpu.mem.input.is_replaying() {
+            return Err("can't reload the ROM while recording or replaying input".into());
+        }
+        let rom = match self.rom_path {
+            Some(ref path) => {
+                info!("reloading the ROM from '{}'", path.display());
+                try!(Rom::load_file(path))
+            }
+            None => return Err("the ROM wasn't loaded from a file".into()),
+        };
+
+        if let Some(title) = rom.get_title() {
+            self.renderer.set_rom_title(title);
+        }
+        self.snes.replace_rom(rom, self.reload_keeps_sram);
+        Ok(())
+    }
+
+    /// Writes a save state to the save state file and returns its path.
+    ///
+    /// The file's previous contents are kept in memory and can be put back with
+    /// `undo_save_state`.
+    pub fn save_state(&mut self) -> BackendResult<PathBuf> {
+        let path = self.storage_path(|s| s.save_state_path(1), "breeze.sav");
+        let mut previous = Vec::new();
+        let overwriting = match File::open(&path).and_then(|mut f| f.read_to_end(&mut previous)) {
+            Ok(_) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = try!(self.create_file(&path));
+        if overwriting {
+            self.undo_save = Some(previous);
+        }
+        try!(self.snes.create_save_state(SaveStateFormat::default(), &mut file));
+        Ok(path)
+    }
+
+    /// Restores the state from the save state file.
+    ///
+    /// The state the console was in before is kept in memory and can be returned to with
+    /// `undo_load_state`.
+    pub fn load_state(&mut self) -> BackendResult<()> {
+        if self.snes.cpu.mem.input.is_recording() || self.snes.cpu.mem.input.is_replaying() {
+            return Err("can't load a state while recording or replaying input".into());
+        }
+        let path = self.storage_path(|s| s.save_state_path(1), "breeze.sav");
+        let file = try!(File::open(path));
+        let current = try!(self.capture_state());
+        if let Err(e) = self.snes.restore_save_state(SaveStateFormat::default(),
+                                                     &mut BufReader::new(file)) {
+            // Don't leave the console in a half-restored state
+            try!(self.snes.restore_save_state(SaveStateFormat::Custom, &mut &current[..]));
+            return Err(e.into());
+        }
+        self.undo_load = Some(current);
+        Ok(())
+    }
+
+    /// Returns to the state the console was in before the last `load_state`.
+    ///
+    /// The state that is left is kept, so undoing again returns to the loaded state.
+    pub fn undo_load_state(&mut self) -> BackendResult<()> {
+        if self.snes.cpu.mem.input.is_recording() || self.snes.cpu.mem.input.is_replaying() {
+            return Err("can't load a state while recording or replaying input".into());
+        }
+        let previous = match self.undo_load.take() {
+            Some(state) => state,
+            None => return Err("no save state was loaded".into()),
+        };
+        let current = try!(self.capture_state());
+        try!(self.snes.restore_save_state(SaveStateFormat::Custom, &mut &previous[..]));
+        self.undo_load = Some(current);
+        Ok(())
+    }
+
+    /// Puts back the contents the save state file had before the last `save_state`.
+    ///
+    /// The overwritten save state is kept, so undoing again restores it.
+    pub fn undo_save_state(&mut self) -> BackendResult<()> {
+        let previous = match self.undo_save.take() {
+            Some(data) => data,
+            None => return Err("no save state was overwritten".into()),
+        };
+        let path = self.storage_path(|s| s.save_state_path(1), "breeze.sav");
+        let mut current = Vec::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut current)) {
+            self.undo_save = Some(previous);
+            return Err(e.into());
+        }
+        if let Err(e) = self.create_file(&path).and_then(|mut file| file.write_all(&previous)) {
+            self.undo_save = Some(previous);
+            return Err(e.into());
+        }
+        self.undo_save = Some(current);
+        Ok(())
+    }
+
+    /// Serializes the console's current state into memory.
+    fn capture_state(&self) -> io::Result<Vec<u8>> {
+        let mut state = Vec::new();
+        try!(self.snes.create_save_state(SaveStateFormat::Custom, &mut state));
+        Ok(state)
+    }
+
     /// Handles a `BackendAction`. Returns `true` if the emulator should exit.
     pub fn handle_action(&mut self, action: BackendAction) -> bool {
         match action {
             BackendAction::Exit => return true,
-            BackendAction::SaveState => {
-                let path = "breeze.sav";
-                let mut file = File::create(path).unwrap();
-                self.snes.create_save_state(SaveStateFormat::default(), &mut file).unwrap();
-                info!("created a save state in '{}'", path);
+            BackendAction::SaveState => match self.save_state() {
+                Ok(path) => {
+                    info!("created a save state in '{}'", path.display());
+                    self.osd.show("State saved");
+                }
+                Err(e) => {
+                    error!("couldn't create a save state: {}", e);
+                    self.osd.show("Couldn't save the state");
+                }
+            },
+            BackendAction::LoadState => match self.load_state() {
+                Ok(()) => {
+                    info!("restored save state");
+                    self.osd.show("State loaded");
+                }
+                Err(e) => {
+                    error!("couldn't load the save state: {}", e);
+                    self.osd.show("Couldn't load the state");
+                }
+            },
+            BackendAction::UndoLoadState => match self.undo_load_state() {
+                Ok(()) => {
+                    info!("returned to the state before the last load");
+                    self.osd.show("State load undone");
+                }
+                Err(e) => {
+                    error!("couldn't undo loading the save state: {}", e);
+                    self.osd.show("Couldn't undo the state load");
+                }
+            },
+            BackendAction::UndoSaveState => match self.undo_save_state() {
+                Ok(()) => {
+                    info!("restored the overwritten save state");
+                    self.osd.show("State save undone");
+                }
+                Err(e) => {
+                    error!("couldn't undo creating the save state: {}", e);
+                    self.osd.show("Couldn't undo the state save");
+                }
+            },
+            BackendAction::Rewind(start) => self.set_rewinding(start),
+            BackendAction::FastForward(enable) => {
+                self.pacer.set_fast_forward(enable);
+                self.osd.set_indicator("FAST FORWARD", enable);
+            }
+            BackendAction::SlowMotion(enable) => {
+                let speed = if enable { self.slow_motion_speed } else { 1.0 };
+                self.set_speed(speed);
+            }
+            BackendAction::Screenshot => {
+                let path = match self.storage {
+                    Some(ref storage) => storage.next_screenshot_path(),
+                    // Don't overwrite earlier screenshots in the working directory
+                    None => storage::first_unused_path(Path::new(""), "breeze-",
+                                                       screenshot::EXTENSION),
+                };
+                match self.save_screenshot(&path) {
+                    Ok(()) => {
+                        info!("saved a screenshot to '{}'", path.display());
+                        self.osd.show("Screenshot saved");
+                    }
+                    Err(e) => {
+                        error!("couldn't save screenshot to '{}': {}", path.display(), e);
+                        self.osd.show("Couldn't save screenshot");
+                    }
+                }
+            }
+            BackendAction::Reset => {
+                info!("resetting the system");
+                self.snes.reset();
+                self.osd.show("Reset");
+            }
+            BackendAction::ReloadRom => match self.reload_rom() {
+                Ok(()) => self.osd.show("ROM reloaded"),
+                Err(e) => {
+                    error!("couldn't reload the ROM: {}", e);
+                    self.osd.show("Couldn't reload the ROM");
+                }
+            },
+            BackendAction::TogglePause => {
+                let paused = !self.is_paused();
+                info!("{} emulation", if paused { "pausing" } else { "resuming" });
+                self.set_paused(paused);
             }
-            BackendAction::LoadState => {
-                if self.snes.cpu.mem.input.is_recording() || self.snes.cpu.mem.input.is_replaying() {
-                    error!("cannot load a save state while recording or replaying input!");
+            BackendAction::FrameAdvance => {
+                if self.is_paused() {
+                    self.frame_advance = true;
                 } else {
-                    let file = File::open("breeze.sav").unwrap();
-                    let mut bufrd = BufReader::new(file);
-                    self.snes.restore_save_state(SaveStateFormat::default(), &mut bufrd).unwrap();
-                    info!("restored save state");
+                    info!("pausing emulation");
+                    self.set_paused(true);
+                }
+            }
+            BackendAction::PlayMacro(slot) => {
+                match self.macros.get(slot as usize) {
+                    Some(&Some(ref input_macro)) => {
+                        self.snes.cpu.mem.input.play_macro(0, input_macro.clone());
+                    }
+                    _ => self.osd.show(format!("Macro {} is empty", slot + 1)),
                 }
             }
+            BackendAction::RecordMacro(slot) => {
+                if self.snes.cpu.mem.input.is_recording_macro(0) {
+                    let input_macro = self.snes.cpu.mem.input.stop_macro_recording(0).unwrap();
+                    let default = format!("breeze-macro{}.txt", slot + 1);
+                    let path = self.storage_path(|s| s.macro_path(slot), &default);
+                    if let Err(e) = self.create_file(&path)
+                        .and_then(|mut file| write!(file, "{}", input_macro)) {
+                        error!("couldn't save macro to '{}': {}", path.display(), e);
+                    }
+                    info!("recorded a macro of {} frames into slot {} (saved to '{}')",
+                          input_macro.len(), slot + 1, path.display());
+                    self.osd.set_indicator("REC MACRO", false);
+                    self.osd.show(format!("Macro {} recorded", slot + 1));
+                    if let Some(macro_slot) = self.macros.get_mut(slot as usize) {
+                        *macro_slot = Some(input_macro);
+                    }
+                } else {
+                    self.snes.cpu.mem.input.start_macro_recording(0);
+                    self.osd.set_indicator("REC MACRO", true);
+                }
+            }
+            BackendAction::ToggleHold(button) => {
+                let held = self.snes.cpu.mem.input.toggle_held(0, button);
+                let name = button.name().to_uppercase();
+                self.osd.set_indicator(&format!("HOLD {}", name), held);
+            }
+            BackendAction::DebugSurfaceClosed(id) => {
+                self.debug_views.retain(|&(_, surface)| surface != id);
+            }
         }
 
         false
@@ -560,24 +1696,202 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
     ///
     /// Returns `true` if the backend requested an exit, `false` otherwise.
     pub fn render_frame(&mut self) -> BackendResult<bool> {
-        let actions = {
-            let renderer = &mut self.renderer;
-            self.snes.render_frame(|framebuf| renderer.render(&**framebuf))
-        };
+        // When paused, the last frame is displayed again to keep the window alive and responsive
+        if self.rewinding {
+            try!(self.rewind_frame());
+        } else if !self.is_paused() || self.frame_advance {
+            self.frame_advance = false;
+            self.snes.cpu.mem.ppu.set_extended_output(self.extended_output);
+            try!(self.snes.advance_frame());
+            self.audio.write(self.snes.cpu.mem.apu.audio());
+        }
 
-        for action in try!(actions) {
+        for action in try!(self.present_frame()) {
             if self.handle_action(action) { return Ok(true); }
         }
 
+        if let Some(addr) = self.snes.take_stack_break() {
+            // The details were logged when it happened
+            self.set_paused(true);
+            self.osd.show(format!("Stack wrapped around at ${:06X}", addr));
+        }
+
+        if let Some(ref mut greenzone) = self.greenzone {
+            if self.snes.cpu.mem.input.is_recording() {
+                if let Err(e) = greenzone.capture(&self.snes) {
+                    error!("couldn't capture greenzone state, disabling the greenzone: {}", e);
+                    self.greenzone = None;
+                }
+            }
+        }
+
+        if let Some(ref mut triggers) = self.triggers {
+            triggers.check(&mut self.snes);
+        }
+
+        try!(self.update_debug_views());
+        self.pacer.wait(self.renderer.frame_timing());
+        self.update_audio_rate();
         Ok(false)
     }
 
+    /// Starts or stops rewinding (while the rewind hotkey is held).
+    fn set_rewinding(&mut self, rewinding: bool) {
+        if rewinding {
+            if self.snes.rewind_buffer().is_none() {
+                self.osd.show("Rewinding is disabled");
+                return;
+            }
+            let input = &self.snes.cpu.mem.input;
+            if input.is_recording() || input.is_replaying() {
+                // The recorded input would no longer match the emulated frames
+                self.osd.show("Can't rewind while recording or replaying input");
+                return;
+            }
+        }
+        self.rewinding = rewinding;
+        self.osd.set_indicator("REWIND", rewinding);
+    }
+
+    /// Goes back by one rewind state and emulates a frame from there to have something to display.
+    fn rewind_frame(&mut self) -> BackendResult<()> {
+        // Going back 2 frames skips the state captured after the frame emulated last time
+        match self.snes.rewind(2) {
+            Ok(Some(_)) => {
+                self.snes.cpu.mem.ppu.set_extended_output(self.extended_output);
+                try!(self.snes.advance_frame());
+            }
+            Ok(None) => self.osd.show("Can't rewind any further"),
+            Err(e) => {
+                error!("couldn't rewind: {}", e);
+                self.osd.show("Couldn't rewind");
+                self.set_rewinding(false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the PPU's output frame to the renderer, with the OSD drawn on top.
+    ///
+    /// The frame size can change between frames (hi-res frames are twice as wide, overscan and
+    /// interlace add scanlines), so the renderer is told about the new size first. If it can't
+    /// display those frames, they are shrunk to the native size from now on.
+    fn present_frame(&mut self) -> BackendResult<Vec<BackendAction>> {
+        let mut size = self.snes.cpu.mem.ppu.output_size();
+        if size != self.frame_size {
+            match self.renderer.set_frame_size(size.0, size.1) {
+                Ok(()) => self.frame_size = size,
+                Err(e) => {
+                    if !self.snes.cpu.mem.ppu.extended_frame() { return Err(e) }
+                    info!("renderer can't display {}x{} frames ({}), shrinking them to {}x{}",
+                          size.0, size.1, e, SCREEN_WIDTH, SCREEN_HEIGHT);
+                    self.extended_output = false;
+                    self.snes.cpu.mem.ppu.set_extended_output(false);
+                    size = self.snes.cpu.mem.ppu.output_size();
+                }
+            }
+        }
+
+        render_with_osd(&mut self.renderer,
+                        &mut self.osd,
+                        &mut self.osd_frame,
+                        self.snes.cpu.mem.ppu.output_frame(),
+                        size)
+    }
+
+    /// Measures the audio buffer level and adjusts the audio sink's playback rate accordingly.
+    fn update_audio_rate(&mut self) {
+        let hold = self.is_paused() || self.rewinding || self.pacer.is_fast_forwarding() ||
+            self.pacer.speed() != 1.0;
+        let controller = match self.rate_control {
+            Some(ref mut controller) => controller,
+            None => return,
+        };
+
+        if hold {
+            // No samples are produced at the normal rate, so the buffer level is meaningless. The
+            // sink plays silence when it runs dry (eg. between advanced frames), and adjusting
+            // the rate to compensate would only distort the audio once we're back to normal.
+            controller.reset();
+            self.audio.set_rate_adjust(1.0);
+            return;
+        }
+
+        if let Some(latency) = self.audio.latency() {
+            self.audio.set_rate_adjust(controller.update(latency));
+        }
+    }
+
+    /// Writes a crash report after emulation panicked, along with a save state (same file name,
+    /// with the extension `.sav`). Returns the path of the report.
+    pub fn write_crash_report(&self, message: &str) -> io::Result<PathBuf> {
+        let path = self.storage_path(|s| s.next_crash_report_path(), "breeze-crash.txt");
+        let mut file = BufWriter::new(try!(self.create_file(&path)));
+        try!(crash_report::write_report(&self.snes, message, &mut file));
+        try!(file.flush());
+
+        if let Some(state) = crash_report::save_state(&self.snes) {
+            let state_path = path.with_extension("sav");
+            let mut file = try!(self.create_file(&state_path));
+            try!(file.write_all(&state));
+            info!("saved the machine state to '{}'", state_path.display());
+        }
+        Ok(path)
+    }
+
+    /// Returns the path of a file in the game's storage, or `default` (in the working directory)
+    /// if there is no storage.
+    fn storage_path<F>(&self, f: F, default: &str) -> PathBuf
+    where F: FnOnce(&GameStorage) -> PathBuf {
+        match self.storage {
+            Some(ref storage) => f(storage),
+            None => PathBuf::from(default),
+        }
+    }
+
+    /// Creates a file returned by `storage_path`.
+    fn create_file(&self, path: &Path) -> io::Result<File> {
+        match self.storage {
+            Some(ref storage) => storage.create_file(path),
+            None => File::create(path),
+        }
+    }
+
+    /// Writes the last rendered frame to a file (see `Screenshot::write`).
+    fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(try!(self.create_file(path)));
+        try!(self.snes.screenshot().write(&mut file));
+        file.flush()
+    }
+
     /// Runs the emulator in a loop
     ///
     /// This will emulate the system and render frames until the backend signals that the emulator
     /// should exit.
     pub fn run(&mut self) -> BackendResult<()> {
         while !try!(self.render_frame()) {}
+
+        let stats = self.snes.stats();
+        info!("emulated {} frames ({} master cycles, {} CPU cycles, {} APU cycles, {} master \
+               cycles of DMA)", stats.frames, stats.total.master_cy, stats.total.cpu_cy,
+               stats.total.apu_cy, stats.total.dma_cy);
         Ok(())
     }
 }
+
+/// Passes a frame to the renderer, drawing the OSD on top of it if it's visible.
+fn render_with_osd<R: Renderer>(renderer: &mut R,
+                                osd: &mut Osd,
+                                osd_frame: &mut Vec<u8>,
+                                frame: &[u8],
+                                size: (u32, u32)) -> BackendResult<Vec<BackendAction>> {
+    osd.tick();
+    if !osd.is_visible() {
+        return renderer.render(frame);
+    }
+
+    osd_frame.clear();
+    osd_frame.extend_from_slice(frame);
+    osd.draw(osd_frame, size.0, size.1);
+    renderer.render(osd_frame)
+}
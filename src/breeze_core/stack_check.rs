@@ -0,0 +1,141 @@
+//! Diagnostics for stack overflows and underflows
+//!
+//! When the CPU's stack pointer wraps around, something has usually gone wrong already: A game
+//! returned from more subroutines than it called, or an emulator bug corrupted the stack. The
+//! `StackPolicy` decides whether that is ignored, logged (once, with the code that led up to it)
+//! or stops emulation so the situation can be inspected in the debugger.
+
+use log_util::{target, CrashLog};
+
+use wdc65816::{Cpu, Mem, StackWrap};
+use wdc65816::callstack::CallKind;
+
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// Number of recently executed instructions included in a report (if the crash log is enabled)
+const REPORT_INSTRUCTIONS: usize = 16;
+
+/// What to do when the stack pointer wraps around
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StackPolicy {
+    /// Don't check the stack at all
+    Ignore,
+    /// Log the first wrap (with context), ignore the following ones
+    LogOnce,
+    /// Log every wrap and stop emulation (see `Snes::take_stack_break`)
+    Break,
+}
+
+impl Default for StackPolicy {
+    fn default() -> Self { StackPolicy::LogOnce }
+}
+
+impl FromStr for StackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "ignore" => Ok(StackPolicy::Ignore),
+            "log" => Ok(StackPolicy::LogOnce),
+            "break" => Ok(StackPolicy::Break),
+            _ => Err(format!("unknown stack policy: {} (expected `ignore`, `log` or `break`)", s)),
+        }
+    }
+}
+
+impl fmt::Display for StackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            StackPolicy::Ignore => "ignore",
+            StackPolicy::LogOnce => "log",
+            StackPolicy::Break => "break",
+        })
+    }
+}
+
+/// Applies the `StackPolicy` to the stack wraps reported by the CPU.
+#[derive(Clone, Debug, Default)]
+pub struct StackCheck {
+    policy: StackPolicy,
+    /// A wrap has already been logged (for `StackPolicy::LogOnce`)
+    logged: bool,
+    /// Address of the instruction that caused a wrap we should stop at
+    pending_break: Option<u32>,
+}
+
+impl StackCheck {
+    pub fn new(policy: StackPolicy) -> Self {
+        StackCheck {
+            policy: policy,
+            logged: false,
+            pending_break: None,
+        }
+    }
+
+    pub fn policy(&self) -> StackPolicy { self.policy }
+
+    /// Handles a wrap caused by the instruction at `pc` (`$BBAAAA`). `cpu` is the state after the
+    /// instruction was executed, `crash_log` provides the instructions leading up to it.
+    pub fn check<M: Mem>(&mut self,
+                         wrap: StackWrap,
+                         pc: u32,
+                         cpu: &Cpu<M>,
+                         crash_log: Option<&CrashLog>) {
+        match self.policy {
+            StackPolicy::Ignore => return,
+            StackPolicy::LogOnce if self.logged => return,
+            StackPolicy::LogOnce => {}
+            StackPolicy::Break => self.pending_break = Some(pc),
+        }
+
+        self.logged = true;
+        warn!(target: target::CPU, "{}", report(wrap, pc, cpu, crash_log));
+        if self.policy == StackPolicy::LogOnce {
+            warn!(target: target::CPU,
+                  "(further stack overflows and underflows will not be logged)");
+        }
+    }
+
+    /// Returns (and forgets) the address of the instruction that caused a wrap, if emulation
+    /// should stop because of it.
+    pub fn take_break(&mut self) -> Option<u32> { self.pending_break.take() }
+}
+
+/// Describes a wrap, along with the call stack and the last instructions executed (if their
+/// recording is enabled).
+fn report<M: Mem>(wrap: StackWrap, pc: u32, cpu: &Cpu<M>, crash_log: Option<&CrashLog>)
+                  -> String {
+    let what = match wrap {
+        StackWrap::Overflow => "overflow",
+        StackWrap::Underflow => "underflow",
+    };
+    let mut s = format!("stack {} at ${:06X} (S is now ${:04X})", what, pc, cpu.s);
+
+    if let Some(call_stack) = cpu.call_stack() {
+        s.push_str("\ncall stack (innermost first):");
+        for frame in call_stack.frames().iter().rev() {
+            let kind = match frame.kind {
+                CallKind::Jsr => "jsr",
+                CallKind::Jsl => "jsl",
+                CallKind::Nmi => "nmi",
+                CallKind::Irq => "irq",
+            };
+            let _ = write!(s, "\n  {} ${:06X} from ${:06X} (S=${:04X})",
+                kind, frame.target, frame.caller, frame.sp);
+        }
+    }
+
+    if let Some(crash_log) = crash_log {
+        s.push_str("\nlast instructions:\n");
+        s.push_str(&recent_instructions(crash_log));
+    }
+
+    s
+}
+
+fn recent_instructions(crash_log: &CrashLog) -> String {
+    let mut buf = Vec::new();
+    let _ = crash_log.dump_instructions(&mut buf, REPORT_INSTRUCTIONS);
+    String::from_utf8_lossy(&buf).trim_right().to_string()
+}
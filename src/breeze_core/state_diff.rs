@@ -0,0 +1,270 @@
+//! Finding the source of desyncs
+//!
+//! When two emulators that should run in lockstep (netplay peers, a movie and its re-recording)
+//! diverge, the interesting question is *what* diverged first. A `StateSnapshot` captures all
+//! memory and registers of a `Snes`, and comparing two snapshots yields a `StateDiff` listing the
+//! differing registers and the differing byte ranges of each memory region.
+//!
+//! Peers that can't exchange full snapshots can exchange `StateHashes` instead (a few dozen
+//! bytes), which at least tells them which regions to look at.
+
+use hash::hash_bytes;
+use rom::Rom;
+use save::SaveStateFormat;
+use snes::Snes;
+
+use std::fmt;
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Differences at most this many bytes apart are reported as a single range.
+const MERGE_DISTANCE: usize = 8;
+
+/// Maximum number of ranges printed per region by the `Display` impl of `StateDiff`.
+const MAX_PRINTED_RANGES: usize = 16;
+
+/// A copy of the emulated state relevant for desync hunting.
+#[derive(Clone)]
+pub struct StateSnapshot {
+    /// `(name, contents)` of all memory regions
+    regions: Vec<(&'static str, Vec<u8>)>,
+    /// `(name, value)` of all registers, including the frame and cycle counters
+    registers: Vec<(String, u64)>,
+}
+
+impl StateSnapshot {
+    /// Captures the current state of `snes`.
+    pub fn capture(snes: &Snes) -> Self {
+        let p = snes.peripherals();
+        let regions = vec![
+            ("wram", p.wram.to_vec()),
+            ("vram", p.ppu.vram.to_vec()),
+            ("cgram", p.ppu.cgram.to_vec()),
+            ("oam", p.ppu.oam.to_vec()),
+            ("aram", p.apu.ram().to_vec()),
+            ("sram", p.rom.ram().to_vec()),
+        ];
+
+        let cpu = snes.cpu();
+        let mut registers: Vec<(String, u64)> = vec![
+            ("frame".to_string(), snes.frame_count()),
+            ("master_cy".to_string(), snes.master_cy()),
+            ("cpu.a".to_string(), cpu.a as u64),
+            ("cpu.x".to_string(), cpu.x as u64),
+            ("cpu.y".to_string(), cpu.y as u64),
+            ("cpu.s".to_string(), cpu.s as u64),
+            ("cpu.d".to_string(), cpu.d as u64),
+            ("cpu.dbr".to_string(), cpu.dbr as u64),
+            ("cpu.pbr".to_string(), cpu.pbr as u64),
+            ("cpu.pc".to_string(), cpu.pc as u64),
+            ("cpu.p".to_string(), cpu.status().0 as u64),
+            ("cpu.e".to_string(), cpu.emulation() as u64),
+        ];
+        registers.extend(p.apu.registers().iter()
+            .map(|&(name, value)| (format!("apu.{}", name), value as u64)));
+        registers.extend(p.ppu.registers().values().iter()
+            .map(|&(name, value)| (format!("ppu.{}", name), value as u64)));
+
+        StateSnapshot {
+            regions: regions,
+            registers: registers,
+        }
+    }
+
+    /// Restores a save state for `rom` into a fresh system and captures its state.
+    pub fn from_save_state(rom: Rom, format: SaveStateFormat, r: &mut BufRead)
+                           -> io::Result<Self> {
+        let mut snes = Snes::new(rom);
+        try!(snes.restore_save_state(format, r));
+        Ok(StateSnapshot::capture(&snes))
+    }
+
+    /// Returns the contents of the memory region called `name`.
+    pub fn region(&self, name: &str) -> Option<&[u8]> {
+        self.regions.iter().find(|&&(region, _)| region == name).map(|&(_, ref data)| &data[..])
+    }
+
+    /// Hashes every memory region and the registers.
+    pub fn hashes(&self) -> StateHashes {
+        let mut registers = Vec::new();
+        for &(ref name, value) in &self.registers {
+            registers.extend_from_slice(name.as_bytes());
+            registers.extend((0..8).map(|i| (value >> (i * 8)) as u8));
+        }
+
+        let mut hashes = vec![("registers".to_string(), hash_bytes(&registers))];
+        hashes.extend(self.regions.iter()
+            .map(|&(name, ref data)| (name.to_string(), hash_bytes(data))));
+        StateHashes(hashes)
+    }
+
+    /// Compares `self` (the "left" side) to `other` (the "right" side).
+    pub fn diff(&self, other: &StateSnapshot) -> StateDiff {
+        let registers = self.registers.iter().zip(&other.registers)
+            .filter(|&(&(_, left), &(_, right))| left != right)
+            .map(|(&(ref name, left), &(_, right))| RegisterDiff {
+                name: name.clone(),
+                left: left,
+                right: right,
+            })
+            .collect();
+
+        let regions = self.regions.iter().zip(&other.regions)
+            .filter_map(|(&(name, ref left), &(_, ref right))| diff_region(name, left, right))
+            .collect();
+
+        StateDiff {
+            registers: registers,
+            regions: regions,
+        }
+    }
+}
+
+/// Finds the differing byte ranges of a memory region. Bytes only present on one side (if the
+/// sizes differ) count as different.
+fn diff_region(name: &'static str, left: &[u8], right: &[u8]) -> Option<RegionDiff> {
+    let len = left.len().max(right.len());
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut bytes = 0;
+    for i in 0..len {
+        if left.get(i) == right.get(i) { continue }
+
+        bytes += 1;
+        if let Some(range) = ranges.last_mut() {
+            if i - range.end < MERGE_DISTANCE {
+                range.end = i + 1;
+                continue;
+            }
+        }
+        ranges.push(i..i + 1);
+    }
+
+    if ranges.is_empty() {
+        None
+    } else {
+        Some(RegionDiff {
+            name: name,
+            ranges: ranges,
+            bytes: bytes,
+        })
+    }
+}
+
+/// A register that differs between two snapshots
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterDiff {
+    /// Register name, prefixed with the chip (`cpu.pc`, `apu.a`, `ppu.bgmode`)
+    pub name: String,
+    pub left: u64,
+    pub right: u64,
+}
+
+/// The differences in a memory region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionDiff {
+    /// Region name (`wram`, `vram`, `cgram`, `oam`, `aram` or `sram`)
+    pub name: &'static str,
+    /// Differing offsets into the region. Nearby differences are merged, so ranges may contain
+    /// some equal bytes.
+    pub ranges: Vec<Range<usize>>,
+    /// Total number of differing bytes
+    pub bytes: usize,
+}
+
+/// All differences between two snapshots
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDiff {
+    pub registers: Vec<RegisterDiff>,
+    pub regions: Vec<RegionDiff>,
+}
+
+impl StateDiff {
+    /// Returns whether the snapshots are identical.
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.regions.is_empty()
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "states are identical");
+        }
+
+        if !self.registers.is_empty() {
+            try!(writeln!(f, "{} registers differ:", self.registers.len()));
+            for reg in &self.registers {
+                try!(writeln!(f, "  {:<16} ${:04X} != ${:04X}", reg.name, reg.left, reg.right));
+            }
+        }
+        for region in &self.regions {
+            try!(writeln!(f, "{}: {} bytes differ in {} ranges", region.name, region.bytes,
+                region.ranges.len()));
+            for range in region.ranges.iter().take(MAX_PRINTED_RANGES) {
+                try!(writeln!(f, "  ${:05X}-${:05X} ({} bytes)", range.start, range.end - 1,
+                    range.end - range.start));
+            }
+            if region.ranges.len() > MAX_PRINTED_RANGES {
+                try!(writeln!(f, "  ... and {} more", region.ranges.len() - MAX_PRINTED_RANGES));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes of the registers and each memory region of a snapshot.
+///
+/// The text representation (`name=hash` pairs separated by spaces, eg.
+/// `registers=1f2e... wram=...`) can be sent to a peer and parsed back with `FromStr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateHashes(Vec<(String, u64)>);
+
+impl StateHashes {
+    /// Returns `(name, hash)` pairs for the registers and every memory region.
+    pub fn values(&self) -> &[(String, u64)] { &self.0 }
+
+    /// Returns the names of the parts whose hashes differ between `self` and `other` (or that are
+    /// only present on one side).
+    pub fn mismatches(&self, other: &StateHashes) -> Vec<String> {
+        let mut names: Vec<String> = self.0.iter()
+            .filter(|&&(ref name, hash)| other.get(name) != Some(hash))
+            .map(|&(ref name, _)| name.clone())
+            .collect();
+        names.extend(other.0.iter()
+            .filter(|&&(ref name, _)| self.get(name).is_none())
+            .map(|&(ref name, _)| name.clone()));
+        names
+    }
+
+    fn get(&self, name: &str) -> Option<u64> {
+        self.0.iter().find(|&&(ref n, _)| n == name).map(|&(_, hash)| hash)
+    }
+}
+
+impl fmt::Display for StateHashes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(ref name, hash)) in self.0.iter().enumerate() {
+            if i > 0 { try!(write!(f, " ")) }
+            try!(write!(f, "{}={:016x}", name, hash));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for StateHashes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.split_whitespace().map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(hash)) => match u64::from_str_radix(hash, 16) {
+                    Ok(hash) => Ok((name.to_string(), hash)),
+                    Err(_) => Err(format!("invalid hash: {}", hash)),
+                },
+                _ => Err(format!("expected `name=hash`, got `{}`", pair)),
+            }
+        }).collect::<Result<_, _>>().map(StateHashes)
+    }
+}
@@ -0,0 +1,68 @@
+//! Emulation statistics
+//!
+//! Frontends can poll these (via `Snes::stats`) to display performance overlays.
+
+use std::ops::Sub;
+use std::time::{Duration, Instant};
+
+/// Cycle counters of the different components.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Master clock cycles emulated
+    pub master_cy: u64,
+    /// CPU clock cycles spent executing instructions (without wait states)
+    pub cpu_cy: u64,
+    /// SPC700 clock cycles
+    pub apu_cy: u64,
+    /// Master clock cycles the PPU was run for
+    pub ppu_cy: u64,
+    /// Master clock cycles the CPU was halted for DMA and HDMA transfers
+    pub dma_cy: u64,
+}
+
+impl Sub for Counters {
+    type Output = Counters;
+
+    fn sub(self, rhs: Counters) -> Counters {
+        Counters {
+            master_cy: self.master_cy - rhs.master_cy,
+            cpu_cy: self.cpu_cy - rhs.cpu_cy,
+            apu_cy: self.apu_cy - rhs.apu_cy,
+            ppu_cy: self.ppu_cy - rhs.ppu_cy,
+            dma_cy: self.dma_cy - rhs.dma_cy,
+        }
+    }
+}
+
+/// Statistics collected while emulating.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Counters since the statistics were last reset
+    pub total: Counters,
+    /// Number of frames rendered
+    pub frames: u64,
+    /// Counters of the last completed frame
+    pub last_frame: Counters,
+    /// Wall-clock time it took to emulate the last frame. `None` until a full frame was emulated.
+    pub last_frame_time: Option<Duration>,
+
+    frame_start_counters: Counters,
+    frame_start: Option<Instant>,
+}
+
+impl Stats {
+    /// Called when emulation of a frame starts.
+    pub fn start_frame(&mut self) {
+        if self.frame_start.is_none() {
+            self.frame_start = Some(Instant::now());
+            self.frame_start_counters = self.total;
+        }
+    }
+
+    /// Called when a frame was rendered. Computes the per-frame statistics.
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        self.last_frame = self.total - self.frame_start_counters;
+        self.last_frame_time = self.frame_start.take().map(|start| start.elapsed());
+    }
+}
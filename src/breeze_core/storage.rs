@@ -0,0 +1,163 @@
+//! Per-game files
+//!
+//! Everything the emulator stores for a game (battery-backed SRAM, save states, cheats, per-game
+//! settings, macros and screenshots) goes into a directory of its own below a common base
+//! directory. The directory is named after the ROM's title and hash, so games with the same title
+//! (or a renamed ROM file) still get the right files:
+//!
+//! ```text
+//! <base>/
+//!     SUPER MARIOWORLD-0123456789abcdef/
+//!         game.srm
+//!         config.toml
+//!         cheats.txt
+//!         states/1.sav
+//!         states/exit.sav
+//!         macros/1.txt
+//!         screenshots/0001.ppm (or .png)
+//! ```
+//!
+//! Frontends should use the paths returned by `GameStorage` instead of coming up with their own
+//! layout.
+
+use rom::Rom;
+use screenshot;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the first file in `dir` named `<prefix><number>.<extension>` that doesn't
+/// exist yet, counting from `0001`.
+pub fn first_unused_path(dir: &Path, prefix: &str, extension: &str) -> PathBuf {
+    (1..).map(|n| dir.join(format!("{}{:04}.{}", prefix, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Locates the files stored for a game.
+#[derive(Clone, Debug)]
+pub struct GameStorage {
+    dir: PathBuf,
+}
+
+impl GameStorage {
+    /// Creates the storage for `rom` below the base directory `base`. Nothing is created on disk
+    /// until a file is written.
+    pub fn new<P: Into<PathBuf>>(base: P, rom: &Rom) -> Self {
+        let title: String = rom.get_title().unwrap_or("").chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == ' ' || c == '-' { c } else { '_' })
+            .collect();
+        let name = match title.trim() {
+            "" => format!("{:016x}", rom.hash()),
+            title => format!("{}-{:016x}", title, rom.hash()),
+        };
+
+        GameStorage {
+            dir: base.into().join(name),
+        }
+    }
+
+    /// Returns the default base directory: `$BREEZE_DATA_DIR` if set, otherwise the platform's
+    /// data directory (`$XDG_DATA_HOME/breeze`, `~/.local/share/breeze` or `%APPDATA%\breeze`),
+    /// falling back to `breeze-data` in the working directory.
+    pub fn default_base_dir() -> PathBuf {
+        if let Some(dir) = env::var_os("BREEZE_DATA_DIR") {
+            return dir.into();
+        }
+        if let Some(dir) = env::var_os("XDG_DATA_HOME") {
+            return Path::new(&dir).join("breeze");
+        }
+        if cfg!(windows) {
+            if let Some(dir) = env::var_os("APPDATA") {
+                return Path::new(&dir).join("breeze");
+            }
+        }
+        if let Some(dir) = env::var_os("HOME") {
+            return Path::new(&dir).join(".local").join("share").join("breeze");
+        }
+        PathBuf::from("breeze-data")
+    }
+
+    /// Returns the game's directory.
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Path of the battery-backed cartridge RAM
+    pub fn sram_path(&self) -> PathBuf { self.dir.join("game.srm") }
+
+    /// Path of the per-game configuration, which overrides the global one
+    pub fn config_path(&self) -> PathBuf { self.dir.join("config.toml") }
+
+    /// Path of the game's cheat list
+    pub fn cheats_path(&self) -> PathBuf { self.dir.join("cheats.txt") }
+
+    /// Path of the save state in a slot
+    pub fn save_state_path(&self, slot: u8) -> PathBuf {
+        self.dir.join("states").join(format!("{}.sav", slot))
+    }
+
+    /// Path of the state saved when the emulator exits, to continue from there next time
+    pub fn exit_state_path(&self) -> PathBuf {
+        self.dir.join("states").join("exit.sav")
+    }
+
+    /// Path of the input macro in a slot (`0-8`, the file names count from 1 like the hotkeys)
+    pub fn macro_path(&self, slot: u8) -> PathBuf {
+        self.dir.join("macros").join(format!("{}.txt", slot as u32 + 1))
+    }
+
+    /// Returns the path of a new screenshot (the first unused number).
+    pub fn next_screenshot_path(&self) -> PathBuf {
+        first_unused_path(&self.dir.join("screenshots"), "", screenshot::EXTENSION)
+    }
+
+    /// Returns the path of a new crash report (the first unused number). The crash's save state
+    /// is stored next to it (see `crash_report::save_report`).
+    pub fn next_crash_report_path(&self) -> PathBuf {
+        first_unused_path(&self.dir.join("crashes"), "", "txt")
+    }
+
+    /// Creates a file (and the directories containing it) for writing.
+    pub fn create_file(&self, path: &Path) -> io::Result<File> {
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        File::create(path)
+    }
+
+    /// Restores the cartridge RAM of `rom` from the SRAM file. Returns `false` if there is no such
+    /// file (or the cartridge has no RAM).
+    pub fn load_sram(&self, rom: &mut Rom) -> io::Result<bool> {
+        if rom.ram().is_empty() {
+            return Ok(false);
+        }
+
+        let mut data = Vec::new();
+        match File::open(self.sram_path()) {
+            Ok(mut file) => try!(file.read_to_end(&mut data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let ram = rom.ram_mut();
+        if data.len() != ram.len() {
+            warn!("SRAM file is {} bytes, but the cartridge has {} bytes of RAM", data.len(),
+                  ram.len());
+        }
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        Ok(true)
+    }
+
+    /// Writes the cartridge RAM of `rom` to the SRAM file. Does nothing if the cartridge has no
+    /// RAM.
+    pub fn save_sram(&self, rom: &Rom) -> io::Result<()> {
+        if rom.ram().is_empty() {
+            return Ok(());
+        }
+
+        let mut file = try!(self.create_file(&self.sram_path()));
+        file.write_all(rom.ram())
+    }
+}
@@ -0,0 +1,156 @@
+//! Symbol files
+//!
+//! Loads the labels of a program from the symbol files written by common SNES assemblers, so that
+//! traces, the disassembler and the debugger can show (and accept) `NmiHandler+3` instead of
+//! `$00:8123`. Supported formats (detected per line):
+//!
+//! * WLA-DX `.sym` files: `00:8000 Reset` in the `[labels]` section (other sections are ignored)
+//! * ca65/ld65 VICE label files (`ld65 -Ln labels.txt`): `al 008000 .Reset`
+//!
+//! Lines starting with `;` or `#` are comments.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Labels further away than this from an address aren't used to describe it
+const MAX_OFFSET: u32 = 0x1000;
+
+/// Maps 24-bit addresses to labels and back.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// Address -> labels defined at that address (in the order they were loaded)
+    by_addr: BTreeMap<u32, Vec<String>>,
+    by_name: HashMap<String, u32>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self { Self::default() }
+
+    /// Loads a symbol file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = try!(File::open(path));
+        let mut symbols = SymbolTable::new();
+        try!(symbols.read(BufReader::new(file)));
+        Ok(symbols)
+    }
+
+    /// Reads symbols from a WLA-DX or VICE label file, adding them to the table. Lines that
+    /// can't be parsed are skipped.
+    pub fn read<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        // WLA-DX files are split into sections, only `[labels]` contains labels. If there's no
+        // section header at all, assume the whole file contains labels.
+        let mut in_labels = true;
+        for line in reader.lines() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') { continue }
+
+            if line.starts_with('[') {
+                in_labels = line == "[labels]";
+                continue;
+            }
+            if !in_labels { continue }
+
+            match parse_line(line) {
+                Some((addr, name)) => self.add(addr, name),
+                None => debug!("skipping symbol file line: {}", line),
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a label.
+    pub fn add(&mut self, addr: u32, name: String) {
+        self.by_name.insert(name.clone(), addr);
+        self.by_addr.entry(addr).or_insert_with(Vec::new).push(name);
+    }
+
+    /// Returns the number of labels.
+    pub fn len(&self) -> usize { self.by_name.len() }
+
+    pub fn is_empty(&self) -> bool { self.by_name.is_empty() }
+
+    /// Returns the first label defined at exactly `addr`.
+    pub fn label(&self, addr: u32) -> Option<&str> {
+        self.by_addr.get(&addr).and_then(|names| names.first()).map(|name| &**name)
+    }
+
+    /// Describes an address relative to the closest label at or before it (`NmiHandler+3`). Only
+    /// labels in the same bank and at most `$1000` bytes away are considered.
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        let (&label_addr, names) = match self.by_addr.range(..addr + 1).next_back() {
+            Some(entry) => entry,
+            None => return None,
+        };
+        if label_addr >> 16 != addr >> 16 || addr - label_addr > MAX_OFFSET {
+            return None;
+        }
+
+        Some(match addr - label_addr {
+            0 => names[0].clone(),
+            offset => format!("{}+{}", names[0], offset),
+        })
+    }
+
+    /// Resolves `Label` or `Label+N`/`Label-N` (`N` is decimal, or hexadecimal with a `$` prefix)
+    /// to an address.
+    pub fn resolve(&self, s: &str) -> Option<u32> {
+        let (name, offset) = match s.rfind(|c| c == '+' || c == '-') {
+            Some(i) if i > 0 => {
+                let offset = &s[i + 1..];
+                let offset = if offset.starts_with('$') {
+                    i64::from_str_radix(&offset[1..], 16)
+                } else {
+                    offset.parse()
+                };
+                match offset {
+                    Ok(offset) if &s[i..i + 1] == "-" => (&s[..i], -offset),
+                    Ok(offset) => (&s[..i], offset),
+                    Err(_) => (s, 0),
+                }
+            }
+            _ => (s, 0),
+        };
+
+        self.by_name.get(name.trim()).and_then(|&addr| {
+            let addr = addr as i64 + offset;
+            if addr >= 0 && addr <= 0xffffff { Some(addr as u32) } else { None }
+        })
+    }
+}
+
+/// Parses a WLA-DX (`BB:AAAA name`) or VICE (`al BBAAAA .name`) label line.
+fn parse_line(line: &str) -> Option<(u32, String)> {
+    let mut tokens = line.split_whitespace();
+    let first = match tokens.next() {
+        Some(token) => token,
+        None => return None,
+    };
+
+    let (addr, name) = if first == "al" {
+        let addr = tokens.next().and_then(|addr| u32::from_str_radix(addr, 16).ok());
+        // ca65 prefixes names with a dot
+        (addr, tokens.next().map(|name| name.trim_left_matches('.')))
+    } else {
+        let mut split = first.splitn(2, ':');
+        let addr = match (split.next(), split.next()) {
+            (Some(bank), Some(addr)) => {
+                match (u8::from_str_radix(bank, 16), u16::from_str_radix(addr, 16)) {
+                    (Ok(bank), Ok(addr)) => Some((bank as u32) << 16 | addr as u32),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        (addr, tokens.next())
+    };
+
+    match (addr, name) {
+        (Some(addr), Some(name)) if addr <= 0xffffff && !name.is_empty() => {
+            Some((addr, name.to_string()))
+        }
+        _ => None,
+    }
+}
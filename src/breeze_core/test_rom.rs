@@ -0,0 +1,332 @@
+//! Test ROM harness
+//!
+//! Runs test ROMs (eg. PeterLemon's CPU and PPU tests, or SPC700 test suites) headless for a fixed
+//! number of frames and checks their results. The tests to run are listed in a manifest file with
+//! one test per line:
+//!
+//! ```text
+//! # Comments start with `#`. Paths are relative to the manifest.
+//! cpu/CPUADC.sfc      frames=120  hash=4f1a0c3e9b2d7785
+//! cpu/CPUAND.sfc      frames=120  mem=7E0010:01,FF
+//! spc/spc_test.sfc    frames=600  apu=00F0:00
+//! ```
+//!
+//! * `hash=HASH`: After running all frames, the hash of the last frame (see `Snes::frame_hash`)
+//!   must be `HASH`. If a test doesn't match, the report contains the actual hash, so new tests
+//!   can be added by running them once with a dummy hash and checking the output manually.
+//! * `mem=BBAAAA:PASS[,FAIL]`: The test writes its result to memory. It passes as soon as the byte
+//!   at `$BBAAAA` is `PASS`, and fails as soon as it is `FAIL` (or when all frames were run).
+//! * `apu=AAAA:PASS[,FAIL]`: Like `mem`, but checks APU RAM.
+//!
+//! `frames` defaults to 60.
+
+use rom::Rom;
+use snes::Snes;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// Number of frames a test runs if the manifest doesn't say otherwise
+const DEFAULT_FRAMES: u32 = 60;
+
+/// How the result of a test is determined
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// Compare the hash of the last frame
+    FrameHash(u64),
+    /// Wait for a byte in the CPU's address space to become a pass or fail value
+    Memory { addr: u32, pass: u8, fail: Option<u8> },
+    /// Wait for a byte in APU RAM to become a pass or fail value
+    ApuMemory { addr: u16, pass: u8, fail: Option<u8> },
+}
+
+/// A test ROM and how to check its result
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestRom {
+    pub path: PathBuf,
+    /// Maximum number of frames to run
+    pub frames: u32,
+    pub check: Check,
+}
+
+/// The result of a test
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The test ran, but the check failed (contains a description)
+    Fail(String),
+    /// The test couldn't be run or crashed the emulator
+    Error(String),
+}
+
+impl TestRom {
+    /// Loads and runs the test, returning its outcome.
+    pub fn run(&self) -> Outcome {
+        let rom = match load_rom(&self.path) {
+            Ok(rom) => rom,
+            Err(e) => return Outcome::Error(format!("couldn't load ROM: {}", e)),
+        };
+
+        // Test ROMs are likely to hit unimplemented features, which might panic. That shouldn't
+        // take down the whole suite.
+        catch_panics(|| self.run_rom(rom))
+    }
+
+    fn run_rom(&self, rom: Rom) -> Outcome {
+        let mut snes = Snes::new(rom);
+        // Panics are reported as errors, dumping the crash log for every failing ROM would bury
+        // the report
+        snes.set_crash_log(None);
+        for _ in 0..self.frames {
+            if let Err(e) = snes.advance_frame() {
+                return Outcome::Error(format!("emulation failed: {}", e));
+            }
+
+            if let Some(outcome) = self.check_memory(&mut snes) {
+                return outcome;
+            }
+        }
+
+        match self.check {
+            Check::FrameHash(expected) => {
+                let hash = snes.frame_hash();
+                if hash == expected {
+                    Outcome::Pass
+                } else {
+                    Outcome::Fail(format!("expected frame hash {:016x}, got {:016x}", expected,
+                        hash))
+                }
+            }
+            Check::Memory { addr, .. } => Outcome::Fail(format!(
+                "no result after {} frames (${:06X} = {})", self.frames, addr,
+                format_byte(snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16)))),
+            Check::ApuMemory { addr, .. } => Outcome::Fail(format!(
+                "no result after {} frames (APU ${:04X} = ${:02X})", self.frames, addr,
+                snes.peripherals().apu.ram()[addr as usize])),
+        }
+    }
+
+    /// Checks a memory-mapped result. Returns `None` if the test hasn't finished yet.
+    fn check_memory(&self, snes: &mut Snes) -> Option<Outcome> {
+        let (value, pass, fail) = match self.check {
+            Check::FrameHash(_) => return None,
+            Check::Memory { addr, pass, fail } => {
+                (snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16), pass, fail)
+            }
+            Check::ApuMemory { addr, pass, fail } => {
+                (Some(snes.peripherals().apu.ram()[addr as usize]), pass, fail)
+            }
+        };
+
+        match value {
+            Some(value) if value == pass => Some(Outcome::Pass),
+            Some(value) if Some(value) == fail => {
+                Some(Outcome::Fail(format!("test reported failure (${:02X})", value)))
+            }
+            Some(_) => None,
+            None => Some(Outcome::Error("result address isn't mapped to memory".to_string())),
+        }
+    }
+
+    /// Parses a manifest line. Relative ROM paths are resolved relative to `base`.
+    pub fn parse(line: &str, base: &Path) -> Result<Self, String> {
+        let mut tokens = line.split_whitespace();
+        let path = match tokens.next() {
+            Some(path) => base.join(path),
+            None => return Err("empty test line".to_string()),
+        };
+
+        let mut frames = DEFAULT_FRAMES;
+        let mut check = None;
+        for token in tokens {
+            let mut split = token.splitn(2, '=');
+            let (name, value) = match (split.next(), split.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(format!("expected `name=value`, found `{}`", token)),
+            };
+            match name {
+                "frames" => {
+                    frames = try!(value.parse()
+                        .map_err(|_| format!("invalid frame count: {}", value)));
+                }
+                "hash" => {
+                    check = Some(Check::FrameHash(try!(u64::from_str_radix(value, 16)
+                        .map_err(|_| format!("invalid hash: {}", value)))));
+                }
+                "mem" => {
+                    let (addr, pass, fail) = try!(parse_result_location(value, 0xffffff));
+                    check = Some(Check::Memory { addr: addr, pass: pass, fail: fail });
+                }
+                "apu" => {
+                    let (addr, pass, fail) = try!(parse_result_location(value, 0xffff));
+                    check = Some(Check::ApuMemory { addr: addr as u16, pass: pass, fail: fail });
+                }
+                _ => return Err(format!("unknown test option: {}", name)),
+            }
+        }
+
+        match check {
+            Some(check) => Ok(TestRom {
+                path: path,
+                frames: frames,
+                check: check,
+            }),
+            None => Err(format!("no check given for {} (use `hash`, `mem` or `apu`)",
+                path.display())),
+        }
+    }
+}
+
+/// Parses `ADDR:PASS[,FAIL]` (all hexadecimal).
+fn parse_result_location(s: &str, max_addr: u32) -> Result<(u32, u8, Option<u8>), String> {
+    fn hex(s: &str, max: u32) -> Result<u32, String> {
+        match u32::from_str_radix(s.trim_left_matches('$'), 16) {
+            Ok(n) if n <= max => Ok(n),
+            _ => Err(format!("invalid hexadecimal value: {}", s)),
+        }
+    }
+
+    let mut split = s.splitn(2, ':');
+    let (addr, values) = match (split.next(), split.next()) {
+        (Some(addr), Some(values)) => (try!(hex(addr, max_addr)), values),
+        _ => return Err(format!("expected `ADDR:PASS[,FAIL]`, found `{}`", s)),
+    };
+    let mut split = values.splitn(2, ',');
+    let pass = try!(hex(split.next().unwrap_or(""), 0xff)) as u8;
+    let fail = match split.next() {
+        Some(fail) => Some(try!(hex(fail, 0xff)) as u8),
+        None => None,
+    };
+    Ok((addr, pass, fail))
+}
+
+/// Runs `f`, turning a panic into `Outcome::Error`.
+pub fn catch_panics<F>(f: F) -> Outcome where F: FnOnce() -> Outcome {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(outcome) => outcome,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => msg.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.clone(),
+                    None => "unknown panic".to_string(),
+                },
+            };
+            Outcome::Error(format!("emulator panicked: {}", msg))
+        }
+    }
+}
+
+fn format_byte(value: Option<u8>) -> String {
+    match value {
+        Some(value) => format!("${:02X}", value),
+        None => "unmapped".to_string(),
+    }
+}
+
+/// Loads a ROM file.
+pub fn load_rom(path: &Path) -> Result<Rom, Box<Error>> {
+    Ok(try!(Rom::load_file(path)))
+}
+
+/// A list of test ROMs
+#[derive(Clone, Debug, Default)]
+pub struct TestSuite {
+    pub tests: Vec<TestRom>,
+}
+
+impl TestSuite {
+    /// Reads a manifest file (see the module documentation for the format).
+    pub fn load<P: AsRef<Path>>(manifest: P) -> Result<Self, Box<Error>> {
+        let manifest = manifest.as_ref();
+        let base = manifest.parent().unwrap_or(Path::new(""));
+        let reader = BufReader::new(try!(File::open(manifest)));
+
+        let mut tests = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => &line,
+            };
+            if line.trim().is_empty() { continue }
+
+            tests.push(try!(TestRom::parse(line, base)
+                .map_err(|e| format!("{}:{}: {}", manifest.display(), i + 1, e))));
+        }
+        Ok(TestSuite { tests: tests })
+    }
+
+    /// Runs all tests, calling `progress` after each one.
+    pub fn run<F>(&self, mut progress: F) -> Report
+    where F: FnMut(&TestResult) {
+        let mut results = Vec::with_capacity(self.tests.len());
+        for test in &self.tests {
+            let result = TestResult {
+                path: test.path.clone(),
+                outcome: test.run(),
+            };
+            progress(&result);
+            results.push(result);
+        }
+        Report { results: results }
+    }
+}
+
+/// The outcome of a single test ROM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.outcome {
+            Outcome::Pass => write!(f, "{} ... ok", self.path.display()),
+            Outcome::Fail(ref msg) => write!(f, "{} ... FAILED: {}", self.path.display(), msg),
+            Outcome::Error(ref msg) => write!(f, "{} ... ERROR: {}", self.path.display(), msg),
+        }
+    }
+}
+
+/// Results of a test suite run
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub results: Vec<TestResult>,
+}
+
+impl Report {
+    /// Returns the number of tests that passed.
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.outcome == Outcome::Pass).count()
+    }
+
+    /// Returns the number of tests that didn't pass (including errors).
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// Returns whether all tests passed.
+    pub fn success(&self) -> bool { self.failed() == 0 }
+
+    /// Returns a one-line summary of the results.
+    pub fn summary(&self) -> String {
+        format!("test result: {}. {} passed; {} failed",
+            if self.success() { "ok" } else { "FAILED" }, self.passed(), self.failed())
+    }
+}
+
+impl fmt::Display for Report {
+    /// Lists all tests, followed by a summary line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for result in &self.results {
+            try!(writeln!(f, "{}", result));
+        }
+        write!(f, "{}", self.summary())
+    }
+}
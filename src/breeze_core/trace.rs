@@ -0,0 +1,219 @@
+//! Filtered instruction trace
+//!
+//! The CPU cores can log every instruction via `trace!`, which floods the log and can't be
+//! narrowed down. A `Tracer` writes executed CPU instructions to a file instead, and only those
+//! matching a `TraceFilter`. Trace files can be rotated to keep them at a manageable size. All of
+//! this can be changed while the emulator is running (see `Snes::tracer_mut`).
+//!
+//! If a symbol table is set, every line ends with the location of the instruction relative to the
+//! closest label (eg. `; NmiHandler+3`).
+
+use snes::Peripherals;
+use symbols::SymbolTable;
+
+use wdc65816::Cpu;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Decides which instructions end up in the trace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only trace instructions located in these inclusive `$BBAAAA` ranges (everywhere if empty)
+    pub ranges: Vec<(u32, u32)>,
+    /// Only trace once this many frames have been emulated
+    pub start_frame: u64,
+    /// Only trace these opcodes (all if empty)
+    pub opcodes: Vec<u8>,
+}
+
+impl TraceFilter {
+    /// Returns whether the instruction with the given opcode at the given address should be traced
+    /// in the given frame.
+    pub fn matches(&self, addr: u32, opcode: u8, frame: u64) -> bool {
+        frame >= self.start_frame &&
+        (self.ranges.is_empty() || self.ranges.iter().any(|&(a, b)| addr >= a && addr <= b)) &&
+        (self.opcodes.is_empty() || self.opcodes.contains(&opcode))
+    }
+
+    /// Returns whether the trace needs to know the opcode to decide if an instruction matches.
+    fn needs_opcode(&self) -> bool { !self.opcodes.is_empty() }
+
+    /// Sets a filter option by name. `range` (`BBAAAA-BBAAAA`, hexadecimal) and `opcode` (a
+    /// hexadecimal byte) can be given multiple times and add to the existing list. `start_frame`
+    /// takes a frame number.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn parse_hex(value: &str, max: u32) -> Result<u32, String> {
+            let hex = value.trim_left_matches('$');
+            match u32::from_str_radix(hex, 16) {
+                Ok(n) if n <= max => Ok(n),
+                _ => Err(format!("invalid hexadecimal value: {}", value)),
+            }
+        }
+
+        match name {
+            "range" => {
+                let mut split = value.splitn(2, '-');
+                let (start, end) = match (split.next(), split.next()) {
+                    (Some(start), Some(end)) => {
+                        (try!(parse_hex(start, 0xffffff)), try!(parse_hex(end, 0xffffff)))
+                    }
+                    _ => return Err(format!("invalid range (expected `START-END`): {}", value)),
+                };
+                if start > end {
+                    return Err(format!("range start is after its end: {}", value));
+                }
+                self.ranges.push((start, end));
+            }
+            "start_frame" => {
+                self.start_frame = try!(value.parse()
+                    .map_err(|_| format!("invalid frame number: {}", value)));
+            }
+            "opcode" => self.opcodes.push(try!(parse_hex(value, 0xff)) as u8),
+            _ => return Err(format!("unknown trace filter option: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Parses and applies an option of the form `name=value`.
+    pub fn apply_option(&mut self, opt: &str) -> Result<(), String> {
+        let mut split = opt.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(name), Some(value)) => self.set_option(name.trim(), value.trim()),
+            _ => Err(format!("invalid trace filter (expected `name=value`): {}", opt)),
+        }
+    }
+}
+
+/// Writes executed CPU instructions to a file.
+pub struct Tracer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    filter: TraceFilter,
+    enabled: bool,
+    /// Start a new file once the current one is this large (in bytes). 0 disables rotation.
+    max_size: u64,
+    /// Number of rotated files to keep (`trace.log.1`, `trace.log.2`, ...)
+    max_files: u32,
+    /// Bytes written to the current file
+    written: u64,
+    symbols: SymbolTable,
+}
+
+impl Tracer {
+    /// Creates (or truncates) the trace file at `path`. The tracer starts out enabled, with an
+    /// empty filter and without rotation.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Tracer> {
+        let path = path.as_ref().to_path_buf();
+        let file = BufWriter::new(try!(File::create(&path)));
+        Ok(Tracer {
+            path: path,
+            file: file,
+            filter: TraceFilter::default(),
+            enabled: true,
+            max_size: 0,
+            max_files: 0,
+            written: 0,
+            symbols: SymbolTable::new(),
+        })
+    }
+
+    pub fn filter(&self) -> &TraceFilter { &self.filter }
+
+    /// Replaces the filter. Takes effect with the next instruction.
+    pub fn set_filter(&mut self, filter: TraceFilter) {
+        self.filter = filter;
+    }
+
+    /// Sets the labels used to annotate trace lines.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn is_enabled(&self) -> bool { self.enabled }
+
+    /// Pauses or resumes tracing without closing the file.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Enables rotation: Once the file reaches `max_size` bytes, it is renamed to `<path>.1`
+    /// (shifting older files up to `<path>.<max_files>`) and a new file is started. With
+    /// `max_files == 0`, the file is simply truncated. A `max_size` of 0 disables rotation.
+    pub fn set_rotation(&mut self, max_size: u64, max_files: u32) {
+        self.max_size = max_size;
+        self.max_files = max_files;
+    }
+
+    /// Writes buffered trace lines to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Traces the instruction the CPU is about to execute in the given frame, if it passes the
+    /// filter.
+    pub fn trace(&mut self, cpu: &mut Cpu<Peripherals>, frame: u64) -> io::Result<()> {
+        if !self.enabled || cpu.waiting() { return Ok(()) }
+
+        let (pbr, pc) = (cpu.pbr, cpu.pc);
+        let addr = (pbr as u32) << 16 | pc as u32;
+        if !self.filter.needs_opcode() && !self.filter.matches(addr, 0, frame) {
+            return Ok(());
+        }
+
+        let (small_acc, small_index) = (cpu.status().small_acc(), cpu.status().small_index());
+        let instr = cpu.mem.disassemble(pbr, pc, small_acc, small_index);
+        if !self.filter.matches(addr, instr.bytes()[0], frame) { return Ok(()) }
+
+        let mut line = format!("${:02X}:{:04X} {:02X}  {:14} a:{:04X} x:{:04X} y:{:04X} s:{:04X} \
+                                d:{:04X} dbr:{:02X} emu:{} {} V:{:3} H:{:3}",
+            pbr, pc, instr.bytes()[0], instr.to_string(), cpu.a, cpu.x, cpu.y, cpu.s, cpu.d,
+            cpu.dbr, cpu.emulation() as u8, cpu.status(), cpu.mem.ppu.v_counter(),
+            cpu.mem.ppu.h_counter());
+        if let Some(label) = self.symbols.describe(addr) {
+            line.push_str(" ; ");
+            line.push_str(&label);
+        }
+        line.push('\n');
+        try!(self.file.write_all(line.as_bytes()));
+        self.written += line.len() as u64;
+
+        if self.max_size != 0 && self.written >= self.max_size {
+            try!(self.rotate());
+        }
+        Ok(())
+    }
+
+    /// Moves the current file out of the way and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        try!(self.file.flush());
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    try!(fs::rename(from, self.rotated_path(i + 1)));
+                }
+            }
+            try!(fs::rename(&self.path, self.rotated_path(1)));
+        }
+
+        self.file = BufWriter::new(try!(File::create(&self.path)));
+        self.written = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.flush() {
+            error!("couldn't write trace to '{}': {}", self.path.display(), e);
+        }
+    }
+}
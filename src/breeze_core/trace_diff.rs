@@ -0,0 +1,242 @@
+//! Comparing execution against a reference trace
+//!
+//! A `TraceDiff` reads a CPU trace produced by another emulator (in bsnes' format) and compares it
+//! to the state of our CPU before every instruction. Emulation is halted at the first divergence,
+//! and the last few instructions of both traces are printed. This makes finding CPU bugs a lot
+//! easier than comparing traces by hand.
+//!
+//! bsnes trace lines look like this (only the address and the register values are compared, the
+//! disassembly and the timing information are ignored):
+//!
+//! ```text
+//! 008000 sei                     A:0000 X:0000 Y:0000 S:01ff D:0000 DB:00 nvMXdIzc V:  0 H:  0
+//! ```
+
+use snes::Peripherals;
+
+use wdc65816::{Cpu, Mem};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Number of instructions preceding a divergence that are printed
+const CONTEXT_LINES: usize = 8;
+
+/// CPU state before executing an instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceState {
+    /// Address of the instruction (`$BBAAAA`)
+    pub pc: u32,
+    pub a: u16,
+    pub x: u16,
+    pub y: u16,
+    pub s: u16,
+    pub d: u16,
+    pub dbr: u8,
+    /// Processor status register
+    pub p: u8,
+}
+
+impl TraceState {
+    /// Captures the state of a CPU.
+    pub fn from_cpu<M: Mem>(cpu: &Cpu<M>) -> Self {
+        TraceState {
+            pc: (cpu.pbr as u32) << 16 | cpu.pc as u32,
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            s: cpu.s,
+            d: cpu.d,
+            dbr: cpu.dbr,
+            p: cpu.status().0,
+        }
+    }
+
+    /// Parses a line of a bsnes CPU trace.
+    pub fn parse_bsnes(line: &str) -> Result<Self, String> {
+        fn hex(line: &str, value: Option<&str>, max: u32) -> Result<u32, String> {
+            match value.map(|value| u32::from_str_radix(value, 16)) {
+                Some(Ok(n)) if n <= max => Ok(n),
+                _ => Err(format!("malformed trace line: {}", line.trim_right())),
+            }
+        }
+
+        // Some versions separate the bank from the address (`00:8000`)
+        let mut pc = None;
+        let (mut a, mut x, mut y, mut s, mut d, mut dbr, mut p) =
+            (None, None, None, None, None, None, None);
+        for (i, token) in line.split_whitespace().enumerate() {
+            if i == 0 {
+                pc = Some(token.replace(':', ""));
+                continue;
+            }
+            let mut split = token.splitn(2, ':');
+            match (split.next(), split.next()) {
+                (Some("A"), value) => a = value,
+                (Some("X"), value) => x = value,
+                (Some("Y"), value) => y = value,
+                (Some("S"), value) => s = value,
+                (Some("D"), value) => d = value,
+                (Some("DB"), value) => dbr = value,
+                (Some(flags), None) if p.is_none() && is_flags(flags) => p = Some(flags),
+                _ => {}
+            }
+        }
+
+        let p = match p {
+            Some(flags) => flags.chars().take(8).fold(0, |p, c| p << 1 | c.is_uppercase() as u8),
+            None => return Err(format!("malformed trace line: {}", line.trim_right())),
+        };
+        Ok(TraceState {
+            pc: try!(hex(line, pc.as_ref().map(|pc| &**pc), 0xffffff)),
+            a: try!(hex(line, a, 0xffff)) as u16,
+            x: try!(hex(line, x, 0xffff)) as u16,
+            y: try!(hex(line, y, 0xffff)) as u16,
+            s: try!(hex(line, s, 0xffff)) as u16,
+            d: try!(hex(line, d, 0xffff)) as u16,
+            dbr: try!(hex(line, dbr, 0xff)) as u8,
+            p: p,
+        })
+    }
+}
+
+/// Returns whether `s` looks like bsnes' flag display (`nvmxdizc`, uppercase means set).
+fn is_flags(s: &str) -> bool {
+    s.len() >= 8 &&
+    s.chars().take(8).zip("nvmxdizc".chars()).all(|(c, flag)| c.to_ascii_lowercase() == flag)
+}
+
+impl fmt::Display for TraceState {
+    /// Formats the state like bsnes does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:06x} A:{:04x} X:{:04x} Y:{:04x} S:{:04x} D:{:04x} DB:{:02x} ",
+            self.pc, self.a, self.x, self.y, self.s, self.d, self.dbr));
+        for (i, c) in "nvmxdizc".chars().enumerate() {
+            let set = self.p & (0x80 >> i) != 0;
+            try!(write!(f, "{}", if set { c.to_ascii_uppercase() } else { c }));
+        }
+        Ok(())
+    }
+}
+
+/// The point where execution diverged from the reference trace
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Line number in the reference trace (starting at 1)
+    pub line: u64,
+    pub expected: TraceState,
+    pub actual: TraceState,
+    /// The last few lines of the reference trace, including the diverging one
+    pub reference_context: Vec<String>,
+    /// The last few instructions we executed, including the diverging one
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "execution diverged from the reference trace at line {}", self.line));
+        try!(writeln!(f, "reference:"));
+        for line in &self.reference_context {
+            try!(writeln!(f, "  {}", line));
+        }
+        try!(writeln!(f, "breeze:"));
+        for line in &self.context {
+            try!(writeln!(f, "  {}", line));
+        }
+        write!(f, "expected {}\nbut got  {}", self.expected, self.actual)
+    }
+}
+
+impl Error for Divergence {
+    fn description(&self) -> &str { "execution diverged from the reference trace" }
+}
+
+/// Compares execution against a reference trace.
+pub struct TraceDiff {
+    reader: Box<BufRead + Send>,
+    /// Number of reference lines consumed
+    line: u64,
+    /// Last reference lines
+    reference_context: VecDeque<String>,
+    /// Our last instructions, formatted like the reference
+    context: VecDeque<String>,
+    /// Set when the reference trace has ended
+    finished: bool,
+}
+
+impl TraceDiff {
+    pub fn new(reader: Box<BufRead + Send>) -> Self {
+        TraceDiff {
+            reader: reader,
+            line: 0,
+            reference_context: VecDeque::with_capacity(CONTEXT_LINES),
+            context: VecDeque::with_capacity(CONTEXT_LINES),
+            finished: false,
+        }
+    }
+
+    /// Opens a reference trace file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = try!(File::open(path));
+        Ok(TraceDiff::new(Box::new(BufReader::new(file))))
+    }
+
+    /// Returns whether the whole reference trace was compared.
+    pub fn is_finished(&self) -> bool { self.finished }
+
+    /// Compares the CPU state before the next instruction is executed to the next line of the
+    /// reference trace.
+    ///
+    /// Lines that don't look like CPU trace lines (eg. blank lines or interleaved SPC700 traces)
+    /// are skipped.
+    pub fn check(&mut self, cpu: &mut Cpu<Peripherals>) -> Result<(), Box<Error>> {
+        if self.finished || cpu.waiting() { return Ok(()) }
+
+        let mut line = String::new();
+        let expected;
+        loop {
+            line.clear();
+            if try!(self.reader.read_line(&mut line)) == 0 {
+                info!("reference trace ended after {} lines without diverging", self.line);
+                self.finished = true;
+                return Ok(());
+            }
+            self.line += 1;
+            if let Ok(state) = TraceState::parse_bsnes(&line) {
+                expected = state;
+                break;
+            }
+        }
+
+        let actual = TraceState::from_cpu(cpu);
+        let (small_acc, small_index) = (cpu.status().small_acc(), cpu.status().small_index());
+        let instr = cpu.mem.disassemble(cpu.pbr, cpu.pc, small_acc, small_index);
+        push_context(&mut self.reference_context, line.trim_right().to_string());
+        push_context(&mut self.context, format!("{:06x} {:23} {}", actual.pc, instr.to_string(),
+            // Skip the PC, it's already at the start of the line
+            &actual.to_string()[7..]));
+
+        if actual != expected {
+            self.finished = true;
+            return Err(Box::new(Divergence {
+                line: self.line,
+                expected: expected,
+                actual: actual,
+                reference_context: self.reference_context.iter().cloned().collect(),
+                context: self.context.iter().cloned().collect(),
+            }));
+        }
+        Ok(())
+    }
+}
+
+fn push_context(context: &mut VecDeque<String>, line: String) {
+    if context.len() == CONTEXT_LINES {
+        context.pop_front();
+    }
+    context.push_back(line);
+}
@@ -0,0 +1,173 @@
+//! Memory triggers ("achievements")
+//!
+//! A `Trigger` is a named condition over memory and registers, written in the debugger's
+//! expression language (see the `expr` module). A `TriggerSet` evaluates its triggers once per
+//! frame and calls a callback whenever a trigger's condition becomes true, eg. when the player
+//! reaches level 2:
+//!
+//! ```text
+//! Reached level 2: [7E:0010] == 2 && [7E:0020] > 10
+//! ```
+//!
+//! Triggers are edge-sensitive: They fire in the frame the condition changes from false to true,
+//! not in every frame it holds. This makes "address X became Y while Z > 10" a simple
+//! `[X] == Y && [Z] > 10`.
+//!
+//! Trigger files contain one `NAME: CONDITION` line per trigger. Empty lines and lines starting
+//! with `#` are ignored.
+
+use expr::Expr;
+use snes::Snes;
+use symbols::SymbolTable;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// A named condition
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    name: String,
+    condition: Expr,
+    /// Fire again every time the condition becomes true (instead of only the first time)
+    repeat: bool,
+    /// Value of the condition in the last checked frame
+    was_true: bool,
+    /// Number of times the trigger has fired
+    fired: u32,
+}
+
+impl Trigger {
+    /// Creates a trigger that fires the first time `condition` becomes true, like an achievement.
+    pub fn new<S: Into<String>>(name: S, condition: Expr) -> Self {
+        Trigger {
+            name: name.into(),
+            condition: condition,
+            repeat: false,
+            was_true: false,
+            fired: 0,
+        }
+    }
+
+    /// Creates a trigger that fires every time `condition` becomes true.
+    pub fn repeating<S: Into<String>>(name: S, condition: Expr) -> Self {
+        Trigger { repeat: true, ..Self::new(name, condition) }
+    }
+
+    /// Parses a `NAME: CONDITION` line into a (non-repeating) trigger.
+    pub fn parse(s: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        // Addresses in the condition can contain a `:`, but not followed by a space
+        let mut split = s.splitn(2, ": ");
+        match (split.next(), split.next()) {
+            (Some(name), Some(condition)) if !name.trim().is_empty() => {
+                let condition = try!(Expr::parse(condition, symbols));
+                Ok(Trigger::new(name.trim(), condition))
+            }
+            _ => Err(format!("expected `NAME: CONDITION`, got `{}`", s.trim())),
+        }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn condition(&self) -> &Expr { &self.condition }
+
+    pub fn is_repeating(&self) -> bool { self.repeat }
+
+    /// Returns how often the trigger has fired.
+    pub fn fire_count(&self) -> u32 { self.fired }
+
+    /// Evaluates the condition and returns whether the trigger fires.
+    fn check(&mut self, snes: &mut Snes) -> bool {
+        if !self.repeat && self.fired > 0 { return false }
+
+        let is_true = self.condition.is_true(snes);
+        let fires = is_true && !self.was_true;
+        self.was_true = is_true;
+        if fires {
+            self.fired += 1;
+        }
+        fires
+    }
+
+    /// Forgets that the trigger has fired and what the condition's value was.
+    pub fn reset(&mut self) {
+        self.was_true = false;
+        self.fired = 0;
+    }
+}
+
+/// Passed to the callback when a trigger fires
+#[derive(Copy, Clone, Debug)]
+pub struct Fired<'a> {
+    pub trigger: &'a Trigger,
+    /// Number of frames emulated when the trigger fired
+    pub frame: u64,
+}
+
+/// A set of triggers with their callbacks
+#[derive(Default)]
+pub struct TriggerSet {
+    triggers: Vec<Trigger>,
+    callbacks: Vec<Box<FnMut(&Fired)>>,
+}
+
+impl TriggerSet {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a trigger. `callback` is called every time it fires.
+    pub fn add<F>(&mut self, trigger: Trigger, callback: F) where F: FnMut(&Fired) + 'static {
+        self.triggers.push(trigger);
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Returns the triggers in the order they were added.
+    pub fn triggers(&self) -> &[Trigger] { &self.triggers }
+
+    pub fn len(&self) -> usize { self.triggers.len() }
+
+    pub fn is_empty(&self) -> bool { self.triggers.is_empty() }
+
+    /// Evaluates all triggers and calls the callbacks of those that fire. This should be called
+    /// once per frame, after the frame was emulated.
+    ///
+    /// Returns the number of triggers that fired.
+    pub fn check(&mut self, snes: &mut Snes) -> usize {
+        let mut count = 0;
+        for (trigger, callback) in self.triggers.iter_mut().zip(&mut self.callbacks) {
+            if trigger.check(snes) {
+                count += 1;
+                callback(&Fired {
+                    trigger: trigger,
+                    frame: snes.frame_count(),
+                });
+            }
+        }
+        count
+    }
+
+    /// Resets all triggers (eg. after the system was reset).
+    pub fn reset(&mut self) {
+        for trigger in &mut self.triggers {
+            trigger.reset();
+        }
+    }
+}
+
+/// Loads the triggers from a trigger file (see the module documentation for the format).
+pub fn load_triggers<P: AsRef<Path>>(path: P, symbols: &SymbolTable)
+                                     -> Result<Vec<Trigger>, Box<Error>> {
+    let path = path.as_ref();
+    let reader = BufReader::new(try!(File::open(path)));
+    let mut triggers = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+
+        let trigger = try!(Trigger::parse(line, symbols)
+            .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e)));
+        triggers.push(trigger);
+    }
+    Ok(triggers)
+}
@@ -0,0 +1,93 @@
+//! Watch expressions
+//!
+//! A `Watch` samples an expression (see the `expr` module) while the debugger executes code and
+//! keeps a history of its values, so game variables can be followed alongside execution.
+
+use expr::Expr;
+use snes::Snes;
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// Number of samples kept per watch. Older samples are discarded.
+const MAX_SAMPLES: usize = 1024;
+
+/// When a watch is sampled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchMode {
+    /// After every instruction. Only changes of the value are recorded, since most instructions
+    /// don't touch the watched variable.
+    Step,
+    /// After every frame
+    Frame,
+}
+
+impl FromStr for WatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "step" => Ok(WatchMode::Step),
+            "frame" => Ok(WatchMode::Frame),
+            _ => Err(format!("invalid watch mode '{}' (expected `step` or `frame`)", s)),
+        }
+    }
+}
+
+/// A recorded value
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// Frame number at the time of sampling
+    pub frame: u64,
+    /// Address of the next instruction to be executed (`$BBAAAA`)
+    pub pc: u32,
+    pub value: u32,
+}
+
+/// An expression that is sampled periodically
+#[derive(Clone, Debug)]
+pub struct Watch {
+    expr: Expr,
+    mode: WatchMode,
+    samples: VecDeque<Sample>,
+}
+
+impl Watch {
+    pub fn new(expr: Expr, mode: WatchMode) -> Self {
+        Watch {
+            expr: expr,
+            mode: mode,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn expr(&self) -> &Expr { &self.expr }
+
+    pub fn mode(&self) -> WatchMode { self.mode }
+
+    /// Returns the recorded samples, oldest first.
+    pub fn samples(&self) -> &VecDeque<Sample> { &self.samples }
+
+    /// Returns the most recently sampled value.
+    pub fn last_value(&self) -> Option<u32> { self.samples.back().map(|sample| sample.value) }
+
+    /// Evaluates the expression and records its value. In `Step` mode, the value is only recorded
+    /// if it differs from the last sample.
+    pub fn sample(&mut self, snes: &mut Snes) {
+        let value = self.expr.eval(snes);
+        if self.mode == WatchMode::Step && self.last_value() == Some(value) { return }
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        let cpu = snes.cpu();
+        self.samples.push_back(Sample {
+            frame: snes.frame_count(),
+            pc: (cpu.pbr as u32) << 16 | cpu.pc as u32,
+            value: value,
+        });
+    }
+
+    /// Discards all samples.
+    pub fn clear(&mut self) { self.samples.clear() }
+}
@@ -4,12 +4,31 @@
 extern crate breeze_backend;
 extern crate cpal;
 
-use breeze_backend::{BackendResult, AudioSink};
+use breeze_backend::{BackendResult, AudioSink, APU_SAMPLE_RATE};
+use breeze_backend::resample::Resampler;
 
-use cpal::{get_default_endpoint, Voice, SampleFormat, SamplesRate, UnknownTypeBuffer};
+use cpal::{get_default_endpoint, Format, Voice, SampleFormat, SamplesRate, UnknownTypeBuffer};
+
+use std::time::Duration;
 
 pub struct CpalAudio {
     voice: Voice,
+    /// Converts the APU's output to the device's sample rate and applies the rate adjustment
+    resampler: Resampler,
+    /// Resampled data waiting to be sent to the device
+    buf: Vec<(i16, i16)>,
+}
+
+/// Picks the best format out of the formats supported by the device. We need a stereo format, and
+/// prefer 32 kHz and signed 16-bit samples, since that's what the APU produces.
+fn pick_format(formats: &[Format]) -> Option<&Format> {
+    let stereo = || formats.iter().filter(|fmt| fmt.channels.len() == 2);
+    let native = SamplesRate(APU_SAMPLE_RATE);
+
+    stereo().find(|fmt| fmt.data_type == SampleFormat::I16 && fmt.samples_rate == native)
+        .or_else(|| stereo().find(|fmt| fmt.samples_rate == native))
+        .or_else(|| stereo().find(|fmt| fmt.data_type == SampleFormat::I16))
+        .or_else(|| stereo().next())
 }
 
 impl AudioSink for CpalAudio {
@@ -23,36 +42,67 @@ impl AudioSink for CpalAudio {
             debug!("supported format: {:?}", fmt);
         }
 
-        let format = match formats.iter().find(|fmt| {
-            fmt.data_type == SampleFormat::I16 && fmt.samples_rate == SamplesRate(32000) &&
-            fmt.channels.len() == 2
-        }) {
+        let format = match pick_format(&formats) {
             Some(fmt) => fmt,
-            None => return Err("no support for signed 16-bit data".into()),
+            None => return Err("no supported stereo audio format".into()),
         };
 
         info!("audio format: {:?}", format);
+        if format.samples_rate.0 != APU_SAMPLE_RATE {
+            info!("device doesn't support {} Hz output, resampling to {} Hz",
+                APU_SAMPLE_RATE, format.samples_rate.0);
+        }
 
         let voice = try!(Voice::new(&endpoint, &format));
 
         Ok(CpalAudio {
+            resampler: Resampler::new(APU_SAMPLE_RATE, format.samples_rate.0),
             voice: voice,
+            buf: Vec::new(),
         })
     }
 
-    fn write(&mut self, mut data: &[(i16, i16)]) {
+    fn write(&mut self, data: &[(i16, i16)]) {
+        self.buf.clear();
+        self.resampler.process(data, &mut self.buf);
+
+        let mut data = &self.buf[..];
         while !data.is_empty() {
-            match self.voice.append_data(data.len() * 2) {
-                UnknownTypeBuffer::I16(mut buffer) => {
+            macro_rules! fill {
+                ( $buffer:ident, $convert:expr ) => {{
+                    let mut buffer = $buffer;
                     for out in buffer.chunks_mut(2) {
                         let (first, rest) = data.split_first().unwrap();
-                        out[0] = first.0;
-                        out[1] = first.1;
+                        out[0] = $convert(first.0);
+                        out[1] = $convert(first.1);
                         data = rest;
                     }
-                },
-                _ => unimplemented!(),
+                }};
+            }
+
+            match self.voice.append_data(data.len() * 2) {
+                UnknownTypeBuffer::I16(buffer) => fill!(buffer, |s: i16| s),
+                UnknownTypeBuffer::U16(buffer) => fill!(buffer, |s: i16| (s as i32 + 32768) as u16),
+                UnknownTypeBuffer::F32(buffer) => fill!(buffer, |s: i16| s as f32 / 32768.0),
             }
         }
+
+        self.voice.play();
+    }
+
+    fn output_rate(&self) -> u32 {
+        self.voice.format().samples_rate.0
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        let channels = self.voice.format().channels.len() as u64;
+        let rate = self.voice.format().samples_rate.0 as u64;
+        let frames = self.voice.get_pending_samples() as u64 / channels;
+        let nanos = frames * 1_000_000_000 / rate;
+        Some(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+    }
+
+    fn set_rate_adjust(&mut self, adjust: f64) {
+        self.resampler.set_rate_adjust(adjust);
     }
 }
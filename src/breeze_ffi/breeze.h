@@ -0,0 +1,114 @@
+/*
+ * C API of the Breeze SNES emulator
+ *
+ * Link against the `breeze_ffi` shared library. All functions taking a `BreezeEmulator *` require
+ * a handle returned by `breeze_create` that hasn't been destroyed. A handle must not be used from
+ * multiple threads at the same time.
+ *
+ * Functions returning `int` return 0 on success and -1 on failure. After a failure,
+ * `breeze_last_error` describes what went wrong.
+ */
+
+#ifndef BREEZE_H
+#define BREEZE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Size of the framebuffer returned by `breeze_framebuffer`, in pixels */
+#define BREEZE_SCREEN_WIDTH 256
+#define BREEZE_SCREEN_HEIGHT 224
+
+/* Sample rate of the audio returned by `breeze_audio`, in Hz */
+#define BREEZE_AUDIO_RATE 32000
+
+/* Button bits for `breeze_set_input` */
+#define BREEZE_BUTTON_R      (1 << 4)
+#define BREEZE_BUTTON_L      (1 << 5)
+#define BREEZE_BUTTON_X      (1 << 6)
+#define BREEZE_BUTTON_A      (1 << 7)
+#define BREEZE_BUTTON_RIGHT  (1 << 8)
+#define BREEZE_BUTTON_LEFT   (1 << 9)
+#define BREEZE_BUTTON_DOWN   (1 << 10)
+#define BREEZE_BUTTON_UP     (1 << 11)
+#define BREEZE_BUTTON_START  (1 << 12)
+#define BREEZE_BUTTON_SELECT (1 << 13)
+#define BREEZE_BUTTON_Y      (1 << 14)
+#define BREEZE_BUTTON_B      (1 << 15)
+
+typedef struct BreezeEmulator BreezeEmulator;
+
+/* Creates an emulator without a ROM. Never returns NULL. */
+BreezeEmulator *breeze_create(void);
+
+/* Destroys an emulator. Does nothing if `emu` is NULL. */
+void breeze_destroy(BreezeEmulator *emu);
+
+/*
+ * Loads a ROM image (with or without an SMC header) from memory and resets the system. The data
+ * is copied, so it can be freed afterwards. Fails if the data isn't a valid ROM image.
+ */
+int breeze_load_rom(BreezeEmulator *emu, const uint8_t *data, size_t len);
+
+/* Emulates until the next frame is completed. Fails if no ROM is loaded or emulation failed. */
+int breeze_run_frame(BreezeEmulator *emu);
+
+/*
+ * Returns the last rendered frame as RGB24 data (3 bytes per pixel, row by row), or NULL if no ROM
+ * is loaded. The pointer is valid until the next call of `breeze_run_frame`, `breeze_load_rom`,
+ * `breeze_load_state` or `breeze_destroy`.
+ */
+const uint8_t *breeze_framebuffer(const BreezeEmulator *emu);
+
+/*
+ * Returns the audio produced by the last `breeze_run_frame` call as interleaved stereo samples
+ * (left, right) and stores the number of sample pairs in `*frames` (if `frames` isn't NULL). The
+ * pointer is valid until the next call of `breeze_run_frame`, `breeze_load_rom` or
+ * `breeze_destroy`.
+ *
+ * Note that the emulated DSP doesn't produce any samples yet, so there currently are no frames.
+ */
+const int16_t *breeze_audio(const BreezeEmulator *emu, size_t *frames);
+
+/*
+ * Sets the buttons held on the joypad in `port` (0 or 1) to `buttons`, a combination of
+ * `BREEZE_BUTTON_*` bits. Takes effect the next time the game reads the joypad. Invalid ports are
+ * ignored.
+ */
+void breeze_set_input(BreezeEmulator *emu, unsigned int port, uint16_t buttons);
+
+/*
+ * Returns the buttons (`BREEZE_BUTTON_*` bits) the game saw when it last read the joypad in `port`,
+ * including buttons pressed by macros or held with the hold feature. Meant for input displays.
+ * Returns 0 if no ROM is loaded or `port` is invalid.
+ */
+uint16_t breeze_latched_input(const BreezeEmulator *emu, unsigned int port);
+
+/*
+ * Creates a save state and returns its size in bytes, or 0 on failure. The state is written to
+ * `buf` if it's large enough (`len` is at least the returned size), so calling this with a NULL
+ * `buf` queries the size.
+ */
+size_t breeze_save_state(BreezeEmulator *emu, uint8_t *buf, size_t len);
+
+/*
+ * Restores a save state created by `breeze_save_state` for the same ROM. If this fails, the system
+ * may be left in an inconsistent state and should be reset by loading the ROM again.
+ */
+int breeze_load_state(BreezeEmulator *emu, const uint8_t *data, size_t len);
+
+/*
+ * Returns a description of the last failure, or NULL if the last call succeeded. The string is
+ * valid until the next call taking `emu`.
+ */
+const char *breeze_last_error(const BreezeEmulator *emu);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
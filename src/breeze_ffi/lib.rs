@@ -0,0 +1,246 @@
+//! C API for embedding the emulator
+//!
+//! This crate builds a shared library that lets frontends written in other languages (C, C#,
+//! Swift, ...) run the emulator. The functions are declared and documented in `breeze.h`.
+//!
+//! All functions taking a `BreezeEmulator` pointer require a pointer returned by `breeze_create`
+//! that wasn't passed to `breeze_destroy` yet. A handle must not be used by multiple threads at the
+//! same time. Panics inside the emulator are caught and reported as errors, they never unwind into
+//! the caller.
+
+#![deny(warnings)]
+#![deny(unused_import_braces, unused_qualifications, unused_extern_crates)]
+
+extern crate breeze_core;
+extern crate breeze_backend;
+
+use breeze_core::input::Peripheral;
+use breeze_core::rom::Rom;
+use breeze_core::save::SaveStateFormat;
+use breeze_core::snes::Snes;
+use breeze_backend::input::joypad::{BUTTONS, JoypadImpl, JoypadState};
+
+use std::any::Any;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_uint};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A joypad whose buttons are set with `breeze_set_input`. The button masks use the bit numbers of
+/// `JoypadState` (the `JoypadButton` discriminants).
+struct FfiJoypad(Arc<AtomicUsize>);
+
+impl JoypadImpl for FfiJoypad {
+    fn update_state(&mut self) -> JoypadState {
+        let mask = self.0.load(Ordering::SeqCst);
+        let mut state = JoypadState::new();
+        for &button in &BUTTONS {
+            state.set(button, mask & 1 << button as u8 != 0);
+        }
+        state
+    }
+}
+
+/// The emulator handle handed out to C
+pub struct BreezeEmulator {
+    /// The emulated system, once a ROM was loaded
+    snes: Option<Snes>,
+    /// Button masks of the joypads in both controller ports
+    input: [Arc<AtomicUsize>; 2],
+    /// Interleaved stereo samples produced during the last frame
+    audio: Vec<i16>,
+    /// Message describing the last error
+    error: Option<CString>,
+}
+
+impl BreezeEmulator {
+    /// Runs `f` on the emulated system, turning a missing ROM or a panic into an error.
+    fn with_snes<T, F>(&mut self, f: F) -> Result<T, String>
+    where F: FnOnce(&mut Snes) -> Result<T, String> {
+        match self.snes {
+            Some(ref mut snes) => catch_panics(|| f(snes)),
+            None => Err("no ROM loaded".to_string()),
+        }
+    }
+
+    /// Records the error of a failed call and returns the result as a C status code.
+    fn status(&mut self, result: Result<(), String>) -> c_int {
+        match result {
+            Ok(()) => {
+                self.error = None;
+                0
+            }
+            Err(msg) => {
+                // Interior NUL bytes can't be represented, but shouldn't happen either
+                self.error = CString::new(msg.replace('\0', "")).ok();
+                -1
+            }
+        }
+    }
+}
+
+/// Runs `f`, turning a panic into an error.
+fn catch_panics<T, F>(f: F) -> Result<T, String> where F: FnOnce() -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        Err(format!("emulator panicked: {}", panic_message(&*payload)))
+    })
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => msg.clone(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn breeze_create() -> *mut BreezeEmulator {
+    Box::into_raw(Box::new(BreezeEmulator {
+        snes: None,
+        input: [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))],
+        audio: Vec::new(),
+        error: None,
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_destroy(emu: *mut BreezeEmulator) {
+    if !emu.is_null() {
+        drop(Box::from_raw(emu));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_load_rom(emu: *mut BreezeEmulator, data: *const u8, len: usize)
+                                         -> c_int {
+    let emu = &mut *emu;
+    if data.is_null() {
+        return emu.status(Err("ROM data is NULL".to_string()));
+    }
+    let data = slice::from_raw_parts(data, len);
+
+    let input = emu.input.clone();
+    let result = catch_panics(|| {
+        let rom = try!(Rom::from_bytes(data).map_err(|e| format!("invalid ROM: {}", e)));
+        let mut snes = Snes::new(rom);
+        // Panics are reported to the caller instead
+        snes.set_crash_log(None);
+        for port in 0..2 {
+            let joypad = FfiJoypad(input[port as usize].clone());
+            snes.peripherals_mut().input.ports[port] =
+                Some(Peripheral::new_joypad(Box::new(joypad)));
+        }
+        Ok(snes)
+    });
+
+    let result = result.map(|snes| {
+        emu.snes = Some(snes);
+        emu.audio.clear();
+    });
+    emu.status(result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_run_frame(emu: *mut BreezeEmulator) -> c_int {
+    let emu = &mut *emu;
+    emu.audio.clear();
+    let result = emu.with_snes(|snes| {
+        snes.advance_frame()
+            .map_err(|e| format!("emulation failed: {}", e))
+    });
+    emu.status(result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_framebuffer(emu: *const BreezeEmulator) -> *const u8 {
+    match (*emu).snes {
+        Some(ref snes) => snes.peripherals().ppu.framebuf.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_audio(emu: *const BreezeEmulator, frames: *mut usize)
+                                      -> *const i16 {
+    let emu = &*emu;
+    if !frames.is_null() {
+        *frames = emu.audio.len() / 2;
+    }
+    emu.audio.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_set_input(emu: *mut BreezeEmulator, port: c_uint, buttons: u16) {
+    if let Some(input) = (*emu).input.get(port as usize) {
+        input.store(buttons as usize, Ordering::SeqCst);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_latched_input(emu: *const BreezeEmulator, port: c_uint) -> u16 {
+    let state = match (*emu).snes {
+        Some(ref snes) if port < 2 => snes.peripherals().input.latched_state(port as u8),
+        _ => None,
+    };
+    match state {
+        Some(state) => BUTTONS.iter()
+            .filter(|&&button| state.is_pressed(button))
+            .fold(0, |mask, &button| mask | 1 << button as u8),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_save_state(emu: *mut BreezeEmulator, buf: *mut u8, len: usize)
+                                           -> usize {
+    let emu = &mut *emu;
+    let result = emu.with_snes(|snes| {
+        let mut state = Vec::new();
+        try!(snes.create_save_state(SaveStateFormat::Custom, &mut state)
+            .map_err(|e| format!("couldn't create save state: {}", e)));
+        Ok(state)
+    });
+
+    match result {
+        Ok(state) => {
+            if !buf.is_null() && len >= state.len() {
+                ptr::copy_nonoverlapping(state.as_ptr(), buf, state.len());
+            }
+            emu.error = None;
+            state.len()
+        }
+        Err(msg) => {
+            emu.status(Err(msg));
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_load_state(emu: *mut BreezeEmulator, data: *const u8, len: usize)
+                                           -> c_int {
+    let emu = &mut *emu;
+    if data.is_null() {
+        return emu.status(Err("save state data is NULL".to_string()));
+    }
+    let mut data = slice::from_raw_parts(data, len);
+    let result = emu.with_snes(|snes| {
+        snes.restore_save_state(SaveStateFormat::Custom, &mut data)
+            .map_err(|e| format!("couldn't restore save state: {}", e))
+    });
+    emu.status(result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn breeze_last_error(emu: *const BreezeEmulator) -> *const c_char {
+    match (*emu).error {
+        Some(ref msg) => msg.as_ptr(),
+        None => ptr::null(),
+    }
+}
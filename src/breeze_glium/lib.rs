@@ -5,13 +5,16 @@
 extern crate breeze_backend;
 
 use breeze_backend::{BackendAction, BackendResult, Renderer};
+use breeze_backend::filter::{Filter, FilterStage};
+use breeze_backend::hotkey::HotkeyMap;
+use breeze_backend::pacing::FrameTiming;
 use breeze_backend::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
-use breeze_backend::viewport::Viewport;
+use breeze_backend::viewport::{DisplayOptions, Viewport};
 
 use glium::{DisplayBuild, Surface, Rect};
 use glium::backend::glutin_backend::GlutinFacade;
 use glium::index::{NoIndices, PrimitiveType};
-use glium::glutin::WindowBuilder;
+use glium::glutin::{ElementState, VirtualKeyCode, WindowBuilder};
 use glium::program::Program;
 use glium::texture::{ClientFormat, RawImage2d, SrgbTexture2d};
 use glium::uniforms::MagnifySamplerFilter;
@@ -62,12 +65,21 @@ pub struct GliumRenderer {
     program: Program,
     /// This texture is updated with the PPU's data every frame
     texture: SrgbTexture2d,
+    /// Post-processing applied to the PPU's data before uploading it
+    filter: FilterStage,
+    /// Size of the frames passed to `render`
+    frame_size: (u32, u32),
+    /// Current window size in pixels
+    win_size: (u32, u32),
+    display_opts: DisplayOptions,
+    hotkeys: HotkeyMap,
 }
 
 impl GliumRenderer {
     fn handle_events(&mut self) -> BackendResult<Vec<BackendAction>> {
         use glium::glutin::Event::*;
 
+        let mut actions = Vec::new();
         for ev in self.display.poll_events() {
             match ev {
                 Closed => {
@@ -75,18 +87,47 @@ impl GliumRenderer {
                     return Ok(vec![BackendAction::Exit]);
                 }
                 Resized(w, h) => {
-                    resize(&mut self.vbuf, w, h);
+                    self.win_size = (w, h);
+                    self.update_viewport();
+                }
+                KeyboardInput(state, _, Some(key)) => {
+                    let pressed = state == ElementState::Pressed;
+                    actions.extend(self.hotkeys.handle_key(&key_name(key), pressed));
                 }
                 _ => {}
             }
         }
 
-        Ok(vec![])
+        Ok(actions)
+    }
+
+    /// Recalculates the geometry after the window, texture or display options have changed.
+    fn update_viewport(&mut self) {
+        let (win_w, win_h) = self.win_size;
+        let viewport = Viewport::for_frame(win_w,
+                                           win_h,
+                                           self.texture.width(),
+                                           self.texture.height(),
+                                           &self.display_opts);
+        resize(&mut self.vbuf, win_w, win_h, viewport);
+    }
+}
+
+/// Converts a glutin key code to the key name used by `HotkeyMap`.
+fn key_name(key: VirtualKeyCode) -> String {
+    let name = format!("{:?}", key);
+    match key {
+        VirtualKeyCode::Back => "Backspace".to_owned(),
+        VirtualKeyCode::Key1 | VirtualKeyCode::Key2 | VirtualKeyCode::Key3 |
+        VirtualKeyCode::Key4 | VirtualKeyCode::Key5 | VirtualKeyCode::Key6 |
+        VirtualKeyCode::Key7 | VirtualKeyCode::Key8 | VirtualKeyCode::Key9 |
+        VirtualKeyCode::Key0 => name[3..].to_owned(),
+        _ => name,
     }
 }
 
-fn resize(vbuf: &mut VertexBuffer<Vertex>, win_w: u32, win_h: u32) {
-    let Viewport { x, y, w, h } = Viewport::for_window_size(win_w, win_h);
+fn resize(vbuf: &mut VertexBuffer<Vertex>, win_w: u32, win_h: u32, viewport: Viewport) {
+    let Viewport { x, y, w, h } = viewport;
     let (win_w, win_h) = (win_w as f32, win_h as f32);
     let (x, y, w, h) = (x as f32 / win_w, y as f32 / win_h, w as f32 / win_w, h as f32 / win_h);
 
@@ -117,28 +158,43 @@ impl Renderer for GliumRenderer {
             .build_glium());
 
         let mut vbuf = try!(VertexBuffer::empty_dynamic(&display, 4));
-        resize(&mut vbuf, SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3);
+        resize(&mut vbuf, SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3,
+               Viewport::for_window_size(SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3));
 
         Ok(GliumRenderer {
             vbuf: vbuf,
             program: try!(
                 Program::from_source(&display, VERTEX_SHADER_SRC, FRAGMENT_SHADER_SRC, None)),
             texture: try!(SrgbTexture2d::empty(&display, SCREEN_WIDTH, SCREEN_HEIGHT)),
+            filter: FilterStage::new(),
+            frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            win_size: (SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3),
+            display_opts: DisplayOptions::default(),
+            hotkeys: HotkeyMap::default(),
             display: display,
         })
     }
 
     fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>> {
+        // the filter determines the texture size, so recreate the texture if that has changed
+        let (frame_w, frame_h) = self.frame_size;
+        let (width, height) = self.filter.output_size(frame_w, frame_h);
+        if self.texture.width() != width || self.texture.height() != height {
+            self.texture = try!(SrgbTexture2d::empty(&self.display, width, height));
+            self.update_viewport();
+        }
+
         // upload new texture data
+        let frame_data = self.filter.process(frame_data, frame_w, frame_h);
         self.texture.write(Rect {
             left: 0,
             bottom: 0,
-            width: SCREEN_WIDTH,
-            height: SCREEN_HEIGHT,
+            width: width,
+            height: height,
         }, RawImage2d {
             data: Cow::Borrowed(frame_data),
-            width: SCREEN_WIDTH,
-            height: SCREEN_HEIGHT,
+            width: width,
+            height: height,
             format: ClientFormat::U8U8U8,
         });
 
@@ -163,4 +219,40 @@ impl Renderer for GliumRenderer {
             win_ref.set_title(title);
         }
     }
+
+    fn set_filter(&mut self, filter: Option<Box<Filter>>) -> BackendResult<()> {
+        self.filter.set_filter(filter);
+        Ok(())
+    }
+
+    fn set_display_options(&mut self, opts: DisplayOptions) -> BackendResult<()> {
+        self.display_opts = opts;
+        self.update_viewport();
+        Ok(())
+    }
+
+    fn set_window_scale(&mut self, scale: u32) -> BackendResult<()> {
+        let (frame_w, frame_h) = self.frame_size;
+        let (width, height) = (frame_w * scale, frame_h * scale);
+        if let Some(win_ref) = self.display.get_window() {
+            win_ref.set_inner_size(width, height);
+        }
+        self.win_size = (width, height);
+        self.update_viewport();
+        Ok(())
+    }
+
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        // `render` recreates the texture when its size no longer matches
+        self.frame_size = (width, height);
+        Ok(())
+    }
+
+    fn set_hotkeys(&mut self, hotkeys: HotkeyMap) -> BackendResult<()> {
+        self.hotkeys = hotkeys;
+        Ok(())
+    }
+
+    /// We don't enable vsync, since glutin can't tell us the display's refresh rate.
+    fn frame_timing(&self) -> FrameTiming { FrameTiming::Timer }
 }
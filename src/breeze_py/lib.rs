@@ -0,0 +1,225 @@
+//! Python bindings
+//!
+//! Exposes the emulator to Python as the `breeze` module, for reinforcement learning and
+//! automated game analysis. Emulation runs headless and as fast as possible; the caller decides
+//! when to advance, what the controllers press and what to read from memory:
+//!
+//! ```python
+//! import breeze
+//!
+//! emu = breeze.Emulator.from_file("game.sfc")
+//! emu.set_input(0, ["start"])
+//! emu.run_frame()
+//! lives = emu.peek(0x7E0010)
+//! state = emu.save_state()
+//! emu.run(60)
+//! emu.load_state(state)
+//! rgb = emu.frame()   # 256x224 RGB24
+//! ```
+//!
+//! Addresses are 24-bit `$BBAAAA` addresses in the CPU's address space. Reading them has no side
+//! effects (I/O registers that would be affected by a read can't be peeked).
+
+#![deny(warnings)]
+#![deny(unused_import_braces, unused_qualifications, unused_extern_crates)]
+
+extern crate breeze_core;
+extern crate breeze_backend;
+extern crate pyo3;
+
+use breeze_core::input::Peripheral;
+use breeze_core::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use breeze_core::rom::Rom;
+use breeze_core::save::SaveStateFormat;
+use breeze_core::snes::Snes;
+use breeze_backend::input::joypad::{BUTTONS, JoypadButton, JoypadImpl, JoypadState};
+
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// A joypad whose state is set with `Emulator.set_input`
+struct PyJoypad(Arc<Mutex<JoypadState>>);
+
+impl JoypadImpl for PyJoypad {
+    fn update_state(&mut self) -> JoypadState {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// An emulated SNES with a ROM loaded and a joypad plugged into both controller ports
+#[pyclass(unsendable, module = "breeze")]
+pub struct Emulator {
+    snes: Snes,
+    input: [Arc<Mutex<JoypadState>>; 2],
+}
+
+impl Emulator {
+    fn with_rom(rom: Rom) -> Self {
+        let input = [Arc::new(Mutex::new(JoypadState::new())),
+                     Arc::new(Mutex::new(JoypadState::new()))];
+        let mut snes = Snes::new(rom);
+        // Panics are raised as Python exceptions instead
+        snes.set_crash_log(None);
+        for port in 0..2 {
+            let joypad = PyJoypad(input[port as usize].clone());
+            snes.peripherals_mut().input.ports[port] =
+                Some(Peripheral::new_joypad(Box::new(joypad)));
+        }
+
+        Emulator {
+            snes: snes,
+            input: input,
+        }
+    }
+}
+
+#[pymethods]
+impl Emulator {
+    /// Creates an emulator running the ROM image in `rom` (a `bytes` object, with or without SMC
+    /// header).
+    #[new]
+    fn new(rom: &[u8]) -> PyResult<Self> {
+        let rom = try!(Rom::from_bytes(rom)
+            .map_err(|e| PyValueError::new_err(format!("invalid ROM: {}", e))));
+        Ok(Emulator::with_rom(rom))
+    }
+
+    /// Creates an emulator running the ROM file at `path`.
+    #[staticmethod]
+    fn from_file(path: &str) -> PyResult<Self> {
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| PyIOError::new_err(format!("couldn't read {}: {}", path, e))));
+        Emulator::new(&data)
+    }
+
+    /// Emulates until the next frame is completed.
+    fn run_frame(&mut self) -> PyResult<()> {
+        try!(self.snes.advance_frame()
+            .map_err(|e| PyRuntimeError::new_err(format!("emulation failed: {}", e))));
+        Ok(())
+    }
+
+    /// Emulates `frames` frames.
+    fn run(&mut self, frames: u32) -> PyResult<()> {
+        for _ in 0..frames {
+            try!(self.run_frame());
+        }
+        Ok(())
+    }
+
+    /// Resets the system. The cartridge RAM and the controller state are kept.
+    fn reset(&mut self) {
+        self.snes.reset();
+    }
+
+    /// Number of frames emulated since the emulator was created
+    #[getter]
+    fn frame_count(&self) -> u64 { self.snes.frame_count() }
+
+    /// Returns the last frame as RGB24 `bytes` (`SCREEN_WIDTH * SCREEN_HEIGHT * 3` bytes, row by
+    /// row).
+    fn frame<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, &self.snes.peripherals().ppu.framebuf[..])
+    }
+
+    /// Sets the buttons held on the joypad in `port` (0 or 1) to the given list of button names
+    /// (`a`, `b`, `x`, `y`, `l`, `r`, `start`, `select`, `up`, `down`, `left`, `right`). Takes
+    /// effect the next time the game reads the joypad.
+    fn set_input(&mut self, port: usize, buttons: Vec<String>) -> PyResult<()> {
+        let input = try!(self.input.get(port)
+            .ok_or_else(|| PyValueError::new_err(format!("invalid controller port: {}", port))));
+
+        let mut state = JoypadState::new();
+        for button in &buttons {
+            let button: JoypadButton = try!(button.parse().map_err(PyValueError::new_err));
+            state.set(button, true);
+        }
+        *input.lock().unwrap() = state;
+        Ok(())
+    }
+
+    /// Returns the names of the buttons the game saw when it last read the joypad in `port` (for
+    /// input displays).
+    fn latched_input(&self, port: u8) -> PyResult<Vec<&'static str>> {
+        if port > 1 {
+            return Err(PyValueError::new_err(format!("invalid controller port: {}", port)));
+        }
+        let state = self.snes.peripherals().input.latched_state(port).unwrap_or_default();
+        Ok(BUTTONS.iter().filter(|&&b| state.is_pressed(b)).map(|b| b.name()).collect())
+    }
+
+    /// Returns the byte at `addr`, or `None` if it can't be read without side effects.
+    fn peek(&mut self, addr: u32) -> PyResult<Option<u8>> {
+        try!(check_addr(addr));
+        Ok(self.snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16))
+    }
+
+    /// Returns `len` bytes starting at `addr`. Raises `ValueError` if any of them can't be read
+    /// without side effects.
+    fn read<'py>(&mut self, py: Python<'py>, addr: u32, len: u32) -> PyResult<&'py PyBytes> {
+        let mut bytes = Vec::with_capacity(len as usize);
+        for i in 0..len {
+            let addr = addr + i;
+            try!(check_addr(addr));
+            match self.snes.peripherals_mut().peek((addr >> 16) as u8, addr as u16) {
+                Some(byte) => bytes.push(byte),
+                None => return Err(PyValueError::new_err(format!("can't read ${:06X}", addr))),
+            }
+        }
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Writes `value` to `addr`. Returns whether the address is writable (RAM or an ignored
+    /// write).
+    fn poke(&mut self, addr: u32, value: u8) -> PyResult<bool> {
+        try!(check_addr(addr));
+        Ok(self.snes.peripherals_mut().poke((addr >> 16) as u8, addr as u16, value))
+    }
+
+    /// Returns the 128 KB of work RAM (`$7E0000-$7FFFFF`) as `bytes`.
+    fn wram<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, &self.snes.peripherals().wram[..])
+    }
+
+    /// Returns the cartridge RAM (SRAM) as `bytes`.
+    fn sram<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, self.snes.peripherals().rom.ram())
+    }
+
+    /// Creates a save state and returns it as `bytes`.
+    fn save_state<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        let mut state = Vec::new();
+        try!(self.snes.create_save_state(SaveStateFormat::Custom, &mut state)
+            .map_err(|e| PyRuntimeError::new_err(format!("couldn't create save state: {}", e))));
+        Ok(PyBytes::new(py, &state))
+    }
+
+    /// Restores a save state created by `save_state` for the same ROM.
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        let mut state = state;
+        self.snes.restore_save_state(SaveStateFormat::Custom, &mut state)
+            .map_err(|e| PyValueError::new_err(format!("couldn't restore save state: {}", e)))
+    }
+}
+
+fn check_addr(addr: u32) -> PyResult<()> {
+    if addr > 0xffffff {
+        Err(PyValueError::new_err(format!("address out of range: ${:X}", addr)))
+    } else {
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn breeze(_py: Python, m: &PyModule) -> PyResult<()> {
+    try!(m.add_class::<Emulator>());
+    try!(m.add("SCREEN_WIDTH", SCREEN_WIDTH));
+    try!(m.add("SCREEN_HEIGHT", SCREEN_HEIGHT));
+    Ok(())
+}
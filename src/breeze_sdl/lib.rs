@@ -1,25 +1,35 @@
-//! Render to an SDL window
+//! SDL backend: Renders to an SDL window, plays audio and reads keyboard input
 
 #[macro_use] extern crate log;
 extern crate breeze_backend;
 extern crate sdl2;
 extern crate libc;
 
-use breeze_backend::{BackendAction, BackendResult};
+use breeze_backend::{AudioBufferConfig, AudioSink, BackendAction, BackendResult, DebugSurfaceId};
+use breeze_backend::APU_SAMPLE_RATE;
+use breeze_backend::filter::{Filter, FilterStage};
+use breeze_backend::hotkey::HotkeyMap;
 use breeze_backend::input::joypad::{JoypadImpl, JoypadState, JoypadButton};
+use breeze_backend::pacing::FrameTiming;
+use breeze_backend::resample::Resampler;
 use breeze_backend::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
-use breeze_backend::viewport::Viewport;
+use breeze_backend::viewport::{DisplayOptions, Viewport};
 
 use sdl2::{EventPump, Sdl};
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use sdl2::event::WindowEventId;
-use sdl2::keyboard::Scancode;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::{Renderer, Texture, TextureAccess};
 use sdl2::rect::Rect;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 
 /// Signal handler saving/restoring when initializing SDL
 mod signal {
@@ -59,6 +69,9 @@ struct SdlManager {
     sdl: Sdl,
     event_pump: EventPump,
     resized_to: Option<(u32, u32)>,
+    hotkeys: HotkeyMap,
+    /// SDL window IDs of the open debug windows
+    debug_windows: Vec<(u32, DebugSurfaceId)>,
 }
 
 impl SdlManager {
@@ -68,32 +81,56 @@ impl SdlManager {
     fn update(&mut self) -> BackendResult<Vec<BackendAction>> {
         use self::sdl2::event::Event::*;
 
+        let mut actions = Vec::new();
         for event in self.event_pump.poll_iter() {
             match event {
                 Quit { .. } => {
                     info!("quit event -> exiting");
                     return Ok(vec![BackendAction::Exit]);
                 }
-                Window { win_event_id: WindowEventId::Resized, data1: w, data2: h, .. } => {
+                Window { win_event_id: WindowEventId::Resized, window_id, data1: w, data2: h, .. }
+                if debug_surface(&self.debug_windows, window_id).is_none() => {
                     info!("window resized to {}x{}", w, h);
                     self.resized_to = Some((w as u32, h as u32));
                 }
-                KeyDown { scancode: Some(Scancode::F5), .. } => {
-                    return Ok(vec![BackendAction::SaveState]);
+                Window { win_event_id: WindowEventId::Close, window_id, .. } => {
+                    // SDL only sends `Quit` when the last window is closed, so we have to handle
+                    // the main window being closed while debug windows are open
+                    match debug_surface(&self.debug_windows, window_id) {
+                        Some(id) => {
+                            self.debug_windows.retain(|&(_, surface)| surface != id);
+                            actions.push(BackendAction::DebugSurfaceClosed(id));
+                        }
+                        None => {
+                            info!("main window closed -> exiting");
+                            return Ok(vec![BackendAction::Exit]);
+                        }
+                    }
                 }
-                KeyDown { scancode: Some(Scancode::F9), .. } => {
-                    return Ok(vec![BackendAction::LoadState]);
+                KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                    actions.extend(self.hotkeys.handle_key(scancode.name(), true));
+                }
+                KeyUp { scancode: Some(scancode), .. } => {
+                    actions.extend(self.hotkeys.handle_key(scancode.name(), false));
                 }
                 _ => {}
             }
         }
 
-        Ok(vec![])
+        Ok(actions)
     }
 
     fn resized(&mut self) -> Option<(u32, u32)> { self.resized_to.take() }
 }
 
+/// Looks up the debug surface displayed in the given window.
+fn debug_surface(debug_windows: &[(u32, DebugSurfaceId)], window_id: u32)
+-> Option<DebugSurfaceId> {
+    debug_windows.iter()
+        .find(|&&(window, _)| window == window_id)
+        .map(|&(_, id)| id)
+}
+
 impl Deref for SdlManager {
     type Target = Sdl;
     fn deref(&self) -> &Sdl { &self.sdl }
@@ -110,6 +147,8 @@ thread_local! {
             sdl: sdl,
             event_pump: pump,
             resized_to: None,
+            hotkeys: HotkeyMap::default(),
+            debug_windows: Vec::new(),
         })
     }
 }
@@ -117,6 +156,23 @@ thread_local! {
 pub struct SdlRenderer {
     renderer: Renderer<'static>,
     texture: Texture,
+    /// Post-processing applied to the PPU's data before uploading it
+    filter: FilterStage,
+    /// Size of the frames passed to `render`
+    frame_size: (u32, u32),
+    /// Current window size in pixels
+    win_size: (u32, u32),
+    display_opts: DisplayOptions,
+    timing: FrameTiming,
+    debug_windows: Vec<DebugWindow>,
+    next_debug_id: u32,
+}
+
+/// An additional window showing a debug surface.
+struct DebugWindow {
+    id: DebugSurfaceId,
+    renderer: Renderer<'static>,
+    texture: Texture,
 }
 
 impl ::breeze_backend::Renderer for SdlRenderer {
@@ -127,6 +183,19 @@ impl ::breeze_backend::Renderer for SdlRenderer {
             let window = try!(video.window("breeze", SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3)
                 .resizable()
                 .build());
+            // We use vsync, so we need to know how fast the display runs (SDL reports 0 if it
+            // doesn't know)
+            let refresh_rate = window.display_index()
+                .and_then(|index| video.current_display_mode(index))
+                .map(|mode| mode.refresh_rate)
+                .unwrap_or(0);
+            let timing = if refresh_rate > 0 {
+                FrameTiming::Vsync(refresh_rate as f64)
+            } else {
+                FrameTiming::Timer
+            };
+            info!("display refresh rate: {} Hz", refresh_rate);
+
             let renderer = try!(window.renderer()
                 .accelerated()
                 .present_vsync()
@@ -140,6 +209,13 @@ impl ::breeze_backend::Renderer for SdlRenderer {
             let mut this = SdlRenderer {
                 renderer: renderer,
                 texture: texture,
+                filter: FilterStage::new(),
+                frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+                win_size: (SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3),
+                display_opts: DisplayOptions::default(),
+                timing: timing,
+                debug_windows: Vec::new(),
+                next_debug_id: 0,
             };
             this.resize_to(SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3);
 
@@ -152,13 +228,35 @@ impl ::breeze_backend::Renderer for SdlRenderer {
             self.resize_to(w, h)
         }
 
+        // the filter determines the texture size, so recreate the texture if that has changed
+        let (frame_w, frame_h) = self.frame_size;
+        let (width, height) = self.filter.output_size(frame_w, frame_h);
+        let query = self.texture.query();
+        if query.width != width || query.height != height {
+            self.texture = try!(self.renderer.create_texture(
+                PixelFormatEnum::RGB24,
+                TextureAccess::Static,
+                width,
+                height).map_err(|e| format!("{:?}", e)));
+            let (win_w, win_h) = self.win_size;
+            self.resize_to(win_w, win_h);
+        }
+
         // FIXME Can this be done with fewer copies?
-        self.texture.update(None, frame_data, SCREEN_WIDTH as usize * 3).unwrap();
+        let frame_data = self.filter.process(frame_data, frame_w, frame_h);
+        self.texture.update(None, frame_data, width as usize * 3).unwrap();
         self.renderer.clear();
         self.renderer.copy(&self.texture, None, None).unwrap();
         self.renderer.present();
 
-        SDL.with(|sdl| sdl.borrow_mut().update())
+        let actions = try!(SDL.with(|sdl| sdl.borrow_mut().update()));
+        for action in &actions {
+            if let BackendAction::DebugSurfaceClosed(id) = *action {
+                // Dropping the renderer destroys the window
+                self.debug_windows.retain(|window| window.id != id);
+            }
+        }
+        Ok(actions)
     }
 
     fn set_rom_title(&mut self, title: &str) {
@@ -166,12 +264,111 @@ impl ::breeze_backend::Renderer for SdlRenderer {
             win.set_title(title).unwrap();
         }
     }
+
+    fn set_filter(&mut self, filter: Option<Box<Filter>>) -> BackendResult<()> {
+        self.filter.set_filter(filter);
+        Ok(())
+    }
+
+    fn set_display_options(&mut self, opts: DisplayOptions) -> BackendResult<()> {
+        self.display_opts = opts;
+        let (win_w, win_h) = self.win_size;
+        self.resize_to(win_w, win_h);
+        Ok(())
+    }
+
+    fn set_window_scale(&mut self, scale: u32) -> BackendResult<()> {
+        let (frame_w, frame_h) = self.frame_size;
+        let (width, height) = (frame_w * scale, frame_h * scale);
+        if let Some(window) = self.renderer.window_mut() {
+            try!(window.set_size(width, height).map_err(|e| format!("{:?}", e)));
+        }
+        self.resize_to(width, height);
+        Ok(())
+    }
+
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        // `render` recreates the texture when its size no longer matches
+        self.frame_size = (width, height);
+        Ok(())
+    }
+
+    fn set_hotkeys(&mut self, hotkeys: HotkeyMap) -> BackendResult<()> {
+        SDL.with(|sdl| sdl.borrow_mut().hotkeys = hotkeys);
+        Ok(())
+    }
+
+    fn frame_timing(&self) -> FrameTiming { self.timing }
+
+    fn open_debug_surface(&mut self, title: &str, width: u32, height: u32)
+    -> BackendResult<DebugSurfaceId> {
+        SDL.with(|sdl_cell| {
+            let mut sdl = sdl_cell.borrow_mut();
+            let video = try!(sdl.sdl.video());
+            let window = try!(video.window(title, width * 2, height * 2)
+                .resizable()
+                .build());
+            let window_id = window.id();
+            // No vsync here, the main window already does that
+            let renderer = try!(window.renderer().accelerated().build());
+            let texture = try!(renderer.create_texture(
+                PixelFormatEnum::RGB24,
+                TextureAccess::Static,
+                width,
+                height).map_err(|e| format!("{:?}", e)));
+
+            let id = DebugSurfaceId(self.next_debug_id);
+            self.next_debug_id += 1;
+            self.debug_windows.push(DebugWindow {
+                id: id,
+                renderer: renderer,
+                texture: texture,
+            });
+            sdl.debug_windows.push((window_id, id));
+            Ok(id)
+        })
+    }
+
+    fn update_debug_surface(&mut self,
+                            id: DebugSurfaceId,
+                            data: &[u8],
+                            width: u32,
+                            height: u32) -> BackendResult<()> {
+        let window = match self.debug_windows.iter_mut().find(|window| window.id == id) {
+            Some(window) => window,
+            None => return Err(format!("invalid debug surface: {:?}", id).into()),
+        };
+
+        let query = window.texture.query();
+        if query.width != width || query.height != height {
+            window.texture = try!(window.renderer.create_texture(
+                PixelFormatEnum::RGB24,
+                TextureAccess::Static,
+                width,
+                height).map_err(|e| format!("{:?}", e)));
+        }
+
+        try!(window.texture.update(None, data, width as usize * 3)
+            .map_err(|e| format!("{:?}", e)));
+        window.renderer.clear();
+        try!(window.renderer.copy(&window.texture, None, None));
+        window.renderer.present();
+        Ok(())
+    }
+
+    fn close_debug_surface(&mut self, id: DebugSurfaceId) {
+        self.debug_windows.retain(|window| window.id != id);
+        SDL.with(|sdl| sdl.borrow_mut().debug_windows.retain(|&(_, surface)| surface != id));
+    }
 }
 
 impl SdlRenderer {
     /// Handle a window resize to `w, h`
     fn resize_to(&mut self, w: u32, h: u32) {
-        let Viewport { x, y, w, h } = Viewport::for_window_size(w, h);
+        self.win_size = (w, h);
+        let query = self.texture.query();
+        let Viewport { x, y, w, h } =
+            Viewport::for_frame(w, h, query.width, query.height, &self.display_opts);
 
         let viewport = Rect::new(x as i32, y as i32, w, h);
         self.renderer.set_viewport(Some(viewport));
@@ -180,6 +377,180 @@ impl SdlRenderer {
     }
 }
 
+/// Interleaved stereo samples waiting to be played, shared with SDL's audio thread.
+type SampleQueue = Arc<Mutex<VecDeque<i16>>>;
+
+/// If more than this many times the target latency is queued, the oldest samples are dropped.
+/// This happens when the emulator runs faster than real time.
+const MAX_QUEUED_TARGETS: usize = 4;
+
+/// Counts how often the queue ran dry, shared with SDL's audio thread.
+#[derive(Default)]
+struct UnderrunCounter {
+    count: AtomicUsize,
+    /// The last callback already ran out of samples (a long gap counts once)
+    starved: AtomicBool,
+}
+
+struct QueueCallback {
+    queue: SampleQueue,
+    underruns: Arc<UnderrunCounter>,
+}
+
+impl AudioCallback for QueueCallback {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let mut queue = self.queue.lock().unwrap();
+        let starved = queue.len() < out.len();
+        for sample in out.iter_mut() {
+            // Output silence when we run out of samples
+            *sample = queue.pop_front().unwrap_or(0);
+        }
+
+        if starved && !self.underruns.starved.swap(true, Ordering::Relaxed) {
+            self.underruns.count.fetch_add(1, Ordering::Relaxed);
+        } else if !starved {
+            self.underruns.starved.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Audio sink using SDL's audio subsystem. SDL takes care of converting the samples to whatever
+/// format the device needs.
+pub struct SdlAudio {
+    /// The device stops playing when this is dropped
+    #[allow(dead_code)]
+    device: AudioDevice<QueueCallback>,
+    queue: SampleQueue,
+    underruns: Arc<UnderrunCounter>,
+    freq: u32,
+    /// Maximum number of queued samples (per channel)
+    max_queued: usize,
+    /// Converts the APU's output to the device's sample rate and applies the rate adjustment
+    resampler: Resampler,
+    /// Resampled data waiting to be queued
+    buf: Vec<(i16, i16)>,
+}
+
+/// Opens the default playback device with the given buffer config. Returns the device and its
+/// sample rate.
+fn open_device(audio: &AudioSubsystem,
+               config: AudioBufferConfig,
+               queue: &SampleQueue,
+               underruns: &Arc<UnderrunCounter>)
+               -> BackendResult<(AudioDevice<QueueCallback>, u32)> {
+    if config.period_size == 0 || config.period_size > u16::max_value() as u32 {
+        return Err(format!("invalid audio period size: {} (expected 1 to {})",
+                           config.period_size, u16::max_value()).into());
+    }
+    let desired = AudioSpecDesired {
+        freq: Some(APU_SAMPLE_RATE as i32),
+        channels: Some(2),
+        samples: Some(config.period_size as u16),
+    };
+
+    let mut freq = 0;
+    let device = try!(audio.open_playback(None, &desired, |spec| {
+        info!("audio spec: {} Hz, {} channels, {} samples", spec.freq, spec.channels,
+            spec.samples);
+        freq = spec.freq as u32;
+        QueueCallback { queue: queue.clone(), underruns: underruns.clone() }
+    }));
+    device.resume();
+    Ok((device, freq))
+}
+
+/// Returns the number of queued samples (per channel) after which old samples are dropped.
+fn max_queued(config: AudioBufferConfig, freq: u32) -> usize {
+    // The config is in frames at the device's rate, but SDL may have picked a different one
+    let target = config.target_latency(freq);
+    let frames = target.as_secs() * freq as u64 +
+        target.subsec_nanos() as u64 * freq as u64 / 1_000_000_000;
+    frames as usize * MAX_QUEUED_TARGETS
+}
+
+impl AudioSink for SdlAudio {
+    fn create() -> BackendResult<Self> {
+        SDL.with(|sdl_cell| {
+            let sdl = sdl_cell.borrow();
+            let audio = try!(sdl.audio());
+            let config = AudioBufferConfig::default();
+            let queue = SampleQueue::default();
+            let underruns = Arc::new(UnderrunCounter::default());
+            let (device, freq) = try!(open_device(&audio, config, &queue, &underruns));
+
+            Ok(SdlAudio {
+                device: device,
+                queue: queue,
+                underruns: underruns,
+                freq: freq,
+                max_queued: max_queued(config, freq),
+                resampler: Resampler::new(APU_SAMPLE_RATE, freq),
+                buf: Vec::new(),
+            })
+        })
+    }
+
+    fn write(&mut self, data: &[(i16, i16)]) {
+        self.buf.clear();
+        self.resampler.process(data, &mut self.buf);
+
+        let mut queue = self.queue.lock().unwrap();
+        for &(l, r) in &self.buf {
+            queue.push_back(l);
+            queue.push_back(r);
+        }
+
+        let max = self.max_queued * 2;
+        if queue.len() > max {
+            let excess = queue.len() - max;
+            queue.drain(..excess);
+        }
+    }
+
+    fn output_rate(&self) -> u32 {
+        self.freq
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        let frames = self.queue.lock().unwrap().len() as u64 / 2;
+        let nanos = frames * 1_000_000_000 / self.freq as u64;
+        Some(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+    }
+
+    fn set_rate_adjust(&mut self, adjust: f64) {
+        self.resampler.set_rate_adjust(adjust);
+    }
+
+    fn set_buffer_config(&mut self, config: AudioBufferConfig) -> BackendResult<()> {
+        if config.periods == 0 {
+            return Err("the audio buffer needs at least 1 period".into());
+        }
+        let (device, freq) = try!(SDL.with(|sdl_cell| {
+            let audio = try!(sdl_cell.borrow().audio());
+            open_device(&audio, config, &self.queue, &self.underruns)
+        }));
+
+        // Replacing the old device closes it
+        self.device = device;
+        if freq != self.freq {
+            self.freq = freq;
+            self.resampler = Resampler::new(APU_SAMPLE_RATE, freq);
+        }
+        self.max_queued = max_queued(config, freq);
+        Ok(())
+    }
+
+    fn underruns(&self) -> Option<u64> {
+        Some(self.underruns.count.load(Ordering::Relaxed) as u64)
+    }
+}
+
+/// Reads the keyboard through SDL.
+///
+/// SDL is managed per thread, so this only sees key presses when used on the thread that created
+/// the SDL renderer (even though it can be moved to other threads).
 pub struct KeyboardInput;
 
 impl JoypadImpl for KeyboardInput {
@@ -0,0 +1,572 @@
+//! Render to a winit window using wgpu (Vulkan, Metal, DX12 or GL, whatever the platform offers)
+//!
+//! The PPU's frame is uploaded into a texture every frame and drawn onto the window by a fragment
+//! shader. The default shader just samples the texture, but it can be replaced with one of the
+//! built-in shaders (`scanlines`, `crt`) or a custom WGSL shader via `Renderer::set_shader` to
+//! implement post-processing effects. CPU filters (`Renderer::set_filter`) are applied before the
+//! frame is uploaded, so both can be combined.
+
+#[macro_use] extern crate log;
+extern crate breeze_backend;
+extern crate pollster;
+extern crate wgpu;
+extern crate winit;
+
+use breeze_backend::{BackendAction, BackendResult, Renderer};
+use breeze_backend::filter::{Filter, FilterStage};
+use breeze_backend::hotkey::HotkeyMap;
+use breeze_backend::pacing::FrameTiming;
+use breeze_backend::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use breeze_backend::viewport::{DisplayOptions, Viewport};
+
+use winit::dpi::PhysicalSize;
+use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
+use winit::event_loop::EventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::platform::pump_events::EventLoopExtPumpEvents;
+use winit::window::{Window, WindowBuilder};
+
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Vertex shader drawing a textured rectangle spanning the whole viewport. Generates its vertices
+/// from the vertex index, so no vertex buffer is needed (draw 4 vertices as a triangle strip).
+const VERTEX_SHADER_SRC: &'static str = r#"
+    struct VertexOutput {
+        @builtin(position) position: vec4<f32>,
+        @location(0) tex_coords: vec2<f32>,
+    };
+
+    @vertex
+    fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+        let uv = vec2<f32>(f32(index & 1u), f32(index >> 1u));
+        var out: VertexOutput;
+        out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+        out.tex_coords = uv;
+        return out;
+    }
+"#;
+
+/// The default fragment shader, which maps the frame onto the viewport without any processing.
+///
+/// Replacement shaders must declare the same bindings and entry point: The frame texture is bound
+/// at `@group(0) @binding(0)`, its (nearest-neighbor) sampler at `@binding(1)`, and `fs_main`
+/// receives the texture coordinates at `@location(0)`.
+pub const DEFAULT_FRAGMENT_SHADER_SRC: &'static str = r#"
+    @group(0) @binding(0) var frame: texture_2d<f32>;
+    @group(0) @binding(1) var frame_sampler: sampler;
+
+    @fragment
+    fn fs_main(@location(0) tex_coords: vec2<f32>) -> @location(0) vec4<f32> {
+        return textureSample(frame, frame_sampler, tex_coords);
+    }
+"#;
+
+/// Darkens the space between the lines of the (unfiltered) frame, like the gaps between the
+/// scanlines of a CRT.
+pub const SCANLINES_SHADER_SRC: &'static str = r#"
+    @group(0) @binding(0) var frame: texture_2d<f32>;
+    @group(0) @binding(1) var frame_sampler: sampler;
+
+    @fragment
+    fn fs_main(@location(0) tex_coords: vec2<f32>) -> @location(0) vec4<f32> {
+        let color = textureSample(frame, frame_sampler, tex_coords);
+        let line = fract(tex_coords.y * f32(textureDimensions(frame).y));
+        let brightness = mix(0.6, 1.0, sin(line * 3.14159265));
+        return vec4<f32>(color.rgb * brightness, 1.0);
+    }
+"#;
+
+/// Imitates a CRT TV: Curved screen, scanlines and an aperture grille mask.
+pub const CRT_SHADER_SRC: &'static str = r#"
+    @group(0) @binding(0) var frame: texture_2d<f32>;
+    @group(0) @binding(1) var frame_sampler: sampler;
+
+    @fragment
+    fn fs_main(@location(0) tex_coords: vec2<f32>) -> @location(0) vec4<f32> {
+        // Barrel distortion
+        let centered = tex_coords * 2.0 - 1.0;
+        let curved = centered * (1.0 + 0.03 * dot(centered, centered));
+        let uv = curved * 0.5 + 0.5;
+
+        let size = vec2<f32>(textureDimensions(frame));
+        let color = textureSample(frame, frame_sampler, uv).rgb;
+
+        let line = fract(uv.y * size.y);
+        let scanline = mix(0.55, 1.0, sin(line * 3.14159265));
+
+        // Each pixel is split into red, green and blue stripes
+        let stripe = u32(floor(uv.x * size.x * 3.0)) % 3u;
+        var mask = vec3<f32>(0.8, 0.8, 0.8);
+        mask[stripe] = 1.2;
+
+        let inside = all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0));
+        return vec4<f32>(select(vec3<f32>(0.0), color * scanline * mask, inside), 1.0);
+    }
+"#;
+
+/// Returns the source of a built-in fragment shader.
+fn builtin_shader(name: &str) -> Option<&'static str> {
+    match name {
+        "default" => Some(DEFAULT_FRAGMENT_SHADER_SRC),
+        "scanlines" => Some(SCANLINES_SHADER_SRC),
+        "crt" => Some(CRT_SHADER_SRC),
+        _ => None,
+    }
+}
+
+pub struct WgpuRenderer {
+    // Field order matters: The surface must be dropped before the window it renders to.
+    surface: wgpu::Surface<'static>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    /// This texture is updated with the (filtered) PPU's data every frame
+    texture: wgpu::Texture,
+    texture_size: (u32, u32),
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    vertex_shader: wgpu::ShaderModule,
+    pipeline: wgpu::RenderPipeline,
+    /// Post-processing applied to the PPU's data before uploading it
+    filter: FilterStage,
+    /// Size of the frames passed to `render`
+    frame_size: (u32, u32),
+    display_opts: DisplayOptions,
+    hotkeys: HotkeyMap,
+    /// Scratch buffer for converting the RGB24 frame to RGBA (wgpu has no 24-bit formats)
+    rgba: Vec<u8>,
+    window: Arc<Window>,
+    event_loop: EventLoop<()>,
+}
+
+impl WgpuRenderer {
+    /// Replaces the fragment shader used to draw the frame (see `DEFAULT_FRAGMENT_SHADER_SRC` for
+    /// what the shader has to look like).
+    ///
+    /// If the shader fails to compile, the error is returned and the old shader is kept.
+    pub fn set_wgsl_shader(&mut self, wgsl: &str) -> BackendResult<()> {
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipeline = build_pipeline(&self.device,
+                                      &self.bind_group_layout,
+                                      &self.vertex_shader,
+                                      wgsl,
+                                      self.config.format);
+        if let Some(e) = pollster::block_on(self.device.pop_error_scope()) {
+            return Err(format!("failed to build shader: {}", e).into());
+        }
+
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    /// Recreates the frame texture (and the bind group referencing it) with a new size.
+    fn resize_texture(&mut self, width: u32, height: u32) {
+        let (texture, bind_group) = create_frame_texture(&self.device,
+                                                         &self.bind_group_layout,
+                                                         &self.sampler,
+                                                         width,
+                                                         height);
+        self.texture = texture;
+        self.bind_group = bind_group;
+        self.texture_size = (width, height);
+        self.rgba = vec![0xff; width as usize * height as usize * 4];
+    }
+
+    fn handle_events(&mut self) -> BackendResult<Vec<BackendAction>> {
+        let mut actions = Vec::new();
+        let mut resized_to = None;
+        let hotkeys = &self.hotkeys;
+
+        self.event_loop.pump_events(Some(Duration::from_secs(0)), |event, _| {
+            let event = match event {
+                Event::WindowEvent { event, .. } => event,
+                _ => return,
+            };
+
+            match event {
+                WindowEvent::CloseRequested => {
+                    info!("quit event -> exiting");
+                    actions.push(BackendAction::Exit);
+                }
+                WindowEvent::Resized(PhysicalSize { width, height }) => {
+                    info!("window resized to {}x{}", width, height);
+                    resized_to = Some((width, height));
+                }
+                WindowEvent::KeyboardInput {
+                    event: KeyEvent {
+                        physical_key: PhysicalKey::Code(code),
+                        state,
+                        repeat: false,
+                        ..
+                    },
+                    ..
+                } => {
+                    let pressed = state == ElementState::Pressed;
+                    actions.extend(hotkeys.handle_key(&key_name(code), pressed));
+                }
+                _ => {}
+            }
+        });
+
+        if let Some((w, h)) = resized_to {
+            self.resize(w, h);
+        }
+
+        Ok(actions)
+    }
+
+    fn resize(&mut self, w: u32, h: u32) {
+        // A size of 0 isn't allowed, we just keep the old configuration until the window is
+        // restored (`render` doesn't draw anything while that is the case).
+        if w != 0 && h != 0 {
+            self.config.width = w;
+            self.config.height = h;
+            self.surface.configure(&self.device, &self.config);
+        }
+    }
+
+    /// Draws the current frame texture to the window.
+    fn draw(&mut self) -> BackendResult<()> {
+        let size = self.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            // Minimized
+            return Ok(());
+        }
+
+        let frame = match self.surface.get_current_texture() {
+            Ok(frame) => frame,
+            Err(wgpu::SurfaceError::Lost) | Err(wgpu::SurfaceError::Outdated) => {
+                // Happens when the window was resized before we got the event. Skip this frame.
+                self.surface.configure(&self.device, &self.config);
+                return Ok(());
+            }
+            Err(wgpu::SurfaceError::Timeout) => {
+                warn!("timeout while acquiring the next frame, skipping it");
+                return Ok(());
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let Viewport { x, y, w, h } = Viewport::for_frame(self.config.width,
+                                                          self.config.height,
+                                                          self.texture_size.0,
+                                                          self.texture_size.1,
+                                                          &self.display_opts);
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("breeze frame encoder"),
+        });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("breeze frame pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            // The viewport's origin is the bottom-left corner (like OpenGL), wgpu's is top-left
+            let top = self.config.height.saturating_sub(y + h);
+            pass.set_viewport(x as f32, top as f32, w as f32, h as f32, 0.0, 1.0);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.draw(0..4, 0..1);
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+        frame.present();
+        Ok(())
+    }
+}
+
+/// Converts a winit key code to the key name used by `HotkeyMap`.
+fn key_name(code: KeyCode) -> String {
+    let name = format!("{:?}", code);
+    match code {
+        KeyCode::Enter => "Return".to_owned(),
+        _ if name.starts_with("Key") => name[3..].to_owned(),
+        _ if name.starts_with("Digit") => name[5..].to_owned(),
+        _ => name,
+    }
+}
+
+fn create_frame_texture(device: &wgpu::Device,
+                        bind_group_layout: &wgpu::BindGroupLayout,
+                        sampler: &wgpu::Sampler,
+                        width: u32,
+                        height: u32) -> (wgpu::Texture, wgpu::BindGroup) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("breeze frame texture"),
+        size: wgpu::Extent3d {
+            width: width,
+            height: height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("breeze bind group"),
+        layout: bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &texture.create_view(&wgpu::TextureViewDescriptor::default())),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    });
+
+    (texture, bind_group)
+}
+
+fn build_pipeline(device: &wgpu::Device,
+                  bind_group_layout: &wgpu::BindGroupLayout,
+                  vertex_shader: &wgpu::ShaderModule,
+                  fragment_src: &str,
+                  format: wgpu::TextureFormat) -> wgpu::RenderPipeline {
+    let fragment_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("breeze fragment shader"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(fragment_src)),
+    });
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("breeze pipeline layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("breeze pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: vertex_shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fragment_shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleStrip,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+impl Renderer for WgpuRenderer {
+    fn create() -> BackendResult<Self> {
+        let event_loop = try!(EventLoop::new());
+        let window = Arc::new(try!(WindowBuilder::new()
+            .with_title("breeze")
+            .with_inner_size(PhysicalSize::new(SCREEN_WIDTH * 3, SCREEN_HEIGHT * 3))
+            .build(&event_loop)));
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let surface = try!(instance.create_surface(window.clone()));
+        let adapter = match pollster::block_on(instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            })) {
+            Some(adapter) => adapter,
+            None => return Err("no suitable graphics adapter found".into()),
+        };
+        info!("using graphics adapter: {:?}", adapter.get_info());
+
+        let (device, queue) = try!(pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("breeze device"),
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::downlevel_webgl2_defaults()
+                    .using_resolution(adapter.limits()),
+            }, None)));
+
+        let size = window.inner_size();
+        let mut config = match surface.get_default_config(&adapter, size.width, size.height) {
+            Some(config) => config,
+            None => return Err("surface isn't supported by the graphics adapter".into()),
+        };
+        config.present_mode = wgpu::PresentMode::Fifo;
+        surface.configure(&device, &config);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("breeze frame sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                label: Some("breeze bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+        let (texture, bind_group) = create_frame_texture(&device,
+                                                         &bind_group_layout,
+                                                         &sampler,
+                                                         SCREEN_WIDTH,
+                                                         SCREEN_HEIGHT);
+
+        let vertex_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("breeze vertex shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(VERTEX_SHADER_SRC)),
+        });
+        let pipeline = build_pipeline(&device,
+                                      &bind_group_layout,
+                                      &vertex_shader,
+                                      DEFAULT_FRAGMENT_SHADER_SRC,
+                                      config.format);
+
+        Ok(WgpuRenderer {
+            surface: surface,
+            device: device,
+            queue: queue,
+            config: config,
+            texture: texture,
+            texture_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            sampler: sampler,
+            bind_group_layout: bind_group_layout,
+            bind_group: bind_group,
+            vertex_shader: vertex_shader,
+            pipeline: pipeline,
+            filter: FilterStage::new(),
+            frame_size: (SCREEN_WIDTH, SCREEN_HEIGHT),
+            display_opts: DisplayOptions::default(),
+            hotkeys: HotkeyMap::default(),
+            rgba: vec![0xff; SCREEN_WIDTH as usize * SCREEN_HEIGHT as usize * 4],
+            window: window,
+            event_loop: event_loop,
+        })
+    }
+
+    fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>> {
+        // the filter determines the texture size, so recreate the texture if that has changed
+        let (frame_w, frame_h) = self.frame_size;
+        let (width, height) = self.filter.output_size(frame_w, frame_h);
+        if self.texture_size != (width, height) {
+            self.resize_texture(width, height);
+        }
+
+        // upload new texture data (the alpha channel is never touched, so it stays opaque)
+        let frame_data = self.filter.process(frame_data, frame_w, frame_h);
+        for (rgba, rgb) in self.rgba.chunks_mut(4).zip(frame_data.chunks(3)) {
+            rgba[..3].copy_from_slice(rgb);
+        }
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &self.rgba,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 4),
+                rows_per_image: Some(height),
+            },
+            wgpu::Extent3d {
+                width: width,
+                height: height,
+                depth_or_array_layers: 1,
+            });
+
+        try!(self.draw());
+        self.handle_events()
+    }
+
+    fn set_rom_title(&mut self, title: &str) {
+        self.window.set_title(title);
+    }
+
+    fn set_filter(&mut self, filter: Option<Box<Filter>>) -> BackendResult<()> {
+        self.filter.set_filter(filter);
+        Ok(())
+    }
+
+    fn set_display_options(&mut self, opts: DisplayOptions) -> BackendResult<()> {
+        self.display_opts = opts;
+        Ok(())
+    }
+
+    /// The surface is reconfigured when the resize event arrives.
+    fn set_window_scale(&mut self, scale: u32) -> BackendResult<()> {
+        let (frame_w, frame_h) = self.frame_size;
+        let size = PhysicalSize::new(frame_w * scale, frame_h * scale);
+        let _ = self.window.request_inner_size(size);
+        Ok(())
+    }
+
+    /// The texture is recreated by the next call to `render`.
+    fn set_frame_size(&mut self, width: u32, height: u32) -> BackendResult<()> {
+        self.frame_size = (width, height);
+        Ok(())
+    }
+
+    fn set_hotkeys(&mut self, hotkeys: HotkeyMap) -> BackendResult<()> {
+        self.hotkeys = hotkeys;
+        Ok(())
+    }
+
+    /// The surface uses FIFO presentation (vsync), so we report the refresh rate of the monitor the
+    /// window is on.
+    fn frame_timing(&self) -> FrameTiming {
+        let millihertz = self.window.current_monitor()
+            .and_then(|monitor| monitor.refresh_rate_millihertz());
+        match millihertz {
+            Some(mhz) => FrameTiming::Vsync(mhz as f64 / 1000.0),
+            None => FrameTiming::Timer,
+        }
+    }
+
+    /// Accepts the built-in shaders `default`, `scanlines` and `crt`, or the source of a WGSL
+    /// fragment shader.
+    fn set_shader(&mut self, shader: &str) -> BackendResult<()> {
+        let source = builtin_shader(shader).unwrap_or(shader);
+        self.set_wgsl_shader(source)
+    }
+}
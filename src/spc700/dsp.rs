@@ -1,6 +1,146 @@
 //! Emulates the DSP used in the APU.
+//!
+//! The DSP produces a stereo sample every 32 SPC700 cycles (32 kHz). For every sample, each voice
+//! decodes its BRR-compressed sample data at its pitch, applies its envelope and volume, and the
+//! mixed result is fed through the echo unit.
+//!
+//! We work on whole samples instead of emulating the DSP's per-cycle register accesses, so
+//! register writes take effect with the next sample. Much of the arithmetic follows blargg's
+//! `SPC_DSP`.
 
-#![allow(dead_code)]    // FIXME Implement the DSP
+#[cfg(feature = "std")] use libsavestate::SaveState;
+#[cfg(feature = "std")] use std::io::{self, Read, Write};
+
+/// Number of SPC700 cycles per DSP sample
+pub const CYCLES_PER_SAMPLE: u8 = 32;
+
+/// The envelope counter counts down from this value (minus 1), wrapping around to it when it
+/// reaches 0
+const COUNTER_RANGE: u16 = 2048 * 5 * 3;
+
+/// Number of samples between envelope/noise updates for each of the 32 rates (rate 0 never
+/// updates)
+static COUNTER_RATES: [u16; 32] = [
+    0, 2048, 1536,
+    1280, 1024, 768,
+    640, 512, 384,
+    320, 256, 192,
+    160, 128, 96,
+    80, 64, 48,
+    40, 32, 24,
+    20, 16, 12,
+    10, 8, 6,
+    5, 4, 3,
+    2,
+    1,
+];
+
+/// Offsets added to the counter for each rate, so that the rates of each group of 3 don't all
+/// update on the same sample
+static COUNTER_OFFSETS: [u16; 32] = [
+    1, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    536, 0, 1040,
+    0,
+    0,
+];
+
+/// Gaussian interpolation table used to resample the decoded BRR samples. The 4 weights applied
+/// to a group of samples always add up to about 2048 (1.0).
+static GAUSS: [i16; 512] = [
+       0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,    0,
+       1,    1,    1,    1,    1,    1,    1,    1,    1,    1,    1,    2,    2,    2,    2,    2,
+       2,    2,    3,    3,    3,    3,    3,    4,    4,    4,    4,    4,    5,    5,    5,    5,
+       6,    6,    6,    6,    7,    7,    7,    8,    8,    8,    9,    9,    9,   10,   10,   10,
+      11,   11,   11,   12,   12,   13,   13,   14,   14,   15,   15,   15,   16,   16,   17,   17,
+      18,   19,   19,   20,   20,   21,   21,   22,   23,   23,   24,   24,   25,   26,   27,   27,
+      28,   29,   29,   30,   31,   32,   32,   33,   34,   35,   36,   36,   37,   38,   39,   40,
+      41,   42,   43,   44,   45,   46,   47,   48,   49,   50,   51,   52,   53,   54,   55,   56,
+      58,   59,   60,   61,   62,   64,   65,   66,   67,   69,   70,   71,   73,   74,   76,   77,
+      78,   80,   81,   83,   84,   86,   87,   89,   90,   92,   94,   95,   97,   99,  100,  102,
+     104,  106,  107,  109,  111,  113,  115,  117,  118,  120,  122,  124,  126,  128,  130,  132,
+     134,  137,  139,  141,  143,  145,  147,  150,  152,  154,  156,  159,  161,  163,  166,  168,
+     171,  173,  175,  178,  180,  183,  186,  188,  191,  193,  196,  199,  201,  204,  207,  210,
+     212,  215,  218,  221,  224,  227,  230,  233,  236,  239,  242,  245,  248,  251,  254,  257,
+     260,  263,  267,  270,  273,  276,  280,  283,  286,  290,  293,  297,  300,  304,  307,  311,
+     314,  318,  321,  325,  328,  332,  336,  339,  343,  347,  351,  354,  358,  362,  366,  370,
+     374,  378,  381,  385,  389,  393,  397,  401,  405,  410,  414,  418,  422,  426,  430,  434,
+     439,  443,  447,  451,  456,  460,  464,  469,  473,  477,  482,  486,  491,  495,  499,  504,
+     508,  513,  517,  522,  527,  531,  536,  540,  545,  550,  554,  559,  563,  568,  573,  577,
+     582,  587,  592,  596,  601,  606,  611,  615,  620,  625,  630,  635,  640,  644,  649,  654,
+     659,  664,  669,  674,  678,  683,  688,  693,  698,  703,  708,  713,  718,  723,  728,  732,
+     737,  742,  747,  752,  757,  762,  767,  772,  777,  782,  787,  792,  797,  802,  806,  811,
+     816,  821,  826,  831,  836,  841,  846,  851,  855,  860,  865,  870,  875,  880,  884,  889,
+     894,  899,  904,  908,  913,  918,  923,  927,  932,  937,  941,  946,  951,  955,  960,  965,
+     969,  974,  978,  983,  988,  992,  997, 1001, 1005, 1010, 1014, 1019, 1023, 1027, 1032, 1036,
+    1040, 1045, 1049, 1053, 1057, 1061, 1066, 1070, 1074, 1078, 1082, 1086, 1090, 1094, 1098, 1102,
+    1106, 1109, 1113, 1117, 1121, 1125, 1128, 1132, 1136, 1139, 1143, 1146, 1150, 1153, 1157, 1160,
+    1164, 1167, 1170, 1174, 1177, 1180, 1183, 1186, 1190, 1193, 1196, 1199, 1202, 1205, 1207, 1210,
+    1213, 1216, 1219, 1221, 1224, 1227, 1229, 1232, 1234, 1237, 1239, 1241, 1244, 1246, 1248, 1251,
+    1253, 1255, 1257, 1259, 1261, 1263, 1265, 1267, 1269, 1270, 1272, 1274, 1275, 1277, 1279, 1280,
+    1282, 1283, 1284, 1286, 1287, 1288, 1290, 1291, 1292, 1293, 1294, 1295, 1296, 1297, 1297, 1298,
+    1299, 1300, 1300, 1301, 1302, 1302, 1303, 1303, 1303, 1304, 1304, 1304, 1304, 1304, 1305, 1305,
+];
+
+/// Clamps a value to the range of an `i16`.
+fn clamp16(value: i32) -> i32 {
+    if value < -0x8000 { -0x8000 } else if value > 0x7fff { 0x7fff } else { value }
+}
+
+/// Returns whether something running at `rate` (0-31) should be updated on this sample, given the
+/// current value of the global counter.
+fn counter_elapsed(counter: u16, rate: u8) -> bool {
+    let rate = rate as usize;
+    rate != 0 && (counter + COUNTER_OFFSETS[rate]) % COUNTER_RATES[rate] == 0
+}
+
+/// State of a voice's envelope
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EnvMode {
+    Attack,
+    Decay,
+    Sustain,
+    /// Entered on key off (or when a sample ends without looping). The envelope decreases by 8
+    /// every sample until it reaches 0.
+    Release,
+}
+
+impl Default for EnvMode {
+    fn default() -> Self { EnvMode::Release }
+}
+
+#[cfg(feature = "std")]
+impl SaveState for EnvMode {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let value: u8 = match *self {
+            EnvMode::Attack => 0,
+            EnvMode::Decay => 1,
+            EnvMode::Sustain => 2,
+            EnvMode::Release => 3,
+        };
+        value.save_state(w)
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        let mut value = 0u8;
+        try!(value.restore_state(r));
+        *self = match value {
+            0 => EnvMode::Attack,
+            1 => EnvMode::Decay,
+            2 => EnvMode::Sustain,
+            3 => EnvMode::Release,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "invalid envelope mode")),
+        };
+        Ok(())
+    }
+}
 
 #[derive(Copy, Clone, Default)]
 struct Voice {
@@ -26,9 +166,177 @@ struct Voice {
     out: u8,
     /// $xf - 8-tap FIR filter coefficients
     fir: u8,
+    /// $xa, $xb and $xe - Unused registers (readable and writable, but have no effect)
+    unused: [u8; 3],
+
+    // Internal state
+
+    /// Address of the BRR block being decoded
+    brr_addr: u16,
+    /// Index of the next sample to decode in the current BRR block (0-15)
+    brr_sample: u8,
+    /// The last 4 decoded samples, oldest first. Like on the hardware, they are stored doubled
+    /// (with the lowest bit clear).
+    buf: [i16; 4],
+    /// Position between `buf[1]` and `buf[2]` (as a 12-bit fraction). Advanced by the pitch every
+    /// sample, a new sample is decoded whenever it wraps around.
+    pos: u16,
+    /// Current envelope level (11 bits)
+    level: u16,
+    /// Envelope level before it was clamped (needed by GAIN mode 7)
+    hidden_level: i16,
+    env_mode: EnvMode,
+    /// Number of samples until a voice that was keyed on starts playing
+    kon_delay: u8,
 }
 
-impl_save_state!(Voice { lvol, rvol, pitch, source, adsr1, adsr2, gain, env, out, fir } ignore {});
+#[cfg(feature = "std")]
+impl_save_state!(Voice { lvol, rvol, pitch, source, adsr1, adsr2, gain, env, out, fir, unused,
+    brr_addr, brr_sample, buf, pos, level, hidden_level, env_mode, kon_delay } ignore {});
+
+impl Voice {
+    /// Restarts the voice at the BRR block at `start`.
+    fn key_on(&mut self, start: u16) {
+        self.brr_addr = start;
+        self.brr_sample = 0;
+        self.buf = [0; 4];
+        self.pos = 0;
+        self.level = 0;
+        self.hidden_level = 0;
+        self.env_mode = EnvMode::Attack;
+        self.kon_delay = 5;
+    }
+
+    /// Decodes the next sample of the current BRR block into `buf`. If that was the last sample of
+    /// the block, the block's header is returned and the caller has to move on to the next block.
+    fn decode_sample(&mut self, ram: &[u8]) -> Option<u8> {
+        let header = ram[self.brr_addr as usize];
+        let data = ram[(self.brr_addr as usize + 1 + self.brr_sample as usize / 2) & 0xffff];
+        let nibble = if self.brr_sample & 1 == 0 { data >> 4 } else { data & 0x0f };
+
+        // Sign-extend the nibble and apply the shift. Shifts above 12 are invalid and produce
+        // either 0 or -2048.
+        let shift = header >> 4;
+        let mut s = (((nibble << 4) as i8) >> 4) as i32;
+        s = if shift <= 12 { (s << shift) >> 1 } else if s < 0 { -2048 } else { 0 };
+
+        // Apply the filter, using the previous 2 samples
+        let p1 = self.buf[3] as i32;
+        let p2 = (self.buf[2] as i32) >> 1;
+        match (header >> 2) & 0x03 {
+            0 => {}
+            1 => {
+                s += p1 >> 1;
+                s += -p1 >> 5;
+            }
+            2 => {
+                s += p1 - p2;
+                s += p2 >> 4;
+                s += (p1 * -3) >> 6;
+            }
+            _ => {
+                s += p1 - p2;
+                s += (p1 * -13) >> 7;
+                s += (p2 * 3) >> 4;
+            }
+        }
+
+        // The result is clamped, then doubled (which can overflow)
+        let s = (clamp16(s) * 2) as i16;
+        self.buf = [self.buf[1], self.buf[2], self.buf[3], s];
+
+        self.brr_sample += 1;
+        if self.brr_sample == 16 {
+            self.brr_sample = 0;
+            Some(header)
+        } else {
+            None
+        }
+    }
+
+    /// Applies gaussian interpolation to the decoded samples, at the current position.
+    fn interpolate(&self) -> i32 {
+        let offset = (self.pos >> 4) as usize & 0xff;
+        let fwd = 255 - offset;
+
+        let mut out = (GAUSS[fwd] as i32 * self.buf[0] as i32) >> 11;
+        out += (GAUSS[fwd + 256] as i32 * self.buf[1] as i32) >> 11;
+        out += (GAUSS[offset + 256] as i32 * self.buf[2] as i32) >> 11;
+        out = out as i16 as i32;
+        out += (GAUSS[offset] as i32 * self.buf[3] as i32) >> 11;
+        clamp16(out) & !1
+    }
+
+    /// Updates the envelope level according to the envelope mode and the `ADSR`/`GAIN` settings.
+    fn run_envelope(&mut self, counter: u16) {
+        if self.env_mode == EnvMode::Release {
+            self.level = self.level.saturating_sub(8);
+            return;
+        }
+
+        let mut env = self.level as i32;
+        let rate;
+        let env_data;
+        if self.adsr1 & 0x80 != 0 {
+            env_data = self.adsr2;
+            if self.env_mode == EnvMode::Attack {
+                rate = (self.adsr1 & 0x0f) * 2 + 1;
+                env += if rate < 31 { 0x20 } else { 0x400 };
+            } else {
+                // Decay and Sustain are exponential
+                env -= 1;
+                env -= env >> 8;
+                rate = if self.env_mode == EnvMode::Decay {
+                    ((self.adsr1 >> 3) & 0x0e) + 0x10
+                } else {
+                    self.adsr2 & 0x1f
+                };
+            }
+        } else {
+            env_data = self.gain;
+            let mode = self.gain >> 5;
+            if mode < 4 {
+                // Direct: Set the level to the value written
+                env = self.gain as i32 * 0x10;
+                rate = 31;
+            } else {
+                rate = self.gain & 0x1f;
+                match mode {
+                    // Linear decrease
+                    4 => env -= 0x20,
+                    // Exponential decrease
+                    5 => {
+                        env -= 1;
+                        env -= env >> 8;
+                    }
+                    // Linear increase
+                    6 => env += 0x20,
+                    // Bent line increase: Slows down above 3/4 of the maximum
+                    _ => env += if self.hidden_level as u16 >= 0x600 { 0x08 } else { 0x20 },
+                }
+            }
+        }
+
+        if self.env_mode == EnvMode::Decay && env >> 8 == (env_data >> 5) as i32 {
+            self.env_mode = EnvMode::Sustain;
+        }
+
+        self.hidden_level = env as i16;
+
+        // Linear decrease can go below 0, which is caught here as well
+        if env < 0 || env > 0x7ff {
+            env = if env < 0 { 0 } else { 0x7ff };
+            if self.env_mode == EnvMode::Attack {
+                self.env_mode = EnvMode::Decay;
+            }
+        }
+
+        // Only the level itself is controlled by the rate
+        if counter_elapsed(counter, rate) {
+            self.level = env as u16;
+        }
+    }
+}
 
 pub struct Dsp {
     voices: [Voice; 8],
@@ -73,10 +381,36 @@ pub struct Dsp {
     echo_buf: u8,
     /// $7d - EDL: Echo delay (ring buffer size) (4 bits only!)
     echo_delay: u8,
+    /// $1d - Unused register (readable and writable, but has no effect)
+    unused_1d: u8,
+
+    // Internal state
+
+    /// Voices that were keyed on since the last sample (written to `KON`)
+    kon_pending: u8,
+    /// Counter controlling the envelope and noise rates. Counts down every sample.
+    counter: u16,
+    /// Noise generator (15-bit LFSR)
+    noise_lfsr: u16,
+    /// Offset of the next sample in the echo buffer (in Bytes)
+    echo_offset: u16,
+    /// Size of the echo buffer in Bytes. Taken from `EDL` when the buffer wraps around.
+    echo_length: u16,
+    /// The last 8 samples read from the echo buffer (oldest first) for the FIR filter
+    echo_hist_l: [i16; 8],
+    echo_hist_r: [i16; 8],
+
+    /// Voices that are mixed into the output (1 bit per voice). This is a debugging aid, not part
+    /// of the emulated hardware.
+    // FIXME Expose each voice's output before mixing so it can be dumped with
+    // `breeze_backend::wav::VoiceWavDump`
+    voice_mask: u8,
 }
 
+#[cfg(feature = "std")]
 impl_save_state!(Dsp { voices, lmvol, rmvol, levol, revol, keyon, keyoff, flags, endx, efb, pmod,
-    noise, echo, srcdir, echo_buf, echo_delay } ignore {});
+    noise, echo, srcdir, echo_buf, echo_delay, unused_1d, kon_pending, counter, noise_lfsr,
+    echo_offset, echo_length, echo_hist_l, echo_hist_r } ignore { voice_mask });
 
 impl Dsp {
     pub fn new() -> Dsp {
@@ -97,9 +431,263 @@ impl Dsp {
             srcdir: 0,
             echo_buf: 0,
             echo_delay: 0,
+            unused_1d: 0,
+            kon_pending: 0,
+            counter: 0,
+            noise_lfsr: 0x4000,
+            echo_offset: 0,
+            echo_length: 0,
+            echo_hist_l: [0; 8],
+            echo_hist_r: [0; 8],
+            voice_mask: 0xff,
+        }
+    }
+
+    /// Looks up the start address (or, if `looped` is set, the loop address) of a sample in the
+    /// source directory.
+    fn source_addr(&self, source: u8, looped: bool, ram: &[u8]) -> u16 {
+        let dir_entry = self.srcdir as usize * 0x100 + source as usize * 4;
+        let addr = (dir_entry + if looped { 2 } else { 0 }) & 0xffff;
+        ram[addr] as u16 | (ram[(addr + 1) & 0xffff] as u16) << 8
+    }
+
+    /// Returns the state of voice `voice` (`0-7`). `ram` is needed to look up the sample
+    /// addresses in the source directory.
+    pub fn voice_state(&self, voice: usize, ram: &[u8]) -> VoiceState {
+        let v = &self.voices[voice];
+        let bit = 1 << voice;
+
+        let envelope = if self.keyoff & bit != 0 || v.env_mode == EnvMode::Release {
+            Envelope::Release
+        } else if v.adsr1 & 0x80 != 0 {
+            Envelope::Adsr {
+                attack: v.adsr1 & 0x0f,
+                decay: (v.adsr1 >> 4) & 0x07,
+                sustain_level: v.adsr2 >> 5,
+                sustain_rate: v.adsr2 & 0x1f,
+            }
+        } else {
+            Envelope::Gain(v.gain)
+        };
+
+        VoiceState {
+            source: v.source,
+            start_addr: self.source_addr(v.source, false, ram),
+            loop_addr: self.source_addr(v.source, true, ram),
+            pitch: v.pitch & 0x3fff,
+            envelope: envelope,
+            env: v.env,
+            out: v.out as i8,
+            lvol: v.lvol,
+            rvol: v.rvol,
+            key_on: self.keyon & bit != 0,
+            ended: self.endx & bit != 0,
+            muted: self.voice_mask & bit == 0,
+        }
+    }
+
+    /// Returns the mask of voices that are mixed into the output (bit N set = voice N audible).
+    pub fn voice_mask(&self) -> u8 { self.voice_mask }
+
+    /// Sets the mask of voices that are mixed into the output. Muting a voice doesn't affect
+    /// emulation (the APU program still sees the voice playing).
+    pub fn set_voice_mask(&mut self, mask: u8) {
+        self.voice_mask = mask;
+    }
+
+    /// Produces the next stereo sample (left, right). Must be called every `CYCLES_PER_SAMPLE`
+    /// SPC700 cycles.
+    ///
+    /// `ram` is the APU RAM the voices read their samples from. The echo unit also writes to it.
+    pub fn sample(&mut self, ram: &mut [u8]) -> (i16, i16) {
+        self.counter = if self.counter == 0 { COUNTER_RANGE - 1 } else { self.counter - 1 };
+
+        if counter_elapsed(self.counter, self.flags & 0x1f) {
+            let feedback = (self.noise_lfsr << 13) ^ (self.noise_lfsr << 14);
+            self.noise_lfsr = (feedback & 0x4000) ^ (self.noise_lfsr >> 1);
+        }
+
+        let kon = self.kon_pending;
+        self.kon_pending = 0;
+        for voice in 0..8 {
+            let bit = 1 << voice;
+            if self.keyoff & bit != 0 {
+                self.voices[voice].env_mode = EnvMode::Release;
+            }
+            if kon & bit != 0 {
+                let start = self.source_addr(self.voices[voice].source, false, ram);
+                self.voices[voice].key_on(start);
+                self.endx &= !bit;
+            }
+        }
+
+        let mut main = [0; 2];
+        let mut echo = [0; 2];
+        // Output of the previous voice, used for pitch modulation
+        let mut prev_output = 0;
+        for voice in 0..8 {
+            let bit = 1 << voice;
+            let output = self.run_voice(voice, prev_output, ram);
+            prev_output = output;
+
+            if self.voice_mask & bit != 0 {
+                let v = &self.voices[voice];
+                let amp = [(output * v.lvol as i32) >> 7, (output * v.rvol as i32) >> 7];
+                for ch in 0..2 {
+                    main[ch] = clamp16(main[ch] + amp[ch]);
+                    if self.echo & bit != 0 {
+                        echo[ch] = clamp16(echo[ch] + amp[ch]);
+                    }
+                }
+            }
+        }
+
+        // Echo: Read the oldest sample from the echo buffer, run the FIR filter over the last 8
+        // samples read and write the new sample (with feedback) in its place
+        let echo_addr = self.echo_buf as usize * 0x100 + self.echo_offset as usize;
+        let echo_in = [
+            self.echo_input(echo_addr, ram, 0),
+            self.echo_input(echo_addr + 2, ram, 1),
+        ];
+
+        let mvol = [self.lmvol as i8 as i32, self.rmvol as i8 as i32];
+        let evol = [self.levol as i8 as i32, self.revol as i8 as i32];
+        let mut out = [0; 2];
+        for ch in 0..2 {
+            let main_out = ((main[ch] * mvol[ch]) >> 7) as i16 as i32;
+            out[ch] = clamp16(main_out + ((echo_in[ch] * evol[ch]) >> 7));
+
+            let feedback = ((echo_in[ch] * self.efb as i8 as i32) >> 7) as i16 as i32;
+            echo[ch] = clamp16(echo[ch] + feedback) & !1;
+        }
+
+        if self.flags & 0x20 == 0 {
+            for ch in 0..2 {
+                let addr = echo_addr + ch * 2;
+                ram[addr & 0xffff] = echo[ch] as u8;
+                ram[(addr + 1) & 0xffff] = (echo[ch] >> 8) as u8;
+            }
+        }
+
+        // The buffer size is only updated when the buffer wraps around
+        self.echo_offset += 4;
+        if self.echo_offset >= self.echo_length {
+            self.echo_offset = 0;
+        }
+        if self.echo_offset == 0 {
+            self.echo_length = (self.echo_delay as u16 & 0x0f) * 0x800;
+        }
+
+        if self.flags & 0x40 != 0 {
+            // Muted
+            (0, 0)
+        } else {
+            (out[0] as i16, out[1] as i16)
         }
     }
 
+    /// Renders `out.len() / 2` stereo samples into `out`, with the left and right channels
+    /// interleaved.
+    pub fn render_audio(&mut self, ram: &mut [u8], out: &mut [i16]) {
+        debug_assert!(out.len() % 2 == 0, "odd number of audio samples requested");
+        for i in 0..out.len() / 2 {
+            let (l, r) = self.sample(ram);
+            out[i * 2] = l;
+            out[i * 2 + 1] = r;
+        }
+    }
+
+    /// Runs a voice for one sample and returns its output (with the envelope applied, but before
+    /// the voice's volume is applied). `prev_output` is the output of the previous voice.
+    fn run_voice(&mut self, voice: usize, prev_output: i32, ram: &[u8]) -> i32 {
+        let bit = 1 << voice;
+
+        if self.flags & 0x80 != 0 {
+            // Soft reset silences all voices
+            let v = &mut self.voices[voice];
+            v.env_mode = EnvMode::Release;
+            v.level = 0;
+        }
+
+        if self.voices[voice].kon_delay > 0 {
+            // The voice was just keyed on and isn't running yet
+            let v = &mut self.voices[voice];
+            v.kon_delay -= 1;
+            v.level = 0;
+            v.hidden_level = 0;
+            v.env = 0;
+            v.out = 0;
+            return 0;
+        }
+
+        let sample = if self.noise & bit != 0 {
+            (self.noise_lfsr << 1) as i16 as i32
+        } else {
+            self.voices[voice].interpolate()
+        };
+        let output = ((sample * self.voices[voice].level as i32) >> 11) & !1;
+
+        // Advance the position, decoding a new sample every time it wraps around
+        let mut pitch = (self.voices[voice].pitch & 0x3fff) as i32;
+        if self.pmod & bit != 0 && voice != 0 {
+            pitch += ((prev_output >> 5) * pitch) >> 10;
+        }
+        let pos = self.voices[voice].pos as i32 + pitch;
+        self.voices[voice].pos = (pos & 0xfff) as u16;
+        for _ in 0..pos >> 12 {
+            if let Some(header) = self.voices[voice].decode_sample(ram) {
+                self.end_block(voice, header, ram);
+            }
+        }
+
+        let counter = self.counter;
+        let v = &mut self.voices[voice];
+        v.run_envelope(counter);
+        v.env = (v.level >> 4) as u8;
+        v.out = (output >> 8) as u8;
+        output
+    }
+
+    /// Moves a voice on to the next BRR block after the block with the given `header` was fully
+    /// decoded.
+    fn end_block(&mut self, voice: usize, header: u8, ram: &[u8]) {
+        if header & 0x01 != 0 {
+            // End of the sample: Continue at the loop address. If the loop flag is clear, the
+            // voice is silenced, but it keeps running.
+            self.endx |= 1 << voice;
+            let loop_addr = self.source_addr(self.voices[voice].source, true, ram);
+            let v = &mut self.voices[voice];
+            v.brr_addr = loop_addr;
+            if header & 0x02 == 0 {
+                v.env_mode = EnvMode::Release;
+                v.level = 0;
+            }
+        } else {
+            let v = &mut self.voices[voice];
+            v.brr_addr = v.brr_addr.wrapping_add(9);
+        }
+    }
+
+    /// Reads a sample of channel `ch` (0 = left, 1 = right) from the echo buffer at `addr` into
+    /// the FIR history and returns the filtered echo input.
+    fn echo_input(&mut self, addr: usize, ram: &[u8], ch: usize) -> i32 {
+        let raw = ram[addr & 0xffff] as u16 | (ram[(addr + 1) & 0xffff] as u16) << 8;
+        let hist = if ch == 0 { &mut self.echo_hist_l } else { &mut self.echo_hist_r };
+        for i in 0..7 {
+            hist[i] = hist[i + 1];
+        }
+        hist[7] = (raw as i16) >> 1;
+
+        // The first 7 taps are summed with 16-bit wraparound, only the last one is clamped
+        let mut sum = 0;
+        for i in 0..7 {
+            sum += (hist[i] as i32 * self.voices[i].fir as i8 as i32) >> 6;
+        }
+        let mut out = sum as i16 as i32;
+        out += ((hist[7] as i32 * self.voices[7].fir as i8 as i32) >> 6) as i16 as i32;
+        clamp16(out) & !1
+    }
+
     /// Load a value from a DSP register
     pub fn load(&mut self, mut reg: u8) -> u8 {
         reg &= 0x7f;
@@ -119,6 +707,7 @@ impl Dsp {
             0x5d => self.srcdir,
             0x6d => self.echo_buf,
             0x7d => self.echo_delay,
+            0x1d => self.unused_1d,
             _ => {
                 let voice = &self.voices[(reg >> 4) as usize];
                 match reg & 0x0f {
@@ -132,24 +721,31 @@ impl Dsp {
                     0x07 => voice.gain,
                     0x08 => voice.env,
                     0x09 => voice.out,
+                    0x0a => voice.unused[0],
+                    0x0b => voice.unused[1],
+                    0x0e => voice.unused[2],
                     0x0f => voice.fir,
-                    _ => panic!("invalid DSP read from ${:02X}", reg),
+                    _ => unreachable!(),
                 }
             }
         }
     }
 
-    /// Store a value in a DSP register
+    /// Store a value in a DSP register. `$80-$ff` are read-only mirrors of `$00-$7f`.
     pub fn store(&mut self, reg: u8, value: u8) {
         match reg {
+            0x80 ... 0xff => {}
             0x0c => self.lmvol = value,
             0x1c => self.rmvol = value,
             0x2c => self.levol = value,
             0x3c => self.revol = value,
-            0x4c => self.keyon = value,
+            0x4c => {
+                self.keyon = value;
+                self.kon_pending |= value;
+            }
             0x5c => self.keyoff = value,
             0x6c => self.flags = value,
-            0x7c => self.endx = value,
+            0x7c => self.endx = 0,     // Any write clears all flags
             0x0d => self.efb = value,
             0x2d => self.pmod = value,
             0x3d => self.noise = value,
@@ -157,6 +753,7 @@ impl Dsp {
             0x5d => self.srcdir = value,
             0x6d => self.echo_buf = value,
             0x7d => self.echo_delay = value,
+            0x1d => self.unused_1d = value,
             _ => {
                 let voice = &mut self.voices[(reg >> 4) as usize];
                 match reg & 0x0f {
@@ -170,26 +767,209 @@ impl Dsp {
                     0x07 => voice.gain = value,
                     0x08 => once!(warn!("ignoring write to envelope value")),
                     0x09 => once!(warn!("ignoring write to sample value")),
+                    0x0a => voice.unused[0] = value,
+                    0x0b => voice.unused[1] = value,
+                    0x0e => voice.unused[2] = value,
                     0x0f => voice.fir = value,
-                    _ => panic!("invalid DSP write to ${:02X}", reg),
+                    _ => unreachable!(),
                 }
             }
         }
     }
 }
 
-enum BrrLoop {
-    /// Continue playing with the next BRR block
-    Continue,
-    /// Jump to the block's loop address and set the ENDx flag for this voice
-    Loop,
-    /// Jump to the loop address, set ENDx, enter `Release`, set env to $000
+/// Envelope mode of a voice
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Envelope {
+    /// ADSR envelope (`VxADSR1` bit 7 set), with the raw rates and sustain level
+    Adsr {
+        /// Attack rate (0-15)
+        attack: u8,
+        /// Decay rate (0-7)
+        decay: u8,
+        /// Sustain level (0-7)
+        sustain_level: u8,
+        /// Sustain rate (0-31)
+        sustain_rate: u8,
+    },
+    /// Envelope controlled by `VxGAIN` (raw register value)
+    Gain(u8),
+    /// The voice was keyed off and fades out
     Release,
 }
 
-struct BrrBlock {
-    /// 0-12 where 0 = silent and 12 = loudest
-    shift: u8,
-    /// 0-3, 0 = no filter
-    filter: u8,
+/// Snapshot of a voice's state, for debugging and visualization
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VoiceState {
+    /// Source number (index into the source directory)
+    pub source: u8,
+    /// Start address of the BRR sample, from the source directory
+    pub start_addr: u16,
+    /// Loop address of the BRR sample, from the source directory
+    pub loop_addr: u16,
+    /// Pitch (14 bits, `$1000` plays the sample at 32 kHz)
+    pub pitch: u16,
+    pub envelope: Envelope,
+    /// Current envelope value (`VxENVX`)
+    pub env: u8,
+    /// Current output level (`VxOUTX`)
+    pub out: i8,
+    pub lvol: i8,
+    pub rvol: i8,
+    /// Key on flag (`KON`)
+    pub key_on: bool,
+    /// Set when the sample reached its end block (`ENDX`)
+    pub ended: bool,
+    /// Muted for debugging (not mixed into the output)
+    pub muted: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counter_elapsed, Dsp, EnvMode, Voice, COUNTER_RANGE, COUNTER_RATES};
+
+    /// Address of the BRR block in `brr_ram`
+    const BLOCK: u16 = 0x0100;
+
+    /// Creates APU RAM containing a single BRR block with the given header and 16 nibbles.
+    fn brr_ram(header: u8, nibbles: [u8; 16]) -> Vec<u8> {
+        let mut ram = vec![0; 0x10000];
+        ram[BLOCK as usize] = header;
+        for i in 0..8 {
+            ram[BLOCK as usize + 1 + i] = nibbles[i * 2] << 4 | nibbles[i * 2 + 1];
+        }
+        ram
+    }
+
+    /// Decodes the first `count` samples of the block, starting with the given previous samples
+    /// (doubled, like in `Voice::buf`). Returns the decoded samples (also doubled).
+    fn decode(header: u8, nibbles: [u8; 16], prev: (i16, i16), count: usize) -> Vec<i16> {
+        let ram = brr_ram(header, nibbles);
+        let mut voice = Voice::default();
+        voice.brr_addr = BLOCK;
+        voice.buf = [0, 0, prev.0, prev.1];
+        (0..count).map(|_| {
+            voice.decode_sample(&ram);
+            voice.buf[3]
+        }).collect()
+    }
+
+    #[test]
+    fn brr_block_end() {
+        let ram = brr_ram(0xc3, [0; 16]);
+        let mut voice = Voice::default();
+        voice.brr_addr = BLOCK;
+        for _ in 0..15 {
+            assert_eq!(voice.decode_sample(&ram), None);
+        }
+        assert_eq!(voice.decode_sample(&ram), Some(0xc3));
+        assert_eq!(voice.brr_sample, 0);
+    }
+
+    #[test]
+    fn brr_filter0_shift() {
+        let mut nibbles = [0; 16];
+        nibbles[..4].copy_from_slice(&[0x1, 0xf, 0x7, 0x8]);
+        // Shift 12 is the largest valid one
+        assert_eq!(decode(0xc0, nibbles, (0, 0), 4), [4096, -4096, 28672, -32768]);
+        // Shift 0 loses the lowest bit
+        assert_eq!(decode(0x00, [0x1, 0x2, 0xe, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], (0, 0), 3),
+                   [0, 2, -2]);
+    }
+
+    #[test]
+    fn brr_invalid_shift() {
+        // Shifts above 12 produce 0 for positive and -2048 for negative nibbles
+        for header in &[0xd0, 0xe0, 0xf0] {
+            let mut nibbles = [0; 16];
+            nibbles[..3].copy_from_slice(&[0x7, 0x8, 0xf]);
+            assert_eq!(decode(*header, nibbles, (0, 0), 3), [0, -4096, -4096]);
+        }
+    }
+
+    #[test]
+    fn brr_filters() {
+        // Decode a 0 nibble with shift 0, so only the filter contributes. The previous samples
+        // are 200 and 500 (stored doubled).
+        let prev = (400, 1000);
+        assert_eq!(decode(0x00, [0; 16], prev, 1), [0]);
+        assert_eq!(decode(0x04, [0; 16], prev, 1), [2 * 468]);
+        assert_eq!(decode(0x08, [0; 16], prev, 1), [2 * 765]);
+        assert_eq!(decode(0x0c, [0; 16], prev, 1), [2 * 735]);
+    }
+
+    #[test]
+    fn brr_clamp_and_overflow() {
+        let mut nibbles = [0; 16];
+        nibbles[0] = 0x7;
+        // Filter 1 with a large previous sample: The sum fits in 16 bits, but doubling it wraps
+        // around like on the hardware
+        assert_eq!(decode(0xc4, nibbles, (0, 30000), 1), [(28398 * 2) as i16]);
+        // Filter 2 overshoots and is clamped before doubling
+        assert_eq!(decode(0xc8, nibbles, (0, 32766), 1), [(0x7fff * 2) as i16]);
+    }
+
+    #[test]
+    fn counter_rates() {
+        // Over a full counter period, every rate fires a fixed number of times
+        for rate in 0..32 {
+            let fired = (0..COUNTER_RANGE).filter(|&c| counter_elapsed(c, rate)).count();
+            let expected = match rate {
+                0 => 0,
+                _ => (COUNTER_RANGE / COUNTER_RATES[rate as usize]) as usize,
+            };
+            assert_eq!(fired, expected, "rate {}", rate);
+        }
+    }
+
+    #[test]
+    fn adsr_attack_and_decay() {
+        let mut voice = Voice::default();
+        voice.adsr1 = 0x8f;     // ADSR, fastest attack (rate 31), decay rate 0
+        voice.adsr2 = 0xe0;     // Sustain level 7
+        voice.env_mode = EnvMode::Attack;
+
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x400);
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x7ff);
+        assert_eq!(voice.env_mode, EnvMode::Decay);
+        // Decay is exponential, and sustain level 7 is reached right away
+        voice.run_envelope(0);
+        assert_eq!(voice.env_mode, EnvMode::Sustain);
+    }
+
+    #[test]
+    fn gain_modes() {
+        let mut voice = Voice::default();
+        voice.env_mode = EnvMode::Attack;
+        voice.gain = 0x40;      // Direct
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x400);
+
+        voice.gain = 0x9f;      // Linear decrease at rate 31
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x3e0);
+
+        voice.gain = 0xdf;      // Linear increase at rate 31
+        voice.run_envelope(0);
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x420);
+
+        voice.env_mode = EnvMode::Release;
+        voice.run_envelope(0);
+        assert_eq!(voice.level, 0x418);
+    }
+
+    #[test]
+    fn unused_registers() {
+        let mut dsp = Dsp::new();
+        for &reg in &[0x0a, 0x3b, 0x7e, 0x1d] {
+            dsp.store(reg, reg ^ 0xff);
+            assert_eq!(dsp.load(reg), reg ^ 0xff);
+            // `$80-$ff` mirror the registers, but can't be written
+            dsp.store(reg | 0x80, 0);
+            assert_eq!(dsp.load(reg | 0x80), reg ^ 0xff);
+        }
+    }
 }
@@ -9,30 +9,43 @@
 
 #![deny(warnings)]
 #![deny(unused_import_braces, unused_qualifications, unused_extern_crates)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(not(feature = "std"))] #[macro_use] extern crate alloc;
+// Lets the rest of the crate (and `byte_array!`) use `std::` paths for what's in `core`
+#[cfg(not(feature = "std"))] extern crate core as std;
+#[cfg(not(feature = "std"))] use alloc::vec::Vec;
 #[macro_use] extern crate log;
 #[macro_use] #[no_link] extern crate byte_array;
-#[macro_use] extern crate libsavestate;
+#[cfg(feature = "std")] #[macro_use] extern crate libsavestate;
 
 #[macro_use] mod once;
 mod addressing;
 mod dsp;
 mod ipl;
+pub mod port_log;
 mod statusreg;
 mod timer;
 
 use addressing::AddressingMode;
-use dsp::Dsp;
+pub use dsp::{Dsp, Envelope, VoiceState, CYCLES_PER_SAMPLE};
 use ipl::IPL_ROM;
+use port_log::PortLog;
 use statusreg::StatusReg;
 use timer::Timer;
 
 
 const RAM_SIZE: usize = 65536;
+#[cfg(feature = "std")]
 byte_array!(Ram[RAM_SIZE] with u16 indexing, save state please);
+#[cfg(not(feature = "std"))]
+byte_array!(Ram[RAM_SIZE] with u16 indexing please);
 
 const RESET_VEC: u16 = 0xFFFE;
 
+/// Number of DSP voices
+pub const VOICES: usize = 8;
+
 /// The SPC700 is an 8-bit processor with a 16-bit address space.
 ///
 /// It has 64 KB of RAM shared with the DSP. The last 64 Bytes in its address space are mapped to
@@ -55,6 +68,10 @@ pub struct Spc700 {
     timers: [Timer; 3],
 
     dsp: Dsp,
+    /// SPC700 cycles since the DSP produced the last sample
+    dsp_cy: u8,
+    /// Samples produced by the DSP since the last call to `clear_audio`
+    audio: Vec<(i16, i16)>,
 
     a: u8,
     x: u8,
@@ -66,10 +83,13 @@ pub struct Spc700 {
     cy: u8,
 
     pub trace: bool,
+    /// Log of port accesses. Not part of the emulated state.
+    port_log: Option<PortLog>,
 }
 
-impl_save_state!(Spc700 { mem, ipl_rom_mapped, reg_dsp_addr, io_vals, timers, dsp, a, x, y, sp, pc,
-    psw } ignore { cy, trace });
+#[cfg(feature = "std")]
+impl_save_state!(Spc700 { mem, ipl_rom_mapped, reg_dsp_addr, io_vals, timers, dsp, dsp_cy, a, x, y,
+    sp, pc, psw } ignore { audio, cy, trace, port_log });
 
 impl Default for Spc700 {
     fn default() -> Self {
@@ -84,6 +104,8 @@ impl Default for Spc700 {
             io_vals: [0; 4],
             timers: [Timer::new(); 3],
             dsp: Dsp::new(),
+            dsp_cy: 0,
+            audio: Vec::new(),
             a: 0,
             x: 0,
             y: 0,
@@ -92,6 +114,7 @@ impl Default for Spc700 {
             psw: StatusReg(0),  // FIXME is 0 correct?
             cy: 0,
             trace: false,
+            port_log: None,
         }
     }
 }
@@ -103,15 +126,83 @@ impl Spc700 {
     pub fn store_port(&mut self, port: u8, value: u8) {
         debug_assert!(port < 4);
         self.io_vals[port as usize] = value;
+        if let Some(ref mut log) = self.port_log {
+            log.record_cpu(true, port, value, self.pc);
+        }
+    }
+
+    /// Get the 64 KB of APU RAM.
+    pub fn ram(&self) -> &[u8] {
+        &*self.mem
+    }
+
+    /// Get mutable access to the 64 KB of APU RAM. Useful for filling it with an initial pattern.
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut *self.mem
+    }
+
+    /// Returns the CPU registers as `(name, value)` pairs.
+    pub fn registers(&self) -> [(&'static str, u16); 6] {
+        [("a", self.a as u16), ("x", self.x as u16), ("y", self.y as u16),
+         ("sp", self.sp as u16), ("pc", self.pc), ("psw", self.psw.0 as u16)]
+    }
+
+    /// Returns the state of DSP voice `voice` (`0-7`).
+    pub fn voice_state(&self, voice: usize) -> VoiceState {
+        self.dsp.voice_state(voice, &*self.mem)
+    }
+
+    /// Returns whether the given DSP voice is muted.
+    pub fn voice_muted(&self, voice: usize) -> bool {
+        self.dsp.voice_mask() & (1 << voice) == 0
+    }
+
+    /// Mutes or unmutes a DSP voice. Muted voices keep playing (as far as the APU program can
+    /// tell), but aren't mixed into the output.
+    pub fn set_voice_muted(&mut self, voice: usize, muted: bool) {
+        let mask = self.dsp.voice_mask();
+        self.dsp.set_voice_mask(if muted { mask & !(1 << voice) } else { mask | 1 << voice });
+    }
+
+    /// Mutes all voices except `voice`, or unmutes all voices if `voice` is `None`.
+    pub fn solo_voice(&mut self, voice: Option<usize>) {
+        self.dsp.set_voice_mask(match voice {
+            Some(voice) => 1 << voice,
+            None => 0xff,
+        });
+    }
+
+    /// Returns the stereo samples (left, right) produced by the DSP since the last call to
+    /// `clear_audio`. The DSP runs at 32 kHz.
+    pub fn audio(&self) -> &[(i16, i16)] { &self.audio }
+
+    /// Discards the samples returned by `audio`. Should be called after they were passed on, or
+    /// they pile up.
+    pub fn clear_audio(&mut self) {
+        self.audio.clear();
     }
 
     /// Load a byte from an IO port
     pub fn read_port(&mut self, port: u8) -> u8 {
         debug_assert!(port < 4);
         let val = self.mem[0xf4 + port as u16];
+        if let Some(ref mut log) = self.port_log {
+            log.record_cpu(false, port, val, self.pc);
+        }
         val
     }
 
+    /// Starts (or, when passing `None`, stops) logging port accesses.
+    pub fn set_port_log(&mut self, log: Option<PortLog>) {
+        self.port_log = log;
+    }
+
+    /// Returns the port access log, if enabled.
+    pub fn port_log(&self) -> Option<&PortLog> { self.port_log.as_ref() }
+
+    /// Returns the port access log mutably (eg. to clear it), if enabled.
+    pub fn port_log_mut(&mut self) -> Option<&mut PortLog> { self.port_log.as_mut() }
+
     fn load(&mut self, addr: u16) -> u8 {
         match addr {
             0xf0 => panic!("undocumented register unimplemented"),
@@ -126,7 +217,13 @@ impl Spc700 {
                 panic!("APU attempted read from write-only register ${:02X}", addr),
             0xf2 => self.reg_dsp_addr,
             0xf3 => self.dsp.load(self.reg_dsp_addr),
-            0xf4 ... 0xf7 => self.io_vals[addr as usize - 0xf4],
+            0xf4 ... 0xf7 => {
+                let val = self.io_vals[addr as usize - 0xf4];
+                if let Some(ref mut log) = self.port_log {
+                    log.record_apu(false, addr as u8 - 0xf4, val);
+                }
+                val
+            }
             0xfd => {
                 let val = self.timers[0].val;
                 self.timers[0].val = 0;
@@ -182,6 +279,11 @@ impl Spc700 {
             0xfa => self.timers[0].div = val,
             0xfb => self.timers[1].div = val,
             0xfc => self.timers[2].div = val,
+            0xf4 ... 0xf7 => {
+                if let Some(ref mut log) = self.port_log {
+                    log.record_apu(true, addr as u8 - 0xf4, val);
+                }
+            }
             0xfd ... 0xff => panic!("APU attempted to write to read-only register ${:04X}", addr),
             // NB: Stores to 0xf4 - 0xf9 are just sent to RAM
             _ => {}
@@ -245,39 +347,42 @@ impl Spc700 {
         ];
 
         let pc = self.pc;
+        if let Some(ref mut log) = self.port_log {
+            log.start_instruction(pc);
+        }
 
         macro_rules! e {
             ($e:expr) => ($e)
         }
         macro_rules! instr {
             ( _ $name:ident ) => {{
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, stringify!($name));
                 }
                 self.$name()
             }};
             ( $s:tt $name:ident ) => {{
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, e!($s));
                 }
                 self.$name()
             }};
             ( _ $name:ident ($arg:tt) ) => {{
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, concat!(stringify!($name), " ", $arg));
                 }
                 self.$name(e!($arg))
             }};
             ( _ $name:ident ($arg:tt) $am:ident ) => {{
                 let am = self.$am();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(concat!(stringify!($name), " {}.", $arg), am));
                 }
                 self.$name(e!($arg), am)
             }};
             ( $s:tt $name:ident ($arg:tt) $am:ident ) => {{
                 let am = self.$am();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(e!($s), am));
                 }
                 self.$name(e!($arg), am)
@@ -285,7 +390,7 @@ impl Spc700 {
             ( _ $name:ident ($arg:tt) $am:ident $am2:ident ) => {{
                 let am = self.$am();
                 let am2 = self.$am2();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc,
                         &format!(concat!(stringify!($name), " {}.", $arg, ", {}"), am, am2));
                 }
@@ -294,21 +399,21 @@ impl Spc700 {
             ( $s:tt $name:ident ($arg:tt) $am:ident $am2:ident ) => {{
                 let am = self.$am();
                 let am2 = self.$am2();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(e!($s), am, am2));
                 }
                 self.$name(e!($arg), am, am2)
             }};
             ( _ $name:ident $am:ident ) => {{
                 let am = self.$am();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(concat!(stringify!($name), " {}"), am));
                 }
                 self.$name(am)
             }};
             ( $s:tt $name:ident $am:ident ) => {{
                 let am = self.$am();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(e!($s), am));
                 }
                 self.$name(am)
@@ -316,7 +421,7 @@ impl Spc700 {
             ( _ $name:ident $am:ident $am2:ident ) => {{
                 let am = self.$am();
                 let am2 = self.$am2();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(concat!(stringify!($name), " {1}, {0}"), am, am2));
                 }
                 self.$name(am, am2)
@@ -324,7 +429,7 @@ impl Spc700 {
             ( $s:tt $name:ident $am:ident $am2:ident ) => {{
                 let am = self.$am();
                 let am2 = self.$am2();
-                if log_enabled!(Trace) && self.trace {
+                if self.trace && log_enabled!(Trace) {
                     self.trace_op(pc, &format!(e!($s), am, am2));
                 }
                 self.$name(am, am2)
@@ -586,6 +691,16 @@ impl Spc700 {
         self.timers[0].update(128, self.cy);
         self.timers[1].update(128, self.cy);
         self.timers[2].update(16, self.cy);
+        if let Some(ref mut log) = self.port_log {
+            log.add_cycles(self.cy);
+        }
+
+        self.dsp_cy += self.cy;
+        while self.dsp_cy >= CYCLES_PER_SAMPLE {
+            self.dsp_cy -= CYCLES_PER_SAMPLE;
+            let sample = self.dsp.sample(&mut *self.mem);
+            self.audio.push(sample);
+        }
         self.cy
     }
 
@@ -0,0 +1,138 @@
+//! Logging of the communication between the main CPU and the APU
+//!
+//! The CPU and the SPC700 talk through 4 ports (`$2140-$2143` on the CPU side, `$f4-$f7` on the
+//! APU side). Sound drivers implement handshakes on top of them, and when these break, the game
+//! usually hangs waiting for an answer. A `PortLog` records every port access from both sides, so
+//! the conversation can be reconstructed.
+//!
+//! Timestamps are APU cycles since the log was enabled. Since the APU is run in bursts to catch up
+//! with the CPU, CPU accesses may be off by the length of a burst.
+
+#[cfg(not(feature = "std"))] use alloc::collections::VecDeque;
+#[cfg(feature = "std")] use std::collections::VecDeque;
+#[cfg(feature = "std")] use std::io::{self, Write};
+
+/// Default number of entries kept
+const DEFAULT_CAPACITY: usize = 65536;
+
+/// Which processor accessed a port
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Accessor {
+    /// The main CPU (`$2140-$2143`)
+    Cpu,
+    /// The SPC700 (`$f4-$f7`)
+    Apu,
+}
+
+/// A port access
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PortAccess {
+    /// APU cycle at which the access happened (see the module documentation)
+    pub cycle: u64,
+    pub accessor: Accessor,
+    /// `true` for writes, `false` for reads
+    pub write: bool,
+    /// Port number (0-3)
+    pub port: u8,
+    pub value: u8,
+    /// SPC700 program counter: For APU accesses, the address of the instruction accessing the
+    /// port. For CPU accesses, the address of the next instruction the APU will execute.
+    pub apu_pc: u16,
+    /// Number of consecutive identical reads this entry stands for (1 for writes). Polling loops
+    /// would otherwise flood the log.
+    pub count: u32,
+}
+
+/// Records port accesses. Only the most recent accesses are kept.
+#[derive(Clone, Debug)]
+pub struct PortLog {
+    entries: VecDeque<PortAccess>,
+    capacity: usize,
+    /// APU cycles since the log was created
+    cycle: u64,
+    /// Address of the instruction currently executed by the SPC700
+    instr_pc: u16,
+}
+
+impl Default for PortLog {
+    fn default() -> Self { Self::with_capacity(DEFAULT_CAPACITY) }
+}
+
+impl PortLog {
+    /// Creates a log that keeps the last 65536 entries.
+    pub fn new() -> Self { Self::default() }
+
+    /// Creates a log that keeps the last `capacity` entries.
+    pub fn with_capacity(capacity: usize) -> Self {
+        PortLog {
+            entries: VecDeque::new(),
+            capacity: capacity,
+            cycle: 0,
+            instr_pc: 0,
+        }
+    }
+
+    /// Returns the recorded accesses, oldest first.
+    pub fn entries(&self) -> &VecDeque<PortAccess> { &self.entries }
+
+    pub fn clear(&mut self) { self.entries.clear() }
+
+    /// Called by the SPC700 before it executes the instruction at `pc`.
+    pub fn start_instruction(&mut self, pc: u16) { self.instr_pc = pc }
+
+    /// Called by the SPC700 after executing an instruction that took `cy` cycles.
+    pub fn add_cycles(&mut self, cy: u8) { self.cycle += cy as u64 }
+
+    /// Records an access by the SPC700.
+    pub fn record_apu(&mut self, write: bool, port: u8, value: u8) {
+        let pc = self.instr_pc;
+        self.record(Accessor::Apu, write, port, value, pc);
+    }
+
+    /// Records an access by the main CPU. `apu_pc` is the SPC700's program counter.
+    pub fn record_cpu(&mut self, write: bool, port: u8, value: u8, apu_pc: u16) {
+        self.record(Accessor::Cpu, write, port, value, apu_pc);
+    }
+
+    fn record(&mut self, accessor: Accessor, write: bool, port: u8, value: u8, apu_pc: u16) {
+        if !write {
+            if let Some(last) = self.entries.back_mut() {
+                if !last.write && last.accessor == accessor && last.port == port &&
+                   last.value == value && (accessor == Accessor::Cpu || last.apu_pc == apu_pc) {
+                    last.count = last.count.saturating_add(1);
+                    return;
+                }
+            }
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(PortAccess {
+            cycle: self.cycle,
+            accessor: accessor,
+            write: write,
+            port: port,
+            value: value,
+            apu_pc: apu_pc,
+            count: 1,
+        });
+    }
+
+    /// Writes the log as CSV, with a header line.
+    #[cfg(feature = "std")]
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(writeln!(out, "cycle,accessor,access,port,value,apu_pc,count"));
+        for entry in &self.entries {
+            try!(writeln!(out, "{},{},{},{},${:02X},${:04X},{}",
+                entry.cycle,
+                match entry.accessor { Accessor::Cpu => "cpu", Accessor::Apu => "apu" },
+                if entry.write { "write" } else { "read" },
+                entry.port,
+                entry.value,
+                entry.apu_pc,
+                entry.count));
+        }
+        Ok(())
+    }
+}
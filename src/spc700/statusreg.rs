@@ -9,6 +9,7 @@ const INTERRUPT_FLAG: u8   = 0x04;  // Interrupt enable flag, not disable
 const ZERO_FLAG: u8        = 0x02;
 const CARRY_FLAG: u8       = 0x01;
 
+#[cfg(feature = "std")]
 impl_save_state_for_newtype!(StatusReg);
 
 impl StatusReg {
@@ -15,6 +15,7 @@ pub struct Timer {
     stage2: u16,
 }
 
+#[cfg(feature = "std")]
 impl_save_state!(Timer { div, val, enabled, stage1, stage2 } ignore {});
 
 impl Default for Timer {
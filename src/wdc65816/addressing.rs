@@ -107,7 +107,7 @@ impl AddressingMode {
             AddressingMode::Immediate(_) => panic!("loadb on 16-bit immediate"),
             AddressingMode::Immediate8(val) => val,
             _ => {
-                let (bank, addr) = self.address(cpu);
+                let (bank, addr) = self.read_address(cpu);
                 cpu.loadb(bank, addr)
             }
         }
@@ -117,7 +117,7 @@ impl AddressingMode {
             AddressingMode::Immediate(val) => val,
             AddressingMode::Immediate8(_) => panic!("loadw on 8-bit immediate"),
             _ => {
-                let (bank, addr) = self.address(cpu);
+                let (bank, addr) = self.read_address(cpu);
                 cpu.loadw(bank, addr)
             }
         }
@@ -132,13 +132,34 @@ impl AddressingMode {
         cpu.storew(bank, addr, value);
     }
 
+    /// Computes the effective address of a read. Indexed reads through `a,x`, `a,y` and `(d),y`
+    /// take an extra cycle when the index crosses a page boundary or the index registers are 16
+    /// bits wide. Writes and read-modify-write instructions always take that cycle, so it's part
+    /// of their base cycle count instead.
+    fn read_address<M: Mem>(&self, cpu: &mut Cpu<M>) -> (u8, u16) {
+        use self::AddressingMode::*;
+
+        let (bank, addr) = self.address(cpu);
+        let index = match *self {
+            AbsIndexedX(_) => Some(cpu.x),
+            AbsIndexedY(_) | DirectIndirectIndexed(_) => Some(cpu.y),
+            _ => None,
+        };
+        if let Some(index) = index {
+            // With 8-bit index registers, the low byte wraps around iff a page was crossed
+            if !cpu.p.small_index() || addr & 0xff < index { cpu.cy += 1 }
+        }
+
+        (bank, addr)
+    }
+
     /// Computes the effective address as a bank-address-tuple. Panics if the addressing mode is
     /// immediate. For jumps, the effective address is the jump target.
+    ///
+    /// This adds the cycle taken by direct page accesses when the low byte of D isn't zero.
     pub fn address<M: Mem>(&self, cpu: &mut Cpu<M>) -> (u8, u16) {
         use self::AddressingMode::*;
 
-        // FIXME is something here dependant on register sizes?
-        // -> Yes, the cycle count. This causes bad timing, fix it!
         // FIXME Use next bank on some address overflows
 
         match *self {
@@ -149,7 +170,6 @@ impl AddressingMode {
                 (bank, addr)
             }
             AbsLongIndexedX(bank, addr) => {
-                if !cpu.p.small_index() { cpu.cy += 1 }
                 let a = ((bank as u32) << 16) | addr as u32;
                 let eff_addr = a + cpu.x as u32;
                 assert!(eff_addr & 0xff000000 == 0, "address overflow");
@@ -158,11 +178,9 @@ impl AddressingMode {
                 (bank as u8, addr)
             }
             AbsIndexedX(offset) => {
-                if !cpu.p.small_index() { cpu.cy += 1 }
                 (cpu.dbr, offset + cpu.x)
             }
             AbsIndexedY(offset) => {
-                if !cpu.p.small_index() { cpu.cy += 1 }
                 (cpu.dbr, offset + cpu.y)
             }
             AbsIndexedIndirect(addr_ptr) => {
@@ -191,12 +209,10 @@ impl AddressingMode {
             }
             DirectIndexedX(offset) => {
                 if cpu.d & 0xff != 0 { cpu.cy += 1 }
-                if !cpu.p.small_index() { cpu.cy += 1 }
                 (0, cpu.d.wrapping_add(offset as u16).wrapping_add(cpu.x))
             }
             DirectIndexedY(offset) => {
                 if cpu.d & 0xff != 0 { cpu.cy += 1 }
-                if !cpu.p.small_index() { cpu.cy += 1 }
                 (0, cpu.d.wrapping_add(offset as u16).wrapping_add(cpu.y))
             }
             DirectIndexedIndirect(offset) => {
@@ -208,7 +224,6 @@ impl AddressingMode {
             }
             DirectIndirectIndexed(offset) => {
                 if cpu.d & 0xff != 0 { cpu.cy += 1 }
-                if !cpu.p.small_index() { cpu.cy += 1 }
 
                 let addr_ptr = cpu.d.wrapping_add(offset as u16);
                 let lo = cpu.loadb(0, addr_ptr) as u32;
@@ -241,7 +256,6 @@ impl AddressingMode {
                 // instruction and the Direct Register. The effective address is this 24-bit base
                 // address plus the Y Index Register."
                 if cpu.d & 0xff != 0 { cpu.cy += 1 }
-                if !cpu.p.small_index() { cpu.cy += 1 }
 
                 let addr_ptr = cpu.d.wrapping_add(offset as u16);
                 let lo = cpu.loadb(0, addr_ptr) as u32;
@@ -0,0 +1,86 @@
+//! Call stack tracking
+//!
+//! When enabled (see `Cpu::set_call_stack_tracking`), the CPU records every `jsr`, `jsl` and
+//! interrupt entry and removes the entries again when the matching `rts`, `rtl` or `rti` is
+//! executed. This gives debuggers a backtrace and lets profilers attribute cycles to subroutines.
+//!
+//! Games frequently mess with the stack: They pop return addresses to return from several
+//! subroutines at once, push fake return addresses and `rts` to them to implement jump tables, or
+//! reset the stack pointer entirely. Instead of trusting the return address, entries are matched
+//! by the stack pointer they were created with:
+//!
+//! * A return removes all entries whose stack pointer is at or below the stack pointer before the
+//!   return. If there is no such entry, the return was used as a jump and nothing is removed.
+//! * A call first removes all entries at or below the new entry's stack pointer, since their
+//!   return addresses have been overwritten.
+
+#[cfg(not(feature = "std"))] use alloc::vec::Vec;
+
+/// How a subroutine or handler was entered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    /// `jsr` (returns with `rts`)
+    Jsr,
+    /// `jsl` (returns with `rtl`)
+    Jsl,
+    /// NMI (returns with `rti`)
+    Nmi,
+    /// IRQ (returns with `rti`)
+    Irq,
+}
+
+/// An entry on the call stack
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: CallKind,
+    /// Address of the call instruction, or of the instruction that was interrupted (`$BBAAAA`)
+    pub caller: u32,
+    /// Address of the subroutine or interrupt handler (`$BBAAAA`)
+    pub target: u32,
+    /// Stack pointer after the return address was pushed
+    pub sp: u16,
+}
+
+/// Maximum number of entries kept. Games that never return from their subroutines would
+/// otherwise grow the stack forever.
+const MAX_DEPTH: usize = 256;
+
+/// The emulated call stack, innermost entry last.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns all entries, outermost first.
+    pub fn frames(&self) -> &[Frame] { &self.frames }
+
+    /// Returns the innermost entry (the subroutine currently executing), or `None` if no
+    /// subroutine was called.
+    pub fn current(&self) -> Option<&Frame> { self.frames.last() }
+
+    pub fn depth(&self) -> usize { self.frames.len() }
+
+    pub fn clear(&mut self) { self.frames.clear() }
+
+    /// Records a call.
+    pub fn call(&mut self, frame: Frame) {
+        self.discard_below(frame.sp);
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    /// Records a return. `sp` is the stack pointer before the return address is popped.
+    pub fn ret(&mut self, sp: u16) {
+        self.discard_below(sp);
+    }
+
+    /// Removes all entries whose stack pointer is `sp` or lower.
+    fn discard_below(&mut self, sp: u16) {
+        self.frames.retain(|frame| frame.sp > sp);
+    }
+}
@@ -0,0 +1,321 @@
+//! 65816 disassembler
+//!
+//! Decodes all 256 opcodes, including the ones the emulator doesn't implement yet. The output uses
+//! the same syntax as the instruction trace.
+
+use super::{Cpu, Mem};
+
+use std::fmt;
+
+/// Operand formats (the disassembler's equivalent of `AddressingMode`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    /// No operand
+    Implied,
+    /// Operates on the accumulator
+    Acc,
+    /// Immediate value, size depends on the M flag
+    ImmAcc,
+    /// Immediate value, size depends on the X flag
+    ImmIndex,
+    /// 8-bit immediate (`rep`, `sep`, `brk`, `cop`, `wdm`)
+    Imm8,
+    /// 16-bit immediate (`pea`)
+    Imm16,
+    Rel,
+    RelLong,
+    Direct,
+    DirectX,
+    DirectY,
+    DirectIndexedIndirect,
+    DirectIndirect,
+    DirectIndirectIndexed,
+    DirectIndirectLong,
+    DirectIndirectLongIdx,
+    Absolute,
+    AbsX,
+    AbsY,
+    AbsIndexedIndirect,
+    AbsoluteIndirect,
+    AbsoluteIndirectLong,
+    AbsoluteLong,
+    AbsLongX,
+    StackRel,
+    StackRelIndirectIndexed,
+    /// Source and destination bank (`mvn`, `mvp`)
+    BlockMove,
+}
+
+use self::Operand::*;
+
+impl Operand {
+    /// Returns the addressing mode's name, as used by `opcode_info`.
+    fn name(&self) -> &'static str {
+        match *self {
+            Implied =>                 "implied",
+            Acc =>                     "accumulator",
+            ImmAcc =>                  "immediate (acc)",
+            ImmIndex =>                "immediate (index)",
+            Imm8 =>                    "immediate (8-bit)",
+            Imm16 =>                   "immediate (16-bit)",
+            Rel =>                     "relative",
+            RelLong =>                 "relative long",
+            Direct =>                  "direct",
+            DirectX =>                 "direct,x",
+            DirectY =>                 "direct,y",
+            DirectIndexedIndirect =>   "(direct,x)",
+            DirectIndirect =>          "(direct)",
+            DirectIndirectIndexed =>   "(direct),y",
+            DirectIndirectLong =>      "[direct]",
+            DirectIndirectLongIdx =>   "[direct],y",
+            Absolute =>                "absolute",
+            AbsX =>                    "absolute,x",
+            AbsY =>                    "absolute,y",
+            AbsIndexedIndirect =>      "(absolute,x)",
+            AbsoluteIndirect =>        "(absolute)",
+            AbsoluteIndirectLong =>    "[absolute]",
+            AbsoluteLong =>            "long",
+            AbsLongX =>                "long,x",
+            StackRel =>                "stack,s",
+            StackRelIndirectIndexed => "(stack,s),y",
+            BlockMove =>               "block move",
+        }
+    }
+}
+
+static OPCODES: [(&'static str, Operand); 256] = [
+    // $00 - $0f
+    ("brk", Imm8), ("ora", DirectIndexedIndirect), ("cop", Imm8), ("ora", StackRel),
+    ("tsb", Direct), ("ora", Direct), ("asl", Direct), ("ora", DirectIndirectLong),
+    ("php", Implied), ("ora", ImmAcc), ("asl", Acc), ("phd", Implied),
+    ("tsb", Absolute), ("ora", Absolute), ("asl", Absolute), ("ora", AbsoluteLong),
+    // $10 - $1f
+    ("bpl", Rel), ("ora", DirectIndirectIndexed), ("ora", DirectIndirect),
+    ("ora", StackRelIndirectIndexed),
+    ("trb", Direct), ("ora", DirectX), ("asl", DirectX), ("ora", DirectIndirectLongIdx),
+    ("clc", Implied), ("ora", AbsY), ("inc", Acc), ("tcs", Implied),
+    ("trb", Absolute), ("ora", AbsX), ("asl", AbsX), ("ora", AbsLongX),
+    // $20 - $2f
+    ("jsr", Absolute), ("and", DirectIndexedIndirect), ("jsl", AbsoluteLong), ("and", StackRel),
+    ("bit", Direct), ("and", Direct), ("rol", Direct), ("and", DirectIndirectLong),
+    ("plp", Implied), ("and", ImmAcc), ("rol", Acc), ("pld", Implied),
+    ("bit", Absolute), ("and", Absolute), ("rol", Absolute), ("and", AbsoluteLong),
+    // $30 - $3f
+    ("bmi", Rel), ("and", DirectIndirectIndexed), ("and", DirectIndirect),
+    ("and", StackRelIndirectIndexed),
+    ("bit", DirectX), ("and", DirectX), ("rol", DirectX), ("and", DirectIndirectLongIdx),
+    ("sec", Implied), ("and", AbsY), ("dec", Acc), ("tsc", Implied),
+    ("bit", AbsX), ("and", AbsX), ("rol", AbsX), ("and", AbsLongX),
+    // $40 - $4f
+    ("rti", Implied), ("eor", DirectIndexedIndirect), ("wdm", Imm8), ("eor", StackRel),
+    ("mvp", BlockMove), ("eor", Direct), ("lsr", Direct), ("eor", DirectIndirectLong),
+    ("pha", Implied), ("eor", ImmAcc), ("lsr", Acc), ("phk", Implied),
+    ("jmp", Absolute), ("eor", Absolute), ("lsr", Absolute), ("eor", AbsoluteLong),
+    // $50 - $5f
+    ("bvc", Rel), ("eor", DirectIndirectIndexed), ("eor", DirectIndirect),
+    ("eor", StackRelIndirectIndexed),
+    ("mvn", BlockMove), ("eor", DirectX), ("lsr", DirectX), ("eor", DirectIndirectLongIdx),
+    ("cli", Implied), ("eor", AbsY), ("phy", Implied), ("tcd", Implied),
+    ("jml", AbsoluteLong), ("eor", AbsX), ("lsr", AbsX), ("eor", AbsLongX),
+    // $60 - $6f
+    ("rts", Implied), ("adc", DirectIndexedIndirect), ("per", RelLong), ("adc", StackRel),
+    ("stz", Direct), ("adc", Direct), ("ror", Direct), ("adc", DirectIndirectLong),
+    ("pla", Implied), ("adc", ImmAcc), ("ror", Acc), ("rtl", Implied),
+    ("jmp", AbsoluteIndirect), ("adc", Absolute), ("ror", Absolute), ("adc", AbsoluteLong),
+    // $70 - $7f
+    ("bvs", Rel), ("adc", DirectIndirectIndexed), ("adc", DirectIndirect),
+    ("adc", StackRelIndirectIndexed),
+    ("stz", DirectX), ("adc", DirectX), ("ror", DirectX), ("adc", DirectIndirectLongIdx),
+    ("sei", Implied), ("adc", AbsY), ("ply", Implied), ("tdc", Implied),
+    ("jmp", AbsIndexedIndirect), ("adc", AbsX), ("ror", AbsX), ("adc", AbsLongX),
+    // $80 - $8f
+    ("bra", Rel), ("sta", DirectIndexedIndirect), ("brl", RelLong), ("sta", StackRel),
+    ("sty", Direct), ("sta", Direct), ("stx", Direct), ("sta", DirectIndirectLong),
+    ("dey", Implied), ("bit", ImmAcc), ("txa", Implied), ("phb", Implied),
+    ("sty", Absolute), ("sta", Absolute), ("stx", Absolute), ("sta", AbsoluteLong),
+    // $90 - $9f
+    ("bcc", Rel), ("sta", DirectIndirectIndexed), ("sta", DirectIndirect),
+    ("sta", StackRelIndirectIndexed),
+    ("sty", DirectX), ("sta", DirectX), ("stx", DirectY), ("sta", DirectIndirectLongIdx),
+    ("tya", Implied), ("sta", AbsY), ("txs", Implied), ("txy", Implied),
+    ("stz", Absolute), ("sta", AbsX), ("stz", AbsX), ("sta", AbsLongX),
+    // $a0 - $af
+    ("ldy", ImmIndex), ("lda", DirectIndexedIndirect), ("ldx", ImmIndex), ("lda", StackRel),
+    ("ldy", Direct), ("lda", Direct), ("ldx", Direct), ("lda", DirectIndirectLong),
+    ("tay", Implied), ("lda", ImmAcc), ("tax", Implied), ("plb", Implied),
+    ("ldy", Absolute), ("lda", Absolute), ("ldx", Absolute), ("lda", AbsoluteLong),
+    // $b0 - $bf
+    ("bcs", Rel), ("lda", DirectIndirectIndexed), ("lda", DirectIndirect),
+    ("lda", StackRelIndirectIndexed),
+    ("ldy", DirectX), ("lda", DirectX), ("ldx", DirectY), ("lda", DirectIndirectLongIdx),
+    ("clv", Implied), ("lda", AbsY), ("tsx", Implied), ("tyx", Implied),
+    ("ldy", AbsX), ("lda", AbsX), ("ldx", AbsY), ("lda", AbsLongX),
+    // $c0 - $cf
+    ("cpy", ImmIndex), ("cmp", DirectIndexedIndirect), ("rep", Imm8), ("cmp", StackRel),
+    ("cpy", Direct), ("cmp", Direct), ("dec", Direct), ("cmp", DirectIndirectLong),
+    ("iny", Implied), ("cmp", ImmAcc), ("dex", Implied), ("wai", Implied),
+    ("cpy", Absolute), ("cmp", Absolute), ("dec", Absolute), ("cmp", AbsoluteLong),
+    // $d0 - $df
+    ("bne", Rel), ("cmp", DirectIndirectIndexed), ("cmp", DirectIndirect),
+    ("cmp", StackRelIndirectIndexed),
+    ("pei", DirectIndirect), ("cmp", DirectX), ("dec", DirectX), ("cmp", DirectIndirectLongIdx),
+    ("cld", Implied), ("cmp", AbsY), ("phx", Implied), ("stp", Implied),
+    ("jml", AbsoluteIndirectLong), ("cmp", AbsX), ("dec", AbsX), ("cmp", AbsLongX),
+    // $e0 - $ef
+    ("cpx", ImmIndex), ("sbc", DirectIndexedIndirect), ("sep", Imm8), ("sbc", StackRel),
+    ("cpx", Direct), ("sbc", Direct), ("inc", Direct), ("sbc", DirectIndirectLong),
+    ("inx", Implied), ("sbc", ImmAcc), ("nop", Implied), ("xba", Implied),
+    ("cpx", Absolute), ("sbc", Absolute), ("inc", Absolute), ("sbc", AbsoluteLong),
+    // $f0 - $ff
+    ("beq", Rel), ("sbc", DirectIndirectIndexed), ("sbc", DirectIndirect),
+    ("sbc", StackRelIndirectIndexed),
+    ("pea", Imm16), ("sbc", DirectX), ("inc", DirectX), ("sbc", DirectIndirectLongIdx),
+    ("sed", Implied), ("sbc", AbsY), ("plx", Implied), ("xce", Implied),
+    ("jsr", AbsIndexedIndirect), ("sbc", AbsX), ("inc", AbsX), ("sbc", AbsLongX),
+];
+
+/// A decoded instruction
+#[derive(Clone, Debug)]
+pub struct Instruction {
+    /// Bank the instruction is located in
+    pub bank: u8,
+    /// Address of the opcode byte
+    pub addr: u16,
+    /// Opcode and operand bytes, only the first `len()` bytes are valid
+    bytes: [u8; 4],
+    len: u8,
+    mnemonic: &'static str,
+    operand: Operand,
+}
+
+impl Instruction {
+    /// Returns the instruction's mnemonic (eg. `"lda"`).
+    pub fn mnemonic(&self) -> &'static str { self.mnemonic }
+
+    /// Returns the size of the instruction in bytes, including the opcode.
+    pub fn len(&self) -> u8 { self.len }
+
+    /// Returns the raw bytes of the instruction (the opcode followed by its operand).
+    pub fn bytes(&self) -> &[u8] { &self.bytes[..self.len as usize] }
+
+    /// Returns the address of the instruction following this one (wraps inside the bank, just like
+    /// the program counter).
+    pub fn next_addr(&self) -> u16 { self.addr.wrapping_add(self.len as u16) }
+
+    /// Returns the full 24-bit address a branch, direct jump or call transfers control to (or the
+    /// address pushed by `per`). Returns `None` for all other instructions, including indirect
+    /// jumps, whose target depends on memory.
+    pub fn target(&self) -> Option<u32> {
+        let bank = (self.bank as u32) << 16;
+        match self.operand {
+            Rel => Some(bank | self.next_addr().wrapping_add(self.op8() as i8 as u16) as u32),
+            RelLong => Some(bank | self.next_addr().wrapping_add(self.op16()) as u32),
+            Absolute if self.mnemonic == "jmp" || self.mnemonic == "jsr" => {
+                Some(bank | self.op16() as u32)
+            }
+            AbsoluteLong if self.mnemonic == "jml" || self.mnemonic == "jsl" => {
+                Some((self.bytes[3] as u32) << 16 | self.op16() as u32)
+            }
+            _ => None,
+        }
+    }
+
+    fn op8(&self) -> u8 { self.bytes[1] }
+    fn op16(&self) -> u16 { self.bytes[1] as u16 | (self.bytes[2] as u16) << 8 }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str(self.mnemonic));
+
+        match self.operand {
+            Implied =>                 Ok(()),
+            Acc =>                     write!(f, " a"),
+            ImmAcc | ImmIndex if self.len == 2 => write!(f, " #${:02X}", self.op8()),
+            ImmAcc | ImmIndex |
+            Imm16 =>                   write!(f, " #${:04X}", self.op16()),
+            Imm8 =>                    write!(f, " #${:02X}", self.op8()),
+            Rel => {
+                let target = self.next_addr().wrapping_add(self.op8() as i8 as u16);
+                write!(f, " ${:04X}", target)
+            }
+            RelLong => {
+                let target = self.next_addr().wrapping_add(self.op16());
+                write!(f, " ${:04X}", target)
+            }
+            Direct =>                  write!(f, " ${:02X}", self.op8()),
+            DirectX =>                 write!(f, " ${:02X},x", self.op8()),
+            DirectY =>                 write!(f, " ${:02X},y", self.op8()),
+            DirectIndexedIndirect =>   write!(f, " (${:02X},x)", self.op8()),
+            DirectIndirect =>          write!(f, " (${:02X})", self.op8()),
+            DirectIndirectIndexed =>   write!(f, " (${:02X}),y", self.op8()),
+            DirectIndirectLong =>      write!(f, " [${:02X}]", self.op8()),
+            DirectIndirectLongIdx =>   write!(f, " [${:02X}],y", self.op8()),
+            Absolute =>                write!(f, " ${:04X}", self.op16()),
+            AbsX =>                    write!(f, " ${:04X},x", self.op16()),
+            AbsY =>                    write!(f, " ${:04X},y", self.op16()),
+            AbsIndexedIndirect =>      write!(f, " (${:04X},x)", self.op16()),
+            AbsoluteIndirect =>        write!(f, " (${:04X})", self.op16()),
+            AbsoluteIndirectLong =>    write!(f, " [${:04X}]", self.op16()),
+            AbsoluteLong =>            write!(f, " ${:02X}:{:04X}", self.bytes[3], self.op16()),
+            AbsLongX =>                write!(f, " ${:02X}:{:04X},x", self.bytes[3], self.op16()),
+            StackRel =>                write!(f, " ${:02X},s", self.op8()),
+            StackRelIndirectIndexed => write!(f, " (${:02X},s),y", self.op8()),
+            // The operand stores the destination bank first
+            BlockMove =>               write!(f, " ${:02X},${:02X}", self.bytes[2], self.bytes[1]),
+        }
+    }
+}
+
+/// Returns the mnemonic and the name of the addressing mode of `opcode` (eg. `("lda", "direct,x")`
+/// for `$B5`).
+pub fn opcode_info(opcode: u8) -> (&'static str, &'static str) {
+    let (mnemonic, operand) = OPCODES[opcode as usize];
+    (mnemonic, operand.name())
+}
+
+/// Decodes the instruction at `bank:addr`.
+///
+/// The size of immediate operands depends on the M and X flags, which are passed as `small_acc`
+/// and `small_index`. Note that the instruction bytes are read from `mem`, which might have side
+/// effects when pointed at I/O registers.
+pub fn disassemble<M: Mem>(mem: &mut M, bank: u8, addr: u16, small_acc: bool, small_index: bool)
+                           -> Instruction {
+    let opcode = mem.load(bank, addr);
+    let (mnemonic, operand) = OPCODES[opcode as usize];
+    let operand_len = match operand {
+        Implied | Acc => 0,
+        ImmAcc => if small_acc { 1 } else { 2 },
+        ImmIndex => if small_index { 1 } else { 2 },
+        Imm8 | Rel | Direct | DirectX | DirectY | DirectIndexedIndirect | DirectIndirect |
+        DirectIndirectIndexed | DirectIndirectLong | DirectIndirectLongIdx | StackRel |
+        StackRelIndirectIndexed => 1,
+        Imm16 | RelLong | Absolute | AbsX | AbsY | AbsIndexedIndirect | AbsoluteIndirect |
+        AbsoluteIndirectLong | BlockMove => 2,
+        AbsoluteLong | AbsLongX => 3,
+    };
+
+    let mut bytes = [opcode, 0, 0, 0];
+    for i in 1..operand_len + 1 {
+        // Like the program counter, operand fetches wrap inside the bank
+        bytes[i] = mem.load(bank, addr.wrapping_add(i as u16));
+    }
+
+    Instruction {
+        bank: bank,
+        addr: addr,
+        bytes: bytes,
+        len: operand_len as u8 + 1,
+        mnemonic: mnemonic,
+        operand: operand,
+    }
+}
+
+impl<M: Mem> Cpu<M> {
+    /// Decodes the instruction at `bank:addr`, using the current M and X flags to determine the
+    /// size of immediate operands.
+    pub fn disassemble(&mut self, bank: u8, addr: u16) -> Instruction {
+        let (small_acc, small_index) = (self.p.small_acc(), self.p.small_index());
+        disassemble(&mut self.mem, bank, addr, small_acc, small_index)
+    }
+}
@@ -1,20 +1,65 @@
 //! 65816 emulator (work in progress)
-
+//!
+//! The CPU doesn't know anything about the system it's part of: Everything it's connected to is
+//! behind the `Mem` trait. Besides loading and storing bytes, `Mem` has optional hooks for the
+//! RDY and interrupt lines, the data bus' open-bus value and the cycles taken by each instruction,
+//! so the core can drive other 65816 systems (or a test harness) as well as the SNES.
+//!
+//! With the default `std` feature disabled, the crate is `#![no_std]` and only depends on
+//! `alloc`. Save states are not available in that configuration.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))] #[macro_use] extern crate alloc;
+// Lets the rest of the crate use `std::` paths for what's in `core`
+#[cfg(not(feature = "std"))] extern crate core as std;
 #[macro_use] extern crate log;
-#[macro_use] extern crate libsavestate;
+#[cfg(feature = "std")] #[macro_use] extern crate libsavestate;
 
-use libsavestate::SaveState;
+#[cfg(not(feature = "std"))] use alloc::string::ToString;
+#[cfg(feature = "std")] use libsavestate::SaveState;
 
 mod addressing;
+pub mod callstack;
+pub mod disasm;
+pub mod opstats;
 mod statusreg;
 
 use addressing::AddressingMode;
-use statusreg::StatusReg;
+use callstack::{CallKind, CallStack, Frame};
+use opstats::OpcodeStats;
+pub use statusreg::StatusReg;
 
 /// Trait for devices attached to the 65816's address/data bus
+///
+/// Only `load` and `store` have to be implemented. The default implementations of the other
+/// methods describe a system that doesn't use the corresponding feature.
 pub trait Mem {
     fn load(&mut self, bank: u8, addr: u16) -> u8;
     fn store(&mut self, bank: u8, addr: u16, value: u8);
+
+    /// Loads a byte, given the value left on the data bus by the last access. Reading an address
+    /// nothing responds to returns that value on real hardware ("open bus").
+    ///
+    /// This is what the CPU calls. By default, the bus value is ignored and `load` is called.
+    fn load_with_bus(&mut self, bank: u8, addr: u16, _bus: u8) -> u8 {
+        self.load(bank, addr)
+    }
+
+    /// Called after every instruction or interrupt sequence with the number of CPU cycles it
+    /// took (the same value `Cpu::dispatch` returns).
+    fn cycles(&mut self, _cy: u16) {}
+
+    /// Returns the state of the RDY input. While it's low (`false`), the CPU is halted.
+    fn rdy(&mut self) -> bool { true }
+
+    /// Returns whether the (level-sensitive) IRQ line is asserted. Polled before every
+    /// instruction.
+    fn irq(&mut self) -> bool { false }
+
+    /// Returns whether the (edge-sensitive) NMI line went active since the last call. Polled
+    /// before every instruction.
+    fn nmi(&mut self) -> bool { false }
 }
 
 // Emulation mode vectors
@@ -36,6 +81,15 @@ const BRK_VEC16: u16 = 0xFFE6;
 #[allow(dead_code)]
 const COP_VEC16: u16 = 0xFFE4;
 
+/// A push or pull that made the stack pointer wrap around (see `Cpu::take_stack_wrap`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StackWrap {
+    /// A push wrapped from the bottom of the stack ($0000, or $0100 in emulation mode) to the top
+    Overflow,
+    /// A pull wrapped from the top of the stack back to the bottom
+    Underflow,
+}
+
 pub struct Cpu<M: Mem> {
     pub a: u16,
     pub x: u16,
@@ -59,16 +113,25 @@ pub struct Cpu<M: Mem> {
 
     /// CPU clock cycle counter for the current instruction.
     cy: u16,
+    /// Last value transferred over the data bus
+    bus: u8,
 
     pub trace: bool,
+    /// Subroutine calls and interrupts, if call stack tracking is enabled
+    call_stack: Option<CallStack>,
+    /// First stack pointer wrap since the last call to `take_stack_wrap`
+    stack_wrap: Option<StackWrap>,
+    /// Execution counts of all opcodes, if enabled
+    op_stats: Option<OpcodeStats>,
     pub mem: M,
 }
 
 // Needs an explicit impl because `Cpu` is generic over `M`.
+#[cfg(feature = "std")]
 impl<M: Mem + SaveState> SaveState for Cpu<M> {
     impl_save_state_fns!(Cpu {
-        a, x, y, s, dbr, pbr, d, pc, p, emulation, wai, mem
-    } ignore { cy, trace });
+        a, x, y, s, dbr, pbr, d, pc, p, emulation, wai, bus, mem
+    } ignore { cy, trace, call_stack, stack_wrap, op_stats });
 }
 
 impl<M: Mem> Cpu<M> {
@@ -97,15 +160,85 @@ impl<M: Mem> Cpu<M> {
             emulation: true,
             wai: false,
             cy: 0,
+            bus: pch as u8,
             trace: false,
+            call_stack: None,
+            stack_wrap: None,
+            op_stats: None,
             mem: mem,
         }
     }
 
+    /// Returns the processor status register.
+    pub fn status(&self) -> &StatusReg { &self.p }
+
+    /// Returns whether the CPU is running in emulation mode.
+    pub fn emulation(&self) -> bool { self.emulation }
+
+    /// Returns whether the CPU executed a WAI instruction and is waiting for an interrupt.
+    pub fn waiting(&self) -> bool { self.wai }
+
+    /// Returns the last value transferred over the data bus (see `Mem::load_with_bus`).
+    pub fn data_bus(&self) -> u8 { self.bus }
+
+    /// Enables or disables tracking of subroutine calls and interrupts (see the `callstack`
+    /// module). Enabling it starts with an empty call stack.
+    pub fn set_call_stack_tracking(&mut self, enabled: bool) {
+        self.call_stack = if enabled { Some(CallStack::new()) } else { None };
+    }
+
+    /// Returns the call stack, if call stack tracking is enabled.
+    pub fn call_stack(&self) -> Option<&CallStack> { self.call_stack.as_ref() }
+
+    /// Starts (or, when passing `None`, stops) counting the executed opcodes (see the `opstats`
+    /// module).
+    pub fn set_opcode_stats(&mut self, stats: Option<OpcodeStats>) {
+        self.op_stats = stats;
+    }
+
+    /// Returns the opcode statistics, if they're enabled.
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> { self.op_stats.as_ref() }
+
+    /// Get a mutable reference to the opcode statistics (eg. to clear them).
+    pub fn opcode_stats_mut(&mut self) -> Option<&mut OpcodeStats> { self.op_stats.as_mut() }
+
+    /// Returns (and forgets) the first stack overflow or underflow that happened since the last
+    /// call. The stack pointer wraps around just like on hardware, but it's usually a sign of a
+    /// bug (in the game or the emulator), so hosts can use this to report it.
+    pub fn take_stack_wrap(&mut self) -> Option<StackWrap> { self.stack_wrap.take() }
+
+    fn report_stack_wrap(&mut self, wrap: StackWrap) {
+        if self.stack_wrap.is_none() {
+            self.stack_wrap = Some(wrap);
+        }
+    }
+
+    /// Records a call to `bank:addr` on the call stack (if enabled). Must be called after the
+    /// return address was pushed.
+    fn track_call(&mut self, kind: CallKind, caller: u32, bank: u8, addr: u16) {
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.call(Frame {
+                kind: kind,
+                caller: caller,
+                target: (bank as u32) << 16 | addr as u32,
+                sp: self.s,
+            });
+        }
+    }
+
+    /// Records a return on the call stack (if enabled). Must be called before the return address
+    /// is popped.
+    fn track_return(&mut self) {
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.ret(self.s);
+        }
+    }
+
     /// Load a byte from memory.
     fn loadb(&mut self, bank: u8, addr: u16) -> u8 {
-        // FIXME Remove?
-        self.mem.load(bank, addr)
+        let bus = self.bus;
+        self.bus = self.mem.load_with_bus(bank, addr, bus);
+        self.bus
     }
     fn loadw(&mut self, bank: u8, addr: u16) -> u16 {
         assert!(addr < 0xffff, "loadw on bank boundary");
@@ -117,7 +250,7 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn storeb(&mut self, bank: u8, addr: u16, value: u8) {
-        // FIXME Remove?
+        self.bus = value;
         self.mem.store(bank, addr, value)
     }
     fn storew(&mut self, bank: u8, addr: u16, value: u16) {
@@ -150,11 +283,13 @@ impl<M: Mem> Cpu<M> {
         self.storeb(0, s, value);
         if self.emulation {
             // stack must stay in 0x01xx
-            assert_eq!(self.s & 0xff00, 0x0100);
-            let s = self.s as u8 - 1;
+            debug_assert_eq!(self.s & 0xff00, 0x0100);
+            if self.s as u8 == 0x00 { self.report_stack_wrap(StackWrap::Overflow) }
+            let s = (self.s as u8).wrapping_sub(1);
             self.s = (self.s & 0xff00) | s as u16;
         } else {
-            self.s -= 1;
+            if self.s == 0x0000 { self.report_stack_wrap(StackWrap::Overflow) }
+            self.s = self.s.wrapping_sub(1);
         }
     }
 
@@ -168,11 +303,13 @@ impl<M: Mem> Cpu<M> {
     fn popb(&mut self) -> u8 {
         if self.emulation {
             // stack must stay in 0x01xx
-            assert_eq!(self.s & 0xff00, 0x0100);
-            let s = self.s as u8 + 1;
+            debug_assert_eq!(self.s & 0xff00, 0x0100);
+            if self.s as u8 == 0xff { self.report_stack_wrap(StackWrap::Underflow) }
+            let s = (self.s as u8).wrapping_add(1);
             self.s = (self.s & 0xff00) | s as u16;
         } else {
-            self.s += 1;
+            if self.s == 0xffff { self.report_stack_wrap(StackWrap::Underflow) }
+            self.s = self.s.wrapping_add(1);
         }
 
         let s = self.s;
@@ -206,7 +343,7 @@ impl<M: Mem> Cpu<M> {
 
     fn trace_op(&self, pc: u16, raw: u8, op: &str, am: Option<&AddressingMode>) {
         use log::LogLevel::Trace;
-        if !log_enabled!(Trace) || !self.trace { return }
+        if !self.trace || !log_enabled!(Trace) { return }
 
         let opstr = match am {
             Some(am) => format!("{} {}", op, am),
@@ -230,39 +367,68 @@ impl<M: Mem> Cpu<M> {
 
     /// Executes a single opcode and returns the number of CPU clock cycles used.
     ///
-    /// Note that in case a WAI instruction was executed, this will *not* execute anything and
-    /// return 0. An interrupt has to be caused to resume work.
+    /// Before the instruction, the NMI and IRQ lines are polled (see `Mem::nmi` and `Mem::irq`). If
+    /// an interrupt is taken, the interrupt sequence is executed instead of an instruction.
+    ///
+    /// Note that in case a WAI instruction was executed or the RDY line is low, this will *not*
+    /// execute anything and return 0. An interrupt has to be caused to resume work after WAI.
     pub fn dispatch(&mut self) -> u16 {
-        // CPU cycles each opcode takes (at the minimum).
-        // This table assumes that fetching a byte takes 1 CPU cycle. The `Mem` implementor can add
-        // additional wait state cycles externally.
-        // (FIXME: Is the above correct? Critical for timing!)
+        // Base CPU cycles each opcode takes with 8-bit registers, in emulation mode, when the low
+        // byte of D is zero and conditional branches aren't taken. Every byte accessed takes 1 CPU
+        // cycle here, memory speed (eg. FastROM) is up to the `Mem` implementor to account for.
+        //
+        // The rest is added by the instructions and addressing modes:
+        // * 1 cycle per extra byte accessed with a 16-bit accumulator or index register (2 for
+        //   read-modify-write instructions)
+        // * 1 cycle for direct page accesses if the low byte of D isn't zero
+        // * 1 cycle for indexed reads crossing a page boundary or using 16-bit index registers
+        // * 1 cycle for taken branches, and another one in emulation mode when the branch target
+        //   is on another page
+        // * 1 cycle for pulling the PBR in native mode (`rti`)
         static CYCLE_TABLE: [u8; 256] = [
             7,6,7,4,5,3,5,6, 3,2,2,4,6,4,6,5,   // $00 - $0f
             2,5,5,7,5,4,6,6, 2,4,2,2,6,4,7,5,   // $10 - $1f
             6,6,8,4,3,3,5,6, 4,2,2,5,4,4,6,5,   // $20 - $2f
             2,5,5,7,4,4,6,6, 2,4,2,2,4,4,7,5,   // $30 - $3f
-            7,6,2,4,7,3,5,6, 3,2,2,3,3,4,6,5,   // $40 - $4f
+            6,6,2,4,7,3,5,6, 3,2,2,3,3,4,6,5,   // $40 - $4f
             2,5,5,7,7,4,6,6, 2,4,3,2,4,4,7,5,   // $50 - $5f
-            7,6,6,4,3,3,5,6, 4,2,2,6,5,4,6,5,   // $60 - $6f
-            2,5,5,7,4,4,6,6, 2,4,4,2,6,2,7,5,   // $70 - $7f
-            2,6,3,4,3,3,3,2, 2,2,2,3,4,4,4,5,   // $80 - $8f
-            2,6,5,7,4,4,4,6, 2,5,2,2,3,5,5,5,   // $90 - $9f
+            6,6,6,4,3,3,5,6, 4,2,2,6,5,4,6,5,   // $60 - $6f
+            2,5,5,7,4,4,6,6, 2,4,4,2,6,4,7,5,   // $70 - $7f
+            2,6,4,4,3,3,3,6, 2,2,2,3,4,4,4,5,   // $80 - $8f
+            2,6,5,7,4,4,4,6, 2,5,2,2,4,5,5,5,   // $90 - $9f
             2,6,2,4,3,3,3,6, 2,2,2,4,4,4,4,5,   // $a0 - $af
             2,5,5,7,4,4,4,6, 2,4,2,2,4,4,4,5,   // $b0 - $bf
             2,6,3,4,3,3,5,6, 2,2,2,3,4,4,6,5,   // $c0 - $cf
             2,5,5,7,6,4,6,6, 2,4,3,3,6,4,7,5,   // $d0 - $df
             2,6,3,4,3,3,5,6, 2,2,2,3,4,4,6,5,   // $e0 - $ef
-            2,5,5,7,5,4,6,6, 2,4,4,2,6,4,7,5,   // $f0 - $ff
+            2,5,5,7,5,4,6,6, 2,4,4,2,8,4,7,5,   // $f0 - $ff
         ];
 
+        if !self.mem.rdy() { return 0; }
+
+        self.cy = 0;
+        if self.mem.nmi() {
+            self.trigger_nmi();
+            return self.interrupt_cycles();
+        }
+        if self.mem.irq() {
+            if !self.p.irq_disable() {
+                self.interrupt_irq();
+                return self.interrupt_cycles();
+            }
+            // With IRQs disabled, an IRQ still ends a WAI, but execution continues after it
+            self.wai = false;
+        }
+
         // Still waiting for interrupt? Don't do any work.
         if self.wai { return 0; }
 
         let pc = self.pc;
-        self.cy = 0;
         let op = self.fetchb();
         self.cy += CYCLE_TABLE[op as usize] as u16;
+        if let Some(ref mut stats) = self.op_stats {
+            stats.record(op);
+        }
 
         macro_rules! instr {
             ( $name:ident ) => {{
@@ -297,6 +463,7 @@ impl<M: Mem> Cpu<M> {
             // Processor status
             0x18 => instr!(clc),
             0x38 => instr!(sec),
+            0xb8 => instr!(clv),
             0x58 => instr!(cli),
             0x78 => instr!(sei),
             0xcb => instr!(wai),
@@ -484,7 +651,7 @@ impl<M: Mem> Cpu<M> {
 
             // Branches
             0x80 => instr!(bra rel),
-            0x82 => instr!(bra relative_long),  // BRL
+            0x82 => instr!(brl relative_long),
             0xf0 => instr!(beq rel),
             0xd0 => instr!(bne rel),
             0x10 => instr!(bpl rel),
@@ -514,6 +681,16 @@ impl<M: Mem> Cpu<M> {
             }
         }
 
+        self.mem.cycles(self.cy);
+        self.cy
+    }
+
+    /// Returns the cycles taken by an interrupt sequence triggered through the interrupt lines
+    /// and reports them to `Mem::cycles`.
+    fn interrupt_cycles(&mut self) -> u16 {
+        // One more cycle in native mode to push the PBR
+        self.cy = if self.emulation { 7 } else { 8 };
+        self.mem.cycles(self.cy);
         self.cy
     }
 
@@ -529,23 +706,29 @@ impl<M: Mem> Cpu<M> {
     /// Invokes the IRQ handler if interrupts are enabled. Returns whether the interrupt was
     /// generated.
     pub fn trigger_irq(&mut self) -> bool {
-        if !self.p.irq_disable() {
+        if self.p.irq_disable() {
             false
         } else {
-            if self.emulation {
-                self.interrupt(IRQ_VEC8);
-            } else {
-                self.interrupt(IRQ_VEC16);
-            }
+            self.interrupt_irq();
             true
         }
     }
 
+    /// Invokes the IRQ handler, regardless of the I flag.
+    fn interrupt_irq(&mut self) {
+        if self.emulation {
+            self.interrupt(IRQ_VEC8);
+        } else {
+            self.interrupt(IRQ_VEC16);
+        }
+    }
+
     /// Execute an IRQ sequence. This pushes PBR, PC and the processor status register P on the
     /// stack, sets the PBR to 0, loads the handler address from the given vector, and jumps to the
     /// handler.
     fn interrupt(&mut self, vector: u16) {
         self.wai = false;
+        let pbr_before = self.pbr;
 
         if !self.emulation {
             let pbr = self.pbr;
@@ -558,6 +741,8 @@ impl<M: Mem> Cpu<M> {
         let p = self.p.0;
         self.pushb(p);
 
+        // Further IRQs are disabled until the handler returns
+        self.p.set_irq_disable(true);
         // Interrupts clear the decimal flag (http://www.6502.org/tutorials/decimal_mode.html)
         // ...but only in native mode
         if !self.emulation {
@@ -566,9 +751,17 @@ impl<M: Mem> Cpu<M> {
 
         let handler = self.loadw(0, vector);
         self.pc = handler;
+
+        let kind = match vector {
+            NMI_VEC8 | NMI_VEC16 => CallKind::Nmi,
+            _ => CallKind::Irq,
+        };
+        let (caller, pbr) = ((pbr_before as u32) << 16 | pc as u32, self.pbr);
+        self.track_call(kind, caller, pbr, handler);
     }
 
     fn return_from_interrupt(&mut self) {
+        self.track_return();
         let p = self.popb();
         self.p.0 = p;
         let pc = self.popw();
@@ -604,6 +797,15 @@ impl<M: Mem> Cpu<M> {
         self.pc = target.1;
     }
 
+    /// Takes a short (8-bit relative) branch and adds the cycles it takes.
+    fn take_branch(&mut self, target: (u8, u16)) {
+        self.cy += 1;
+        if self.emulation && self.pc & 0xff00 != target.1 & 0xff00 {
+            self.cy += 1;
+        }
+        self.branch(target);
+    }
+
     /// Changes the status register.
     fn set_p(&mut self, new: u8) {
         let small_idx = self.p.small_index();
@@ -963,24 +1165,24 @@ impl<M: Mem> Cpu<M> {
             self.p.set_carry(self.a & 0x8000 != 0);
             let res = (self.a << 1) | c as u16;
             self.a = self.p.set_nz(res);
-            self.cy += 1;
         }
     }
     /// Rotate Memory Left
     fn rol(&mut self, am: AddressingMode) {
         // Sets N, Z, and C. C is used to fill the rightmost bit.
         let c: u8 = if self.p.carry() { 1 } else { 0 };
+        let (bank, addr) = am.address(self);
         if self.p.small_acc() {
-            let a = am.clone().loadb(self);
+            let a = self.loadb(bank, addr);
             self.p.set_carry(a & 0x80 != 0);
             let res = self.p.set_nz_8((a << 1) | c);
-            am.storeb(self, res);
+            self.storeb(bank, addr, res);
         } else {
-            let a = am.clone().loadw(self);
+            let a = self.loadw(bank, addr);
             self.p.set_carry(a & 0x8000 != 0);
             let res = self.p.set_nz((a << 1) | c as u16);
-            am.storew(self, res);
-            self.cy += 1;   // FIXME times 2?
+            self.storew(bank, addr, res);
+            self.cy += 2;
         }
     }
 
@@ -999,16 +1201,18 @@ impl<M: Mem> Cpu<M> {
     /// Logical Shift Right
     fn lsr(&mut self, am: AddressingMode) {
         // Sets N (always cleared), Z and C. The leftmost bit is filled with 0.
+        let (bank, addr) = am.address(self);
         if self.p.small_acc() {
-            let a = am.clone().loadb(self);
+            let a = self.loadb(bank, addr);
             self.p.set_carry(a & 0x01 != 0);
             let res = self.p.set_nz_8(a >> 1);
-            am.storeb(self, res);
+            self.storeb(bank, addr, res);
         } else {
-            let a = am.clone().loadw(self);
+            let a = self.loadw(bank, addr);
             self.p.set_carry(a & 0x0001 != 0);
             let res = self.p.set_nz(a >> 1);
-            am.storew(self, res);
+            self.storew(bank, addr, res);
+            self.cy += 2;
         }
     }
     /// Rotate accumulator right
@@ -1024,7 +1228,6 @@ impl<M: Mem> Cpu<M> {
             let val = self.a;
             self.p.set_carry(val & 0x0001 != 0);
             self.a = self.p.set_nz((val >> 1) | ((c as u16) << 15));
-            self.cy += 2;
         }
     }
     /// Rotate Memory Right
@@ -1135,6 +1338,7 @@ impl<M: Mem> Cpu<M> {
             let res = self.loadw(bank, addr).wrapping_add(1);
             self.p.set_nz(res);
             self.storew(bank, addr, res);
+            self.cy += 2;
         }
     }
     /// Increment accumulator
@@ -1188,6 +1392,7 @@ impl<M: Mem> Cpu<M> {
             let res = self.loadw(bank, addr).wrapping_sub(1);
             self.p.set_nz(res);
             self.storew(bank, addr, res);
+            self.cy += 2;
         }
     }
     /// Decrement X
@@ -1225,6 +1430,11 @@ impl<M: Mem> Cpu<M> {
     }
     /// Branch always (inside current program bank, but this isn't checked)
     fn bra(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        self.take_branch(a);
+    }
+    /// Branch Always Long
+    fn brl(&mut self, am: AddressingMode) {
         let a = am.address(self);
         self.branch(a);
     }
@@ -1232,64 +1442,56 @@ impl<M: Mem> Cpu<M> {
     fn bpl(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if !self.p.negative() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if Minus/Negative (N = 1)
     fn bmi(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.negative() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if Overflow Clear
     fn bvc(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if !self.p.overflow() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if Overflow Set
     fn bvs(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.overflow() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if carry clear
     fn bcc(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if !self.p.carry() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if carry set
     fn bcs(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.carry() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if Equal
     fn beq(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.zero() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
     /// Branch if Not Equal (Branch if Z = 0)
     fn bne(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if !self.p.zero() {
-            self.branch(a);
-            self.cy += 1;
+            self.take_branch(a);
         }
     }
 
@@ -1321,17 +1523,17 @@ impl<M: Mem> Cpu<M> {
     /// Test and set memory bits against accumulator
     fn tsb(&mut self, am: AddressingMode) {
         // Sets Z
-        // FIXME Is this correct?
-        if self.p.small_index() {
-            let val = am.clone().loadb(self);
+        let (bank, addr) = am.address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
             self.p.set_zero(val & self.a as u8 == 0);
             let res = val | self.a as u8;
-            am.storeb(self, res);
+            self.storeb(bank, addr, res);
         } else {
-            let val = am.clone().loadw(self);
+            let val = self.loadw(bank, addr);
             self.p.set_zero(val & self.a == 0);
             let res = val | self.a;
-            am.storew(self, res);
+            self.storew(bank, addr, res);
 
             self.cy += 2;
         }
@@ -1339,17 +1541,17 @@ impl<M: Mem> Cpu<M> {
     /// Test and reset memory bits against accumulator
     fn trb(&mut self, am: AddressingMode) {
         // Sets Z
-        // FIXME Is this correct?
-        if self.p.small_index() {
-            let val = am.clone().loadb(self);
+        let (bank, addr) = am.address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
             self.p.set_zero(val & self.a as u8 == 0);
             let res = val & !(self.a as u8);
-            am.storeb(self, res);
+            self.storeb(bank, addr, res);
         } else {
-            let val = am.clone().loadw(self);
+            let val = self.loadw(bank, addr);
             self.p.set_zero(val & self.a == 0);
             let res = val & !self.a;
-            am.storew(self, res);
+            self.storew(bank, addr, res);
 
             self.cy += 2;
         }
@@ -1410,7 +1612,11 @@ impl<M: Mem> Cpu<M> {
         self.pushb((pc >> 8) as u8);
         self.pushb(pc as u8);
 
+        let pbr = self.pbr;
         self.pc = am.address(self).1;
+        // All `jsr` variants are 3 bytes long
+        let (caller, target) = ((pbr as u32) << 16 | pc.wrapping_sub(2) as u32, self.pc);
+        self.track_call(CallKind::Jsr, caller, pbr, target);
     }
     /// Long jump to subroutine. Additionally saves PBR on the stack and sets it to the bank
     /// returned by `am.address()`.
@@ -1422,14 +1628,20 @@ impl<M: Mem> Cpu<M> {
         self.pushb((pc >> 8) as u8);
         self.pushb(pc as u8);
 
+        let caller = (self.pbr as u32) << 16 | pc.wrapping_sub(3) as u32;
         let (pbr, pc) = am.address(self);
         self.pbr = pbr;
         self.pc = pc;
+        self.track_call(CallKind::Jsl, caller, pbr, pc);
     }
     /// Return from Interrupt
-    fn rti(&mut self) { self.return_from_interrupt() }
+    fn rti(&mut self) {
+        if !self.emulation { self.cy += 1 }
+        self.return_from_interrupt()
+    }
     /// Return from Subroutine (Short - Like JSR)
     fn rts(&mut self) {
+        self.track_return();
         let pcl = self.popb() as u16;
         let pch = self.popb() as u16;
         let pc = (pch << 8) | pcl;
@@ -1439,6 +1651,7 @@ impl<M: Mem> Cpu<M> {
     ///
     /// This also restores the PBR.
     fn rtl(&mut self) {
+        self.track_return();
         let pcl = self.popb() as u16;
         let pch = self.popb() as u16;
         let pbr = self.popb();
@@ -1453,6 +1666,7 @@ impl<M: Mem> Cpu<M> {
     fn sed(&mut self) { self.p.set_decimal(true) }
     fn clc(&mut self) { self.p.set_carry(false) }
     fn sec(&mut self) { self.p.set_carry(true) }
+    fn clv(&mut self) { self.p.set_overflow(false) }
 
     fn wai(&mut self) { self.wai = true; }
 
@@ -1667,7 +1881,6 @@ impl<M: Mem> Cpu<M> {
         if self.p.small_acc() {
             AddressingMode::Immediate8(self.fetchb())
         } else {
-            self.cy += 1;
             AddressingMode::Immediate(self.fetchw())
         }
     }
@@ -1676,7 +1889,6 @@ impl<M: Mem> Cpu<M> {
         if self.p.small_index() {
             AddressingMode::Immediate8(self.fetchb())
         } else {
-            self.cy += 1;
             AddressingMode::Immediate(self.fetchw())
         }
     }
@@ -0,0 +1,124 @@
+//! Opcode usage statistics
+//!
+//! When enabled (see `Cpu::set_opcode_stats`), the CPU counts how often every opcode is executed.
+//! The histogram shows which instructions (and addressing modes) a game relies on, and the
+//! opcodes that were never executed tell how much of the instruction set a test run covered.
+
+#[cfg(not(feature = "std"))] use alloc::vec::Vec;
+
+use disasm;
+
+use std::fmt;
+
+/// Execution counts of all 256 opcodes.
+#[derive(Clone)]
+pub struct OpcodeStats {
+    counts: [u64; 256],
+}
+
+impl Default for OpcodeStats {
+    fn default() -> Self {
+        OpcodeStats { counts: [0; 256] }
+    }
+}
+
+impl fmt::Debug for OpcodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpcodeStats {{ total: {}, covered: {} }}", self.total(), self.covered())
+    }
+}
+
+impl OpcodeStats {
+    pub fn new() -> Self { Self::default() }
+
+    /// Counts an execution of `opcode`.
+    pub fn record(&mut self, opcode: u8) {
+        self.counts[opcode as usize] += 1;
+    }
+
+    /// Returns how often `opcode` was executed.
+    pub fn count(&self, opcode: u8) -> u64 { self.counts[opcode as usize] }
+
+    /// Returns the total number of instructions executed.
+    pub fn total(&self) -> u64 { self.counts.iter().sum() }
+
+    /// Returns the number of different opcodes that were executed at least once.
+    pub fn covered(&self) -> usize { self.counts.iter().filter(|&&n| n > 0).count() }
+
+    /// Returns the opcodes that were never executed, in ascending order.
+    pub fn uncovered(&self) -> Vec<u8> {
+        (0..256).filter(|&op| self.counts[op] == 0).map(|op| op as u8).collect()
+    }
+
+    /// Returns `(opcode, count)` pairs of all executed opcodes, most frequent first.
+    pub fn by_opcode(&self) -> Vec<(u8, u64)> {
+        let mut ops: Vec<_> = (0..256)
+            .filter(|&op| self.counts[op] > 0)
+            .map(|op| (op as u8, self.counts[op]))
+            .collect();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ops
+    }
+
+    /// Returns `(addressing mode, count)` pairs of all used addressing modes, most frequent first
+    /// (see `disasm::opcode_info` for the names).
+    pub fn by_addressing_mode(&self) -> Vec<(&'static str, u64)> {
+        let mut modes: Vec<(&'static str, u64)> = Vec::new();
+        for (op, &count) in self.counts.iter().enumerate() {
+            if count == 0 { continue }
+            let (_, mode) = disasm::opcode_info(op as u8);
+            match modes.iter_mut().find(|entry| entry.0 == mode) {
+                Some(entry) => entry.1 += count,
+                None => modes.push((mode, count)),
+            }
+        }
+        modes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        modes
+    }
+
+    /// Resets all counts to 0.
+    pub fn clear(&mut self) {
+        self.counts = [0; 256];
+    }
+}
+
+/// Dumps the histograms as a table: Opcodes first, then addressing modes, then the opcodes that
+/// were never executed.
+impl fmt::Display for OpcodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        let percent = |count: u64| {
+            if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 }
+        };
+
+        try!(writeln!(f, "{} instructions executed, {} of 256 opcodes covered",
+                      total, self.covered()));
+        try!(writeln!(f));
+        try!(writeln!(f, "{:8}{:33} {:>12} {:>7}", "opcode", "instruction", "count", "%"));
+        for (op, count) in self.by_opcode() {
+            let (mnemonic, mode) = disasm::opcode_info(op);
+            try!(writeln!(f, "${:02X}     {} {:29} {:>12} {:>7.3}",
+                          op, mnemonic, mode, count, percent(count)));
+        }
+
+        try!(writeln!(f));
+        try!(writeln!(f, "{:33} {:>12} {:>7}", "addressing mode", "count", "%"));
+        for (mode, count) in self.by_addressing_mode() {
+            try!(writeln!(f, "{:33} {:>12} {:>7.3}", mode, count, percent(count)));
+        }
+
+        let uncovered = self.uncovered();
+        if !uncovered.is_empty() {
+            try!(writeln!(f));
+            try!(writeln!(f, "never executed:"));
+            for line in uncovered.chunks(16) {
+                for (i, &op) in line.iter().enumerate() {
+                    if i > 0 { try!(f.write_str(" ")) }
+                    try!(write!(f, "${:02X}", op));
+                }
+                try!(writeln!(f));
+            }
+        }
+        Ok(())
+    }
+}
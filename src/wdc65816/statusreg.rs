@@ -19,6 +19,7 @@ const CARRY_FLAG: u8 = 0x01;
 
 pub struct StatusReg(pub u8);
 
+#[cfg(feature = "std")]
 impl_save_state_for_newtype!(StatusReg);
 
 impl StatusReg {
@@ -0,0 +1,175 @@
+//! Tests for the optional `Mem` hooks (interrupt and RDY lines, open bus, cycle callback)
+
+extern crate wdc65816;
+
+use wdc65816::{Cpu, Mem};
+
+/// Address the code is placed at (bank 0)
+const CODE_ADDR: u16 = 0x8000;
+/// Interrupt handlers (both just contain an `rti`)
+const NMI_HANDLER: u16 = 0x9000;
+const IRQ_HANDLER: u16 = 0x9100;
+
+/// 32 KB of RAM at `$0000-$7fff` and 32 KB of ROM at `$8000-$ffff`, mirrored into every bank.
+/// `$2000-$7fff` is unmapped and returns the open-bus value.
+struct TestBus {
+    mem: Vec<u8>,
+    rdy: bool,
+    irq: bool,
+    nmi: bool,
+    cycles: u64,
+}
+
+impl TestBus {
+    fn new(code: &[u8]) -> Self {
+        let mut mem = vec![0; 0x10000];
+        mem[CODE_ADDR as usize..CODE_ADDR as usize + code.len()].copy_from_slice(code);
+        mem[NMI_HANDLER as usize] = 0x40;   // rti
+        mem[IRQ_HANDLER as usize] = 0x40;
+        for &(vector, addr) in &[(0xfffc, CODE_ADDR), (0xfffa, NMI_HANDLER),
+                                 (0xfffe, IRQ_HANDLER)] {
+            mem[vector] = addr as u8;
+            mem[vector + 1] = (addr >> 8) as u8;
+        }
+
+        TestBus {
+            mem: mem,
+            rdy: true,
+            irq: false,
+            nmi: false,
+            cycles: 0,
+        }
+    }
+}
+
+impl Mem for TestBus {
+    fn load(&mut self, _bank: u8, addr: u16) -> u8 { self.mem[addr as usize] }
+
+    fn store(&mut self, _bank: u8, addr: u16, value: u8) {
+        if addr < 0x2000 {
+            self.mem[addr as usize] = value;
+        }
+    }
+
+    fn load_with_bus(&mut self, bank: u8, addr: u16, bus: u8) -> u8 {
+        match addr {
+            0x2000 ... 0x7fff => bus,
+            _ => self.load(bank, addr),
+        }
+    }
+
+    fn cycles(&mut self, cy: u16) { self.cycles += cy as u64 }
+
+    fn rdy(&mut self) -> bool { self.rdy }
+
+    fn irq(&mut self) -> bool { self.irq }
+
+    fn nmi(&mut self) -> bool {
+        let nmi = self.nmi;
+        self.nmi = false;
+        nmi
+    }
+}
+
+#[test]
+fn cycle_callback() {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0xa9, 0x12,         // lda #$12
+        0x8d, 0x00, 0x10,   // sta $1000
+        0xea,               // nop
+    ]));
+
+    let total: u64 = (0..3).map(|_| cpu.dispatch() as u64).sum();
+    assert!(total > 0);
+    assert_eq!(cpu.mem.cycles, total);
+    assert_eq!(cpu.mem.mem[0x1000], 0x12);
+}
+
+#[test]
+fn rdy_halts_cpu() {
+    let mut cpu = Cpu::new(TestBus::new(&[0xea]));
+    cpu.mem.rdy = false;
+    assert_eq!(cpu.dispatch(), 0);
+    assert_eq!(cpu.pc, CODE_ADDR);
+    assert_eq!(cpu.mem.cycles, 0);
+
+    cpu.mem.rdy = true;
+    assert!(cpu.dispatch() > 0);
+    assert_eq!(cpu.pc, CODE_ADDR + 1);
+}
+
+#[test]
+fn open_bus() {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0xad, 0x00, 0x50,   // lda $5000
+        0xa9, 0x34,         // lda #$34
+        0x8d, 0x00, 0x10,   // sta $1000
+        0xad, 0x00, 0x60,   // lda $6000
+    ]));
+
+    // The last byte on the bus is the high byte of the address
+    cpu.dispatch();
+    assert_eq!(cpu.a & 0xff, 0x50);
+    assert_eq!(cpu.data_bus(), 0x50);
+
+    cpu.dispatch();
+    cpu.dispatch();
+    assert_eq!(cpu.data_bus(), 0x34);
+    cpu.dispatch();
+    assert_eq!(cpu.a & 0xff, 0x60);
+}
+
+#[test]
+fn nmi_line() {
+    let mut cpu = Cpu::new(TestBus::new(&[0xea, 0xea]));
+    cpu.mem.nmi = true;
+    assert_eq!(cpu.dispatch(), 7);
+    assert_eq!(cpu.pc, NMI_HANDLER);
+
+    // The NMI is only taken once per edge
+    cpu.dispatch();     // rti
+    assert_eq!(cpu.pc, CODE_ADDR);
+    cpu.dispatch();
+    assert_eq!(cpu.pc, CODE_ADDR + 1);
+}
+
+#[test]
+fn irq_line() {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0x58,   // cli
+        0xea,   // nop
+    ]));
+
+    // IRQs are disabled after reset
+    cpu.mem.irq = true;
+    cpu.dispatch();
+    assert_eq!(cpu.pc, CODE_ADDR + 1);
+
+    assert_eq!(cpu.dispatch(), 7);
+    assert_eq!(cpu.pc, IRQ_HANDLER);
+    assert!(cpu.status().irq_disable());
+
+    // The line is level-sensitive, so the IRQ is taken again after `rti` re-enables IRQs
+    cpu.dispatch();     // rti
+    assert_eq!(cpu.pc, CODE_ADDR + 1);
+    cpu.dispatch();
+    assert_eq!(cpu.pc, IRQ_HANDLER);
+}
+
+#[test]
+fn irq_ends_wai_with_irqs_disabled() {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0xcb,   // wai
+        0xea,   // nop
+    ]));
+
+    cpu.dispatch();
+    assert!(cpu.waiting());
+    assert_eq!(cpu.dispatch(), 0);
+
+    // Execution continues after the `wai` without calling the handler
+    cpu.mem.irq = true;
+    cpu.dispatch();
+    assert!(!cpu.waiting());
+    assert_eq!(cpu.pc, CODE_ADDR + 2);
+}
@@ -0,0 +1,23 @@
+//! Test fixtures shared by the integration tests
+
+use wdc65816::Mem;
+
+/// 64 KB of RAM with the code at `$8000`, mirrored into every bank.
+pub struct TestBus {
+    mem: Vec<u8>,
+}
+
+impl TestBus {
+    pub fn new(code: &[u8]) -> Self {
+        let mut mem = vec![0; 0x10000];
+        mem[0x8000..0x8000 + code.len()].copy_from_slice(code);
+        mem[0xfffc] = 0x00;
+        mem[0xfffd] = 0x80;
+        TestBus { mem: mem }
+    }
+}
+
+impl Mem for TestBus {
+    fn load(&mut self, _bank: u8, addr: u16) -> u8 { self.mem[addr as usize] }
+    fn store(&mut self, _bank: u8, addr: u16, value: u8) { self.mem[addr as usize] = value }
+}
@@ -0,0 +1,79 @@
+//! Tests for decimal mode `adc` and `sbc`
+
+extern crate wdc65816;
+
+mod common;
+
+use common::TestBus;
+use wdc65816::Cpu;
+
+/// Result of a decimal mode operation: `(a, n, v, z, c)`
+type Flags = (u16, bool, bool, bool, bool);
+
+/// Runs `lda #a; adc/sbc #val` with an 8-bit accumulator and the carry set to `carry`.
+fn run8(opcode: u8, a: u8, val: u8, carry: bool) -> Flags {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0xf8,                               // sed
+        if carry { 0x38 } else { 0x18 },   // sec / clc
+        0xa9, a,                            // lda #a
+        opcode, val,                        // adc/sbc #val
+    ]));
+    for _ in 0..4 { cpu.dispatch(); }
+
+    let p = cpu.status();
+    (cpu.a & 0xff, p.negative(), p.overflow(), p.zero(), p.carry())
+}
+
+/// Runs `lda #a; adc/sbc #val` with a 16-bit accumulator in native mode.
+fn run16(opcode: u8, a: u16, val: u16, carry: bool) -> Flags {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0x18,                                   // clc
+        0xfb,                                   // xce
+        0xc2, 0x20,                             // rep #$20
+        0xf8,                                   // sed
+        if carry { 0x38 } else { 0x18 },       // sec / clc
+        0xa9, a as u8, (a >> 8) as u8,          // lda #a
+        opcode, val as u8, (val >> 8) as u8,    // adc/sbc #val
+    ]));
+    for _ in 0..7 { cpu.dispatch(); }
+
+    let p = cpu.status();
+    (cpu.a, p.negative(), p.overflow(), p.zero(), p.carry())
+}
+
+const ADC: u8 = 0x69;
+const SBC: u8 = 0xe9;
+
+#[test]
+fn adc_8bit() {
+    assert_eq!(run8(ADC, 0x15, 0x27, false), (0x42, false, false, false, false));
+    assert_eq!(run8(ADC, 0x09, 0x01, false), (0x10, false, false, false, false));
+    assert_eq!(run8(ADC, 0x58, 0x46, true),  (0x05, false, true, false, true));
+    assert_eq!(run8(ADC, 0x99, 0x01, false), (0x00, false, false, true, true));
+    assert_eq!(run8(ADC, 0x79, 0x00, true),  (0x80, true, true, false, false));
+}
+
+#[test]
+fn sbc_8bit() {
+    assert_eq!(run8(SBC, 0x42, 0x15, true),  (0x27, false, false, false, true));
+    assert_eq!(run8(SBC, 0x32, 0x02, false), (0x29, false, false, false, true));
+    assert_eq!(run8(SBC, 0x12, 0x21, true),  (0x91, true, false, false, false));
+    assert_eq!(run8(SBC, 0x00, 0x01, true),  (0x99, true, false, false, false));
+    assert_eq!(run8(SBC, 0x27, 0x27, true),  (0x00, false, false, true, true));
+}
+
+#[test]
+fn adc_16bit() {
+    assert_eq!(run16(ADC, 0x1234, 0x5678, false), (0x6912, false, false, false, false));
+    assert_eq!(run16(ADC, 0x0999, 0x0001, false), (0x1000, false, false, false, false));
+    assert_eq!(run16(ADC, 0x7999, 0x0001, false), (0x8000, true, true, false, false));
+    assert_eq!(run16(ADC, 0x9999, 0x0001, false), (0x0000, false, false, true, true));
+}
+
+#[test]
+fn sbc_16bit() {
+    assert_eq!(run16(SBC, 0x5000, 0x1234, true), (0x3766, false, false, false, true));
+    assert_eq!(run16(SBC, 0x1000, 0x0001, true), (0x0999, false, false, false, true));
+    assert_eq!(run16(SBC, 0x8000, 0x0001, true), (0x7999, false, true, false, true));
+    assert_eq!(run16(SBC, 0x0000, 0x0001, true), (0x9999, true, false, false, false));
+}
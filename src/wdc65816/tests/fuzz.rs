@@ -0,0 +1,622 @@
+//! Differential fuzzing of the CPU core
+//!
+//! Runs randomized instruction sequences on the CPU core and on a simple, table-driven model of
+//! the 65816 and compares registers, flags and memory afterwards. Both operate on a flat 64 KB
+//! address space (all banks are mirrors of it). Failing sequences are shrunk to a minimal sequence
+//! that still fails before they are reported.
+//!
+//! The instructions used are limited to those that can't change control flow, and the model
+//! stays in native mode with `D = 0` and `DBR = 0` (decimal mode isn't exercised either).
+//!
+//! The number of sequences and the random seed can be changed with the `FUZZ_ITERATIONS` and
+//! `FUZZ_SEED` environment variables.
+
+extern crate wdc65816;
+
+use wdc65816::{Cpu, Mem};
+
+use std::env;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+const DEFAULT_ITERATIONS: u32 = 1000;
+const DEFAULT_SEED: u64 = 0x5eed_65c8_16de_cade;
+/// Maximum number of random instructions in a sequence
+const MAX_LEN: usize = 32;
+/// Address the code is placed at (bank 0)
+const CODE_ADDR: u16 = 0x8000;
+/// Absolute addressing only targets this range, so that the code and stack aren't overwritten
+const ABS_RANGE: (u16, u16) = (0x0200, 0x02ff);
+
+/// 64 KB of memory, mirrored into every bank
+#[derive(Clone, PartialEq, Eq)]
+struct FlatMem(Vec<u8>);
+
+impl Mem for FlatMem {
+    fn load(&mut self, _bank: u8, addr: u16) -> u8 { self.0[addr as usize] }
+    fn store(&mut self, _bank: u8, addr: u16, value: u8) { self.0[addr as usize] = value }
+}
+
+/// Operand format of an instruction
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    /// Immediate, 8 or 16 bits depending on the accumulator size
+    ImmAcc,
+    /// Immediate, 8 or 16 bits depending on the index register size
+    ImmIndex,
+    /// 8-bit immediate (`REP`/`SEP`)
+    Imm8,
+    /// Direct page (with `D = 0`)
+    Direct,
+    /// Absolute, in `ABS_RANGE`
+    Absolute,
+}
+
+/// What an instruction does
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Lda, Ldx, Ldy, Sta, Stx, Sty, Stz,
+    Adc, Sbc, And, Ora, Eor, Cmp, Cpx, Cpy, Bit, Tsb, Trb,
+    Inc, Dec, Asl, Lsr, Rol, Ror,
+    Inx, Iny, Dex, Dey,
+    Tax, Tay, Txa, Tya, Txy, Tyx, Xba,
+    Clc, Sec, Clv, Rep, Sep, Xce, Nop,
+    Pha, Pla, Phx, Plx, Phy, Ply, Php,
+}
+
+use Kind::*;
+use Mode::*;
+
+/// The instructions the fuzzer generates
+static OPCODES: &'static [(u8, &'static str, Kind, Mode)] = &[
+    (0xa9, "lda", Lda, ImmAcc), (0xa5, "lda", Lda, Direct), (0xad, "lda", Lda, Absolute),
+    (0xa2, "ldx", Ldx, ImmIndex), (0xa6, "ldx", Ldx, Direct), (0xae, "ldx", Ldx, Absolute),
+    (0xa0, "ldy", Ldy, ImmIndex), (0xa4, "ldy", Ldy, Direct), (0xac, "ldy", Ldy, Absolute),
+    (0x85, "sta", Sta, Direct), (0x8d, "sta", Sta, Absolute),
+    (0x86, "stx", Stx, Direct), (0x8e, "stx", Stx, Absolute),
+    (0x84, "sty", Sty, Direct), (0x8c, "sty", Sty, Absolute),
+    (0x64, "stz", Stz, Direct), (0x9c, "stz", Stz, Absolute),
+    (0x69, "adc", Adc, ImmAcc), (0x65, "adc", Adc, Direct), (0x6d, "adc", Adc, Absolute),
+    (0xe9, "sbc", Sbc, ImmAcc), (0xe5, "sbc", Sbc, Direct), (0xed, "sbc", Sbc, Absolute),
+    (0x29, "and", And, ImmAcc), (0x25, "and", And, Direct), (0x2d, "and", And, Absolute),
+    (0x09, "ora", Ora, ImmAcc), (0x05, "ora", Ora, Direct), (0x0d, "ora", Ora, Absolute),
+    (0x49, "eor", Eor, ImmAcc), (0x45, "eor", Eor, Direct), (0x4d, "eor", Eor, Absolute),
+    (0xc9, "cmp", Cmp, ImmAcc), (0xc5, "cmp", Cmp, Direct), (0xcd, "cmp", Cmp, Absolute),
+    (0xe0, "cpx", Cpx, ImmIndex), (0xe4, "cpx", Cpx, Direct), (0xec, "cpx", Cpx, Absolute),
+    (0xc0, "cpy", Cpy, ImmIndex), (0xc4, "cpy", Cpy, Direct), (0xcc, "cpy", Cpy, Absolute),
+    (0x89, "bit", Bit, ImmAcc), (0x24, "bit", Bit, Direct), (0x2c, "bit", Bit, Absolute),
+    (0x04, "tsb", Tsb, Direct), (0x0c, "tsb", Tsb, Absolute),
+    (0x14, "trb", Trb, Direct), (0x1c, "trb", Trb, Absolute),
+    (0x1a, "inc", Inc, Implied), (0xe6, "inc", Inc, Direct), (0xee, "inc", Inc, Absolute),
+    (0x3a, "dec", Dec, Implied), (0xc6, "dec", Dec, Direct), (0xce, "dec", Dec, Absolute),
+    (0x0a, "asl", Asl, Implied), (0x06, "asl", Asl, Direct), (0x0e, "asl", Asl, Absolute),
+    (0x4a, "lsr", Lsr, Implied), (0x46, "lsr", Lsr, Direct), (0x4e, "lsr", Lsr, Absolute),
+    (0x2a, "rol", Rol, Implied), (0x26, "rol", Rol, Direct), (0x2e, "rol", Rol, Absolute),
+    (0x6a, "ror", Ror, Implied), (0x66, "ror", Ror, Direct), (0x6e, "ror", Ror, Absolute),
+    (0xe8, "inx", Inx, Implied), (0xc8, "iny", Iny, Implied),
+    (0xca, "dex", Dex, Implied), (0x88, "dey", Dey, Implied),
+    (0xaa, "tax", Tax, Implied), (0xa8, "tay", Tay, Implied),
+    (0x8a, "txa", Txa, Implied), (0x98, "tya", Tya, Implied),
+    (0x9b, "txy", Txy, Implied), (0xbb, "tyx", Tyx, Implied),
+    (0xeb, "xba", Xba, Implied),
+    (0x18, "clc", Clc, Implied), (0x38, "sec", Sec, Implied), (0xb8, "clv", Clv, Implied),
+    (0xc2, "rep", Rep, Imm8), (0xe2, "sep", Sep, Imm8),
+    (0xea, "nop", Nop, Implied),
+    (0x48, "pha", Pha, Implied), (0x68, "pla", Pla, Implied),
+    (0xda, "phx", Phx, Implied), (0xfa, "plx", Plx, Implied),
+    (0x5a, "phy", Phy, Implied), (0x7a, "ply", Ply, Implied),
+    (0x08, "php", Php, Implied),
+];
+
+/// Instructions that are only used in the preamble
+static XCE: (u8, &'static str, Kind, Mode) = (0xfb, "xce", Xce, Implied);
+
+const FLAG_N: u8 = 0x80;
+const FLAG_V: u8 = 0x40;
+const FLAG_M: u8 = 0x20;
+const FLAG_X: u8 = 0x10;
+const FLAG_D: u8 = 0x08;
+const FLAG_Z: u8 = 0x02;
+const FLAG_C: u8 = 0x01;
+
+/// An instruction and its operand
+#[derive(Copy, Clone)]
+struct Op {
+    /// Entry of `OPCODES` (or `XCE`)
+    info: &'static (u8, &'static str, Kind, Mode),
+    operand: u16,
+}
+
+impl Op {
+    fn kind(&self) -> Kind { self.info.2 }
+    fn mode(&self) -> Mode { self.info.3 }
+}
+
+/// The 65816 model the core is compared against
+#[derive(Clone, PartialEq, Eq)]
+struct Model {
+    a: u16,
+    x: u16,
+    y: u16,
+    s: u16,
+    d: u16,
+    dbr: u8,
+    p: u8,
+    e: bool,
+    mem: FlatMem,
+}
+
+impl Model {
+    /// State after reset
+    fn new(mem: FlatMem) -> Self {
+        Model { a: 0, x: 0, y: 0, s: 0x0100, d: 0, dbr: 0, p: 0x34, e: true, mem: mem }
+    }
+
+    fn small_acc(&self) -> bool { self.e || self.p & FLAG_M != 0 }
+    fn small_index(&self) -> bool { self.e || self.p & FLAG_X != 0 }
+
+    fn flag(&mut self, flag: u8, set: bool) {
+        if set { self.p |= flag } else { self.p &= !flag }
+    }
+
+    /// Sets N and Z according to an 8- or 16-bit value.
+    fn nz(&mut self, value: u16, small: bool) {
+        let (zero, neg) = if small {
+            (value & 0xff == 0, value & 0x80 != 0)
+        } else {
+            (value == 0, value & 0x8000 != 0)
+        };
+        self.flag(FLAG_Z, zero);
+        self.flag(FLAG_N, neg);
+    }
+
+    fn read(&self, addr: u16, small: bool) -> u16 {
+        let lo = self.mem.0[addr as usize] as u16;
+        if small { lo } else { lo | (self.mem.0[addr.wrapping_add(1) as usize] as u16) << 8 }
+    }
+
+    fn write(&mut self, addr: u16, value: u16, small: bool) {
+        self.mem.0[addr as usize] = value as u8;
+        if !small { self.mem.0[addr.wrapping_add(1) as usize] = (value >> 8) as u8 }
+    }
+
+    fn push(&mut self, value: u16, small: bool) {
+        if !small {
+            let s = self.s;
+            self.mem.0[s as usize] = (value >> 8) as u8;
+            self.s = s.wrapping_sub(1);
+        }
+        let s = self.s;
+        self.mem.0[s as usize] = value as u8;
+        self.s = s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self, small: bool) -> u16 {
+        self.s = self.s.wrapping_add(1);
+        let lo = self.mem.0[self.s as usize] as u16;
+        if small { return lo }
+        self.s = self.s.wrapping_add(1);
+        lo | (self.mem.0[self.s as usize] as u16) << 8
+    }
+
+    /// Writes the low or full accumulator (the high byte is kept in 8-bit mode).
+    fn set_acc(&mut self, value: u16) {
+        let small = self.small_acc();
+        self.a = if small { self.a & 0xff00 | value & 0xff } else { value };
+        self.nz(value, small);
+    }
+
+    fn set_index(&mut self, value: u16) -> u16 {
+        let small = self.small_index();
+        let value = if small { value & 0xff } else { value };
+        self.nz(value, small);
+        value
+    }
+
+    fn add(&mut self, a: u16, b: u16, small: bool) -> u16 {
+        let (mask, sign) = if small { (0xff, 0x80) } else { (0xffff, 0x8000) };
+        let (a, b) = (a as u32 & mask, b as u32 & mask);
+        let result = a + b + (self.p & FLAG_C) as u32;
+        self.flag(FLAG_C, result > mask);
+        self.flag(FLAG_V, !(a ^ b) & (a ^ result) & sign != 0);
+        (result & mask) as u16
+    }
+
+    fn compare(&mut self, reg: u16, value: u16, small: bool) {
+        let (reg, value) = if small { (reg & 0xff, value & 0xff) } else { (reg, value) };
+        self.flag(FLAG_C, reg >= value);
+        self.nz(reg.wrapping_sub(value), small);
+    }
+
+    /// Shifts/rotates `value` (of the given size), setting C, N and Z.
+    fn shift(&mut self, kind: Kind, value: u16, small: bool) -> u16 {
+        let top = if small { 0x80 } else { 0x8000 };
+        let carry = self.p & FLAG_C != 0;
+        let (result, carry_out) = match kind {
+            Asl => (value << 1, value & top != 0),
+            Rol => (value << 1 | carry as u16, value & top != 0),
+            Lsr => (value >> 1, value & 1 != 0),
+            Ror => (value >> 1 | if carry { top } else { 0 }, value & 1 != 0),
+            _ => unreachable!(),
+        };
+        let result = if small { result & 0xff } else { result };
+        self.flag(FLAG_C, carry_out);
+        self.nz(result, small);
+        result
+    }
+
+    /// Executes an instruction.
+    fn step(&mut self, op: &Op) {
+        let (acc8, idx8) = (self.small_acc(), self.small_index());
+        let addr = match op.mode() {
+            Direct => Some(self.d.wrapping_add(op.operand)),
+            Absolute => Some(op.operand),
+            _ => None,
+        };
+        // Fetches the operand, sized like the accumulator (`small == true`) or 16-bit
+        let operand = |m: &Model, small: bool| match addr {
+            Some(addr) => m.read(addr, small),
+            None => op.operand,
+        };
+
+        match op.kind() {
+            Lda => { let v = operand(self, acc8); self.set_acc(v) }
+            Ldx => { let v = operand(self, idx8); self.x = self.set_index(v) }
+            Ldy => { let v = operand(self, idx8); self.y = self.set_index(v) }
+            Sta => { let a = self.a; self.write(addr.unwrap(), a, acc8) }
+            Stx => { let x = self.x; self.write(addr.unwrap(), x, idx8) }
+            Sty => { let y = self.y; self.write(addr.unwrap(), y, idx8) }
+            Stz => self.write(addr.unwrap(), 0, acc8),
+            Adc => {
+                let (a, v) = (self.a, operand(self, acc8));
+                let r = self.add(a, v, acc8);
+                self.set_acc(r);
+            }
+            Sbc => {
+                let (a, v) = (self.a, operand(self, acc8));
+                let r = self.add(a, !v, acc8);
+                self.set_acc(r);
+            }
+            And => { let v = self.a & operand(self, acc8); self.set_acc(v) }
+            Ora => { let v = self.a | operand(self, acc8); self.set_acc(v) }
+            Eor => { let v = self.a ^ operand(self, acc8); self.set_acc(v) }
+            Cmp => { let (a, v) = (self.a, operand(self, acc8)); self.compare(a, v, acc8) }
+            Cpx => { let (x, v) = (self.x, operand(self, idx8)); self.compare(x, v, idx8) }
+            Cpy => { let (y, v) = (self.y, operand(self, idx8)); self.compare(y, v, idx8) }
+            Bit => {
+                let v = operand(self, acc8);
+                let mask = if acc8 { 0xff } else { 0xffff };
+                let zero = self.a & v & mask == 0;
+                self.flag(FLAG_Z, zero);
+                if addr.is_some() {
+                    let (n, v_bit) = if acc8 { (0x80, 0x40) } else { (0x8000, 0x4000) };
+                    self.flag(FLAG_N, v & n != 0);
+                    self.flag(FLAG_V, v & v_bit != 0);
+                }
+            }
+            Tsb | Trb => {
+                let addr = addr.unwrap();
+                let v = self.read(addr, acc8);
+                let mask = if acc8 { 0xff } else { 0xffff };
+                let zero = self.a & v & mask == 0;
+                self.flag(FLAG_Z, zero);
+                let a = self.a;
+                self.write(addr, if op.kind() == Tsb { v | a } else { v & !a }, acc8);
+            }
+            Inc | Dec => {
+                let delta = if op.kind() == Inc { 1 } else { 0xffff };
+                match addr {
+                    Some(addr) => {
+                        let v = self.read(addr, acc8).wrapping_add(delta);
+                        self.write(addr, v, acc8);
+                        self.nz(v, acc8);
+                    }
+                    None => { let v = self.a.wrapping_add(delta); self.set_acc(v) }
+                }
+            }
+            Asl | Lsr | Rol | Ror => match addr {
+                Some(addr) => {
+                    let v = self.read(addr, acc8);
+                    let v = self.shift(op.kind(), v, acc8);
+                    self.write(addr, v, acc8);
+                }
+                None => {
+                    let v = if acc8 { self.a & 0xff } else { self.a };
+                    let v = self.shift(op.kind(), v, acc8);
+                    self.a = if acc8 { self.a & 0xff00 | v } else { v };
+                }
+            },
+            Inx => { let v = self.x.wrapping_add(1); self.x = self.set_index(v) }
+            Iny => { let v = self.y.wrapping_add(1); self.y = self.set_index(v) }
+            Dex => { let v = self.x.wrapping_sub(1); self.x = self.set_index(v) }
+            Dey => { let v = self.y.wrapping_sub(1); self.y = self.set_index(v) }
+            Tax => { let v = self.a; self.x = self.set_index(v) }
+            Tay => { let v = self.a; self.y = self.set_index(v) }
+            Txy => { let v = self.x; self.y = self.set_index(v) }
+            Tyx => { let v = self.y; self.x = self.set_index(v) }
+            Txa => { let v = self.x; self.set_acc(v) }
+            Tya => { let v = self.y; self.set_acc(v) }
+            Xba => {
+                self.a = self.a.swap_bytes();
+                let a = self.a;
+                self.nz(a, true);
+            }
+            Clc => self.p &= !FLAG_C,
+            Sec => self.p |= FLAG_C,
+            Clv => self.p &= !FLAG_V,
+            Rep => {
+                self.p &= !(op.operand as u8);
+                if self.e { self.p |= FLAG_M | FLAG_X }
+            }
+            Sep => {
+                self.p |= op.operand as u8;
+                if self.p & FLAG_X != 0 {
+                    self.x &= 0xff;
+                    self.y &= 0xff;
+                }
+            }
+            Xce => {
+                let carry = self.p & FLAG_C != 0;
+                self.flag(FLAG_C, self.e);
+                self.e = carry;
+                if self.e {
+                    self.p |= FLAG_M | FLAG_X;
+                    self.x &= 0xff;
+                    self.y &= 0xff;
+                    self.s = 0x0100 | self.s & 0xff;
+                }
+            }
+            Nop => {}
+            Pha => { let a = self.a; self.push(a, acc8) }
+            Phx => { let x = self.x; self.push(x, idx8) }
+            Phy => { let y = self.y; self.push(y, idx8) }
+            Php => { let p = self.p as u16; self.push(p, true) }
+            Pla => { let v = self.pull(acc8); self.set_acc(v) }
+            Plx => { let v = self.pull(idx8); self.x = self.set_index(v) }
+            Ply => { let v = self.pull(idx8); self.y = self.set_index(v) }
+        }
+    }
+}
+
+/// xorshift64* - good enough for generating test cases, and reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 { self.next() % n }
+}
+
+/// A test case: Initial memory and register values, and the instructions to execute
+#[derive(Clone)]
+struct Case {
+    mem: FlatMem,
+    /// Initial A, X, Y
+    regs: (u16, u16, u16),
+    /// Initial status register (`D` is always clear)
+    p: u8,
+    ops: Vec<Op>,
+}
+
+impl Case {
+    fn random(rng: &mut Rng) -> Self {
+        let mut mem = vec![0; 0x10000];
+        for byte in mem[..ABS_RANGE.1 as usize + 1].iter_mut() {
+            *byte = rng.next() as u8;
+        }
+
+        let len = 1 + rng.below(MAX_LEN as u64) as usize;
+        let ops = (0..len).map(|_| {
+            let info = &OPCODES[rng.below(OPCODES.len() as u64) as usize];
+            let operand = match info.3 {
+                Implied => 0,
+                ImmAcc | ImmIndex => rng.next() as u16,
+                Imm8 => rng.next() as u8 as u16 & !FLAG_D as u16,
+                // Leave room for 16-bit accesses
+                Direct => rng.below(0xff) as u16,
+                Absolute => ABS_RANGE.0 + rng.below((ABS_RANGE.1 - ABS_RANGE.0) as u64) as u16,
+            };
+            Op { info: info, operand: operand }
+        }).collect();
+
+        Case {
+            mem: FlatMem(mem),
+            regs: (rng.next() as u16, rng.next() as u16, rng.next() as u16),
+            p: rng.next() as u8 & !FLAG_D,
+            ops: ops,
+        }
+    }
+
+    /// Returns the instructions that set up the initial state, followed by the test instructions.
+    fn program(&self) -> Vec<Op> {
+        fn op(opcode: u8, operand: u16) -> Op {
+            Op { info: OPCODES.iter().find(|o| o.0 == opcode).unwrap(), operand: operand }
+        }
+
+        let mut program = vec![
+            op(0x18, 0),                    // clc
+            Op { info: &XCE, operand: 0 },  // xce
+            op(0xc2, 0x30),                 // rep #$30
+            op(0xa9, self.regs.0),          // lda #a
+            op(0xa2, self.regs.1),          // ldx #x
+            op(0xa0, self.regs.2),          // ldy #y
+            op(0xc2, 0xff),                 // rep #$ff
+            op(0xe2, self.p as u16),        // sep #p
+        ];
+        program.extend_from_slice(&self.ops);
+        program
+    }
+
+    /// Assembles the program, using the operand sizes in effect for every instruction.
+    fn assemble(program: &[Op]) -> Vec<u8> {
+        // Emulation mode at reset
+        let (mut acc8, mut idx8, mut native) = (true, true, false);
+        let mut code = Vec::new();
+        for op in program {
+            code.push(op.info.0);
+            let wide = match op.mode() {
+                Implied => None,
+                ImmAcc => Some(!acc8),
+                ImmIndex => Some(!idx8),
+                Imm8 | Direct => Some(false),
+                Absolute => Some(true),
+            };
+            match wide {
+                Some(true) => code.extend_from_slice(&[op.operand as u8, (op.operand >> 8) as u8]),
+                Some(false) => code.push(op.operand as u8),
+                None => {}
+            }
+            match op.kind() {
+                Xce => native = true,   // The preamble only uses it to switch to native mode
+                Rep if native => {
+                    acc8 &= op.operand & FLAG_M as u16 == 0;
+                    idx8 &= op.operand & FLAG_X as u16 == 0;
+                }
+                Sep => {
+                    acc8 |= op.operand & FLAG_M as u16 != 0;
+                    idx8 |= op.operand & FLAG_X as u16 != 0;
+                }
+                _ => {}
+            }
+        }
+        code
+    }
+
+    /// Runs the case on both the core and the model. Returns a description of the differences, if
+    /// any.
+    fn check(&self) -> Option<String> {
+        let program = self.program();
+        let code = Case::assemble(&program);
+
+        let mut model = Model::new(self.mem.clone());
+        for op in &program {
+            model.step(op);
+        }
+
+        let mut mem = self.mem.clone();
+        let start = CODE_ADDR as usize;
+        mem.0[start..start + code.len()].copy_from_slice(&code);
+        mem.0[0xfffc] = CODE_ADDR as u8;
+        mem.0[0xfffd] = (CODE_ADDR >> 8) as u8;
+        // The model doesn't know about the code
+        model.mem.0[start..start + code.len()].copy_from_slice(&code);
+        model.mem.0[0xfffc] = mem.0[0xfffc];
+        model.mem.0[0xfffd] = mem.0[0xfffd];
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut cpu = Cpu::new(mem);
+            for _ in 0..program.len() {
+                cpu.dispatch();
+            }
+            cpu
+        }));
+        let cpu = match result {
+            Ok(cpu) => cpu,
+            Err(_) => return Some("the core panicked".to_string()),
+        };
+
+        let mut diff = String::new();
+        {
+            let mut compare = |name: &str, core: u32, model: u32| if core != model {
+                writeln!(diff, "{}: core {:04X}, model {:04X}", name, core, model).unwrap();
+            };
+            compare("A", cpu.a as u32, model.a as u32);
+            compare("X", cpu.x as u32, model.x as u32);
+            compare("Y", cpu.y as u32, model.y as u32);
+            compare("S", cpu.s as u32, model.s as u32);
+            compare("D", cpu.d as u32, model.d as u32);
+            compare("DBR", cpu.dbr as u32, model.dbr as u32);
+            compare("P", cpu.status().0 as u32, model.p as u32);
+            compare("E", cpu.emulation() as u32, model.e as u32);
+            compare("PC", cpu.pc as u32, (CODE_ADDR as usize + code.len()) as u32);
+            for (addr, (&core, &model)) in cpu.mem.0.iter().zip(model.mem.0.iter()).enumerate() {
+                compare(&format!("${:04X}", addr), core as u32, model as u32);
+            }
+        }
+
+        if diff.is_empty() { None } else { Some(diff) }
+    }
+
+    /// Removes instructions and simplifies operands as long as the case keeps failing.
+    fn shrink(mut self) -> Self {
+        loop {
+            let mut progress = false;
+
+            let mut i = 0;
+            while i < self.ops.len() {
+                let mut smaller = self.clone();
+                smaller.ops.remove(i);
+                if smaller.check().is_some() {
+                    self = smaller;
+                    progress = true;
+                } else {
+                    i += 1;
+                }
+            }
+
+            for i in 0..self.ops.len() {
+                if self.ops[i].mode() == Imm8 || self.ops[i].operand == 0 { continue }
+                for &operand in &[0, 1, 0x80, 0x8000] {
+                    let mut simpler = self.clone();
+                    simpler.ops[i].operand = operand;
+                    if operand < self.ops[i].operand && simpler.check().is_some() {
+                        self = simpler;
+                        progress = true;
+                        break;
+                    }
+                }
+            }
+
+            if !progress { return self }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut s = format!("A={:04X} X={:04X} Y={:04X} P={:02X}\n", self.regs.0, self.regs.1,
+            self.regs.2, self.p);
+        for op in &self.ops {
+            match op.mode() {
+                Implied => writeln!(s, "  {}", op.info.1),
+                ImmAcc | ImmIndex | Imm8 => writeln!(s, "  {} #${:X}", op.info.1, op.operand),
+                Direct => writeln!(s, "  {} ${:02X}", op.info.1, op.operand),
+                Absolute => writeln!(s, "  {} ${:04X}", op.info.1, op.operand),
+            }.unwrap();
+        }
+        s
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().ok().expect(&format!("invalid value for {}", name)),
+        Err(_) => default,
+    }
+}
+
+#[test]
+fn differential() {
+    let iterations = env_or("FUZZ_ITERATIONS", DEFAULT_ITERATIONS);
+    let seed = env_or("FUZZ_SEED", DEFAULT_SEED);
+
+    // The core panics on some bugs, don't print all of those while shrinking
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut rng = Rng(seed | 1);
+    for i in 0..iterations {
+        let case = Case::random(&mut rng);
+        if case.check().is_some() {
+            let case = case.shrink();
+            panic::set_hook(hook);
+            panic!("core and model disagree (case {} of seed {}):\n{}\n{}", i, seed,
+                case.describe(), case.check().unwrap());
+        }
+    }
+    panic::set_hook(hook);
+}
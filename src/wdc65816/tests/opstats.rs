@@ -0,0 +1,38 @@
+//! Tests for the opcode usage statistics
+
+extern crate wdc65816;
+
+mod common;
+
+use common::TestBus;
+use wdc65816::Cpu;
+use wdc65816::opstats::OpcodeStats;
+
+#[test]
+fn counts_opcodes_and_modes() {
+    let mut cpu = Cpu::new(TestBus::new(&[
+        0xa9, 0x12,         // lda #$12
+        0x85, 0x10,         // sta $10
+        0xa5, 0x10,         // lda $10
+        0xea,               // nop
+        0xea,               // nop
+    ]));
+
+    // Nothing is counted until the stats are enabled
+    cpu.dispatch();
+    assert!(cpu.opcode_stats().is_none());
+    cpu.set_opcode_stats(Some(OpcodeStats::new()));
+    for _ in 0..4 {
+        cpu.dispatch();
+    }
+
+    let stats = cpu.opcode_stats().unwrap();
+    assert_eq!(stats.total(), 4);
+    assert_eq!(stats.covered(), 3);
+    assert_eq!(stats.count(0xa9), 0);
+    assert_eq!(stats.count(0xea), 2);
+    assert_eq!(stats.by_opcode()[0], (0xea, 2));
+    assert_eq!(stats.by_addressing_mode(), vec![("direct", 2), ("implied", 2)]);
+    assert_eq!(stats.uncovered().len(), 253);
+    assert!(!stats.uncovered().contains(&0x85));
+}
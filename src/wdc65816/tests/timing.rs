@@ -0,0 +1,60 @@
+//! Tests for the instruction timing returned by `Cpu::dispatch`
+
+extern crate wdc65816;
+
+mod common;
+
+use common::TestBus;
+use wdc65816::Cpu;
+
+/// Runs the given code and returns the cycles taken by each instruction.
+fn cycles(code: &[u8], count: usize) -> Vec<u16> {
+    let mut cpu = Cpu::new(TestBus::new(code));
+    (0..count).map(|_| cpu.dispatch()).collect()
+}
+
+#[test]
+fn register_size() {
+    assert_eq!(cycles(&[
+        0x18,               // clc
+        0xfb,               // xce
+        0xa9, 0x12,         // lda #$12
+        0xc2, 0x30,         // rep #$30
+        0xa9, 0x34, 0x12,   // lda #$1234
+        0x8d, 0x00, 0x10,   // sta $1000
+        0xee, 0x00, 0x10,   // inc $1000
+        0x2a,               // rol a
+        0xa2, 0x00, 0x10,   // ldx #$1000
+    ], 9), [2, 2, 2, 3, 3, 5, 8, 2, 3]);
+}
+
+#[test]
+fn direct_page_low_byte() {
+    assert_eq!(cycles(&[
+        0xa5, 0x10,         // lda $10
+        0xf4, 0x01, 0x00,   // pea $0001
+        0x2b,               // pld
+        0xa5, 0x10,         // lda $10
+    ], 4), [3, 5, 5, 4]);
+}
+
+#[test]
+fn indexed_page_crossing() {
+    assert_eq!(cycles(&[
+        0xa2, 0x08,         // ldx #$08
+        0xbd, 0xf0, 0x10,   // lda $10f0,x
+        0xbd, 0xf8, 0x10,   // lda $10f8,x
+        0x9d, 0xf0, 0x10,   // sta $10f0,x
+        0x9d, 0xf8, 0x10,   // sta $10f8,x
+    ], 5), [2, 4, 5, 5, 5]);
+}
+
+#[test]
+fn branches() {
+    assert_eq!(cycles(&[
+        0x18,               // clc
+        0xb0, 0x00,         // bcs +0 (not taken)
+        0x90, 0x00,         // bcc +0
+        0x80, 0x80,         // bra -128 (crosses into page $7f)
+    ], 4), [2, 2, 3, 4]);
+}